@@ -0,0 +1,92 @@
+#![no_main]
+use libfuzzer_sys::arbitrary::{Arbitrary, Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+use regalloc2::checker::Checker;
+use regalloc2::fuzzing::func::{Func, Options};
+
+#[derive(Clone, Debug)]
+struct TestCase {
+    func: Func,
+    save_callee_saves: bool,
+    interp_seed: u64,
+}
+
+impl Arbitrary for TestCase {
+    fn arbitrary(u: &mut Unstructured) -> Result<TestCase> {
+        Ok(TestCase {
+            // Edit restrictions, delay slots, flags and no-spill vregs
+            // send a function to the backtracking allocator, which
+            // `ion_checker` covers.
+            func: Func::arbitrary_with_options(
+                u,
+                &Options {
+                    reused_inputs: true,
+                    fixed_regs: true,
+                    clobbers: true,
+                    control_flow: true,
+                    reducible: false,
+                    block_params: true,
+                    always_local_uses: false,
+                    reserved_spillslots: true,
+                    constants: true,
+                    moves: true,
+                    excluded_regs: true,
+                    pref_regs: true,
+                    edit_restrictions: false,
+                    delay_slots: false,
+                    conditional_defs: true,
+                    equivalent_vregs: true,
+                    flags: false,
+                    multi_fixed_regs: true,
+                    late_positions: true,
+                    branch_defs: true,
+                    transfer_args: true,
+                    spill_weights: true,
+                    cold_blocks: true,
+                    soft_clobbers: true,
+                    spill_preferences: true,
+                    no_spill: false,
+                    reftypes: true,
+                    stack_operands: true,
+                    fixed_stack_operands: true,
+                    remat: true,
+                    pinned: false,
+                    non_ssa: true,
+                    debug_labels: true,
+                    live_vregs: 0,
+                    loop_depth: 0,
+                    fixed_reg_percent: 0,
+                    reuse_percent: 0,
+                    clobber_call_percent: 0,
+                },
+            )?,
+            save_callee_saves: bool::arbitrary(u)?,
+            interp_seed: u64::arbitrary(u)?,
+        })
+    }
+}
+
+fuzz_target!(|testcase: TestCase| {
+    let func = testcase.func;
+    let _ = env_logger::try_init();
+    log::debug!("func:\n{:?}", func);
+    let env = regalloc2::fuzzing::func::machine_env();
+    let options = regalloc2::RegallocOptions {
+        algorithm: regalloc2::AllocatorAlgorithm::Fast,
+        save_callee_saves: testcase.save_callee_saves,
+        report_blockparams: true,
+        ..Default::default()
+    };
+    let out = regalloc2::run_with_options(&func, &env, &options)
+        .unwrap_or_else(|e| panic!("regalloc did not succeed: {}", e));
+
+    let mut checker = Checker::new(&func);
+    checker.add_machine_env(&env);
+    checker.prepare(&out);
+    checker.run().expect("checker failed");
+
+    if let Err(e) = regalloc2::fuzzing::interp::run(&func, &out, testcase.interp_seed, 1000) {
+        panic!("interpreter found a mismatch: {}", e);
+    }
+});
@@ -1,27 +1,71 @@
 #![no_main]
+use libfuzzer_sys::arbitrary::{Arbitrary, Result, Unstructured};
 use libfuzzer_sys::fuzz_target;
-use libfuzzer_sys::arbitrary::{Arbitrary, Unstructured, Result};
 
-use regalloc2::fuzzing::func::{Func, Options};
 use regalloc2::checker::Checker;
+use regalloc2::fuzzing::func::{Func, Options};
 
 #[derive(Clone, Debug)]
 struct TestCase {
     func: Func,
+    chaos_seed: Option<u64>,
+    high_effort: bool,
+    pre_spill: bool,
+    spillslot_per_vreg: bool,
+    interp_seed: u64,
 }
 
 impl Arbitrary for TestCase {
     fn arbitrary(u: &mut Unstructured) -> Result<TestCase> {
         Ok(TestCase {
-            func: Func::arbitrary_with_options(u, &Options {
-                reused_inputs: true,
-                fixed_regs: true,
-                clobbers: true,
-                control_flow: true,
-                reducible: false,
-                block_params: true,
-                always_local_uses: false,
-            })?,
+            func: Func::arbitrary_with_options(
+                u,
+                &Options {
+                    reused_inputs: true,
+                    fixed_regs: true,
+                    clobbers: true,
+                    control_flow: true,
+                    reducible: false,
+                    block_params: true,
+                    always_local_uses: false,
+                    reserved_spillslots: true,
+                    constants: true,
+                    moves: true,
+                    excluded_regs: true,
+                    pref_regs: true,
+                    edit_restrictions: true,
+                    delay_slots: true,
+                    conditional_defs: true,
+                    equivalent_vregs: true,
+                    flags: true,
+                    multi_fixed_regs: true,
+                    late_positions: true,
+                    branch_defs: true,
+                    transfer_args: true,
+                    spill_weights: true,
+                    cold_blocks: true,
+                    soft_clobbers: true,
+                    spill_preferences: true,
+                    no_spill: true,
+                    reftypes: true,
+                    stack_operands: true,
+                    fixed_stack_operands: true,
+                    remat: true,
+                    pinned: true,
+                    non_ssa: true,
+                    debug_labels: true,
+                    live_vregs: 0,
+                    loop_depth: 0,
+                    fixed_reg_percent: 0,
+                    reuse_percent: 0,
+                    clobber_call_percent: 0,
+                },
+            )?,
+            chaos_seed: Option::<u64>::arbitrary(u)?,
+            high_effort: bool::arbitrary(u)?,
+            pre_spill: bool::arbitrary(u)?,
+            spillslot_per_vreg: bool::arbitrary(u)?,
+            interp_seed: u64::arbitrary(u)?,
         })
     }
 }
@@ -31,9 +75,31 @@ fuzz_target!(|testcase: TestCase| {
     let _ = env_logger::try_init();
     log::debug!("func:\n{:?}", func);
     let env = regalloc2::fuzzing::func::machine_env();
-    let out = regalloc2::ion::run(&func, &env).expect("regalloc did not succeed");
+    let options = regalloc2::RegallocOptions {
+        chaos_seed: testcase.chaos_seed,
+        pre_spill: testcase.pre_spill,
+        spillslot_per_vreg: testcase.spillslot_per_vreg,
+        report_blockparams: true,
+        ..if testcase.high_effort {
+            regalloc2::RegallocOptions::high_effort()
+        } else {
+            Default::default()
+        }
+    };
+    let out = match regalloc2::ion::run_with_options(&func, &env, &options) {
+        Ok(out) => out,
+        // Too many no-spill vregs may be live at once.
+        Err(regalloc2::RegAllocError::NoSpill(..)) => return,
+        Err(e) => panic!("regalloc did not succeed: {}", e),
+    };
 
     let mut checker = Checker::new(&func);
     checker.prepare(&out);
     checker.run().expect("checker failed");
+
+    // The checker takes some of the allocator's claims on trust;
+    // actually running the allocated program does not.
+    if let Err(e) = regalloc2::fuzzing::interp::run(&func, &out, testcase.interp_seed, 1000) {
+        panic!("interpreter found a mismatch: {}", e);
+    }
 });
@@ -0,0 +1,148 @@
+#![no_main]
+use libfuzzer_sys::arbitrary::{Arbitrary, Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+use regalloc2::checker::Checker;
+use regalloc2::fuzzing::func::{Func, Options};
+use regalloc2::{AllocatorAlgorithm, Function, Inst, RegAllocError, RegallocOptions};
+
+// Runs each function through differently-configured allocation
+// pipelines and compares their verdicts. Every pipeline must either
+// produce an allocation the checker accepts, and that computes the
+// same values when interpreted, or reject the function with the same
+// error, since whether a function can be allocated does not depend on
+// the allocator's heuristics. This catches bugs that a single
+// pipeline fuzzed against the checker cannot: e.g., an input
+// constraint one path misinterprets and rejects while another
+// handles it correctly.
+//
+// No-spill vregs are left out: whether there is room to keep them
+// in registers can depend on the heuristics.
+//
+// The reference is the single-pass allocator
+// (`AllocatorAlgorithm::Fast`), which shares none of the backtracking
+// allocator's splitting and eviction; against it run the backtracking
+// allocator in its usual configuration, and with its decisions
+// randomized and the function split into regions.
+
+#[derive(Clone, Debug)]
+struct TestCase {
+    func: Func,
+    chaos_seed: u64,
+    region_size: usize,
+    interp_seed: u64,
+}
+
+impl Arbitrary for TestCase {
+    fn arbitrary(u: &mut Unstructured) -> Result<TestCase> {
+        Ok(TestCase {
+            func: Func::arbitrary_with_options(
+                u,
+                &Options {
+                    reused_inputs: true,
+                    fixed_regs: true,
+                    clobbers: true,
+                    control_flow: true,
+                    reducible: false,
+                    block_params: true,
+                    always_local_uses: false,
+                    reserved_spillslots: true,
+                    constants: true,
+                    moves: true,
+                    excluded_regs: true,
+                    pref_regs: true,
+                    edit_restrictions: true,
+                    delay_slots: true,
+                    conditional_defs: true,
+                    equivalent_vregs: true,
+                    flags: true,
+                    multi_fixed_regs: true,
+                    late_positions: true,
+                    branch_defs: true,
+                    transfer_args: true,
+                    spill_weights: true,
+                    cold_blocks: true,
+                    soft_clobbers: true,
+                    spill_preferences: true,
+                    no_spill: false,
+                    reftypes: true,
+                    stack_operands: true,
+                    fixed_stack_operands: true,
+                    remat: true,
+                    pinned: true,
+                    non_ssa: true,
+                    debug_labels: true,
+                    live_vregs: 0,
+                    loop_depth: 0,
+                    fixed_reg_percent: 0,
+                    reuse_percent: 0,
+                    clobber_call_percent: 0,
+                },
+            )?,
+            chaos_seed: u64::arbitrary(u)?,
+            region_size: u.int_in_range(1..=64)?,
+            interp_seed: u64::arbitrary(u)?,
+        })
+    }
+}
+
+/// The outcome of one pipeline, reduced to what must agree across
+/// pipelines: for an allocation, the first instruction at which the
+/// interpreter found a value other than the original function's, if
+/// any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Verdict {
+    Allocated(Option<Inst>),
+    Rejected(std::mem::Discriminant<RegAllocError>),
+}
+
+fn verdict<F: Function>(func: &F, options: &RegallocOptions, interp_seed: u64) -> Verdict {
+    let env = regalloc2::fuzzing::func::machine_env();
+    match regalloc2::run_with_options(func, &env, options) {
+        Ok(out) => {
+            let mut checker = Checker::new(func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+            let mismatch = regalloc2::fuzzing::interp::run(func, &out, interp_seed, 1000).err();
+            Verdict::Allocated(mismatch.map(|m| m.inst))
+        }
+        Err(e @ RegAllocError::CheckerFailed(_)) => panic!("unexpected error: {}", e),
+        Err(e) => Verdict::Rejected(std::mem::discriminant(&e)),
+    }
+}
+
+fuzz_target!(|testcase: TestCase| {
+    let func = testcase.func;
+    let _ = env_logger::try_init();
+    log::debug!("func:\n{:?}", func);
+    let checked = RegallocOptions {
+        report_blockparams: true,
+        ..RegallocOptions::default()
+    };
+    let pipelines = [
+        RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            ..checked.clone()
+        },
+        checked.clone(),
+        RegallocOptions {
+            chaos_seed: Some(testcase.chaos_seed),
+            region_size: Some(testcase.region_size),
+            ..checked
+        },
+    ];
+    let verdicts: Vec<Verdict> = pipelines
+        .iter()
+        .map(|options| verdict(&func, options, testcase.interp_seed))
+        .collect();
+    for (options, v) in pipelines.iter().zip(&verdicts).skip(1) {
+        assert_eq!(
+            *v, verdicts[0],
+            "verdicts diverge from the reference with options {:?}",
+            options
+        );
+    }
+    if let Verdict::Allocated(Some(inst)) = verdicts[0] {
+        panic!("interpreter found a mismatch at {:?} in every pipeline", inst);
+    }
+});
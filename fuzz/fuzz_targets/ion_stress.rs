@@ -0,0 +1,53 @@
+#![no_main]
+use libfuzzer_sys::arbitrary::{Arbitrary, Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+use regalloc2::checker::Checker;
+use regalloc2::fuzzing::func::{Func, Options};
+
+// Functions aimed at one subsystem at a time by the generator's
+// knobs: many values live at once (eviction), deep loops (splitting
+// and move placement), dense fixed-reg and reuse constraints, and
+// calls that clobber most registers (spilling and spillslots).
+#[derive(Clone, Debug)]
+struct TestCase {
+    func: Func,
+    interp_seed: u64,
+}
+
+impl Arbitrary for TestCase {
+    fn arbitrary(u: &mut Unstructured) -> Result<TestCase> {
+        let opts = Options {
+            live_vregs: u.int_in_range(0..=64)?,
+            loop_depth: u.int_in_range(0..=4)?,
+            fixed_reg_percent: u.int_in_range(0..=100)?,
+            reuse_percent: u.int_in_range(0..=100)?,
+            clobber_call_percent: u.int_in_range(0..=100)?,
+            ..Options::default()
+        };
+        Ok(TestCase {
+            func: Func::arbitrary_with_options(u, &opts)?,
+            interp_seed: u64::arbitrary(u)?,
+        })
+    }
+}
+
+fuzz_target!(|testcase: TestCase| {
+    let func = testcase.func;
+    let _ = env_logger::try_init();
+    log::debug!("func:\n{:?}", func);
+    let env = regalloc2::fuzzing::func::machine_env();
+    let options = regalloc2::RegallocOptions {
+        report_blockparams: true,
+        ..Default::default()
+    };
+    let out = regalloc2::ion::run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+
+    let mut checker = Checker::new(&func);
+    checker.prepare(&out);
+    checker.run().expect("checker failed");
+
+    if let Err(e) = regalloc2::fuzzing::interp::run(&func, &out, testcase.interp_seed, 1000) {
+        panic!("interpreter found a mismatch: {}", e);
+    }
+});
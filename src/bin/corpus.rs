@@ -0,0 +1,86 @@
+//! Run the allocator over a corpus of functions and print quality
+//! metrics as CSV, one row per function, for comparing heuristic
+//! changes across commits.
+//!
+//! Usage: `corpus <dir> [iterations]`
+//!
+//! Each file in `<dir>` holds the raw input bytes from which
+//! `Func::arbitrary()` builds a function, i.e. the format of the
+//! `ion` and `ion_checker` fuzz corpora. Allocation time is the
+//! minimum over `iterations` runs (default 1).
+
+use arbitrary::{Arbitrary, Unstructured};
+use regalloc2::fuzzing::func::{machine_env, Func};
+use regalloc2::{AllocationKind, Edit, Function, RegallocOptions};
+use std::time::{Duration, Instant};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let dir = args.next().expect("usage: corpus <dir> [iterations]");
+    let iterations: usize = args
+        .next()
+        .map(|s| s.parse().expect("iterations must be a number"))
+        .unwrap_or(1);
+
+    let mut paths = std::fs::read_dir(&dir)
+        .expect("could not read corpus directory")
+        .map(|entry| entry.expect("could not read corpus entry").path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let env = machine_env();
+    let options = RegallocOptions::default();
+    println!("name,insts,blocks,vregs,moves,spills,reloads,spillslots,time_us");
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let bytes = std::fs::read(&path).expect("could not read corpus file");
+        let func = match Func::arbitrary(&mut Unstructured::new(&bytes[..])) {
+            Ok(func) => func,
+            Err(e) => {
+                eprintln!("{}: not a valid function: {:?}", name, e);
+                continue;
+            }
+        };
+
+        let mut time = Duration::from_secs(u64::MAX);
+        let mut out = None;
+        for _ in 0..std::cmp::max(iterations, 1) {
+            let start = Instant::now();
+            let result = regalloc2::ion::run_with_options(&func, &env, &options);
+            time = std::cmp::min(time, start.elapsed());
+            out = Some(result);
+        }
+        let out = match out.unwrap() {
+            Ok(out) => out,
+            Err(e) => {
+                eprintln!("{}: regalloc failed: {}", name, e);
+                continue;
+            }
+        };
+
+        let (mut moves, mut spills, mut reloads) = (0, 0, 0);
+        for (_, edit) in &out.edits {
+            if let &Edit::Move { from, to } = edit {
+                moves += 1;
+                match (from.kind(), to.kind()) {
+                    (AllocationKind::Reg, AllocationKind::Stack) => spills += 1,
+                    (AllocationKind::Stack, AllocationKind::Reg) => reloads += 1,
+                    _ => {}
+                }
+            }
+        }
+        println!(
+            "{},{},{},{},{},{},{},{},{}",
+            name,
+            func.insts(),
+            func.blocks(),
+            func.num_vregs(),
+            moves,
+            spills,
+            reloads,
+            out.num_spillslots,
+            time.as_micros()
+        );
+    }
+}
@@ -5,6 +5,7 @@ use smallvec::{smallvec, SmallVec};
 /// A conceptually infinite-length bitvector that allows bitwise operations and
 /// iteration over set bits efficiently.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BitVec {
     bits: SmallVec<[u64; 2]>,
 }
@@ -49,7 +50,7 @@ impl BitVec {
     }
 
     #[inline(always)]
-    pub fn get(&mut self, idx: usize) -> bool {
+    pub fn get(&self, idx: usize) -> bool {
         let word = idx / BITS_PER_WORD;
         let bit = idx % BITS_PER_WORD;
         if word >= self.bits.len() {
@@ -59,15 +60,37 @@ impl BitVec {
         }
     }
 
+    /// Grow to at least `words` words, so that word-at-a-time
+    /// operations over that many words need no bounds checks.
+    #[inline(always)]
+    fn ensure_words(&mut self, words: usize) {
+        if words > self.bits.len() {
+            self.ensure_idx(words - 1);
+        }
+    }
+
     pub fn or(&mut self, other: &Self) {
-        if other.bits.is_empty() {
-            return;
+        self.ensure_words(other.bits.len());
+        or_words(&mut self.bits[..other.bits.len()], &other.bits[..]);
+    }
+
+    /// Union all of `others` into this set. This grows the set once,
+    /// to the longest of them, and then ORs in each a whole word at a
+    /// time.
+    pub fn or_all(&mut self, others: &[&Self]) {
+        let words = others.iter().map(|other| other.bits.len()).max();
+        self.ensure_words(words.unwrap_or(0));
+        for other in others {
+            or_words(&mut self.bits[..other.bits.len()], &other.bits[..]);
         }
-        let last_idx = other.bits.len() - 1;
-        self.ensure_idx(last_idx);
+    }
 
-        for (self_word, other_word) in self.bits.iter_mut().zip(other.bits.iter()) {
-            *self_word |= *other_word;
+    /// Remove all of the bits set in `other` from this set, a whole
+    /// word at a time.
+    pub fn and_not(&mut self, other: &Self) {
+        let words = std::cmp::min(self.bits.len(), other.bits.len());
+        for (self_word, other_word) in self.bits[..words].iter_mut().zip(&other.bits[..words]) {
+            *self_word &= !*other_word;
         }
     }
 
@@ -91,6 +114,16 @@ impl BitVec {
     }
 }
 
+/// OR `src` into `dst`, which must have the same length. Equal-length
+/// slices let the compiler drop the bounds checks and vectorize the
+/// loop.
+#[inline(always)]
+fn or_words(dst: &mut [u64], src: &[u64]) {
+    for (dst_word, src_word) in dst.iter_mut().zip(src) {
+        *dst_word |= *src_word;
+    }
+}
+
 pub struct SetBitsIter<'a> {
     words: &'a [u64],
     word_idx: usize,
@@ -136,4 +169,42 @@ mod test {
 
         assert_eq!(sum, checksum);
     }
+
+    #[test]
+    fn test_bulk_ops() {
+        let mut a = BitVec::new();
+        let mut b = BitVec::new();
+        let mut c = BitVec::new();
+        for i in 0..300 {
+            if i % 3 == 0 {
+                a.set(i, true);
+            }
+            if i % 5 == 0 {
+                b.set(i * 2, true);
+            }
+        }
+        c.set(1000, true);
+
+        let mut union = BitVec::new();
+        union.or_all(&[&a, &b, &c]);
+        let mut expected = a.clone();
+        expected.or(&b);
+        expected.or(&c);
+        assert_eq!(
+            union.iter().collect::<Vec<_>>(),
+            expected.iter().collect::<Vec<_>>()
+        );
+        assert!(union.get(1000) && union.get(590) && union.get(297));
+
+        union.and_not(&a);
+        for bit in union.iter() {
+            assert!(!a.get(bit));
+            assert!(b.get(bit) || c.get(bit));
+        }
+        assert!(union.get(1000) && union.get(10) && !union.get(30));
+
+        union.and_not(&b);
+        union.and_not(&c);
+        assert_eq!(union.iter().next(), None);
+    }
 }
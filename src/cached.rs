@@ -0,0 +1,347 @@
+//! A `Function` adapter that snapshots the data the allocator queries
+//! most often into flat arrays, for embedders whose own `Function`
+//! computes it on demand (e.g. by walking their IR, or by decoding
+//! instructions). The allocator asks for a block's successors and
+//! predecessors, its instructions, and each instruction's operands
+//! and clobbers many times over across its passes; wrapping the
+//! function in a `CachedFunction` computes each of these once.
+//!
+//! ```ignore
+//! let cached = CachedFunction::new(&func);
+//! let output = regalloc2::run(&cached, &env)?;
+//! ```
+
+use crate::bitvec::BitVec;
+use crate::{
+    Block, Function, Inst, InstRange, Operand, PReg, RegClass, RematKind, SpillPreference,
+    SpillWeight, VReg,
+};
+
+/// Variable-length lists, one per index, stored end to end.
+struct FlatLists<T> {
+    items: Vec<T>,
+    /// The end of each list in `items`; each starts where the
+    /// previous one ends.
+    ends: Vec<u32>,
+}
+
+impl<T: Copy> FlatLists<T> {
+    fn new() -> Self {
+        FlatLists {
+            items: vec![],
+            ends: vec![],
+        }
+    }
+
+    fn push(&mut self, list: &[T]) {
+        self.items.extend_from_slice(list);
+        self.ends.push(self.items.len() as u32);
+    }
+
+    fn get(&self, index: usize) -> &[T] {
+        let start = if index == 0 {
+            0
+        } else {
+            self.ends[index - 1] as usize
+        };
+        &self.items[start..self.ends[index] as usize]
+    }
+}
+
+/// A `Function` that answers the CFG queries (`block_insns`,
+/// `block_succs`, `block_preds`, `block_params`), the operand queries
+/// (`inst_operands`, `visit_operands`) and the clobber queries
+/// (`inst_clobbers`, `inst_soft_clobbers`), together with `is_branch`
+/// and `is_ret`, from a snapshot taken on construction. Every other
+/// query is passed through to the wrapped function, which must not
+/// change while it is wrapped.
+pub struct CachedFunction<'a, F: Function> {
+    func: &'a F,
+    insts: usize,
+    entry_block: Block,
+    num_vregs: usize,
+    block_insns: Vec<InstRange>,
+    block_succs: FlatLists<Block>,
+    block_preds: FlatLists<Block>,
+    block_params: FlatLists<VReg>,
+    operands: FlatLists<Operand>,
+    clobbers: FlatLists<PReg>,
+    soft_clobbers: FlatLists<PReg>,
+    branches: BitVec,
+    rets: BitVec,
+}
+
+impl<'a, F: Function> CachedFunction<'a, F> {
+    /// Snapshot `func`. Operands are read with `visit_operands()`, so
+    /// `func` need not keep them materialized.
+    pub fn new(func: &'a F) -> Self {
+        let mut cached = CachedFunction {
+            func,
+            insts: func.insts(),
+            entry_block: func.entry_block(),
+            num_vregs: func.num_vregs(),
+            block_insns: Vec::with_capacity(func.blocks()),
+            block_succs: FlatLists::new(),
+            block_preds: FlatLists::new(),
+            block_params: FlatLists::new(),
+            operands: FlatLists::new(),
+            clobbers: FlatLists::new(),
+            soft_clobbers: FlatLists::new(),
+            branches: BitVec::with_capacity(func.insts()),
+            rets: BitVec::with_capacity(func.insts()),
+        };
+        for block in 0..func.blocks() {
+            let block = Block::new(block);
+            cached.block_insns.push(func.block_insns(block));
+            cached.block_succs.push(func.block_succs(block));
+            cached.block_preds.push(func.block_preds(block));
+            cached.block_params.push(func.block_params(block));
+        }
+        for inst in 0..func.insts() {
+            let inst = Inst::new(inst);
+            func.visit_operands(inst, &mut |_, op| cached.operands.items.push(op));
+            cached
+                .operands
+                .ends
+                .push(cached.operands.items.len() as u32);
+            cached.clobbers.push(func.inst_clobbers(inst));
+            cached.soft_clobbers.push(func.inst_soft_clobbers(inst));
+            cached.branches.set(inst.index(), func.is_branch(inst));
+            cached.rets.set(inst.index(), func.is_ret(inst));
+        }
+        cached
+    }
+
+    /// The wrapped function.
+    pub fn inner(&self) -> &'a F {
+        self.func
+    }
+}
+
+impl<'a, F: Function> Function for CachedFunction<'a, F> {
+    fn insts(&self) -> usize {
+        self.insts
+    }
+
+    fn blocks(&self) -> usize {
+        self.block_insns.len()
+    }
+
+    fn entry_block(&self) -> Block {
+        self.entry_block
+    }
+
+    fn block_insns(&self, block: Block) -> InstRange {
+        self.block_insns[block.index()]
+    }
+
+    fn block_succs(&self, block: Block) -> &[Block] {
+        self.block_succs.get(block.index())
+    }
+
+    fn block_preds(&self, block: Block) -> &[Block] {
+        self.block_preds.get(block.index())
+    }
+
+    fn block_params(&self, block: Block) -> &[VReg] {
+        self.block_params.get(block.index())
+    }
+
+    fn is_cold_block(&self, block: Block) -> bool {
+        self.func.is_cold_block(block)
+    }
+
+    fn is_call(&self, insn: Inst) -> bool {
+        self.func.is_call(insn)
+    }
+
+    fn is_ret(&self, insn: Inst) -> bool {
+        self.rets.get(insn.index())
+    }
+
+    fn is_branch(&self, insn: Inst) -> bool {
+        self.branches.get(insn.index())
+    }
+
+    fn branch_has_delay_slot(&self, insn: Inst) -> bool {
+        self.func.branch_has_delay_slot(insn)
+    }
+
+    fn groups_fixed_uses(&self, insn: Inst) -> bool {
+        self.func.groups_fixed_uses(insn)
+    }
+
+    fn is_safepoint(&self, insn: Inst) -> bool {
+        self.func.is_safepoint(insn)
+    }
+
+    fn is_move(&self, insn: Inst) -> Option<(VReg, VReg)> {
+        self.func.is_move(insn)
+    }
+
+    fn equivalent_vreg(&self, vreg: VReg) -> Option<VReg> {
+        self.func.equivalent_vreg(vreg)
+    }
+
+    fn no_edits_after(&self, insn: Inst) -> bool {
+        self.func.no_edits_after(insn)
+    }
+
+    fn writes_flags(&self, insn: Inst) -> bool {
+        self.func.writes_flags(insn)
+    }
+
+    fn reads_flags(&self, insn: Inst) -> bool {
+        self.func.reads_flags(insn)
+    }
+
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        self.operands.get(insn.index())
+    }
+
+    fn visit_operands(&self, insn: Inst, f: &mut dyn FnMut(usize, Operand)) {
+        for (i, &op) in self.operands.get(insn.index()).iter().enumerate() {
+            f(i, op);
+        }
+    }
+
+    fn inst_clobbers(&self, insn: Inst) -> &[PReg] {
+        self.clobbers.get(insn.index())
+    }
+
+    fn inst_call_conv(&self, insn: Inst) -> Option<usize> {
+        self.func.inst_call_conv(insn)
+    }
+
+    fn inst_soft_clobbers(&self, insn: Inst) -> &[PReg] {
+        self.soft_clobbers.get(insn.index())
+    }
+
+    fn num_vregs(&self) -> usize {
+        self.num_vregs
+    }
+
+    fn is_ssa(&self) -> bool {
+        self.func.is_ssa()
+    }
+
+    fn debug_vreg_name(&self, vreg: VReg) -> Option<&str> {
+        self.func.debug_vreg_name(vreg)
+    }
+
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        self.func.debug_value_labels()
+    }
+
+    fn spill_weight(&self, vreg: VReg) -> SpillWeight {
+        self.func.spill_weight(vreg)
+    }
+
+    fn spill_preference(&self, vreg: VReg) -> SpillPreference {
+        self.func.spill_preference(vreg)
+    }
+
+    fn no_spill(&self, vreg: VReg) -> bool {
+        self.func.no_spill(vreg)
+    }
+
+    fn pinned_vreg(&self, vreg: VReg) -> Option<PReg> {
+        self.func.pinned_vreg(vreg)
+    }
+
+    fn is_reftype(&self, vreg: VReg) -> bool {
+        self.func.is_reftype(vreg)
+    }
+
+    fn derived_ref_base(&self, vreg: VReg) -> Option<VReg> {
+        self.func.derived_ref_base(vreg)
+    }
+
+    fn is_rematerializable(&self, vreg: VReg) -> Option<RematKind> {
+        self.func.is_rematerializable(vreg)
+    }
+
+    fn spillslot_size(&self, regclass: RegClass, for_vreg: VReg) -> usize {
+        self.func.spillslot_size(regclass, for_vreg)
+    }
+
+    fn multi_spillslot_named_by_last_slot(&self) -> bool {
+        self.func.multi_spillslot_named_by_last_slot()
+    }
+
+    fn num_reserved_spillslots(&self) -> usize {
+        self.func.num_reserved_spillslots()
+    }
+}
+
+#[cfg(all(test, feature = "checker"))]
+mod test {
+    use super::*;
+    use crate::fuzzing::func::{machine_env, FuncBuilder};
+    use crate::test::VisitOnly;
+    use crate::{RegallocOptions, SpillWeight};
+
+    #[test]
+    fn test_cached_function() {
+        // A branch with an argument into a block with a parameter,
+        // and instructions with clobbers and soft clobbers.
+        let env = machine_env();
+        let (p0, p1) = (PReg::new(0, RegClass::Int), PReg::new(1, RegClass::Int));
+        let mut builder = FuncBuilder::new();
+        let (entry, exit) = (builder.block(), builder.block());
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .params(exit, &[v2])
+            .spill_weight(v1, SpillWeight::Scale(4.0))
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v1)], &[p0])
+            .soft_clobbers(entry, &[p1])
+            .branch(entry, &[exit], &[v0])
+            .inst(exit, &[Operand::reg_use(v2), Operand::reg_use(v1)], &[p1])
+            .ret(exit);
+        let func = builder.build().unwrap();
+
+        // The snapshot reads operands through `visit_operands()`, and
+        // then also answers `inst_operands()`.
+        let visit_only = VisitOnly(&func);
+        let cached = CachedFunction::new(&visit_only);
+        for inst in (0..func.insts()).map(Inst::new) {
+            assert_eq!(cached.inst_operands(inst), func.inst_operands(inst));
+            assert_eq!(cached.inst_clobbers(inst), func.inst_clobbers(inst));
+        }
+        for block in (0..func.blocks()).map(Block::new) {
+            assert_eq!(cached.block_succs(block), func.block_succs(block));
+            assert_eq!(cached.block_preds(block), func.block_preds(block));
+            assert_eq!(cached.block_params(block), func.block_params(block));
+        }
+
+        // Queries it does not cache pass through unchanged.
+        let checked = RegallocOptions {
+            report_blockparams: true,
+            ..RegallocOptions::default()
+        };
+        let options = RegallocOptions {
+            validate_output: true,
+            ..checked.clone()
+        };
+        for (cached, uncached) in [
+            (
+                crate::run_with_options(&cached, &env, &options),
+                crate::run_with_options(&visit_only, &env, &checked),
+            ),
+            (
+                crate::run_with_options(&CachedFunction::new(&func), &env, &options),
+                crate::run_with_options(&func, &env, &checked),
+            ),
+        ] {
+            let out = cached.expect("regalloc did not succeed");
+            let expected = uncached.unwrap();
+            assert_eq!(out.allocs, expected.allocs);
+            assert_eq!(out.edits, expected.edits);
+            assert_eq!(
+                out.preserved_soft_clobbers,
+                expected.preserved_soft_clobbers
+            );
+        }
+    }
+}
@@ -18,7 +18,8 @@ pub struct CFGInfo {
     pub vreg_def_blockparam: Vec<(Block, u32)>,
     /// For each block, the first instruction.
     pub block_entry: Vec<ProgPoint>,
-    /// For each block, the last instruction.
+    /// For each block, the last point of the last instruction (the
+    /// late half of its `After` point).
     pub block_exit: Vec<ProgPoint>,
     /// For each block, what is its position in its successor's preds,
     /// if it has a single successor?
@@ -29,6 +30,8 @@ pub struct CFGInfo {
     /// just one value per block and always know any block's position in its
     /// successors' preds lists.)
     pub pred_pos: Vec<usize>,
+    /// For each block, the number of natural loops that contain it.
+    pub loop_depth: Vec<u32>,
 }
 
 impl CFGInfo {
@@ -55,17 +58,14 @@ impl CFGInfo {
             }
             for inst in f.block_insns(block).iter() {
                 insn_block[inst.index()] = block;
-                for operand in f.inst_operands(inst) {
-                    match operand.kind() {
-                        OperandKind::Def => {
-                            vreg_def_inst[operand.vreg().vreg()] = inst;
-                        }
-                        _ => {}
+                f.visit_operands(inst, &mut |_, operand| {
+                    if operand.kind() == OperandKind::Def {
+                        vreg_def_inst[operand.vreg().vreg()] = inst;
                     }
-                }
+                });
             }
             block_entry[block.index()] = ProgPoint::before(f.block_insns(block).first());
-            block_exit[block.index()] = ProgPoint::after(f.block_insns(block).last());
+            block_exit[block.index()] = ProgPoint::after(f.block_insns(block).last()).late();
 
             if f.block_preds(block).len() > 1 {
                 for (i, &pred) in f.block_preds(block).iter().enumerate() {
@@ -82,6 +82,35 @@ impl CFGInfo {
             }
         }
 
+        // Compute loop depths: for each loop header (a block that
+        // dominates one of its preds), walk backward from the
+        // backedges to the header, marking the loop body.
+        let mut loop_depth = vec![0; f.blocks()];
+        let mut in_loop = vec![Block::invalid(); f.blocks()];
+        let mut worklist = vec![];
+        for header in 0..f.blocks() {
+            let header = Block::new(header);
+            worklist.extend(
+                f.block_preds(header)
+                    .iter()
+                    .cloned()
+                    .filter(|&pred| domtree::dominates(&domtree[..], header, pred)),
+            );
+            if worklist.is_empty() {
+                continue;
+            }
+            in_loop[header.index()] = header;
+            loop_depth[header.index()] += 1;
+            while let Some(block) = worklist.pop() {
+                if in_loop[block.index()] == header {
+                    continue;
+                }
+                in_loop[block.index()] = header;
+                loop_depth[block.index()] += 1;
+                worklist.extend(f.block_preds(block).iter().cloned());
+            }
+        }
+
         CFGInfo {
             postorder,
             domtree,
@@ -91,6 +120,7 @@ impl CFGInfo {
             block_entry,
             block_exit,
             pred_pos,
+            loop_depth,
         }
     }
 
@@ -108,3 +138,29 @@ impl CFGInfo {
         self.pred_pos[block.index()]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzzing::func::FuncBuilder;
+
+    #[test]
+    fn test_loop_depth() {
+        // An outer loop (b1-b4) around an inner one (b2-b3), then an
+        // exit: each block is as deep as the loops containing it.
+        let mut builder = FuncBuilder::new();
+        let blocks: Vec<Block> = (0..7).map(|_| builder.block()).collect();
+        let b = |i: usize| blocks[i];
+        builder
+            .branch(b(0), &[b(1)], &[])
+            .branch(b(1), &[b(2)], &[])
+            .branch(b(2), &[b(3), b(4)], &[])
+            .branch(b(3), &[b(2)], &[])
+            .branch(b(4), &[b(5), b(6)], &[])
+            .branch(b(5), &[b(1)], &[])
+            .ret(b(6));
+        let func = builder.build().unwrap();
+        let cfginfo = CFGInfo::new(&func);
+        assert_eq!(cfginfo.loop_depth, vec![0, 1, 2, 2, 1, 1, 0]);
+    }
+}
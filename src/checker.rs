@@ -30,27 +30,68 @@
 //!
 //!       A[alloc_d] := A[alloc_s]
 //!
+//!   - `Edit::DefineConstant` inserted by RA: [ alloc_d := const V_i ]
+//!
+//!       A[alloc_d] := `V_i`
+//!
+//!     (N.B.: V_i's def must have the `Constant` policy; its defining
+//!     instruction may have been elided, with a `none` allocation.)
+//!
+//!   - `Edit::Remat` inserted by RA:       [ alloc_d := remat V_i ]
+//!
+//!       A[alloc_d] := `V_i`
+//!
+//!     (N.B.: `Function::is_rematerializable` must give V_i the
+//!     edit's kind.)
+//!
 //!   - phi-node          [ V_i := phi block_j:V_j, block_k:V_k, ... ]
 //!     with allocations  [ A_i := phi block_j:A_j, block_k:A_k, ... ]
 //!     (N.B.: phi-nodes are not semantically present in the final
-//!      machine code, but we include their allocations so that this
-//!      checker can work)
+//!     machine code, but we include their allocations so that this
+//!     checker can work)
 //!
 //!       A[A_i] := meet(A_j, A_k, ...)
 //!
 //!   - statement in pre-regalloc function [ V_i := op V_j, V_k, ... ]
 //!     with allocated form                [ A_i := op A_j, A_k, ... ]
 //!
-//!       A[A_i] := `V_i`
+//!       A[A_i] := `V_i`; A[a] := bottom, for each other `a` holding `V_i`
 //!
 //!     In other words, a statement, even after allocation, generates
 //!     a symbol that corresponds to its original virtual-register
-//!     def.
+//!     def, and any other copy of the vreg's old value is stale (a
+//!     vreg may be defined more than once in a non-SSA function).
+//!     (N.B.: a `Mod` operand is both an arg, read before the
+//!     statement, and a def, written after it.)
 //!
 //!     (N.B.: moves in pre-regalloc function fall into this last case
 //!     -- they are "just another operation" and generate a new
 //!     symbol)
 //!
+//!   - safepoint (see `Function::is_safepoint`), after its statement,
+//!     with stackmap [ S_1, S_2, ... ] and derived pairs
+//!     [ (D_1, B_1), ... ]
+//!
+//!       A[a] := bottom, for each `a` not in [ S_j ] or [ D_k ] holding a reference
+//!
+//!     where each `S_j` must hold a reference-typed symbol, each
+//!     `D_k` a symbol derived from the one in `B_k`, which is among
+//!     [ S_j ], and the allocations the statement defines are left
+//!     alone. (N.B.: this
+//!     models a moving collector, which updates the references it
+//!     finds and leaves any other copy stale.)
+//!
+//!   - clobbers of a statement, once its uses at `Before` are read,
+//!     [ R_1, R_2, ... ]
+//!
+//!       A[R_j] := bottom, for each `R_j` the statement does not define
+//!
+//!     where the clobbers are the statement's `Function::inst_clobbers`,
+//!     those of its calling convention if a `MachineEnv` was given,
+//!     and its soft clobbers other than those the allocator reports
+//!     as preserved. (N.B.: a use at a later position, see
+//!     `OperandPos`, must not be in a clobbered register.)
+//!
 //! At control-flow join points, the symbols meet using a very simple
 //! lattice meet-function: two different symbols in the same
 //! allocation meet to "conflicted"; otherwise, the symbol meets with
@@ -65,26 +106,57 @@
 
 #![allow(dead_code)]
 
+use crate::liveness::inst_operands;
 use crate::{
-    Allocation, AllocationKind, Block, Edit, Function, Inst, InstPosition, Operand, OperandKind,
-    OperandPolicy, OperandPos, Output, ProgPoint, VReg,
+    Allocation, AllocationKind, Block, Edit, Function, Inst, MachineEnv, Operand, OperandKind,
+    OperandPolicy, OperandPos, Output, PReg, ProgPoint, RegClass, RematKind, SpillSlot, VReg,
 };
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::default::Default;
 use std::hash::Hash;
 use std::result::Result;
 
 use log::debug;
 
-/// A set of errors detected by the regalloc checker.
+/// A set of errors detected by the regalloc checker. With the
+/// `enable-serde` feature, it can be serialized (e.g. to JSON) for
+/// automated triage of fuzz findings.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CheckerErrors {
     errors: Vec<CheckerError>,
+    /// The context of each error, in the same order.
+    reports: Vec<CheckerReport>,
+    /// The `Function::debug_vreg_name` of each named vreg that the
+    /// errors mention.
+    vreg_names: Vec<(VReg, String)>,
+}
+
+/// The context in which the checker found an error, where it has
+/// one: where the error is, and for an operand that reads the wrong
+/// value, how that value got there.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckerReport {
+    /// The program point of the error.
+    pub point: Option<ProgPoint>,
+    /// The index of the operand the error concerns, within its
+    /// instruction's operands.
+    pub operand: Option<usize>,
+    /// The value the operand's allocation should hold.
+    pub expected: Option<CheckerValue>,
+    /// The value it holds instead.
+    pub found: Option<CheckerValue>,
+    /// The edits that carried the found value into the allocation, in
+    /// program order: back along one path to the def, blockparam or
+    /// constant that produced it, as far as that can be traced.
+    pub edits: Vec<(ProgPoint, Edit)>,
 }
 
 /// A single error detected by the regalloc checker.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CheckerError {
     MissingAllocation {
         inst: Inst,
@@ -121,12 +193,256 @@ pub enum CheckerError {
         op: Operand,
         alloc: Allocation,
     },
+    AllocationIsNotStack {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    AllocationIsNotFixedStack {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    AllocationIsExcluded {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
     AllocationIsNotReuse {
         inst: Inst,
         op: Operand,
         alloc: Allocation,
         expected_alloc: Allocation,
     },
+    DefineConstantOfNonConstant {
+        vreg: VReg,
+        alloc: Allocation,
+    },
+    RematOfNonRematerializable {
+        vreg: VReg,
+        kind: RematKind,
+        alloc: Allocation,
+    },
+    AllocationInterference {
+        alloc: Allocation,
+        point: ProgPoint,
+        vregs: (VReg, VReg),
+    },
+    SpillSlotClassMismatch {
+        alloc: Allocation,
+        class: RegClass,
+    },
+    SpillSlotOutOfRange {
+        alloc: Allocation,
+        num_spillslots: usize,
+    },
+    SpillSlotOverlap {
+        allocs: (Allocation, Allocation),
+    },
+    NonRefInStackmap {
+        inst: Inst,
+        alloc: Allocation,
+    },
+    DerivedRefWithoutBase {
+        inst: Inst,
+        alloc: Allocation,
+    },
+}
+
+impl CheckerErrors {
+    /// The individual errors found, in no particular order.
+    pub fn errors(&self) -> &[CheckerError] {
+        &self.errors[..]
+    }
+
+    /// The context of each error, in the same order as `errors()`.
+    pub fn reports(&self) -> &[CheckerReport] {
+        &self.reports[..]
+    }
+}
+
+impl std::fmt::Display for CheckerErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} error(s)", self.errors.len())?;
+        for error in &self.errors {
+            write!(f, "\n  {}", error)?;
+        }
+        if !self.vreg_names.is_empty() {
+            write!(f, "\n  where")?;
+            for (i, (vreg, name)) in self.vreg_names.iter().enumerate() {
+                let sep = if i == 0 { " " } else { ", " };
+                write!(f, "{}{} is {}", sep, vreg, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CheckerErrors {}
+
+impl CheckerError {
+    /// The vregs this error mentions.
+    fn vregs(&self) -> Vec<VReg> {
+        match self {
+            Self::MissingAllocation { op, .. }
+            | Self::UnknownValueInAllocation { op, .. }
+            | Self::ConflictedValueInAllocation { op, .. }
+            | Self::PolicyViolated { op, .. }
+            | Self::AllocationIsNotReg { op, .. }
+            | Self::AllocationIsNotFixedReg { op, .. }
+            | Self::AllocationIsNotStack { op, .. }
+            | Self::AllocationIsNotFixedStack { op, .. }
+            | Self::AllocationIsExcluded { op, .. }
+            | Self::AllocationIsNotReuse { op, .. } => vec![op.vreg()],
+            Self::IncorrectValueInAllocation { op, actual, .. } => vec![op.vreg(), *actual],
+            Self::DefineConstantOfNonConstant { vreg, .. }
+            | Self::RematOfNonRematerializable { vreg, .. } => vec![*vreg],
+            Self::AllocationInterference { vregs, .. } => vec![vregs.0, vregs.1],
+            Self::SpillSlotClassMismatch { .. }
+            | Self::SpillSlotOutOfRange { .. }
+            | Self::SpillSlotOverlap { .. }
+            | Self::NonRefInStackmap { .. }
+            | Self::DerivedRefWithoutBase { .. } => vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for CheckerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::MissingAllocation { inst, op } => {
+                write!(f, "inst{}: operand {} has no allocation", inst.index(), op)
+            }
+            Self::UnknownValueInAllocation { inst, op, alloc } => write!(
+                f,
+                "inst{}: operand {} reads {}, which holds no known value",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::ConflictedValueInAllocation { inst, op, alloc } => write!(
+                f,
+                "inst{}: operand {} reads {}, which holds different values on different paths",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::IncorrectValueInAllocation {
+                inst,
+                op,
+                alloc,
+                actual,
+            } => write!(
+                f,
+                "inst{}: operand {} reads {}, which holds {} instead",
+                inst.index(),
+                op,
+                alloc,
+                actual
+            ),
+            Self::PolicyViolated { inst, op, alloc } => write!(
+                f,
+                "inst{}: allocation {} violates the policy of operand {}",
+                inst.index(),
+                alloc,
+                op
+            ),
+            Self::AllocationIsNotReg { inst, op, alloc } => write!(
+                f,
+                "inst{}: operand {} needs a register but was given {}",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotFixedReg { inst, op, alloc } => write!(
+                f,
+                "inst{}: operand {} needs its fixed register but was given {}",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotStack { inst, op, alloc } => write!(
+                f,
+                "inst{}: operand {} needs a spillslot but was given {}",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotFixedStack { inst, op, alloc } => write!(
+                f,
+                "inst{}: operand {} needs its fixed stack slot but was given {}",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsExcluded { inst, op, alloc } => write!(
+                f,
+                "inst{}: operand {} was given excluded register {}",
+                inst.index(),
+                op,
+                alloc
+            ),
+            Self::AllocationIsNotReuse {
+                inst,
+                op,
+                alloc,
+                expected_alloc,
+            } => write!(
+                f,
+                "inst{}: operand {} was given {} but must reuse its input's {}",
+                inst.index(),
+                op,
+                alloc,
+                expected_alloc
+            ),
+            Self::DefineConstantOfNonConstant { vreg, alloc } => write!(
+                f,
+                "constant materialized into {} for {}, which is not a constant",
+                alloc, vreg
+            ),
+            Self::RematOfNonRematerializable { vreg, kind, alloc } => write!(
+                f,
+                "{} rematerialized into {} as {:?}, which is not how it is rematerialized",
+                vreg, alloc, kind
+            ),
+            Self::AllocationInterference {
+                alloc,
+                point,
+                vregs,
+            } => write!(
+                f,
+                "{:?}: {} holds both {} and {}",
+                point, alloc, vregs.0, vregs.1
+            ),
+            Self::SpillSlotClassMismatch { alloc, class } => {
+                write!(f, "{} holds a value of class {:?}", alloc, class)
+            }
+            Self::SpillSlotOutOfRange {
+                alloc,
+                num_spillslots,
+            } => write!(
+                f,
+                "{} extends beyond the {} spillslots of the frame",
+                alloc, num_spillslots
+            ),
+            Self::SpillSlotOverlap { allocs } => {
+                write!(f, "spillslots {} and {} overlap", allocs.0, allocs.1)
+            }
+            Self::NonRefInStackmap { inst, alloc } => write!(
+                f,
+                "inst{}: stackmap lists {}, which holds no reference",
+                inst.index(),
+                alloc
+            ),
+            Self::DerivedRefWithoutBase { inst, alloc } => write!(
+                f,
+                "inst{}: stackmap lists the derived reference in {} without a slot holding \
+                 its base",
+                inst.index(),
+                alloc
+            ),
+        }
+    }
 }
 
 /// Abstract state for an allocation.
@@ -135,7 +451,8 @@ pub enum CheckerError {
 /// number of mutually unordered value-points in between, one per real
 /// or virtual register. Any two different registers meet to \bot.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum CheckerValue {
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CheckerValue {
     /// "top" value: this storage slot has no known value.
     Unknown,
     /// "bottom" value: this storage slot has a conflicted value.
@@ -254,10 +571,11 @@ impl CheckerState {
         Ok(())
     }
 
-    /// Check an instruction against this state. This must be called
-    /// twice: once with `InstPosition::Before`, and once with
-    /// `InstPosition::After` (after updating state with defs).
-    fn check(&self, pos: InstPosition, checkinst: &CheckerInst) -> Result<(), CheckerError> {
+    /// Check an instruction's uses at `pos` against this state. This
+    /// must be called at each position in turn, after updating state
+    /// with the defs at that position. An error comes with the index
+    /// of its operand.
+    fn check(&self, pos: OperandPos, checkinst: &CheckerInst) -> Result<(), (usize, CheckerError)> {
         match checkinst {
             &CheckerInst::Op {
                 inst,
@@ -265,31 +583,21 @@ impl CheckerState {
                 ref allocs,
                 ..
             } => {
-                // Skip Use-checks at the After point if there are any
-                // reused inputs: the Def which reuses the input
-                // happens early.
-                let has_reused_input = operands
-                    .iter()
-                    .any(|op| matches!(op.policy(), OperandPolicy::Reuse(_)));
-                if has_reused_input && pos == InstPosition::After {
-                    return Ok(());
-                }
-
                 // For each operand, check (i) that the allocation
                 // contains the expected vreg, and (ii) that it meets
                 // the requirements of the OperandPolicy.
-                for (op, alloc) in operands.iter().zip(allocs.iter()) {
-                    let is_here = match (op.pos(), pos) {
-                        (OperandPos::Before, InstPosition::Before)
-                        | (OperandPos::Both, InstPosition::Before) => true,
-                        (OperandPos::After, InstPosition::After)
-                        | (OperandPos::Both, InstPosition::After) => true,
-                        _ => false,
-                    };
-                    if !is_here {
+                for (i, (op, alloc)) in operands.iter().zip(allocs.iter()).enumerate() {
+                    if op.pos() != pos || op.kind() == OperandKind::Def {
                         continue;
                     }
-                    if op.kind() == OperandKind::Def {
+                    // A reused input shares its location with the
+                    // reusing def, which may overwrite it by the time
+                    // a late use would be read.
+                    if pos >= OperandPos::After
+                        && operands
+                            .iter()
+                            .any(|def| def.policy() == OperandPolicy::Reuse(i))
+                    {
                         continue;
                     }
 
@@ -302,7 +610,8 @@ impl CheckerState {
                         "checker: checkinst {:?}: op {:?}, alloc {:?}, checker value {:?}",
                         checkinst, op, alloc, val
                     );
-                    self.check_val(inst, *op, *alloc, val, allocs)?;
+                    self.check_val(inst, *op, *alloc, val, allocs)
+                        .map_err(|e| (i, e))?;
                 }
             }
             _ => {}
@@ -310,10 +619,11 @@ impl CheckerState {
         Ok(())
     }
 
-    /// Update according to instruction.
-    fn update(&mut self, checkinst: &CheckerInst) {
+    /// Update according to instruction. `reftypes` are the
+    /// reference-typed vregs, whose values a safepoint moves.
+    fn update(&mut self, checkinst: &CheckerInst, reftypes: &HashSet<VReg>) {
         match checkinst {
-            &CheckerInst::Move { into, from } => {
+            &CheckerInst::Move { into, from, .. } => {
                 let val = self
                     .allocations
                     .get(&from)
@@ -325,17 +635,10 @@ impl CheckerState {
                 );
                 self.allocations.insert(into, val);
             }
-            &CheckerInst::Op {
-                ref operands,
-                ref allocs,
-                ..
-            } => {
-                for (op, alloc) in operands.iter().zip(allocs.iter()) {
-                    if op.kind() != OperandKind::Def {
-                        continue;
-                    }
-                    self.allocations
-                        .insert(*alloc, CheckerValue::Reg(op.vreg(), false));
+            &CheckerInst::Op { .. } => {
+                for &pos in &OperandPos::ALL {
+                    self.update_defs(pos, checkinst, reftypes);
+                    self.update_clobbers(pos, checkinst);
                 }
             }
             &CheckerInst::BlockParams {
@@ -345,7 +648,83 @@ impl CheckerState {
             } => {
                 for (vreg, alloc) in vregs.iter().zip(allocs.iter()) {
                     self.allocations
-                        .insert(*alloc, CheckerValue::Reg(*vreg, false));
+                        .insert(*alloc, CheckerValue::Reg(*vreg, reftypes.contains(vreg)));
+                }
+            }
+            &CheckerInst::DefineConstant { into, vreg, .. }
+            | &CheckerInst::Remat { into, vreg, .. } => {
+                self.allocations
+                    .insert(into, CheckerValue::Reg(vreg, false));
+            }
+            CheckerInst::Safepoint {
+                slots,
+                derived,
+                defs,
+                ..
+            } => {
+                // The collector may move any reference it can find,
+                // leaving a stale copy anywhere else.
+                for (alloc, val) in self.allocations.iter_mut() {
+                    if let CheckerValue::Reg(_, true) = val {
+                        if !slots.contains(alloc)
+                            && !derived.iter().any(|(slot, _)| slot == alloc)
+                            && !defs.contains(alloc)
+                        {
+                            *val = CheckerValue::Conflicted;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Update according to an instruction's defs at `pos`.
+    fn update_defs(&mut self, pos: OperandPos, checkinst: &CheckerInst, reftypes: &HashSet<VReg>) {
+        if let CheckerInst::Op {
+            operands, allocs, ..
+        } = checkinst
+        {
+            for (op, alloc) in operands.iter().zip(allocs.iter()) {
+                // A `Mod` is read at `Before` (see `check`) and
+                // written at `After`.
+                let def_pos = match op.kind() {
+                    OperandKind::Def => op.pos(),
+                    OperandKind::Mod => OperandPos::After,
+                    OperandKind::Use => continue,
+                };
+                if def_pos != pos {
+                    continue;
+                }
+                if op.policy() == OperandPolicy::Constant && *alloc == Allocation::none() {
+                    // Elided constant def: the value only exists
+                    // where it is later materialized.
+                    continue;
+                }
+                // A vreg redefined in a non-SSA function leaves any
+                // other copy of its old value stale.
+                for val in self.allocations.values_mut() {
+                    if let CheckerValue::Reg(vreg, _) = *val {
+                        if vreg == op.vreg() {
+                            *val = CheckerValue::Conflicted;
+                        }
+                    }
+                }
+                let is_ref = reftypes.contains(&op.vreg());
+                self.allocations
+                    .insert(*alloc, CheckerValue::Reg(op.vreg(), is_ref));
+            }
+        }
+    }
+
+    /// Update according to an instruction's clobbers, which take
+    /// effect once its uses at `Before` are read: a use at any later
+    /// position of a clobbered register finds it conflicted.
+    fn update_clobbers(&mut self, pos: OperandPos, checkinst: &CheckerInst) {
+        if let CheckerInst::Op { clobbers, .. } = checkinst {
+            if pos == OperandPos::Before {
+                for &preg in clobbers {
+                    self.allocations
+                        .insert(Allocation::reg(preg), CheckerValue::Conflicted);
                 }
             }
         }
@@ -359,17 +738,34 @@ impl CheckerState {
         allocs: &[Allocation],
     ) -> Result<(), CheckerError> {
         match op.policy() {
-            OperandPolicy::Any => {}
-            OperandPolicy::Reg => {
+            OperandPolicy::Any | OperandPolicy::Constant => {}
+            OperandPolicy::Reg | OperandPolicy::PrefReg(_) => {
                 if alloc.kind() != AllocationKind::Reg {
                     return Err(CheckerError::AllocationIsNotReg { inst, op, alloc });
                 }
             }
+            OperandPolicy::RegExcept(excluded) => match alloc.as_reg() {
+                None => return Err(CheckerError::AllocationIsNotReg { inst, op, alloc }),
+                Some(preg) if excluded.contains(preg) => {
+                    return Err(CheckerError::AllocationIsExcluded { inst, op, alloc });
+                }
+                Some(_) => {}
+            },
             OperandPolicy::FixedReg(preg) => {
                 if alloc != Allocation::reg(preg) {
                     return Err(CheckerError::AllocationIsNotFixedReg { inst, op, alloc });
                 }
             }
+            OperandPolicy::Stack => {
+                if alloc.kind() != AllocationKind::Stack {
+                    return Err(CheckerError::AllocationIsNotStack { inst, op, alloc });
+                }
+            }
+            OperandPolicy::FixedStack(slot) => {
+                if alloc != Allocation::stack(slot) {
+                    return Err(CheckerError::AllocationIsNotFixedStack { inst, op, alloc });
+                }
+            }
             OperandPolicy::Reuse(idx) => {
                 if alloc.kind() != AllocationKind::Reg {
                     return Err(CheckerError::AllocationIsNotReg { inst, op, alloc });
@@ -393,15 +789,21 @@ impl CheckerState {
 pub(crate) enum CheckerInst {
     /// A move between allocations (these could be registers or
     /// spillslots).
-    Move { into: Allocation, from: Allocation },
+    Move {
+        into: Allocation,
+        from: Allocation,
+        pos: ProgPoint,
+    },
 
     /// A regular instruction with fixed use and def slots. Contains
     /// both the original operands (as given to the regalloc) and the
-    /// allocation results.
+    /// allocation results, and the registers it clobbers other than
+    /// those it defines.
     Op {
         inst: Inst,
         operands: Vec<Operand>,
         allocs: Vec<Allocation>,
+        clobbers: Vec<PReg>,
     },
 
     /// The top of a block with blockparams. We define the given vregs
@@ -410,6 +812,34 @@ pub(crate) enum CheckerInst {
         block: Block,
         vregs: Vec<VReg>,
         allocs: Vec<Allocation>,
+        pos: ProgPoint,
+    },
+
+    /// A materialization of a constant vreg into an allocation.
+    DefineConstant {
+        into: Allocation,
+        vreg: VReg,
+        pos: ProgPoint,
+    },
+
+    /// A rematerialization of a vreg into an allocation.
+    Remat {
+        into: Allocation,
+        vreg: VReg,
+        kind: RematKind,
+        pos: ProgPoint,
+    },
+
+    /// A safepoint, just after its instruction's `Op`: the
+    /// references in `slots` (its stackmap) are updated in place, as
+    /// are the derived references in `derived`, each with the slot of
+    /// its base, and any elsewhere, other than the instruction's own
+    /// `defs`, become stale.
+    Safepoint {
+        inst: Inst,
+        slots: Vec<Allocation>,
+        derived: Vec<(Allocation, Allocation)>,
+        defs: Vec<Allocation>,
     },
 }
 
@@ -418,6 +848,14 @@ pub struct Checker<'a, F: Function> {
     f: &'a F,
     bb_in: HashMap<Block, CheckerState>,
     bb_insts: HashMap<Block, Vec<CheckerInst>>,
+    constant_vregs: HashSet<VReg>,
+    reftypes: HashSet<VReg>,
+    live_ranges: HashMap<Allocation, Vec<(ProgPoint, ProgPoint, VReg)>>,
+    num_spillslots: usize,
+    callee_saves: Vec<(PReg, SpillSlot)>,
+    /// The clobbers of each of the `MachineEnv`'s calling
+    /// conventions, if one was given.
+    call_conv_clobbers: Vec<Vec<PReg>>,
 }
 
 impl<'a, F: Function> Checker<'a, F> {
@@ -428,20 +866,84 @@ impl<'a, F: Function> Checker<'a, F> {
     pub fn new(f: &'a F) -> Checker<'a, F> {
         let mut bb_in = HashMap::new();
         let mut bb_insts = HashMap::new();
+        let mut constant_vregs = HashSet::new();
+        let mut reftypes = HashSet::new();
 
         for block in 0..f.blocks() {
             let block = Block::new(block);
             bb_in.insert(block, Default::default());
             bb_insts.insert(block, vec![]);
+            for &vreg in f.block_params(block) {
+                if f.is_reftype(vreg) {
+                    reftypes.insert(vreg);
+                }
+            }
+            for inst in f.block_insns(block).iter() {
+                for op in inst_operands(f, inst) {
+                    if op.policy() == OperandPolicy::Constant {
+                        constant_vregs.insert(op.vreg());
+                    } else if op.kind() != OperandKind::Use && f.is_reftype(op.vreg()) {
+                        reftypes.insert(op.vreg());
+                    }
+                }
+            }
         }
 
-        Checker { f, bb_in, bb_insts }
+        Checker {
+            f,
+            bb_in,
+            bb_insts,
+            constant_vregs,
+            reftypes,
+            live_ranges: HashMap::new(),
+            num_spillslots: 0,
+            callee_saves: vec![],
+            call_conv_clobbers: vec![],
+        }
+    }
+
+    /// Check the clobbers of calls with a calling convention (see
+    /// `Function::inst_call_conv`) against those of `env`'s. Must be
+    /// called before `prepare()`.
+    pub fn add_machine_env(&mut self, env: &MachineEnv) {
+        self.call_conv_clobbers = env
+            .call_convs
+            .iter()
+            .map(|conv| conv.clobbers.clone())
+            .collect();
+    }
+
+    /// Record that, per the allocator's final liverange data, `vreg`
+    /// lives in `alloc` from `from` up to (but not including) `to`.
+    /// If any ranges are added, `run()` also verifies that no two
+    /// distinct vregs share an allocation at overlapping points. This
+    /// is independent of the dataflow check, and catches an allocator
+    /// handing out the same register or slot twice even where no
+    /// operand happens to read the clobbered value.
+    pub fn add_live_range(
+        &mut self,
+        vreg: VReg,
+        from: ProgPoint,
+        to: ProgPoint,
+        alloc: Allocation,
+    ) {
+        if alloc == Allocation::none() {
+            return;
+        }
+        self.live_ranges
+            .entry(alloc)
+            .or_default()
+            .push((from, to, vreg));
     }
 
     /// Build the list of checker instructions based on the given func
-    /// and allocation results.
+    /// and allocation results. The allocator must have been run with
+    /// `RegallocOptions::report_blockparams` set, so that `out`
+    /// records where blockparams live.
     pub fn prepare(&mut self, out: &Output) {
         debug!("checker: out = {:?}", out);
+        self.num_spillslots = out.num_spillslots;
+        self.callee_saves = out.callee_saves.clone();
         // For each original instruction, create an `Op`.
         let mut last_inst = None;
         let mut insert_idx = 0;
@@ -455,15 +957,46 @@ impl<'a, F: Function> Checker<'a, F> {
                 self.handle_edits(block, out, &mut insert_idx, ProgPoint::before(inst));
 
                 // Instruction itself.
-                let operands: Vec<_> = self.f.inst_operands(inst).iter().cloned().collect();
+                let operands: Vec<_> = inst_operands(self.f, inst).into_vec();
                 let allocs: Vec<_> = out.inst_allocs(inst).iter().cloned().collect();
+                let safepoint = if self.f.is_safepoint(inst) && !self.reftypes.is_empty() {
+                    let slots = out
+                        .safepoint_slots
+                        .iter()
+                        .filter(|&&(i, _)| i == inst)
+                        .map(|&(_, slot)| Allocation::stack(slot))
+                        .collect();
+                    let derived = out
+                        .safepoint_derived_slots
+                        .iter()
+                        .filter(|&&(i, _, _)| i == inst)
+                        .map(|&(_, slot, base)| (Allocation::stack(slot), Allocation::stack(base)))
+                        .collect();
+                    let defs = operands
+                        .iter()
+                        .zip(allocs.iter())
+                        .filter(|(op, _)| op.kind() != OperandKind::Use)
+                        .map(|(_, &alloc)| alloc)
+                        .collect();
+                    Some(CheckerInst::Safepoint {
+                        inst,
+                        slots,
+                        derived,
+                        defs,
+                    })
+                } else {
+                    None
+                };
+                let clobbers = self.clobbers(inst, out, &operands, &allocs);
                 let checkinst = CheckerInst::Op {
                     inst,
                     operands,
                     allocs,
+                    clobbers,
                 };
                 debug!("checker: adding inst {:?}", checkinst);
                 self.bb_insts.get_mut(&block).unwrap().push(checkinst);
+                self.bb_insts.get_mut(&block).unwrap().extend(safepoint);
 
                 // Any inserted edits after instruction.
                 self.handle_edits(block, out, &mut insert_idx, ProgPoint::after(inst));
@@ -471,6 +1004,43 @@ impl<'a, F: Function> Checker<'a, F> {
         }
     }
 
+    /// The registers `inst` clobbers, other than those it defines.
+    fn clobbers(
+        &self,
+        inst: Inst,
+        out: &Output,
+        operands: &[Operand],
+        allocs: &[Allocation],
+    ) -> Vec<PReg> {
+        let conv_clobbers = match self.f.inst_call_conv(inst) {
+            Some(conv) if conv < self.call_conv_clobbers.len() => {
+                &self.call_conv_clobbers[conv][..]
+            }
+            _ => &[],
+        };
+        let soft_clobbers = self.f.inst_soft_clobbers(inst).iter().filter(|&&preg| {
+            out.preserved_soft_clobbers
+                .binary_search(&(inst, preg))
+                .is_err()
+        });
+        let mut regs: Vec<PReg> = vec![];
+        for &preg in self
+            .f
+            .inst_clobbers(inst)
+            .iter()
+            .chain(conv_clobbers)
+            .chain(soft_clobbers)
+        {
+            let defined = operands.iter().zip(allocs.iter()).any(|(op, &alloc)| {
+                op.kind() != OperandKind::Use && alloc == Allocation::reg(preg)
+            });
+            if !defined && !regs.contains(&preg) {
+                regs.push(preg);
+            }
+        }
+        regs
+    }
+
     fn handle_edits(&mut self, block: Block, out: &Output, idx: &mut usize, pos: ProgPoint) {
         while *idx < out.edits.len() && out.edits[*idx].0 <= pos {
             let &(edit_pos, ref edit) = &out.edits[*idx];
@@ -484,7 +1054,11 @@ impl<'a, F: Function> Checker<'a, F> {
                     self.bb_insts
                         .get_mut(&block)
                         .unwrap()
-                        .push(CheckerInst::Move { into: to, from });
+                        .push(CheckerInst::Move {
+                            into: to,
+                            from,
+                            pos,
+                        });
                 }
                 &Edit::BlockParams {
                     ref vregs,
@@ -494,9 +1068,31 @@ impl<'a, F: Function> Checker<'a, F> {
                         block,
                         vregs: vregs.clone(),
                         allocs: allocs.clone(),
+                        pos,
                     };
                     self.bb_insts.get_mut(&block).unwrap().push(inst);
                 }
+                &Edit::DefineConstant { vreg, to } => {
+                    self.bb_insts
+                        .get_mut(&block)
+                        .unwrap()
+                        .push(CheckerInst::DefineConstant {
+                            into: to,
+                            vreg,
+                            pos,
+                        });
+                }
+                &Edit::Remat { vreg, kind, to } => {
+                    self.bb_insts
+                        .get_mut(&block)
+                        .unwrap()
+                        .push(CheckerInst::Remat {
+                            into: to,
+                            vreg,
+                            kind,
+                            pos,
+                        });
+                }
             }
         }
     }
@@ -511,7 +1107,7 @@ impl<'a, F: Function> Checker<'a, F> {
             let mut state = self.bb_in.get(&block).cloned().unwrap();
             debug!("analyze: block {} has state {:?}", block.index(), state);
             for inst in self.bb_insts.get(&block).unwrap() {
-                state.update(inst);
+                state.update(inst, &self.reftypes);
                 debug!("analyze: inst {:?} -> state {:?}", inst, state);
             }
 
@@ -539,25 +1135,397 @@ impl<'a, F: Function> Checker<'a, F> {
     /// for errors.
     fn find_errors(&self) -> Result<(), CheckerErrors> {
         let mut errors = vec![];
+        // The context of each error that has one; errors without are
+        // filled in with the default.
+        let mut reports = vec![];
+        let mut slots = HashMap::new();
         for (block, input) in &self.bb_in {
             let mut state = input.clone();
-            for inst in self.bb_insts.get(block).unwrap() {
-                if let Err(e) = state.check(InstPosition::Before, inst) {
-                    debug!("Checker error: {:?}", e);
-                    errors.push(e);
+            for (idx, inst) in self.bb_insts.get(block).unwrap().iter().enumerate() {
+                self.note_slots(&state, inst, &mut slots, &mut errors);
+                if let &CheckerInst::DefineConstant { into, vreg, .. } = inst {
+                    if !self.constant_vregs.contains(&vreg) {
+                        errors
+                            .push(CheckerError::DefineConstantOfNonConstant { vreg, alloc: into });
+                    }
+                }
+                if let &CheckerInst::Remat {
+                    into, vreg, kind, ..
+                } = inst
+                {
+                    if self.f.is_rematerializable(vreg) != Some(kind) {
+                        errors.push(CheckerError::RematOfNonRematerializable {
+                            vreg,
+                            kind,
+                            alloc: into,
+                        });
+                    }
+                }
+                if let CheckerInst::Safepoint {
+                    inst,
+                    slots,
+                    derived,
+                    ..
+                } = inst
+                {
+                    // A derived reference is listed with its base,
+                    // unless the safepoint redefines the base.
+                    let defines = |vreg: VReg| {
+                        inst_operands(self.f, *inst)
+                            .iter()
+                            .any(|op| op.kind() != OperandKind::Use && op.vreg() == vreg)
+                    };
+                    for &alloc in slots {
+                        match state.allocations.get(&alloc) {
+                            Some(&CheckerValue::Reg(vreg, true)) => {
+                                if self.f.derived_ref_base(vreg).is_some_and(|b| !defines(b)) {
+                                    errors.push(CheckerError::DerivedRefWithoutBase {
+                                        inst: *inst,
+                                        alloc,
+                                    });
+                                }
+                            }
+                            _ => errors.push(CheckerError::NonRefInStackmap { inst: *inst, alloc }),
+                        }
+                    }
+                    for &(alloc, base) in derived {
+                        let paired =
+                            match (state.allocations.get(&alloc), state.allocations.get(&base)) {
+                                (
+                                    Some(&CheckerValue::Reg(vreg, true)),
+                                    Some(&CheckerValue::Reg(base_vreg, true)),
+                                ) => {
+                                    self.f.derived_ref_base(vreg) == Some(base_vreg)
+                                        && slots.contains(&base)
+                                }
+                                _ => false,
+                            };
+                        if !paired {
+                            errors.push(CheckerError::DerivedRefWithoutBase { inst: *inst, alloc });
+                        }
+                    }
                 }
-                state.update(inst);
-                if let Err(e) = state.check(InstPosition::After, inst) {
-                    debug!("Checker error: {:?}", e);
-                    errors.push(e);
+                if let &CheckerInst::Op { .. } = inst {
+                    // At each of the instruction's positions in turn,
+                    // its defs there are written, and then its uses
+                    // there must still hold their values. The
+                    // clobbers take effect once the uses at `Before`
+                    // are read.
+                    for &pos in &OperandPos::ALL {
+                        state.update_defs(pos, inst, &self.reftypes);
+                        if let Err((i, e)) = state.check(pos, inst) {
+                            debug!("Checker error: {:?}", e);
+                            reports.resize(errors.len(), CheckerReport::default());
+                            reports.push(self.operand_report(*block, idx, &state, inst, i, &e));
+                            errors.push(e);
+                        }
+                        state.update_clobbers(pos, inst);
+                    }
+                } else {
+                    state.update(inst, &self.reftypes);
                 }
             }
         }
 
+        for &(preg, slot) in &self.callee_saves {
+            let alloc = Allocation::stack(slot);
+            self.note_slot(&mut slots, &mut errors, alloc, preg.class(), None);
+        }
+        self.check_slot_layout(&slots, &mut errors);
+        self.find_interference(&mut errors);
+
         if errors.is_empty() {
             Ok(())
         } else {
-            Err(CheckerErrors { errors })
+            let mut vregs: Vec<VReg> = errors.iter().flat_map(|e| e.vregs()).collect();
+            vregs.sort_unstable();
+            vregs.dedup();
+            let vreg_names = vregs
+                .into_iter()
+                .filter_map(|vreg| Some((vreg, self.f.debug_vreg_name(vreg)?.to_string())))
+                .collect();
+            reports.resize(errors.len(), CheckerReport::default());
+            for (error, report) in errors.iter().zip(reports.iter_mut()) {
+                if let &CheckerError::AllocationInterference { point, .. } = error {
+                    report.point = Some(point);
+                }
+            }
+            Err(CheckerErrors {
+                errors,
+                reports,
+                vreg_names,
+            })
+        }
+    }
+
+    /// The context of `error`, found in operand `i` of `checkinst`,
+    /// the `idx`th instruction of `block`, in `state`.
+    fn operand_report(
+        &self,
+        block: Block,
+        idx: usize,
+        state: &CheckerState,
+        checkinst: &CheckerInst,
+        i: usize,
+        error: &CheckerError,
+    ) -> CheckerReport {
+        let (inst, op, alloc) = match checkinst {
+            &CheckerInst::Op {
+                inst,
+                ref operands,
+                ref allocs,
+                ..
+            } => (inst, operands[i], allocs[i]),
+            _ => unreachable!(),
+        };
+        let found = state.allocations.get(&alloc).cloned().unwrap_or_default();
+        let edits = match error {
+            CheckerError::UnknownValueInAllocation { .. }
+            | CheckerError::ConflictedValueInAllocation { .. }
+            | CheckerError::IncorrectValueInAllocation { .. } => {
+                self.trace_edits(block, idx, alloc, found)
+            }
+            _ => vec![],
+        };
+        CheckerReport {
+            point: Some(op.pos().at(inst)),
+            operand: Some(i),
+            expected: Some(CheckerValue::Reg(op.vreg(), false)),
+            found: if alloc == Allocation::none() {
+                None
+            } else {
+                Some(found)
+            },
+            edits,
+        }
+    }
+
+    /// The edits that carried `found` into `alloc` before the `idx`th
+    /// instruction of `block`, in program order. Walks back through
+    /// moves to the def, blockparam or constant that produced it, and
+    /// across the start of a block into a predecessor that ends with
+    /// the same value in the allocation; each block is visited once.
+    fn trace_edits(
+        &self,
+        mut block: Block,
+        mut idx: usize,
+        mut alloc: Allocation,
+        found: CheckerValue,
+    ) -> Vec<(ProgPoint, Edit)> {
+        let mut edits = vec![];
+        let mut visited = HashSet::new();
+        'blocks: loop {
+            for inst in self.bb_insts[&block][..idx].iter().rev() {
+                match inst {
+                    CheckerInst::Move { into, from, pos } if *into == alloc => {
+                        let edit = Edit::Move {
+                            from: *from,
+                            to: *into,
+                        };
+                        edits.push((*pos, edit));
+                        alloc = *from;
+                    }
+                    CheckerInst::DefineConstant { into, vreg, pos } if *into == alloc => {
+                        let edit = Edit::DefineConstant {
+                            vreg: *vreg,
+                            to: *into,
+                        };
+                        edits.push((*pos, edit));
+                        break 'blocks;
+                    }
+                    CheckerInst::Remat {
+                        into,
+                        vreg,
+                        kind,
+                        pos,
+                    } if *into == alloc => {
+                        let edit = Edit::Remat {
+                            vreg: *vreg,
+                            kind: *kind,
+                            to: *into,
+                        };
+                        edits.push((*pos, edit));
+                        break 'blocks;
+                    }
+                    CheckerInst::BlockParams {
+                        vregs, allocs, pos, ..
+                    } if allocs.contains(&alloc) => {
+                        let edit = Edit::BlockParams {
+                            vregs: vregs.clone(),
+                            allocs: allocs.clone(),
+                        };
+                        edits.push((*pos, edit));
+                        break 'blocks;
+                    }
+                    CheckerInst::Op {
+                        operands, allocs, ..
+                    } if operands
+                        .iter()
+                        .zip(allocs.iter())
+                        .any(|(op, &a)| op.kind() != OperandKind::Use && a == alloc) =>
+                    {
+                        break 'blocks;
+                    }
+                    _ => {}
+                }
+            }
+            if !visited.insert(block) {
+                break;
+            }
+            let pred = self.f.block_preds(block).iter().find(|&&pred| {
+                let mut state = self.bb_in[&pred].clone();
+                for inst in &self.bb_insts[&pred] {
+                    state.update(inst, &self.reftypes);
+                }
+                state.allocations.get(&alloc).cloned().unwrap_or_default() == found
+            });
+            match pred {
+                Some(&pred) => {
+                    block = pred;
+                    idx = self.bb_insts[&pred].len();
+                }
+                None => break,
+            }
+        }
+        edits.reverse();
+        edits
+    }
+
+    /// Record the spillslots `inst` stores to or loads from.
+    fn note_slots(
+        &self,
+        state: &CheckerState,
+        inst: &CheckerInst,
+        slots: &mut HashMap<usize, (Allocation, usize)>,
+        errors: &mut Vec<CheckerError>,
+    ) {
+        match inst {
+            CheckerInst::Op {
+                operands, allocs, ..
+            } => {
+                for (op, &alloc) in operands.iter().zip(allocs.iter()) {
+                    self.note_slot(slots, errors, alloc, op.class(), Some(op.vreg()));
+                }
+            }
+            CheckerInst::Move { into, from, .. } => {
+                let (into, from) = (*into, *from);
+                // The value moved is whatever `from` holds here.
+                let vreg = match state.allocations.get(&from) {
+                    Some(&CheckerValue::Reg(vreg, _)) => Some(vreg),
+                    _ => None,
+                };
+                let class = vreg.map_or(from.class(), |vreg| vreg.class());
+                self.note_slot(slots, errors, from, class, vreg);
+                self.note_slot(slots, errors, into, class, vreg);
+            }
+            CheckerInst::BlockParams { vregs, allocs, .. } => {
+                for (&vreg, &alloc) in vregs.iter().zip(allocs.iter()) {
+                    self.note_slot(slots, errors, alloc, vreg.class(), Some(vreg));
+                }
+            }
+            CheckerInst::DefineConstant { into, vreg, .. }
+            | CheckerInst::Remat { into, vreg, .. } => {
+                self.note_slot(slots, errors, *into, vreg.class(), Some(*vreg));
+            }
+            CheckerInst::Safepoint { .. } => {}
+        }
+    }
+
+    /// If `alloc` is a spillslot, check that it was made for values
+    /// of `class`, and record the size it needs to hold `vreg` (if
+    /// known). Slots reserved by the embedder are not checked beyond
+    /// their class.
+    fn note_slot(
+        &self,
+        slots: &mut HashMap<usize, (Allocation, usize)>,
+        errors: &mut Vec<CheckerError>,
+        alloc: Allocation,
+        class: RegClass,
+        vreg: Option<VReg>,
+    ) {
+        let slot = match alloc.as_stack() {
+            Some(slot) => slot,
+            None => return,
+        };
+        if slot.class() != class {
+            errors.push(CheckerError::SpillSlotClassMismatch { alloc, class });
+            return;
+        }
+        if slot.index() < self.f.num_reserved_spillslots() {
+            return;
+        }
+        let vreg = vreg.unwrap_or_else(|| VReg::new(VReg::MAX, class));
+        let size = self.f.spillslot_size(class, vreg);
+        let entry = slots.entry(slot.index()).or_insert((alloc, size));
+        if entry.0 != alloc {
+            // The same index was named with two different classes.
+            errors.push(CheckerError::SpillSlotClassMismatch {
+                alloc: entry.0,
+                class,
+            });
+        }
+        entry.1 = std::cmp::max(entry.1, size);
+    }
+
+    /// Check that the spillslots seen fit in the frame and that no
+    /// two of them overlap, given the size each must have.
+    fn check_slot_layout(
+        &self,
+        slots: &HashMap<usize, (Allocation, usize)>,
+        errors: &mut Vec<CheckerError>,
+    ) {
+        let mut extents: Vec<(usize, usize, Allocation)> = slots
+            .iter()
+            .map(|(&index, &(alloc, size))| {
+                let start = if self.f.multi_spillslot_named_by_last_slot() {
+                    (index + 1).saturating_sub(size)
+                } else {
+                    index
+                };
+                (start, start + size, alloc)
+            })
+            .collect();
+        extents.sort_unstable_by_key(|&(start, end, _)| (start, end));
+        for &(_, end, alloc) in &extents {
+            if end > self.num_spillslots {
+                errors.push(CheckerError::SpillSlotOutOfRange {
+                    alloc,
+                    num_spillslots: self.num_spillslots,
+                });
+            }
+        }
+        for pair in extents.windows(2) {
+            if pair[1].0 < pair[0].1 {
+                errors.push(CheckerError::SpillSlotOverlap {
+                    allocs: (pair[0].2, pair[1].2),
+                });
+            }
+        }
+    }
+
+    /// Check the live ranges added with `add_live_range()` for two
+    /// vregs in the same allocation at once.
+    fn find_interference(&self, errors: &mut Vec<CheckerError>) {
+        for (&alloc, ranges) in &self.live_ranges {
+            let mut ranges = ranges.clone();
+            ranges.sort_unstable_by_key(|&(from, to, _)| (from, to));
+            // The range reaching furthest among those seen so far.
+            let mut furthest: Option<(ProgPoint, VReg)> = None;
+            for &(from, to, vreg) in &ranges {
+                if let Some((end, other)) = furthest {
+                    if from < end && other != vreg {
+                        errors.push(CheckerError::AllocationInterference {
+                            alloc,
+                            point: from,
+                            vregs: (other, vreg),
+                        });
+                    }
+                    if to <= end {
+                        continue;
+                    }
+                }
+                furthest = Some((to, vreg));
+            }
         }
     }
 
@@ -587,10 +1555,14 @@ impl<'a, F: Function> Checker<'a, F> {
                         inst,
                         ref operands,
                         ref allocs,
+                        ref clobbers,
                     } => {
                         debug!("  inst{}: {:?} ({:?})", inst.index(), operands, allocs);
+                        if !clobbers.is_empty() {
+                            debug!("    clobber: {:?}", clobbers);
+                        }
                     }
-                    &CheckerInst::Move { from, into } => {
+                    &CheckerInst::Move { from, into, .. } => {
                         debug!("    {} -> {}", from, into);
                     }
                     &CheckerInst::BlockParams {
@@ -604,8 +1576,19 @@ impl<'a, F: Function> Checker<'a, F> {
                         }
                         debug!("    blockparams: {}", args.join(", "));
                     }
+                    &CheckerInst::DefineConstant { into, vreg, .. } => {
+                        debug!("    constant {} -> {}", vreg, into);
+                    }
+                    &CheckerInst::Remat {
+                        into, vreg, kind, ..
+                    } => {
+                        debug!("    remat {} ({:?}) -> {}", vreg, kind, into);
+                    }
+                    CheckerInst::Safepoint { slots, derived, .. } => {
+                        debug!("    safepoint: {:?} derived {:?}", slots, derived);
+                    }
                 }
-                state.update(inst);
+                state.update(inst, &self.reftypes);
                 print_state(&state);
             }
         }
@@ -613,3 +1596,437 @@ impl<'a, F: Function> Checker<'a, F> {
         result
     }
 }
+
+/// Check `output`, the result of allocating `func` for `env`,
+/// returning all errors found. The allocator must have been run with
+/// `RegallocOptions::report_blockparams` set (see
+/// `Checker::prepare()`).
+pub fn check<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    output: &Output,
+) -> Result<(), CheckerErrors> {
+    let mut checker = Checker::new(func);
+    checker.add_machine_env(env);
+    checker.prepare(output);
+    checker.run()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzzing::func::{machine_env, Func, FuncBuilder, InstData, InstOpcode};
+    use crate::ion::CodeRange;
+    use crate::{CallConv, RegallocOptions, RematKind};
+
+    #[test]
+    fn test_late_use_of_clobber() {
+        // An instruction reads its vreg in the register it clobbers:
+        // fine at `Before`, which is read before the clobber, but not
+        // at any later position. The allocation is made without the
+        // clobber, which the allocator would keep the vreg out of.
+        let env = machine_env();
+        let preg = env.regs_by_class[RegClass::Int as u8 as usize][0];
+        let build = |pos: OperandPos, clobbers: &[PReg]| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            let use_ = Operand::new(v0, OperandPolicy::FixedReg(preg), OperandKind::Use, pos);
+            builder
+                .inst(block, &[Operand::reg_fixed_def(v0, preg)], &[])
+                .inst(block, &[use_], clobbers)
+                .ret(block);
+            builder.build().unwrap()
+        };
+
+        let opts = RegallocOptions {
+            report_blockparams: true,
+            ..RegallocOptions::default()
+        };
+        let func = build(OperandPos::Before, &[]);
+        let out =
+            crate::ion::run_with_options(&func, &env, &opts).expect("regalloc did not succeed");
+        assert!(out.edits.is_empty());
+        check(&build(OperandPos::Before, &[preg]), &env, &out).expect("checker failed");
+
+        for pos in [
+            OperandPos::BeforeLate,
+            OperandPos::After,
+            OperandPos::AfterLate,
+        ] {
+            check(&build(pos, &[]), &env, &out).expect("checker failed");
+            let errors = check(&build(pos, &[preg]), &env, &out).unwrap_err();
+            assert!(matches!(
+                errors.errors[..],
+                [CheckerError::ConflictedValueInAllocation { inst, alloc, .. }]
+                    if inst.index() == 1 && alloc == Allocation::reg(preg)
+            ));
+        }
+    }
+
+    /// Allocate `func` with the options the checker needs.
+    fn allocate(func: &Func, env: &MachineEnv) -> Output {
+        let options = RegallocOptions {
+            report_blockparams: true,
+            ..RegallocOptions::default()
+        };
+        crate::run_with_options(func, env, &options).expect("regalloc did not succeed")
+    }
+
+    /// A value defined in a register, kept across an instruction that
+    /// clobbers every integer register, and then used: it is spilled
+    /// and reloaded around the clobbers.
+    fn spill_across_clobbers(env: &MachineEnv) -> (Func, VReg) {
+        let clobbers = env.regs_by_class[RegClass::Int as u8 as usize].clone();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[], &clobbers)
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        (builder.build().unwrap(), v0)
+    }
+
+    #[test]
+    fn test_interference() {
+        // Two values live at once. Claiming that one of them is also
+        // in the other's register is caught, though no operand reads
+        // a wrong value.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions {
+            report_blockparams: true,
+            ..RegallocOptions::default()
+        };
+        let liveness =
+            crate::ion::Liveness::compute(&func, &env, &options).expect("liveness failed");
+        let allocated = liveness.merge().allocate();
+        let ranges = allocated.live_ranges();
+        let out = allocated.reify();
+        let run = |extra: &[(VReg, CodeRange, Allocation)]| {
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            for &(vreg, range, alloc) in ranges.iter().chain(extra) {
+                checker.add_live_range(vreg, range.from(), range.to(), alloc);
+            }
+            checker.run()
+        };
+        run(&[]).expect("checker failed");
+
+        let (&(_, range0, alloc0), &(_, _, alloc1)) = (
+            ranges.iter().find(|r| r.0 == v0).unwrap(),
+            ranges.iter().find(|r| r.0 == v1).unwrap(),
+        );
+        assert!(alloc0.as_reg().is_some() && alloc1.as_reg().is_some());
+        assert_ne!(alloc0, alloc1);
+        let errors = run(&[(v0, range0, alloc1)]).expect_err("interference not caught");
+        assert!(!errors.errors().is_empty());
+        assert!(errors.errors().iter().all(|e| matches!(
+            e,
+            CheckerError::AllocationInterference { alloc, .. } if *alloc == alloc1
+        )));
+    }
+
+    #[test]
+    fn test_reports() {
+        // A value spilled across a call that clobbers every register,
+        // whose def is then moved elsewhere: the use reads nothing,
+        // and the report traces the spill and reload that brought it.
+        let env = machine_env();
+        let clobbers = env.regs_by_class[RegClass::Int as u8 as usize].clone();
+        let (func, v0) = spill_across_clobbers(&env);
+        let mut out = allocate(&func, &env);
+        let moves: Vec<(ProgPoint, Edit)> = out
+            .edits
+            .iter()
+            .filter(|(_, edit)| matches!(edit, Edit::Move { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(moves.len(), 2);
+
+        let def = out.inst_alloc_offsets[0] as usize;
+        let other = clobbers
+            .iter()
+            .find(|&&preg| Allocation::reg(preg) != out.allocs[def])
+            .unwrap();
+        out.allocs[def] = Allocation::reg(*other);
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        let errors = checker.run().expect_err("missing value not caught");
+        assert_eq!(errors.errors().len(), errors.reports().len());
+        let (error, report) = errors
+            .errors()
+            .iter()
+            .zip(errors.reports())
+            .find(|(e, _)| matches!(e, CheckerError::UnknownValueInAllocation { .. }))
+            .expect("use of missing value not reported");
+        assert!(
+            matches!(error, CheckerError::UnknownValueInAllocation { inst, .. } if inst.index() == 2)
+        );
+        assert_eq!(report.point, Some(ProgPoint::before(Inst::new(2))));
+        assert_eq!(report.operand, Some(0));
+        assert_eq!(report.expected, Some(CheckerValue::Reg(v0, false)));
+        assert_eq!(report.found, Some(CheckerValue::Unknown));
+        assert_eq!(report.edits, moves);
+
+        #[cfg(feature = "enable-serde")]
+        {
+            let json = serde_json::to_string(&errors).unwrap();
+            let back: CheckerErrors = serde_json::from_str(&json).unwrap();
+            assert_eq!(format!("{:?}", back), format!("{:?}", errors));
+        }
+    }
+
+    #[test]
+    fn test_clobbers() {
+        // A value kept in its def's register across an instruction
+        // that clobbers it, rather than moved out of the way.
+        let mut env = machine_env();
+        let clobbers = env.regs_by_class[RegClass::Int as u8 as usize].clone();
+        env.call_convs.push(CallConv {
+            name: "test".to_string(),
+            clobbers: clobbers.clone(),
+            ..CallConv::default()
+        });
+        let build = |call: bool| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            builder.inst(block, &[Operand::reg_def(v0)], &[]);
+            if call {
+                builder.call(block, &[], 0);
+            } else {
+                builder.inst(block, &[], &clobbers);
+            }
+            builder.inst(block, &[Operand::reg_use(v0)], &[]).ret(block);
+            builder.build().unwrap()
+        };
+        for call in [false, true] {
+            let func = build(call);
+            let mut out = allocate(&func, &env);
+            check(&func, &env, &out).expect("checker failed");
+
+            out.edits.clear();
+            let def = out.inst_alloc_offsets[0] as usize;
+            let use_ = out.inst_alloc_offsets[2] as usize;
+            out.allocs[use_] = out.allocs[def];
+            let errors = check(&func, &env, &out).expect_err("clobbered value not caught");
+            assert!(errors.errors().iter().any(|e| matches!(
+                e,
+                CheckerError::ConflictedValueInAllocation { inst, .. } if inst.index() == 2
+            )));
+        }
+    }
+
+    #[test]
+    fn test_spillslots() {
+        let env = machine_env();
+        let (func, _) = spill_across_clobbers(&env);
+        let out = allocate(&func, &env);
+        check(&func, &env, &out).expect("checker failed");
+        assert_eq!(out.num_spillslots, 1);
+
+        // A frame too small for the slot used.
+        let mut bad = out.clone();
+        bad.num_spillslots = 0;
+        let errors = check(&func, &env, &bad).expect_err("slot beyond the frame not caught");
+        assert!(!errors.errors().is_empty());
+        assert!(errors
+            .errors()
+            .iter()
+            .all(|e| matches!(e, CheckerError::SpillSlotOutOfRange { .. })));
+
+        // A spill into a slot made for another class.
+        let mut bad = out.clone();
+        let to = bad
+            .edits
+            .iter_mut()
+            .find_map(|(_, edit)| match edit {
+                Edit::Move { from, to } if from.as_reg().is_some() && to.as_stack().is_some() => {
+                    Some(to)
+                }
+                _ => None,
+            })
+            .expect("no spill");
+        let slot = to.as_stack().unwrap();
+        *to = Allocation::stack(SpillSlot::new(slot.index(), RegClass::Float));
+        let errors = check(&func, &env, &bad).expect_err("class mismatch not caught");
+        assert!(errors
+            .errors()
+            .iter()
+            .any(|e| matches!(e, CheckerError::SpillSlotClassMismatch { .. })));
+    }
+
+    #[test]
+    fn test_stackmaps() {
+        // v0 is a reference spilled across the call at inst2, and v1 a
+        // plain value spilled there too.
+        let env = machine_env();
+        let clobbers = env.regs_by_class[RegClass::Int as u8 as usize].clone();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .reftype(v0)
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[]);
+        builder.add_inst(block, InstData::new(InstOpcode::Call, vec![], clobbers));
+        builder
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = allocate(&func, &env);
+        check(&func, &env, &out).expect("checker failed");
+        let call = Inst::new(2);
+        assert_eq!(out.safepoint_slots.len(), 1);
+        let spilled = |out: &Output| -> Vec<SpillSlot> {
+            out.edits
+                .iter()
+                .filter_map(|(_, edit)| match edit {
+                    Edit::Move { from, to } if from.as_reg().is_some() => to.as_stack(),
+                    _ => None,
+                })
+                .collect()
+        };
+        let plain = *spilled(&out)
+            .iter()
+            .find(|&&slot| out.safepoint_slots[0] != (call, slot))
+            .expect("v1 not spilled");
+
+        // A stackmap that leaves out the reference: v0 is stale after
+        // the call.
+        let mut missing = out.clone();
+        missing.safepoint_slots.clear();
+        let errors = check(&func, &env, &missing).expect_err("missing reference not caught");
+        assert!(errors.errors().iter().any(|e| matches!(
+            e,
+            CheckerError::ConflictedValueInAllocation { inst, .. } if inst.index() == 3
+        )));
+
+        // A stackmap that lists v1's slot.
+        let mut extra = out.clone();
+        extra.safepoint_slots.push((call, plain));
+        extra
+            .safepoint_slots
+            .sort_by_key(|&(inst, slot)| (inst, slot.index()));
+        let errors = check(&func, &env, &extra).expect_err("non-reference not caught");
+        assert!(matches!(
+            errors.errors()[..],
+            [CheckerError::NonRefInStackmap { inst, alloc }]
+                if inst == call && alloc == Allocation::stack(plain)
+        ));
+    }
+
+    #[test]
+    fn test_derived_refs() {
+        // v1 is derived from v0 and live across the call at inst2,
+        // which clobbers p0..p23; both are spilled there.
+        let env = machine_env();
+        let clobbers = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .reftype(v0)
+            .reftype(v1)
+            .derived(v1, v0)
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1), Operand::reg_use(v0)], &[]);
+        let call = vec![Operand::reg_def(v2)];
+        builder.add_inst(block, InstData::new(InstOpcode::Call, call, clobbers));
+        builder
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = allocate(&func, &env);
+        check(&func, &env, &out).expect("checker failed");
+        let call = Inst::new(2);
+        let (_, derived, base) = out.safepoint_derived_slots[0];
+        let unpaired_ref = |errors: &CheckerErrors| {
+            matches!(
+                errors.errors()[..],
+                [CheckerError::DerivedRefWithoutBase { inst, alloc }]
+                    if inst == call && alloc == Allocation::stack(derived)
+            )
+        };
+
+        // The derived slot listed on its own, as a plain reference.
+        let mut unpaired = out.clone();
+        unpaired.safepoint_derived_slots.clear();
+        unpaired.safepoint_slots = vec![(call, derived)];
+        let errors = check(&func, &env, &unpaired).expect_err("unpaired reference not caught");
+        assert!(unpaired_ref(&errors), "{:?}", errors);
+
+        // The derived slot paired with a base left out of the stackmap.
+        let mut baseless = out.clone();
+        baseless.safepoint_slots.retain(|&(_, slot)| slot != base);
+        let errors = check(&func, &env, &baseless).expect_err("missing base not caught");
+        assert!(unpaired_ref(&errors), "{:?}", errors);
+    }
+
+    #[test]
+    fn test_remat() {
+        // v0 is recomputed before its use after the clobbers; the
+        // checker accepts that only when the function can recompute it,
+        // in the way the edit says.
+        let env = machine_env();
+        let build = |remat: bool| {
+            let clobbers = env.regs_by_class[RegClass::Int as u8 as usize].clone();
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            builder
+                .inst(block, &[Operand::reg_def(v0)], &[])
+                .inst(block, &[], &clobbers)
+                .inst(block, &[Operand::reg_use(v0)], &[])
+                .ret(block);
+            if remat {
+                builder.remat(v0, RematKind::Address);
+            }
+            builder.build().unwrap()
+        };
+        let func = build(true);
+        let out = allocate(&func, &env);
+        assert!(matches!(
+            out.edits[..],
+            [(
+                _,
+                Edit::Remat {
+                    kind: RematKind::Address,
+                    ..
+                }
+            )]
+        ));
+        check(&func, &env, &out).expect("checker failed");
+
+        let errors =
+            check(&build(false), &env, &out).expect_err("remat of a plain vreg not caught");
+        assert!(matches!(
+            errors.errors()[..],
+            [CheckerError::RematOfNonRematerializable { .. }]
+        ));
+
+        let mut bad = out.clone();
+        if let Edit::Remat { kind, .. } = &mut bad.edits[0].1 {
+            *kind = RematKind::Constant;
+        }
+        let errors = check(&func, &env, &bad).expect_err("wrong remat kind not caught");
+        assert!(matches!(
+            errors.errors()[..],
+            [CheckerError::RematOfNonRematerializable {
+                kind: RematKind::Constant,
+                ..
+            }]
+        ));
+    }
+}
@@ -0,0 +1,550 @@
+//! A compact form of `Output`, for embedders that hold many
+//! allocation results at once (e.g. batch compilation of thousands of
+//! functions before emission). It keeps only what emission needs, in
+//! two allocations per function: one arena of `u32` words for the
+//! per-instruction allocations and the other tables, and one byte
+//! stream of edits, each delta-encoded against the previous one's
+//! program point.
+
+use crate::{
+    bitvec::BitVec, Allocation, Edit, Function, Inst, MachineEnv, Output, PReg, ProgPoint,
+    ProgPointIndex, RegAllocError, RegClass, RegallocOptions, RematKind, SpillSlot, VReg,
+};
+
+/// The sections of `CompactOutput::words`, in order.
+const ALLOC_OFFSETS: usize = 0;
+const ALLOCS: usize = 1;
+const SPILLSLOT_OFFSETS: usize = 2;
+const CALLEE_SAVES: usize = 3;
+const ELIDED_MOVES: usize = 4;
+const SAFEPOINT_LOCATIONS: usize = 5;
+const PRESERVED_SOFT_CLOBBERS: usize = 6;
+const SAFEPOINT_SLOTS: usize = 7;
+const SAFEPOINT_DERIVED_SLOTS: usize = 8;
+const DEBUG_LOCATIONS: usize = 9;
+const NUM_SECTIONS: usize = 10;
+
+/// Edit tags in the edit stream.
+const MOVE: u8 = 0;
+const BLOCK_PARAMS: u8 = 1;
+const DEFINE_CONSTANT: u8 = 2;
+const REMAT: u8 = 3;
+
+/// The parts of an `Output` needed for emission, packed: operand
+/// allocations, edits, the spillslot layout, callee saves, elided
+/// moves, safepoint locations, preserved soft clobbers, stackmaps
+/// and debug locations. Diagnostic results (`stats`, `fingerprint`,
+/// `explanations`, `block_edits` and the like) are dropped.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompactOutput {
+    num_spillslots: u32,
+    spillslot_area_size: u32,
+    num_insts: u32,
+    /// The start of each section in `words`, and its end.
+    sections: [u32; NUM_SECTIONS + 1],
+    words: Box<[u32]>,
+    edits: Box<[u8]>,
+}
+
+fn spillslot_bits(slot: SpillSlot) -> u32 {
+    (slot.index() as u32) | ((slot.class() as u32) << 24)
+}
+
+fn spillslot_from_bits(bits: u32) -> SpillSlot {
+    let class = if bits >> 24 == 0 {
+        RegClass::Int
+    } else {
+        RegClass::Float
+    };
+    SpillSlot::new((bits & 0x00ff_ffff) as usize, class)
+}
+
+fn preg_bits(preg: PReg) -> u32 {
+    ((preg.hw_enc() as u32) << 16) | (preg.index() as u32)
+}
+
+fn preg_from_bits(bits: u32) -> PReg {
+    let preg = PReg::from_index((bits & 0xffff) as usize);
+    PReg::new_with_hw_enc(preg.num(), (bits >> 16) as usize, preg.class())
+}
+
+/// The position within its instruction of a `ProgPoint`, in its low
+/// two bits; the instruction is stored separately.
+fn progpoint_pos_bits(point: ProgPoint) -> u32 {
+    ((point.pos as u32) << 1) | (point.is_late() as u32)
+}
+
+fn progpoint_from_bits(inst: u32, pos: u32) -> ProgPoint {
+    let inst = Inst::new(inst as usize);
+    let point = if pos & 2 == 0 {
+        ProgPoint::before(inst)
+    } else {
+        ProgPoint::after(inst)
+    };
+    if pos & 1 != 0 {
+        point.late()
+    } else {
+        point
+    }
+}
+
+fn vreg_bits(vreg: VReg) -> u32 {
+    ((vreg.vreg() as u32) << 1) | (vreg.class() as u32)
+}
+
+fn vreg_from_bits(bits: u32) -> VReg {
+    let class = if bits & 1 == 0 {
+        RegClass::Int
+    } else {
+        RegClass::Float
+    };
+    VReg::new((bits >> 1) as usize, class)
+}
+
+/// A `RematKind` as a tag in its low two bits, with an `Other`
+/// payload above them.
+fn remat_kind_bits(kind: RematKind) -> u64 {
+    match kind {
+        RematKind::Constant => 0,
+        RematKind::Address => 1,
+        RematKind::Other(payload) => ((payload as u64) << 2) | 2,
+    }
+}
+
+fn remat_kind_from_bits(bits: u64) -> RematKind {
+    match bits & 3 {
+        0 => RematKind::Constant,
+        1 => RematKind::Address,
+        _ => RematKind::Other((bits >> 2) as u32),
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+impl CompactOutput {
+    /// Pack the parts of `output` needed for emission.
+    pub fn new(output: &Output) -> Self {
+        let mut words = Vec::with_capacity(output.inst_alloc_offsets.len() + output.allocs.len());
+        let mut sections = [0; NUM_SECTIONS + 1];
+
+        sections[ALLOC_OFFSETS] = words.len() as u32;
+        words.extend_from_slice(&output.inst_alloc_offsets);
+        sections[ALLOCS] = words.len() as u32;
+        words.extend(output.allocs.iter().map(|alloc| alloc.bits()));
+        sections[SPILLSLOT_OFFSETS] = words.len() as u32;
+        words.extend_from_slice(&output.spillslot_offsets);
+        sections[CALLEE_SAVES] = words.len() as u32;
+        for &(preg, slot) in &output.callee_saves {
+            words.push(preg_bits(preg));
+            words.push(spillslot_bits(slot));
+        }
+        sections[ELIDED_MOVES] = words.len() as u32;
+        let num_insts = output.inst_alloc_offsets.len();
+        for chunk in 0..num_insts.div_ceil(32) {
+            let mut word = 0;
+            for bit in 0..32 {
+                if output.elided_moves.get(chunk * 32 + bit) {
+                    word |= 1 << bit;
+                }
+            }
+            words.push(word);
+        }
+        sections[SAFEPOINT_LOCATIONS] = words.len() as u32;
+        for &(inst, vreg, alloc) in &output.safepoint_locations {
+            words.push(inst.index() as u32);
+            words.push(vreg_bits(vreg));
+            words.push(alloc.bits());
+        }
+        sections[PRESERVED_SOFT_CLOBBERS] = words.len() as u32;
+        for &(inst, preg) in &output.preserved_soft_clobbers {
+            words.push(inst.index() as u32);
+            words.push(preg_bits(preg));
+        }
+        sections[SAFEPOINT_SLOTS] = words.len() as u32;
+        for &(inst, slot) in &output.safepoint_slots {
+            words.push(inst.index() as u32);
+            words.push(spillslot_bits(slot));
+        }
+        sections[SAFEPOINT_DERIVED_SLOTS] = words.len() as u32;
+        for &(inst, slot, base) in &output.safepoint_derived_slots {
+            words.push(inst.index() as u32);
+            words.push(spillslot_bits(slot));
+            words.push(spillslot_bits(base));
+        }
+        sections[DEBUG_LOCATIONS] = words.len() as u32;
+        for &(label, from, to, alloc) in &output.debug_locations {
+            words.push(label);
+            words.push(from.inst.index() as u32);
+            words.push(to.inst.index() as u32);
+            words.push(progpoint_pos_bits(from) | (progpoint_pos_bits(to) << 2));
+            words.push(alloc.bits());
+        }
+        sections[NUM_SECTIONS] = words.len() as u32;
+
+        let mut edits = vec![];
+        let mut last: ProgPointIndex = 0;
+        for (pos, edit) in &output.edits {
+            let index = pos.to_index();
+            write_varint(&mut edits, (index - last) as u64);
+            last = index;
+            match edit {
+                Edit::Move { from, to } => {
+                    edits.push(MOVE);
+                    write_varint(&mut edits, from.bits() as u64);
+                    write_varint(&mut edits, to.bits() as u64);
+                }
+                Edit::BlockParams { vregs, allocs } => {
+                    edits.push(BLOCK_PARAMS);
+                    write_varint(&mut edits, vregs.len() as u64);
+                    for (&vreg, alloc) in vregs.iter().zip(allocs.iter()) {
+                        write_varint(&mut edits, vreg_bits(vreg) as u64);
+                        write_varint(&mut edits, alloc.bits() as u64);
+                    }
+                }
+                Edit::DefineConstant { vreg, to } => {
+                    edits.push(DEFINE_CONSTANT);
+                    write_varint(&mut edits, vreg_bits(*vreg) as u64);
+                    write_varint(&mut edits, to.bits() as u64);
+                }
+                Edit::Remat { vreg, kind, to } => {
+                    edits.push(REMAT);
+                    write_varint(&mut edits, vreg_bits(*vreg) as u64);
+                    write_varint(&mut edits, remat_kind_bits(*kind));
+                    write_varint(&mut edits, to.bits() as u64);
+                }
+            }
+        }
+
+        CompactOutput {
+            num_spillslots: output.num_spillslots as u32,
+            spillslot_area_size: output.spillslot_area_size,
+            num_insts: num_insts as u32,
+            sections,
+            words: words.into_boxed_slice(),
+            edits: edits.into_boxed_slice(),
+        }
+    }
+
+    fn section(&self, section: usize) -> &[u32] {
+        let start = self.sections[section] as usize;
+        let end = self.sections[section + 1] as usize;
+        &self.words[start..end]
+    }
+
+    /// The number of bytes held on the heap.
+    pub fn heap_size(&self) -> usize {
+        self.words.len() * std::mem::size_of::<u32>() + self.edits.len()
+    }
+
+    /// See `Output::num_spillslots`.
+    pub fn num_spillslots(&self) -> usize {
+        self.num_spillslots as usize
+    }
+
+    /// See `Output::spillslot_area_size`.
+    pub fn spillslot_area_size(&self) -> u32 {
+        self.spillslot_area_size
+    }
+
+    /// The allocations of `inst`'s operands, as `Output::inst_allocs()`.
+    pub fn inst_allocs(&self, inst: Inst) -> impl Iterator<Item = Allocation> + '_ {
+        let offsets = self.section(ALLOC_OFFSETS);
+        let start = offsets[inst.index()] as usize;
+        let end = offsets
+            .get(inst.index() + 1)
+            .map(|&end| end as usize)
+            .unwrap_or(self.section(ALLOCS).len());
+        self.section(ALLOCS)[start..end]
+            .iter()
+            .map(|&bits| Allocation::from_bits(bits))
+    }
+
+    /// The edits, sorted by program point, as `Output::edits`.
+    pub fn edits(&self) -> CompactEdits<'_> {
+        CompactEdits {
+            bytes: &self.edits,
+            pos: 0,
+            last: 0,
+        }
+    }
+
+    /// See `Output::spillslot_offset()`.
+    pub fn spillslot_offset(&self, slot: SpillSlot) -> Option<u32> {
+        match self.section(SPILLSLOT_OFFSETS).get(slot.index()) {
+            Some(&offset) if offset != u32::MAX => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// See `Output::callee_saves`.
+    pub fn callee_saves(&self) -> impl Iterator<Item = (PReg, SpillSlot)> + '_ {
+        self.section(CALLEE_SAVES)
+            .chunks(2)
+            .map(|pair| (preg_from_bits(pair[0]), spillslot_from_bits(pair[1])))
+    }
+
+    /// See `Output::is_elided_move()`.
+    pub fn is_elided_move(&self, inst: Inst) -> bool {
+        let word = self.section(ELIDED_MOVES)[inst.index() / 32];
+        word & (1 << (inst.index() % 32)) != 0
+    }
+
+    /// See `Output::safepoint_locations`.
+    pub fn safepoint_locations(&self) -> impl Iterator<Item = (Inst, VReg, Allocation)> + '_ {
+        self.section(SAFEPOINT_LOCATIONS).chunks(3).map(|entry| {
+            (
+                Inst::new(entry[0] as usize),
+                vreg_from_bits(entry[1]),
+                Allocation::from_bits(entry[2]),
+            )
+        })
+    }
+
+    /// See `Output::preserved_soft_clobbers`.
+    pub fn preserved_soft_clobbers(&self) -> impl Iterator<Item = (Inst, PReg)> + '_ {
+        self.section(PRESERVED_SOFT_CLOBBERS)
+            .chunks(2)
+            .map(|pair| (Inst::new(pair[0] as usize), preg_from_bits(pair[1])))
+    }
+
+    /// See `Output::safepoint_slots`.
+    pub fn safepoint_slots(&self) -> impl Iterator<Item = (Inst, SpillSlot)> + '_ {
+        self.section(SAFEPOINT_SLOTS)
+            .chunks(2)
+            .map(|pair| (Inst::new(pair[0] as usize), spillslot_from_bits(pair[1])))
+    }
+
+    /// See `Output::safepoint_derived_slots`.
+    pub fn safepoint_derived_slots(
+        &self,
+    ) -> impl Iterator<Item = (Inst, SpillSlot, SpillSlot)> + '_ {
+        self.section(SAFEPOINT_DERIVED_SLOTS)
+            .chunks(3)
+            .map(|entry| {
+                (
+                    Inst::new(entry[0] as usize),
+                    spillslot_from_bits(entry[1]),
+                    spillslot_from_bits(entry[2]),
+                )
+            })
+    }
+
+    /// See `Output::debug_locations`.
+    pub fn debug_locations(
+        &self,
+    ) -> impl Iterator<Item = (u32, ProgPoint, ProgPoint, Allocation)> + '_ {
+        self.section(DEBUG_LOCATIONS).chunks(5).map(|entry| {
+            (
+                entry[0],
+                progpoint_from_bits(entry[1], entry[3]),
+                progpoint_from_bits(entry[2], entry[3] >> 2),
+                Allocation::from_bits(entry[4]),
+            )
+        })
+    }
+
+    /// Unpack into an `Output`, with the fields that are not kept
+    /// left empty.
+    pub fn expand(&self) -> Output {
+        let mut elided_moves = BitVec::new();
+        for inst in 0..self.num_insts as usize {
+            if self.is_elided_move(Inst::new(inst)) {
+                elided_moves.set(inst, true);
+            }
+        }
+        Output {
+            num_spillslots: self.num_spillslots(),
+            edits: self.edits().collect(),
+            allocs: self
+                .section(ALLOCS)
+                .iter()
+                .map(|&bits| Allocation::from_bits(bits))
+                .collect(),
+            inst_alloc_offsets: self.section(ALLOC_OFFSETS).to_vec(),
+            spillslot_offsets: self.section(SPILLSLOT_OFFSETS).to_vec(),
+            spillslot_area_size: self.spillslot_area_size,
+            callee_saves: self.callee_saves().collect(),
+            elided_moves,
+            edge_moves: vec![],
+            block_edits: vec![],
+            used_regs: vec![],
+            edit_clobbered_regs: vec![],
+            explanations: vec![],
+            safepoint_locations: self.safepoint_locations().collect(),
+            preserved_soft_clobbers: self.preserved_soft_clobbers().collect(),
+            safepoint_slots: self.safepoint_slots().collect(),
+            safepoint_derived_slots: self.safepoint_derived_slots().collect(),
+            debug_locations: self.debug_locations().collect(),
+            fingerprint: Default::default(),
+            stats: Default::default(),
+        }
+    }
+}
+
+/// An iterator over the edits of a `CompactOutput`.
+pub struct CompactEdits<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    last: ProgPointIndex,
+}
+
+impl<'a> Iterator for CompactEdits<'a> {
+    type Item = (ProgPoint, Edit);
+
+    fn next(&mut self) -> Option<(ProgPoint, Edit)> {
+        if self.pos == self.bytes.len() {
+            return None;
+        }
+        let bytes = self.bytes;
+        self.last += read_varint(bytes, &mut self.pos) as ProgPointIndex;
+        let tag = bytes[self.pos];
+        self.pos += 1;
+        let read_alloc = |pos: &mut usize| Allocation::from_bits(read_varint(bytes, pos) as u32);
+        let edit = match tag {
+            MOVE => {
+                let from = read_alloc(&mut self.pos);
+                let to = read_alloc(&mut self.pos);
+                Edit::Move { from, to }
+            }
+            BLOCK_PARAMS => {
+                let len = read_varint(bytes, &mut self.pos) as usize;
+                let mut vregs = Vec::with_capacity(len);
+                let mut allocs = Vec::with_capacity(len);
+                for _ in 0..len {
+                    vregs.push(vreg_from_bits(read_varint(bytes, &mut self.pos) as u32));
+                    allocs.push(read_alloc(&mut self.pos));
+                }
+                Edit::BlockParams { vregs, allocs }
+            }
+            DEFINE_CONSTANT => {
+                let vreg = vreg_from_bits(read_varint(bytes, &mut self.pos) as u32);
+                let to = read_alloc(&mut self.pos);
+                Edit::DefineConstant { vreg, to }
+            }
+            REMAT => {
+                let vreg = vreg_from_bits(read_varint(bytes, &mut self.pos) as u32);
+                let kind = remat_kind_from_bits(read_varint(bytes, &mut self.pos));
+                let to = read_alloc(&mut self.pos);
+                Edit::Remat { vreg, kind, to }
+            }
+            _ => unreachable!(),
+        };
+        Some((ProgPoint::from_index(self.last), edit))
+    }
+}
+
+/// Run the allocator, as `run()`, and pack the result.
+pub fn run_compact<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<CompactOutput, RegAllocError> {
+    crate::run_with_options(func, env, options).map(|output| CompactOutput::new(&output))
+}
+
+#[cfg(all(test, feature = "checker"))]
+mod test {
+    use super::*;
+    use crate::checker::check;
+    use crate::fuzzing::func::{machine_env, FuncBuilder, InstData, InstOpcode};
+    use crate::{Operand, PReg, RegClass, SpillSlot, VReg};
+
+    #[test]
+    fn test_compact_output() {
+        // A reference and a moved value live across a call that
+        // clobbers the caller-saved registers, one then used in a
+        // callee-saved register: every kept section has an entry.
+        let env = machine_env();
+        let caller_saved: Vec<PReg> = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let callee_saved = PReg::new(30, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .reftype(v0)
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[]);
+        builder.add_inst(
+            block,
+            InstData::new(
+                InstOpcode::Move,
+                vec![Operand::reg_def(v2), Operand::reg_use(v1)],
+                vec![],
+            ),
+        );
+        builder.add_inst(block, InstData::new(InstOpcode::Call, vec![], caller_saved));
+        builder
+            .inst(
+                block,
+                &[
+                    Operand::reg_use(v0),
+                    Operand::reg_fixed_use(v2, callee_saved),
+                ],
+                &[],
+            )
+            .ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions {
+            report_blockparams: true,
+            save_callee_saves: true,
+            report_safepoint_locations: true,
+            ..RegallocOptions::default()
+        };
+        let out = crate::run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        assert!(!out.edits.is_empty());
+        assert!(!out.callee_saves.is_empty());
+        assert!(!out.safepoint_locations.is_empty());
+        assert!(!out.safepoint_slots.is_empty());
+
+        let compact = run_compact(&func, &env, &options).unwrap();
+        let full = (out.allocs.len() + out.inst_alloc_offsets.len()) * 4
+            + out.edits.len() * std::mem::size_of::<(ProgPoint, Edit)>()
+            + out.safepoint_locations.len() * std::mem::size_of::<(Inst, VReg, Allocation)>()
+            + out.safepoint_slots.len() * std::mem::size_of::<(Inst, SpillSlot)>()
+            + out.callee_saves.len() * std::mem::size_of::<(PReg, SpillSlot)>();
+        assert!(compact.heap_size() < full);
+
+        assert_eq!(compact.num_spillslots(), out.num_spillslots);
+        assert_eq!(compact.edits().collect::<Vec<_>>(), out.edits);
+        for inst in 0..func.insts() {
+            let inst = Inst::new(inst);
+            let allocs: Vec<Allocation> = compact.inst_allocs(inst).collect();
+            assert_eq!(allocs, out.inst_allocs(inst));
+            assert_eq!(compact.is_elided_move(inst), out.is_elided_move(inst));
+        }
+        assert_eq!(compact.callee_saves().collect::<Vec<_>>(), out.callee_saves);
+        assert_eq!(
+            compact.safepoint_locations().collect::<Vec<_>>(),
+            out.safepoint_locations
+        );
+        assert_eq!(
+            compact.safepoint_slots().collect::<Vec<_>>(),
+            out.safepoint_slots
+        );
+
+        // The expanded output is as good as the original for the
+        // checker.
+        check(&func, &env, &compact.expand()).expect("checker failed");
+    }
+}
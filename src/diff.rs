@@ -0,0 +1,236 @@
+//! Structured comparison of two allocations of the same function,
+//! e.g. under different options or by different versions of the
+//! allocator: which operands moved, which edits came and went, and
+//! how the frame changed. This is meant for evaluating heuristic
+//! changes, without comparing two full dumps of `Output` by eye.
+
+use crate::liveness::inst_operands;
+use crate::{
+    Allocation, Edit, Function, Inst, InstPosition, MachineEnv, Output, ProgPoint, RegAllocError,
+    RegallocOptions, VReg,
+};
+
+/// An operand that was allocated differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OperandChange {
+    pub inst: Inst,
+    /// The operand's index in `Function::inst_operands()`.
+    pub slot: usize,
+    pub vreg: VReg,
+    pub before: Allocation,
+    pub after: Allocation,
+}
+
+/// The differences between two allocations of one function.
+#[derive(Clone, Debug, Default)]
+pub struct OutputDiff {
+    /// Operands allocated differently, in program order.
+    pub operands: Vec<OperandChange>,
+    /// Edits only in the first allocation, sorted by program point.
+    pub removed_edits: Vec<(ProgPoint, Edit)>,
+    /// Edits only in the second allocation, sorted by program point.
+    pub added_edits: Vec<(ProgPoint, Edit)>,
+    /// `Output::num_spillslots` of the first and second allocations.
+    pub spillslots: (usize, usize),
+    /// `Output::spillslot_area_size` of the first and second
+    /// allocations.
+    pub spillslot_area_size: (u32, u32),
+}
+
+impl OutputDiff {
+    /// Do the two allocations agree entirely?
+    pub fn is_empty(&self) -> bool {
+        self.operands.is_empty()
+            && self.removed_edits.is_empty()
+            && self.added_edits.is_empty()
+            && self.spillslots.0 == self.spillslots.1
+            && self.spillslot_area_size.0 == self.spillslot_area_size.1
+    }
+
+    /// The vregs with some operand allocated differently, sorted.
+    pub fn changed_vregs(&self) -> Vec<VReg> {
+        let mut vregs: Vec<VReg> = self.operands.iter().map(|change| change.vreg).collect();
+        vregs.sort_unstable();
+        vregs.dedup();
+        vregs
+    }
+}
+
+impl std::fmt::Display for OutputDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.spillslots.0 != self.spillslots.1 {
+            writeln!(
+                f,
+                "spillslots: {} -> {}",
+                self.spillslots.0, self.spillslots.1
+            )?;
+        }
+        if self.spillslot_area_size.0 != self.spillslot_area_size.1 {
+            writeln!(
+                f,
+                "spillslot area size: {} -> {}",
+                self.spillslot_area_size.0, self.spillslot_area_size.1
+            )?;
+        }
+        for change in &self.operands {
+            writeln!(
+                f,
+                "inst{} operand {} ({}): {} -> {}",
+                change.inst.index(),
+                change.slot,
+                change.vreg,
+                change.before,
+                change.after
+            )?;
+        }
+        let point = |point: &ProgPoint| {
+            let pos = match point.pos {
+                InstPosition::Before => "before",
+                InstPosition::After => "after",
+            };
+            format!("{} inst{}", pos, point.inst.index())
+        };
+        // Interleave removed and added edits in program order.
+        let mut edits: Vec<(&str, &ProgPoint, &Edit)> = self
+            .removed_edits
+            .iter()
+            .map(|(at, edit)| ("-", at, edit))
+            .chain(self.added_edits.iter().map(|(at, edit)| ("+", at, edit)))
+            .collect();
+        edits.sort_by_key(|&(_, at, _)| *at);
+        for (sign, at, edit) in edits {
+            writeln!(f, "{} {}: {}", sign, point(at), edit)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare two allocations, `before` and `after`, of `func`.
+pub fn diff<F: Function>(func: &F, before: &Output, after: &Output) -> OutputDiff {
+    let mut operands = vec![];
+    for inst in 0..func.insts() {
+        let inst = Inst::new(inst);
+        let allocs = before.inst_allocs(inst).iter().zip(after.inst_allocs(inst));
+        for (slot, (&b, &a)) in allocs.enumerate() {
+            if a != b {
+                operands.push(OperandChange {
+                    inst,
+                    slot,
+                    vreg: inst_operands(func, inst)[slot].vreg(),
+                    before: b,
+                    after: a,
+                });
+            }
+        }
+    }
+
+    // Match up the edits at each program point, in either order.
+    let (mut removed_edits, mut added_edits) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    while i < before.edits.len() || j < after.edits.len() {
+        let point = match (before.edits.get(i), after.edits.get(j)) {
+            (Some((a, _)), Some((b, _))) => std::cmp::min(*a, *b),
+            (Some((a, _)), None) => *a,
+            (None, Some((b, _))) => *b,
+            (None, None) => unreachable!(),
+        };
+        let i_end = i + before.edits[i..]
+            .iter()
+            .take_while(|e| e.0 == point)
+            .count();
+        let j_end = j + after.edits[j..].iter().take_while(|e| e.0 == point).count();
+        let mut unmatched: Vec<&(ProgPoint, Edit)> = after.edits[j..j_end].iter().collect();
+        for edit in &before.edits[i..i_end] {
+            match unmatched.iter().position(|other| other.1 == edit.1) {
+                Some(k) => {
+                    unmatched.remove(k);
+                }
+                None => removed_edits.push(edit.clone()),
+            }
+        }
+        added_edits.extend(unmatched.into_iter().cloned());
+        i = i_end;
+        j = j_end;
+    }
+
+    OutputDiff {
+        operands,
+        removed_edits,
+        added_edits,
+        spillslots: (before.num_spillslots, after.num_spillslots),
+        spillslot_area_size: (before.spillslot_area_size, after.spillslot_area_size),
+    }
+}
+
+/// Allocate `func` with each of two sets of options and compare the
+/// results.
+pub fn diff_options<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    before: &RegallocOptions,
+    after: &RegallocOptions,
+) -> Result<OutputDiff, RegAllocError> {
+    let a = crate::run_with_options(func, env, before)?;
+    let b = crate::run_with_options(func, env, after)?;
+    Ok(diff(func, &a, &b))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzzing::func::{machine_env, FuncBuilder};
+    use crate::{Operand, PReg, RegClass};
+
+    #[test]
+    fn test_diff() {
+        // Two values live across an instruction that clobbers p0 and
+        // p1, allocated once with only those two registers, so that
+        // both are spilled, and once with only p2 and p3: every
+        // operand moves, and every spill and reload goes away.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        let (p0, p1) = (PReg::new(0, RegClass::Int), PReg::new(1, RegClass::Int));
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[], &[p0, p1])
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let run = |regs: std::ops::Range<usize>| {
+            let mut env = machine_env();
+            env.regs = regs.map(|i| PReg::new(i, RegClass::Int)).collect();
+            env.regs_by_class[RegClass::Int as usize] = env.regs.clone();
+            env.callee_saved_regs.clear();
+            crate::run(&func, &env).expect("regalloc did not succeed")
+        };
+        let (before, after) = (run(0..2), run(2..4));
+        assert!(diff(&func, &before, &before).is_empty());
+
+        let d = diff(&func, &before, &after);
+        assert!(!d.is_empty());
+        assert_eq!(d.changed_vregs(), vec![v0, v1]);
+        assert_eq!(d.operands.len(), 4);
+        for change in &d.operands {
+            assert_eq!(before.inst_allocs(change.inst)[change.slot], change.before);
+            assert_eq!(after.inst_allocs(change.inst)[change.slot], change.after);
+            assert_eq!(
+                func.inst_operands(change.inst)[change.slot].vreg(),
+                change.vreg
+            );
+        }
+        assert!(after.edits.is_empty());
+        assert!(!before.edits.is_empty());
+        assert_eq!(d.removed_edits, before.edits);
+        assert!(d.added_edits.is_empty());
+        assert_eq!(d.spillslots, (2, 0));
+        assert!(!d.to_string().is_empty());
+
+        // The same options give the same allocation.
+        let env = machine_env();
+        let options = RegallocOptions::default();
+        let d = diff_options(&func, &env, &options, &options).expect("regalloc did not succeed");
+        assert!(d.is_empty());
+    }
+}
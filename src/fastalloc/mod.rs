@@ -0,0 +1,1026 @@
+//! A fast, single-pass allocator for baseline and debug compiles,
+//! selected with `AllocatorAlgorithm::Fast`.
+//!
+//! Every vreg lives in a spillslot of its own for its whole lifetime.
+//! Registers are used only around each instruction: the operands that
+//! need one are reloaded into it just before the instruction, and
+//! stored back to their spillslots just after it, so that no value is
+//! ever held in a register from one instruction to the next. There is
+//! no liveness analysis, bundle merging, allocation queue, eviction or
+//! splitting; each instruction is allocated once, in order. The code
+//! it produces is much slower than the backtracking allocator's, but
+//! takes a fraction of the time to produce.
+//!
+//! Functions that this scheme cannot allocate are handed to the
+//! backtracking allocator instead: those with points where edits are
+//! forbidden (`Function::no_edits_after`, or live condition flags),
+//! branches with delay slots, vregs that must not be spilled
+//! (`Function::no_spill`) or are pinned to a register
+//! (`Function::pinned_vreg`), or an instruction needing more registers
+//! than are left after its fixed registers and clobbers; and those
+//! whose spillslots would exceed `RegallocOptions::max_spillslots`.
+//!
+//! Liveness is only computed for functions with safepoints, to find
+//! the references live across each. Otherwise, a use of a vreg that
+//! its def does not dominate is caught only by
+//! `RegallocOptions::validate_ssa`.
+
+use crate::bitvec::BitVec;
+use crate::cfg::CFGInfo;
+use crate::ion::{sort_debug_locations, CodeRange};
+use crate::liveness::{inst_operands, LivenessInfo};
+use crate::moves::ParallelMoves;
+use crate::{
+    Allocation, AllocationKind, AllocatorAlgorithm, Block, BlockEdits, EdgeMoves, Edit, Function,
+    Inst, MachineEnv, OperandKind, OperandPolicy, OperandPos, Output, PReg, ProgPoint,
+    ProgPointIndex, RegAllocError, RegClass, RegallocOptions, SpillSlot, VReg,
+};
+use smallvec::{smallvec, SmallVec};
+use std::collections::BTreeMap;
+
+/// The order of the edits at one program point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum EditPrio {
+    CalleeSave,
+    InEdgeMoves,
+    BlockParam,
+    Reload,
+    Spill,
+    OutEdgeMoves,
+    CalleeRestore,
+}
+
+struct Env<'a, F: Function> {
+    func: &'a F,
+    env: &'a MachineEnv,
+    opts: &'a RegallocOptions,
+    cfginfo: &'a CFGInfo,
+
+    /// The spillslot of each vreg, once it has one.
+    vreg_slots: Vec<Option<SpillSlot>>,
+    /// Every spillslot allocated, with its size, in index order.
+    spillslots: Vec<(SpillSlot, u32)>,
+    num_spillslots: u32,
+    /// The vregs defined with `OperandPolicy::Constant`.
+    constants: BitVec,
+    /// The reference-typed vregs, other than constants.
+    reftypes: Vec<VReg>,
+    safepoints: Vec<Inst>,
+    /// The soft clobbers a fixed-register operand holds a value in
+    /// past the instruction's `Before` uses.
+    preserved_soft_clobbers: Vec<(Inst, PReg)>,
+
+    allocs: Vec<Allocation>,
+    inst_alloc_offsets: Vec<u32>,
+    edits: Vec<(ProgPointIndex, EditPrio, Edit)>,
+    callee_saves: Vec<(PReg, SpillSlot)>,
+}
+
+impl<'a, F: Function> Env<'a, F> {
+    fn new(
+        func: &'a F,
+        env: &'a MachineEnv,
+        opts: &'a RegallocOptions,
+        cfginfo: &'a CFGInfo,
+    ) -> Self {
+        let mut constants = BitVec::with_capacity(func.num_vregs());
+        for inst in 0..func.insts() {
+            func.visit_operands(Inst::new(inst), &mut |_, op| {
+                if op.kind() == OperandKind::Def && op.policy() == OperandPolicy::Constant {
+                    constants.set(op.vreg().vreg(), true);
+                }
+            });
+        }
+        Env {
+            func,
+            env,
+            opts,
+            cfginfo,
+            vreg_slots: vec![None; func.num_vregs()],
+            spillslots: vec![],
+            num_spillslots: func.num_reserved_spillslots() as u32,
+            constants,
+            reftypes: vec![],
+            safepoints: vec![],
+            preserved_soft_clobbers: vec![],
+            allocs: vec![],
+            inst_alloc_offsets: Vec::with_capacity(func.insts()),
+            edits: vec![],
+            callee_saves: vec![],
+        }
+    }
+
+    /// Allocate a new spillslot after all others, aligned to its size.
+    fn allocate_spillslot(&mut self, class: RegClass, size: u32) -> SpillSlot {
+        debug_assert!(size.is_power_of_two());
+        let offset = (self.num_spillslots + size - 1) & !(size - 1);
+        let slot = if self.func.multi_spillslot_named_by_last_slot() {
+            offset + size - 1
+        } else {
+            offset
+        };
+        let slot = SpillSlot::new(slot as usize, class);
+        self.spillslots.push((slot, size));
+        self.num_spillslots = offset + size;
+        slot
+    }
+
+    /// The spillslot holding `vreg`, allocated on first mention.
+    fn slot(&mut self, vreg: VReg) -> Allocation {
+        let slot = match self.vreg_slots[vreg.vreg()] {
+            Some(slot) => slot,
+            None => {
+                let size = self.func.spillslot_size(vreg.class(), vreg) as u32;
+                let slot = self.allocate_spillslot(vreg.class(), size);
+                self.vreg_slots[vreg.vreg()] = Some(slot);
+                if self.func.is_reftype(vreg) && !self.constants.get(vreg.vreg()) {
+                    self.reftypes.push(vreg);
+                }
+                slot
+            }
+        };
+        Allocation::stack(slot)
+    }
+
+    fn add_edit(&mut self, pos: ProgPoint, prio: EditPrio, edit: Edit) {
+        self.edits.push((pos.to_index(), prio, edit));
+    }
+
+    /// Load the value of `vreg` into `to` just before `inst`: from
+    /// its spillslot, or as a constant.
+    fn reload(&mut self, inst: Inst, vreg: VReg, to: Allocation) {
+        let edit = if self.constants.get(vreg.vreg()) {
+            Edit::DefineConstant { vreg, to }
+        } else {
+            Edit::Move {
+                from: self.slot(vreg),
+                to,
+            }
+        };
+        self.add_edit(ProgPoint::before(inst), EditPrio::Reload, edit);
+    }
+
+    /// Store the value of `vreg` from `from` to its spillslot just
+    /// after `inst`.
+    fn spill(&mut self, inst: Inst, vreg: VReg, from: Allocation) {
+        let to = self.slot(vreg);
+        self.add_edit(
+            ProgPoint::after(inst),
+            EditPrio::Spill,
+            Edit::Move { from, to },
+        );
+    }
+
+    /// Take a register of `class` that satisfies `policy` and is not
+    /// in `taken`, preferring that of a `PrefReg` policy, and then
+    /// those in `MachineEnv::preferred_regs_by_class`: no value stays
+    /// in a register across an instruction, let alone a call.
+    fn pick_reg(
+        &self,
+        policy: OperandPolicy,
+        class: RegClass,
+        taken: &mut SmallVec<[PReg; 16]>,
+    ) -> Option<PReg> {
+        let regs = &self.env.regs_by_class[class as u8 as usize];
+        let ok = |preg: PReg| {
+            !taken.contains(&preg)
+                && match policy {
+                    OperandPolicy::RegExcept(excluded) => !excluded.contains(preg),
+                    _ => true,
+                }
+        };
+        let preferred = match policy {
+            OperandPolicy::PrefReg(pref) => regs.iter().find(|&&preg| preg == pref && ok(preg)),
+            _ => None,
+        };
+        let preg = *preferred
+            .or_else(|| {
+                self.env
+                    .preferred_regs_by_class
+                    .get(class as u8 as usize)?
+                    .iter()
+                    .find(|&&preg| regs.contains(&preg) && ok(preg))
+            })
+            .or_else(|| regs.iter().find(|&&preg| ok(preg)))?;
+        taken.push(preg);
+        Some(preg)
+    }
+
+    /// Allocate the operands of `inst`. Returns `None` if it needs
+    /// more registers than are free.
+    fn allocate_inst(&mut self, inst: Inst) -> Option<()> {
+        let operands = inst_operands(self.func, inst);
+        let is_branch = self.func.is_branch(inst);
+        if is_branch && self.func.branch_has_delay_slot(inst) {
+            return None;
+        }
+        if self.func.is_safepoint(inst) {
+            self.safepoints.push(inst);
+        }
+
+        // The registers no other operand may take: those fixed by
+        // an operand, and those the instruction clobbers. A return's
+        // operands must also avoid the callee-saved registers, which
+        // are restored just before it.
+        let mut taken: SmallVec<[PReg; 16]> = smallvec![];
+        for op in &operands {
+            if let OperandPolicy::FixedReg(preg) = op.policy() {
+                taken.push(preg);
+            }
+        }
+        taken.extend(self.env.inst_clobbers(self.func, inst));
+        taken.extend(self.func.inst_soft_clobbers(inst).iter().cloned());
+        if self.opts.save_callee_saves && self.func.is_ret(inst) {
+            taken.extend(self.env.callee_saved_regs.iter().cloned());
+        }
+
+        let mut allocs: SmallVec<[Allocation; 8]> = smallvec![Allocation::none(); operands.len()];
+        for (i, op) in operands.iter().enumerate() {
+            let vreg = op.vreg();
+            if self.func.no_spill(vreg) || self.func.pinned_vreg(vreg).is_some() {
+                return None;
+            }
+            let constant = self.constants.get(vreg.vreg());
+            // An input reused by a def must be in a register.
+            let reused = operands
+                .iter()
+                .any(|def| def.policy() == OperandPolicy::Reuse(i));
+            let policy = match op.policy() {
+                OperandPolicy::Reuse(_) => continue,
+                policy @ OperandPolicy::FixedReg(_) => policy,
+                _ if reused => OperandPolicy::Reg,
+                policy => policy,
+            };
+            allocs[i] = match (op.kind(), policy) {
+                // A branch arg read only by the out-edge moves stays
+                // where it is.
+                (OperandKind::Use, _) if is_branch && op.pos() == OperandPos::Transfer => {
+                    if constant {
+                        Allocation::none()
+                    } else {
+                        self.slot(vreg)
+                    }
+                }
+                (OperandKind::Use, OperandPolicy::Any | OperandPolicy::Stack) if !constant => {
+                    self.slot(vreg)
+                }
+                (OperandKind::Use, OperandPolicy::Stack) => {
+                    let slot = self.slot(vreg);
+                    self.reload(inst, vreg, slot);
+                    slot
+                }
+                (OperandKind::Use, OperandPolicy::FixedStack(slot)) => {
+                    let slot = Allocation::stack(slot);
+                    self.reload(inst, vreg, slot);
+                    slot
+                }
+                (OperandKind::Use, OperandPolicy::FixedReg(preg)) => {
+                    self.reload(inst, vreg, Allocation::reg(preg));
+                    Allocation::reg(preg)
+                }
+                (OperandKind::Use, policy) => {
+                    let preg = self.pick_reg(policy, op.class(), &mut taken)?;
+                    self.reload(inst, vreg, Allocation::reg(preg));
+                    Allocation::reg(preg)
+                }
+                (OperandKind::Def, OperandPolicy::Constant) => Allocation::none(),
+                (OperandKind::Def, OperandPolicy::Any | OperandPolicy::Stack) => self.slot(vreg),
+                (OperandKind::Def, OperandPolicy::FixedStack(slot)) => {
+                    self.spill(inst, vreg, Allocation::stack(slot));
+                    Allocation::stack(slot)
+                }
+                (OperandKind::Def, OperandPolicy::FixedReg(preg)) => {
+                    self.spill(inst, vreg, Allocation::reg(preg));
+                    Allocation::reg(preg)
+                }
+                (OperandKind::Def, policy) => {
+                    let preg = self.pick_reg(policy, op.class(), &mut taken)?;
+                    self.spill(inst, vreg, Allocation::reg(preg));
+                    Allocation::reg(preg)
+                }
+                // Modified in its spillslot, or reloaded into a
+                // register and stored back.
+                (OperandKind::Mod, OperandPolicy::Any | OperandPolicy::Stack) => self.slot(vreg),
+                (OperandKind::Mod, OperandPolicy::FixedReg(preg)) => {
+                    self.reload(inst, vreg, Allocation::reg(preg));
+                    self.spill(inst, vreg, Allocation::reg(preg));
+                    Allocation::reg(preg)
+                }
+                (OperandKind::Mod, policy) => {
+                    let preg = self.pick_reg(policy, op.class(), &mut taken)?;
+                    self.reload(inst, vreg, Allocation::reg(preg));
+                    self.spill(inst, vreg, Allocation::reg(preg));
+                    Allocation::reg(preg)
+                }
+            };
+        }
+        // A reusing def is written to the register its input was
+        // reloaded into.
+        for (i, op) in operands.iter().enumerate() {
+            if let OperandPolicy::Reuse(idx) = op.policy() {
+                allocs[i] = allocs[idx];
+                self.spill(inst, op.vreg(), allocs[i]);
+            }
+        }
+
+        let soft_clobbers = self.func.inst_soft_clobbers(inst);
+        for op in &operands {
+            if let OperandPolicy::FixedReg(preg) = op.policy() {
+                let read_first = op.kind() == OperandKind::Use && op.pos() == OperandPos::Before;
+                if !read_first && soft_clobbers.contains(&preg) {
+                    self.preserved_soft_clobbers.push((inst, preg));
+                }
+            }
+        }
+
+        self.inst_alloc_offsets.push(self.allocs.len() as u32);
+        self.allocs.extend(allocs);
+        Some(())
+    }
+
+    /// Move the args of the branch ending `block` into the spillslots
+    /// of its successors' blockparams.
+    fn insert_edge_moves(&mut self, block: Block) {
+        let last = self.func.block_insns(block).last();
+        let succs = self.func.block_succs(block);
+        if succs.is_empty() {
+            return;
+        }
+        let args: SmallVec<[VReg; 8]> = inst_operands(self.func, last)
+            .iter()
+            .filter(|op| op.kind() == OperandKind::Use)
+            .map(|op| op.vreg())
+            .collect();
+        let mut next_arg = 0;
+        for &succ in succs {
+            let params = self.func.block_params(succ);
+            let args = &args[next_arg..next_arg + params.len()];
+            next_arg += params.len();
+            if params.is_empty() {
+                continue;
+            }
+
+            // As in the backtracking allocator: at the end of the
+            // predecessor if the successor has others, and otherwise
+            // at the start of the successor. There are no critical
+            // edges.
+            let to_ins = self.func.block_preds(succ).len()
+                + if succ == self.func.entry_block() {
+                    1
+                } else {
+                    0
+                };
+            let (pos, prio) = if to_ins > 1 {
+                debug_assert_eq!(succs.len(), 1);
+                (ProgPoint::after(last), EditPrio::OutEdgeMoves)
+            } else {
+                let first = self.func.block_insns(succ).first();
+                (ProgPoint::before(first), EditPrio::InEdgeMoves)
+            };
+
+            // Nothing is held in a register between instructions, so
+            // any register of the class will do as the scratch.
+            for class in [RegClass::Int, RegClass::Float] {
+                let scratch = self
+                    .env
+                    .scratch_by_class
+                    .get(class as u8 as usize)
+                    .or_else(|| self.env.regs_by_class[class as u8 as usize].first())
+                    .map_or(Allocation::none(), |&preg| Allocation::reg(preg));
+                let mut moves = ParallelMoves::new(scratch);
+                let mut constants: SmallVec<[(VReg, Allocation); 4]> = smallvec![];
+                for (&arg, &param) in args.iter().zip(params.iter()) {
+                    if param.class() != class {
+                        continue;
+                    }
+                    let to = self.slot(param);
+                    if self.constants.get(arg.vreg()) {
+                        constants.push((arg, to));
+                    } else {
+                        let from = self.slot(arg);
+                        if from != to {
+                            moves.add(from, to);
+                        }
+                    }
+                }
+                for (from, to) in moves.resolve() {
+                    self.add_edit(pos, prio, Edit::Move { from, to });
+                }
+                for (vreg, to) in constants {
+                    self.add_edit(pos, prio, Edit::DefineConstant { vreg, to });
+                }
+            }
+        }
+    }
+
+    fn allocate(&mut self) -> Option<()> {
+        let report_blockparams = self.opts.report_blockparams || self.opts.validates_output();
+        for block in 0..self.func.blocks() {
+            let block = Block::new(block);
+            let params = self.func.block_params(block);
+            if params
+                .iter()
+                .any(|&vreg| self.func.no_spill(vreg) || self.func.pinned_vreg(vreg).is_some())
+            {
+                return None;
+            }
+            if report_blockparams && !params.is_empty() {
+                let allocs = params.iter().map(|&vreg| self.slot(vreg)).collect();
+                self.add_edit(
+                    self.cfginfo.block_entry[block.index()],
+                    EditPrio::BlockParam,
+                    Edit::BlockParams {
+                        vregs: params.to_vec(),
+                        allocs,
+                    },
+                );
+            }
+            for inst in self.func.block_insns(block).iter() {
+                self.allocate_inst(inst)?;
+            }
+            self.insert_edge_moves(block);
+        }
+        Some(())
+    }
+
+    /// The registers the allocation uses, and those edits write; see
+    /// `Output::used_regs`.
+    fn used_regs(&self) -> (Vec<PReg>, Vec<PReg>) {
+        let mut used = vec![];
+        let mut clobbered = vec![];
+        used.extend(self.allocs.iter().filter_map(|alloc| alloc.as_reg()));
+        for (_, _, edit) in &self.edits {
+            match *edit {
+                Edit::Move { from, to } => {
+                    used.extend(from.as_reg());
+                    clobbered.extend(to.as_reg());
+                }
+                Edit::DefineConstant { to, .. } | Edit::Remat { to, .. } => {
+                    clobbered.extend(to.as_reg())
+                }
+                Edit::BlockParams { .. } => {}
+            }
+        }
+        used.extend(clobbered.iter().cloned());
+        used.sort_unstable_by_key(|preg| preg.index());
+        used.dedup();
+        clobbered.sort_unstable_by_key(|preg| preg.index());
+        clobbered.dedup();
+        (used, clobbered)
+    }
+
+    fn insert_callee_saves(&mut self, used: &[PReg]) {
+        let entry = ProgPoint::before(self.func.block_insns(self.func.entry_block()).first());
+        let rets: Vec<Inst> = (0..self.func.insts())
+            .map(Inst::new)
+            .filter(|&inst| self.func.is_ret(inst))
+            .collect();
+        for i in 0..self.env.callee_saved_regs.len() {
+            let preg = self.env.callee_saved_regs[i];
+            if !used.contains(&preg) {
+                continue;
+            }
+            let size = self
+                .func
+                .spillslot_size(preg.class(), VReg::new(VReg::MAX, preg.class()))
+                as u32;
+            let slot = self.allocate_spillslot(preg.class(), size);
+            let (reg, stack) = (Allocation::reg(preg), Allocation::stack(slot));
+            self.add_edit(
+                entry,
+                EditPrio::CalleeSave,
+                Edit::Move {
+                    from: reg,
+                    to: stack,
+                },
+            );
+            for &ret in &rets {
+                self.add_edit(
+                    ProgPoint::before(ret),
+                    EditPrio::CalleeRestore,
+                    Edit::Move {
+                        from: stack,
+                        to: reg,
+                    },
+                );
+            }
+            self.callee_saves.push((preg, slot));
+        }
+    }
+
+    /// The stackmap of each safepoint, and the locations of the vregs
+    /// live across it if asked for; see `Output::safepoint_slots`,
+    /// `Output::safepoint_derived_slots` and
+    /// `Output::safepoint_locations`.
+    #[allow(clippy::type_complexity)]
+    fn compute_safepoints(
+        &self,
+    ) -> Result<
+        (
+            Vec<(Inst, SpillSlot)>,
+            Vec<(Inst, SpillSlot, SpillSlot)>,
+            Vec<(Inst, VReg, Allocation)>,
+        ),
+        RegAllocError,
+    > {
+        let mut slots = vec![];
+        let mut derived = vec![];
+        let mut locations = vec![];
+        let report = self.opts.report_safepoint_locations;
+        if self.safepoints.is_empty() {
+            return Ok((slots, derived, locations));
+        }
+        for (vreg, slot) in self.vreg_slots.iter().enumerate() {
+            let vreg = match slot {
+                Some(slot) => VReg::new(vreg, slot.class()),
+                None => continue,
+            };
+            if let Some(base) = self.func.derived_ref_base(vreg) {
+                if !self.func.is_reftype(vreg)
+                    || !self.func.is_reftype(base)
+                    || self.func.derived_ref_base(base).is_some()
+                    || self.constants.get(base.vreg())
+                    || self.vreg_slots[base.vreg()].is_none()
+                {
+                    return Err(RegAllocError::DerivedRef(vreg));
+                }
+            }
+        }
+        if self.reftypes.is_empty() && !report {
+            return Ok((slots, derived, locations));
+        }
+        let liveness = LivenessInfo::new(self.func, self.cfginfo)?;
+        for &inst in &self.safepoints {
+            let block = self.cfginfo.insn_block[inst.index()];
+            let operands = inst_operands(self.func, inst);
+            let is_def = |vreg: VReg| {
+                operands
+                    .iter()
+                    .any(|op| op.kind() != OperandKind::Use && op.vreg() == vreg)
+            };
+            // Live both before and after the safepoint, and not
+            // defined by it.
+            let live_across = |vreg: VReg| {
+                let live_after = if inst == self.func.block_insns(block).last() {
+                    self.func.is_branch(inst) && liveness.is_live_out(block, vreg)
+                } else {
+                    liveness.is_live_at(vreg, ProgPoint::before(inst.next()))
+                };
+                live_after && liveness.is_live_at(vreg, ProgPoint::before(inst)) && !is_def(vreg)
+            };
+            for &vreg in &self.reftypes {
+                if !live_across(vreg) {
+                    continue;
+                }
+                let slot = self.vreg_slots[vreg.vreg()].unwrap();
+                match self.func.derived_ref_base(vreg) {
+                    // The safepoint's own def of the base is not the
+                    // one the vreg was derived from.
+                    Some(base) if is_def(base) => slots.push((inst, slot)),
+                    // The base's slot is its own for the whole
+                    // function, so it still holds the base where the
+                    // base is dead, even past a def the safepoint
+                    // writes at `AfterLate`. (A function handed to the
+                    // backtracking allocator keeps the base alive in
+                    // its slot through `AfterLate` instead.)
+                    Some(base) => {
+                        let base_slot = self.vreg_slots[base.vreg()].unwrap();
+                        derived.push((inst, slot, base_slot));
+                        slots.push((inst, base_slot));
+                    }
+                    None => slots.push((inst, slot)),
+                }
+            }
+            if report {
+                for (vreg, slot) in self.vreg_slots.iter().enumerate() {
+                    let slot = match slot {
+                        Some(slot) if !self.constants.get(vreg) => *slot,
+                        _ => continue,
+                    };
+                    let vreg = VReg::new(vreg, slot.class());
+                    if live_across(vreg) {
+                        locations.push((inst, vreg, Allocation::stack(slot)));
+                    }
+                }
+            }
+        }
+        slots.sort_unstable_by_key(|&(inst, slot)| (inst, slot.index()));
+        slots.dedup();
+        derived.sort_unstable_by_key(|&(inst, slot, _)| (inst, slot.index()));
+        Ok((slots, derived, locations))
+    }
+
+    /// Where each labelled vreg is over its label's range: in its
+    /// spillslot, wherever it is live; see `Output::debug_locations`.
+    #[allow(clippy::type_complexity)]
+    fn compute_debug_locations(
+        &self,
+    ) -> Result<Vec<(u32, ProgPoint, ProgPoint, Allocation)>, RegAllocError> {
+        let mut locations = vec![];
+        let labels = self.func.debug_value_labels();
+        if labels.is_empty() {
+            return Ok(locations);
+        }
+        let liveness = LivenessInfo::new(self.func, self.cfginfo)?;
+        for &(vreg, from, to, label) in labels {
+            let slot = match self.vreg_slots[vreg.vreg()] {
+                Some(slot) if !self.constants.get(vreg.vreg()) => Allocation::stack(slot),
+                _ => continue,
+            };
+            let label_range = CodeRange::new(ProgPoint::before(from), ProgPoint::before(to));
+            for range in liveness.vreg_ranges(vreg) {
+                if range.overlaps(&label_range) {
+                    locations.push((
+                        label,
+                        range.from().max(label_range.from()),
+                        range.to().min(label_range.to()),
+                        slot,
+                    ));
+                }
+            }
+        }
+        sort_debug_locations(&mut locations);
+        Ok(locations)
+    }
+
+    fn compute_block_edits(&self) -> Vec<BlockEdits> {
+        let mut block_edits = vec![BlockEdits::default(); self.func.blocks()];
+        for &(pos, _, ref edit) in &self.edits {
+            let inst = ProgPoint::from_index(pos).inst;
+            let counts = &mut block_edits[self.cfginfo.insn_block[inst.index()].index()];
+            match *edit {
+                Edit::Move { from, to } => match (from.kind(), to.kind()) {
+                    (AllocationKind::Stack, AllocationKind::Stack) => counts.stack_moves += 1,
+                    (AllocationKind::Stack, _) => counts.reloads += 1,
+                    (_, AllocationKind::Stack) => counts.spills += 1,
+                    _ => counts.moves += 1,
+                },
+                Edit::DefineConstant { .. } => counts.constants += 1,
+                Edit::Remat { .. } => counts.remats += 1,
+                Edit::BlockParams { .. } => {}
+            }
+        }
+        block_edits
+    }
+
+    fn compute_edge_moves(&self) -> Vec<EdgeMoves> {
+        let mut counts: BTreeMap<(Block, Block), (usize, usize)> = BTreeMap::new();
+        for &(pos, prio, ref edit) in &self.edits {
+            let block = self.cfginfo.insn_block[ProgPoint::from_index(pos).inst.index()];
+            let edge = match prio {
+                EditPrio::InEdgeMoves => (self.func.block_preds(block)[0], block),
+                EditPrio::OutEdgeMoves => (block, self.func.block_succs(block)[0]),
+                _ => continue,
+            };
+            let cost = match edit {
+                &Edit::Move { from, to }
+                    if from.kind() == AllocationKind::Stack
+                        || to.kind() == AllocationKind::Stack =>
+                {
+                    2
+                }
+                _ => 1,
+            };
+            let entry = counts.entry(edge).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += cost;
+        }
+        counts
+            .into_iter()
+            .map(|((from, to), (moves, cost))| EdgeMoves {
+                from,
+                to,
+                moves,
+                cost,
+            })
+            .collect()
+    }
+
+    fn compute_elided_moves(&self) -> BitVec {
+        let mut elided = BitVec::new();
+        for inst in 0..self.func.insts() {
+            let inst = Inst::new(inst);
+            let (src, dst) = match self.func.is_move(inst) {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let offset = self.inst_alloc_offsets[inst.index()] as usize;
+            let mut src_alloc = None;
+            let mut dst_alloc = None;
+            for (i, op) in inst_operands(self.func, inst).iter().enumerate() {
+                match op.kind() {
+                    OperandKind::Use if op.vreg() == src => {
+                        src_alloc = Some(self.allocs[offset + i])
+                    }
+                    OperandKind::Def if op.vreg() == dst => {
+                        dst_alloc = Some(self.allocs[offset + i])
+                    }
+                    _ => {}
+                }
+            }
+            if src_alloc.is_some() && src_alloc == dst_alloc {
+                elided.set(inst.index(), true);
+            }
+        }
+        elided
+    }
+
+    fn compute_spillslot_offsets(&self) -> (Vec<u32>, u32) {
+        if self.env.spillslot_layout_by_class.is_empty() {
+            return (vec![], 0);
+        }
+        let mut offsets = vec![u32::MAX; self.num_spillslots as usize];
+        let mut offset: u32 = 0;
+        for &(slot, size) in &self.spillslots {
+            let layout = self.env.spillslot_layout_by_class[slot.class() as u8 as usize];
+            debug_assert!(layout.align.is_power_of_two());
+            offset = (offset + layout.align - 1) & !(layout.align - 1);
+            offsets[slot.index()] = offset;
+            offset += size * layout.unit_bytes;
+        }
+        (offsets, offset)
+    }
+
+    fn into_output(mut self) -> Result<Output, RegAllocError> {
+        let (used_regs, edit_clobbered_regs) = self.used_regs();
+        if self.opts.save_callee_saves {
+            self.insert_callee_saves(&used_regs);
+        }
+        self.edits.sort_by_key(|&(pos, prio, _)| (pos, prio));
+        let (safepoint_slots, safepoint_derived_slots, safepoint_locations) =
+            self.compute_safepoints()?;
+        let debug_locations = self.compute_debug_locations()?;
+        let edge_moves = if self.opts.report_edge_moves {
+            self.compute_edge_moves()
+        } else {
+            vec![]
+        };
+        let block_edits = self.compute_block_edits();
+        let elided_moves = self.compute_elided_moves();
+        let (spillslot_offsets, spillslot_area_size) = self.compute_spillslot_offsets();
+        self.preserved_soft_clobbers
+            .sort_unstable_by_key(|&(inst, preg)| (inst, preg.index()));
+        self.preserved_soft_clobbers.dedup();
+        Ok(Output {
+            num_spillslots: self.num_spillslots as usize,
+            edits: self
+                .edits
+                .into_iter()
+                .map(|(pos, _, edit)| (ProgPoint::from_index(pos), edit))
+                .collect(),
+            allocs: self.allocs,
+            inst_alloc_offsets: self.inst_alloc_offsets,
+            spillslot_offsets,
+            spillslot_area_size,
+            callee_saves: self.callee_saves,
+            elided_moves,
+            edge_moves,
+            block_edits,
+            used_regs,
+            edit_clobbered_regs,
+            explanations: vec![],
+            safepoint_locations,
+            preserved_soft_clobbers: self.preserved_soft_clobbers,
+            safepoint_slots,
+            safepoint_derived_slots,
+            debug_locations,
+            fingerprint: self.opts.fingerprint(),
+            stats: Default::default(),
+        })
+    }
+}
+
+/// Run the fast allocator, or the backtracking allocator for a
+/// function it cannot handle (see the module documentation); the
+/// output's `Fingerprint::algorithm` records which ran.
+pub fn run<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<Output, RegAllocError> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("fastalloc").entered();
+
+    // Reserve or release the frame pointer for this function.
+    let fp_env;
+    let env = if mach_env.frame_pointer.is_some() {
+        fp_env = mach_env.with_frame_pointer(options.allocate_frame_pointer);
+        &fp_env
+    } else {
+        mach_env
+    };
+
+    if func.insts() > ProgPoint::MAX_INSTS {
+        return Err(RegAllocError::TooManyInsts(func.insts()));
+    }
+    let cfginfo = CFGInfo::new(func);
+    if options.validate_ssa {
+        crate::ssa::validate_ssa(func, &cfginfo)?;
+    }
+    crate::ion::check_reg_classes(func, env)?;
+    let restricted = crate::ion::restricted_points(func)?;
+    if options.save_callee_saves {
+        crate::ion::callee_restored_insts(func, env, &restricted)?;
+    }
+
+    let mut fast = Env::new(func, env, options, &cfginfo);
+    let output = if restricted.iter().next().is_none() && fast.allocate().is_some() {
+        Some(fast.into_output()?)
+    } else {
+        None
+    };
+    let output = match output {
+        Some(output) if output.num_spillslots <= options.max_spillslots.unwrap_or(usize::MAX) => {
+            output
+        }
+        _ => {
+            log::debug!("fastalloc: falling back to the backtracking allocator");
+            let options = RegallocOptions {
+                algorithm: AllocatorAlgorithm::Backtracking,
+                ..options.clone()
+            };
+            return crate::ion::run_with_options(func, mach_env, &options);
+        }
+    };
+
+    #[cfg(feature = "checker")]
+    if options.validate_output {
+        let mut checker = crate::checker::Checker::new(func);
+        checker.add_machine_env(env);
+        checker.prepare(&output);
+        checker.run().map_err(RegAllocError::CheckerFailed)?;
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzzing::func::{machine_env, FuncBuilder, InstData, InstOpcode};
+    use crate::fuzzing::interp;
+    use crate::Operand;
+
+    fn fast() -> RegallocOptions {
+        RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            report_blockparams: true,
+            ..RegallocOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_fast_allocator() {
+        // Each value is stored to its spillslot after its def, and
+        // reloaded before each use.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(
+                block,
+                &[Operand::reg_def(v1), Operand::reg_use(v0)],
+                &[PReg::new(0, RegClass::Int)],
+            )
+            .inst(block, &[Operand::stack_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run(&func, &machine_env(), &fast()).expect("regalloc did not succeed");
+        assert_eq!(
+            out.to_string(),
+            "spillslots: 2\n\
+             spillslot offsets: stack0@0 stack1@8 (area size 16)\n\
+             inst0: p0i\n\
+             \x20 after inst0: move p0i -> stack0\n\
+             \x20 before inst1: move stack0 -> p2i\n\
+             inst1: p1i, p2i\n\
+             \x20 after inst1: move p1i -> stack1\n\
+             inst2: stack1\n\
+             inst3:\n"
+        );
+        assert_eq!(out.fingerprint.algorithm, AllocatorAlgorithm::Fast);
+    }
+
+    #[test]
+    fn test_fast_allocator_edge_moves() {
+        // The join has two preds, so each pred's args are moved from
+        // their spillslots into the blockparams' after its branch,
+        // swapped on one edge; the branch args are reloaded as well.
+        let mut builder = FuncBuilder::new();
+        let (b0, b1, b2, b3) = (
+            builder.block(),
+            builder.block(),
+            builder.block(),
+            builder.block(),
+        );
+        let (v0, v1, v2, v3) = (
+            builder.vreg(),
+            builder.vreg(),
+            builder.vreg(),
+            builder.vreg(),
+        );
+        builder
+            .inst(b0, &[Operand::reg_def(v0)], &[])
+            .inst(b0, &[Operand::reg_def(v1)], &[])
+            .branch(b0, &[b1, b2], &[])
+            .branch(b1, &[b3], &[v0, v1])
+            .branch(b2, &[b3], &[v1, v0])
+            .params(b3, &[v2, v3])
+            .inst(b3, &[Operand::reg_use(v2), Operand::reg_use(v3)], &[])
+            .ret(b3);
+        let func = builder.build().unwrap();
+        let out = run(&func, &machine_env(), &fast()).expect("regalloc did not succeed");
+        assert_eq!(
+            out.to_string(),
+            "spillslots: 4\n\
+             spillslot offsets: stack0@0 stack1@8 stack2@16 stack3@24 (area size 32)\n\
+             inst0: p0i\n\
+             \x20 after inst0: move p0i -> stack0\n\
+             inst1: p0i\n\
+             \x20 after inst1: move p0i -> stack1\n\
+             inst2:\n\
+             \x20 before inst3: move stack0 -> p0i\n\
+             \x20 before inst3: move stack1 -> p1i\n\
+             inst3: p0i, p1i\n\
+             \x20 after inst3: move stack0 -> stack2\n\
+             \x20 after inst3: move stack1 -> stack3\n\
+             \x20 before inst4: move stack1 -> p0i\n\
+             \x20 before inst4: move stack0 -> p1i\n\
+             inst4: p0i, p1i\n\
+             \x20 after inst4: move stack0 -> stack3\n\
+             \x20 after inst4: move stack1 -> stack2\n\
+             \x20 before inst5: blockparams v2=stack2, v3=stack3\n\
+             \x20 before inst5: move stack2 -> p0i\n\
+             \x20 before inst5: move stack3 -> p1i\n\
+             inst5: p0i, p1i\n\
+             inst6:\n"
+        );
+        for seed in 0..4 {
+            if let Err(e) = interp::run(&func, &out, seed, func.insts()) {
+                panic!("interpreter found a mismatch: {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fast_allocator_fallback() {
+        // Edits are forbidden between inst1 and inst2, where v0 would
+        // be reloaded, so the backtracking allocator runs instead.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .no_edits_after(block)
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let env = machine_env();
+        let out = run(&func, &env, &fast()).expect("regalloc did not succeed");
+        assert_eq!(out.fingerprint.algorithm, AllocatorAlgorithm::Backtracking);
+        assert!(out.edits.is_empty());
+        let ion = crate::run_with_options(
+            &func,
+            &env,
+            &RegallocOptions {
+                report_blockparams: true,
+                ..RegallocOptions::default()
+            },
+        )
+        .expect("regalloc did not succeed");
+        assert_eq!(out.to_string(), ion.to_string());
+    }
+
+    #[test]
+    fn test_fast_allocator_derived_refs() {
+        // v1 is derived from v0, which is dead after inst1 but for the
+        // call at inst2: the stackmap there lists v0's slot, and pairs
+        // v1's with it.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .reftype(v0)
+            .reftype(v1)
+            .derived(v1, v0)
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1), Operand::reg_use(v0)], &[]);
+        let call = vec![Operand::reg_def(v2)];
+        builder.add_inst(block, InstData::new(InstOpcode::Call, call, vec![]));
+        builder
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run(&func, &machine_env(), &fast()).expect("regalloc did not succeed");
+        assert_eq!(out.fingerprint.algorithm, AllocatorAlgorithm::Fast);
+        let (s0, s1) = (
+            SpillSlot::new(0, RegClass::Int),
+            SpillSlot::new(1, RegClass::Int),
+        );
+        let call = Inst::new(2);
+        assert_eq!(out.safepoint_slots, vec![(call, s0)]);
+        assert_eq!(out.safepoint_derived_slots, vec![(call, s1, s0)]);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{
-    domtree, postorder, Allocation, Block, Function, Inst, InstRange, MachineEnv, Operand,
-    OperandKind, OperandPolicy, OperandPos, PReg, RegClass, VReg,
+    cfg::CFGInfo, domtree, postorder, ssa::validate_ssa, Allocation, Block, ExcludedRegs, Function,
+    Inst, InstRange, MachineEnv, Operand, OperandKind, OperandPolicy, OperandPos, PReg,
+    RegAllocError, RegClass, RematKind, SpillPreference, SpillSlot, SpillSlotLayout, SpillWeight,
+    VReg,
 };
 
 use arbitrary::Result as ArbitraryResult;
@@ -11,6 +13,7 @@ pub enum InstOpcode {
     Phi,
     Op,
     Call,
+    Move,
     Ret,
     Branch,
 }
@@ -20,37 +23,44 @@ pub struct InstData {
     op: InstOpcode,
     operands: Vec<Operand>,
     clobbers: Vec<PReg>,
+    soft_clobbers: Vec<PReg>,
+    call_conv: Option<usize>,
+    no_edits_after: bool,
+    delay_slot: bool,
+    reads_flags: bool,
+    writes_flags: bool,
 }
 
 impl InstData {
+    pub fn new(op: InstOpcode, operands: Vec<Operand>, clobbers: Vec<PReg>) -> InstData {
+        InstData {
+            op,
+            operands,
+            clobbers,
+            soft_clobbers: vec![],
+            call_conv: None,
+            no_edits_after: false,
+            delay_slot: false,
+            reads_flags: false,
+            writes_flags: false,
+        }
+    }
     pub fn op(def: usize, uses: &[usize]) -> InstData {
         let mut operands = vec![Operand::reg_def(VReg::new(def, RegClass::Int))];
         for &u in uses {
             operands.push(Operand::reg_use(VReg::new(u, RegClass::Int)));
         }
-        InstData {
-            op: InstOpcode::Op,
-            operands,
-            clobbers: vec![],
-        }
+        InstData::new(InstOpcode::Op, operands, vec![])
     }
     pub fn branch(uses: &[usize]) -> InstData {
         let mut operands = vec![];
         for &u in uses {
             operands.push(Operand::reg_use(VReg::new(u, RegClass::Int)));
         }
-        InstData {
-            op: InstOpcode::Branch,
-            operands,
-            clobbers: vec![],
-        }
+        InstData::new(InstOpcode::Branch, operands, vec![])
     }
     pub fn ret() -> InstData {
-        InstData {
-            op: InstOpcode::Ret,
-            operands: vec![],
-            clobbers: vec![],
-        }
+        InstData::new(InstOpcode::Ret, vec![], vec![])
     }
 }
 
@@ -61,7 +71,20 @@ pub struct Func {
     block_preds: Vec<Vec<Block>>,
     block_succs: Vec<Vec<Block>>,
     block_params: Vec<Vec<VReg>>,
+    cold_blocks: Vec<bool>,
     num_vregs: usize,
+    reserved_spillslots: usize,
+    equivalent_vregs: Vec<Option<VReg>>,
+    vreg_names: Vec<Option<String>>,
+    spill_weights: Vec<SpillWeight>,
+    spill_preferences: Vec<SpillPreference>,
+    no_spill_vregs: Vec<bool>,
+    reftype_vregs: Vec<bool>,
+    derived_refs: Vec<Option<VReg>>,
+    remat_vregs: Vec<Option<RematKind>>,
+    pinned_vregs: Vec<Option<PReg>>,
+    non_ssa: bool,
+    debug_labels: Vec<(VReg, Inst, Inst, u32)>,
 }
 
 impl Function for Func {
@@ -94,6 +117,10 @@ impl Function for Func {
         &self.block_params[block.index()][..]
     }
 
+    fn is_cold_block(&self, block: Block) -> bool {
+        self.cold_blocks[block.index()]
+    }
+
     fn is_call(&self, insn: Inst) -> bool {
         self.insts[insn.index()].op == InstOpcode::Call
     }
@@ -106,12 +133,41 @@ impl Function for Func {
         self.insts[insn.index()].op == InstOpcode::Branch
     }
 
-    fn is_safepoint(&self, _: Inst) -> bool {
-        false
+    fn branch_has_delay_slot(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].delay_slot
+    }
+
+    fn groups_fixed_uses(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].op == InstOpcode::Call
+    }
+
+    fn is_safepoint(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].op == InstOpcode::Call
+    }
+
+    fn is_move(&self, insn: Inst) -> Option<(VReg, VReg)> {
+        let inst = &self.insts[insn.index()];
+        if inst.op == InstOpcode::Move {
+            Some((inst.operands[1].vreg(), inst.operands[0].vreg()))
+        } else {
+            None
+        }
+    }
+
+    fn equivalent_vreg(&self, vreg: VReg) -> Option<VReg> {
+        self.equivalent_vregs.get(vreg.vreg()).cloned().flatten()
+    }
+
+    fn no_edits_after(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].no_edits_after
     }
 
-    fn is_move(&self, _: Inst) -> Option<(VReg, VReg)> {
-        None
+    fn writes_flags(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].writes_flags
+    }
+
+    fn reads_flags(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].reads_flags
     }
 
     fn inst_operands(&self, insn: Inst) -> &[Operand] {
@@ -122,19 +178,102 @@ impl Function for Func {
         &self.insts[insn.index()].clobbers[..]
     }
 
+    fn inst_call_conv(&self, insn: Inst) -> Option<usize> {
+        self.insts[insn.index()].call_conv
+    }
+
+    fn inst_soft_clobbers(&self, insn: Inst) -> &[PReg] {
+        &self.insts[insn.index()].soft_clobbers[..]
+    }
+
     fn num_vregs(&self) -> usize {
         self.num_vregs
     }
 
+    fn is_ssa(&self) -> bool {
+        !self.non_ssa
+    }
+
+    fn debug_vreg_name(&self, vreg: VReg) -> Option<&str> {
+        self.vreg_names.get(vreg.vreg())?.as_deref()
+    }
+
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        &self.debug_labels[..]
+    }
+
+    fn spill_weight(&self, vreg: VReg) -> SpillWeight {
+        self.spill_weights
+            .get(vreg.vreg())
+            .cloned()
+            .unwrap_or(SpillWeight::Default)
+    }
+
+    fn spill_preference(&self, vreg: VReg) -> SpillPreference {
+        self.spill_preferences
+            .get(vreg.vreg())
+            .cloned()
+            .unwrap_or(SpillPreference::Default)
+    }
+
+    fn no_spill(&self, vreg: VReg) -> bool {
+        self.no_spill_vregs
+            .get(vreg.vreg())
+            .cloned()
+            .unwrap_or(false)
+    }
+
+    fn pinned_vreg(&self, vreg: VReg) -> Option<PReg> {
+        self.pinned_vregs.get(vreg.vreg()).cloned().flatten()
+    }
+
+    fn is_reftype(&self, vreg: VReg) -> bool {
+        self.reftype_vregs
+            .get(vreg.vreg())
+            .cloned()
+            .unwrap_or(false)
+    }
+
+    fn derived_ref_base(&self, vreg: VReg) -> Option<VReg> {
+        self.derived_refs.get(vreg.vreg()).cloned().flatten()
+    }
+
+    fn is_rematerializable(&self, vreg: VReg) -> Option<RematKind> {
+        self.remat_vregs.get(vreg.vreg()).cloned().flatten()
+    }
+
     fn spillslot_size(&self, regclass: RegClass, _: VReg) -> usize {
         match regclass {
             RegClass::Int => 1,
             RegClass::Float => 2,
         }
     }
+
+    fn num_reserved_spillslots(&self) -> usize {
+        self.reserved_spillslots
+    }
 }
 
-struct FuncBuilder {
+/// Builds a `Func` block by block, e.g.:
+///
+/// ```
+/// use regalloc2::fuzzing::func::FuncBuilder;
+/// use regalloc2::Operand;
+///
+/// let mut builder = FuncBuilder::new();
+/// let (entry, exit) = (builder.block(), builder.block());
+/// let (v0, v1) = (builder.vreg(), builder.vreg());
+/// builder
+///     .inst(entry, &[Operand::reg_def(v0)], &[])
+///     .branch(entry, &[exit], &[v0])
+///     .params(exit, &[v1])
+///     .ret(exit);
+/// let func = builder.build().unwrap();
+/// ```
+///
+/// The first block is the entry. Blocks and vregs are numbered in the
+/// order they are added, and insts in block order.
+pub struct FuncBuilder {
     postorder: Vec<Block>,
     idom: Vec<Block>,
     f: Func,
@@ -142,7 +281,7 @@ struct FuncBuilder {
 }
 
 impl FuncBuilder {
-    fn new() -> Self {
+    pub fn new() -> Self {
         FuncBuilder {
             postorder: vec![],
             idom: vec![],
@@ -150,15 +289,29 @@ impl FuncBuilder {
                 block_preds: vec![],
                 block_succs: vec![],
                 block_params: vec![],
+                cold_blocks: vec![],
                 insts: vec![],
                 blocks: vec![],
                 num_vregs: 0,
+                reserved_spillslots: 0,
+                equivalent_vregs: vec![],
+                vreg_names: vec![],
+                spill_weights: vec![],
+                spill_preferences: vec![],
+                no_spill_vregs: vec![],
+                reftype_vregs: vec![],
+                derived_refs: vec![],
+                remat_vregs: vec![],
+                pinned_vregs: vec![],
+                non_ssa: false,
+                debug_labels: vec![],
             },
             insts_per_block: vec![],
         }
     }
 
-    pub fn add_block(&mut self) -> Block {
+    /// Add an empty block.
+    pub fn block(&mut self) -> Block {
         let b = Block::new(self.f.blocks.len());
         self.f
             .blocks
@@ -166,10 +319,223 @@ impl FuncBuilder {
         self.f.block_preds.push(vec![]);
         self.f.block_succs.push(vec![]);
         self.f.block_params.push(vec![]);
+        self.f.cold_blocks.push(false);
         self.insts_per_block.push(vec![]);
         b
     }
 
+    /// Add an integer vreg.
+    pub fn vreg(&mut self) -> VReg {
+        let vreg = VReg::new(self.f.num_vregs, RegClass::Int);
+        self.f.num_vregs += 1;
+        vreg
+    }
+
+    /// Give `vreg` a name for diagnostics; see
+    /// `Function::debug_vreg_name`.
+    pub fn name(&mut self, vreg: VReg, name: &str) -> &mut Self {
+        if self.f.vreg_names.len() <= vreg.vreg() {
+            self.f.vreg_names.resize(vreg.vreg() + 1, None);
+        }
+        self.f.vreg_names[vreg.vreg()] = Some(name.to_string());
+        self
+    }
+
+    /// Adjust the spill weight of `vreg`; see `Function::spill_weight`.
+    pub fn spill_weight(&mut self, vreg: VReg, weight: SpillWeight) -> &mut Self {
+        if self.f.spill_weights.len() <= vreg.vreg() {
+            self.f
+                .spill_weights
+                .resize(vreg.vreg() + 1, SpillWeight::Default);
+        }
+        self.f.spill_weights[vreg.vreg()] = weight;
+        self
+    }
+
+    /// Say where `vreg` should go without a register; see
+    /// `Function::spill_preference`.
+    pub fn spill_preference(&mut self, vreg: VReg, pref: SpillPreference) -> &mut Self {
+        if self.f.spill_preferences.len() <= vreg.vreg() {
+            self.f
+                .spill_preferences
+                .resize(vreg.vreg() + 1, SpillPreference::Default);
+        }
+        self.f.spill_preferences[vreg.vreg()] = pref;
+        self
+    }
+
+    /// Keep `vreg` in a register throughout; see
+    /// `Function::no_spill`.
+    pub fn no_spill(&mut self, vreg: VReg) -> &mut Self {
+        if self.f.no_spill_vregs.len() <= vreg.vreg() {
+            self.f.no_spill_vregs.resize(vreg.vreg() + 1, false);
+        }
+        self.f.no_spill_vregs[vreg.vreg()] = true;
+        self
+    }
+
+    /// Hold `vreg` in `preg` wherever it is live; see
+    /// `Function::pinned_vreg`.
+    pub fn pin(&mut self, vreg: VReg, preg: PReg) -> &mut Self {
+        if self.f.pinned_vregs.len() <= vreg.vreg() {
+            self.f.pinned_vregs.resize(vreg.vreg() + 1, None);
+        }
+        self.f.pinned_vregs[vreg.vreg()] = Some(preg);
+        self
+    }
+
+    /// Mark `vreg` as holding a reference; see
+    /// `Function::is_reftype`.
+    pub fn reftype(&mut self, vreg: VReg) -> &mut Self {
+        if self.f.reftype_vregs.len() <= vreg.vreg() {
+            self.f.reftype_vregs.resize(vreg.vreg() + 1, false);
+        }
+        self.f.reftype_vregs[vreg.vreg()] = true;
+        self
+    }
+
+    /// Mark `vreg` as derived from `base`; see
+    /// `Function::derived_ref_base`.
+    pub fn derived(&mut self, vreg: VReg, base: VReg) -> &mut Self {
+        if self.f.derived_refs.len() <= vreg.vreg() {
+            self.f.derived_refs.resize(vreg.vreg() + 1, None);
+        }
+        self.f.derived_refs[vreg.vreg()] = Some(base);
+        self
+    }
+
+    /// Say how `vreg` is recomputed; see
+    /// `Function::is_rematerializable`.
+    pub fn remat(&mut self, vreg: VReg, kind: RematKind) -> &mut Self {
+        if self.f.remat_vregs.len() <= vreg.vreg() {
+            self.f.remat_vregs.resize(vreg.vreg() + 1, None);
+        }
+        self.f.remat_vregs[vreg.vreg()] = Some(kind);
+        self
+    }
+
+    /// Link `vreg` with `other` as sharing storage; see
+    /// `Function::equivalent_vreg`.
+    pub fn equivalent(&mut self, vreg: VReg, other: VReg) -> &mut Self {
+        if self.f.equivalent_vregs.len() <= vreg.vreg() {
+            self.f.equivalent_vregs.resize(vreg.vreg() + 1, None);
+        }
+        self.f.equivalent_vregs[vreg.vreg()] = Some(other);
+        self
+    }
+
+    /// Allow vregs to be redefined and modified in place; see
+    /// `Function::is_ssa`.
+    pub fn non_ssa(&mut self) -> &mut Self {
+        self.f.non_ssa = true;
+        self
+    }
+
+    /// Label `vreg` as holding the debug variable `label` from `from`
+    /// up to `to`; see `Function::debug_value_labels`.
+    pub fn label(&mut self, vreg: VReg, from: Inst, to: Inst, label: u32) -> &mut Self {
+        self.f.debug_labels.push((vreg, from, to, label));
+        self
+    }
+
+    /// Reserve the first `count` spillslots for the function's own
+    /// use; see `Function::num_reserved_spillslots`.
+    pub fn reserved_spillslots(&mut self, count: usize) -> &mut Self {
+        self.f.reserved_spillslots = count;
+        self
+    }
+
+    /// Mark `block` cold; see `Function::is_cold_block`.
+    pub fn cold(&mut self, block: Block) -> &mut Self {
+        self.f.cold_blocks[block.index()] = true;
+        self
+    }
+
+    /// Set the blockparams of `block`.
+    pub fn params(&mut self, block: Block, params: &[VReg]) -> &mut Self {
+        self.f.block_params[block.index()] = params.to_vec();
+        self
+    }
+
+    /// Append an instruction with the given operands and clobbers to
+    /// `block`.
+    pub fn inst(&mut self, block: Block, operands: &[Operand], clobbers: &[PReg]) -> &mut Self {
+        let data = InstData::new(InstOpcode::Op, operands.to_vec(), clobbers.to_vec());
+        self.add_inst(block, data);
+        self
+    }
+
+    /// Append a call following calling convention `call_conv` (an
+    /// index into `MachineEnv::call_convs`) to `block`.
+    pub fn call(&mut self, block: Block, operands: &[Operand], call_conv: usize) -> &mut Self {
+        let mut data = InstData::new(InstOpcode::Call, operands.to_vec(), vec![]);
+        data.call_conv = Some(call_conv);
+        self.add_inst(block, data);
+        self
+    }
+
+    /// Give the last instruction appended to `block` the given soft
+    /// clobbers.
+    pub fn soft_clobbers(&mut self, block: Block, pregs: &[PReg]) -> &mut Self {
+        let inst = self.insts_per_block[block.index()].last_mut().unwrap();
+        inst.soft_clobbers = pregs.to_vec();
+        self
+    }
+
+    /// Forbid edits between the last instruction appended to `block`
+    /// and the next one (see `Function::no_edits_after`).
+    pub fn no_edits_after(&mut self, block: Block) -> &mut Self {
+        let inst = self.insts_per_block[block.index()].last_mut().unwrap();
+        inst.no_edits_after = true;
+        self
+    }
+
+    /// Mark the last instruction appended to `block` as writing the
+    /// flags (see `Function::writes_flags`).
+    pub fn writes_flags(&mut self, block: Block) -> &mut Self {
+        let inst = self.insts_per_block[block.index()].last_mut().unwrap();
+        inst.writes_flags = true;
+        self
+    }
+
+    /// Mark the last instruction appended to `block` as reading the
+    /// flags (see `Function::reads_flags`).
+    pub fn reads_flags(&mut self, block: Block) -> &mut Self {
+        let inst = self.insts_per_block[block.index()].last_mut().unwrap();
+        inst.reads_flags = true;
+        self
+    }
+
+    /// Give the branch that ends `block` a delay slot (see
+    /// `Function::branch_has_delay_slot`): its args are read from
+    /// wherever the successor's blockparams live, so take any location.
+    pub fn delay_slot(&mut self, block: Block) -> &mut Self {
+        let inst = self.insts_per_block[block.index()].last_mut().unwrap();
+        for op in &mut inst.operands {
+            *op = Operand::new(op.vreg(), OperandPolicy::Any, op.kind(), op.pos());
+        }
+        inst.delay_slot = true;
+        self
+    }
+
+    /// End `block` with a branch to `succs`. As `Function::is_branch`
+    /// requires, `args` are the args to each successor's blockparams
+    /// in turn.
+    pub fn branch(&mut self, block: Block, succs: &[Block], args: &[VReg]) -> &mut Self {
+        for &succ in succs {
+            self.add_edge(block, succ);
+        }
+        let operands = args.iter().map(|&arg| Operand::reg_use(arg)).collect();
+        self.add_inst(block, InstData::new(InstOpcode::Branch, operands, vec![]));
+        self
+    }
+
+    /// End `block` with a return.
+    pub fn ret(&mut self, block: Block) -> &mut Self {
+        self.add_inst(block, InstData::ret());
+        self
+    }
+
     pub fn add_inst(&mut self, block: Block, data: InstData) {
         self.insts_per_block[block.index()].push(data);
     }
@@ -179,8 +545,31 @@ impl FuncBuilder {
         self.f.block_preds[to.index()].push(from);
     }
 
-    pub fn set_block_params(&mut self, block: Block, params: &[VReg]) {
-        self.f.block_params[block.index()] = params.iter().cloned().collect();
+    /// Build the function, checking that the allocator will accept
+    /// it: that every block ends in its only branch or return, with as
+    /// many args as its successors have blockparams; that no block
+    /// with several successors has one with several predecessors (a
+    /// critical edge, which must be split); and that the vregs are in
+    /// SSA form, or are at least defined before use if `non_ssa` was
+    /// called.
+    pub fn build(self) -> Result<Func, RegAllocError> {
+        for block in 0..self.f.blocks.len() {
+            if self.insts_per_block[block].is_empty() {
+                return Err(RegAllocError::BB(Block::new(block)));
+            }
+            let succs = &self.f.block_succs[block];
+            if succs.len() > 1
+                && succs
+                    .iter()
+                    .any(|succ| self.f.block_preds[succ.index()].len() > 1)
+            {
+                return Err(RegAllocError::BB(Block::new(block)));
+            }
+        }
+        let func = self.finalize();
+        let cfginfo = CFGInfo::new(&func);
+        validate_ssa(&func, &cfginfo)?;
+        Ok(func)
     }
 
     fn compute_doms(&mut self) {
@@ -195,7 +584,9 @@ impl FuncBuilder {
         );
     }
 
-    fn finalize(mut self) -> Func {
+    /// Build the function without `build`'s checks, for tests of the
+    /// allocator's own.
+    pub(crate) fn finalize(mut self) -> Func {
         for (blocknum, blockrange) in self.f.blocks.iter_mut().enumerate() {
             let begin_inst = self.f.insts.len();
             for inst in &self.insts_per_block[blocknum] {
@@ -209,6 +600,12 @@ impl FuncBuilder {
     }
 }
 
+impl Default for FuncBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Arbitrary for OperandPolicy {
     fn arbitrary(u: &mut Unstructured) -> ArbitraryResult<Self> {
         Ok(*u.choose(&[OperandPolicy::Any, OperandPolicy::Reg])?)
@@ -250,6 +647,74 @@ pub struct Options {
     pub reducible: bool,
     pub block_params: bool,
     pub always_local_uses: bool,
+    pub reserved_spillslots: bool,
+    pub constants: bool,
+    pub moves: bool,
+    pub excluded_regs: bool,
+    pub pref_regs: bool,
+    pub edit_restrictions: bool,
+    pub delay_slots: bool,
+    pub conditional_defs: bool,
+    pub equivalent_vregs: bool,
+    pub flags: bool,
+    pub multi_fixed_regs: bool,
+    pub late_positions: bool,
+    pub branch_defs: bool,
+    pub transfer_args: bool,
+    pub spill_weights: bool,
+    pub cold_blocks: bool,
+    pub soft_clobbers: bool,
+    pub spill_preferences: bool,
+    /// Keep about one vreg in eight in a register throughout. Too
+    /// many may be live at once for that to be possible, so
+    /// allocation can then fail with `RegAllocError::NoSpill`.
+    pub no_spill: bool,
+    /// Mark about one vreg in four (other than those kept in a
+    /// register by `no_spill`) as holding a reference. Calls are the
+    /// safepoints, and read references only with an `Any`, `Stack` or
+    /// `FixedStack` policy, or with a `FixedReg` policy at `Before`.
+    pub reftypes: bool,
+    /// Require some operands to be in a spillslot
+    /// (`OperandPolicy::Stack`).
+    pub stack_operands: bool,
+    /// Place some operands in a reserved spillslot
+    /// (`OperandPolicy::FixedStack`), when `reserved_spillslots` gives
+    /// the function any.
+    pub fixed_stack_operands: bool,
+    /// Mark about one vreg in four as rematerializable. The
+    /// interpreter takes a rematerialization to give the value the
+    /// vreg's def last computed.
+    pub remat: bool,
+    /// Define one more vreg on entry, as for `live_vregs`, and pin it
+    /// to a register that `machine_env()` does not allocate, reading
+    /// it only with policies that the register satisfies.
+    pub pinned: bool,
+    /// Leave SSA form: turn some uses in plain (non-call)
+    /// instructions into `Mod` operands, and others into redefinitions
+    /// of their vreg. Blockparams and vregs that are constant,
+    /// rematerializable, pinned, conditionally defined or references
+    /// are left alone.
+    pub non_ssa: bool,
+    /// Label about one vreg in four as a debug variable over a random
+    /// range of instructions; see `Function::debug_value_labels`.
+    pub debug_labels: bool,
+    /// Vregs defined on entry and live to every return, on top of
+    /// those the blocks use locally, and occasionally used in between.
+    pub live_vregs: usize,
+    /// The depth of loop nest to build around the middle of the
+    /// function, as far as its blocks (at most 100) allow; any other
+    /// forks in the CFG join without looping back.
+    pub loop_depth: usize,
+    /// The percentage of instructions to give an operand fixed to a
+    /// register, beyond those that `fixed_regs` picks.
+    pub fixed_reg_percent: u8,
+    /// The percentage of instructions whose def reuses an input,
+    /// beyond those that `reused_inputs` picks.
+    pub reuse_percent: u8,
+    /// The percentage of instructions to make calls that clobber
+    /// every caller-saved register (those `machine_env()` does not
+    /// list as callee-saved).
+    pub clobber_call_percent: u8,
 }
 
 impl std::default::Default for Options {
@@ -262,10 +727,47 @@ impl std::default::Default for Options {
             reducible: false,
             block_params: true,
             always_local_uses: false,
+            reserved_spillslots: false,
+            constants: false,
+            moves: false,
+            excluded_regs: false,
+            pref_regs: false,
+            edit_restrictions: false,
+            delay_slots: false,
+            conditional_defs: false,
+            equivalent_vregs: false,
+            flags: false,
+            multi_fixed_regs: false,
+            late_positions: false,
+            branch_defs: false,
+            transfer_args: false,
+            spill_weights: false,
+            cold_blocks: false,
+            soft_clobbers: false,
+            spill_preferences: false,
+            no_spill: false,
+            reftypes: false,
+            stack_operands: false,
+            fixed_stack_operands: false,
+            remat: false,
+            pinned: false,
+            non_ssa: false,
+            debug_labels: false,
+            live_vregs: 0,
+            loop_depth: 0,
+            fixed_reg_percent: 0,
+            reuse_percent: 0,
+            clobber_call_percent: 0,
         }
     }
 }
 
+/// Whether to make a choice that is made `percent`% of the time.
+/// Consumes no input if `percent` is zero.
+fn chance(u: &mut Unstructured, percent: u8) -> ArbitraryResult<bool> {
+    Ok(percent > 0 && u.int_in_range(1..=100)? <= percent)
+}
+
 impl Arbitrary for Func {
     fn arbitrary(u: &mut Unstructured) -> ArbitraryResult<Func> {
         Func::arbitrary_with_options(u, &Options::default())
@@ -284,10 +786,17 @@ impl Func {
         //      or one defined in a dominating block.
 
         let mut builder = FuncBuilder::new();
-        for _ in 0..u.int_in_range(1..=100)? {
-            builder.add_block();
+        // A nest of `loop_depth` loops needs twice as many forks, less
+        // one, of three blocks each, and a block to join the last.
+        let loop_forks = (2 * opts.loop_depth).saturating_sub(1);
+        let min_blocks = std::cmp::min(3 * loop_forks + 1, 100).max(1);
+        for _ in 0..u.int_in_range(min_blocks..=100)? {
+            builder.block();
         }
         let num_blocks = builder.f.blocks.len();
+        if opts.reserved_spillslots {
+            builder.f.reserved_spillslots = u.int_in_range(0..=4)?;
+        }
 
         // Generate a CFG. Create a "spine" of either single blocks,
         // with links to the next; or fork patterns, with the left
@@ -303,7 +812,11 @@ impl Func {
         let mut min_backedge_dest = num_blocks;
         while from < num_blocks {
             in_blocks.push(from);
-            if num_blocks > 3 && from < num_blocks - 3 && bool::arbitrary(u)? && opts.control_flow {
+            let force_fork = out_blocks.len() < loop_forks;
+            if num_blocks > 3
+                && from < num_blocks - 3
+                && (force_fork || (bool::arbitrary(u)? && opts.control_flow))
+            {
                 // To avoid critical edges, we use from+1 as an edge
                 // block, and advance `from` an extra block; `from+2`
                 // will be the next normal iteration.
@@ -317,6 +830,21 @@ impl Func {
             }
             from += 1;
         }
+        if opts.loop_depth > 0 {
+            // Nest the loops: the i-th fork heads a loop whose latch is
+            // the right fork of the i-th fork from the end, so long as
+            // it comes later. The other right forks join straight on.
+            for (i, &pred) in out_blocks.iter().enumerate() {
+                let latch_of = out_blocks.len() - 1 - i;
+                let succ = if latch_of <= i && latch_of < opts.loop_depth {
+                    out_blocks[latch_of] - 1
+                } else {
+                    pred + 2
+                };
+                builder.add_edge(Block::new(pred), Block::new(succ));
+            }
+            out_blocks.clear();
+        }
         for pred in out_blocks {
             let mut succ = *u.choose(&in_blocks[..])?;
             if opts.reducible && (pred >= succ) {
@@ -352,9 +880,7 @@ impl Func {
         for block in 0..num_blocks {
             let mut vregs = vec![];
             for _ in 0..u.int_in_range(5..=15)? {
-                let vreg = VReg::new(builder.f.num_vregs, RegClass::Int);
-                builder.f.num_vregs += 1;
-                vregs.push(vreg);
+                vregs.push(builder.vreg());
             }
             vregs_by_block.push(vregs.clone());
             vregs_by_block_to_be_defined.push(vec![]);
@@ -368,15 +894,27 @@ impl Func {
                 }
             }
             vregs_by_block_to_be_defined.last_mut().unwrap().reverse();
-            builder.set_block_params(Block::new(block), &block_params[block][..]);
+            builder.params(Block::new(block), &block_params[block][..]);
+        }
+
+        // Defined first thing in the entry block, which dominates every
+        // use.
+        let live_vregs: Vec<VReg> = (0..opts.live_vregs + opts.pinned as usize)
+            .map(|_| builder.vreg())
+            .collect();
+        for &vreg in &live_vregs {
+            builder.add_inst(Block::new(0), InstData::op(vreg.vreg(), &[]));
         }
 
         for block in 0..num_blocks {
             let mut avail = block_params[block].clone();
             let mut remaining_nonlocal_uses = u.int_in_range(0..=3)?;
+            let mut multi_fixed_vregs: Vec<VReg> = vec![];
             while let Some(vreg) = vregs_by_block_to_be_defined[block].pop() {
                 let def_policy = OperandPolicy::arbitrary(u)?;
-                let def_pos = if bool::arbitrary(u)? {
+                let def_pos = if opts.late_positions {
+                    *u.choose(&OperandPos::ALL[..])?
+                } else if bool::arbitrary(u)? {
                     OperandPos::Before
                 } else {
                     OperandPos::After
@@ -384,7 +922,9 @@ impl Func {
                 let mut operands = vec![Operand::new(vreg, def_policy, OperandKind::Def, def_pos)];
                 let mut allocations = vec![Allocation::none()];
                 for _ in 0..u.int_in_range(0..=3)? {
-                    let vreg = if avail.len() > 0
+                    let vreg = if !live_vregs.is_empty() && bool::arbitrary(u)? {
+                        *u.choose(&live_vregs[..])?
+                    } else if !avail.is_empty()
                         && (opts.always_local_uses
                             || remaining_nonlocal_uses == 0
                             || bool::arbitrary(u)?)
@@ -408,27 +948,47 @@ impl Func {
                         break;
                     };
                     let use_policy = OperandPolicy::arbitrary(u)?;
-                    operands.push(Operand::new(
-                        vreg,
-                        use_policy,
-                        OperandKind::Use,
-                        OperandPos::Before,
-                    ));
+                    let use_pos = if opts.late_positions {
+                        *u.choose(&OperandPos::ALL[..])?
+                    } else {
+                        OperandPos::Before
+                    };
+                    operands.push(Operand::new(vreg, use_policy, OperandKind::Use, use_pos));
                     allocations.push(Allocation::none());
                 }
                 let mut clobbers: Vec<PReg> = vec![];
-                if operands.len() > 1 && opts.reused_inputs && bool::arbitrary(u)? {
+                if operands.len() > 1
+                    && ((opts.reused_inputs && bool::arbitrary(u)?)
+                        || chance(u, opts.reuse_percent)?)
+                {
                     // Make the def a reused input.
                     let op = operands[0];
                     assert_eq!(op.kind(), OperandKind::Def);
                     let reused = u.int_in_range(1..=(operands.len() - 1))?;
-                    operands[0] = Operand::new(
-                        op.vreg(),
-                        OperandPolicy::Reuse(reused),
-                        op.kind(),
-                        OperandPos::After,
+                    // The reused input is read before the def overwrites it.
+                    let input = operands[reused];
+                    operands[reused] = Operand::new(
+                        input.vreg(),
+                        input.policy(),
+                        input.kind(),
+                        OperandPos::Before,
                     );
-                } else if opts.fixed_regs && bool::arbitrary(u)? {
+                    operands[0] = if opts.conditional_defs && bool::arbitrary(u)? {
+                        Operand::reg_cond_def(op.vreg(), reused)
+                    } else {
+                        Operand::new(
+                            op.vreg(),
+                            OperandPolicy::Reuse(reused),
+                            op.kind(),
+                            OperandPos::After,
+                        )
+                    };
+                } else if opts.constants && operands.len() == 1 && bool::arbitrary(u)? {
+                    // An input-less def: make it a rematerializable constant.
+                    operands[0] = Operand::constant_def(operands[0].vreg());
+                } else if (opts.fixed_regs && bool::arbitrary(u)?)
+                    || chance(u, opts.fixed_reg_percent)?
+                {
                     // Pick an operand and make it a fixed reg.
                     let fixed_reg = PReg::new(u.int_in_range(0..=30)?, RegClass::Int);
                     let i = u.int_in_range(0..=(operands.len() - 1))?;
@@ -439,22 +999,165 @@ impl Func {
                         op.kind(),
                         op.pos(),
                     );
+                } else if opts.excluded_regs && bool::arbitrary(u)? {
+                    // Pick an operand and keep it out of a few registers.
+                    let mut excluded = vec![];
+                    for _ in 0..u.int_in_range(1..=ExcludedRegs::MAX_LEN)? {
+                        excluded.push(PReg::new(u.int_in_range(0..=30)?, RegClass::Int));
+                    }
+                    let i = u.int_in_range(0..=(operands.len() - 1))?;
+                    let op = operands[i];
+                    operands[i] = Operand::new(
+                        op.vreg(),
+                        OperandPolicy::RegExcept(ExcludedRegs::new(&excluded)),
+                        op.kind(),
+                        op.pos(),
+                    );
+                } else if opts.pref_regs && bool::arbitrary(u)? {
+                    // Pick an operand and give it a preferred reg (perhaps
+                    // the scratch reg, which must then be ignored).
+                    let pref_reg = PReg::new(u.int_in_range(0..=31)?, RegClass::Int);
+                    let i = u.int_in_range(0..=(operands.len() - 1))?;
+                    let op = operands[i];
+                    operands[i] = Operand::new(
+                        op.vreg(),
+                        OperandPolicy::PrefReg(pref_reg),
+                        op.kind(),
+                        op.pos(),
+                    );
+                } else if opts.stack_operands && bool::arbitrary(u)? {
+                    // Pick an operand and require it in a spillslot, as
+                    // for a memory operand, unless the inst also reads
+                    // its vreg otherwise.
+                    let i = u.int_in_range(0..=(operands.len() - 1))?;
+                    let op = operands[i];
+                    if operands
+                        .iter()
+                        .enumerate()
+                        .all(|(j, other)| j == i || other.vreg() != op.vreg())
+                    {
+                        operands[i] =
+                            Operand::new(op.vreg(), OperandPolicy::Stack, op.kind(), op.pos());
+                    }
+                } else if opts.fixed_stack_operands
+                    && builder.f.reserved_spillslots > 0
+                    && bool::arbitrary(u)?
+                {
+                    // Pick an operand and place it in a reserved slot, as
+                    // for a stack argument.
+                    let slot = u.int_in_range(0..=(builder.f.reserved_spillslots - 1))?;
+                    let i = u.int_in_range(0..=(operands.len() - 1))?;
+                    let op = operands[i];
+                    operands[i] = Operand::new(
+                        op.vreg(),
+                        OperandPolicy::FixedStack(SpillSlot::new(slot, op.class())),
+                        op.kind(),
+                        op.pos(),
+                    );
                 } else if opts.clobbers && bool::arbitrary(u)? {
                     for _ in 0..u.int_in_range(0..=5)? {
                         let reg = u.int_in_range(0..=30)?;
-                        if clobbers.iter().any(|r| r.hw_enc() == reg) {
+                        if clobbers.iter().any(|r| r.num() == reg) {
                             break;
                         }
                         clobbers.push(PReg::new(reg, RegClass::Int));
                     }
                 }
-                let op = *u.choose(&[InstOpcode::Op, InstOpcode::Call])?;
+                if opts.multi_fixed_regs && bool::arbitrary(u)? {
+                    // Constrain one vreg to several fixed regs at once, and
+                    // reuse vregs so constrained earlier in the block with a
+                    // fresh fixed reg. Stay clear of the reused input, vregs
+                    // with excluded regs, this inst's clobbers and any reg
+                    // another operand is fixed to, so that the constraints
+                    // remain satisfiable.
+                    let mut unfixable = vec![];
+                    for op in &operands {
+                        match op.policy() {
+                            OperandPolicy::Reuse(idx) => unfixable.push(operands[idx].vreg()),
+                            OperandPolicy::RegExcept(_) | OperandPolicy::Stack => {
+                                unfixable.push(op.vreg())
+                            }
+                            _ => {}
+                        }
+                    }
+                    let mut taken = clobbers.clone();
+                    for op in &operands {
+                        if let OperandPolicy::FixedReg(preg) = op.policy() {
+                            taken.push(preg);
+                        }
+                    }
+                    let mut fixed_uses = vec![];
+                    if operands.len() > 1 {
+                        let i = u.int_in_range(1..=(operands.len() - 1))?;
+                        let vreg = operands[i].vreg();
+                        if !unfixable.contains(&vreg) {
+                            for _ in 0..u.int_in_range(2..=3)? {
+                                let preg = PReg::new(u.int_in_range(0..=30)?, RegClass::Int);
+                                if !taken.contains(&preg) {
+                                    taken.push(preg);
+                                    fixed_uses.push((vreg, preg));
+                                }
+                            }
+                        }
+                    }
+                    if !multi_fixed_vregs.is_empty() && bool::arbitrary(u)? {
+                        let vreg = *u.choose(&multi_fixed_vregs[..])?;
+                        let preg = PReg::new(u.int_in_range(0..=30)?, RegClass::Int);
+                        if !unfixable.contains(&vreg) && !taken.contains(&preg) {
+                            fixed_uses.push((vreg, preg));
+                        }
+                    }
+                    for (vreg, preg) in fixed_uses {
+                        operands.push(Operand::new(
+                            vreg,
+                            OperandPolicy::FixedReg(preg),
+                            OperandKind::Use,
+                            OperandPos::Before,
+                        ));
+                        allocations.push(Allocation::none());
+                        if !multi_fixed_vregs.contains(&vreg) {
+                            multi_fixed_vregs.push(vreg);
+                        }
+                    }
+                }
+                let mut op = *u.choose(&[InstOpcode::Op, InstOpcode::Call])?;
+                if opts.moves && operands.len() == 2 && clobbers.is_empty() && bool::arbitrary(u)? {
+                    // A plain register-to-register copy.
+                    operands[0] = Operand::reg_def(operands[0].vreg());
+                    operands[1] = Operand::reg_use(operands[1].vreg());
+                    op = InstOpcode::Move;
+                }
+                if op != InstOpcode::Move
+                    && chance(u, opts.clobber_call_percent)?
+                    && operands
+                        .iter()
+                        .all(|op| !matches!(op.policy(), OperandPolicy::FixedReg(_)))
+                {
+                    // A call that clobbers every caller-saved register.
+                    op = InstOpcode::Call;
+                    clobbers = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+                }
+                let mut soft_clobbers: Vec<PReg> = vec![];
+                if opts.soft_clobbers && op != InstOpcode::Move && bool::arbitrary(u)? {
+                    for _ in 0..u.int_in_range(1..=5)? {
+                        let preg = PReg::new(u.int_in_range(0..=30)?, RegClass::Int);
+                        if !clobbers.contains(&preg) && !soft_clobbers.contains(&preg) {
+                            soft_clobbers.push(preg);
+                        }
+                    }
+                }
                 builder.add_inst(
                     Block::new(block),
                     InstData {
                         op,
                         operands,
                         clobbers,
+                        soft_clobbers,
+                        call_conv: None,
+                        no_edits_after: false,
+                        delay_slot: false,
+                        reads_flags: false,
+                        writes_flags: false,
                     },
                 );
                 avail.push(vreg);
@@ -480,10 +1183,370 @@ impl Func {
                         args.push(vreg.vreg());
                     }
                 }
-                builder.add_inst(Block::new(block), InstData::branch(&args[..]));
+                let mut branch = InstData::branch(&args[..]);
+                if opts.delay_slots
+                    && builder.f.block_succs[block].len() == 1
+                    && bool::arbitrary(u)?
+                {
+                    // Its args are read after the edge moves, from
+                    // wherever the successor's blockparams live.
+                    for op in &mut branch.operands {
+                        *op = Operand::new(
+                            op.vreg(),
+                            OperandPolicy::Any,
+                            OperandKind::Use,
+                            OperandPos::Before,
+                        );
+                    }
+                    branch.delay_slot = true;
+                }
+                if opts.transfer_args {
+                    // Some args are read only by the out-edge moves.
+                    for op in &mut branch.operands {
+                        if bool::arbitrary(u)? {
+                            *op = Operand::transfer_use(op.vreg());
+                        }
+                    }
+                }
+                if opts.branch_defs && !branch.delay_slot && bool::arbitrary(u)? {
+                    // Define a link register, usable in every block
+                    // the branch dominates, among the args.
+                    let vreg = builder.vreg();
+                    let policy = if opts.fixed_regs && bool::arbitrary(u)? {
+                        OperandPolicy::FixedReg(PReg::new(u.int_in_range(0..=30)?, RegClass::Int))
+                    } else {
+                        OperandPolicy::arbitrary(u)?
+                    };
+                    let pos = if opts.late_positions {
+                        *u.choose(&OperandPos::ALL[..])?
+                    } else if bool::arbitrary(u)? {
+                        OperandPos::Before
+                    } else {
+                        OperandPos::After
+                    };
+                    let i = u.int_in_range(0..=branch.operands.len())?;
+                    branch
+                        .operands
+                        .insert(i, Operand::new(vreg, policy, OperandKind::Def, pos));
+                    vregs_by_block[block].push(vreg);
+                }
+                builder.add_inst(Block::new(block), branch);
             } else {
+                if !live_vregs.is_empty() {
+                    let uses = live_vregs
+                        .iter()
+                        .map(|&vreg| {
+                            Operand::new(
+                                vreg,
+                                OperandPolicy::Any,
+                                OperandKind::Use,
+                                OperandPos::Before,
+                            )
+                        })
+                        .collect();
+                    builder.add_inst(
+                        Block::new(block),
+                        InstData::new(InstOpcode::Op, uses, vec![]),
+                    );
+                }
                 builder.add_inst(Block::new(block), InstData::ret());
             }
+
+            if opts.edit_restrictions || opts.flags {
+                // Forbid edits between some adjacent insts, in runs of
+                // at most three insts, where no constraint of the insts
+                // themselves needs a move (nor do edge moves precede a
+                // branch with a delay slot, nor need references be
+                // spilled for a safepoint). Each restriction is either
+                // explicit or due to the flags being live.
+                let restrictable = |inst: &InstData| {
+                    inst.clobbers.is_empty()
+                        && !inst.delay_slot
+                        && !(opts.reftypes && inst.op == InstOpcode::Call)
+                        && inst.operands.iter().all(|op| {
+                            !matches!(
+                                op.policy(),
+                                OperandPolicy::FixedReg(_)
+                                    | OperandPolicy::Reuse(_)
+                                    | OperandPolicy::Stack
+                                    | OperandPolicy::FixedStack(_)
+                            )
+                        })
+                };
+                let insts = &mut builder.insts_per_block[block];
+                let mut run = 0;
+                for i in 1..insts.len() {
+                    if run < 2
+                        && restrictable(&insts[i - 1])
+                        && restrictable(&insts[i])
+                        && bool::arbitrary(u)?
+                    {
+                        if opts.flags && (!opts.edit_restrictions || bool::arbitrary(u)?) {
+                            insts[i - 1].writes_flags = true;
+                            insts[i].reads_flags = true;
+                        } else {
+                            insts[i - 1].no_edits_after = true;
+                        }
+                        run += 1;
+                    } else {
+                        run = 0;
+                    }
+                }
+            }
+        }
+
+        if opts.equivalent_vregs {
+            // Link some vregs with an earlier one; the links may well
+            // be bogus (overlapping live ranges), as in a frontend
+            // with imprecise knowledge.
+            for vreg in 0..builder.f.num_vregs {
+                let other = if vreg > 0 && bool::arbitrary(u)? {
+                    let other = u.int_in_range(0..=(vreg - 1))?;
+                    Some(VReg::new(other, RegClass::Int))
+                } else {
+                    None
+                };
+                builder.f.equivalent_vregs.push(other);
+            }
+        }
+
+        if opts.spill_weights {
+            for _ in 0..builder.f.num_vregs {
+                let weight = match u.int_in_range(0..=3)? {
+                    0 => SpillWeight::Scale(u.int_in_range(0..=40)? as f32 / 10.0),
+                    1 => SpillWeight::Override(u.int_in_range(0..=u32::MAX)?),
+                    _ => SpillWeight::Default,
+                };
+                builder.f.spill_weights.push(weight);
+            }
+        }
+
+        if opts.spill_preferences {
+            for _ in 0..builder.f.num_vregs {
+                let pref = match u.int_in_range(0..=3)? {
+                    0 => SpillPreference::CalleeSavedReg,
+                    1 => SpillPreference::Memory,
+                    _ => SpillPreference::Default,
+                };
+                builder.f.spill_preferences.push(pref);
+            }
+        }
+
+        if opts.no_spill {
+            for _ in 0..builder.f.num_vregs {
+                let no_spill = u.int_in_range(0..=7)? == 0;
+                builder.f.no_spill_vregs.push(no_spill);
+            }
+        }
+
+        if opts.reftypes {
+            for vreg in 0..builder.f.num_vregs {
+                let no_spill = builder.f.no_spill_vregs.get(vreg) == Some(&true);
+                let reftype = !no_spill && u.int_in_range(0..=3)? == 0;
+                builder.f.reftype_vregs.push(reftype);
+            }
+            // A safepoint cannot take a reference it keeps live in a
+            // register, as the reference must be in its spillslot
+            // there: read them with `Any`, unless fixed to a register
+            // up front.
+            for inst in builder.insts_per_block.iter_mut().flatten() {
+                if inst.op != InstOpcode::Call {
+                    continue;
+                }
+                let reuses = inst
+                    .operands
+                    .iter()
+                    .any(|op| matches!(op.policy(), OperandPolicy::Reuse(_)));
+                for op in inst.operands.iter_mut() {
+                    let early_fixed = matches!(op.policy(), OperandPolicy::FixedReg(_))
+                        && op.pos() == OperandPos::Before
+                        && !reuses;
+                    if op.kind() == OperandKind::Use
+                        && builder.f.reftype_vregs[op.vreg().vreg()]
+                        && !early_fixed
+                        && !matches!(
+                            op.policy(),
+                            OperandPolicy::Stack | OperandPolicy::FixedStack(_)
+                        )
+                    {
+                        *op = Operand::new(op.vreg(), OperandPolicy::Any, op.kind(), op.pos());
+                    }
+                }
+            }
+        }
+
+        if opts.remat {
+            for _ in 0..builder.f.num_vregs {
+                let kind = match u.int_in_range(0..=11)? {
+                    0 => Some(RematKind::Constant),
+                    1 => Some(RematKind::Address),
+                    2 => Some(RematKind::Other(u32::arbitrary(u)?)),
+                    _ => None,
+                };
+                builder.f.remat_vregs.push(kind);
+            }
+        }
+
+        if opts.pinned {
+            let vreg = *live_vregs.last().unwrap();
+            builder.pin(vreg, PReg::new(32, RegClass::Int));
+            if let Some(reftype) = builder.f.reftype_vregs.get_mut(vreg.vreg()) {
+                *reftype = false;
+            }
+            for inst in builder.insts_per_block.iter_mut().flatten() {
+                for op in inst.operands.iter_mut() {
+                    if op.vreg() == vreg
+                        && !matches!(
+                            op.policy(),
+                            OperandPolicy::Any
+                                | OperandPolicy::Reg
+                                | OperandPolicy::PrefReg(_)
+                                | OperandPolicy::RegExcept(_)
+                        )
+                    {
+                        *op = Operand::new(vreg, OperandPolicy::Reg, op.kind(), op.pos());
+                    }
+                }
+            }
+        }
+
+        if opts.reftypes {
+            // Derive some referencing defs from a reference the same
+            // inst reads, as an address computation would.
+            let mut fixed = vec![false; builder.f.num_vregs];
+            for op in builder
+                .insts_per_block
+                .iter()
+                .flatten()
+                .flat_map(|i| &i.operands)
+            {
+                if op.kind() == OperandKind::Def && op.policy() == OperandPolicy::Constant {
+                    fixed[op.vreg().vreg()] = true;
+                }
+            }
+            let reftype = |vreg: VReg| builder.f.reftype_vregs.get(vreg.vreg()) == Some(&true);
+            let mut derived = vec![];
+            for inst in builder.insts_per_block.iter().flatten() {
+                if inst.op != InstOpcode::Op {
+                    continue;
+                }
+                for def in &inst.operands {
+                    let d = def.vreg();
+                    if def.kind() != OperandKind::Def || !reftype(d) || fixed[d.vreg()] {
+                        continue;
+                    }
+                    let base = inst.operands.iter().map(|op| op.vreg()).find(|&base| {
+                        base != d
+                            && reftype(base)
+                            && !fixed[base.vreg()]
+                            && derived.iter().all(|&(v, _): &(VReg, VReg)| v != base)
+                    });
+                    if let Some(base) = base {
+                        if bool::arbitrary(u)? {
+                            derived.push((d, base));
+                            // A base may not itself be derived.
+                            fixed[base.vreg()] = true;
+                        }
+                    }
+                }
+            }
+            for (vreg, base) in derived {
+                builder.derived(vreg, base);
+            }
+        }
+
+        if opts.non_ssa {
+            builder.non_ssa();
+            // Vregs that must keep their single def.
+            let mut single_def = vec![false; builder.f.num_vregs];
+            for &param in builder.f.block_params.iter().flatten() {
+                single_def[param.vreg()] = true;
+            }
+            for op in builder
+                .insts_per_block
+                .iter()
+                .flatten()
+                .flat_map(|i| &i.operands)
+            {
+                if op.kind() == OperandKind::Def
+                    && (op.policy() == OperandPolicy::Constant || op.is_conditional_def())
+                {
+                    single_def[op.vreg().vreg()] = true;
+                }
+            }
+            for (vreg, single) in single_def.iter_mut().enumerate() {
+                *single |= builder.f.remat_vregs.get(vreg).is_some_and(|k| k.is_some())
+                    || builder
+                        .f
+                        .pinned_vregs
+                        .get(vreg)
+                        .is_some_and(|p| p.is_some())
+                    || builder.f.reftype_vregs.get(vreg) == Some(&true);
+            }
+            // Rewrite a use read only once by its inst, and not reused,
+            // where no edits are forbidden around the inst. As the use
+            // was reachable only past a def, so is what replaces it.
+            for insts in builder.insts_per_block.iter_mut() {
+                for i in 0..insts.len() {
+                    let restricted = insts[i].no_edits_after
+                        || insts[i].reads_flags
+                        || (i > 0 && (insts[i - 1].no_edits_after || insts[i - 1].writes_flags));
+                    if insts[i].op != InstOpcode::Op || restricted {
+                        continue;
+                    }
+                    let operands = &mut insts[i].operands;
+                    for j in 0..operands.len() {
+                        let op = operands[j];
+                        let reused = operands
+                            .iter()
+                            .any(|other| other.policy() == OperandPolicy::Reuse(j));
+                        if op.kind() != OperandKind::Use
+                            || op.pos() != OperandPos::Before
+                            || single_def[op.vreg().vreg()]
+                            || reused
+                            || matches!(op.policy(), OperandPolicy::FixedStack(_))
+                            || operands.iter().filter(|o| o.vreg() == op.vreg()).count() > 1
+                        {
+                            continue;
+                        }
+                        match u.int_in_range(0..=7)? {
+                            0 | 1 => {
+                                operands[j] = Operand::new(
+                                    op.vreg(),
+                                    op.policy(),
+                                    OperandKind::Mod,
+                                    OperandPos::Before,
+                                );
+                            }
+                            2 => operands[j] = Operand::reg_def(op.vreg()),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        if opts.cold_blocks {
+            for cold in builder.f.cold_blocks.iter_mut() {
+                *cold = u.int_in_range(0..=3)? == 0;
+            }
+        }
+
+        if opts.debug_labels {
+            let insts: usize = builder.insts_per_block.iter().map(|i| i.len()).sum();
+            for vreg in 0..builder.f.num_vregs {
+                if u.int_in_range(0..=3)? != 0 {
+                    continue;
+                }
+                let from = u.int_in_range(0..=insts - 1)?;
+                let to = u.int_in_range(from + 1..=insts)?;
+                builder.label(
+                    VReg::new(vreg, RegClass::Int),
+                    Inst::new(from),
+                    Inst::new(to),
+                    vreg as u32,
+                );
+            }
         }
 
         Ok(builder.finalize())
@@ -492,7 +1555,7 @@ impl Func {
 
 impl std::fmt::Debug for Func {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{{\n")?;
+        writeln!(f, "{{")?;
         for (i, blockrange) in self.blocks.iter().enumerate() {
             let succs = self.block_succs[i]
                 .iter()
@@ -507,23 +1570,107 @@ impl std::fmt::Debug for Func {
                 .map(|v| format!("v{}", v.vreg()))
                 .collect::<Vec<_>>()
                 .join(", ");
-            write!(
+            let cold = if self.cold_blocks[i] { " cold" } else { "" };
+            writeln!(
                 f,
-                "  block{}({}): # succs:{:?} preds:{:?}\n",
-                i, params, succs, preds
+                "  block{}({}): # succs:{:?} preds:{:?}{}",
+                i, params, succs, preds, cold
             )?;
             for inst in blockrange.iter() {
-                write!(
+                writeln!(
                     f,
-                    "    inst{}: {:?} ops:{:?} clobber:{:?}\n",
+                    "    inst{}: {:?} ops:{:?} clobber:{:?}{}{}{}{}{}",
                     inst.index(),
                     self.insts[inst.index()].op,
                     self.insts[inst.index()].operands,
-                    self.insts[inst.index()].clobbers
+                    self.insts[inst.index()].clobbers,
+                    if self.insts[inst.index()].soft_clobbers.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" soft_clobber:{:?}", self.insts[inst.index()].soft_clobbers)
+                    },
+                    if self.insts[inst.index()].no_edits_after {
+                        " no_edits_after"
+                    } else {
+                        ""
+                    },
+                    if self.insts[inst.index()].delay_slot {
+                        " delay_slot"
+                    } else {
+                        ""
+                    },
+                    if self.insts[inst.index()].writes_flags {
+                        " writes_flags"
+                    } else {
+                        ""
+                    },
+                    if self.insts[inst.index()].reads_flags {
+                        " reads_flags"
+                    } else {
+                        ""
+                    }
                 )?;
             }
         }
-        write!(f, "}}\n")?;
+        for (vreg, other) in self.equivalent_vregs.iter().enumerate() {
+            if let Some(other) = other {
+                writeln!(f, "  v{} ~ v{}", vreg, other.vreg())?;
+            }
+        }
+        for (vreg, name) in self.vreg_names.iter().enumerate() {
+            if let Some(name) = name {
+                writeln!(f, "  v{} = {}", vreg, name)?;
+            }
+        }
+        for (vreg, weight) in self.spill_weights.iter().enumerate() {
+            if *weight != SpillWeight::Default {
+                writeln!(f, "  v{} weight {:?}", vreg, weight)?;
+            }
+        }
+        for (vreg, pref) in self.spill_preferences.iter().enumerate() {
+            if *pref != SpillPreference::Default {
+                writeln!(f, "  v{} spill {:?}", vreg, pref)?;
+            }
+        }
+        for (vreg, &no_spill) in self.no_spill_vregs.iter().enumerate() {
+            if no_spill {
+                writeln!(f, "  v{} no_spill", vreg)?;
+            }
+        }
+        for (vreg, &reftype) in self.reftype_vregs.iter().enumerate() {
+            if reftype {
+                writeln!(f, "  v{} reftype", vreg)?;
+            }
+        }
+        for (vreg, base) in self.derived_refs.iter().enumerate() {
+            if let Some(base) = base {
+                writeln!(f, "  v{} derived from {}", vreg, base)?;
+            }
+        }
+        for (vreg, kind) in self.remat_vregs.iter().enumerate() {
+            if let Some(kind) = kind {
+                writeln!(f, "  v{} remat {:?}", vreg, kind)?;
+            }
+        }
+        for (vreg, preg) in self.pinned_vregs.iter().enumerate() {
+            if let Some(preg) = preg {
+                writeln!(f, "  v{} pinned {}", vreg, preg)?;
+            }
+        }
+        if self.non_ssa {
+            writeln!(f, "  non_ssa")?;
+        }
+        for &(vreg, from, to, label) in &self.debug_labels {
+            writeln!(
+                f,
+                "  v{} label {} inst{}..inst{}",
+                vreg.vreg(),
+                label,
+                from.index(),
+                to.index()
+            )?;
+        }
+        writeln!(f, "}}")?;
         Ok(())
     }
 }
@@ -534,9 +1681,192 @@ pub fn machine_env() -> MachineEnv {
     let regs_by_class: Vec<Vec<PReg>> = vec![regs.clone(), vec![]];
     let scratch_by_class: Vec<PReg> =
         vec![PReg::new(31, RegClass::Int), PReg::new(0, RegClass::Float)];
+    let spillslot_layout_by_class = vec![
+        SpillSlotLayout {
+            unit_bytes: 8,
+            align: 8,
+        },
+        SpillSlotLayout {
+            unit_bytes: 16,
+            align: 16,
+        },
+    ];
+    // The last few allocatable registers are callee-saved.
+    let callee_saved_regs = (24..31).map(|i| PReg::new(i, RegClass::Int)).collect();
     MachineEnv {
         regs,
         regs_by_class,
         scratch_by_class,
+        spillslot_layout_by_class,
+        callee_saved_regs,
+        frame_pointer: None,
+        call_convs: vec![],
+        reg_costs: vec![],
+        preferred_regs_by_class: vec![],
+        non_preferred_regs_by_class: vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_rejects_unterminated_block() {
+        let mut builder = FuncBuilder::new();
+        let (b0, b1) = (builder.block(), builder.block());
+        let v0 = builder.vreg();
+        builder.inst(b0, &[Operand::reg_def(v0)], &[]);
+        builder.branch(b0, &[b1], &[]);
+        builder.inst(b1, &[Operand::reg_use(v0)], &[]);
+        assert!(matches!(builder.build(), Err(RegAllocError::BB(b)) if b == b1));
+
+        let mut builder = FuncBuilder::new();
+        let b0 = builder.block();
+        builder.block();
+        builder.ret(b0);
+        assert!(matches!(builder.build(), Err(RegAllocError::BB(b)) if b.index() == 1));
+    }
+
+    #[test]
+    fn test_build_rejects_critical_edge() {
+        // b0 branches to b1 and b2, and b1 falls into b2: the edge
+        // b0 -> b2 leaves a block with two successors for one with
+        // two predecessors.
+        let mut builder = FuncBuilder::new();
+        let (b0, b1, b2) = (builder.block(), builder.block(), builder.block());
+        builder.branch(b0, &[b1, b2], &[]);
+        builder.branch(b1, &[b2], &[]);
+        builder.ret(b2);
+        assert!(matches!(builder.build(), Err(RegAllocError::BB(b)) if b == b0));
+    }
+
+    #[test]
+    fn test_build_rejects_non_ssa() {
+        let build = |non_ssa: bool| {
+            let mut builder = FuncBuilder::new();
+            let b0 = builder.block();
+            let v0 = builder.vreg();
+            builder.inst(b0, &[Operand::reg_def(v0)], &[]);
+            builder.inst(b0, &[Operand::reg_def(v0)], &[]);
+            builder.inst(b0, &[Operand::reg_use(v0)], &[]);
+            builder.ret(b0);
+            if non_ssa {
+                builder.non_ssa();
+            }
+            builder.build()
+        };
+        assert!(matches!(build(false), Err(RegAllocError::SSA(v, _)) if v.vreg() == 0));
+        assert!(build(true).is_ok());
+    }
+
+    /// Generate a function with `opts` from a fixed byte stream.
+    fn generate(opts: &Options) -> Func {
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        loop {
+            let bytes: Vec<u8> = (0..10000)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect();
+            if let Ok(func) = Func::arbitrary_with_options(&mut Unstructured::new(&bytes), opts) {
+                return func;
+            }
+        }
+    }
+
+    #[test]
+    fn test_stress_options() {
+        // Loops nest exactly as deep as asked.
+        for depth in 1..=3 {
+            let func = generate(&Options {
+                loop_depth: depth,
+                ..Options::default()
+            });
+            let cfginfo = CFGInfo::new(&func);
+            assert_eq!(cfginfo.loop_depth.iter().max(), Some(&(depth as u32)));
+        }
+
+        // The values live throughout are defined first, and used by
+        // each return.
+        let func = generate(&Options {
+            live_vregs: 40,
+            ..Options::default()
+        });
+        let live: Vec<VReg> = func.insts[..40]
+            .iter()
+            .map(|inst| {
+                assert_eq!(inst.operands[0].kind(), OperandKind::Def);
+                inst.operands[0].vreg()
+            })
+            .collect();
+        for block in 0..func.blocks() {
+            let last = func.block_insns(Block::new(block)).last();
+            if func.is_ret(last) {
+                let uses: Vec<VReg> = func
+                    .inst_operands(last.prev())
+                    .iter()
+                    .map(|op| op.vreg())
+                    .collect();
+                assert_eq!(uses, live);
+            }
+        }
+
+        // At 100%, every instruction that can take a constraint does.
+        // The generated instructions are those that define a value
+        // and are neither branches nor the live values' defs.
+        let generated = |func: &Func| -> Vec<Inst> {
+            (0..func.insts())
+                .map(Inst::new)
+                .filter(|&inst| {
+                    let ops = func.inst_operands(inst);
+                    !func.is_branch(inst)
+                        && ops.first().is_some_and(|op| op.kind() == OperandKind::Def)
+                })
+                .collect()
+        };
+        let func = generate(&Options {
+            reuse_percent: 100,
+            ..Options::default()
+        });
+        let with_inputs: Vec<Inst> = generated(&func)
+            .into_iter()
+            .filter(|&inst| func.inst_operands(inst).len() > 1)
+            .collect();
+        assert!(!with_inputs.is_empty());
+        for inst in with_inputs {
+            let ops = func.inst_operands(inst);
+            assert!(
+                matches!(ops[0].policy(), OperandPolicy::Reuse(_)),
+                "{:?}",
+                ops
+            );
+        }
+        let func = generate(&Options {
+            fixed_reg_percent: 100,
+            ..Options::default()
+        });
+        for inst in generated(&func) {
+            let ops = func.inst_operands(inst);
+            assert!(
+                ops.iter()
+                    .any(|op| matches!(op.policy(), OperandPolicy::FixedReg(_))),
+                "{:?}",
+                ops
+            );
+        }
+        let func = generate(&Options {
+            clobber_call_percent: 100,
+            ..Options::default()
+        });
+        let calls = generated(&func);
+        assert!(!calls.is_empty());
+        for inst in calls {
+            assert_eq!(func.insts[inst.index()].op, InstOpcode::Call);
+            assert_eq!(func.inst_clobbers(inst).len(), 24);
+        }
     }
 }
@@ -0,0 +1,421 @@
+//! Interpreter: executes a function before and after allocation and
+//! compares the values its operands read, as a semantic oracle.
+//!
+//! The checker reasons symbolically and takes the allocator's word for
+//! some facts, e.g. where blockparams live (`Edit::BlockParams`); it
+//! also does not model clobbers. This interpreter instead runs the
+//! function concretely. It steps the original function, a map from
+//! vreg to value, in lockstep with the allocated program, a map from
+//! allocation (register or spillslot) to value that the allocator's
+//! edits move values through and each instruction's clobbers erase,
+//! along with the soft clobbers the allocation does not rely on.
+//! Every use must find the same value in its allocation as its vreg
+//! holds, so an edge move ordered wrongly, a scratch register
+//! overwritten while still needed, or a value left in a clobbered
+//! register shows up as a mismatch.
+//!
+//! The semantics are arbitrary but deterministic given a seed: an
+//! instruction defines, or modifies in place with a `Mod` operand, a
+//! hash of its inputs; a move copies its input;
+//! a constant is a hash of its vreg; a conditional def leaves its
+//! prior value in place when the low bit of its hash is set; and each
+//! branch picks a pseudorandom successor. A safepoint acts as a moving
+//! collector: it rehashes every reference-typed value, in the SSA
+//! function and in the spillslots of its stackmap alone, so a
+//! reference read from anywhere else afterward is stale.
+
+use crate::liveness::inst_operands;
+use crate::{
+    Allocation, Block, Edit, Function, Inst, Operand, OperandKind, OperandPolicy, OperandPos,
+    Output, PReg, ProgPoint, VReg,
+};
+
+use std::collections::HashMap;
+
+/// A use whose allocation did not hold the value of its vreg.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub inst: Inst,
+    pub op: Operand,
+    pub alloc: Allocation,
+    pub expected: u64,
+    /// The value in the allocation, or `None` if nothing was written
+    /// to it since it was last clobbered, if ever.
+    pub actual: Option<u64>,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "inst{}: operand {} reads {}, which holds ",
+            self.inst.index(),
+            self.op,
+            self.alloc
+        )?;
+        match self.actual {
+            Some(actual) => write!(f, "{:#x}", actual)?,
+            None => write!(f, "no value")?,
+        }
+        write!(f, " instead of {:#x}", self.expected)
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+fn mix(x: u64) -> u64 {
+    // The splitmix64 finalizer.
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+struct Interpreter<'a, F: Function> {
+    func: &'a F,
+    output: &'a Output,
+    seed: u64,
+    /// The value of each vreg in the original function.
+    vregs: Vec<u64>,
+    /// The value in each allocation in the allocated program.
+    machine: HashMap<Allocation, u64>,
+    /// The reference-typed vregs, which safepoints relocate.
+    reftypes: Vec<VReg>,
+}
+
+impl<'a, F: Function> Interpreter<'a, F> {
+    fn constant(&self, vreg: VReg) -> u64 {
+        mix(self.seed ^ mix(vreg.vreg() as u64))
+    }
+
+    fn run_edits(&mut self, point: ProgPoint) {
+        let edits = &self.output.edits;
+        let start = edits.partition_point(|&(p, _)| p < point);
+        for (_, edit) in edits[start..].iter().take_while(|&&(p, _)| p == point) {
+            match *edit {
+                Edit::Move { from, to } => match self.machine.get(&from).copied() {
+                    Some(value) => {
+                        self.machine.insert(to, value);
+                    }
+                    None => {
+                        self.machine.remove(&to);
+                    }
+                },
+                Edit::DefineConstant { vreg, to } => {
+                    let value = self.constant(vreg);
+                    self.machine.insert(to, value);
+                }
+                // Recomputing the value gives what its def last
+                // computed.
+                Edit::Remat { vreg, to, .. } => {
+                    let value = self.vregs[vreg.vreg()];
+                    self.machine.insert(to, value);
+                }
+                // Metadata only: the moves must already have put the
+                // blockparams there.
+                Edit::BlockParams { .. } => {}
+            }
+        }
+    }
+
+    /// Relocate the references at safepoint `inst`, other than those
+    /// it defines. A derived reference moves with its base, so its
+    /// new value depends on the base's old one.
+    fn relocate(&mut self, inst: Inst) {
+        let salt = mix(self.seed ^ !(inst.index() as u64));
+        let defs: Vec<VReg> = inst_operands(self.func, inst)
+            .iter()
+            .filter(|op| op.kind() != OperandKind::Use)
+            .map(|op| op.vreg())
+            .collect();
+        let old = self.vregs.clone();
+        for &vreg in &self.reftypes {
+            if defs.contains(&vreg) {
+                continue;
+            }
+            let value = match self.func.derived_ref_base(vreg) {
+                Some(base) if !defs.contains(&base) => old[vreg.vreg()] ^ old[base.vreg()],
+                _ => old[vreg.vreg()],
+            };
+            self.vregs[vreg.vreg()] = mix(value ^ salt);
+        }
+        let output = self.output;
+        let start = output
+            .safepoint_derived_slots
+            .partition_point(|&(i, _, _)| i < inst);
+        let derived: Vec<(Allocation, u64)> = output.safepoint_derived_slots[start..]
+            .iter()
+            .take_while(|&&(i, _, _)| i == inst)
+            .filter_map(|&(_, slot, base)| {
+                let slot = Allocation::stack(slot);
+                let value = self.machine.get(&slot)?;
+                let base = self.machine.get(&Allocation::stack(base)).copied()?;
+                Some((slot, mix(value ^ base ^ salt)))
+            })
+            .collect();
+        self.machine.extend(derived);
+        let start = output.safepoint_slots.partition_point(|&(i, _)| i < inst);
+        for &(_, slot) in output.safepoint_slots[start..]
+            .iter()
+            .take_while(|&&(i, _)| i == inst)
+        {
+            if let Some(value) = self.machine.get_mut(&Allocation::stack(slot)) {
+                *value = mix(*value ^ salt);
+            }
+        }
+    }
+
+    fn read(&self, inst: Inst, op: Operand, alloc: Allocation) -> Result<u64, Mismatch> {
+        let expected = self.vregs[op.vreg().vreg()];
+        let actual = self.machine.get(&alloc).copied();
+        if actual != Some(expected) {
+            return Err(Mismatch {
+                inst,
+                op,
+                alloc,
+                expected,
+                actual,
+            });
+        }
+        Ok(expected)
+    }
+
+    fn step(&mut self, inst: Inst) -> Result<(), Mismatch> {
+        let func = self.func;
+        let output = self.output;
+        let operands = inst_operands(func, inst);
+        let allocs = output.inst_allocs(inst);
+
+        let inputs: Vec<u64> = operands
+            .iter()
+            .filter(|op| op.kind() != OperandKind::Def)
+            .map(|op| self.vregs[op.vreg().vreg()])
+            .collect();
+
+        // At each of the instruction's positions in turn, its defs
+        // there are written, and then its uses there are read. The
+        // clobbers, other than the registers the instruction defines,
+        // take effect once the uses at `Before` are read, as in the
+        // checker. A `Mod` is read at `Before` and written at `After`.
+        for &pos in &OperandPos::ALL {
+            for (i, (op, &alloc)) in operands.iter().zip(allocs.iter()).enumerate() {
+                let def_pos = match op.kind() {
+                    OperandKind::Def => op.pos(),
+                    OperandKind::Mod => OperandPos::After,
+                    OperandKind::Use => continue,
+                };
+                if def_pos != pos {
+                    continue;
+                }
+                let mut value = if op.policy() == OperandPolicy::Constant {
+                    self.constant(op.vreg())
+                } else if let Some((src, _)) = func.is_move(inst) {
+                    self.vregs[src.vreg()]
+                } else {
+                    inputs.iter().fold(
+                        mix(self.seed ^ mix(((inst.index() as u64) << 8) | i as u64)),
+                        |hash, &input| mix(hash ^ input),
+                    )
+                };
+                let mut written = true;
+                if let OperandPolicy::Reuse(prior) = op.policy() {
+                    if op.is_conditional_def() && value & 1 != 0 {
+                        value = self.vregs[operands[prior].vreg().vreg()];
+                        written = false;
+                    }
+                }
+                self.vregs[op.vreg().vreg()] = value;
+                if written && alloc != Allocation::none() {
+                    self.machine.insert(alloc, value);
+                }
+            }
+
+            for (i, (op, &alloc)) in operands.iter().zip(allocs.iter()).enumerate() {
+                if op.kind() == OperandKind::Def || op.pos() != pos {
+                    continue;
+                }
+                // A reused input shares its location with the reusing
+                // def, which has overwritten it by `After`.
+                if pos >= OperandPos::After
+                    && operands
+                        .iter()
+                        .any(|def| def.policy() == OperandPolicy::Reuse(i))
+                {
+                    continue;
+                }
+                self.read(inst, *op, alloc)?;
+            }
+
+            if pos == OperandPos::Before {
+                let defined = |preg: PReg| {
+                    operands.iter().zip(allocs.iter()).any(|(op, &alloc)| {
+                        op.kind() != OperandKind::Use && alloc == Allocation::reg(preg)
+                    })
+                };
+                for &preg in func.inst_clobbers(inst) {
+                    if !defined(preg) {
+                        self.machine.remove(&Allocation::reg(preg));
+                    }
+                }
+                for &preg in func.inst_soft_clobbers(inst) {
+                    if !defined(preg)
+                        && output
+                            .preserved_soft_clobbers
+                            .binary_search_by_key(&(inst, preg.index()), |&(inst, preg)| {
+                                (inst, preg.index())
+                            })
+                            .is_err()
+                    {
+                        self.machine.remove(&Allocation::reg(preg));
+                    }
+                }
+            }
+        }
+        if func.is_safepoint(inst) && !self.reftypes.is_empty() {
+            self.relocate(inst);
+        }
+        Ok(())
+    }
+}
+
+/// Execute the function and its allocation in lockstep from the entry
+/// block, with semantics derived from `seed`, until a return or until
+/// `max_insts` instructions have run. Returns the first use whose
+/// allocation did not hold the value of its vreg, if any.
+///
+/// The output must have passed the checker's structural checks (every
+/// operand has an allocation, and so on); this only compares values.
+pub fn run<F: Function>(
+    func: &F,
+    output: &Output,
+    seed: u64,
+    max_insts: usize,
+) -> Result<(), Mismatch> {
+    let mut reftypes = vec![];
+    for block in 0..func.blocks() {
+        let block = Block::new(block);
+        reftypes.extend(
+            func.block_params(block)
+                .iter()
+                .filter(|&&v| func.is_reftype(v)),
+        );
+        for inst in func.block_insns(block).iter() {
+            reftypes.extend(
+                inst_operands(func, inst)
+                    .iter()
+                    .filter(|op| op.kind() != OperandKind::Use)
+                    .filter(|op| op.policy() != OperandPolicy::Constant)
+                    .map(|op| op.vreg())
+                    .filter(|&v| func.is_reftype(v)),
+            );
+        }
+    }
+    let mut interp = Interpreter {
+        func,
+        output,
+        seed,
+        vregs: vec![0; func.num_vregs()],
+        machine: HashMap::new(),
+        reftypes,
+    };
+    let mut rng = seed;
+    let mut block = func.entry_block();
+    let mut steps = 0;
+    loop {
+        let mut next: Option<Block> = None;
+        for inst in func.block_insns(block).iter() {
+            if steps == max_insts {
+                return Ok(());
+            }
+            steps += 1;
+            interp.run_edits(ProgPoint::before(inst));
+            interp.step(inst)?;
+            if func.is_branch(inst) {
+                // The branch's uses are the args of each successor's
+                // blockparams in turn.
+                rng = mix(rng);
+                let succs = func.block_succs(block);
+                let idx = (rng % succs.len() as u64) as usize;
+                let offset: usize = succs[..idx]
+                    .iter()
+                    .map(|&succ| func.block_params(succ).len())
+                    .sum();
+                let succ = succs[idx];
+                let args: Vec<u64> = inst_operands(func, inst)
+                    .iter()
+                    .filter(|op| op.kind() == OperandKind::Use)
+                    .skip(offset)
+                    .zip(func.block_params(succ))
+                    .map(|(arg, _)| interp.vregs[arg.vreg().vreg()])
+                    .collect();
+                for (&param, value) in func.block_params(succ).iter().zip(args) {
+                    interp.vregs[param.vreg()] = value;
+                }
+                next = Some(succ);
+            }
+            interp.run_edits(ProgPoint::after(inst));
+        }
+        match next {
+            Some(succ) => block = succ,
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzzing::func::{machine_env, FuncBuilder};
+
+    #[test]
+    fn test_interp() {
+        // v0 and v1 are defined in p1 and p2, and read swapped: a cycle
+        // of moves before inst 2. Run in the wrong order, or not at
+        // all, they leave a use reading the other value.
+        let env = machine_env();
+        let p = |i: usize| env.regs[i];
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, p(1))], &[])
+            .inst(block, &[Operand::reg_fixed_def(v1, p(2))], &[])
+            .inst(
+                block,
+                &[
+                    Operand::reg_fixed_use(v0, p(2)),
+                    Operand::reg_fixed_use(v1, p(1)),
+                ],
+                &[],
+            )
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = crate::run(&func, &env).expect("regalloc failed");
+        for seed in 0..4 {
+            run(&func, &out, seed, 10).expect("interpreter found a mismatch");
+        }
+
+        let before_use = ProgPoint::before(Inst::new(2));
+        let cycle: Vec<usize> = (0..out.edits.len())
+            .filter(|&i| out.edits[i].0 == before_use)
+            .collect();
+        assert_eq!(cycle.len(), 2);
+        let mut reordered = out.clone();
+        reordered.edits.swap(cycle[0], cycle[1]);
+        let mismatch = run(&func, &reordered, 0, 10).unwrap_err();
+        assert_eq!(mismatch.inst, Inst::new(2));
+        assert_eq!(mismatch.op.vreg(), v1);
+        assert_eq!(mismatch.alloc, Allocation::reg(p(1)));
+
+        let mut unmoved = out;
+        unmoved
+            .edits
+            .retain(|(_, edit)| !matches!(edit, Edit::Move { .. }));
+        let mismatch = run(&func, &unmoved, 0, 10).unwrap_err();
+        assert_eq!(mismatch.inst, Inst::new(2));
+        assert_eq!(mismatch.op.vreg(), v0);
+        assert_eq!(mismatch.alloc, Allocation::reg(p(2)));
+        assert!(mismatch.actual.is_some());
+        assert_ne!(mismatch.actual, Some(mismatch.expected));
+    }
+}
@@ -1,3 +1,4 @@
 //! Utilities for fuzzing.
 
 pub mod func;
+pub mod interp;
@@ -19,9 +19,6 @@
  *     - safepoints?
  *     - split just before uses with fixed regs and/or just after defs
  *       with fixed regs?
- *   - try-any-reg allocate loop should randomly probe in caller-save
- *     ("preferred") regs first -- have a notion of "preferred regs" in
- *     MachineEnv?
  *   - measure average liverange length / number of splits / ...
  *
  * - reused-input reg: don't allocate register for input that is reused.
@@ -34,27 +31,33 @@
  *   - lower blockparams to blockparams directly
  *   - use temps properly (`alloc_tmp()` vs `alloc_reg()`)
  *
- * - produce stackmaps
+ * - stackmaps
  *   - stack constraint (also: unify this with stack-args? spillslot vs user stackslot?)
- *   - vreg reffyness
- *   - if reffy vreg, add to stackmap lists during reification scan
  */
 
 #![allow(dead_code, unused_imports)]
 
+mod phases;
+pub use phases::{
+    Allocate, AllocationSteps, Decision, DecisionOutcome, Liveness, Merge, ProbeResult,
+};
+
 use crate::bitvec::BitVec;
 use crate::cfg::CFGInfo;
 use crate::index::ContainerComparator;
+use crate::liveness::LivenessInfo;
 use crate::moves::ParallelMoves;
 use crate::{
-    define_index, domtree, Allocation, AllocationKind, Block, Edit, Function, Inst, InstPosition,
-    MachineEnv, Operand, OperandKind, OperandPolicy, OperandPos, Output, PReg, ProgPoint,
-    RegAllocError, RegClass, SpillSlot, VReg,
+    define_index, domtree, vreg_label, Allocation, AllocationEvent, AllocationKind, Block,
+    BlockEdits, BlockPressure, EdgeMoves, Edit, Function, Inst, InstPosition, MachineEnv, Operand,
+    OperandKind, OperandPolicy, OperandPos, Output, PReg, ProgPoint, ProgPointIndex, RegAllocError,
+    RegClass, RegallocOptions, RematKind, SpillPreference, SpillSlot, SpillWeight, SplitReason,
+    VReg,
 };
 use log::debug;
 use smallvec::{smallvec, SmallVec};
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BinaryHeap};
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
 
 #[cfg(not(debug))]
@@ -69,12 +72,22 @@ fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllocErr
 
 /// A range from `from` (inclusive) to `to` (exclusive).
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CodeRange {
     from: ProgPoint,
     to: ProgPoint,
 }
 
 impl CodeRange {
+    pub fn new(from: ProgPoint, to: ProgPoint) -> Self {
+        Self { from, to }
+    }
+    pub fn from(&self) -> ProgPoint {
+        self.from
+    }
+    pub fn to(&self) -> ProgPoint {
+        self.to
+    }
     pub fn is_empty(&self) -> bool {
         self.from == self.to
     }
@@ -97,6 +110,11 @@ impl std::cmp::PartialOrd for CodeRange {
         Some(self.cmp(other))
     }
 }
+/// Ranges compare by position, and any two that overlap compare
+/// `Equal` (unlike `==`, which compares endpoints). This is a total
+/// order only over pairwise-disjoint ranges: `[0, 2)` and `[2, 4)`
+/// each equal `[1, 3)` but not each other. Use it only to sort or look
+/// up ranges known to be disjoint.
 impl std::cmp::Ord for CodeRange {
     fn cmp(&self, other: &Self) -> Ordering {
         if self.to <= other.from {
@@ -119,6 +137,7 @@ define_index!(PRegIndex);
 define_index!(SpillSlotIndex);
 
 type LiveBundleVec = SmallVec<[LiveBundleIndex; 4]>;
+type UseList = SmallVec<[UseIndex; 4]>;
 
 #[derive(Clone, Debug)]
 struct LiveRange {
@@ -128,8 +147,9 @@ struct LiveRange {
     uses_spill_weight: u32,
     num_fixed_uses_and_flags: u32,
 
-    first_use: UseIndex,
-    last_use: UseIndex,
+    // Sorted by position; uses at the same position keep their
+    // insertion order.
+    uses: UseList,
     def: DefIndex,
 
     next_in_bundle: LiveRangeIndex,
@@ -182,9 +202,12 @@ struct Use {
     operand: Operand,
     pos: ProgPoint,
     slot: usize,
-    next_use: UseIndex,
 }
 
+/// The slot of a `Use` that has no operand: one that keeps a
+/// reference-typed vreg in its spillslot during a safepoint.
+const SLOT_NONE: usize = usize::MAX;
+
 #[derive(Clone, Debug)]
 struct Def {
     operand: Operand,
@@ -200,6 +223,17 @@ struct LiveBundle {
     allocation: Allocation,
     prio: u32, // recomputed after every bulk update
     spill_weight_and_props: u32,
+    /// Registers that probes found held by a fixed reservation (e.g.
+    /// a clobber) somewhere in the bundle. Those never move, so until
+    /// the bundle's ranges change (by a split), these registers need
+    /// not be probed again.
+    fixed_conflicts: SmallVec<[PReg; 4]>,
+    /// The bundle that last evicted this one, if any, and the length
+    /// of the chain of evictions that led to it: each bundle evicted
+    /// by a bundle that was itself evicted extends its evictor's
+    /// chain by one.
+    evicted_by: LiveBundleIndex,
+    eviction_depth: u32,
 }
 
 impl LiveBundle {
@@ -222,7 +256,7 @@ impl LiveBundle {
 
     #[inline(always)]
     fn cached_spill_weight(&self) -> u32 {
-        self.spill_weight_and_props & !((1 << 30) - 1)
+        self.spill_weight_and_props & ((1 << 30) - 1)
     }
 }
 
@@ -233,14 +267,30 @@ struct SpillSet {
     class: RegClass,
     slot: SpillSlotIndex,
     reg_hint: Option<PReg>,
+    /// The spillset belongs to a constant or rematerializable vreg: it
+    /// gets no spillslot, and its value is rematerialized rather than
+    /// reloaded.
+    remat: bool,
+    /// How many times the spillset's bundles have gone back into the
+    /// allocation queue, evicted or split; see `MAX_REQUEUES`.
+    requeues: u32,
 }
 
 #[derive(Clone, Debug)]
 struct VRegData {
     reg: VReg,
-    def: DefIndex,
     blockparam: Block,
     first_range: LiveRangeIndex,
+    is_constant: bool,
+    /// How to recompute the vreg, if it is rematerialized wherever it
+    /// is spilled; see `Function::is_rematerializable`.
+    remat: Option<RematKind>,
+    /// The register the vreg is held in wherever it is live, if any;
+    /// see `Function::pinned_vreg`.
+    pinned: Option<PReg>,
+    /// The vreg's own spillslot, used wherever it is spilled, if
+    /// `RegallocOptions::spillslot_per_vreg` is set.
+    spillslot: SpillSlotIndex,
 }
 
 #[derive(Clone, Debug)]
@@ -277,22 +327,49 @@ struct PRegData {
  * PReg --(ranges)--> set(LiveRange)
  */
 
+/// Storage for the LiveRanges, LiveBundles, SpillSets, Uses and Defs
+/// of one allocator run, addressed by typed indices and freed all at
+/// once when the run completes. An embedder allocating many functions
+/// can keep one arena and pass it to `run_with_arena()` each time, so
+/// that later runs reuse the memory of earlier ones rather than
+/// growing these tables from scratch. A run that fails with an error
+/// gives the storage back too; one that panics does not, and leaves
+/// the arena empty.
+///
+/// This reuses the capacity of those tables only: it is not a bump
+/// allocator. Each range's list of uses is a small vector of its own,
+/// inline up to a few uses and allocated separately beyond that.
+#[derive(Clone, Debug, Default)]
+pub struct Arena {
+    ranges: Vec<LiveRange>,
+    bundles: Vec<LiveBundle>,
+    spillsets: Vec<SpillSet>,
+    uses: Vec<Use>,
+    defs: Vec<Def>,
+}
+
+impl Arena {
+    fn clear(&mut self) {
+        self.ranges.clear();
+        self.bundles.clear();
+        self.spillsets.clear();
+        self.uses.clear();
+        self.defs.clear();
+    }
+}
+
 #[derive(Clone, Debug)]
 struct Env<'a, F: Function> {
     func: &'a F,
     env: &'a MachineEnv,
+    opts: &'a RegallocOptions,
     cfginfo: CFGInfo,
     liveins: Vec<BitVec>,
     /// Blockparam outputs: from-vreg, (end of) from-block, (start of)
     /// to-block, to-vreg. The field order is significant: these are sorted so
     /// that a scan over vregs, then blocks in each range, can scan in
-    /// order through this (sorted) list and add allocs to the
-    /// half-move list.
+    /// order through this (sorted) list and insert the moves.
     blockparam_outs: Vec<(VRegIndex, Block, Block, VRegIndex)>,
-    /// Blockparam inputs: to-vreg, (start of) to-block, (end of)
-    /// from-block. As above for `blockparam_outs`, field order is
-    /// significant.
-    blockparam_ins: Vec<(VRegIndex, Block, Block)>,
     /// Blockparam allocs: block, idx, vreg, alloc. Info to describe
     /// blockparam locations at block entry, for metadata purposes
     /// (e.g. for the checker).
@@ -305,9 +382,41 @@ struct Env<'a, F: Function> {
     defs: Vec<Def>,
     vregs: Vec<VRegData>,
     pregs: Vec<PRegData>,
+    // The allocatable registers of each class in the order the
+    // probing loops try them: in increasing order of cost (see
+    // `MachineEnv::reg_costs`), then of preference (see
+    // `MachineEnv::preferred_regs_by_class`), otherwise in the order
+    // listed, with the (start, len) of each run of equal cost and
+    // preference. There is one order for bundles that do not live
+    // across a call, and one for those that do.
+    probe_regs_by_class: Vec<[Vec<PReg>; 2]>,
+    probe_runs_by_class: Vec<[Vec<(usize, usize)>; 2]>,
+    // The calls (see `Function::is_call`), in order.
+    calls: Vec<Inst>,
     allocation_queue: PrioQueue,
+    // With `RegallocOptions::region_size`, the bundles of the regions
+    // not yet allocated, last region first: each region's go into the
+    // allocation queue once the one before it is done (see
+    // `split_bundles_at_regions()`).
+    pending_regions: Vec<Vec<LiveBundleIndex>>,
     hot_code: LiveRangeSet,
+    // Code in blocks that `Function::is_cold_block` marks, with runs
+    // of consecutive cold blocks merged.
+    cold_code: LiveRangeSet,
     clobbers: Vec<Inst>, // Sorted list of insts with clobbers.
+    // Soft clobbers (see `Function::inst_soft_clobbers`), sorted by
+    // inst.
+    soft_clobbers: Vec<(Inst, PReg)>,
+    // Insts after which no edit may be placed (see
+    // `restricted_points()`).
+    no_edits_after: BitVec,
+    // Insts over which the callee-saved registers are reserved, having
+    // been moved back for a return (see `callee_restored_insts()`).
+    callee_restored: BitVec,
+    // Bases of derived references, each kept in its spillslot at a
+    // safepoint where it is otherwise dead; sorted by inst (see
+    // `compute_derived_keepalives()`).
+    derived_keepalives: Vec<(Inst, VReg)>,
 
     spilled_bundles: Vec<LiveBundleIndex>,
     spillslots: Vec<SpillSlotData>,
@@ -323,15 +432,58 @@ struct Env<'a, F: Function> {
     // was to the approprate PReg.
     //
     // (progpoint, copy-from-preg, copy-to-preg)
-    multi_fixed_reg_fixups: Vec<(ProgPoint, PRegIndex, PRegIndex)>,
+    multi_fixed_reg_fixups: Vec<(ProgPoint, PRegIndex, PRegIndex, usize)>,
+    // The fixed-reg uses in instructions that group them (see
+    // `Function::groups_fixed_uses`), allocated as `Any` uses: each
+    // is moved to its register just before the instruction.
+    //
+    // (inst, operand slot, fixed preg)
+    grouped_fixed_uses: Vec<(Inst, usize, PReg)>,
+    // The operands with a fixed stack slot, allocated as `Any`
+    // operands: a use is copied into its slot just before the
+    // instruction, and a def out of its slot just after.
+    //
+    // (inst, operand slot, kind, fixed spillslot)
+    fixed_stack_operands: Vec<(Inst, usize, OperandKind, SpillSlot)>,
 
     inserted_moves: Vec<InsertedMove>,
+    // Per-class spillslot for saving a register borrowed as scratch
+    // when no register is free; allocated on first use.
+    scratch_save_slots: [Option<SpillSlot>; 2],
 
     // Output:
-    edits: Vec<(u32, InsertMovePrio, Edit)>,
+    edits: Vec<(ProgPointIndex, InsertMovePrio, Edit)>,
     allocs: Vec<Allocation>,
     inst_alloc_offsets: Vec<u32>,
     num_spillslots: u32,
+    spillslot_offsets: Vec<u32>,
+    spillslot_area_size: u32,
+    callee_saves: Vec<(PReg, SpillSlot)>,
+    elided_moves: BitVec,
+    edge_moves: Vec<EdgeMoves>,
+    safepoint_locations: Vec<(Inst, VReg, Allocation)>,
+    preserved_soft_clobbers: Vec<(Inst, PReg)>,
+    safepoint_slots: Vec<(Inst, SpillSlot)>,
+    safepoint_derived_slots: Vec<(Inst, SpillSlot, SpillSlot)>,
+    debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
+    block_edits: Vec<BlockEdits>,
+    used_regs: Vec<PReg>,
+    edit_clobbered_regs: Vec<PReg>,
+
+    // If set, `process_next_bundle()` records what it did here.
+    decision: Option<Decision>,
+    // Set once the requeues overall pass their limit, after which
+    // every bundle is treated as churning; see `MAX_REQUEUES`.
+    requeue_limit_hit: bool,
+    // Set if allocation gave up at the global limit on bundles
+    // processed; see `MAX_PROCESS_BUNDLE_FACTOR`.
+    iteration_limit_exceeded: bool,
+    // Events in the allocation of each vreg, in explain mode (see
+    // `RegallocOptions::explain`); empty until the first.
+    explanations: Vec<Vec<AllocationEvent>>,
+    // State of the random tie-breaker, in chaos mode (see
+    // `RegallocOptions::chaos_seed`).
+    chaos: Option<u64>,
 
     stats: Stats,
 
@@ -355,30 +507,40 @@ struct SpillSlotList {
     last_spillslot: SpillSlotIndex,
 }
 
+/// Bundles waiting for allocation, highest priority first. A
+/// priority is a bundle's length, usually a small integer: keep a
+/// FIFO bucket per priority below `EXACT_PRIO_BUCKETS`, and above
+/// that one per `PRIO_BUCKET_MANTISSA_BITS`-bit step of each power of
+/// two, so that the buckets are bounded in number however long a
+/// bundle is. Pushing and popping take constant (amortized) time, and
+/// bundles in the same bucket come out in the order they went in.
 #[derive(Clone, Debug)]
 struct PrioQueue {
-    heap: std::collections::BinaryHeap<PrioQueueEntry>,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-struct PrioQueueEntry {
-    prio: u32,
-    bundle: LiveBundleIndex,
+    buckets: Vec<VecDeque<LiveBundleIndex>>,
+    // No bucket above this one holds a bundle.
+    max: usize,
+    len: usize,
 }
 
+/// A map from disjoint code ranges to the live ranges occupying them.
+/// Keys compare equal when they overlap, so a lookup finds any range
+/// overlapping the one given; insert only through `insert`.
 #[derive(Clone, Debug)]
-struct LiveRangeSet {
-    btree: BTreeMap<LiveRangeKey, LiveRangeIndex>,
+pub(crate) struct LiveRangeSet {
+    pub(crate) btree: BTreeMap<LiveRangeKey, LiveRangeIndex>,
 }
 
+/// A `CodeRange` as a key in a `LiveRangeSet`, with the same
+/// overlap-as-equality ordering (see `CodeRange`'s `Ord`), which
+/// `PartialEq` shares here.
 #[derive(Clone, Copy, Debug)]
-struct LiveRangeKey {
-    from: u32,
-    to: u32,
+pub(crate) struct LiveRangeKey {
+    from: ProgPointIndex,
+    to: ProgPointIndex,
 }
 
 impl LiveRangeKey {
-    fn from_range(range: &CodeRange) -> Self {
+    pub(crate) fn from_range(range: &CodeRange) -> Self {
         Self {
             from: range.from.to_index(),
             to: range.to.to_index(),
@@ -419,26 +581,64 @@ impl<'a> ContainerComparator for PrioQueueComparator<'a> {
     }
 }
 
+/// Priorities below this each have a bucket of their own.
+const EXACT_PRIO_BUCKETS: usize = 1 << 6;
+
+/// Above `EXACT_PRIO_BUCKETS`, each power of two is divided into this
+/// many bits' worth of buckets, so that priorities within about 1/8 of
+/// each other share one.
+const PRIO_BUCKET_MANTISSA_BITS: u32 = 3;
+
 impl PrioQueue {
+    /// The bucket for `prio`; higher priorities have higher buckets.
+    fn bucket(prio: usize) -> usize {
+        if prio < EXACT_PRIO_BUCKETS {
+            return prio;
+        }
+        let exact_bits = EXACT_PRIO_BUCKETS.trailing_zeros();
+        let log2 = usize::BITS - 1 - prio.leading_zeros();
+        let mantissa =
+            (prio >> (log2 - PRIO_BUCKET_MANTISSA_BITS)) & ((1 << PRIO_BUCKET_MANTISSA_BITS) - 1);
+        EXACT_PRIO_BUCKETS
+            + (((log2 - exact_bits) as usize) << PRIO_BUCKET_MANTISSA_BITS)
+            + mantissa
+    }
+
     fn new() -> Self {
         PrioQueue {
-            heap: std::collections::BinaryHeap::new(),
+            buckets: vec![],
+            max: 0,
+            len: 0,
         }
     }
 
     fn insert(&mut self, bundle: LiveBundleIndex, prio: usize) {
-        self.heap.push(PrioQueueEntry {
-            prio: prio as u32,
-            bundle,
-        });
+        let bucket = Self::bucket(prio);
+        if bucket >= self.buckets.len() {
+            self.buckets.resize_with(bucket + 1, VecDeque::new);
+        }
+        self.buckets[bucket].push_back(bundle);
+        self.max = std::cmp::max(self.max, bucket);
+        self.len += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
-    fn is_empty(self) -> bool {
-        self.heap.is_empty()
+    fn len(&self) -> usize {
+        self.len
     }
 
     fn pop(&mut self) -> Option<LiveBundleIndex> {
-        self.heap.pop().map(|entry| entry.bundle)
+        if self.is_empty() {
+            return None;
+        }
+        while self.buckets[self.max].is_empty() {
+            self.max -= 1;
+        }
+        self.len -= 1;
+        self.buckets[self.max].pop_front()
     }
 }
 
@@ -448,27 +648,104 @@ impl LiveRangeSet {
             btree: BTreeMap::new(),
         }
     }
+
+    /// Add a range, which must not overlap any already in the set: the
+    /// map would keep the old key under the new value, silently
+    /// corrupting it.
+    pub(crate) fn insert(&mut self, range: CodeRange, lr: LiveRangeIndex) {
+        let key = LiveRangeKey::from_range(&range);
+        debug_assert!(
+            !self.btree.contains_key(&key),
+            "range {:?} overlaps one already in the set",
+            range
+        );
+        self.btree.insert(key, lr);
+    }
 }
 
+/// The longest chain of evictions allowed, each bundle evicting one
+/// that evicted the last, before the allocator breaks it by spilling
+/// one of the bundles involved; see `Env::is_eviction_cascade`.
+const MAX_EVICTION_DEPTH: u32 = 16;
+
+/// The most times a spillset's bundles may go back into the
+/// allocation queue before the next of them to come out is
+/// force-spilled, or, if it must not spill, force-spills the bundles
+/// it evicts instead. Eviction chains are bounded by
+/// `MAX_EVICTION_DEPTH`, but bundles can also keep evicting and
+/// splitting one another without a chain ever growing long; this
+/// bounds that churn. Twice as many requeues per spillset overall
+/// means the allocator is not converging at all, and from then on
+/// every bundle is treated as churning.
+const MAX_REQUEUES: u32 = 64;
+
+/// A global backstop on the allocation loop, whatever keeps it from
+/// converging: once more bundles have been processed than this many
+/// per instruction and bundle, every bundle is treated as churning,
+/// as past the requeue limit; at twice as many, allocation gives up
+/// with `RegAllocError::IterationLimit`. The count of bundles is taken
+/// as it stands, since splitting, which adds bundles, is bounded.
+const MAX_PROCESS_BUNDLE_FACTOR: usize = 50;
+
+/// The highest spill weight that a `Function::spill_weight`
+/// adjustment can give a bundle: just below that of a minimal bundle.
+const MAX_ADJUSTED_SPILL_WEIGHT: u32 = 999_999;
+
+/// The spill weight of a bundle that must not spill (see
+/// `Function::no_spill`), unless it is minimal: heavier than any
+/// unadjusted bundle, but lighter than a minimal bundle that needs a
+/// register only because it must not spill, which in turn is lighter
+/// than every other minimal bundle. Each can evict the one before.
+const NO_SPILL_SPILL_WEIGHT: u32 = MAX_ADJUSTED_SPILL_WEIGHT - 1;
+
 fn spill_weight_from_policy(policy: OperandPolicy) -> u32 {
     match policy {
         OperandPolicy::Any => 1000,
-        OperandPolicy::Reg | OperandPolicy::FixedReg(_) => 2000,
+        OperandPolicy::Reg
+        | OperandPolicy::FixedReg(_)
+        | OperandPolicy::RegExcept(_)
+        | OperandPolicy::PrefReg(_) => 2000,
         _ => 0,
     }
 }
 
+/// The soft clobbers, of a list sorted by inst, whose instructions
+/// `range` overlaps.
+fn soft_clobbers_in(soft_clobbers: &[(Inst, PReg)], range: CodeRange) -> &[(Inst, PReg)] {
+    let start =
+        soft_clobbers.partition_point(|&(inst, _)| ProgPoint::before(inst.next()) <= range.from);
+    let end = soft_clobbers.partition_point(|&(inst, _)| ProgPoint::before(inst) < range.to);
+    &soft_clobbers[start..end.max(start)]
+}
+
+/// Sort debug locations by label, then start, and join each entry to
+/// the previous one if it continues it in the same allocation; see
+/// `Output::debug_locations`.
+pub(crate) fn sort_debug_locations(locations: &mut Vec<(u32, ProgPoint, ProgPoint, Allocation)>) {
+    locations.sort_unstable_by_key(|&(label, from, _, _)| (label, from));
+    locations.dedup_by(|next, prev| {
+        let joins = next.0 == prev.0 && next.1 == prev.2 && next.3 == prev.3;
+        if joins {
+            prev.2 = next.2;
+        }
+        joins
+    });
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Requirement {
     Fixed(PReg),
     Register(RegClass),
+    Stack(RegClass),
     Any(RegClass),
 }
 impl Requirement {
     fn class(self) -> RegClass {
         match self {
             Requirement::Fixed(preg) => preg.class(),
-            Requirement::Register(class) | Requirement::Any(class) => class,
+            Requirement::Register(class) | Requirement::Stack(class) | Requirement::Any(class) => {
+                class
+            }
         }
     }
 
@@ -482,7 +759,8 @@ impl Requirement {
             | (Requirement::Fixed(preg), Requirement::Register(_)) => {
                 Some(Requirement::Fixed(preg))
             }
-            (Requirement::Register(_), Requirement::Register(_)) => Some(self),
+            (Requirement::Register(_), Requirement::Register(_))
+            | (Requirement::Stack(_), Requirement::Stack(_)) => Some(self),
             (Requirement::Fixed(a), Requirement::Fixed(b)) if a == b => Some(self),
             _ => None,
         }
@@ -490,7 +768,11 @@ impl Requirement {
     fn from_operand(op: Operand) -> Requirement {
         match op.policy() {
             OperandPolicy::FixedReg(preg) => Requirement::Fixed(preg),
-            OperandPolicy::Reg | OperandPolicy::Reuse(_) => Requirement::Register(op.class()),
+            OperandPolicy::Reg
+            | OperandPolicy::Reuse(_)
+            | OperandPolicy::RegExcept(_)
+            | OperandPolicy::PrefReg(_) => Requirement::Register(op.class()),
+            OperandPolicy::Stack => Requirement::Stack(op.class()),
             _ => Requirement::Any(op.class()),
         }
     }
@@ -515,19 +797,28 @@ struct InsertedMove {
     prio: InsertMovePrio,
     from_alloc: Allocation,
     to_alloc: Allocation,
+    /// If set, this is a materialization of the given constant or
+    /// rematerializable vreg into `to_alloc` rather than a move;
+    /// `from_alloc` is `none`.
+    remat: Option<VReg>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum InsertMovePrio {
+    CalleeSave,
     InEdgeMoves,
     BlockParam,
     Regular,
     MultiFixedReg,
+    GroupedFixedUses,
+    FixedStack,
     ReusedInput,
     OutEdgeMoves,
+    CalleeRestore,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Stats {
     initial_liverange_count: usize,
     merged_bundle_count: usize,
@@ -538,9 +829,14 @@ pub struct Stats {
     process_bundle_reg_success_any: usize,
     evict_bundle_event: usize,
     evict_bundle_count: usize,
+    eviction_cascades: usize,
+    requeues: usize,
+    requeue_limit_spills: usize,
+    iteration_limit_hits: usize,
     splits: usize,
     splits_clobbers: usize,
     splits_hot: usize,
+    splits_cold: usize,
     splits_conflicts: usize,
     splits_all: usize,
     final_liverange_count: usize,
@@ -548,44 +844,104 @@ pub struct Stats {
     spill_bundle_count: usize,
     spill_bundle_reg_probes: usize,
     spill_bundle_reg_success: usize,
+    callee_saved_reg_success: usize,
+    fixed_conflict_probes_skipped: usize,
+    spillslots_merged: usize,
     blockparam_ins_count: usize,
     blockparam_outs_count: usize,
     blockparam_allocs_count: usize,
     halfmoves_count: usize,
     edits_count: usize,
+    constant_defs_count: usize,
+    remats_count: usize,
+    region_count: usize,
+    region_splits: usize,
+    pre_spills: usize,
+    precolored_bundles: usize,
+    grouped_fixed_uses: usize,
+    fixed_stack_operands: usize,
+    safepoint_uses: usize,
+    dynamic_scratch_count: usize,
+    scratch_saves_count: usize,
+    elided_moves_count: usize,
+    spill_stores: usize,
+    spill_reloads: usize,
+    spill_stores_weighted: usize,
+    spill_reloads_weighted: usize,
+    hot_code_moves: usize,
 }
 
 impl<'a, F: Function> Env<'a, F> {
-    pub(crate) fn new(func: &'a F, env: &'a MachineEnv, cfginfo: CFGInfo) -> Self {
+    pub(crate) fn new(
+        func: &'a F,
+        env: &'a MachineEnv,
+        opts: &'a RegallocOptions,
+        cfginfo: CFGInfo,
+        mut arena: Arena,
+    ) -> Self {
+        arena.clear();
         Self {
             func,
             env,
+            opts,
             cfginfo,
 
             liveins: vec![],
             blockparam_outs: vec![],
-            blockparam_ins: vec![],
             blockparam_allocs: vec![],
-            bundles: vec![],
-            ranges: vec![],
-            spillsets: vec![],
-            uses: vec![],
-            defs: vec![],
+            bundles: arena.bundles,
+            ranges: arena.ranges,
+            spillsets: arena.spillsets,
+            uses: arena.uses,
+            defs: arena.defs,
             vregs: vec![],
             pregs: vec![],
+            probe_regs_by_class: vec![],
+            probe_runs_by_class: vec![],
+            calls: vec![],
             allocation_queue: PrioQueue::new(),
+            pending_regions: vec![],
             clobbers: vec![],
+            soft_clobbers: vec![],
+            no_edits_after: BitVec::new(),
+            callee_restored: BitVec::new(),
+            derived_keepalives: vec![],
             hot_code: LiveRangeSet::new(),
+            cold_code: LiveRangeSet::new(),
             spilled_bundles: vec![],
             spillslots: vec![],
             slots_by_size: vec![],
 
             multi_fixed_reg_fixups: vec![],
+            grouped_fixed_uses: vec![],
+            fixed_stack_operands: vec![],
             inserted_moves: vec![],
             edits: vec![],
             allocs: vec![],
             inst_alloc_offsets: vec![],
             num_spillslots: 0,
+            spillslot_offsets: vec![],
+            spillslot_area_size: 0,
+            callee_saves: vec![],
+            elided_moves: BitVec::new(),
+            edge_moves: vec![],
+            safepoint_locations: vec![],
+            safepoint_slots: vec![],
+            safepoint_derived_slots: vec![],
+            preserved_soft_clobbers: vec![],
+            debug_locations: vec![],
+            block_edits: vec![],
+            used_regs: vec![],
+            edit_clobbered_regs: vec![],
+            decision: None,
+            requeue_limit_hit: false,
+            iteration_limit_exceeded: false,
+            explanations: vec![],
+            // Scramble the seed; xorshift must not start from zero.
+            chaos: opts
+                .chaos_seed
+                .map(|seed| seed.wrapping_mul(0x9e3779b97f4a7c15) | 1),
+            scratch_save_slots: [None; 2],
 
             stats: Stats::default(),
 
@@ -594,32 +950,212 @@ impl<'a, F: Function> Env<'a, F> {
     }
 
     fn create_pregs_and_vregs(&mut self) {
-        // Create RRegs from the RealRegUniverse.
-        for &preg in &self.env.regs {
+        // Create PRegs over the whole index space, so that any PReg
+        // (including fixed-reg constraints and clobbers outside of the
+        // allocatable set) can be looked up directly by its index.
+        for index in 0..PReg::NUM_INDEX {
             self.pregs.push(PRegData {
-                reg: preg,
+                reg: PReg::from_index(index),
                 allocations: LiveRangeSet::new(),
             });
         }
+        // Take the registers' hardware encodings from the machine
+        // environment, so that they are carried into allocations.
+        for &preg in self.env.regs.iter().chain(self.env.scratch_by_class.iter()) {
+            self.pregs[preg.index()].reg = preg;
+        }
+        // Order each class's registers for probing, cheapest first,
+        // and then most preferred first.
+        for regs in &self.env.regs_by_class {
+            let mut orders: [Vec<PReg>; 2] = Default::default();
+            let mut all_runs: [Vec<(usize, usize)>; 2] = Default::default();
+            for (across_call, (order, runs)) in orders.iter_mut().zip(&mut all_runs).enumerate() {
+                let key = |preg: PReg| {
+                    (
+                        self.env.reg_cost(preg),
+                        self.probe_tier(preg, across_call == 1),
+                    )
+                };
+                *order = regs.clone();
+                order.sort_by_key(|&preg| key(preg));
+                let mut run_start = 0;
+                for i in 1..=order.len() {
+                    if i == order.len() || key(order[i]) != key(order[run_start]) {
+                        runs.push((run_start, i - run_start));
+                        run_start = i;
+                    }
+                }
+            }
+            self.probe_regs_by_class.push(orders);
+            self.probe_runs_by_class.push(all_runs);
+        }
+        self.calls = (0..self.func.insts())
+            .map(Inst::new)
+            .filter(|&inst| self.func.is_call(inst))
+            .collect();
         // Create VRegs from the vreg count.
         for idx in 0..self.func.num_vregs() {
             // We'll fill in the real details when we see the def.
             let reg = VReg::new(idx, RegClass::Int);
             self.add_vreg(VRegData {
                 reg,
-                def: DefIndex::invalid(),
                 first_range: LiveRangeIndex::invalid(),
                 blockparam: Block::invalid(),
+                is_constant: false,
+                remat: None,
+                pinned: None,
+                spillslot: SpillSlotIndex::invalid(),
+            });
+        }
+        let func = self.func;
+        let vregs = &mut self.vregs;
+        // Note which vregs are constants up front: liveness sees
+        // uses before defs.
+        for inst in 0..func.insts() {
+            func.visit_operands(Inst::new(inst), &mut |_, op| {
+                if op.policy() == OperandPolicy::Constant {
+                    debug_assert_eq!(op.kind(), OperandKind::Def);
+                    vregs[op.vreg().vreg()].is_constant = true;
+                }
+            });
+        }
+        // Likewise the vregs the function can recompute, other than
+        // constants. Each must be defined by an instruction, as a
+        // value of its own.
+        for inst in 0..func.insts() {
+            func.visit_operands(Inst::new(inst), &mut |_, op| {
+                let vreg = op.vreg();
+                if op.kind() == OperandKind::Def
+                    && op.policy() != OperandPolicy::Constant
+                    && !op.is_conditional_def()
+                    && !func.is_reftype(vreg)
+                {
+                    vregs[vreg.vreg()].remat = func.is_rematerializable(vreg);
+                }
+            });
+        }
+        // A constant or rematerializable value read from a spillslot
+        // must be kept in one.
+        for inst in 0..func.insts() {
+            func.visit_operands(Inst::new(inst), &mut |_, op| {
+                if op.policy() == OperandPolicy::Stack {
+                    vregs[op.vreg().vreg()].is_constant = false;
+                    vregs[op.vreg().vreg()].remat = None;
+                }
             });
         }
+        // Nor can a vreg with more than one value, in a non-SSA
+        // function, be recomputed.
+        if !func.is_ssa() {
+            let mut defined = BitVec::with_capacity(func.num_vregs());
+            for inst in 0..func.insts() {
+                func.visit_operands(Inst::new(inst), &mut |_, op| {
+                    let vreg = op.vreg().vreg();
+                    if op.kind() == OperandKind::Mod
+                        || (op.kind() == OperandKind::Def && defined.get(vreg))
+                    {
+                        vregs[vreg].is_constant = false;
+                        vregs[vreg].remat = None;
+                    }
+                    if op.kind() == OperandKind::Def {
+                        defined.set(vreg, true);
+                    }
+                });
+            }
+        }
+        // The pinned vregs, which are never spilled, so never
+        // recomputed either.
+        let mut pin = |vreg: VReg| {
+            let pinned = func.pinned_vreg(vreg);
+            if pinned.is_some() {
+                vregs[vreg.vreg()].pinned = pinned;
+                vregs[vreg.vreg()].remat = None;
+            }
+        };
+        for block in 0..func.blocks() {
+            let block = Block::new(block);
+            for &vreg in func.block_params(block) {
+                pin(vreg);
+            }
+            for inst in func.block_insns(block).iter() {
+                func.visit_operands(inst, &mut |_, op| pin(op.vreg()));
+            }
+        }
         // Create allocations too.
         for inst in 0..self.func.insts() {
             let start = self.allocs.len() as u32;
             self.inst_alloc_offsets.push(start);
-            for _ in 0..self.func.inst_operands(Inst::new(inst)).len() {
-                self.allocs.push(Allocation::none());
+            let allocs = &mut self.allocs;
+            self.func
+                .visit_operands(Inst::new(inst), &mut |_, _| allocs.push(Allocation::none()));
+        }
+    }
+
+    /// Where `preg` comes among registers of equal cost, for a
+    /// bundle that lives across a call or not: those the environment
+    /// prefers for such a bundle, then those it does not list, then
+    /// those it prefers for the other kind.
+    fn probe_tier(&self, preg: PReg, across_call: bool) -> u8 {
+        let class = preg.class() as u8 as usize;
+        let listed =
+            |by_class: &[Vec<PReg>]| by_class.get(class).is_some_and(|regs| regs.contains(&preg));
+        let (first, last) = if across_call {
+            (
+                &self.env.non_preferred_regs_by_class,
+                &self.env.preferred_regs_by_class,
+            )
+        } else {
+            (
+                &self.env.preferred_regs_by_class,
+                &self.env.non_preferred_regs_by_class,
+            )
+        };
+        if listed(first) {
+            0
+        } else if listed(last) {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// The `i`th register of `class` to probe for a bundle whose
+    /// traversal starts at `start`, and which lives across a call or
+    /// not. Cheaper registers come first, then preferred ones; each
+    /// run of equally cheap and preferred ones is visited from an
+    /// offset of `start`, spreading pressure evenly among them.
+    fn probe_reg(&self, class: RegClass, across_call: bool, i: usize, start: usize) -> PReg {
+        let class = class as u8 as usize;
+        let order = across_call as usize;
+        let &(run_start, run_len) = self.probe_runs_by_class[class][order]
+            .iter()
+            .find(|&&(run_start, run_len)| i < run_start + run_len)
+            .unwrap();
+        self.probe_regs_by_class[class][order][run_start + (i - run_start + start) % run_len]
+    }
+
+    /// Does `bundle` live across a call, i.e. both before it and
+    /// after it?
+    fn bundle_crosses_call(&self, bundle: LiveBundleIndex) -> bool {
+        if self.calls.is_empty() {
+            return false;
+        }
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            let range = self.ranges[iter.index()].range;
+            // The first call the range is live before is the one it
+            // is most likely to be live after.
+            let i = self
+                .calls
+                .partition_point(|&call| ProgPoint::before(call) < range.from);
+            if let Some(&call) = self.calls.get(i) {
+                if ProgPoint::before(call.next()) < range.to {
+                    return true;
+                }
             }
+            iter = self.ranges[iter.index()].next_in_bundle;
         }
+        false
     }
 
     fn add_vreg(&mut self, data: VRegData) -> VRegIndex {
@@ -636,8 +1172,7 @@ impl<'a, F: Function> Env<'a, F> {
             bundle: LiveBundleIndex::invalid(),
             uses_spill_weight: 0,
             num_fixed_uses_and_flags: 0,
-            first_use: UseIndex::invalid(),
-            last_use: UseIndex::invalid(),
+            uses: smallvec![],
             def: DefIndex::invalid(),
             next_in_bundle: LiveRangeIndex::invalid(),
             next_in_reg: LiveRangeIndex::invalid(),
@@ -717,9 +1252,12 @@ impl<'a, F: Function> Env<'a, F> {
                 self.ranges[merged.index()].range.to = self.ranges[iter.index()].range.to;
             }
             if self.ranges[iter.index()].def.is_valid() {
+                // Liveness is exact, so two defs of a non-SSA vreg
+                // never share a range.
+                debug_assert!(self.ranges[merged.index()].def.is_invalid());
                 self.ranges[merged.index()].def = self.ranges[iter.index()].def;
             }
-            self.distribute_liverange_uses(vreg, iter, merged);
+            self.distribute_liverange_uses(iter, merged);
             log::debug!(
                 "    -> after: merged {:?}: {:?}",
                 merged,
@@ -757,12 +1295,7 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
-    fn distribute_liverange_uses(
-        &mut self,
-        vreg: VRegIndex,
-        from: LiveRangeIndex,
-        into: LiveRangeIndex,
-    ) {
+    fn distribute_liverange_uses(&mut self, from: LiveRangeIndex, into: LiveRangeIndex) {
         log::debug!("distribute from {:?} to {:?}", from, into);
         assert_eq!(
             self.ranges[from.index()].vreg,
@@ -770,46 +1303,23 @@ impl<'a, F: Function> Env<'a, F> {
         );
         let from_range = self.ranges[from.index()].range;
         let into_range = self.ranges[into.index()].range;
-        // For every use in `from`...
-        let mut prev = UseIndex::invalid();
-        let mut iter = self.ranges[from.index()].first_use;
-        while iter.is_valid() {
-            let usedata = &mut self.uses[iter.index()];
-            // If we have already passed `into`, we're done.
-            if usedata.pos >= into_range.to {
-                break;
-            }
-            // If this use is within the range of `into`, move it over.
-            if into_range.contains_point(usedata.pos) {
-                log::debug!(" -> moving {:?}", iter);
-                let next = usedata.next_use;
-                if prev.is_valid() {
-                    self.uses[prev.index()].next_use = next;
-                } else {
-                    self.ranges[from.index()].first_use = next;
-                }
-                if iter == self.ranges[from.index()].last_use {
-                    self.ranges[from.index()].last_use = prev;
-                }
-                // `prev` remains the same.
-                self.update_liverange_stats_on_remove_use(from, iter);
-                // This may look inefficient but because we are always merging
-                // non-overlapping LiveRanges, all uses will be at the beginning
-                // or end of the existing use-list; both cases are optimized.
-                self.insert_use_into_liverange_and_update_stats(into, iter);
-                iter = next;
-            } else {
-                prev = iter;
-                iter = usedata.next_use;
-            }
+        // The uses in `from` that lie within `into` form a contiguous
+        // run of the sorted list: move them over.
+        let uses = &self.uses;
+        let from_uses = &self.ranges[from.index()].uses;
+        let start = from_uses.partition_point(|u| uses[u.index()].pos < into_range.from);
+        let end = from_uses.partition_point(|u| uses[u.index()].pos < into_range.to);
+        let moved: UseList = self.ranges[from.index()].uses.drain(start..end).collect();
+        for u in moved {
+            log::debug!(" -> moving {:?}", u);
+            self.update_liverange_stats_on_remove_use(from, u);
+            self.insert_use_into_liverange_and_update_stats(into, u);
         }
 
         // Distribute def too if `from` has a def and the def is in range of `into_range`.
-        if self.ranges[from.index()].def.is_valid() {
-            let def_idx = self.vregs[vreg.index()].def;
-            if from_range.contains_point(self.defs[def_idx.index()].pos) {
-                self.ranges[into.index()].def = def_idx;
-            }
+        let def_idx = self.ranges[from.index()].def;
+        if def_idx.is_valid() && from_range.contains_point(self.defs[def_idx.index()].pos) {
+            self.ranges[into.index()].def = def_idx;
         }
     }
 
@@ -832,39 +1342,13 @@ impl<'a, F: Function> Env<'a, F> {
     }
 
     fn insert_use_into_liverange_and_update_stats(&mut self, into: LiveRangeIndex, u: UseIndex) {
+        // Insert after any uses at the same position. Uses usually
+        // arrive in order, so this is most often an append.
         let insert_pos = self.uses[u.index()].pos;
-        let first = self.ranges[into.index()].first_use;
-        self.uses[u.index()].next_use = UseIndex::invalid();
-        if first.is_invalid() {
-            // Empty list.
-            self.ranges[into.index()].first_use = u;
-            self.ranges[into.index()].last_use = u;
-        } else if insert_pos > self.uses[self.ranges[into.index()].last_use.index()].pos {
-            // After tail.
-            let tail = self.ranges[into.index()].last_use;
-            self.uses[tail.index()].next_use = u;
-            self.ranges[into.index()].last_use = u;
-        } else {
-            // Otherwise, scan linearly to find insertion position.
-            let mut prev = UseIndex::invalid();
-            let mut iter = first;
-            while iter.is_valid() {
-                if self.uses[iter.index()].pos > insert_pos {
-                    break;
-                }
-                prev = iter;
-                iter = self.uses[iter.index()].next_use;
-            }
-            self.uses[u.index()].next_use = iter;
-            if prev.is_valid() {
-                self.uses[prev.index()].next_use = u;
-            } else {
-                self.ranges[into.index()].first_use = u;
-            }
-            if iter.is_invalid() {
-                self.ranges[into.index()].last_use = u;
-            }
-        }
+        let uses = &self.uses;
+        let list = &mut self.ranges[into.index()].uses;
+        let idx = list.partition_point(|u| uses[u.index()].pos <= insert_pos);
+        list.insert(idx, u);
 
         // Update stats.
         let policy = self.uses[u.index()].operand.policy();
@@ -899,17 +1383,217 @@ impl<'a, F: Function> Env<'a, F> {
     fn add_liverange_to_preg(&mut self, range: CodeRange, reg: PReg) {
         let preg_idx = PRegIndex::new(reg.index());
         let lr = self.create_liverange(range);
-        self.pregs[preg_idx.index()]
-            .allocations
-            .btree
-            .insert(LiveRangeKey::from_range(&range), lr);
+        self.pregs[preg_idx.index()].allocations.insert(range, lr);
+    }
+
+    /// Is `vreg` a constant or rematerializable value, recomputed
+    /// rather than reloaded wherever it is spilled? Its spilled
+    /// ranges have no location at all (see `get_alloc_for_range`).
+    fn is_remat(&self, vreg: usize) -> bool {
+        self.vregs[vreg].is_constant || self.vregs[vreg].remat.is_some()
+    }
+
+    /// Is `op` a branch arg read only at the control transfer, that
+    /// needs no `Use`? A spilled constant has no location at all, so
+    /// a constant's use is kept, to give the operand one; likewise a
+    /// rematerializable value's.
+    fn is_transfer_use(&self, op: Operand) -> bool {
+        op.pos() == OperandPos::Transfer && !self.is_remat(op.vreg().vreg())
+    }
+
+    /// Reserve `reg` over `range` within one instruction, unless it
+    /// is already reserved there: an instruction may clobber a
+    /// register twice, or also pass a grouped fixed use in it. The
+    /// clobbers of an instruction cover any other reservation in it,
+    /// and are made first.
+    fn reserve_preg_in_inst(&mut self, range: CodeRange, reg: PReg) {
+        let key = LiveRangeKey::from_range(&range);
+        if !self.pregs[reg.index()].allocations.btree.contains_key(&key) {
+            self.add_liverange_to_preg(range, reg);
+        }
+    }
+
+    /// Check the derived references (see `Function::derived_ref_base`),
+    /// and find each safepoint that a derived reference is live
+    /// across but its base is not, i.e. where liveness must keep the
+    /// base alive. The base is then used from its spillslot at the
+    /// safepoint's `AfterLate` point, so that the slot is not given to
+    /// another value, even one the safepoint defines late, before the
+    /// collector has seen it.
+    fn compute_derived_keepalives(&mut self) -> Result<(), RegAllocError> {
+        let safepoints: Vec<Inst> = (0..self.func.insts())
+            .map(Inst::new)
+            .filter(|&inst| self.func.is_safepoint(inst))
+            .collect();
+        if safepoints.is_empty() {
+            return Ok(());
+        }
+        let mut defs: Vec<VReg> = vec![];
+        let mut constants = BitVec::new();
+        for block in 0..self.func.blocks() {
+            let block = Block::new(block);
+            defs.extend_from_slice(self.func.block_params(block));
+            for inst in self.func.block_insns(block).iter() {
+                self.func.visit_operands(inst, &mut |_, op| {
+                    if op.kind() != OperandKind::Use {
+                        defs.push(op.vreg());
+                        if op.policy() == OperandPolicy::Constant {
+                            constants.set(op.vreg().vreg(), true);
+                        }
+                    }
+                });
+            }
+        }
+        let mut derived: Vec<(VReg, VReg)> = vec![];
+        for vreg in defs {
+            if let Some(base) = self.func.derived_ref_base(vreg) {
+                if !self.func.is_reftype(vreg)
+                    || !self.func.is_reftype(base)
+                    || self.func.derived_ref_base(base).is_some()
+                    || constants.get(base.vreg())
+                {
+                    return Err(RegAllocError::DerivedRef(vreg));
+                }
+                // A constant is never tracked; see
+                // `add_safepoint_uses()`.
+                if !constants.get(vreg.vreg()) {
+                    derived.push((vreg, base));
+                }
+            }
+        }
+        if derived.is_empty() {
+            return Ok(());
+        }
+        derived.sort_unstable_by_key(|&(vreg, _)| vreg.vreg());
+        derived.dedup();
+
+        let func = self.func;
+        let liveness = LivenessInfo::new(func, &self.cfginfo)?;
+        for inst in safepoints {
+            // Live after the safepoint: into the next inst, or out of
+            // the block if it ends with a branch.
+            let block = self.cfginfo.insn_block[inst.index()];
+            let live_after = |vreg: VReg| {
+                if inst == func.block_insns(block).last() {
+                    func.is_branch(inst) && liveness.is_live_out(block, vreg)
+                } else {
+                    liveness.is_live_at(vreg, ProgPoint::before(inst.next()))
+                }
+            };
+            let mut inst_defs: SmallVec<[VReg; 4]> = smallvec![];
+            func.visit_operands(inst, &mut |_, op| {
+                if op.kind() != OperandKind::Use {
+                    inst_defs.push(op.vreg());
+                }
+            });
+            let defined = |vreg: VReg| inst_defs.contains(&vreg);
+            let start = self.derived_keepalives.len();
+            for &(vreg, base) in &derived {
+                if liveness.is_live_at(vreg, ProgPoint::before(inst))
+                    && live_after(vreg)
+                    && !defined(vreg)
+                    && !defined(base)
+                    && !live_after(base)
+                {
+                    self.derived_keepalives.push((inst, base));
+                }
+            }
+            self.derived_keepalives[start..].sort_unstable_by_key(|&(_, base)| base.vreg());
+            self.derived_keepalives.dedup();
+        }
+        Ok(())
     }
 
-    fn compute_liveness(&mut self) {
-        // Create initial LiveIn bitsets.
-        for _ in 0..self.func.blocks() {
-            self.liveins.push(BitVec::new());
+    /// The bases of derived references kept alive at `inst`; see
+    /// `compute_derived_keepalives()`.
+    fn derived_keepalives_at(&self, inst: Inst) -> &[(Inst, VReg)] {
+        let start = self.derived_keepalives.partition_point(|&(i, _)| i < inst);
+        let end = self.derived_keepalives.partition_point(|&(i, _)| i <= inst);
+        &self.derived_keepalives[start..end]
+    }
+
+    /// Compute the vregs live into each block, other than its
+    /// blockparams, iterating backward over the blocks to a
+    /// fixpoint. The sets are exact, so in a non-SSA function (see
+    /// `Function::is_ssa`) a vreg redefined in a loop is not live
+    /// across its redefinition.
+    fn compute_liveins(&mut self) {
+        let num_vregs = self.func.num_vregs();
+        self.liveins = vec![BitVec::new(); self.func.blocks()];
+
+        // A block's own effect on the live set is the same on every
+        // visit: it reads some vregs before any redefinition there
+        // (`gen`) and defines others (`kill`). Walk its insts once for
+        // those, so that each visit is `gen | (liveout & !kill)`, a
+        // word at a time.
+        let mut gen = Vec::with_capacity(self.func.blocks());
+        let mut kill = Vec::with_capacity(self.func.blocks());
+        for block in 0..self.func.blocks() {
+            let block = Block::new(block);
+            let mut block_gen = BitVec::new();
+            let mut block_kill = BitVec::new();
+            for inst in self.func.block_insns(block).rev().iter() {
+                self.func.visit_operands(inst, &mut |_, op| {
+                    if op.kind() == OperandKind::Def {
+                        block_gen.set(op.vreg().vreg(), false);
+                        block_kill.set(op.vreg().vreg(), true);
+                    }
+                });
+                self.func.visit_operands(inst, &mut |_, op| {
+                    if op.kind() != OperandKind::Def {
+                        block_gen.set(op.vreg().vreg(), true);
+                    }
+                });
+                for &(_, base) in self.derived_keepalives_at(inst) {
+                    block_gen.set(base.vreg(), true);
+                }
+            }
+            for param in self.func.block_params(block) {
+                block_gen.set(param.vreg(), false);
+                block_kill.set(param.vreg(), true);
+            }
+            gen.push(block_gen);
+            kill.push(block_kill);
+        }
+
+        // Visit the blocks in postorder first, so that each sees its
+        // successors' sets but for backedges; a block is then
+        // revisited whenever a successor's set grows.
+        let mut workqueue: VecDeque<Block> = self.cfginfo.postorder.iter().cloned().collect();
+        let mut queued = BitVec::with_capacity(self.func.blocks());
+        for &block in &self.cfginfo.postorder {
+            queued.set(block.index(), true);
+        }
+        while let Some(block) = workqueue.pop_front() {
+            queued.set(block.index(), false);
+            let mut live = BitVec::with_capacity(num_vregs);
+            {
+                let liveins = &self.liveins;
+                let succ_liveins: SmallVec<[&BitVec; 4]> = self
+                    .func
+                    .block_succs(block)
+                    .iter()
+                    .map(|succ| &liveins[succ.index()])
+                    .collect();
+                live.or_all(&succ_liveins[..]);
+            }
+            live.and_not(&kill[block.index()]);
+            live.or(&gen[block.index()]);
+            if !live.iter().eq(self.liveins[block.index()].iter()) {
+                self.liveins[block.index()] = live;
+                for &pred in self.func.block_preds(block) {
+                    if !queued.get(pred.index()) {
+                        queued.set(pred.index(), true);
+                        workqueue.push_back(pred);
+                    }
+                }
+            }
         }
+    }
+
+    fn compute_liveness(&mut self) -> Result<(), RegAllocError> {
+        self.compute_derived_keepalives()?;
+        self.compute_liveins();
 
         let num_vregs = self.func.num_vregs();
 
@@ -918,20 +1602,9 @@ impl<'a, F: Function> Env<'a, F> {
         // Create Uses and Defs referring to VRegs, and place the Uses
         // in LiveRanges.
         //
-        // We iterate backward, so as long as blocks are well-ordered
-        // (in RPO), we see uses before defs.
-        //
-        // Because of this, we can construct live ranges in one pass,
-        // i.e., considering each block once, propagating live
-        // registers backward across edges to a bitset at each block
-        // exit point, gen'ing at uses, kill'ing at defs, and meeting
-        // with a union.
-        let mut block_to_postorder: SmallVec<[Option<u32>; 16]> =
-            smallvec![None; self.func.blocks()];
-        for i in 0..self.cfginfo.postorder.len() {
-            let block = self.cfginfo.postorder[i];
-            block_to_postorder[block.index()] = Some(i as u32);
-        }
+        // With the liveins known, we can construct live ranges in one
+        // backward pass over each block, from the vregs live at its
+        // exit point, gen'ing at uses and kill'ing at defs.
 
         // Track current LiveRange for each vreg.
         let mut vreg_ranges: Vec<LiveRangeIndex> =
@@ -940,13 +1613,31 @@ impl<'a, F: Function> Env<'a, F> {
         for i in 0..self.cfginfo.postorder.len() {
             // (avoid borrowing `self`)
             let block = self.cfginfo.postorder[i];
-            block_to_postorder[block.index()] = Some(i as u32);
 
-            // Init live-set to union of liveins from successors
-            // (excluding backedges; those are handled below).
+            // Init live-set to union of liveins from successors, a
+            // word at a time.
             let mut live = BitVec::with_capacity(num_vregs);
-            for &succ in self.func.block_succs(block) {
-                live.or(&self.liveins[succ.index()]);
+            {
+                let liveins = &self.liveins;
+                let succ_liveins: SmallVec<[&BitVec; 4]> = self
+                    .func
+                    .block_succs(block)
+                    .iter()
+                    .map(|succ| &liveins[succ.index()])
+                    .collect();
+                live.or_all(&succ_liveins[..]);
+            }
+
+            // The args the branch passes at the control transfer are
+            // simply live out of the block: the out-edge moves find
+            // them wherever they are at its end.
+            let last = self.func.block_insns(block).last();
+            if self.func.is_branch(last) {
+                self.func.visit_operands(last, &mut |_, op| {
+                    if self.is_transfer_use(op) {
+                        live.set(op.vreg().vreg(), true);
+                    }
+                });
             }
 
             // Initially, registers are assumed live for the whole block.
@@ -974,16 +1665,22 @@ impl<'a, F: Function> Env<'a, F> {
 
             // If the last instruction is a branch (rather than
             // return), create blockparam_out entries.
+            // The args are the branch's uses; any defs among its
+            // operands are skipped.
             if self.func.is_branch(insns.last()) {
-                let operands = self.func.inst_operands(insns.last());
-                let mut i = 0;
+                let mut args: SmallVec<[VReg; 8]> = smallvec![];
+                self.func.visit_operands(insns.last(), &mut |_, op| {
+                    if op.kind() == OperandKind::Use {
+                        args.push(op.vreg());
+                    }
+                });
+                let mut args = args.into_iter();
                 for &succ in self.func.block_succs(block) {
                     for &blockparam in self.func.block_params(succ) {
-                        let from_vreg = VRegIndex::new(operands[i].vreg().vreg());
+                        let from_vreg = VRegIndex::new(args.next().unwrap().vreg());
                         let blockparam_vreg = VRegIndex::new(blockparam.vreg());
                         self.blockparam_outs
                             .push((from_vreg, block, succ, blockparam_vreg));
-                        i += 1;
                     }
                 }
             }
@@ -991,138 +1688,282 @@ impl<'a, F: Function> Env<'a, F> {
             // For each instruction, in reverse order, process
             // operands and clobbers.
             for inst in insns.rev().iter() {
-                if self.func.inst_clobbers(inst).len() > 0 {
+                let mut clobbers = self.env.inst_clobbers(self.func, inst).peekable();
+                let restored = self.callee_restored.get(inst.index());
+                if clobbers.peek().is_some() || restored {
                     self.clobbers.push(inst);
                 }
                 // Mark clobbers with CodeRanges on PRegs.
-                for i in 0..self.func.inst_clobbers(inst).len() {
-                    // don't borrow `self`
-                    let clobber = self.func.inst_clobbers(inst)[i];
-                    let range = CodeRange {
-                        from: ProgPoint::before(inst),
-                        to: ProgPoint::before(inst.next()),
-                    };
-                    self.add_liverange_to_preg(range, clobber);
+                let range = CodeRange {
+                    from: ProgPoint::before(inst),
+                    to: ProgPoint::before(inst.next()),
+                };
+                for clobber in clobbers {
+                    self.reserve_preg_in_inst(range, clobber);
+                }
+                // The callee-saved registers hold their caller's
+                // values again from their restore up to the return.
+                if restored {
+                    for i in 0..self.env.callee_saved_regs.len() {
+                        self.reserve_preg_in_inst(range, self.env.callee_saved_regs[i]);
+                    }
+                }
+                for &preg in self.func.inst_soft_clobbers(inst) {
+                    self.soft_clobbers.push((inst, preg));
                 }
 
                 // Does the instruction have any input-reusing
                 // outputs? This is important below to establish
                 // proper interference wrt other inputs.
+                let func = self.func;
                 let mut reused_input = None;
-                for op in self.func.inst_operands(inst) {
+                func.visit_operands(inst, &mut |_, op| {
                     if let OperandPolicy::Reuse(i) = op.policy() {
-                        reused_input = Some(i);
-                        break;
+                        reused_input.get_or_insert(i);
                     }
-                }
-
-                // Process defs and uses.
-                for i in 0..self.func.inst_operands(inst).len() {
-                    // don't borrow `self`
-                    let operand = self.func.inst_operands(inst)[i];
-                    match operand.kind() {
-                        OperandKind::Def => {
-                            // Create the Def object.
-                            let pos = match operand.pos() {
-                                OperandPos::Before | OperandPos::Both => ProgPoint::before(inst),
-                                OperandPos::After => ProgPoint::after(inst),
-                            };
-                            let def = DefIndex(self.defs.len() as u32);
-                            self.defs.push(Def {
-                                operand,
-                                pos,
-                                slot: i,
-                            });
+                });
 
-                            log::debug!("Def of {} at {:?}", operand.vreg(), pos);
-
-                            // Fill in vreg's actual data.
-                            debug_assert!(self.vregs[operand.vreg().vreg()].def.is_invalid());
-                            self.vregs[operand.vreg().vreg()].reg = operand.vreg();
-                            self.vregs[operand.vreg().vreg()].def = def;
-
-                            // Trim the range for this vreg to start
-                            // at `pos` if it previously ended at the
-                            // start of this block (i.e. was not
-                            // merged into some larger LiveRange due
-                            // to out-of-order blocks).
-                            let mut lr = vreg_ranges[operand.vreg().vreg()];
-                            log::debug!(" -> has existing LR {:?}", lr);
-                            // If there was no liverange (dead def), create a trivial one.
-                            if lr.is_invalid() {
-                                lr = self.add_liverange_to_vreg(
-                                    VRegIndex::new(operand.vreg().vreg()),
-                                    CodeRange {
-                                        from: pos,
-                                        to: pos.next(),
-                                    },
-                                    &mut num_ranges,
-                                );
-                                log::debug!(" -> invalid; created {:?}", lr);
-                            }
-                            if self.ranges[lr.index()].range.from
-                                == self.cfginfo.block_entry[block.index()]
-                            {
-                                log::debug!(" -> started at block start; trimming to {:?}", pos);
-                                self.ranges[lr.index()].range.from = pos;
-                            }
-                            // Note that the liverange contains a def.
-                            self.ranges[lr.index()].def = def;
-                            // Remove from live-set.
-                            live.set(operand.vreg().vreg(), false);
-                            vreg_ranges[operand.vreg().vreg()] = LiveRangeIndex::invalid();
+                // Process defs and uses, defs first: a vreg
+                // redefined here in a non-SSA function is also live
+                // into the instruction, in a range of its own.
+                for &defs in &[true, false] {
+                    func.visit_operands(inst, &mut |i, operand| {
+                        if (operand.kind() == OperandKind::Def) != defs {
+                            return;
                         }
-                        OperandKind::Use => {
-                            // Establish where the use occurs.
-                            let mut pos = match operand.pos() {
-                                OperandPos::Before => ProgPoint::before(inst),
-                                OperandPos::Both | OperandPos::After => ProgPoint::after(inst),
-                            };
-                            // If there are any reused inputs in this
-                            // instruction, and this is *not* the
-                            // reused input, force `pos` to
-                            // `After`. (See note below for why; it's
-                            // very subtle!)
-                            if reused_input.is_some() && reused_input.unwrap() != i {
-                                pos = ProgPoint::after(inst);
+                        match operand.kind() {
+                            OperandKind::Def => {
+                                let operand = self.fixed_stack_operand(inst, i, operand);
+                                // A rematerializable value has no storage
+                                // when it is not in a register, so it
+                                // must be defined into one.
+                                let operand = if self.vregs[operand.vreg().vreg()].remat.is_some()
+                                    && operand.policy() == OperandPolicy::Any
+                                {
+                                    Operand::new(
+                                        operand.vreg(),
+                                        OperandPolicy::Reg,
+                                        operand.kind(),
+                                        operand.pos(),
+                                    )
+                                } else {
+                                    operand
+                                };
+                                // Create the Def object.
+                                let pos = operand.pos().at(inst);
+                                let def = DefIndex(self.defs.len() as u32);
+                                self.defs.push(Def {
+                                    operand,
+                                    pos,
+                                    slot: i,
+                                });
+
+                                log::debug!("Def of {} at {:?}", operand.vreg(), pos);
+
+                                // Fill in vreg's actual data.
+                                self.vregs[operand.vreg().vreg()].reg = operand.vreg();
+
+                                // Trim the range for this vreg to start
+                                // at `pos` if it previously ended at the
+                                // start of this block (i.e. was not
+                                // merged into some larger LiveRange due
+                                // to out-of-order blocks).
+                                let mut lr = vreg_ranges[operand.vreg().vreg()];
+                                log::debug!(" -> has existing LR {:?}", lr);
+                                // If there was no liverange (dead def), create a trivial one.
+                                if lr.is_invalid() {
+                                    lr = self.add_liverange_to_vreg(
+                                        VRegIndex::new(operand.vreg().vreg()),
+                                        CodeRange {
+                                            from: pos,
+                                            to: pos.next(),
+                                        },
+                                        &mut num_ranges,
+                                    );
+                                    log::debug!(" -> invalid; created {:?}", lr);
+                                }
+                                if self.ranges[lr.index()].range.from
+                                    == self.cfginfo.block_entry[block.index()]
+                                {
+                                    log::debug!(
+                                        " -> started at block start; trimming to {:?}",
+                                        pos
+                                    );
+                                    self.ranges[lr.index()].range.from = pos;
+                                }
+                                // Note that the liverange contains a def.
+                                self.ranges[lr.index()].def = def;
+                                // Remove from live-set.
+                                live.set(operand.vreg().vreg(), false);
+                                vreg_ranges[operand.vreg().vreg()] = LiveRangeIndex::invalid();
                             }
-                            // If this is a branch, extend `pos` to
-                            // the end of the block. (Branch uses are
-                            // blockparams and need to be live at the
-                            // end of the block.
-                            if self.func.is_branch(inst) {
-                                pos = self.cfginfo.block_exit[block.index()];
+                            OperandKind::Use if self.is_transfer_use(operand) => {
+                                // Already live out, above; the branch
+                                // does not read it.
                             }
+                            // A `Mod` is a use that keeps the vreg live
+                            // through `After`, where it is written back.
+                            OperandKind::Use | OperandKind::Mod => {
+                                // A constant's value has no storage when
+                                // it is not in a register, so it must be
+                                // used from a register; likewise a
+                                // rematerializable value's.
+                                let operand = if self.is_remat(operand.vreg().vreg())
+                                    && operand.policy() == OperandPolicy::Any
+                                {
+                                    Operand::new(
+                                        operand.vreg(),
+                                        OperandPolicy::Reg,
+                                        operand.kind(),
+                                        operand.pos(),
+                                    )
+                                } else {
+                                    operand
+                                };
+
+                                // Establish where the use occurs.
+                                let mut pos = operand.pos().at(inst);
+                                // If there are any reused inputs in this
+                                // instruction, and this is *not* the
+                                // reused input, read it no earlier than
+                                // the reusing def is written, at
+                                // `After`. (See note below for why; it's
+                                // very subtle!)
+                                if reused_input.is_some()
+                                    && reused_input.unwrap() != i
+                                    && operand.kind() == OperandKind::Use
+                                {
+                                    pos = std::cmp::max(pos, ProgPoint::after(inst));
+                                }
 
-                            // Create the actual use object.
-                            let u = UseIndex(self.uses.len() as u32);
-                            self.uses.push(Use {
-                                operand,
-                                pos,
-                                slot: i,
-                                next_use: UseIndex::invalid(),
-                            });
-
-                            // Create/extend the LiveRange and add the use to the range.
-                            let range = CodeRange {
-                                from: self.cfginfo.block_entry[block.index()],
-                                to: pos.next(),
-                            };
-                            let lr = self.add_liverange_to_vreg(
-                                VRegIndex::new(operand.vreg().vreg()),
-                                range,
-                                &mut num_ranges,
-                            );
-                            vreg_ranges[operand.vreg().vreg()] = lr;
+                                // A grouped fixed-reg use lives anywhere
+                                // up to the instruction; the register is
+                                // only reserved while it is read. A
+                                // reference read by a safepoint is
+                                // grouped, so that it can stay in its
+                                // spillslot across the safepoint.
+                                let operand = match operand.policy() {
+                                    OperandPolicy::FixedReg(preg)
+                                        if pos < ProgPoint::after(inst)
+                                            && operand.kind() == OperandKind::Use
+                                            && (self.func.groups_fixed_uses(inst)
+                                                || (self.func.is_safepoint(inst)
+                                                    && self.func.is_reftype(operand.vreg())))
+                                            && !self.func.is_branch(inst) =>
+                                    {
+                                        self.grouped_fixed_uses.push((inst, i, preg));
+                                        self.stats.grouped_fixed_uses += 1;
+                                        let range = CodeRange {
+                                            from: ProgPoint::before(inst),
+                                            to: ProgPoint::after(inst),
+                                        };
+                                        self.reserve_preg_in_inst(range, preg);
+                                        let policy = if self.is_remat(operand.vreg().vreg()) {
+                                            OperandPolicy::Reg
+                                        } else {
+                                            OperandPolicy::Any
+                                        };
+                                        Operand::new(
+                                            operand.vreg(),
+                                            policy,
+                                            operand.kind(),
+                                            operand.pos(),
+                                        )
+                                    }
+                                    OperandPolicy::FixedStack(_) => {
+                                        self.fixed_stack_operand(inst, i, operand)
+                                    }
+                                    _ => operand,
+                                };
+                                // If this is a branch, extend `pos` to
+                                // the end of the block. (Branch uses are
+                                // blockparams and need to be live at the
+                                // end of the block. Its defs keep their
+                                // own positions, so they interfere with
+                                // these uses.)
+                                if self.func.is_branch(inst) {
+                                    pos = self.cfginfo.block_exit[block.index()];
+                                }
 
-                            log::debug!("Use of {:?} at {:?} -> {:?} -> {:?}", operand, pos, u, lr);
+                                // Create the actual use object.
+                                let u = UseIndex(self.uses.len() as u32);
+                                self.uses.push(Use {
+                                    operand,
+                                    pos,
+                                    slot: i,
+                                });
+
+                                // Create/extend the LiveRange and add the use to the range.
+                                let end = if operand.kind() == OperandKind::Mod {
+                                    std::cmp::max(pos, ProgPoint::after(inst))
+                                } else {
+                                    pos
+                                };
+                                let range = CodeRange {
+                                    from: self.cfginfo.block_entry[block.index()],
+                                    to: end.next(),
+                                };
+                                let lr = self.add_liverange_to_vreg(
+                                    VRegIndex::new(operand.vreg().vreg()),
+                                    range,
+                                    &mut num_ranges,
+                                );
+                                vreg_ranges[operand.vreg().vreg()] = lr;
+
+                                log::debug!(
+                                    "Use of {:?} at {:?} -> {:?} -> {:?}",
+                                    operand,
+                                    pos,
+                                    u,
+                                    lr
+                                );
 
-                            self.insert_use_into_liverange_and_update_stats(lr, u);
+                                self.insert_use_into_liverange_and_update_stats(lr, u);
 
-                            // Add to live-set.
-                            live.set(operand.vreg().vreg(), true);
+                                // Add to live-set.
+                                live.set(operand.vreg().vreg(), true);
+                            }
                         }
-                    }
+                    });
+                }
+
+                // A base kept alive for a derived reference stays in
+                // its spillslot through the safepoint; see
+                // `compute_derived_keepalives()`.
+                for k in 0..self.derived_keepalives_at(inst).len() {
+                    let base = self.derived_keepalives_at(inst)[k].1;
+                    let pos = OperandPos::AfterLate.at(inst);
+                    let u = UseIndex(self.uses.len() as u32);
+                    self.uses.push(Use {
+                        operand: Operand::new(
+                            base,
+                            OperandPolicy::Stack,
+                            OperandKind::Use,
+                            OperandPos::AfterLate,
+                        ),
+                        pos,
+                        slot: SLOT_NONE,
+                    });
+                    let range = CodeRange {
+                        from: self.cfginfo.block_entry[block.index()],
+                        to: pos.next(),
+                    };
+                    let lr = self.add_liverange_to_vreg(
+                        VRegIndex::new(base.vreg()),
+                        range,
+                        &mut num_ranges,
+                    );
+                    vreg_ranges[base.vreg()] = lr;
+                    log::debug!(
+                        "keepalive of {:?} at {:?} -> {:?} -> {:?}",
+                        base,
+                        pos,
+                        u,
+                        lr
+                    );
+                    self.insert_use_into_liverange_and_update_stats(lr, u);
+                    self.stats.safepoint_uses += 1;
+                    live.set(base.vreg(), true);
                 }
             }
 
@@ -1144,113 +1985,42 @@ impl<'a, F: Function> Env<'a, F> {
                         &mut num_ranges,
                     );
                 }
-                // add `blockparam_ins` entries.
-                let vreg_idx = VRegIndex::new(vreg.vreg());
-                for &pred in self.func.block_preds(block) {
-                    self.blockparam_ins.push((vreg_idx, block, pred));
-                }
+                self.stats.blockparam_ins_count += self.func.block_preds(block).len();
             }
 
-            // Loop-handling: to handle backedges, rather than running
-            // a fixpoint loop, we add a live-range for every value
-            // live at the beginning of the loop over the whole loop
-            // body.
-            //
-            // To determine what the "loop body" consists of, we find
-            // the transitively minimum-reachable traversal index in
-            // our traversal order before the current block
-            // index. When we discover a backedge, *all* block indices
-            // within the traversal range are considered part of the
-            // loop body.  This is guaranteed correct (though perhaps
-            // an overapproximation) even for irreducible control
-            // flow, because it will find all blocks to which the
-            // liveness could flow backward over which we've already
-            // scanned, and it should give good results for reducible
-            // control flow with properly ordered blocks.
-            let mut min_pred = i;
-            let mut loop_scan = i;
-            log::debug!(
-                "looking for loops from postorder#{} (block{})",
-                i,
-                self.cfginfo.postorder[i].index()
-            );
-            while loop_scan >= min_pred {
-                let block = self.cfginfo.postorder[loop_scan];
-                log::debug!(
-                    " -> scan at postorder#{} (block{})",
-                    loop_scan,
-                    block.index()
-                );
-                for &pred in self.func.block_preds(block) {
-                    log::debug!(
-                        " -> pred block{} (postorder#{})",
-                        pred.index(),
-                        block_to_postorder[pred.index()].unwrap_or(min_pred as u32)
-                    );
-                    min_pred = std::cmp::min(
-                        min_pred,
-                        block_to_postorder[pred.index()].unwrap_or(min_pred as u32) as usize,
-                    );
-                    log::debug!(" -> min_pred = {}", min_pred);
-                }
-                if loop_scan == 0 {
-                    break;
-                }
-                loop_scan -= 1;
-            }
+            debug_assert!(live.iter().eq(self.liveins[block.index()].iter()));
+            log::debug!("liveins at block {:?} = {:?}", block, live);
 
-            if min_pred < i {
-                // We have one or more backedges, and the loop body is
-                // (conservatively) postorder[min_pred..i]. Find a
-                // range that covers all of those blocks.
-                let loop_blocks = &self.cfginfo.postorder[min_pred..=i];
-                let loop_begin = loop_blocks
-                    .iter()
-                    .map(|b| self.cfginfo.block_entry[b.index()])
-                    .min()
-                    .unwrap();
-                let loop_end = loop_blocks
-                    .iter()
-                    .map(|b| self.cfginfo.block_exit[b.index()])
-                    .max()
-                    .unwrap();
-                let loop_range = CodeRange {
-                    from: loop_begin,
-                    to: loop_end,
-                };
-                log::debug!(
-                    "found backedge wrt postorder: postorder#{}..postorder#{}",
-                    min_pred,
-                    i
-                );
-                log::debug!(" -> loop range {:?}", loop_range);
-                for &loopblock in loop_blocks {
-                    self.liveins[loopblock.index()].or(&live);
-                }
-                for vreg in live.iter() {
-                    log::debug!(
-                        "vreg {:?} live at top of loop (block {:?}) -> range {:?}",
-                        VRegIndex::new(vreg),
-                        block,
-                        loop_range,
-                    );
-                    self.add_liverange_to_vreg(VRegIndex::new(vreg), loop_range, &mut num_ranges);
-                }
+            // The ranges live into this block end here: a def of one
+            // of these vregs in a block visited later (possible only
+            // in a non-SSA function) starts a range of its own.
+            for vreg in live.iter() {
+                vreg_ranges[vreg] = LiveRangeIndex::invalid();
             }
+        }
 
-            log::debug!("liveins at block {:?} = {:?}", block, live);
-            self.liveins[block.index()] = live;
+        // A use that no def reaches makes its vreg live into the
+        // entry block, and its ranges would have no start, so fail
+        // here rather than somewhere downstream.
+        let entry = self.func.entry_block();
+        if let Some(vreg) = self.liveins[entry.index()].iter().next() {
+            if let Some((vreg, inst)) = crate::liveness::find_use_before_def(self.func, vreg) {
+                return Err(RegAllocError::UseBeforeDef(vreg, inst));
+            }
         }
 
+        self.add_safepoint_uses()?;
+
         // Do a cleanup pass: if there are any LiveRanges with
-        // multiple uses (or defs) at the same ProgPoint and there is
-        // more than one FixedReg constraint at that ProgPoint, we
-        // need to record all but one of them in a special fixup list
-        // and handle them later; otherwise, bundle-splitting to
+        // multiple uses (or defs) at the same instruction and there
+        // is more than one FixedReg constraint at that instruction,
+        // we need to record all but one of them in a special fixup
+        // list and handle them later; otherwise, bundle-splitting to
         // create minimal bundles becomes much more complex (we would
-        // have to split the multiple uses at the same progpoint into
-        // different bundles, which breaks invariants related to
-        // disjoint ranges and bundles).
+        // have to split the multiple uses at the same instruction
+        // into different bundles, which breaks invariants related to
+        // disjoint ranges and bundles, and cannot place a move
+        // between its points anyway).
         for vreg in 0..self.vregs.len() {
             let mut iter = self.vregs[vreg].first_range;
             while iter.is_valid() {
@@ -1259,22 +2029,24 @@ impl<'a, F: Function> Env<'a, F> {
                     VRegIndex::new(vreg),
                     iter
                 );
-                let mut last_point = None;
+                let mut last_inst = None;
                 let mut seen_fixed_for_vreg: SmallVec<[VReg; 16]> = smallvec![];
                 let mut first_preg: SmallVec<[PRegIndex; 16]> = smallvec![];
                 let mut extra_clobbers: SmallVec<[(PReg, Inst); 8]> = smallvec![];
                 let mut fixup_multi_fixed_vregs = |pos: ProgPoint,
+                                                   slot: usize,
                                                    op: &mut Operand,
                                                    fixups: &mut Vec<(
                     ProgPoint,
                     PRegIndex,
                     PRegIndex,
+                    usize,
                 )>| {
-                    if last_point.is_some() && Some(pos) != last_point {
+                    if last_inst.is_some() && Some(pos.inst) != last_inst {
                         seen_fixed_for_vreg.clear();
                         first_preg.clear();
                     }
-                    last_point = Some(pos);
+                    last_inst = Some(pos.inst);
 
                     if let OperandPolicy::FixedReg(preg) = op.policy() {
                         let vreg_idx = VRegIndex::new(op.vreg().vreg());
@@ -1289,7 +2061,7 @@ impl<'a, F: Function> Env<'a, F> {
                         {
                             let orig_preg = first_preg[idx];
                             log::debug!(" -> duplicate; switching to policy Reg");
-                            fixups.push((pos, orig_preg, preg_idx));
+                            fixups.push((pos, orig_preg, preg_idx, slot));
                             *op = Operand::new(op.vreg(), OperandPolicy::Reg, op.kind(), op.pos());
                             extra_clobbers.push((preg, pos.inst));
                         } else {
@@ -1299,25 +2071,27 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                 };
 
-                if self.ranges[iter.index()].def.is_valid() {
-                    let def_idx = self.vregs[vreg].def;
+                let def_idx = self.ranges[iter.index()].def;
+                if def_idx.is_valid() {
                     let pos = self.defs[def_idx.index()].pos;
+                    let slot = self.defs[def_idx.index()].slot;
                     fixup_multi_fixed_vregs(
                         pos,
+                        slot,
                         &mut self.defs[def_idx.index()].operand,
                         &mut self.multi_fixed_reg_fixups,
                     );
                 }
 
-                let mut use_iter = self.ranges[iter.index()].first_use;
-                while use_iter.is_valid() {
-                    let pos = self.uses[use_iter.index()].pos;
+                for &use_idx in &self.ranges[iter.index()].uses {
+                    let pos = self.uses[use_idx.index()].pos;
+                    let slot = self.uses[use_idx.index()].slot;
                     fixup_multi_fixed_vregs(
                         pos,
-                        &mut self.uses[use_iter.index()].operand,
+                        slot,
+                        &mut self.uses[use_idx.index()].operand,
                         &mut self.multi_fixed_reg_fixups,
                     );
-                    use_iter = self.uses[use_iter.index()].next_use;
                 }
 
                 for (clobber, inst) in extra_clobbers {
@@ -1333,12 +2107,182 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         self.clobbers.sort();
-        self.blockparam_ins.sort();
-        self.blockparam_outs.sort();
+        self.soft_clobbers.sort_unstable_by_key(|&(inst, _)| inst);
+        // This can be large (edges x params) for big functions; sort
+        // in place rather than with a stable sort's scratch buffer.
+        self.blockparam_outs.sort_unstable();
 
         self.stats.initial_liverange_count = self.ranges.len();
-        self.stats.blockparam_ins_count = self.blockparam_ins.len();
         self.stats.blockparam_outs_count = self.blockparam_outs.len();
+
+        Ok(())
+    }
+
+    /// Note an operand with a fixed stack slot, if `operand` is one,
+    /// and return the operand to allocate in its place: any location
+    /// will do, or a register for a constant or rematerializable
+    /// value, which has no slot of its own.
+    fn fixed_stack_operand(&mut self, inst: Inst, slot: usize, operand: Operand) -> Operand {
+        let fixed = match operand.policy() {
+            OperandPolicy::FixedStack(fixed) => fixed,
+            _ => return operand,
+        };
+        self.fixed_stack_operands
+            .push((inst, slot, operand.kind(), fixed));
+        self.stats.fixed_stack_operands += 1;
+        let policy = if self.is_remat(operand.vreg().vreg()) {
+            OperandPolicy::Reg
+        } else {
+            OperandPolicy::Any
+        };
+        Operand::new(operand.vreg(), policy, operand.kind(), operand.pos())
+    }
+
+    /// Give each reference-typed vreg (see `Function::is_reftype`) a
+    /// use that requires its spillslot at each safepoint it is live
+    /// across, i.e. live both before and after it. The safepoint must
+    /// not also use the vreg in a register.
+    fn add_safepoint_uses(&mut self) -> Result<(), RegAllocError> {
+        let reftypes: Vec<VRegIndex> = (0..self.vregs.len())
+            .filter(|&vreg| {
+                // A constant is never tracked, even one kept in a
+                // spillslot for a `Stack` use.
+                let mut iter = self.vregs[vreg].first_range;
+                while iter.is_valid() {
+                    let def = self.ranges[iter.index()].def;
+                    if def.is_valid()
+                        && self.defs[def.index()].operand.policy() == OperandPolicy::Constant
+                    {
+                        return false;
+                    }
+                    iter = self.ranges[iter.index()].next_in_reg;
+                }
+                self.func.is_reftype(self.vregs[vreg].reg)
+            })
+            .map(VRegIndex::new)
+            .collect();
+        if reftypes.is_empty() {
+            return Ok(());
+        }
+        let safepoints: Vec<Inst> = (0..self.func.insts())
+            .map(Inst::new)
+            .filter(|&inst| self.func.is_safepoint(inst))
+            .collect();
+        if safepoints.is_empty() {
+            return Ok(());
+        }
+        for vreg in reftypes {
+            let reg = self.vregs[vreg.index()].reg;
+            let mut iter = self.vregs[vreg.index()].first_range;
+            while iter.is_valid() {
+                let range = self.ranges[iter.index()].range;
+                let def = self.ranges[iter.index()].def;
+                let def_inst = def.is_valid().then(|| self.defs[def.index()].pos.inst);
+                let first =
+                    safepoints.partition_point(|&inst| ProgPoint::before(inst) < range.from);
+                for &inst in safepoints[first..]
+                    .iter()
+                    .take_while(|&&inst| ProgPoint::before(inst) < range.to)
+                {
+                    if def_inst == Some(inst) {
+                        continue;
+                    }
+                    // Live after the safepoint: into the next inst, or
+                    // out of the block if it ends with a branch.
+                    let block = self.cfginfo.insn_block[inst.index()];
+                    let after = if inst == self.func.block_insns(block).last() {
+                        if !self.func.is_branch(inst) {
+                            continue;
+                        }
+                        self.cfginfo.block_exit[block.index()]
+                    } else {
+                        ProgPoint::before(inst.next())
+                    };
+                    // A base kept alive for a derived reference is
+                    // used from its slot after the safepoint instead.
+                    if !range.contains_point(after)
+                        && !self
+                            .derived_keepalives_at(inst)
+                            .iter()
+                            .any(|&(_, base)| base.vreg() == vreg.index())
+                    {
+                        continue;
+                    }
+
+                    for &use_idx in &self.ranges[iter.index()].uses {
+                        let operand = self.uses[use_idx.index()].operand;
+                        if self.uses[use_idx.index()].pos.inst == inst
+                            && !matches!(
+                                Requirement::from_operand(operand),
+                                Requirement::Any(_) | Requirement::Stack(_)
+                            )
+                        {
+                            return Err(RegAllocError::RefUseAtSafepoint(reg, inst));
+                        }
+                    }
+                    let pos = ProgPoint::before(inst);
+                    log::debug!("safepoint use of {:?} at {:?}", vreg, pos);
+                    let u = UseIndex(self.uses.len() as u32);
+                    self.uses.push(Use {
+                        operand: Operand::new(
+                            reg,
+                            OperandPolicy::Stack,
+                            OperandKind::Use,
+                            OperandPos::Before,
+                        ),
+                        pos,
+                        slot: SLOT_NONE,
+                    });
+                    self.insert_use_into_liverange_and_update_stats(iter, u);
+                    self.stats.safepoint_uses += 1;
+                }
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+        Ok(())
+    }
+
+    /// For each inst, the greatest number of vregs of each class
+    /// (indexed by class) whose live ranges overlap at any one point
+    /// in it.
+    fn inst_pressure(&self) -> Vec<[usize; 2]> {
+        // The change in the number of live vregs of each class at
+        // each point, indexed by `ProgPoint::to_index()`.
+        let points = ProgPoint::before(Inst::new(self.func.insts())).to_index() as usize;
+        let mut deltas = vec![[0isize; 2]; points + 1];
+        for vreg in &self.vregs {
+            let mut iter = vreg.first_range;
+            while iter.is_valid() {
+                let range = self.ranges[iter.index()].range;
+                let class = vreg.reg.class() as u8 as usize;
+                deltas[range.from.to_index() as usize][class] += 1;
+                deltas[std::cmp::min(range.to.to_index() as usize, points)][class] -= 1;
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+
+        let mut pressure = vec![[0; 2]; self.func.insts()];
+        let mut live = [0isize; 2];
+        for (point, delta) in deltas[..points].iter().enumerate() {
+            let inst = &mut pressure[ProgPoint::from_index(point as ProgPointIndex).inst.index()];
+            for class in 0..2 {
+                live[class] += delta[class];
+                inst[class] = std::cmp::max(inst[class], live[class] as usize);
+            }
+        }
+        pressure
+    }
+
+    /// For each block, the greatest number of vregs of each class
+    /// whose live ranges overlap at any one point in it.
+    fn register_pressure(&self) -> Vec<BlockPressure> {
+        let mut pressure = vec![BlockPressure::default(); self.func.blocks()];
+        for (inst, live) in self.inst_pressure().iter().enumerate() {
+            let block = &mut pressure[self.cfginfo.insn_block[inst].index()];
+            block.int = std::cmp::max(block.int, live[RegClass::Int as usize]);
+            block.float = std::cmp::max(block.float, live[RegClass::Float as usize]);
+        }
+        pressure
     }
 
     fn compute_hot_code(&mut self) {
@@ -1364,10 +2308,27 @@ impl<'a, F: Function> Env<'a, F> {
                 let to = self.cfginfo.block_exit[backedge.index()].next();
                 let range = CodeRange { from, to };
                 let lr = self.create_liverange(range);
-                self.hot_code
-                    .btree
-                    .insert(LiveRangeKey::from_range(&range), lr);
+                self.hot_code.insert(range, lr);
+            }
+        }
+    }
+
+    fn compute_cold_code(&mut self) {
+        let mut block = 0;
+        while block < self.func.blocks() {
+            if !self.func.is_cold_block(Block::new(block)) {
+                block += 1;
+                continue;
             }
+            let first = block;
+            while block < self.func.blocks() && self.func.is_cold_block(Block::new(block)) {
+                block += 1;
+            }
+            let from = self.cfginfo.block_entry[first];
+            let to = self.cfginfo.block_exit[block - 1].next();
+            let range = CodeRange { from, to };
+            let lr = self.create_liverange(range);
+            self.cold_code.insert(range, lr);
         }
     }
 
@@ -1380,23 +2341,20 @@ impl<'a, F: Function> Env<'a, F> {
             spillset: SpillSetIndex::invalid(),
             prio: 0,
             spill_weight_and_props: 0,
+            fixed_conflicts: smallvec![],
+            evicted_by: LiveBundleIndex::invalid(),
+            eviction_depth: 0,
         });
         LiveBundleIndex::new(bundle)
     }
 
-    fn try_merge_reused_register(&mut self, from: VRegIndex, to: VRegIndex) {
-        log::debug!("try_merge_reused_register: from {:?} to {:?}", from, to);
-        let def_idx = self.vregs[to.index()].def;
-        log::debug!(" -> def_idx = {:?}", def_idx);
-        debug_assert!(def_idx.is_valid());
-        let def = &mut self.defs[def_idx.index()];
-        let def_point = def.pos;
-        log::debug!(" -> def_point = {:?}", def_point);
-
-        // Can't merge if def happens at use-point.
-        if def_point.pos == InstPosition::Before {
-            return;
-        }
+    fn try_merge_reused_register(&mut self, from: VRegIndex, to: VRegIndex, def_point: ProgPoint) {
+        log::debug!(
+            "try_merge_reused_register: from {:?} to {:?} at {:?}",
+            from,
+            to,
+            def_point
+        );
 
         // Find the corresponding liverange for the use at the def-point.
         let use_lr_at_def = self.find_vreg_liverange_for_pos(from, def_point);
@@ -1423,6 +2381,110 @@ impl<'a, F: Function> Env<'a, F> {
         // best.
     }
 
+    /// Reserve the register of each pinned vreg (see
+    /// `Function::pinned_vreg`) over the vreg's live ranges, as for a
+    /// clobber, so that no other bundle is allocated to it there.
+    /// Fails with `RegAllocError::Pinned` if an operand of the vreg
+    /// does not accept the register, or if the register is clobbered
+    /// or fixed to another operand where the vreg is live.
+    fn reserve_pinned_vregs(&mut self) -> Result<(), RegAllocError> {
+        if self.vregs.iter().all(|vreg| vreg.pinned.is_none()) {
+            return Ok(());
+        }
+        for inst in 0..self.func.insts() {
+            let inst = Inst::new(inst);
+            let mut bad = None;
+            self.func.visit_operands(inst, &mut |_, op| {
+                let preg = match self.vregs[op.vreg().vreg()].pinned {
+                    Some(preg) => preg,
+                    None => return,
+                };
+                let ok = match op.policy() {
+                    OperandPolicy::Any | OperandPolicy::Reg | OperandPolicy::PrefReg(_) => true,
+                    OperandPolicy::FixedReg(fixed) => fixed == preg,
+                    OperandPolicy::RegExcept(excluded) => !excluded.contains(preg),
+                    _ => false,
+                };
+                if !ok || op.is_conditional_def() {
+                    bad.get_or_insert(op.vreg());
+                }
+            });
+            if let Some(vreg) = bad {
+                return Err(RegAllocError::Pinned(vreg, inst));
+            }
+        }
+
+        // The reservations are made before any bundle exists, after
+        // the clobbers, so any overlap is with a clobber, a grouped
+        // fixed use, or another pinned vreg. Each pinned register's
+        // reservations are also kept apart, by vreg, for the check
+        // below.
+        let mut pinned_ranges: BTreeMap<usize, BTreeMap<LiveRangeKey, VReg>> = BTreeMap::new();
+        for vreg in 0..self.vregs.len() {
+            let preg = match self.vregs[vreg].pinned {
+                Some(preg) => preg,
+                None => continue,
+            };
+            let reg = self.vregs[vreg].reg;
+            if self.func.is_reftype(reg) || self.env.scratch_by_class.contains(&preg) {
+                return Err(RegAllocError::Pinned(reg, Inst::invalid()));
+            }
+            let mut iter = self.vregs[vreg].first_range;
+            while iter.is_valid() {
+                let range = self.ranges[iter.index()].range;
+                let key = LiveRangeKey::from_range(&range);
+                if let Some(&other) = self.pregs[preg.index()].allocations.btree.get(&key) {
+                    let at = std::cmp::max(range.from, self.ranges[other.index()].range.from);
+                    return Err(RegAllocError::Pinned(reg, at.inst));
+                }
+                self.add_liverange_to_preg(range, preg);
+                pinned_ranges
+                    .entry(preg.index())
+                    .or_default()
+                    .insert(key, reg);
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+
+        // Operands of other vregs fixed to a pinned register, where
+        // the pinned vreg is live.
+        for inst in 0..self.func.insts() {
+            let inst = Inst::new(inst);
+            let mut bad = None;
+            self.func.visit_operands(inst, &mut |_, op| {
+                let preg = match op.policy() {
+                    OperandPolicy::FixedReg(preg) => preg,
+                    _ => return,
+                };
+                let pos = op.pos().at(inst);
+                let key = LiveRangeKey::from_range(&CodeRange {
+                    from: pos,
+                    to: pos.next(),
+                });
+                let pinned = pinned_ranges
+                    .get(&preg.index())
+                    .and_then(|ranges| ranges.get(&key));
+                if let Some(&pinned) = pinned {
+                    if pinned != op.vreg() {
+                        bad.get_or_insert(pinned);
+                    }
+                }
+            });
+            if let Some(pinned) = bad {
+                return Err(RegAllocError::Pinned(pinned, inst));
+            }
+        }
+        Ok(())
+    }
+
+    /// The register `bundle` is pinned to, if it holds a pinned vreg
+    /// (see `Function::pinned_vreg`). A pinned vreg has a bundle of
+    /// its own, which is never split or spilled.
+    fn bundle_pinned(&self, bundle: LiveBundleIndex) -> Option<PReg> {
+        let first = self.bundles[bundle.index()].first_range;
+        self.vregs[self.ranges[first.index()].vreg.index()].pinned
+    }
+
     fn merge_bundles(&mut self, from: LiveBundleIndex, to: LiveBundleIndex) -> bool {
         if from == to {
             // Merge bundle into self -- trivial merge.
@@ -1434,6 +2496,10 @@ impl<'a, F: Function> Env<'a, F> {
             to.index()
         );
 
+        if self.bundle_pinned(from).is_some() || self.bundle_pinned(to).is_some() {
+            return false;
+        }
+
         let vreg_from = self.ranges[self.bundles[from.index()].first_range.index()].vreg;
         let vreg_to = self.ranges[self.bundles[to.index()].first_range.index()].vreg;
         // Both bundles must deal with the same RegClass. All vregs in a bundle
@@ -1443,6 +2509,11 @@ impl<'a, F: Function> Env<'a, F> {
         if self.vregs[vreg_from.index()].reg.class() != self.vregs[vreg_to.index()].reg.class() {
             return false;
         }
+        // Constants and rematerializable values are never merged with
+        // other vregs: their spilled ranges have no location to share.
+        if self.is_remat(vreg_from.index()) || self.is_remat(vreg_to.index()) {
+            return false;
+        }
 
         // Check for overlap in LiveRanges.
         let mut iter0 = self.bundles[from.index()].first_range;
@@ -1571,6 +2642,142 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
+    /// The registers of each class available to `allocate_linear`,
+    /// most preferred last, if the function is small enough for it:
+    /// every operand policy must be `Any` or `Reg`, no reference may
+    /// need its spillslot at a safepoint, and no class may have more
+    /// live vregs than registers that no clobber reserves.
+    fn linear_allocation_regs(&self) -> Option<Vec<Vec<PReg>>> {
+        if self.stats.safepoint_uses > 0 {
+            return None;
+        }
+        for inst in 0..self.func.insts() {
+            let mut simple = true;
+            self.func
+                .visit_operands(Inst::new(inst), &mut |_, operand| {
+                    simple &= matches!(operand.policy(), OperandPolicy::Any | OperandPolicy::Reg);
+                });
+            if !simple {
+                return None;
+            }
+        }
+        let free: Vec<Vec<PReg>> = self
+            .env
+            .regs_by_class
+            .iter()
+            .map(|regs| {
+                let mut regs: Vec<PReg> = regs
+                    .iter()
+                    .rev()
+                    .filter(|preg| self.pregs[preg.index()].allocations.btree.is_empty())
+                    .cloned()
+                    .collect();
+                // Cheaper registers later, so they are taken first,
+                // and any soft clobber earlier still, so it is taken
+                // last.
+                regs.sort_by_key(|&preg| self.linear_allocation_key(preg));
+                regs
+            })
+            .collect();
+        let mut live = vec![0; free.len()];
+        for vreg in &self.vregs {
+            if vreg.first_range.is_valid() {
+                live[vreg.reg.class() as u8 as usize] += 1;
+            }
+        }
+        if live
+            .iter()
+            .zip(&free)
+            .any(|(&live, free)| live > free.len())
+        {
+            return None;
+        }
+        Some(free)
+    }
+
+    /// The order of `linear_allocation_regs`: registers with a
+    /// greater key are taken first.
+    fn linear_allocation_key(&self, preg: PReg) -> (bool, std::cmp::Reverse<u32>) {
+        let soft = self.soft_clobbers.iter().any(|&(_, reg)| reg == preg);
+        (!soft, std::cmp::Reverse(self.env.reg_cost(preg)))
+    }
+
+    /// Allocate a small function in one pass, with no bundle
+    /// merging, allocation queue or spillslots: each vreg gets the
+    /// register of the vreg it is copied from (by a move, or as a
+    /// blockparam) if that is free over its ranges, and otherwise
+    /// one of `free` (from `linear_allocation_regs`) of its own.
+    fn allocate_linear(&mut self, mut free: Vec<Vec<PReg>>) {
+        let mut hints = vec![VRegIndex::invalid(); self.vregs.len()];
+        for inst in 0..self.func.insts() {
+            if let Some((src, dst)) = self.func.is_move(Inst::new(inst)) {
+                hints[dst.vreg()] = VRegIndex::new(src.vreg());
+            }
+        }
+        for &(from_vreg, _, _, to_vreg) in &self.blockparam_outs {
+            if hints[to_vreg.index()].is_invalid() {
+                hints[to_vreg.index()] = from_vreg;
+            }
+        }
+
+        for (vreg, &hint) in hints.iter().enumerate() {
+            let first_range = self.vregs[vreg].first_range;
+            if first_range.is_invalid() {
+                continue;
+            }
+            let bundle = self.create_bundle();
+            let mut range = first_range;
+            while range.is_valid() {
+                self.insert_liverange_into_bundle(bundle, range);
+                range = self.ranges[range.index()].next_in_reg;
+            }
+
+            if let Some(preg) = self.vregs[vreg].pinned {
+                self.bundles[bundle.index()].allocation = Allocation::reg(preg);
+                continue;
+            }
+            let class = self.vregs[vreg].reg.class();
+            if hint.is_valid() && self.vregs[hint.index()].first_range.is_valid() {
+                let hint_range = self.vregs[hint.index()].first_range;
+                let hint_bundle = self.ranges[hint_range.index()].bundle;
+                if hint_bundle.is_valid() {
+                    if let Some(preg) = self.bundles[hint_bundle.index()].allocation.as_reg() {
+                        if preg.class() == class {
+                            let preg = PRegIndex::new(preg.index());
+                            if let AllocRegResult::Allocated(_) =
+                                self.try_to_allocate_bundle_to_reg(bundle, preg)
+                            {
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
+
+            // A register of its own, which nothing else holds yet:
+            // among the equally good ones, the one the environment
+            // prefers for the bundle.
+            let across_call = self.bundle_crosses_call(bundle);
+            let free = &mut free[class as u8 as usize];
+            let i = (0..free.len())
+                .max_by_key(|&i| {
+                    (
+                        self.linear_allocation_key(free[i]),
+                        std::cmp::Reverse(self.probe_tier(free[i], across_call)),
+                    )
+                })
+                .unwrap();
+            let preg = free.remove(i);
+            match self.try_to_allocate_bundle_to_reg(bundle, PRegIndex::new(preg.index())) {
+                AllocRegResult::Allocated(_) => {}
+                _ => unreachable!(),
+            }
+        }
+
+        self.stats.merged_bundle_count = self.bundles.len();
+        self.finish_process_bundles();
+    }
+
     fn merge_vreg_bundles(&mut self) {
         // Create a bundle for every vreg, initially.
         log::debug!("merge_vreg_bundles: creating vreg bundles");
@@ -1588,13 +2795,32 @@ impl<'a, F: Function> Env<'a, F> {
             log::debug!("vreg v{} gets bundle{}", vreg.index(), bundle.index());
         }
 
+        // Merge the equivalence classes given by the embedder first,
+        // so that they take precedence over our own merging below.
+        for vreg in 0..self.vregs.len() {
+            let other = match self.func.equivalent_vreg(self.vregs[vreg].reg) {
+                Some(other) => other.vreg(),
+                None => continue,
+            };
+            log::debug!("trying to merge equivalent v{} with v{}", vreg, other);
+            let from_range = self.vregs[vreg].first_range;
+            let to_range = self.vregs[other].first_range;
+            if from_range.is_invalid() || to_range.is_invalid() {
+                continue;
+            }
+            let from_bundle = self.ranges[from_range.index()].bundle;
+            let to_bundle = self.ranges[to_range.index()].bundle;
+            self.merge_bundles(from_bundle, to_bundle);
+        }
+
         for inst in 0..self.func.insts() {
             let inst = Inst::new(inst);
 
             // Attempt to merge Reuse-policy operand outputs with the corresponding
-            // inputs.
-            for operand_idx in 0..self.func.inst_operands(inst).len() {
-                let operand = self.func.inst_operands(inst)[operand_idx];
+            // inputs. The inputs are found on a second visit, only
+            // for an instruction that reuses one.
+            let mut reuses: SmallVec<[(usize, VReg, VReg); 2]> = smallvec![];
+            self.func.visit_operands(inst, &mut |operand_idx, operand| {
                 if let OperandPolicy::Reuse(input_idx) = operand.policy() {
                     log::debug!(
                         "trying to merge use and def at reused-op {} on inst{}",
@@ -1603,11 +2829,20 @@ impl<'a, F: Function> Env<'a, F> {
                     );
                     assert_eq!(operand.kind(), OperandKind::Def);
                     assert_eq!(operand.pos(), OperandPos::After);
-                    let input_vreg =
-                        VRegIndex::new(self.func.inst_operands(inst)[input_idx].vreg().vreg());
-                    let output_vreg = VRegIndex::new(operand.vreg().vreg());
-                    self.try_merge_reused_register(input_vreg, output_vreg);
+                    reuses.push((input_idx, VReg::invalid(), operand.vreg()));
                 }
+            });
+            if !reuses.is_empty() {
+                self.func.visit_operands(inst, &mut |i, operand| {
+                    for reuse in reuses.iter_mut().filter(|reuse| reuse.0 == i) {
+                        reuse.1 = operand.vreg();
+                    }
+                });
+            }
+            for (_, input, output) in reuses {
+                let input_vreg = VRegIndex::new(input.vreg());
+                let output_vreg = VRegIndex::new(output.vreg());
+                self.try_merge_reused_register(input_vreg, output_vreg, ProgPoint::after(inst));
             }
 
             // Attempt to merge move srcs and dests.
@@ -1659,6 +2894,7 @@ impl<'a, F: Function> Env<'a, F> {
     }
 
     fn queue_bundles(&mut self) {
+        let mut bundles: Vec<LiveBundleIndex> = vec![];
         for vreg in 0..self.vregs.len() {
             let vreg = VRegIndex::new(vreg);
             let mut lr = self.vregs[vreg.index()].first_range;
@@ -1675,13 +2911,19 @@ impl<'a, F: Function> Env<'a, F> {
                         slot: SpillSlotIndex::invalid(),
                         size,
                         class: reg.class(),
-                        reg_hint: None,
+                        reg_hint: self.preferred_reg(bundle),
+                        remat: self.is_remat(vreg.index()),
+                        requeues: 0,
                     });
                     self.bundles[bundle.index()].spillset = ssidx;
-                    let prio = self.compute_bundle_prio(bundle);
-                    self.bundles[bundle.index()].prio = prio;
-                    self.recompute_bundle_properties(bundle);
-                    self.allocation_queue.insert(bundle, prio as usize);
+                    // A pinned vreg's register is already reserved
+                    // over all of its ranges.
+                    match self.vregs[vreg.index()].pinned {
+                        Some(preg) => {
+                            self.bundles[bundle.index()].allocation = Allocation::reg(preg)
+                        }
+                        None => bundles.push(bundle),
+                    }
                 }
 
                 // Keep going even if we handled one bundle for this vreg above:
@@ -1691,91 +2933,766 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
-        self.stats.merged_bundle_count = self.allocation_queue.heap.len();
+        let boundaries = self.split_bundles_at_regions(&mut bundles);
+        self.pre_spill_bundles(&mut bundles);
+        self.precolor_fixed_operands(&mut bundles);
+
+        let mut regions = vec![vec![]; boundaries.len() + 1];
+        for &bundle in &bundles {
+            let prio = self.compute_bundle_prio(bundle);
+            self.bundles[bundle.index()].prio = prio;
+            self.recompute_bundle_properties(bundle);
+            let first = self.bundles[bundle.index()].first_range;
+            let start = self.ranges[first.index()].range.from;
+            regions[boundaries.partition_point(|&b| b <= start)].push(bundle);
+        }
+        regions.reverse();
+        self.pending_regions = regions;
+        self.queue_next_region();
+
+        self.stats.merged_bundle_count = bundles.len();
     }
 
-    fn process_bundles(&mut self) {
-        let mut count = 0;
-        while let Some(bundle) = self.allocation_queue.pop() {
-            self.stats.process_bundle_count += 1;
-            self.process_bundle(bundle);
-            count += 1;
-            if count > self.func.insts() * 50 {
-                self.dump_state();
-                panic!("Infinite loop!");
-            }
+    /// Move the bundles of the next region still to be allocated into
+    /// the allocation queue, returning `false` if there is none.
+    fn queue_next_region(&mut self) -> bool {
+        let bundles = match self.pending_regions.pop() {
+            Some(bundles) => bundles,
+            None => return false,
+        };
+        log::debug!("allocating next region: {} bundles", bundles.len());
+        for bundle in bundles {
+            let prio = self.bundles[bundle.index()].prio;
+            self.allocation_queue.insert(bundle, prio as usize);
         }
-        self.stats.final_liverange_count = self.ranges.len();
-        self.stats.final_bundle_count = self.bundles.len();
-        self.stats.spill_bundle_count = self.spilled_bundles.len();
+        true
     }
 
-    fn dump_state(&self) {
-        log::debug!("Bundles:");
-        for (i, b) in self.bundles.iter().enumerate() {
-            log::debug!(
-                "bundle{}: first_range={:?} last_range={:?} spillset={:?} alloc={:?}",
-                i,
-                b.first_range,
-                b.last_range,
-                b.spillset,
-                b.allocation
-            );
-        }
-        log::debug!("VRegs:");
-        for (i, v) in self.vregs.iter().enumerate() {
-            log::debug!("vreg{}: def={:?} first_range={:?}", i, v.def, v.first_range,);
+    /// The number of bundles waiting to be processed, in the
+    /// allocation queue or in regions not yet reached.
+    fn queued_bundle_count(&self) -> usize {
+        self.allocation_queue.len() + self.pending_regions.iter().map(Vec::len).sum::<usize>()
+    }
+
+    /// For functions larger than `RegallocOptions::region_size`,
+    /// partition the blocks (in program order) into regions of
+    /// roughly that many instructions, split every bundle at the
+    /// region boundaries, and return the boundaries. Each region's
+    /// bundles are then queued only once the region before it is
+    /// allocated, so that it is allocated on its own: its queue holds
+    /// only its bundles, and since bundles in different regions never
+    /// overlap, eviction and splitting only ever interact within it.
+    /// This bounds the cost of the backtracking loop on very large
+    /// functions. The pieces of a split bundle share a spillset, and
+    /// values crossing a boundary (always a block entry) are stitched
+    /// back together by the usual block-edge half-moves.
+    fn split_bundles_at_regions(&mut self, bundles: &mut Vec<LiveBundleIndex>) -> Vec<ProgPoint> {
+        let region_size = match self.opts.region_size {
+            Some(size) if size > 0 && self.func.insts() > size => size,
+            _ => return vec![],
+        };
+
+        let mut boundaries: Vec<ProgPoint> = vec![];
+        let mut cur_size = 0;
+        for block in 0..self.func.blocks() {
+            if cur_size >= region_size {
+                boundaries.push(self.cfginfo.block_entry[block]);
+                cur_size = 0;
+            }
+            cur_size += self.func.block_insns(Block::new(block)).len();
         }
-        log::debug!("Ranges:");
-        for (i, r) in self.ranges.iter().enumerate() {
-            log::debug!(
-                concat!(
-                    "range{}: range={:?} vreg={:?} bundle={:?} ",
-                    "weight={} fixed={} first_use={:?} last_use={:?} ",
-                    "def={:?} next_in_bundle={:?} next_in_reg={:?}"
-                ),
-                i,
-                r.range,
-                r.vreg,
-                r.bundle,
-                r.uses_spill_weight,
-                r.num_fixed_uses(),
-                r.first_use,
-                r.last_use,
-                r.def,
-                r.next_in_bundle,
-                r.next_in_reg
-            );
+        self.stats.region_count = boundaries.len() + 1;
+        log::debug!("region boundaries: {:?}", boundaries);
+        if boundaries.is_empty() {
+            return boundaries;
         }
-        log::debug!("Uses:");
-        for (i, u) in self.uses.iter().enumerate() {
+
+        for i in 0..bundles.len() {
+            let bundle = bundles[i];
+            let first = self.bundles[bundle.index()].first_range;
+            let last = self.bundles[bundle.index()].last_range;
+            let start = self.ranges[first.index()].range.from;
+            let end = self.ranges[last.index()].range.to;
+            let lo = boundaries.partition_point(|&b| b <= start);
+            let hi = boundaries.partition_point(|&b| b < end);
+            if lo >= hi {
+                continue;
+            }
             log::debug!(
-                "use{}: op={:?} pos={:?} slot={} next_use={:?}",
-                i,
-                u.operand,
-                u.pos,
-                u.slot,
-                u.next_use
+                "splitting bundle {:?} at region boundaries {:?}",
+                bundle,
+                &boundaries[lo..hi]
             );
+            let new_bundles = self.split_bundle_at(bundle, &boundaries[lo..hi]);
+            self.stats.region_splits += new_bundles.len();
+            bundles.extend(new_bundles);
         }
-        log::debug!("Defs:");
-        for (i, d) in self.defs.iter().enumerate() {
-            log::debug!("def{}: op={:?} pos={:?}", i, d.operand, d.pos,);
-        }
+        boundaries
     }
 
-    fn compute_requirement(&self, bundle: LiveBundleIndex) -> Option<Requirement> {
-        let class = self.vregs[self.ranges[self.bundles[bundle.index()].first_range.index()]
-            .vreg
-            .index()]
-        .reg
-        .class();
-        let mut needed = Requirement::Any(class);
-
-        log::debug!("compute_requirement: bundle {:?} class {:?}", bundle, class);
+    /// If `RegallocOptions::pre_spill` is set, find the runs of insts
+    /// where more vregs of a class are live than there are registers
+    /// in it, so that some of them must be in memory. For each run,
+    /// take as many bundles as the excess, cheapest first, from those
+    /// live across the whole run without a def or use in it, and split
+    /// them at its ends. The pieces across the run have no register
+    /// requirement, so they go straight to the spilled list, rather
+    /// than being evicted and split over and over by the bundles that
+    /// do need registers there.
+    fn pre_spill_bundles(&mut self, bundles: &mut Vec<LiveBundleIndex>) {
+        if !self.opts.pre_spill {
+            return;
+        }
 
-        let mut iter = self.bundles[bundle.index()].first_range;
-        while iter.is_valid() {
+        // Each run's class, extent (widened to legal split points),
+        // and the number of bundles to spill across it.
+        let pressure = self.inst_pressure();
+        let mut runs: Vec<(RegClass, CodeRange, usize)> = vec![];
+        for &class in &[RegClass::Int, RegClass::Float] {
+            let n_regs = self.env.regs_by_class[class as u8 as usize].len();
+            let excess = |inst: usize| pressure[inst][class as u8 as usize].saturating_sub(n_regs);
+            let mut inst = 0;
+            while inst < pressure.len() {
+                if excess(inst) == 0 {
+                    inst += 1;
+                    continue;
+                }
+                let start = inst;
+                let mut max_excess = 0;
+                while inst < pressure.len() && excess(inst) > 0 {
+                    max_excess = std::cmp::max(max_excess, excess(inst));
+                    inst += 1;
+                }
+                let from = self.legal_split_point(ProgPoint::before(Inst::new(start)), false);
+                let to = self.legal_split_point(ProgPoint::before(Inst::new(inst)), true);
+                runs.push((class, CodeRange { from, to }, max_excess));
+            }
+        }
+        log::debug!("pre-spill runs: {:?}", runs);
+        if runs.is_empty() {
+            return;
+        }
+        runs.sort_by_key(|&(_, range, _)| range.from);
+
+        // The candidates across each run, as (spill weight, index
+        // into `bundles`).
+        let mut candidates: Vec<Vec<(u32, usize)>> = vec![vec![]; runs.len()];
+        for (i, &bundle) in bundles.iter().enumerate() {
+            if self.bundle_no_spill(bundle) {
+                continue;
+            }
+            let first = self.bundles[bundle.index()].first_range;
+            let class = self.vregs[self.ranges[first.index()].vreg.index()]
+                .reg
+                .class();
+            // The bundle's extents (abutting ranges joined), and the
+            // positions of its defs and uses, in order.
+            let mut extents: SmallVec<[CodeRange; 4]> = smallvec![];
+            let mut positions: SmallVec<[ProgPoint; 8]> = smallvec![];
+            let mut iter = first;
+            while iter.is_valid() {
+                let range = &self.ranges[iter.index()];
+                match extents.last_mut() {
+                    Some(last) if last.to == range.range.from => last.to = range.range.to,
+                    _ => extents.push(range.range),
+                }
+                if range.def.is_valid() {
+                    positions.push(self.defs[range.def.index()].pos);
+                }
+                positions.extend(range.uses.iter().map(|u| self.uses[u.index()].pos));
+                iter = range.next_in_bundle;
+            }
+            positions.sort_unstable();
+
+            let mut weight = None;
+            for extent in &extents {
+                let lo = runs.partition_point(|&(_, range, _)| range.from < extent.from);
+                for r in lo..runs.len() {
+                    let (run_class, range, _) = runs[r];
+                    if range.from >= extent.to {
+                        break;
+                    }
+                    let first_pos = positions.partition_point(|&pos| pos < range.from);
+                    let used = matches!(positions.get(first_pos), Some(&pos) if pos < range.to);
+                    if run_class != class || !extent.contains(&range) || used {
+                        continue;
+                    }
+                    let weight = *weight.get_or_insert_with(|| {
+                        self.bundles[bundle.index()].prio = self.compute_bundle_prio(bundle);
+                        self.recompute_bundle_properties(bundle);
+                        self.bundle_spill_weight(bundle)
+                    });
+                    candidates[r].push((weight, i));
+                }
+            }
+        }
+
+        // Split each chosen bundle at the ends of every run it was
+        // chosen for.
+        let mut splits: Vec<(usize, ProgPoint)> = vec![];
+        for (r, candidates) in candidates.iter_mut().enumerate() {
+            let (_, range, excess) = runs[r];
+            candidates.sort_unstable();
+            for &(_, i) in candidates.iter().take(excess) {
+                splits.push((i, range.from));
+                splits.push((i, range.to));
+                self.stats.pre_spills += 1;
+            }
+        }
+        splits.sort_unstable();
+        splits.dedup();
+        let mut start = 0;
+        while start < splits.len() {
+            let i = splits[start].0;
+            let end = start + splits[start..].partition_point(|&(j, _)| j == i);
+            let points: SmallVec<[ProgPoint; 4]> =
+                splits[start..end].iter().map(|&(_, point)| point).collect();
+            let bundle = bundles[i];
+            log::debug!("pre-spilling bundle {:?} between {:?}", bundle, points);
+            let new_bundles = self.split_bundle_at(bundle, &points);
+            bundles.extend(new_bundles);
+            start = end;
+        }
+    }
+
+    /// The register named by the first def or use in `bundle` with a
+    /// `PrefReg` policy, if it is allocatable, to hint the bundle to.
+    fn preferred_reg(&self, bundle: LiveBundleIndex) -> Option<PReg> {
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            let range = &self.ranges[iter.index()];
+            let def = Some(range.def)
+                .filter(|def| def.is_valid())
+                .map(|def| self.defs[def.index()].operand);
+            let uses = range.uses.iter().map(|u| self.uses[u.index()].operand);
+            for op in def.into_iter().chain(uses) {
+                if let OperandPolicy::PrefReg(preg) = op.policy() {
+                    if self.env.regs_by_class[preg.class() as u8 as usize].contains(&preg) {
+                        return Some(preg);
+                    }
+                }
+            }
+            iter = range.next_in_bundle;
+        }
+        None
+    }
+
+    /// Split every bundle around each of its defs and uses with a
+    /// `FixedReg` policy, and allocate the pieces holding them to their
+    /// registers before the main loop runs, hinting the (first) fixed
+    /// register for the rest of the bundle. Fixed constraints thus
+    /// reserve their registers up front, rather than being discovered
+    /// by conflicts with, and evicting, bundles already placed there.
+    /// Pieces that cannot be allocated (e.g. with conflicting
+    /// constraints) are left for the main loop; those that can are
+    /// removed from `bundles`.
+    fn precolor_fixed_operands(&mut self, bundles: &mut Vec<LiveBundleIndex>) {
+        let mut unallocated = Vec::with_capacity(bundles.len());
+        for &bundle in bundles.iter() {
+            let mut points: SmallVec<[ProgPoint; 4]> = smallvec![];
+            let mut hint = None;
+            let mut iter = self.bundles[bundle.index()].first_range;
+            while iter.is_valid() {
+                let range = &self.ranges[iter.index()];
+                let def = Some(range.def)
+                    .filter(|def| def.is_valid())
+                    .map(|def| (self.defs[def.index()].pos, self.defs[def.index()].operand));
+                let uses = range
+                    .uses
+                    .iter()
+                    .map(|u| (self.uses[u.index()].pos, self.uses[u.index()].operand));
+                for (pos, op) in def.into_iter().chain(uses) {
+                    if let OperandPolicy::FixedReg(preg) = op.policy() {
+                        hint.get_or_insert(preg);
+                        let inst = pos.inst;
+                        points.push(self.legal_split_point(ProgPoint::before(inst), false));
+                        points.push(self.legal_split_point(ProgPoint::before(inst.next()), true));
+                    }
+                }
+                iter = range.next_in_bundle;
+            }
+            if hint.is_none() {
+                unallocated.push(bundle);
+                continue;
+            }
+
+            let spillset = self.bundles[bundle.index()].spillset;
+            if self.spillsets[spillset.index()].reg_hint.is_none() {
+                self.spillsets[spillset.index()].reg_hint = hint;
+            }
+            self.canonicalize_split_points(bundle, &mut points);
+            log::debug!("precoloring bundle {:?}: split at {:?}", bundle, points);
+            let new_bundles = self.split_bundle_at(bundle, &points);
+            for piece in std::iter::once(bundle).chain(new_bundles) {
+                self.bundles[piece.index()].prio = self.compute_bundle_prio(piece);
+                self.recompute_bundle_properties(piece);
+                let preg = match self.compute_requirement(piece) {
+                    Some(Requirement::Fixed(preg))
+                        if self.minimal_bundle(piece)
+                            && !self.compute_excluded_regs(piece).contains(&preg) =>
+                    {
+                        preg
+                    }
+                    _ => {
+                        unallocated.push(piece);
+                        continue;
+                    }
+                };
+                match self.try_to_allocate_bundle_to_reg(piece, PRegIndex::new(preg.index())) {
+                    AllocRegResult::Allocated(_) => {
+                        log::debug!(" -> precolored {:?} to {:?}", piece, preg);
+                        self.stats.precolored_bundles += 1;
+                    }
+                    _ => unallocated.push(piece),
+                }
+            }
+        }
+        *bundles = unallocated;
+    }
+
+    fn process_bundles(&mut self) {
+        while self.process_next_bundle() {}
+        self.finish_process_bundles();
+    }
+
+    /// Pop the next bundle from the allocation queue, moving on to
+    /// the next region when the queue is empty, and process it;
+    /// return `false` if no bundle was left. If `self.decision` is
+    /// set, it is filled in with a record of what was done.
+    fn process_next_bundle(&mut self) -> bool {
+        if self.iteration_limit_exceeded {
+            return false;
+        }
+        let bundle = loop {
+            if let Some(bundle) = self.allocation_queue.pop() {
+                break bundle;
+            }
+            if !self.queue_next_region() {
+                return false;
+            }
+        };
+        self.stats.process_bundle_count += 1;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "bundle",
+            bundle = bundle.index(),
+            prio = self.bundles[bundle.index()].prio
+        )
+        .entered();
+        if let Some(decision) = self.decision.as_mut() {
+            *decision = Decision {
+                bundle: bundle.index(),
+                ranges: vec![],
+                probes: vec![],
+                evicted: vec![],
+                outcome: DecisionOutcome::Split,
+            };
+            let mut iter = self.bundles[bundle.index()].first_range;
+            while iter.is_valid() {
+                decision.ranges.push(self.ranges[iter.index()].range);
+                iter = self.ranges[iter.index()].next_in_bundle;
+            }
+        }
+        self.process_bundle(bundle);
+        // Each spillset that churns is force-spilled once past
+        // `MAX_REQUEUES`, so this many requeues means the allocator
+        // is going around in circles. Treat every bundle as churning
+        // from here on, so that each is spilled between its uses, or
+        // spills what it evicts, rather than go around again.
+        if !self.requeue_limit_hit
+            && self.stats.requeues > 2 * MAX_REQUEUES as usize * self.spillsets.len()
+        {
+            log::debug!("requeue limit reached; force-spilling from here on");
+            self.requeue_limit_hit = true;
+        }
+        // Likewise past the global limit on bundles processed, which
+        // also catches cycles that requeue little; if even that does
+        // not converge, give up rather than loop forever.
+        let limit = MAX_PROCESS_BUNDLE_FACTOR * (self.func.insts() + self.bundles.len());
+        if self.stats.process_bundle_count > limit && self.stats.iteration_limit_hits == 0 {
+            log::debug!("iteration limit reached; force-spilling from here on");
+            self.requeue_limit_hit = true;
+            self.stats.iteration_limit_hits += 1;
+        } else if self.stats.process_bundle_count > 2 * limit {
+            log::debug!("iteration limit reached twice; giving up");
+            self.stats.iteration_limit_hits += 1;
+            self.iteration_limit_exceeded = true;
+            while let Some(bundle) = self.allocation_queue.pop() {
+                self.spilled_bundles.push(bundle);
+            }
+            return false;
+        }
+        true
+    }
+
+    fn finish_process_bundles(&mut self) {
+        self.stats.final_liverange_count = self.ranges.len();
+        self.stats.final_bundle_count = self.bundles.len();
+        self.stats.spill_bundle_count = self.spilled_bundles.len();
+    }
+
+    fn trace_probe(&mut self, preg: PRegIndex, result: &AllocRegResult) {
+        #[cfg(feature = "tracing")]
+        match result {
+            AllocRegResult::Allocated(_) => {
+                tracing::trace!(preg = %self.pregs[preg.index()].reg, "probe: free")
+            }
+            AllocRegResult::Conflict(bundles) => tracing::trace!(
+                preg = %self.pregs[preg.index()].reg,
+                conflicts = bundles.len(),
+                "probe: conflict"
+            ),
+            AllocRegResult::ConflictWithFixed => {
+                tracing::trace!(preg = %self.pregs[preg.index()].reg, "probe: fixed conflict")
+            }
+        }
+        if let Some(decision) = self.decision.as_mut() {
+            let result = match result {
+                AllocRegResult::Allocated(_) => ProbeResult::Allocated,
+                AllocRegResult::Conflict(bundles) => {
+                    ProbeResult::Conflict(bundles.iter().map(|b| b.index()).collect())
+                }
+                AllocRegResult::ConflictWithFixed => ProbeResult::ConflictWithFixed,
+            };
+            decision.probes.push((self.pregs[preg.index()].reg, result));
+        }
+    }
+
+    fn trace_outcome(&mut self, outcome: DecisionOutcome) {
+        #[cfg(feature = "tracing")]
+        match outcome {
+            DecisionOutcome::Allocated(preg) => tracing::debug!(preg = %preg, "allocated"),
+            DecisionOutcome::Deferred => tracing::debug!("deferred"),
+            DecisionOutcome::Spilled => tracing::debug!("spilled"),
+            DecisionOutcome::Split => tracing::debug!("split"),
+            DecisionOutcome::ForceSpilled => tracing::debug!("force-spilled"),
+        }
+        if let Some(decision) = self.decision.as_mut() {
+            decision.outcome = outcome;
+        }
+    }
+
+    /// Some vreg in the given bundle.
+    fn bundle_vreg(&self, bundle: LiveBundleIndex) -> VReg {
+        let first_range = self.bundles[bundle.index()].first_range;
+        self.vregs[self.ranges[first_range.index()].vreg.index()].reg
+    }
+
+    /// In explain mode, record an event for each vreg in `bundle`,
+    /// given the span of the vreg's ranges in the bundle.
+    fn explain<E: Fn(CodeRange) -> AllocationEvent>(&mut self, bundle: LiveBundleIndex, event: E) {
+        if !self.opts.explain {
+            return;
+        }
+        if self.explanations.is_empty() {
+            self.explanations = vec![vec![]; self.vregs.len()];
+        }
+        let mut spans: SmallVec<[(VRegIndex, CodeRange); 4]> = smallvec![];
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            let lr = &self.ranges[iter.index()];
+            match spans.iter_mut().find(|(vreg, _)| *vreg == lr.vreg) {
+                Some((_, span)) => span.to = lr.range.to,
+                None => spans.push((lr.vreg, lr.range)),
+            }
+            iter = lr.next_in_bundle;
+        }
+        for (vreg, span) in spans {
+            let event = event(span);
+            log::debug!(
+                "explain {}: {:?}",
+                vreg_label(self.func, self.vregs[vreg.index()].reg),
+                event
+            );
+            self.explanations[vreg.index()].push(event);
+        }
+    }
+
+    /// In chaos mode, a pseudo-random choice in `0..n`; otherwise
+    /// `None`, and the caller makes its usual deterministic choice.
+    fn chaos_choice(&mut self, n: usize) -> Option<usize> {
+        let state = self.chaos.as_mut()?;
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        Some((*state % n as u64) as usize)
+    }
+
+    /// Check the internal invariants that later phases rely on,
+    /// panicking (and naming `phase`) on the first violation. Only
+    /// compiled in with the `verify-invariants` feature.
+    #[cfg(feature = "verify-invariants")]
+    fn verify_invariants(&self, phase: &str) {
+        // Each vreg's ranges are nonempty, sorted and disjoint, and
+        // contain their uses and def.
+        for vreg in 0..self.vregs.len() {
+            let mut prev: Option<CodeRange> = None;
+            let mut iter = self.vregs[vreg].first_range;
+            while iter.is_valid() {
+                let lr = &self.ranges[iter.index()];
+                assert!(
+                    !lr.range.is_empty(),
+                    "{}: v{} has empty range {:?}",
+                    phase,
+                    vreg,
+                    lr.range
+                );
+                assert_eq!(
+                    lr.vreg.index(),
+                    vreg,
+                    "{}: range {:?} in list of v{} belongs to v{}",
+                    phase,
+                    lr.range,
+                    vreg,
+                    lr.vreg.index()
+                );
+                if let Some(prev) = prev {
+                    assert!(
+                        prev.to <= lr.range.from,
+                        "{}: ranges {:?} and {:?} of v{} are unsorted or overlap",
+                        phase,
+                        prev,
+                        lr.range,
+                        vreg
+                    );
+                }
+                for &use_idx in &lr.uses {
+                    let u = &self.uses[use_idx.index()];
+                    assert!(
+                        lr.range.contains_point(u.pos),
+                        "{}: use of v{} at {:?} lies outside its range {:?}",
+                        phase,
+                        vreg,
+                        u.pos,
+                        lr.range
+                    );
+                }
+                if lr.def.is_valid() {
+                    let pos = self.defs[lr.def.index()].pos;
+                    assert!(
+                        lr.range.contains_point(pos),
+                        "{}: def of v{} at {:?} lies outside its range {:?}",
+                        phase,
+                        vreg,
+                        pos,
+                        lr.range
+                    );
+                }
+                prev = Some(lr.range);
+                iter = lr.next_in_reg;
+            }
+        }
+
+        // Each bundle's ranges are sorted and disjoint, point back to
+        // the bundle, and all share a class with the bundle's
+        // spillset (if it has one yet).
+        for bundle in 0..self.bundles.len() {
+            let data = &self.bundles[bundle];
+            let class = if data.spillset.is_valid() {
+                let spillset = &self.spillsets[data.spillset.index()];
+                if spillset.slot.is_valid() {
+                    assert_eq!(
+                        self.spillslots[spillset.slot.index()].class,
+                        spillset.class,
+                        "{}: spillset of bundle{} has a spillslot of another class",
+                        phase,
+                        bundle
+                    );
+                }
+                Some(spillset.class)
+            } else {
+                None
+            };
+            let mut prev: Option<CodeRange> = None;
+            let mut iter = data.first_range;
+            while iter.is_valid() {
+                let lr = &self.ranges[iter.index()];
+                assert_eq!(
+                    lr.bundle.index(),
+                    bundle,
+                    "{}: range {:?} in bundle{} points to bundle{}",
+                    phase,
+                    lr.range,
+                    bundle,
+                    lr.bundle.index()
+                );
+                if let Some(prev) = prev {
+                    assert!(
+                        prev.to <= lr.range.from,
+                        "{}: ranges {:?} and {:?} of bundle{} are unsorted or overlap",
+                        phase,
+                        prev,
+                        lr.range,
+                        bundle
+                    );
+                }
+                if let Some(class) = class {
+                    assert_eq!(
+                        self.vregs[lr.vreg.index()].reg.class(),
+                        class,
+                        "{}: v{} in bundle{} differs in class from its spillset",
+                        phase,
+                        lr.vreg.index(),
+                        bundle
+                    );
+                }
+                prev = Some(lr.range);
+                iter = lr.next_in_bundle;
+            }
+        }
+    }
+
+    #[cfg(not(feature = "verify-invariants"))]
+    fn verify_invariants(&self, _phase: &str) {}
+
+    fn dump_state(&self) {
+        let mut state = String::new();
+        self.write_state(&mut state).unwrap();
+        for line in state.lines() {
+            log::debug!("{}", line);
+        }
+    }
+
+    fn write_state(&self, out: &mut String) -> std::fmt::Result {
+        use std::fmt::Write;
+        writeln!(out, "Bundles:")?;
+        for (i, b) in self.bundles.iter().enumerate() {
+            writeln!(
+                out,
+                "bundle{}: first_range={:?} last_range={:?} spillset={:?} alloc={:?}",
+                i, b.first_range, b.last_range, b.spillset, b.allocation
+            )?;
+        }
+        writeln!(out, "VRegs:")?;
+        for (i, v) in self.vregs.iter().enumerate() {
+            let name = match self.func.debug_vreg_name(v.reg) {
+                Some(name) => format!(" ({})", name),
+                None => String::new(),
+            };
+            writeln!(out, "vreg{}{}: first_range={:?}", i, name, v.first_range)?;
+        }
+        writeln!(out, "Ranges:")?;
+        for (i, r) in self.ranges.iter().enumerate() {
+            writeln!(
+                out,
+                concat!(
+                    "range{}: range={:?} vreg={:?} bundle={:?} ",
+                    "weight={} fixed={} uses={:?} ",
+                    "def={:?} next_in_bundle={:?} next_in_reg={:?}"
+                ),
+                i,
+                r.range,
+                r.vreg,
+                r.bundle,
+                r.uses_spill_weight,
+                r.num_fixed_uses(),
+                r.uses,
+                r.def,
+                r.next_in_bundle,
+                r.next_in_reg
+            )?;
+        }
+        writeln!(out, "Uses:")?;
+        for (i, u) in self.uses.iter().enumerate() {
+            writeln!(
+                out,
+                "use{}: op={:?} pos={:?} slot={}",
+                i, u.operand, u.pos, u.slot
+            )?;
+        }
+        writeln!(out, "Defs:")?;
+        for (i, d) in self.defs.iter().enumerate() {
+            writeln!(out, "def{}: op={:?} pos={:?}", i, d.operand, d.pos)?;
+        }
+        Ok(())
+    }
+
+    /// If `RegallocOptions::snapshot_dir` is set, write the function
+    /// and the allocator's state after the given phase to
+    /// `<phase>.txt` there. Failure to write is logged but otherwise
+    /// ignored: snapshots are a debugging aid.
+    fn write_snapshot(&self, phase: &str) {
+        let dir = match &self.opts.snapshot_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let mut text = String::new();
+        self.write_function(&mut text).unwrap();
+        self.write_state(&mut text).unwrap();
+        let path = dir.join(format!("{}.txt", phase));
+        if let Err(e) = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, text)) {
+            log::warn!("could not write snapshot {:?}: {}", path, e);
+        }
+    }
+
+    fn write_function(&self, out: &mut String) -> std::fmt::Result {
+        use std::fmt::Write;
+        writeln!(out, "Function:")?;
+        for block in 0..self.func.blocks() {
+            let block = Block::new(block);
+            write!(out, "block{}(", block.index())?;
+            for (i, param) in self.func.block_params(block).iter().enumerate() {
+                write!(out, "{}{}", if i == 0 { "" } else { ", " }, param)?;
+            }
+            write!(out, "): succs:")?;
+            for succ in self.func.block_succs(block) {
+                write!(out, " block{}", succ.index())?;
+            }
+            write!(out, " preds:")?;
+            for pred in self.func.block_preds(block) {
+                write!(out, " block{}", pred.index())?;
+            }
+            writeln!(out)?;
+            for inst in self.func.block_insns(block).iter() {
+                write!(out, "  inst{}:", inst.index())?;
+                for (i, op) in crate::liveness::inst_operands(self.func, inst)
+                    .iter()
+                    .enumerate()
+                {
+                    write!(out, "{}{}", if i == 0 { " " } else { ", " }, op)?;
+                }
+                let mut clobbers = self.env.inst_clobbers(self.func, inst).peekable();
+                if clobbers.peek().is_some() {
+                    write!(out, " clobbers:")?;
+                    for preg in clobbers {
+                        write!(out, " {}", preg)?;
+                    }
+                }
+                writeln!(out)?;
+            }
+        }
+        self.write_vreg_names(out)
+    }
+
+    /// List the vregs that have a `Function::debug_vreg_name`.
+    fn write_vreg_names(&self, out: &mut String) -> std::fmt::Result {
+        use std::fmt::Write;
+        let mut names = self.vregs.iter().filter_map(|v| {
+            let name = self.func.debug_vreg_name(v.reg)?;
+            Some((v.reg, name))
+        });
+        if let Some((vreg, name)) = names.next() {
+            write!(out, "Names: {}={}", vreg, name)?;
+            for (vreg, name) in names {
+                write!(out, ", {}={}", vreg, name)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn compute_requirement(&self, bundle: LiveBundleIndex) -> Option<Requirement> {
+        let class = self.vregs[self.ranges[self.bundles[bundle.index()].first_range.index()]
+            .vreg
+            .index()]
+        .reg
+        .class();
+        let mut needed = Requirement::Any(class);
+
+        log::debug!("compute_requirement: bundle {:?} class {:?}", bundle, class);
+
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
             let range = &self.ranges[iter.index()];
             log::debug!(" -> range {:?}", range.range);
             if range.def.is_valid() {
@@ -1790,15 +3707,12 @@ impl<'a, F: Function> Env<'a, F> {
                 needed = needed.merge(def_req)?;
                 log::debug!("   -> needed {:?}", needed);
             }
-            let mut use_iter = range.first_use;
-            while use_iter.is_valid() {
-                let usedata = &self.uses[use_iter.index()];
-                let use_op = usedata.operand;
+            for &use_idx in &range.uses {
+                let use_op = self.uses[use_idx.index()].operand;
                 let use_req = Requirement::from_operand(use_op);
-                log::debug!(" -> use {:?} op {:?} req {:?}", use_iter, use_op, use_req);
+                log::debug!(" -> use {:?} op {:?} req {:?}", use_idx, use_op, use_req);
                 needed = needed.merge(use_req)?;
                 log::debug!("   -> needed {:?}", needed);
-                use_iter = usedata.next_use;
             }
             iter = range.next_in_bundle;
         }
@@ -1807,6 +3721,34 @@ impl<'a, F: Function> Env<'a, F> {
         Some(needed)
     }
 
+    /// The registers that some use or def in the bundle excludes via
+    /// `OperandPolicy::RegExcept`.
+    fn compute_excluded_regs(&self, bundle: LiveBundleIndex) -> SmallVec<[PReg; 4]> {
+        let mut excluded: SmallVec<[PReg; 4]> = smallvec![];
+        let mut add = |op: Operand| {
+            if let OperandPolicy::RegExcept(regs) = op.policy() {
+                for num in regs.nums() {
+                    let preg = PReg::new(num, op.class());
+                    if !excluded.contains(&preg) {
+                        excluded.push(preg);
+                    }
+                }
+            }
+        };
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            let range = &self.ranges[iter.index()];
+            if range.def.is_valid() {
+                add(self.defs[range.def.index()].operand);
+            }
+            for &use_idx in &range.uses {
+                add(self.uses[use_idx.index()].operand);
+            }
+            iter = range.next_in_bundle;
+        }
+        excluded
+    }
+
     fn try_to_allocate_bundle_to_reg(
         &mut self,
         bundle: LiveBundleIndex,
@@ -1858,33 +3800,130 @@ impl<'a, F: Function> Env<'a, F> {
             let range = &self.ranges[iter.index()];
             self.pregs[reg.index()]
                 .allocations
-                .btree
-                .insert(LiveRangeKey::from_range(&range.range), iter);
+                .insert(range.range, iter);
             iter = range.next_in_bundle;
         }
 
         AllocRegResult::Allocated(Allocation::reg(preg))
     }
 
-    fn evict_bundle(&mut self, bundle: LiveBundleIndex) {
-        log::debug!(
-            "evicting bundle {:?}: alloc {:?}",
-            bundle,
-            self.bundles[bundle.index()].allocation
-        );
-        let preg = match self.bundles[bundle.index()].allocation.as_reg() {
-            Some(preg) => preg,
-            None => {
-                log::debug!(
-                    "  -> has no allocation! {:?}",
-                    self.bundles[bundle.index()].allocation
-                );
-                return;
-            }
-        };
-        let preg_idx = PRegIndex::new(preg.index());
-        self.bundles[bundle.index()].allocation = Allocation::none();
-        let mut iter = self.bundles[bundle.index()].first_range;
+    /// Try to allocate `bundle` to `reg`, unless an earlier probe
+    /// already found a fixed reservation of `reg` in the way.
+    fn probe_bundle_reg(&mut self, bundle: LiveBundleIndex, reg: PRegIndex) -> AllocRegResult {
+        let preg = self.pregs[reg.index()].reg;
+        if self.bundles[bundle.index()].fixed_conflicts.contains(&preg) {
+            log::debug!("probe of {:?} for {:?}: known fixed conflict", preg, bundle);
+            self.stats.fixed_conflict_probes_skipped += 1;
+            return AllocRegResult::ConflictWithFixed;
+        }
+        let result = self.try_to_allocate_bundle_to_reg(bundle, reg);
+        if let AllocRegResult::ConflictWithFixed = result {
+            self.bundles[bundle.index()].fixed_conflicts.push(preg);
+        }
+        result
+    }
+
+    /// Of the registers of `class` (other than `excluded`) that
+    /// `bundle` fits in without evicting anything, the one with the
+    /// best score; see `RegallocOptions::score_free_regs`. Ties go to
+    /// the register probed first.
+    fn best_free_reg(
+        &self,
+        bundle: LiveBundleIndex,
+        class: RegClass,
+        hint: Option<PReg>,
+        excluded: &[PReg],
+    ) -> Option<PReg> {
+        let mut end = ProgPoint::before(Inst::new(0));
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            end = self.ranges[iter.index()].range.to;
+            iter = self.ranges[iter.index()].next_in_bundle;
+        }
+        let after_end = LiveRangeKey::from_range(&CodeRange {
+            from: end,
+            to: end.next(),
+        });
+        let mut best = None;
+        let across_call = self.bundle_crosses_call(bundle);
+        for i in 0..self.env.regs_by_class[class as u8 as usize].len() {
+            let preg = self.probe_reg(class, across_call, i, bundle.index());
+            if excluded.contains(&preg)
+                || self.bundles[bundle.index()].fixed_conflicts.contains(&preg)
+            {
+                continue;
+            }
+            let allocations = &self.pregs[preg.index()].allocations.btree;
+            let mut iter = self.bundles[bundle.index()].first_range;
+            let mut fits = true;
+            while iter.is_valid() && fits {
+                let range = &self.ranges[iter.index()];
+                fits = !allocations.contains_key(&LiveRangeKey::from_range(&range.range));
+                iter = range.next_in_bundle;
+            }
+            if !fits {
+                continue;
+            }
+            let first_use = !allocations
+                .values()
+                .any(|lr| self.ranges[lr.index()].vreg.is_valid());
+            let free_after = allocations
+                .range(after_end..)
+                .next()
+                .map(|(key, _)| key.from - end.to_index())
+                .unwrap_or(ProgPointIndex::MAX);
+            let score = (
+                Some(preg) != hint,
+                first_use && self.env.callee_saved_regs.contains(&preg),
+                self.env.reg_cost(preg),
+                std::cmp::Reverse(free_after),
+            );
+            if !matches!(best, Some((best_score, _)) if best_score <= score) {
+                best = Some((score, preg));
+            }
+        }
+        log::debug!("best free reg for {:?}: {:?}", bundle, best);
+        best.map(|(_, preg)| preg)
+    }
+
+    fn evict_bundle(&mut self, bundle: LiveBundleIndex) {
+        log::debug!(
+            "evicting bundle {:?}: alloc {:?}",
+            bundle,
+            self.bundles[bundle.index()].allocation
+        );
+        if !self.unassign_bundle(bundle) {
+            return;
+        }
+        let prio = self.bundles[bundle.index()].prio;
+        log::debug!(" -> prio {}; back into queue", prio);
+        self.count_requeue(bundle);
+        self.allocation_queue.insert(bundle, prio as usize);
+    }
+
+    /// Count `bundle` going back into the allocation queue; see
+    /// `MAX_REQUEUES`.
+    fn count_requeue(&mut self, bundle: LiveBundleIndex) {
+        self.spillsets[self.bundles[bundle.index()].spillset.index()].requeues += 1;
+        self.stats.requeues += 1;
+    }
+
+    /// Take `bundle` out of its register, returning `false` if it had
+    /// none.
+    fn unassign_bundle(&mut self, bundle: LiveBundleIndex) -> bool {
+        let preg = match self.bundles[bundle.index()].allocation.as_reg() {
+            Some(preg) => preg,
+            None => {
+                log::debug!(
+                    "  -> has no allocation! {:?}",
+                    self.bundles[bundle.index()].allocation
+                );
+                return false;
+            }
+        };
+        let preg_idx = PRegIndex::new(preg.index());
+        self.bundles[bundle.index()].allocation = Allocation::none();
+        let mut iter = self.bundles[bundle.index()].first_range;
         while iter.is_valid() {
             log::debug!(" -> removing LR {:?} from reg {:?}", iter, preg_idx);
             self.pregs[preg_idx.index()]
@@ -1893,9 +3932,50 @@ impl<'a, F: Function> Env<'a, F> {
                 .remove(&LiveRangeKey::from_range(&self.ranges[iter.index()].range));
             iter = self.ranges[iter.index()].next_in_bundle;
         }
-        let prio = self.bundles[bundle.index()].prio;
-        log::debug!(" -> prio {}; back into queue", prio);
-        self.allocation_queue.insert(bundle, prio as usize);
+        true
+    }
+
+    /// Would `bundle` evicting `conflicts` run away: lengthen a
+    /// chain of evictions beyond `MAX_EVICTION_DEPTH`, or evict a
+    /// bundle that is itself on the chain that led to `bundle`?
+    fn is_eviction_cascade(&self, bundle: LiveBundleIndex, conflicts: &LiveBundleVec) -> bool {
+        if self.bundles[bundle.index()].eviction_depth >= MAX_EVICTION_DEPTH {
+            return true;
+        }
+        // The links may themselves form a cycle, so follow no more of
+        // them than a chain may be long.
+        let mut iter = self.bundles[bundle.index()].evicted_by;
+        for _ in 0..MAX_EVICTION_DEPTH {
+            if !iter.is_valid() {
+                break;
+            }
+            if conflicts.contains(&iter) {
+                return true;
+            }
+            iter = self.bundles[iter.index()].evicted_by;
+        }
+        false
+    }
+
+    /// Break up `bundle` around each of its uses, so that it lives in
+    /// its spillslot everywhere else, and requeue the pieces. Any
+    /// register allocation it had is dropped. Returns `false`, leaving
+    /// the bundle alone, if it is already split as far as it goes:
+    /// requeueing it whole would only bring it back unchanged.
+    fn force_spill_bundle(&mut self, bundle: LiveBundleIndex, conflict: LiveBundleIndex) -> bool {
+        let mut split_points = self.find_all_use_split_points(bundle);
+        self.canonicalize_split_points(bundle, &mut split_points);
+        if split_points.is_empty() {
+            return false;
+        }
+        log::debug!("force-spilling bundle {:?}", bundle);
+        self.unassign_bundle(bundle);
+        self.bundles[bundle.index()].evicted_by = LiveBundleIndex::invalid();
+        self.bundles[bundle.index()].eviction_depth = 0;
+        self.stats.splits += 1;
+        self.stats.splits_all += 1;
+        self.requeue_split_bundle(bundle, conflict, SplitReason::AllUses, split_points);
+        true
     }
 
     fn bundle_spill_weight(&self, bundle: LiveBundleIndex) -> u32 {
@@ -1910,6 +3990,49 @@ impl<'a, F: Function> Env<'a, F> {
             .unwrap_or(0)
     }
 
+    /// Is `pos` between two insts that the function forbids edits
+    /// between?
+    fn is_restricted_point(&self, pos: ProgPoint) -> bool {
+        match pos.pos {
+            InstPosition::After => self.no_edits_after.get(pos.inst.index()),
+            InstPosition::Before => {
+                pos.inst.index() > 0 && self.no_edits_after.get(pos.inst.index() - 1)
+            }
+        }
+    }
+
+    /// Move a split point out of any restricted gap: back to the start
+    /// of the run of insts that the gap joins, or, if `later`, forward
+    /// to just past its end. Splitting there instead never inserts a
+    /// move into the gap.
+    fn legal_split_point(&self, pos: ProgPoint, later: bool) -> ProgPoint {
+        if !self.is_restricted_point(pos) {
+            return pos;
+        }
+        let mut inst = match pos.pos {
+            InstPosition::After => pos.inst,
+            InstPosition::Before => pos.inst.prev(),
+        };
+        if later {
+            while self.no_edits_after.get(inst.index()) {
+                inst = inst.next();
+            }
+            ProgPoint::before(inst.next())
+        } else {
+            while inst.index() > 0 && self.no_edits_after.get(inst.index() - 1) {
+                inst = inst.prev();
+            }
+            ProgPoint::before(inst)
+        }
+    }
+
+    /// The first inst of the run of insts joined by restricted gaps
+    /// that contains `inst`. A bundle within one such run cannot be
+    /// split further.
+    fn edit_group_start(&self, inst: Inst) -> Inst {
+        self.legal_split_point(ProgPoint::before(inst), false).inst
+    }
+
     fn recompute_bundle_properties(&mut self, bundle: LiveBundleIndex) {
         let minimal;
         let mut fixed = false;
@@ -1926,25 +4049,30 @@ impl<'a, F: Function> Env<'a, F> {
                     fixed = true;
                 }
             }
-            let mut use_iter = first_range.first_use;
-            while use_iter.is_valid() {
-                let use_data = &self.uses[use_iter.index()];
-                if let OperandPolicy::FixedReg(_) = use_data.operand.policy() {
+            for &use_idx in &first_range.uses {
+                if let OperandPolicy::FixedReg(_) = self.uses[use_idx.index()].operand.policy() {
                     fixed = true;
                     break;
                 }
-                use_iter = use_data.next_use;
             }
             // Minimal if this is the only range in the bundle, and if
             // the range covers only one instruction. Note that it
             // could cover just one ProgPoint, i.e. X.Before..X.After,
-            // or two ProgPoints, i.e. X.Before..X+1.Before.
+            // or two ProgPoints, i.e. X.Before..X+1.Before. Insts
+            // joined by restricted gaps count as one instruction.
             minimal = first_range.next_in_bundle.is_invalid()
-                && first_range.range.from.inst == first_range.range.to.prev().inst;
+                && self.edit_group_start(first_range.range.from.inst)
+                    == self.edit_group_start(first_range.range.to.prev().inst);
         }
 
         let spill_weight = if minimal {
-            if fixed {
+            if !fixed
+                && self.bundle_no_spill(bundle)
+                && matches!(self.compute_requirement(bundle), Some(Requirement::Any(_)))
+            {
+                log::debug!("  -> no-spill and minimal: {}", MAX_ADJUSTED_SPILL_WEIGHT);
+                MAX_ADJUSTED_SPILL_WEIGHT
+            } else if fixed {
                 log::debug!("  -> fixed and minimal: 2000000");
                 2_000_000
             } else {
@@ -1952,23 +4080,51 @@ impl<'a, F: Function> Env<'a, F> {
                 1_000_000
             }
         } else {
-            let mut total = 0;
+            let mut total: u32 = 0;
+            let mut adjusted = false;
             let mut range = self.bundles[bundle.index()].first_range;
             while range.is_valid() {
                 let range_data = &self.ranges[range.index()];
-                if range_data.def.is_valid() {
+                let mut weight = 0;
+                if range_data.def.is_valid()
+                    && self.defs[range_data.def.index()].operand.policy() != OperandPolicy::Constant
+                {
                     log::debug!("  -> has def (2000)");
-                    total += 2000;
+                    weight += 2000;
                 }
                 log::debug!("  -> uses spill weight: {}", range_data.uses_spill_weight);
-                total += range_data.uses_spill_weight;
+                weight += range_data.uses_spill_weight;
+                let vreg = self.vregs[range_data.vreg.index()].reg;
+                match self.func.spill_weight(vreg) {
+                    SpillWeight::Default => {}
+                    SpillWeight::Scale(factor) => {
+                        log::debug!("  -> scaled by {} for {:?}", factor, vreg);
+                        weight = (weight as f32 * factor.max(0.0)) as u32;
+                        adjusted = true;
+                    }
+                    SpillWeight::Override(per_inst) => {
+                        log::debug!("  -> overridden to {} for {:?}", per_inst, vreg);
+                        weight = per_inst.saturating_mul(range_data.range.len() as u32);
+                        adjusted = true;
+                    }
+                }
+                total = total.saturating_add(weight);
                 range = range_data.next_in_bundle;
             }
 
-            if self.bundles[bundle.index()].prio > 0 {
+            let weight = if self.bundles[bundle.index()].prio > 0 {
                 total / self.bundles[bundle.index()].prio
             } else {
                 total
+            };
+            // An adjusted weight must stay below that of every
+            // minimal bundle, which may need to evict this one.
+            if self.bundle_no_spill(bundle) {
+                NO_SPILL_SPILL_WEIGHT
+            } else if adjusted {
+                weight.min(MAX_ADJUSTED_SPILL_WEIGHT)
+            } else {
+                weight
             }
         };
 
@@ -1987,13 +4143,15 @@ impl<'a, F: Function> Env<'a, F> {
         &mut self,
         bundle: LiveBundleIndex,
         conflicting: LiveBundleIndex,
-    ) -> SmallVec<[ProgPoint; 4]> {
+    ) -> (SplitReason, SmallVec<[ProgPoint; 4]>) {
         // Scan the bundle's ranges once. We want to record:
         // - Does the bundle contain any ranges in "hot" code and/or "cold" code?
         //   If so, record the transition points that are fully included in
         //   `bundle`: the first ProgPoint in a hot range if the prior cold
         //   point is also in the bundle; and the first ProgPoint in a cold
         //   range if the prior hot point is also in the bundle.
+        // - Likewise, does it contain code in cold blocks as well as other
+        //   code? If so, record the transitions into and out of cold blocks.
         // - Does the bundle cross any clobbering insts?
         //   If so, record the ProgPoint before each such instruction.
         // - Is there a register use before the conflicting bundle?
@@ -2004,6 +4162,7 @@ impl<'a, F: Function> Env<'a, F> {
         // Then choose one of the above kinds of splits, in priority order.
 
         let mut cold_hot_splits: SmallVec<[ProgPoint; 4]> = smallvec![];
+        let mut cold_block_splits: SmallVec<[ProgPoint; 4]> = smallvec![];
         let mut clobber_splits: SmallVec<[ProgPoint; 4]> = smallvec![];
         let mut last_before_conflict: Option<ProgPoint> = None;
         let mut first_after_conflict: Option<ProgPoint> = None;
@@ -2091,6 +4250,25 @@ impl<'a, F: Function> Env<'a, F> {
                 }
             }
 
+            // Probe the cold-block tree in the same way.
+            if let Some(cold_range_idx) = self
+                .cold_code
+                .btree
+                .get(&LiveRangeKey::from_range(&our_range))
+            {
+                // Our range may begin or end with the cold range, with
+                // other code held in our neighbouring ranges, so split at
+                // any boundary within or at the edges of our range.
+                let cold_range = self.ranges[cold_range_idx.index()].range;
+                log::debug!("   -> overlaps with cold-block range {:?}", cold_range);
+                if our_range.from <= cold_range.from {
+                    cold_block_splits.push(cold_range.from);
+                }
+                if our_range.to >= cold_range.to {
+                    cold_block_splits.push(cold_range.to);
+                }
+            }
+
             // Scan through clobber-insts from last left-off position until the first
             // clobbering inst past this range. Record all clobber sites as potential
             // splits.
@@ -2114,7 +4292,7 @@ impl<'a, F: Function> Env<'a, F> {
 
             let mut update_with_pos = |pos: ProgPoint| {
                 let before_inst = ProgPoint::before(pos.inst);
-                let before_next_inst = before_inst.next().next();
+                let before_next_inst = ProgPoint::before(pos.inst.next());
                 if before_inst > bundle_start
                     && (conflict_from.is_none() || before_inst < conflict_from.unwrap())
                     && (last_before_conflict.is_none()
@@ -2135,12 +4313,10 @@ impl<'a, F: Function> Env<'a, F> {
                 log::debug!("   -> range has def at {:?}", def_data.pos);
                 update_with_pos(def_data.pos);
             }
-            let mut use_idx = self.ranges[our_iter.index()].first_use;
-            while use_idx.is_valid() {
+            for &use_idx in &self.ranges[our_iter.index()].uses {
                 let use_data = &self.uses[use_idx.index()];
                 log::debug!("   -> range has use at {:?}", use_data.pos);
                 update_with_pos(use_data.pos);
-                use_idx = use_data.next_use;
             }
 
             our_iter = self.ranges[our_iter.index()].next_in_bundle;
@@ -2156,35 +4332,125 @@ impl<'a, F: Function> Env<'a, F> {
 
         // Based on the above, we can determine which split strategy we are taking at this
         // iteration:
-        // - If we span both hot and cold code, split into separate "hot" and "cold" bundles.
+        // - If we span both cold blocks and other code, split at the
+        //   boundaries, so that moves land on the unlikely paths.
+        // - Otherwise, if we span both hot and cold code, split into separate
+        //   "hot" and "cold" bundles.
         // - Otherwise, if we span any calls, split just before every call instruction.
         // - Otherwise, if there is a register use after the conflicting bundle,
         //   split at that use-point ("split before first use").
         // - Otherwise, if there is a register use before the conflicting
         //   bundle, split at that use-point ("split after last use").
         // - Otherwise, split at every use, to form minimal bundles.
+        //
+        // No split may insert a move where the function forbids
+        // edits, so first move the candidates out of any restricted
+        // gaps; a candidate that no longer falls inside the bundle is
+        // dropped.
+        let legalize = |this: &Self, splits: &mut SmallVec<[ProgPoint; 4]>, later: bool| {
+            for split in splits.iter_mut() {
+                *split = this.legal_split_point(*split, later);
+            }
+            splits.retain(|split| *split > bundle_start && *split < bundle_end);
+            splits.dedup();
+        };
+        legalize(self, &mut cold_block_splits, false);
+        legalize(self, &mut cold_hot_splits, false);
+        legalize(self, &mut clobber_splits, false);
+        let first_after_conflict = first_after_conflict
+            .map(|split| self.legal_split_point(split, true))
+            .filter(|&split| split < bundle_end);
+        let last_before_conflict = last_before_conflict
+            .map(|split| self.legal_split_point(split, false))
+            .filter(|&split| split > bundle_start);
+
+        // The applicable kinds of split, in priority order. Splitting
+        // at every use always applies, as the last resort.
+        let mut candidates: SmallVec<[(SplitReason, SmallVec<[ProgPoint; 4]>); 4]> = smallvec![];
+        if !cold_block_splits.is_empty() {
+            candidates.push((SplitReason::ColdBlocks, cold_block_splits));
+        }
+        if !cold_hot_splits.is_empty() {
+            candidates.push((SplitReason::HotCode, cold_hot_splits));
+        }
+        if !clobber_splits.is_empty() {
+            candidates.push((SplitReason::Clobbers, clobber_splits));
+        }
+        if let Some(split) = first_after_conflict {
+            candidates.push((SplitReason::AfterConflict, smallvec![split]));
+        }
+        if let Some(split) = last_before_conflict {
+            candidates.push((SplitReason::BeforeConflict, smallvec![split]));
+        }
 
-        if cold_hot_splits.len() > 0 {
-            log::debug!(" going with cold/hot splits: {:?}", cold_hot_splits);
-            self.stats.splits_hot += 1;
-            cold_hot_splits
-        } else if clobber_splits.len() > 0 {
-            log::debug!(" going with clobber splits: {:?}", clobber_splits);
-            self.stats.splits_clobbers += 1;
-            clobber_splits
-        } else if first_after_conflict.is_some() {
-            self.stats.splits_conflicts += 1;
-            log::debug!(" going with first after conflict");
-            smallvec![first_after_conflict.unwrap()]
-        } else if last_before_conflict.is_some() {
-            self.stats.splits_conflicts += 1;
-            log::debug!(" going with last before conflict");
-            smallvec![last_before_conflict.unwrap()]
+        // In chaos mode, take any of them; if comparing splits, the
+        // cheapest (the earliest in priority order among equals);
+        // otherwise the first.
+        let choice = if let Some(choice) = self.chaos_choice(candidates.len() + 1) {
+            choice
+        } else if self.opts.compare_splits {
+            let all_uses = self.find_all_use_split_points(bundle);
+            candidates.push((SplitReason::AllUses, all_uses));
+            (0..candidates.len())
+                .min_by_key(|&i| self.split_cost(&candidates[i].1))
+                .unwrap()
+        } else {
+            0
+        };
+        let (reason, splits) = if choice < candidates.len() {
+            candidates.swap_remove(choice)
         } else {
-            self.stats.splits_all += 1;
-            log::debug!(" splitting at all uses");
-            self.find_all_use_split_points(bundle)
+            (SplitReason::AllUses, self.find_all_use_split_points(bundle))
+        };
+        log::debug!(" going with {:?} splits: {:?}", reason, splits);
+        match reason {
+            SplitReason::HotCode => self.stats.splits_hot += 1,
+            SplitReason::ColdBlocks => self.stats.splits_cold += 1,
+            SplitReason::Clobbers => self.stats.splits_clobbers += 1,
+            SplitReason::AfterConflict | SplitReason::BeforeConflict => {
+                self.stats.splits_conflicts += 1
+            }
+            SplitReason::AllUses => self.stats.splits_all += 1,
+        }
+        (reason, splits)
+    }
+
+    /// The estimated execution frequency of `block`: 8^(loop depth),
+    /// with the depth capped at 10, or zero if the function marks the
+    /// block cold.
+    fn block_weight(&self, block: Block) -> usize {
+        if self.func.is_cold_block(block) {
+            return 0;
+        }
+        let depth = std::cmp::min(self.cfginfo.loop_depth[block.index()], 10);
+        1usize << (3 * depth)
+    }
+
+    /// The modeled cost of splitting a bundle at `points`: a move at
+    /// each point, weighted by the frequency of its block. A point at
+    /// a block entry instead needs a move on each in-edge from an
+    /// earlier block, placed in the predecessor if there are several.
+    /// A point past the last instruction, which splitting at every use
+    /// can give, needs no move and costs nothing.
+    fn split_cost(&self, points: &[ProgPoint]) -> usize {
+        let mut cost = 0;
+        for &point in points {
+            if point.inst.index() >= self.func.insts() {
+                continue;
+            }
+            let block = self.cfginfo.insn_block[point.inst.index()];
+            if point != self.cfginfo.block_entry[block.index()] {
+                cost += self.block_weight(block);
+                continue;
+            }
+            let preds = self.func.block_preds(block);
+            for &pred in preds {
+                if self.cfginfo.block_exit[pred.index()] < point {
+                    cost += self.block_weight(if preds.len() > 1 { pred } else { block });
+                }
+            }
         }
+        cost
     }
 
     fn find_all_use_split_points(&self, bundle: LiveBundleIndex) -> SmallVec<[ProgPoint; 4]> {
@@ -2221,6 +4487,9 @@ impl<'a, F: Function> Env<'a, F> {
                     def_pos,
                     def_end,
                 );
+                // The def's range starts at `def_pos`, so a split there
+                // never needs a move even in a restricted gap.
+                let def_end = self.legal_split_point(def_end, true);
                 if def_pos > bundle_start {
                     splits.push(def_pos);
                 }
@@ -2228,11 +4497,12 @@ impl<'a, F: Function> Env<'a, F> {
                     splits.push(def_end);
                 }
             }
-            let mut use_idx = rangedata.first_use;
-            while use_idx.is_valid() {
+            for &use_idx in &rangedata.uses {
                 let use_data = &self.uses[use_idx.index()];
                 let before_use_inst = ProgPoint::before(use_data.pos.inst);
-                let after_use_inst = before_use_inst.next().next();
+                let after_use_inst = ProgPoint::before(use_data.pos.inst.next());
+                let before_use_inst = self.legal_split_point(before_use_inst, false);
+                let after_use_inst = self.legal_split_point(after_use_inst, true);
                 log::debug!(
                     "  -> splitting before and after use: {:?} and {:?}",
                     before_use_inst,
@@ -2242,11 +4512,35 @@ impl<'a, F: Function> Env<'a, F> {
                     splits.push(before_use_inst);
                 }
                 splits.push(after_use_inst);
-                use_idx = use_data.next_use;
             }
 
             iter = rangedata.next_in_bundle;
         }
+
+        // Within a run of insts joined by restricted gaps, the points
+        // above may all have moved to the run's ends. Splitting between
+        // the bundle's ranges still makes progress without a move, as
+        // long as the split does not cut one vreg's value in two.
+        let first_range = self.bundles[bundle.index()].first_range;
+        if first_range.is_valid()
+            && !splits
+                .iter()
+                .any(|&split| split > bundle_start && split < bundle_end)
+        {
+            let mut prev = first_range;
+            let mut iter = self.ranges[prev.index()].next_in_bundle;
+            while iter.is_valid() {
+                let (prev_data, data) = (&self.ranges[prev.index()], &self.ranges[iter.index()]);
+                if !self.is_restricted_point(data.range.from)
+                    || prev_data.range.to < data.range.from
+                    || prev_data.vreg != data.vreg
+                {
+                    splits.push(data.range.from);
+                }
+                prev = iter;
+                iter = data.next_in_bundle;
+            }
+        }
         splits.sort();
         log::debug!(" -> final splits: {:?}", splits);
         splits
@@ -2270,7 +4564,39 @@ impl<'a, F: Function> Env<'a, F> {
         // around each individual def/use (this is step (v)
         // above). This ensures termination eventually.
 
-        let split_points = self.find_split_points(bundle, first_conflicting_bundle);
+        let (reason, mut split_points) = self.find_split_points(bundle, first_conflicting_bundle);
+        self.canonicalize_split_points(bundle, &mut split_points);
+        if split_points.is_empty() && self.bundle_no_spill(bundle) {
+            // A bundle that must not spill may have no uses left to
+            // split around; halve it instead, so that its pieces
+            // eventually become minimal.
+            split_points.push(self.bundle_midpoint(bundle));
+            self.canonicalize_split_points(bundle, &mut split_points);
+        }
+        self.requeue_split_bundle(bundle, first_conflicting_bundle, reason, split_points);
+    }
+
+    /// A legal split point halfway through `bundle`'s extent.
+    fn bundle_midpoint(&self, bundle: LiveBundleIndex) -> ProgPoint {
+        let first = self.ranges[self.bundles[bundle.index()].first_range.index()]
+            .range
+            .from;
+        let last = self.ranges[self.bundles[bundle.index()].last_range.index()]
+            .range
+            .to;
+        let mid = Inst::new((first.inst.index() + last.inst.index()).div_ceil(2));
+        self.legal_split_point(ProgPoint::before(mid), false)
+    }
+
+    /// Split `bundle` at `split_points`, for `reason`, and put the
+    /// pieces on the allocation queue.
+    fn requeue_split_bundle(
+        &mut self,
+        bundle: LiveBundleIndex,
+        first_conflicting_bundle: LiveBundleIndex,
+        reason: SplitReason,
+        split_points: SmallVec<[ProgPoint; 4]>,
+    ) {
         log::debug!(
             "split bundle {:?} (conflict {:?}): split points {:?}",
             bundle,
@@ -2278,6 +4604,85 @@ impl<'a, F: Function> Env<'a, F> {
             split_points
         );
 
+        if self.opts.explain {
+            let conflict = if first_conflicting_bundle.is_valid() {
+                Some(self.bundle_vreg(first_conflicting_bundle))
+            } else {
+                None
+            };
+            let at = split_points.to_vec();
+            self.explain(bundle, |range| AllocationEvent::Split {
+                range,
+                at: at.clone(),
+                reason,
+                conflict,
+            });
+        }
+
+        let new_bundles = self.split_bundle_at(bundle, &split_points[..]);
+
+        // Enqueue all split-bundles on the allocation queue.
+        self.count_requeue(bundle);
+        let prio = self.compute_bundle_prio(bundle);
+        self.bundles[bundle.index()].prio = prio;
+        self.recompute_bundle_properties(bundle);
+        self.allocation_queue.insert(bundle, prio as usize);
+        for b in new_bundles {
+            let prio = self.compute_bundle_prio(b);
+            self.bundles[b.index()].prio = prio;
+            self.recompute_bundle_properties(b);
+            self.allocation_queue.insert(b, prio as usize);
+        }
+    }
+
+    /// Sort and deduplicate split points, and drop any that would
+    /// leave an empty piece: one at or beyond either end of the
+    /// bundle, or with no part of the bundle between it and the
+    /// previous point. Such pieces would only cycle through the
+    /// allocation queue again.
+    fn canonicalize_split_points(
+        &self,
+        bundle: LiveBundleIndex,
+        points: &mut SmallVec<[ProgPoint; 4]>,
+    ) {
+        points.sort();
+        points.dedup();
+        let mut ranges: SmallVec<[CodeRange; 4]> = smallvec![];
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            ranges.push(self.ranges[iter.index()].range);
+            iter = self.ranges[iter.index()].next_in_bundle;
+        }
+        let (start, end) = match (ranges.first(), ranges.last()) {
+            (Some(first), Some(last)) => (first.from, last.to),
+            _ => return,
+        };
+        let covered = |from: ProgPoint, to: ProgPoint| {
+            ranges
+                .iter()
+                .any(|range| range.from < to && range.to > from)
+        };
+        let mut prev = start;
+        points.retain(|point| {
+            let point = *point;
+            if covered(prev, point) && covered(point, end) {
+                prev = point;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Split `bundle` at the given (sorted) program points, returning
+    /// the newly created bundles. The original bundle keeps the
+    /// leading part. Neither the original nor the new bundles are
+    /// requeued or have their properties recomputed.
+    fn split_bundle_at(
+        &mut self,
+        bundle: LiveBundleIndex,
+        split_points: &[ProgPoint],
+    ) -> LiveBundleVec {
         // Split `bundle` at every ProgPoint in `split_points`,
         // creating new LiveRanges and bundles (and updating vregs'
         // linked lists appropriately), and enqueue the new bundles.
@@ -2320,6 +4725,10 @@ impl<'a, F: Function> Env<'a, F> {
             split_idx += 1;
         }
 
+        // The ranges are about to shrink, so previously found fixed
+        // conflicts may no longer apply.
+        self.bundles[bundle.index()].fixed_conflicts.clear();
+
         let mut new_bundles: LiveBundleVec = smallvec![];
         let mut cur_bundle = bundle;
         let mut iter = self.bundles[bundle.index()].first_range;
@@ -2415,18 +4824,15 @@ impl<'a, F: Function> Env<'a, F> {
                     rest_range
                 );
 
-                // Scan over uses, accumulating stats for those that
-                // stay in the first range, finding the first use that
-                // moves to the rest range.
-                let mut last_use_in_first_range = UseIndex::invalid();
-                let mut use_iter = self.ranges[iter.index()].first_use;
+                // Find the first use that moves to the rest range,
+                // accumulating stats for those that stay in the first
+                // range.
+                let uses = &self.uses;
+                let first_uses = &self.ranges[iter.index()].uses;
+                let split_idx = first_uses.partition_point(|u| uses[u.index()].pos < split_point);
                 let mut num_fixed_uses = 0;
                 let mut uses_spill_weight = 0;
-                while use_iter.is_valid() {
-                    if self.uses[use_iter.index()].pos >= split_point {
-                        break;
-                    }
-                    last_use_in_first_range = use_iter;
+                for &use_iter in &first_uses[..split_idx] {
                     let policy = self.uses[use_iter.index()].operand.policy();
                     log::debug!(
                         " -> use {:?} before split point; policy {:?}",
@@ -2438,25 +4844,17 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                     uses_spill_weight += spill_weight_from_policy(policy);
                     log::debug!("   -> use {:?} remains in orig", use_iter);
-                    use_iter = self.uses[use_iter.index()].next_use;
                 }
 
                 // Move over `rest`'s uses and update stats on first
                 // and rest LRs.
-                if use_iter.is_valid() {
+                if split_idx < first_uses.len() {
                     log::debug!(
                         "   -> moving uses over the split starting at {:?}",
-                        use_iter
+                        first_uses[split_idx]
                     );
-                    self.ranges[rest_lr.index()].first_use = use_iter;
-                    self.ranges[rest_lr.index()].last_use = self.ranges[iter.index()].last_use;
-
-                    self.ranges[iter.index()].last_use = last_use_in_first_range;
-                    if last_use_in_first_range.is_valid() {
-                        self.uses[last_use_in_first_range.index()].next_use = UseIndex::invalid();
-                    } else {
-                        self.ranges[iter.index()].first_use = UseIndex::invalid();
-                    }
+                    let rest_uses = self.ranges[iter.index()].uses.drain(split_idx..).collect();
+                    self.ranges[rest_lr.index()].uses = rest_uses;
 
                     let rest_fixed_uses =
                         self.ranges[iter.index()].num_fixed_uses() - num_fixed_uses;
@@ -2501,25 +4899,71 @@ impl<'a, F: Function> Env<'a, F> {
             iter = next;
         }
 
-        // Enqueue all split-bundles on the allocation queue.
-        let prio = self.compute_bundle_prio(bundle);
-        self.bundles[bundle.index()].prio = prio;
-        self.recompute_bundle_properties(bundle);
-        self.allocation_queue.insert(bundle, prio as usize);
-        for b in new_bundles {
-            let prio = self.compute_bundle_prio(b);
-            self.bundles[b.index()].prio = prio;
-            self.recompute_bundle_properties(b);
-            self.allocation_queue.insert(b, prio as usize);
+        new_bundles
+    }
+
+    /// Would allocating `bundle` to `preg` keep a value in it across
+    /// one of its soft clobbers?
+    fn relies_on_soft_clobber(&self, bundle: LiveBundleIndex, preg: PReg) -> bool {
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            let range = self.ranges[iter.index()].range;
+            if soft_clobbers_in(&self.soft_clobbers, range)
+                .iter()
+                .any(|&(_, reg)| reg == preg)
+            {
+                return true;
+            }
+            iter = self.ranges[iter.index()].next_in_bundle;
         }
+        false
     }
 
     fn process_bundle(&mut self, bundle: LiveBundleIndex) {
         // Find any requirements: for every LR, for every def/use, gather
         // requirements (fixed-reg, any-reg, any) and merge them.
         let req = self.compute_requirement(bundle);
+        // Registers that we must not probe, if any.
+        let excluded = self.compute_excluded_regs(bundle);
+        // A bundle that must not spill needs a register even where
+        // none of its operands do.
+        let no_spill = matches!(req, Some(Requirement::Any(_))) && self.bundle_no_spill(bundle);
+        let req = match req {
+            // A fixed register that is also excluded is as much a
+            // conflict as two different fixed registers.
+            Some(Requirement::Fixed(preg)) if excluded.contains(&preg) => None,
+            Some(Requirement::Any(class)) if no_spill => Some(Requirement::Register(class)),
+            // A stack operand of another vreg merged in with one that
+            // must not spill is a conflict too: splitting separates
+            // them, rather than taking the latter to the stack.
+            Some(Requirement::Stack(_))
+                if !self.minimal_bundle(bundle) && self.bundle_no_spill(bundle) =>
+            {
+                None
+            }
+            req => req,
+        };
+        // A bundle whose spillset has churned through the queue too
+        // often goes to its spillslot between its uses, however the
+        // churn arose; see `MAX_REQUEUES`. If it must not spill, or is
+        // split as far as it goes, it force-spills whatever it evicts
+        // below instead, so that those cannot come back whole to evict
+        // it again.
+        let spillset = self.bundles[bundle.index()].spillset;
+        let churned =
+            self.requeue_limit_hit || self.spillsets[spillset.index()].requeues > MAX_REQUEUES;
+        if churned
+            && !self.bundle_no_spill(bundle)
+            && !self.minimal_bundle(bundle)
+            && self.force_spill_bundle(bundle, LiveBundleIndex::invalid())
+        {
+            log::debug!(" -> requeued too often; force-spilled {:?}", bundle);
+            self.stats.requeue_limit_spills += 1;
+            self.trace_outcome(DecisionOutcome::ForceSpilled);
+            return;
+        }
         // Grab a hint from our spillset, if any.
-        let hint_reg = self.spillsets[self.bundles[bundle.index()].spillset.index()].reg_hint;
+        let hint_reg = self.spillsets[spillset.index()].reg_hint;
         log::debug!(
             "process_bundle: bundle {:?} requirement {:?} hint {:?}",
             bundle,
@@ -2545,10 +4989,13 @@ impl<'a, F: Function> Env<'a, F> {
                 Requirement::Fixed(preg) => {
                     let preg_idx = PRegIndex::new(preg.index());
                     self.stats.process_bundle_reg_probes_fixed += 1;
-                    match self.try_to_allocate_bundle_to_reg(bundle, preg_idx) {
+                    let result = self.probe_bundle_reg(bundle, preg_idx);
+                    self.trace_probe(preg_idx, &result);
+                    match result {
                         AllocRegResult::Allocated(alloc) => {
                             self.stats.process_bundle_reg_success_fixed += 1;
                             log::debug!(" -> allocated to fixed {:?}", preg_idx);
+                            self.trace_outcome(DecisionOutcome::Allocated(preg));
                             self.spillsets[self.bundles[bundle.index()].spillset.index()]
                                 .reg_hint = Some(alloc.as_reg().unwrap());
                             return;
@@ -2562,66 +5009,102 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                 }
                 Requirement::Register(class) => {
+                    // If scoring, take the best free register as the
+                    // hint, so that it is probed first.
+                    let score = self.opts.score_free_regs && self.opts.chaos_seed.is_none();
+                    let hint_reg = if score {
+                        self.best_free_reg(bundle, class, hint_reg, &excluded)
+                            .or(hint_reg)
+                    } else {
+                        hint_reg
+                    };
                     // Scan all pregs and attempt to allocate.
                     let mut lowest_cost_conflict_set: Option<LiveBundleVec> = None;
+                    let across_call = self.bundle_crosses_call(bundle);
                     let n_regs = self.env.regs_by_class[class as u8 as usize].len();
+                    let start = self.chaos_choice(n_regs).unwrap_or(bundle.index());
                     let loop_count = if hint_reg.is_some() {
                         n_regs + 1
                     } else {
                         n_regs
                     };
-                    for i in 0..loop_count {
-                        // The order in which we try registers is somewhat complex:
-                        // - First, if there is a hint, we try that.
-                        // - Then, we try registers in a traversal
-                        //   order that is based on the bundle index,
-                        //   spreading pressure evenly among registers
-                        //   to reduce commitment-map
-                        //   contention. (TODO: account for
-                        //   caller-save vs. callee-saves here too.)
-                        //   Note that we avoid retrying the hint_reg;
-                        //   this is why the loop count is n_regs + 1
-                        //   if there is a hint reg, because we always
-                        //   skip one iteration.
-                        let preg = match (i, hint_reg) {
-                            (0, Some(hint_reg)) => hint_reg,
-                            (i, Some(hint_reg)) => {
-                                let reg = self.env.regs_by_class[class as u8 as usize]
-                                    [(i - 1 + bundle.index()) % n_regs];
-                                if reg == hint_reg {
-                                    continue;
+                    // If there are soft clobbers, probe in two passes:
+                    // first the registers that would not keep a value
+                    // across one, then the rest, so that a soft clobber
+                    // is relied on only when no other register is free.
+                    let passes = if self.soft_clobbers.is_empty() { 1 } else { 2 };
+                    for pass in 0..passes {
+                        for i in 0..loop_count {
+                            // The order in which we try registers is somewhat complex:
+                            // - First, if there is a hint, we try that.
+                            // - Then, we try registers in a traversal
+                            //   order that is cheapest first (see
+                            //   `MachineEnv::reg_costs`) and, among
+                            //   equally cheap registers, based on the
+                            //   bundle index, spreading pressure evenly
+                            //   among registers to reduce commitment-map
+                            //   contention. Among equally cheap
+                            //   registers, those the environment
+                            //   prefers for a bundle that lives across
+                            //   a call, or not, come first (see
+                            //   `MachineEnv::preferred_regs_by_class`).
+                            //   Note that we avoid retrying the hint_reg;
+                            //   this is why the loop count is n_regs + 1
+                            //   if there is a hint reg, because we always
+                            //   skip one iteration.
+                            let preg = match (i, hint_reg) {
+                                (0, Some(hint_reg)) => hint_reg,
+                                (i, Some(hint_reg)) => {
+                                    let reg = self.probe_reg(class, across_call, i - 1, start);
+                                    if reg == hint_reg {
+                                        continue;
+                                    }
+                                    reg
                                 }
-                                reg
+                                (i, None) => self.probe_reg(class, across_call, i, start),
+                            };
+                            if excluded.contains(&preg) {
+                                continue;
                             }
-                            (i, None) => {
-                                self.env.regs_by_class[class as u8 as usize]
-                                    [(i + bundle.index()) % n_regs]
+                            if passes == 2
+                                && self.relies_on_soft_clobber(bundle, preg) != (pass == 1)
+                            {
+                                continue;
                             }
-                        };
 
-                        self.stats.process_bundle_reg_probes_any += 1;
-                        let preg_idx = PRegIndex::new(preg.index());
-                        match self.try_to_allocate_bundle_to_reg(bundle, preg_idx) {
-                            AllocRegResult::Allocated(alloc) => {
-                                self.stats.process_bundle_reg_success_any += 1;
-                                log::debug!(" -> allocated to any {:?}", preg_idx);
-                                self.spillsets[self.bundles[bundle.index()].spillset.index()]
-                                    .reg_hint = Some(alloc.as_reg().unwrap());
-                                return;
-                            }
-                            AllocRegResult::Conflict(bundles) => {
-                                if lowest_cost_conflict_set.is_none() {
-                                    lowest_cost_conflict_set = Some(bundles);
-                                } else if self.maximum_spill_weight_in_bundle_set(&bundles)
-                                    < self.maximum_spill_weight_in_bundle_set(
-                                        lowest_cost_conflict_set.as_ref().unwrap(),
-                                    )
-                                {
-                                    lowest_cost_conflict_set = Some(bundles);
+                            self.stats.process_bundle_reg_probes_any += 1;
+                            let preg_idx = PRegIndex::new(preg.index());
+                            let result = self.probe_bundle_reg(bundle, preg_idx);
+                            self.trace_probe(preg_idx, &result);
+                            match result {
+                                AllocRegResult::Allocated(alloc) => {
+                                    self.stats.process_bundle_reg_success_any += 1;
+                                    log::debug!(" -> allocated to any {:?}", preg_idx);
+                                    self.trace_outcome(DecisionOutcome::Allocated(preg));
+                                    self.spillsets[self.bundles[bundle.index()].spillset.index()]
+                                        .reg_hint = Some(alloc.as_reg().unwrap());
+                                    return;
+                                }
+                                AllocRegResult::Conflict(bundles) => {
+                                    if lowest_cost_conflict_set.is_none() {
+                                        lowest_cost_conflict_set = Some(bundles);
+                                    } else {
+                                        let cost =
+                                            self.maximum_spill_weight_in_bundle_set(&bundles);
+                                        let lowest_cost = self.maximum_spill_weight_in_bundle_set(
+                                            lowest_cost_conflict_set.as_ref().unwrap(),
+                                        );
+                                        if cost < lowest_cost
+                                            || (cost == lowest_cost
+                                                && self.chaos_choice(2) == Some(0))
+                                        {
+                                            lowest_cost_conflict_set = Some(bundles);
+                                        }
+                                    }
+                                }
+                                AllocRegResult::ConflictWithFixed => {
+                                    // Simply don't consider as an option.
                                 }
-                            }
-                            AllocRegResult::ConflictWithFixed => {
-                                // Simply don't consider as an option.
                             }
                         }
                     }
@@ -2635,11 +5118,24 @@ impl<'a, F: Function> Env<'a, F> {
                     lowest_cost_conflict_set.unwrap_or(smallvec![])
                 }
 
+                Requirement::Stack(_) => {
+                    // The bundle must be in its spillslot; there is
+                    // no register to retry later.
+                    log::debug!("spilling bundle {:?} to its spillset", bundle);
+                    self.explain(bundle, |range| AllocationEvent::Spilled { range });
+                    self.spillsets[self.bundles[bundle.index()].spillset.index()]
+                        .bundles
+                        .push(bundle);
+                    self.trace_outcome(DecisionOutcome::Spilled);
+                    return;
+                }
+
                 Requirement::Any(_) => {
                     // If a register is not *required*, spill now (we'll retry
                     // allocation on spilled bundles later).
                     log::debug!("spilling bundle {:?} to spilled_bundles list", bundle);
                     self.spilled_bundles.push(bundle);
+                    self.trace_outcome(DecisionOutcome::Deferred);
                     return;
                 }
             };
@@ -2648,7 +5144,8 @@ impl<'a, F: Function> Env<'a, F> {
 
             // If we have already tried evictions once before and are still unsuccessful, give up
             // and move on to splitting as long as this is not a minimal bundle.
-            if attempts >= 2 && !self.minimal_bundle(bundle) {
+            if attempts >= self.opts.eviction_attempts.unwrap_or(2) && !self.minimal_bundle(bundle)
+            {
                 break;
             }
 
@@ -2668,47 +5165,212 @@ impl<'a, F: Function> Env<'a, F> {
                 break;
             }
 
+            // If evicting would run away, force the cheapest bundle
+            // involved that can be split into its spillslot, and may
+            // be, instead; if that is this one, give up on a register
+            // for it.
+            let mut force_spilled = LiveBundleIndex::invalid();
+            if self.is_eviction_cascade(bundle, &conflicting_bundles) {
+                self.stats.eviction_cascades += 1;
+                let cheapest = std::iter::once(bundle)
+                    .chain(conflicting_bundles.iter().cloned())
+                    .filter(|&b| {
+                        !self.bundles[b.index()].cached_minimal() && !self.bundle_no_spill(b)
+                    })
+                    .min_by_key(|&b| self.bundle_spill_weight(b));
+                log::debug!(" -> eviction cascade; force-spilling {:?}", cheapest);
+                if let Some(cheapest) = cheapest {
+                    if cheapest != bundle {
+                        force_spilled = cheapest;
+                    } else if self.force_spill_bundle(bundle, conflicting_bundles[0]) {
+                        self.trace_outcome(DecisionOutcome::ForceSpilled);
+                        return;
+                    }
+                }
+            }
+
             // Evict all bundles in `conflicting bundles` and try again.
             self.stats.evict_bundle_event += 1;
-            for &bundle in &conflicting_bundles {
+            let depth = self.bundles[bundle.index()].eviction_depth + 1;
+            for &evicted in &conflicting_bundles {
+                if let Some(preg) = self.bundles[evicted.index()].allocation.as_reg() {
+                    if self.opts.explain {
+                        let by = self.bundle_vreg(bundle);
+                        self.explain(evicted, |range| AllocationEvent::Evicted {
+                            range,
+                            preg,
+                            by,
+                        });
+                    }
+                }
+                self.bundles[evicted.index()].evicted_by = bundle;
+                self.bundles[evicted.index()].eviction_depth = depth;
+                if evicted == force_spilled && self.force_spill_bundle(evicted, bundle) {
+                    continue;
+                }
+                if churned
+                    && !self.bundle_no_spill(evicted)
+                    && self.force_spill_bundle(evicted, bundle)
+                {
+                    log::debug!(
+                        " -> evicted by a churning bundle; force-spilled {:?}",
+                        evicted
+                    );
+                    self.stats.requeue_limit_spills += 1;
+                    continue;
+                }
+                let bundle = evicted;
                 log::debug!(" -> evicting {:?}", bundle);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(evicted = bundle.index(), "evict");
                 self.evict_bundle(bundle);
                 self.stats.evict_bundle_count += 1;
+                if let Some(decision) = self.decision.as_mut() {
+                    decision.evicted.push(bundle.index());
+                }
             }
         }
 
-        // A minimal bundle cannot be split.
+        // A minimal bundle cannot be split. If it needs a register
+        // only because it must not spill, leave it to the retry for
+        // free registers; if none is free then, allocation fails.
+        if no_spill && self.minimal_bundle(bundle) {
+            log::debug!(" -> no register for no-spill bundle {:?}", bundle);
+            self.spilled_bundles.push(bundle);
+            self.trace_outcome(DecisionOutcome::Deferred);
+            return;
+        }
         if self.minimal_bundle(bundle) {
             self.dump_state();
         }
         debug_assert!(!self.minimal_bundle(bundle));
 
+        self.trace_outcome(DecisionOutcome::Split);
         self.split_and_requeue_bundle(
             bundle,
             first_conflicting_bundle.unwrap_or(LiveBundleIndex::invalid()),
         );
     }
 
-    fn try_allocating_regs_for_spilled_bundles(&mut self) {
-        for i in 0..self.spilled_bundles.len() {
-            let bundle = self.spilled_bundles[i]; // don't borrow self
-            let any_vreg = self.vregs[self.ranges
-                [self.bundles[bundle.index()].first_range.index()]
-            .vreg
-            .index()]
-            .reg;
-            let class = any_vreg.class();
-            let mut success = false;
-            self.stats.spill_bundle_reg_probes += 1;
-            let nregs = self.env.regs_by_class[class as u8 as usize].len();
-            for i in 0..nregs {
-                let i = (i + bundle.index()) % nregs;
-                let preg = self.env.regs_by_class[class as u8 as usize][i]; // don't borrow self
-                let preg_idx = PRegIndex::new(preg.index());
-                if let AllocRegResult::Allocated(_) =
-                    self.try_to_allocate_bundle_to_reg(bundle, preg_idx)
-                {
-                    self.stats.spill_bundle_reg_success += 1;
+    /// The preference of the vregs in `bundle` for where to go
+    /// without a register; see `Function::spill_preference`.
+    fn bundle_spill_preference(&self, bundle: LiveBundleIndex) -> SpillPreference {
+        let mut all_memory = true;
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            let vreg = self.vregs[self.ranges[iter.index()].vreg.index()].reg;
+            match self.func.spill_preference(vreg) {
+                SpillPreference::CalleeSavedReg => return SpillPreference::CalleeSavedReg,
+                SpillPreference::Memory => {}
+                SpillPreference::Default => all_memory = false,
+            }
+            iter = self.ranges[iter.index()].next_in_bundle;
+        }
+        if all_memory {
+            SpillPreference::Memory
+        } else {
+            SpillPreference::Default
+        }
+    }
+
+    /// Does `bundle` hold a vreg that must not spill? See
+    /// `Function::no_spill`.
+    fn bundle_no_spill(&self, bundle: LiveBundleIndex) -> bool {
+        let mut iter = self.bundles[bundle.index()].first_range;
+        while iter.is_valid() {
+            let vreg = self.ranges[iter.index()].vreg;
+            if vreg.is_valid() && self.func.no_spill(self.vregs[vreg.index()].reg) {
+                return true;
+            }
+            iter = self.ranges[iter.index()].next_in_bundle;
+        }
+        false
+    }
+
+    /// The first vreg that must not spill (see `Function::no_spill`)
+    /// but was allocated a spillslot anyway, with the inst where its
+    /// spilled range starts.
+    pub(crate) fn spilled_no_spill_vreg(&self) -> Option<(VReg, Inst)> {
+        for vreg in &self.vregs {
+            if !self.func.no_spill(vreg.reg) {
+                continue;
+            }
+            let mut iter = vreg.first_range;
+            while iter.is_valid() {
+                if self.get_alloc_for_range(iter).as_stack().is_some() {
+                    return Some((vreg.reg, self.ranges[iter.index()].range.from.inst));
+                }
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+        None
+    }
+
+    /// Allocate `bundle` to the first free callee-saved register of
+    /// `class` in probe order, if any.
+    fn try_allocating_callee_saved_reg(
+        &mut self,
+        bundle: LiveBundleIndex,
+        class: RegClass,
+    ) -> bool {
+        for i in 0..self.probe_regs_by_class[class as u8 as usize][1].len() {
+            let preg = self.probe_regs_by_class[class as u8 as usize][1][i];
+            if !self.env.callee_saved_regs.contains(&preg) {
+                continue;
+            }
+            let preg_idx = PRegIndex::new(preg.index());
+            if let AllocRegResult::Allocated(_) = self.probe_bundle_reg(bundle, preg_idx) {
+                log::debug!(" -> allocated to callee-saved {:?}", preg);
+                self.stats.callee_saved_reg_success += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn try_allocating_regs_for_spilled_bundles(&mut self) {
+        // Bundles that must not spill go first, and then those that
+        // prefer a callee-saved register, so that the others cannot
+        // take their registers. Both keys walk the bundle's ranges, so
+        // compute them once per bundle.
+        let mut spilled_bundles = std::mem::take(&mut self.spilled_bundles);
+        spilled_bundles.sort_by_cached_key(|&b| {
+            (
+                !self.bundle_no_spill(b),
+                self.bundle_spill_preference(b) != SpillPreference::CalleeSavedReg,
+            )
+        });
+        self.spilled_bundles = spilled_bundles;
+        for i in 0..self.spilled_bundles.len() {
+            let bundle = self.spilled_bundles[i]; // don't borrow self
+            let any_vreg = self.vregs[self.ranges
+                [self.bundles[bundle.index()].first_range.index()]
+            .vreg
+            .index()]
+            .reg;
+            let class = any_vreg.class();
+            // Bundles that prefer memory go straight there, unless they
+            // must not spill; those that prefer a callee-saved register
+            // try those first.
+            let pref = match self.bundle_spill_preference(bundle) {
+                SpillPreference::Memory if self.bundle_no_spill(bundle) => SpillPreference::Default,
+                pref => pref,
+            };
+            let mut success = pref == SpillPreference::CalleeSavedReg
+                && self.try_allocating_callee_saved_reg(bundle, class);
+            let nregs = if success || pref == SpillPreference::Memory {
+                0
+            } else {
+                self.stats.spill_bundle_reg_probes += 1;
+                self.env.regs_by_class[class as u8 as usize].len()
+            };
+            let start = self.chaos_choice(nregs.max(1)).unwrap_or(bundle.index());
+            let across_call = nregs > 0 && self.bundle_crosses_call(bundle);
+            for i in 0..nregs {
+                let preg = self.probe_reg(class, across_call, i, start);
+                let preg_idx = PRegIndex::new(preg.index());
+                if let AllocRegResult::Allocated(_) = self.probe_bundle_reg(bundle, preg_idx) {
+                    self.stats.spill_bundle_reg_success += 1;
                     success = true;
                     break;
                 }
@@ -2719,6 +5381,7 @@ impl<'a, F: Function> Env<'a, F> {
                     bundle,
                     self.bundles[bundle.index()].spillset
                 );
+                self.explain(bundle, |range| AllocationEvent::Spilled { range });
                 self.spillsets[self.bundles[bundle.index()].spillset.index()]
                     .bundles
                     .push(bundle);
@@ -2775,18 +5438,51 @@ impl<'a, F: Function> Env<'a, F> {
                 let range = self.ranges[iter.index()].range;
                 self.spillslots[spillslot.index()]
                     .ranges
-                    .btree
-                    .insert(LiveRangeKey::from_range(&range), iter);
+                    .insert(range, iter);
                 iter = self.ranges[iter.index()].next_in_bundle;
             }
         }
     }
 
     fn allocate_spillslots(&mut self) {
+        if self.opts.spillslot_per_vreg {
+            self.assign_vreg_spillslots();
+            return;
+        }
+        self.assign_spillslots(10);
+        if self.opts.shrink_frame {
+            self.merge_spillslots();
+        }
+        if let Some(max) = self.opts.max_spillslots {
+            if self.num_spillslots as usize > max {
+                // Over budget: start over, this time trying every
+                // existing slot before allocating a new one.
+                log::debug!(
+                    "{} spillslots exceed budget of {}; repacking",
+                    self.num_spillslots,
+                    max
+                );
+                self.spillslots.clear();
+                self.slots_by_size.clear();
+                for spillset in &mut self.spillsets {
+                    spillset.slot = SpillSlotIndex::invalid();
+                }
+                self.assign_spillslots(usize::MAX);
+            }
+        }
+
+        log::debug!("spillslot allocator done");
+    }
+
+    /// Assign each spillset to a spillslot, trying up to
+    /// `max_attempts` existing slots before allocating a new one.
+    fn assign_spillslots(&mut self, max_attempts: usize) {
         for spillset in 0..self.spillsets.len() {
             log::debug!("allocate spillslot: {}", spillset);
             let spillset = SpillSetIndex::new(spillset);
-            if self.spillsets[spillset.index()].bundles.is_empty() {
+            if self.spillsets[spillset.index()].bundles.is_empty()
+                || self.spillsets[spillset.index()].remat
+            {
                 continue;
             }
             // Get or create the spillslot list for this size.
@@ -2805,7 +5501,7 @@ impl<'a, F: Function> Env<'a, F> {
             let mut first_slot = SpillSlotIndex::invalid();
             let mut prev = SpillSlotIndex::invalid();
             let mut success = false;
-            for _attempt in 0..10 {
+            for _attempt in 0..max_attempts {
                 if spillslot_iter.is_invalid() {
                     break;
                 }
@@ -2865,8 +5561,98 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
-        // Assign actual slot indices to spillslots.
-        let mut offset: u32 = 0;
+        self.assign_spillslot_indices();
+    }
+
+    /// Give each vreg that is spilled anywhere (other than a constant
+    /// or rematerializable value)
+    /// a spillslot of its own, in vreg order; see
+    /// `RegallocOptions::spillslot_per_vreg`.
+    fn assign_vreg_spillslots(&mut self) {
+        let mut spilled = vec![false; self.vregs.len()];
+        for spillset in &self.spillsets {
+            if spillset.remat {
+                continue;
+            }
+            for &bundle in &spillset.bundles {
+                let mut iter = self.bundles[bundle.index()].first_range;
+                while iter.is_valid() {
+                    spilled[self.ranges[iter.index()].vreg.index()] = true;
+                    iter = self.ranges[iter.index()].next_in_bundle;
+                }
+            }
+        }
+        for (vreg, _) in spilled.iter().enumerate().filter(|(_, &spilled)| spilled) {
+            let reg = self.vregs[vreg].reg;
+            self.vregs[vreg].spillslot = SpillSlotIndex::new(self.spillslots.len());
+            self.spillslots.push(SpillSlotData {
+                ranges: LiveRangeSet::new(),
+                next_spillslot: SpillSlotIndex::invalid(),
+                size: self.func.spillslot_size(reg.class(), reg) as u32,
+                alloc: Allocation::none(),
+                class: reg.class(),
+            });
+        }
+        self.assign_spillslot_indices();
+    }
+
+    /// Merge spillslots of the same size and class whose occupants
+    /// never overlap. The first-fit assignment tries only a few
+    /// existing slots for each spillset, so it may open a new slot
+    /// where an older one would have done.
+    fn merge_spillslots(&mut self) {
+        let mut kept: Vec<SpillSlotIndex> = vec![];
+        let mut remap: Vec<SpillSlotIndex> = vec![];
+        for slot in 0..self.spillslots.len() {
+            let target = kept.iter().cloned().find(|&other| {
+                let a = &self.spillslots[other.index()];
+                let b = &self.spillslots[slot];
+                a.size == b.size
+                    && a.class == b.class
+                    && b.ranges
+                        .btree
+                        .keys()
+                        .all(|key| !a.ranges.btree.contains_key(key))
+            });
+            match target {
+                Some(other) => {
+                    log::debug!("merging spillslot {} into {}", slot, other.index());
+                    let ranges = std::mem::take(&mut self.spillslots[slot].ranges.btree);
+                    self.spillslots[other.index()].ranges.btree.extend(ranges);
+                    remap.push(remap[other.index()]);
+                    self.stats.spillslots_merged += 1;
+                }
+                None => {
+                    remap.push(SpillSlotIndex::new(kept.len()));
+                    kept.push(SpillSlotIndex::new(slot));
+                }
+            }
+        }
+        if kept.len() == self.spillslots.len() {
+            return;
+        }
+
+        // A slot survives if it is the one its occupants map to.
+        let mut slot = 0;
+        self.spillslots.retain(|_| {
+            let survives = kept[remap[slot].index()].index() == slot;
+            slot += 1;
+            survives
+        });
+        for spillset in &mut self.spillsets {
+            if spillset.slot.is_valid() {
+                spillset.slot = remap[spillset.slot.index()];
+            }
+        }
+        // The per-size free lists now refer to stale indices.
+        self.slots_by_size.clear();
+        self.assign_spillslot_indices();
+    }
+
+    /// Assign actual slot indices to spillslots, after any slots
+    /// reserved by the embedder.
+    fn assign_spillslot_indices(&mut self) {
+        let mut offset: u32 = self.func.num_reserved_spillslots() as u32;
         for data in &mut self.spillslots {
             // Align up to `size`.
             debug_assert!(data.size.is_power_of_two());
@@ -2880,8 +5666,90 @@ impl<'a, F: Function> Env<'a, F> {
             offset += data.size;
         }
         self.num_spillslots = offset;
+    }
 
-        log::debug!("spillslot allocator done");
+    /// The vregs with a spillslot extending beyond the first `max`
+    /// slots.
+    fn vregs_in_spillslots_beyond(&self, max: usize) -> Vec<VReg> {
+        let beyond = |slot: SpillSlotIndex| {
+            let data = &self.spillslots[slot.index()];
+            let slot = data.alloc.as_stack().unwrap().index();
+            let end = if self.func.multi_spillslot_named_by_last_slot() {
+                slot + 1
+            } else {
+                slot + data.size as usize
+            };
+            end > max
+        };
+        if self.opts.spillslot_per_vreg {
+            return self
+                .vregs
+                .iter()
+                .filter(|vreg| vreg.spillslot.is_valid() && beyond(vreg.spillslot))
+                .map(|vreg| vreg.reg)
+                .collect();
+        }
+
+        let mut vregs = vec![];
+        for spillset in &self.spillsets {
+            if spillset.slot.is_invalid() || !beyond(spillset.slot) {
+                continue;
+            }
+            for &bundle in &spillset.bundles {
+                let mut iter = self.bundles[bundle.index()].first_range;
+                while iter.is_valid() {
+                    vregs.push(self.vregs[self.ranges[iter.index()].vreg.index()].reg);
+                    iter = self.ranges[iter.index()].next_in_bundle;
+                }
+            }
+        }
+        vregs.sort_unstable_by_key(|vreg| vreg.vreg());
+        vregs.dedup();
+        vregs
+    }
+
+    /// Allocate a new spillslot, not shared with any vreg, after all
+    /// others.
+    fn allocate_dedicated_spillslot(&mut self, class: RegClass, size: u32) -> SpillSlot {
+        debug_assert!(size.is_power_of_two());
+        let offset = (self.num_spillslots + size - 1) & !(size - 1);
+        let slot = if self.func.multi_spillslot_named_by_last_slot() {
+            offset + size - 1
+        } else {
+            offset
+        };
+        let slot = SpillSlot::new(slot as usize, class);
+        self.spillslots.push(SpillSlotData {
+            ranges: LiveRangeSet::new(),
+            next_spillslot: SpillSlotIndex::invalid(),
+            size,
+            alloc: Allocation::stack(slot),
+            class,
+        });
+        self.num_spillslots = offset + size;
+        slot
+    }
+
+    fn compute_spillslot_offsets(&mut self) {
+        // Lay out the spillslots in bytes, in the same order as their
+        // slot indices, and record each slot's starting offset under
+        // the index that names it.
+        self.spillslot_offsets = vec![u32::MAX; self.num_spillslots as usize];
+        let mut offset: u32 = 0;
+        for data in &self.spillslots {
+            let layout = self.env.spillslot_layout_by_class[data.class as u8 as usize];
+            debug_assert!(layout.align.is_power_of_two());
+            offset = (offset + layout.align - 1) & !(layout.align - 1);
+            let slot = data.alloc.as_stack().unwrap();
+            self.spillslot_offsets[slot.index()] = offset;
+            offset += data.size * layout.unit_bytes;
+        }
+        self.spillslot_area_size = offset;
+        log::debug!(
+            "spillslot offsets: {:?}, area size {}",
+            self.spillslot_offsets,
+            self.spillslot_area_size
+        );
     }
 
     fn is_start_of_block(&self, pos: ProgPoint) -> bool {
@@ -2900,6 +5768,7 @@ impl<'a, F: Function> Env<'a, F> {
         from_alloc: Allocation,
         to_alloc: Allocation,
     ) {
+        debug_assert!(!pos.is_late(), "edit within an instruction at {:?}", pos);
         debug!(
             "insert_move: pos {:?} prio {:?} from_alloc {:?} to_alloc {:?}",
             pos, prio, from_alloc, to_alloc
@@ -2909,9 +5778,54 @@ impl<'a, F: Function> Env<'a, F> {
             prio,
             from_alloc,
             to_alloc,
+            remat: None,
+        });
+    }
+
+    fn insert_remat(
+        &mut self,
+        pos: ProgPoint,
+        prio: InsertMovePrio,
+        vreg: VReg,
+        to_alloc: Allocation,
+    ) {
+        debug_assert!(!pos.is_late(), "edit within an instruction at {:?}", pos);
+        debug!(
+            "insert_remat: pos {:?} prio {:?} vreg {:?} to_alloc {:?}",
+            pos, prio, vreg, to_alloc
+        );
+        self.inserted_moves.push(InsertedMove {
+            pos,
+            prio,
+            from_alloc: Allocation::none(),
+            to_alloc,
+            remat: Some(vreg),
         });
     }
 
+    /// Insert a move of `vreg`'s value from `from_alloc` to
+    /// `to_alloc`, where either may be the (`none`) location of a
+    /// spilled constant or rematerializable value: a move out of it
+    /// is a rematerialization and a move into it is dropped.
+    fn insert_move_or_remat(
+        &mut self,
+        pos: ProgPoint,
+        prio: InsertMovePrio,
+        vreg: VRegIndex,
+        from_alloc: Allocation,
+        to_alloc: Allocation,
+    ) {
+        if to_alloc == Allocation::none() {
+            debug_assert!(self.is_remat(vreg.index()));
+        } else if from_alloc == Allocation::none() {
+            debug_assert!(self.is_remat(vreg.index()));
+            let reg = self.vregs[vreg.index()].reg;
+            self.insert_remat(pos, prio, reg, to_alloc);
+        } else {
+            self.insert_move(pos, prio, from_alloc, to_alloc);
+        }
+    }
+
     fn get_alloc(&self, inst: Inst, slot: usize) -> Allocation {
         let inst_allocs = &self.allocs[self.inst_alloc_offsets[inst.index()] as usize..];
         inst_allocs[slot]
@@ -2926,13 +5840,76 @@ impl<'a, F: Function> Env<'a, F> {
         let bundledata = &self.bundles[self.ranges[range.index()].bundle.index()];
         if bundledata.allocation != Allocation::none() {
             bundledata.allocation
+        } else if self.spillsets[bundledata.spillset.index()].remat {
+            // A spilled constant or rematerializable value has no
+            // location at all.
+            Allocation::none()
+        } else if self.opts.spillslot_per_vreg {
+            let vreg = self.ranges[range.index()].vreg;
+            self.spillslots[self.vregs[vreg.index()].spillslot.index()].alloc
         } else {
             self.spillslots[self.spillsets[bundledata.spillset.index()].slot.index()].alloc
         }
     }
 
+    /// Determine the ProgPoint where moves on the (from, to) edge
+    /// should go:
+    /// - If there is more than one in-edge to `to`, then
+    ///   `from` must have only one out-edge; moves go at tail of
+    ///   `from` just before last Branch/Ret.
+    /// - Otherwise, there must be at most one in-edge to `to`,
+    ///   and moves go at start of `to`.
+    fn edge_move_point(&self, from: Block, to: Block) -> (ProgPoint, InsertMovePrio) {
+        let from_last_insn = self.func.block_insns(from).last();
+        let to_first_insn = self.func.block_insns(to).first();
+        let from_is_ret = self.func.is_ret(from_last_insn);
+        let to_is_entry = self.func.entry_block() == to;
+        let from_outs = self.func.block_succs(from).len() + if from_is_ret { 1 } else { 0 };
+        let to_ins = self.func.block_preds(to).len() + if to_is_entry { 1 } else { 0 };
+
+        if to_ins > 1 && from_outs <= 1 && self.func.branch_has_delay_slot(from_last_insn) {
+            // Anything after a branch with a delay slot would run
+            // in the slot or not at all, so the moves go before
+            // it; any of its operands they overwrite are
+            // redirected in `apply_allocations_and_insert_moves`.
+            (
+                ProgPoint::before(from_last_insn),
+                InsertMovePrio::OutEdgeMoves,
+            )
+        } else if to_ins > 1 && from_outs <= 1 {
+            (
+                // N.B.: "after" the branch should be interpreted
+                // by the user as happening before the actual
+                // branching action, but after the branch reads
+                // all necessary inputs. It's necessary to do this
+                // rather than to place the moves before the
+                // branch because the branch may have other
+                // actions than just the control-flow transfer,
+                // and these other actions may require other
+                // inputs (which should be read before the "edge"
+                // moves).
+                //
+                // Edits will only appear after the last (branch)
+                // instruction if the block has only a single
+                // successor; we do not expect the user to somehow
+                // duplicate or predicate these.
+                ProgPoint::after(from_last_insn),
+                InsertMovePrio::OutEdgeMoves,
+            )
+        } else if to_ins <= 1 {
+            (
+                ProgPoint::before(to_first_insn),
+                InsertMovePrio::InEdgeMoves,
+            )
+        } else {
+            panic!(
+                "Critical edge: can't insert moves between blocks {:?} and {:?}",
+                from, to
+            );
+        }
+    }
+
     fn apply_allocations_and_insert_moves(&mut self) {
-        log::debug!("blockparam_ins: {:?}", self.blockparam_ins);
         log::debug!("blockparam_outs: {:?}", self.blockparam_outs);
 
         /// We create "half-moves" in order to allow a single-scan
@@ -2949,10 +5926,22 @@ impl<'a, F: Function> Env<'a, F> {
         /// means that a blockparam-out is also recognized as a normal-out),
         /// and then for each `Dest`, copy the source-alloc to that
         /// dest-alloc.
+        ///
+        /// Both halves of a move that carries a vreg across an edge
+        /// are found while scanning that vreg, so the half-moves are
+        /// resolved after each vreg and the list is reused: it only
+        /// ever holds one vreg's, rather than a list proportional to
+        /// edges times live vregs for the whole function. A move into
+        /// a blockparam is inserted directly where its source is
+        /// found, as the blockparam's allocation at the start of its
+        /// block is already known.
         #[derive(Clone, Debug, PartialEq, Eq)]
         struct HalfMove {
             key: u64,
             alloc: Allocation,
+            /// The vreg whose value is moved (for a `Source`) or
+            /// received (for a `Dest`).
+            vreg: VRegIndex,
         }
         #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
         #[repr(u8)]
@@ -2997,22 +5986,13 @@ impl<'a, F: Function> Env<'a, F> {
 
         let mut reuse_input_insts = vec![];
 
-        let mut blockparam_in_idx = 0;
         let mut blockparam_out_idx = 0;
         for vreg in 0..self.vregs.len() {
             let vreg = VRegIndex::new(vreg);
-            let defidx = self.vregs[vreg.index()].def;
-            let defining_block = if defidx.is_valid() {
-                self.cfginfo.insn_block[self.defs[defidx.index()].pos.inst.index()]
-            } else if self.vregs[vreg.index()].blockparam.is_valid() {
-                self.vregs[vreg.index()].blockparam
-            } else {
-                Block::invalid()
-            };
 
             // For each range in each vreg, insert moves or
-            // half-moves.  We also scan over `blockparam_ins` and
-            // `blockparam_outs`, which are sorted by (block, vreg).
+            // half-moves.  We also scan over `blockparam_outs`, which
+            // is sorted by (vreg, block).
             let mut iter = self.vregs[vreg.index()].first_range;
             let mut prev = LiveRangeIndex::invalid();
             while iter.is_valid() {
@@ -3025,7 +6005,7 @@ impl<'a, F: Function> Env<'a, F> {
                     range,
                     alloc
                 );
-                debug_assert!(alloc != Allocation::none());
+                debug_assert!(alloc != Allocation::none() || self.is_remat(vreg.index()));
 
                 if log::log_enabled!(log::Level::Debug) {
                     self.annotate(
@@ -3077,7 +6057,6 @@ impl<'a, F: Function> Env<'a, F> {
                     } else {
                         None
                     };
-                    debug_assert!(prev_alloc != Allocation::none());
                     if prev_range.to == range.from
                         && !self.is_start_of_block(range.from)
                         && def_pos != Some(range.from)
@@ -3091,7 +6070,13 @@ impl<'a, F: Function> Env<'a, F> {
                             vreg.index()
                         );
                         assert_eq!(range.from.pos, InstPosition::Before);
-                        self.insert_move(range.from, InsertMovePrio::Regular, prev_alloc, alloc);
+                        self.insert_move_or_remat(
+                            range.from,
+                            InsertMovePrio::Regular,
+                            vreg,
+                            prev_alloc,
+                            alloc,
+                        );
                     }
                 }
 
@@ -3116,11 +6101,15 @@ impl<'a, F: Function> Env<'a, F> {
                             continue;
                         }
                         log::debug!(" -> out of this range, requires half-move if live");
+                        // (A blockparam is not live into its block;
+                        // its own Source half-move is added from
+                        // `blockparam_outs` below.)
                         if self.liveins[succ.index()].get(vreg.index()) {
                             log::debug!("  -> live at input to succ, adding halfmove");
                             half_moves.push(HalfMove {
                                 key: half_move_key(block, succ, vreg, HalfMoveKind::Source),
                                 alloc,
+                                vreg,
                             });
                         }
                     }
@@ -3148,15 +6137,13 @@ impl<'a, F: Function> Env<'a, F> {
                                 to_vreg.index(),
                                 to_vreg.index()
                             );
-                            half_moves.push(HalfMove {
-                                key: half_move_key(
-                                    from_block,
-                                    to_block,
-                                    to_vreg,
-                                    HalfMoveKind::Source,
-                                ),
-                                alloc,
-                            });
+                            let entry = self.cfginfo.block_entry[to_block.index()];
+                            if let Some(to_range) = self.find_vreg_liverange_for_pos(to_vreg, entry)
+                            {
+                                let to_alloc = self.get_alloc_for_range(to_range);
+                                let (point, prio) = self.edge_move_point(from_block, to_block);
+                                self.insert_move_or_remat(point, prio, vreg, alloc, to_alloc);
+                            }
                             if log::log_enabled!(log::Level::Debug) {
                                 self.annotate(
                                     self.cfginfo.block_exit[block.index()],
@@ -3179,21 +6166,8 @@ impl<'a, F: Function> Env<'a, F> {
 
                 // Scan over blocks whose beginnings are covered by
                 // this range and for which the vreg is live at the
-                // start of the block, and for which the def of the
-                // vreg is not in this block. For each, for each
-                // predecessor, add a Dest half-move.
-                //
-                // N.B.: why "def of this vreg is not in this block"?
-                // Because live-range computation can over-approximate
-                // (due to the way that we handle loops in a single
-                // pass), especially if the program has irreducible
-                // control flow and/or if blocks are not in RPO, it
-                // may be the case that (i) the vreg is not *actually*
-                // live into this block, but is *defined* in this
-                // block. If the value is defined in this block,
-                // because this is SSA, the value cannot be used
-                // before the def and so we are not concerned about
-                // any incoming allocation for it.
+                // start of the block. For each, for each predecessor,
+                // add a Dest half-move.
                 let mut block = self.cfginfo.insn_block[range.from.inst.index()];
                 if self.cfginfo.block_entry[block.index()] < range.from {
                     block = block.next();
@@ -3203,56 +6177,9 @@ impl<'a, F: Function> Env<'a, F> {
                         break;
                     }
 
-                    // Add half-moves for blockparam inputs.
-                    log::debug!(
-                        "scanning blockparam_ins at vreg {} block {}: blockparam_in_idx = {}",
-                        vreg.index(),
-                        block.index(),
-                        blockparam_in_idx
-                    );
-                    while blockparam_in_idx < self.blockparam_ins.len() {
-                        let (to_vreg, to_block, from_block) =
-                            self.blockparam_ins[blockparam_in_idx];
-                        if (to_vreg, to_block) > (vreg, block) {
-                            break;
-                        }
-                        if (to_vreg, to_block) == (vreg, block) {
-                            half_moves.push(HalfMove {
-                                key: half_move_key(
-                                    from_block,
-                                    to_block,
-                                    to_vreg,
-                                    HalfMoveKind::Dest,
-                                ),
-                                alloc,
-                            });
-                            log::debug!(
-                                "match: blockparam_in: v{} in block{} from block{} into {}",
-                                to_vreg.index(),
-                                to_block.index(),
-                                from_block.index(),
-                                alloc,
-                            );
-                            if log::log_enabled!(log::Level::Debug) {
-                                self.annotate(
-                                    self.cfginfo.block_entry[block.index()],
-                                    format!(
-                                        "blockparam-in: block{} to block{}:into v{} in {}",
-                                        from_block.index(),
-                                        to_block.index(),
-                                        to_vreg.index(),
-                                        alloc
-                                    ),
-                                );
-                            }
-                        }
-                        blockparam_in_idx += 1;
-                    }
-
                     // The below (range incoming into block) must be
-                    // skipped if the def is in this block, as noted
-                    // above.
-                    if block == defining_block || !self.liveins[block.index()].get(vreg.index()) {
+                    // skipped if the vreg is not live into the block.
+                    if !self.liveins[block.index()].get(vreg.index()) {
                         block = block.next();
                         continue;
                     }
@@ -3278,6 +6205,7 @@ impl<'a, F: Function> Env<'a, F> {
                         half_moves.push(HalfMove {
                             key: half_move_key(pred, block, vreg, HalfMoveKind::Dest),
                             alloc,
+                            vreg,
                         });
                     }
 
@@ -3302,118 +6230,112 @@ impl<'a, F: Function> Env<'a, F> {
                     let operand = defdata.operand;
                     let inst = defdata.pos.inst;
                     let slot = defdata.slot;
+                    if operand.policy() == OperandPolicy::Constant && alloc != Allocation::none() {
+                        self.stats.constant_defs_count += 1;
+                    }
                     self.set_alloc(inst, slot, alloc);
                     if let OperandPolicy::Reuse(_) = operand.policy() {
                         reuse_input_insts.push(inst);
                     }
                 }
-                let mut use_iter = self.ranges[iter.index()].first_use;
-                while use_iter.is_valid() {
-                    let usedata = &self.uses[use_iter.index()];
+                for use_idx in self.ranges[iter.index()].uses.clone() {
+                    let usedata = &self.uses[use_idx.index()];
                     debug_assert!(range.contains_point(usedata.pos));
                     let inst = usedata.pos.inst;
                     let slot = usedata.slot;
-                    self.set_alloc(inst, slot, alloc);
-                    use_iter = self.uses[use_iter.index()].next_use;
+                    debug_assert!(alloc != Allocation::none());
+                    if slot != SLOT_NONE {
+                        self.set_alloc(inst, slot, alloc);
+                    }
                 }
 
                 prev = iter;
                 iter = self.ranges[iter.index()].next_in_reg;
             }
-        }
-
-        // Sort the half-moves list. For each (from, to,
-        // from-vreg) tuple, find the from-alloc and all the
-        // to-allocs, and insert moves on the block edge.
-        half_moves.sort_by_key(|h| h.key);
-        log::debug!("halfmoves: {:?}", half_moves);
-        self.stats.halfmoves_count = half_moves.len();
-
-        let mut i = 0;
-        while i < half_moves.len() {
-            // Find a Source.
-            while i < half_moves.len() && half_moves[i].kind() != HalfMoveKind::Source {
-                i += 1;
-            }
-            if i >= half_moves.len() {
-                break;
-            }
-            let src = &half_moves[i];
-            i += 1;
 
-            // Find all Dests.
-            let dest_key = src.key | 1;
-            let first_dest = i;
-            while i < half_moves.len() && half_moves[i].key == dest_key {
+            // Both halves of each of this vreg's edge moves have been
+            // found. For each (from, to, vreg) tuple, find the
+            // from-alloc and all the to-allocs, and insert moves on
+            // the block edge. The order among equal keys (the dests
+            // of one source) doesn't matter, so an in-place unstable
+            // sort suffices.
+            half_moves.sort_unstable_by_key(|h| h.key);
+            log::debug!("halfmoves: {:?}", half_moves);
+            self.stats.halfmoves_count += half_moves.len();
+
+            let mut i = 0;
+            while i < half_moves.len() {
+                // Find a Source.
+                while i < half_moves.len() && half_moves[i].kind() != HalfMoveKind::Source {
+                    i += 1;
+                }
+                if i >= half_moves.len() {
+                    break;
+                }
+                let src = &half_moves[i];
                 i += 1;
-            }
-            let last_dest = i;
 
-            log::debug!(
-                "halfmove match: src {:?} dests {:?}",
-                src,
-                &half_moves[first_dest..last_dest]
-            );
+                // Find all Dests.
+                let dest_key = src.key | 1;
+                let first_dest = i;
+                while i < half_moves.len() && half_moves[i].key == dest_key {
+                    i += 1;
+                }
+                let last_dest = i;
 
-            // Determine the ProgPoint where moves on this (from, to)
-            // edge should go:
-            // - If there is more than one in-edge to `to`, then
-            //   `from` must have only one out-edge; moves go at tail of
-            //   `from` just before last Branch/Ret.
-            // - Otherwise, there must be at most one in-edge to `to`,
-            //   and moves go at start of `to`.
-            let from_last_insn = self.func.block_insns(src.from_block()).last();
-            let to_first_insn = self.func.block_insns(src.to_block()).first();
-            let from_is_ret = self.func.is_ret(from_last_insn);
-            let to_is_entry = self.func.entry_block() == src.to_block();
-            let from_outs =
-                self.func.block_succs(src.from_block()).len() + if from_is_ret { 1 } else { 0 };
-            let to_ins =
-                self.func.block_preds(src.to_block()).len() + if to_is_entry { 1 } else { 0 };
-
-            let (insertion_point, prio) = if to_ins > 1 && from_outs <= 1 {
-                (
-                    // N.B.: "after" the branch should be interpreted
-                    // by the user as happening before the actual
-                    // branching action, but after the branch reads
-                    // all necessary inputs. It's necessary to do this
-                    // rather than to place the moves before the
-                    // branch because the branch may have other
-                    // actions than just the control-flow transfer,
-                    // and these other actions may require other
-                    // inputs (which should be read before the "edge"
-                    // moves).
-                    //
-                    // Edits will only appear after the last (branch)
-                    // instruction if the block has only a single
-                    // successor; we do not expect the user to somehow
-                    // duplicate or predicate these.
-                    ProgPoint::after(from_last_insn),
-                    InsertMovePrio::OutEdgeMoves,
-                )
-            } else if to_ins <= 1 {
-                (
-                    ProgPoint::before(to_first_insn),
-                    InsertMovePrio::InEdgeMoves,
-                )
-            } else {
-                panic!(
-                    "Critical edge: can't insert moves between blocks {:?} and {:?}",
-                    src.from_block(), src.to_block()
+                log::debug!(
+                    "halfmove match: src {:?} dests {:?}",
+                    src,
+                    &half_moves[first_dest..last_dest]
                 );
-            };
 
-            let mut last = None;
-            for dest in first_dest..last_dest {
-                let dest = &half_moves[dest];
-                debug_assert!(last != Some(dest.alloc));
-                self.insert_move(insertion_point, prio, src.alloc, dest.alloc);
-                last = Some(dest.alloc);
+                let (insertion_point, prio) =
+                    self.edge_move_point(src.from_block(), src.to_block());
+                let mut last = None;
+                for dest in &half_moves[first_dest..last_dest] {
+                    debug_assert!(last != Some(dest.alloc) || dest.alloc == Allocation::none());
+                    self.insert_move_or_remat(
+                        insertion_point,
+                        prio,
+                        src.vreg,
+                        src.alloc,
+                        dest.alloc,
+                    );
+                    last = Some(dest.alloc);
+                }
+            }
+            half_moves.clear();
+        }
+
+        // A branch arg read at the control transfer has no use to
+        // take its allocation from: it is wherever the out-edge moves
+        // find it, at the end of the block.
+        for block in 0..self.func.blocks() {
+            let block = Block::new(block);
+            let branch = self.func.block_insns(block).last();
+            if !self.func.is_branch(branch) {
+                continue;
             }
+            let func = self.func;
+            func.visit_operands(branch, &mut |slot, op| {
+                if !self.is_transfer_use(op) {
+                    return;
+                }
+                let vreg = VRegIndex::new(op.vreg().vreg());
+                let exit = self.cfginfo.block_exit[block.index()];
+                let range = self.find_vreg_liverange_for_pos(vreg, exit).unwrap();
+                let alloc = self.get_alloc_for_range(range);
+                self.set_alloc(branch, slot, alloc);
+            });
         }
 
+        // The blockparam list has been fully consumed by the scan
+        // above; release it so that it does not contribute to peak
+        // memory.
+        self.blockparam_outs = vec![];
+
         // Handle multi-fixed-reg constraints by copying.
-        for (progpoint, from_preg, to_preg) in
+        for (progpoint, from_preg, to_preg, slot) in
             std::mem::replace(&mut self.multi_fixed_reg_fixups, vec![])
         {
             log::debug!(
@@ -3422,12 +6344,71 @@ impl<'a, F: Function> Env<'a, F> {
                 from_preg.index(),
                 to_preg.index()
             );
+            // Uses in an inst with a reused input are placed at
+            // `After`, but the inst still reads the copy beforehand.
             self.insert_move(
-                progpoint,
+                ProgPoint::before(progpoint.inst),
                 InsertMovePrio::MultiFixedReg,
                 Allocation::reg(self.pregs[from_preg.index()].reg),
                 Allocation::reg(self.pregs[to_preg.index()].reg),
             );
+            self.set_alloc(
+                progpoint.inst,
+                slot,
+                Allocation::reg(self.pregs[to_preg.index()].reg),
+            );
+        }
+
+        // Move grouped fixed-reg uses into their registers, all at
+        // once.
+        for (inst, slot, preg) in std::mem::take(&mut self.grouped_fixed_uses) {
+            let from = self.get_alloc(inst, slot);
+            log::debug!(
+                "grouped fixed use at {:?} slot {}: {} -> {}",
+                inst,
+                slot,
+                from,
+                preg
+            );
+            self.insert_move(
+                ProgPoint::before(inst),
+                InsertMovePrio::GroupedFixedUses,
+                from,
+                Allocation::reg(preg),
+            );
+            self.set_alloc(inst, slot, Allocation::reg(preg));
+        }
+
+        // Copy operands with a fixed stack slot into it before the
+        // instruction, or out of it afterward.
+        for (inst, slot, kind, fixed) in std::mem::take(&mut self.fixed_stack_operands) {
+            let alloc = self.get_alloc(inst, slot);
+            let fixed = Allocation::stack(fixed);
+            log::debug!(
+                "fixed-stack {:?} at {:?} slot {}: {} <-> {}",
+                kind,
+                inst,
+                slot,
+                alloc,
+                fixed
+            );
+            match kind {
+                OperandKind::Use => self.insert_move(
+                    ProgPoint::before(inst),
+                    InsertMovePrio::FixedStack,
+                    alloc,
+                    fixed,
+                ),
+                OperandKind::Def if alloc != Allocation::none() => self.insert_move(
+                    ProgPoint::after(inst),
+                    InsertMovePrio::FixedStack,
+                    fixed,
+                    alloc,
+                ),
+                OperandKind::Def => {}
+                OperandKind::Mod => unreachable!("a Mod operand cannot have a fixed stack slot"),
+            }
+            self.set_alloc(inst, slot, fixed);
         }
 
         // Handle outputs that reuse inputs: copy beforehand, then set
@@ -3474,10 +6455,10 @@ impl<'a, F: Function> Env<'a, F> {
         // move instruction.
         //
         // [0] https://searchfox.org/mozilla-central/rev/3a798ef9252896fb389679f06dd3203169565af0/js/src/jit/shared/Lowering-shared-inl.h#108-110
+        let func = self.func;
         for inst in reuse_input_insts {
             let mut input_reused: SmallVec<[usize; 4]> = smallvec![];
-            for output_idx in 0..self.func.inst_operands(inst).len() {
-                let operand = self.func.inst_operands(inst)[output_idx];
+            func.visit_operands(inst, &mut |output_idx, operand| {
                 if let OperandPolicy::Reuse(input_idx) = operand.policy() {
                     debug_assert!(!input_reused.contains(&input_idx));
                     debug_assert_eq!(operand.pos(), OperandPos::After);
@@ -3508,7 +6489,7 @@ impl<'a, F: Function> Env<'a, F> {
                         self.set_alloc(inst, input_idx, output_alloc);
                     }
                 }
-            }
+            });
         }
     }
 
@@ -3518,10 +6499,21 @@ impl<'a, F: Function> Env<'a, F> {
         let mut i = 0;
         self.inserted_moves
             .sort_by_key(|m| (m.pos.to_index(), m.prio));
+        // The extent of all moves (of any priority) at the current
+        // program point; used when choosing a scratch register.
+        let mut pos_start = 0;
+        let mut pos_end = 0;
         while i < self.inserted_moves.len() {
             let start = i;
             let pos = self.inserted_moves[i].pos;
             let prio = self.inserted_moves[i].prio;
+            if start == pos_end {
+                pos_start = start;
+                while pos_end < self.inserted_moves.len() && self.inserted_moves[pos_end].pos == pos
+                {
+                    pos_end += 1;
+                }
+            }
             while i < self.inserted_moves.len()
                 && self.inserted_moves[i].pos == pos
                 && self.inserted_moves[i].prio == prio
@@ -3531,37 +6523,99 @@ impl<'a, F: Function> Env<'a, F> {
             let moves = &self.inserted_moves[start..i];
 
             // Get the regclass from one of the moves.
-            let regclass = moves[0].from_alloc.class();
+            let regclass = moves[0].to_alloc.class();
 
             // All moves in `moves` semantically happen in
             // parallel. Let's resolve these to a sequence of moves
             // that can be done one at a time.
-            let mut parallel_moves = ParallelMoves::new(Allocation::reg(
-                self.env.scratch_by_class[regclass as u8 as usize],
-            ));
+            let (scratch, save_scratch) = if i - start > 1 {
+                self.choose_scratch(regclass, pos, &self.inserted_moves[pos_start..pos_end])
+            } else {
+                (Allocation::none(), false)
+            };
+            let mut parallel_moves = ParallelMoves::new(scratch);
             log::debug!("parallel moves at pos {:?} prio {:?}", pos, prio);
+            let mut remats: SmallVec<[(VReg, Allocation); 4]> = smallvec![];
             for m in moves {
-                if m.from_alloc != m.to_alloc {
+                if let Some(vreg) = m.remat {
+                    remats.push((vreg, m.to_alloc));
+                } else if m.from_alloc != m.to_alloc {
                     log::debug!(" {} -> {}", m.from_alloc, m.to_alloc,);
                     parallel_moves.add(m.from_alloc, m.to_alloc);
                 }
             }
 
             let resolved = parallel_moves.resolve();
+            let designated = self.env.scratch_by_class.get(regclass as u8 as usize);
+            let uses_scratch = scratch.as_reg().is_some()
+                && scratch.as_reg() != designated.cloned()
+                && resolved.iter().any(|&(_, dst)| dst == scratch);
+            let scratch_save = if uses_scratch {
+                log::debug!(" using dynamic scratch {}", scratch);
+                self.stats.dynamic_scratch_count += 1;
+                if save_scratch {
+                    self.stats.scratch_saves_count += 1;
+                    Some(Allocation::stack(self.scratch_save_slot(regclass)))
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
 
-            for (src, dst) in resolved {
-                log::debug!("  resolved: {} -> {}", src, dst);
+            if let Some(slot) = scratch_save {
+                self.add_edit(
+                    pos,
+                    prio,
+                    Edit::Move {
+                        from: scratch,
+                        to: slot,
+                    },
+                );
+            }
+            for (src, dst) in resolved {
+                log::debug!("  resolved: {} -> {}", src, dst);
                 self.add_edit(pos, prio, Edit::Move { from: src, to: dst });
             }
+            if let Some(slot) = scratch_save {
+                self.add_edit(
+                    pos,
+                    prio,
+                    Edit::Move {
+                        from: slot,
+                        to: scratch,
+                    },
+                );
+            }
+
+            // Constants are materialized after the moves, which may
+            // still need to read the old values of their destinations;
+            // likewise rematerialized values.
+            for (vreg, to) in remats {
+                let edit = match self.vregs[vreg.vreg()].remat {
+                    Some(kind) => {
+                        log::debug!("  remat: {} -> {}", vreg, to);
+                        self.stats.remats_count += 1;
+                        Edit::Remat { vreg, kind, to }
+                    }
+                    None => {
+                        log::debug!("  constant: {} -> {}", vreg, to);
+                        Edit::DefineConstant { vreg, to }
+                    }
+                };
+                self.add_edit(pos, prio, edit);
+            }
         }
 
-        // Add edits to describe blockparam locations too. This is
-        // required by the checker. This comes after any edge-moves.
+        // Add edits to describe blockparam locations too, if asked
+        // to. These are required by the checker. This comes after any
+        // edge-moves.
         self.blockparam_allocs
-            .sort_by_key(|&(block, idx, _, _)| (block, idx));
+            .sort_unstable_by_key(|&(block, idx, _, _)| (block, idx));
         self.stats.blockparam_allocs_count = self.blockparam_allocs.len();
         let mut i = 0;
-        while i < self.blockparam_allocs.len() {
+        let report = self.opts.report_blockparams || self.opts.validates_output();
+        while report && i < self.blockparam_allocs.len() {
             let start = i;
             let block = self.blockparam_allocs[i].0;
             while i < self.blockparam_allocs.len() && self.blockparam_allocs[i].0 == block {
@@ -3587,6 +6641,52 @@ impl<'a, F: Function> Env<'a, F> {
 
         // Ensure edits are in sorted ProgPoint order.
         self.edits.sort_by_key(|&(pos, prio, _)| (pos, prio));
+
+        // A branch with a delay slot whose out-edge moves were placed
+        // before it reads its arguments after those moves. Any
+        // argument whose location they overwrote is read from the
+        // successor's blockparam location instead.
+        for block in 0..self.func.blocks() {
+            let block = Block::new(block);
+            let branch = self.func.block_insns(block).last();
+            let succs = self.func.block_succs(block);
+            if succs.len() != 1 || !self.func.branch_has_delay_slot(branch) {
+                continue;
+            }
+            let succ = succs[0];
+            let key = (
+                ProgPoint::before(branch).to_index(),
+                InsertMovePrio::OutEdgeMoves,
+            );
+            let start = self
+                .edits
+                .partition_point(|&(pos, prio, _)| (pos, prio) < key);
+            let end = self
+                .edits
+                .partition_point(|&(pos, prio, _)| (pos, prio) <= key);
+            let func = self.func;
+            func.visit_operands(branch, &mut |slot, _| {
+                let alloc = self.get_alloc(branch, slot);
+                let overwritten = self.edits[start..end]
+                    .iter()
+                    .any(|(_, _, edit)| match edit {
+                        &Edit::Move { to, .. }
+                        | &Edit::DefineConstant { to, .. }
+                        | &Edit::Remat { to, .. } => to == alloc,
+                        _ => false,
+                    });
+                if !overwritten {
+                    return;
+                }
+                if let Ok(idx) = self
+                    .blockparam_allocs
+                    .binary_search_by_key(&(succ, slot as u32), |&(block, idx, _, _)| (block, idx))
+                {
+                    let alloc = self.blockparam_allocs[idx].3;
+                    self.set_alloc(branch, slot, alloc);
+                }
+            });
+        }
         self.stats.edits_count = self.edits.len();
 
         // Add debug annotations.
@@ -3604,14 +6704,113 @@ impl<'a, F: Function> Env<'a, F> {
                         ref vregs,
                         ref allocs,
                     } => {
+                        let vregs = vregs
+                            .iter()
+                            .map(|&vreg| vreg_label(self.func, vreg))
+                            .collect::<Vec<_>>();
                         let s = format!("blockparams vregs:{:?} allocs:{:?}", vregs, allocs);
                         self.annotate(ProgPoint::from_index(pos), s);
                     }
+                    &Edit::DefineConstant { vreg, to } => {
+                        self.annotate(
+                            ProgPoint::from_index(pos),
+                            format!("constant {} -> {}", vreg_label(self.func, vreg), to),
+                        );
+                    }
+                    &Edit::Remat { vreg, kind, to } => {
+                        self.annotate(
+                            ProgPoint::from_index(pos),
+                            format!(
+                                "remat {} ({:?}) -> {}",
+                                vreg_label(self.func, vreg),
+                                kind,
+                                to
+                            ),
+                        );
+                    }
                 }
             }
         }
     }
 
+    /// Is `preg` free at `pos` according to the commitment maps, and
+    /// not touched by any of the moves at that point?
+    fn is_preg_free_at(&self, preg: PReg, pos: ProgPoint, moves_at_pos: &[InsertedMove]) -> bool {
+        let reg = Allocation::reg(preg);
+        if moves_at_pos
+            .iter()
+            .any(|m| m.from_alloc == reg || m.to_alloc == reg)
+        {
+            return false;
+        }
+        let key = LiveRangeKey::from_range(&CodeRange {
+            from: pos,
+            to: pos.next(),
+        });
+        !self.pregs[preg.index()]
+            .allocations
+            .btree
+            .contains_key(&key)
+    }
+
+    /// Choose a scratch register for resolving the parallel moves at
+    /// `pos`. The designated scratch register is used if it is free;
+    /// otherwise (in particular, if none is configured for `class`)
+    /// we look for any allocatable register that holds nothing at
+    /// this point. If every register is occupied, we fall back to one
+    /// that the moves do not touch, and return `true` alongside it to
+    /// indicate that its value must be saved around its use.
+    fn choose_scratch(
+        &self,
+        class: RegClass,
+        pos: ProgPoint,
+        moves_at_pos: &[InsertedMove],
+    ) -> (Allocation, bool) {
+        let designated = self.env.scratch_by_class.get(class as u8 as usize).cloned();
+        if let Some(preg) = designated {
+            if self.is_preg_free_at(preg, pos, moves_at_pos) {
+                return (Allocation::reg(preg), false);
+            }
+        }
+        let regs: &[PReg] = self
+            .env
+            .regs_by_class
+            .get(class as u8 as usize)
+            .map(|regs| &regs[..])
+            .unwrap_or(&[]);
+        if let Some(&preg) = regs
+            .iter()
+            .find(|&&preg| self.is_preg_free_at(preg, pos, moves_at_pos))
+        {
+            return (Allocation::reg(preg), false);
+        }
+        if let Some(preg) = designated {
+            return (Allocation::reg(preg), false);
+        }
+        let untouched = regs.iter().cloned().find(|&preg| {
+            let reg = Allocation::reg(preg);
+            !moves_at_pos
+                .iter()
+                .any(|m| m.from_alloc == reg || m.to_alloc == reg)
+        });
+        match untouched {
+            Some(preg) => (Allocation::reg(preg), true),
+            None => (Allocation::none(), false),
+        }
+    }
+
+    /// Get the spillslot used to save a borrowed scratch register of
+    /// the given class, allocating it on first use.
+    fn scratch_save_slot(&mut self, class: RegClass) -> SpillSlot {
+        if let Some(slot) = self.scratch_save_slots[class as u8 as usize] {
+            return slot;
+        }
+        let size = self.func.spillslot_size(class, VReg::new(VReg::MAX, class)) as u32;
+        let slot = self.allocate_dedicated_spillslot(class, size);
+        self.scratch_save_slots[class as u8 as usize] = Some(slot);
+        slot
+    }
+
     fn add_edit(&mut self, pos: ProgPoint, prio: InsertMovePrio, edit: Edit) {
         match &edit {
             &Edit::Move { from, to } if from == to => return,
@@ -3621,28 +6820,447 @@ impl<'a, F: Function> Env<'a, F> {
         self.edits.push((pos.to_index(), prio, edit));
     }
 
-    fn compute_stackmaps(&mut self) {}
+    fn compute_used_regs(&mut self) {
+        // A register is used if the allocation touches it at all: as
+        // an operand, or as the source, destination or scratch
+        // register of an inserted move. It is clobbered by edits if
+        // one writes it.
+        let mut used = vec![];
+        let mut clobbered = vec![];
+        used.extend(self.allocs.iter().filter_map(|alloc| alloc.as_reg()));
+        for (_, _, edit) in &self.edits {
+            match *edit {
+                Edit::Move { from, to } => {
+                    used.extend(from.as_reg());
+                    clobbered.extend(to.as_reg());
+                }
+                Edit::DefineConstant { to, .. } | Edit::Remat { to, .. } => {
+                    clobbered.extend(to.as_reg())
+                }
+                Edit::BlockParams { .. } => {}
+            }
+        }
+        used.extend(clobbered.iter().cloned());
+        used.sort_unstable_by_key(|preg| preg.index());
+        used.dedup();
+        clobbered.sort_unstable_by_key(|preg| preg.index());
+        clobbered.dedup();
+        self.used_regs = used;
+        self.edit_clobbered_regs = clobbered;
+    }
+
+    fn insert_callee_saves(&mut self) {
+        // Save the callee-saved registers that the allocation uses.
+        let mut used = vec![false; PReg::NUM_INDEX];
+        for preg in &self.used_regs {
+            used[preg.index()] = true;
+        }
 
-    pub(crate) fn init(&mut self) -> Result<(), RegAllocError> {
-        self.create_pregs_and_vregs();
-        self.compute_liveness();
-        self.compute_hot_code();
-        self.merge_vreg_bundles();
-        self.queue_bundles();
-        if log::log_enabled!(log::Level::Debug) {
-            self.dump_state();
+        let entry = ProgPoint::before(self.func.block_insns(self.func.entry_block()).first());
+        let rets: Vec<Inst> = (0..self.func.insts())
+            .map(Inst::new)
+            .filter(|&inst| self.func.is_ret(inst))
+            .collect();
+        for i in 0..self.env.callee_saved_regs.len() {
+            let preg = self.env.callee_saved_regs[i];
+            if !used[preg.index()] {
+                continue;
+            }
+            let size = self
+                .func
+                .spillslot_size(preg.class(), VReg::new(VReg::MAX, preg.class()))
+                as u32;
+            let slot = self.allocate_dedicated_spillslot(preg.class(), size);
+            log::debug!("callee-save {} used; saving to {}", preg, slot);
+            let (reg, stack) = (Allocation::reg(preg), Allocation::stack(slot));
+            self.add_edit(
+                entry,
+                InsertMovePrio::CalleeSave,
+                Edit::Move {
+                    from: reg,
+                    to: stack,
+                },
+            );
+            for &ret in &rets {
+                // Not between insts that edits are forbidden between:
+                // the registers are reserved from the earlier point.
+                self.add_edit(
+                    self.legal_split_point(ProgPoint::before(ret), false),
+                    InsertMovePrio::CalleeRestore,
+                    Edit::Move {
+                        from: stack,
+                        to: reg,
+                    },
+                );
+            }
+            self.callee_saves.push((preg, slot));
         }
-        Ok(())
+
+        self.edits.sort_by_key(|&(pos, prio, _)| (pos, prio));
+        self.stats.edits_count = self.edits.len();
+    }
+
+    fn compute_spill_metrics(&mut self) {
+        // Count spill stores and reloads, also weighted by an
+        // estimated execution frequency of 8^(loop depth), and moves of
+        // any kind in hot code.
+        for &(pos, _, ref edit) in &self.edits {
+            let (from, to) = match edit {
+                &Edit::Move { from, to } => (from, to),
+                _ => continue,
+            };
+            let pos = ProgPoint::from_index(pos);
+            let weight = self.block_weight(self.cfginfo.insn_block[pos.inst.index()]);
+            match (from.kind(), to.kind()) {
+                (AllocationKind::Reg, AllocationKind::Stack) => {
+                    self.stats.spill_stores += 1;
+                    self.stats.spill_stores_weighted += weight;
+                }
+                (AllocationKind::Stack, AllocationKind::Reg) => {
+                    self.stats.spill_reloads += 1;
+                    self.stats.spill_reloads_weighted += weight;
+                }
+                _ => {}
+            }
+            let key = LiveRangeKey::from_range(&CodeRange {
+                from: pos,
+                to: pos.next(),
+            });
+            if self.hot_code.btree.contains_key(&key) {
+                self.stats.hot_code_moves += 1;
+            }
+        }
+    }
+
+    fn compute_edge_moves(&mut self) {
+        // Edge moves are placed either at the start of the successor
+        // (if it has one predecessor) or at the end of the
+        // predecessor (if it has one successor); attribute each to
+        // its edge.
+        let mut counts: BTreeMap<(Block, Block), (usize, usize)> = BTreeMap::new();
+        for &(pos, prio, ref edit) in &self.edits {
+            let pos = ProgPoint::from_index(pos);
+            let block = self.cfginfo.insn_block[pos.inst.index()];
+            let edge = match prio {
+                InsertMovePrio::InEdgeMoves => (self.func.block_preds(block)[0], block),
+                InsertMovePrio::OutEdgeMoves => (block, self.func.block_succs(block)[0]),
+                _ => continue,
+            };
+            let cost = match edit {
+                &Edit::Move { from, to }
+                    if from.kind() == AllocationKind::Stack
+                        || to.kind() == AllocationKind::Stack =>
+                {
+                    2
+                }
+                _ => 1,
+            };
+            let entry = counts.entry(edge).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += cost;
+        }
+        self.edge_moves = counts
+            .into_iter()
+            .map(|((from, to), (moves, cost))| EdgeMoves {
+                from,
+                to,
+                moves,
+                cost,
+            })
+            .collect();
+    }
+
+    fn compute_block_edits(&mut self) {
+        self.block_edits = vec![BlockEdits::default(); self.func.blocks()];
+        for &(pos, _, ref edit) in &self.edits {
+            let inst = ProgPoint::from_index(pos).inst;
+            let counts = &mut self.block_edits[self.cfginfo.insn_block[inst.index()].index()];
+            match *edit {
+                Edit::Move { from, to } => match (from.kind(), to.kind()) {
+                    (AllocationKind::Stack, AllocationKind::Stack) => counts.stack_moves += 1,
+                    (AllocationKind::Stack, _) => counts.reloads += 1,
+                    (_, AllocationKind::Stack) => counts.spills += 1,
+                    _ => counts.moves += 1,
+                },
+                Edit::DefineConstant { .. } => counts.constants += 1,
+                Edit::Remat { .. } => counts.remats += 1,
+                Edit::BlockParams { .. } => {}
+            }
+        }
+    }
+
+    fn compute_elided_moves(&mut self) {
+        // A move instruction whose source and destination were given
+        // the same allocation (typically because their bundles were
+        // merged) does nothing; flag it so that the embedder can skip
+        // it during emission.
+        for inst in 0..self.func.insts() {
+            let inst = Inst::new(inst);
+            let (src, dst) = match self.func.is_move(inst) {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let mut src_alloc = None;
+            let mut dst_alloc = None;
+            let offset = self.inst_alloc_offsets[inst.index()] as usize;
+            self.func.visit_operands(inst, &mut |i, op| {
+                let alloc = self.allocs[offset + i];
+                match op.kind() {
+                    OperandKind::Use if op.vreg() == src => src_alloc = Some(alloc),
+                    OperandKind::Def if op.vreg() == dst => dst_alloc = Some(alloc),
+                    _ => {}
+                }
+            });
+            if src_alloc.is_some() && src_alloc == dst_alloc {
+                log::debug!("move at {:?} is elided: {:?}", inst, src_alloc);
+                self.elided_moves.set(inst.index(), true);
+                self.stats.elided_moves_count += 1;
+            }
+        }
+    }
+
+    /// Record the spillslot of each reference-typed vreg during each
+    /// safepoint it is live across; see `Output::safepoint_slots` and
+    /// `Output::safepoint_derived_slots`.
+    fn compute_stackmaps(&mut self) {
+        if self.stats.safepoint_uses == 0 {
+            return;
+        }
+        let mut slots: Vec<(Inst, VRegIndex, SpillSlot)> = vec![];
+        for vreg in 0..self.vregs.len() {
+            let mut iter = self.vregs[vreg].first_range;
+            while iter.is_valid() {
+                for i in 0..self.ranges[iter.index()].uses.len() {
+                    let u = &self.uses[self.ranges[iter.index()].uses[i].index()];
+                    if u.slot != SLOT_NONE {
+                        continue;
+                    }
+                    let inst = u.pos.inst;
+                    let slot = self.get_alloc_for_range(iter).as_stack();
+                    debug_assert!(slot.is_some());
+                    if let Some(slot) = slot {
+                        slots.push((inst, VRegIndex::new(vreg), slot));
+                    }
+                }
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+        slots.sort_unstable_by_key(|&(inst, vreg, _)| (inst, vreg));
+        slots.dedup();
+        for &(inst, vreg, slot) in &slots {
+            // The base is in its slot here, live across or kept alive
+            // (see `compute_derived_keepalives()`), unless the
+            // safepoint itself defines it.
+            let base = self
+                .func
+                .derived_ref_base(self.vregs[vreg.index()].reg)
+                .and_then(|base| {
+                    let base = VRegIndex::new(base.vreg());
+                    slots
+                        .binary_search_by_key(&(inst, base), |&(inst, vreg, _)| (inst, vreg))
+                        .ok()
+                });
+            match base {
+                Some(i) => self.safepoint_derived_slots.push((inst, slot, slots[i].2)),
+                None => self.safepoint_slots.push((inst, slot)),
+            }
+        }
+        self.safepoint_slots
+            .sort_unstable_by_key(|&(inst, slot)| (inst, slot.index()));
+        self.safepoint_slots.dedup();
+        self.safepoint_derived_slots
+            .sort_unstable_by_key(|&(inst, slot, _)| (inst, slot.index()));
+        self.safepoint_derived_slots.dedup();
+    }
+
+    /// Record the location during each safepoint of every vreg live
+    /// across it; see `Output::safepoint_locations`.
+    fn compute_safepoint_locations(&mut self) {
+        let safepoints: Vec<Inst> = (0..self.func.insts())
+            .map(Inst::new)
+            .filter(|&inst| self.func.is_safepoint(inst))
+            .collect();
+        if safepoints.is_empty() {
+            return;
+        }
+
+        for vreg in &self.vregs {
+            let mut prev: Option<CodeRange> = None;
+            let mut iter = vreg.first_range;
+            while iter.is_valid() {
+                let range = self.ranges[iter.index()].range;
+                // The value is where this range puts it while the
+                // safepoint runs, i.e. at its After point, as long as
+                // it was also live before it (rather than defined by
+                // it).
+                let first = safepoints.partition_point(|&inst| ProgPoint::after(inst) < range.from);
+                for &inst in safepoints[first..]
+                    .iter()
+                    .take_while(|&&inst| ProgPoint::after(inst) < range.to)
+                {
+                    let before = ProgPoint::before(inst);
+                    let live_before = range.contains_point(before)
+                        || matches!(prev, Some(prev) if prev.contains_point(before));
+                    let alloc = self.get_alloc_for_range(iter);
+                    if live_before && alloc != Allocation::none() {
+                        self.safepoint_locations.push((inst, vreg.reg, alloc));
+                    }
+                }
+                prev = Some(range);
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+        self.safepoint_locations
+            .sort_unstable_by_key(|&(inst, vreg, _)| (inst, vreg.vreg()));
+    }
+
+    /// Record the soft clobbers the allocation keeps a value in; see
+    /// `Output::preserved_soft_clobbers`. Besides the registers a
+    /// liverange lives in across an instruction, this includes any
+    /// soft clobber an operand is allocated to other than to be read
+    /// first: a late or fixed-reg use fed by a fixup move, say, reads
+    /// the register after the clobber takes effect.
+    fn compute_preserved_soft_clobbers(&mut self) {
+        if self.soft_clobbers.is_empty() {
+            return;
+        }
+        for i in 0..self.soft_clobbers.len() {
+            let (inst, preg) = self.soft_clobbers[i];
+            let offset = self.inst_alloc_offsets[inst.index()] as usize;
+            let mut kept = false;
+            self.func.visit_operands(inst, &mut |i, op| {
+                let read_first = op.kind() == OperandKind::Use && op.pos() == OperandPos::Before;
+                kept |= !read_first && self.allocs[offset + i] == Allocation::reg(preg);
+            });
+            if kept {
+                self.preserved_soft_clobbers.push((inst, preg));
+            }
+        }
+        for vreg in &self.vregs {
+            let mut iter = vreg.first_range;
+            while iter.is_valid() {
+                let range = self.ranges[iter.index()].range;
+                if let Some(preg) = self.get_alloc_for_range(iter).as_reg() {
+                    for &(inst, _) in soft_clobbers_in(&self.soft_clobbers, range)
+                        .iter()
+                        .filter(|&&(_, reg)| reg == preg)
+                    {
+                        self.preserved_soft_clobbers.push((inst, preg));
+                    }
+                }
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+        self.preserved_soft_clobbers
+            .sort_unstable_by_key(|&(inst, preg)| (inst, preg.index()));
+        self.preserved_soft_clobbers.dedup();
+    }
+
+    /// Record where each labelled vreg is over its label's range; see
+    /// `Output::debug_locations`.
+    fn compute_debug_locations(&mut self) {
+        for &(vreg, from, to, label) in self.func.debug_value_labels() {
+            let label_range = CodeRange::new(ProgPoint::before(from), ProgPoint::before(to));
+            let mut iter = self.vregs[vreg.vreg()].first_range;
+            while iter.is_valid() {
+                let range = self.ranges[iter.index()].range;
+                let alloc = self.get_alloc_for_range(iter);
+                if range.overlaps(&label_range) && alloc != Allocation::none() {
+                    self.debug_locations.push((
+                        label,
+                        range.from.max(label_range.from),
+                        range.to.min(label_range.to),
+                        alloc,
+                    ));
+                }
+                iter = self.ranges[iter.index()].next_in_reg;
+            }
+        }
+        sort_debug_locations(&mut self.debug_locations);
     }
 
-    pub(crate) fn run(&mut self) -> Result<(), RegAllocError> {
-        self.process_bundles();
-        self.try_allocating_regs_for_spilled_bundles();
-        self.allocate_spillslots();
+    fn reify(&mut self) {
         self.apply_allocations_and_insert_moves();
         self.resolve_inserted_moves();
+        self.compute_spill_metrics();
+        if self.opts.report_edge_moves {
+            self.compute_edge_moves();
+        }
+        self.compute_used_regs();
+        if self.opts.save_callee_saves {
+            self.insert_callee_saves();
+        }
+        self.compute_block_edits();
+        if !self.env.spillslot_layout_by_class.is_empty() {
+            self.compute_spillslot_offsets();
+        }
+        self.compute_elided_moves();
         self.compute_stackmaps();
-        Ok(())
+        if self.opts.report_safepoint_locations {
+            self.compute_safepoint_locations();
+        }
+        self.compute_preserved_soft_clobbers();
+        self.compute_debug_locations();
+    }
+
+    /// Give back the working storage, emptied, without producing an
+    /// output, as when liveness fails.
+    fn into_arena(self) -> Arena {
+        let mut arena = Arena {
+            ranges: self.ranges,
+            bundles: self.bundles,
+            spillsets: self.spillsets,
+            uses: self.uses,
+            defs: self.defs,
+        };
+        arena.clear();
+        arena
+    }
+
+    fn into_output(self) -> (Output, Arena) {
+        let mut arena = Arena {
+            ranges: self.ranges,
+            bundles: self.bundles,
+            spillsets: self.spillsets,
+            uses: self.uses,
+            defs: self.defs,
+        };
+        arena.clear();
+        let vregs = &self.vregs;
+        let explanations = self
+            .explanations
+            .into_iter()
+            .enumerate()
+            .filter(|(_, events)| !events.is_empty())
+            .map(|(vreg, events)| (vregs[vreg].reg, events))
+            .collect();
+        let output = Output {
+            edits: self
+                .edits
+                .into_iter()
+                .map(|(pos, _, edit)| (ProgPoint::from_index(pos), edit))
+                .collect(),
+            allocs: self.allocs,
+            inst_alloc_offsets: self.inst_alloc_offsets,
+            num_spillslots: self.num_spillslots as usize,
+            spillslot_offsets: self.spillslot_offsets,
+            spillslot_area_size: self.spillslot_area_size,
+            callee_saves: self.callee_saves,
+            elided_moves: self.elided_moves,
+            edge_moves: self.edge_moves,
+            safepoint_locations: self.safepoint_locations,
+            preserved_soft_clobbers: self.preserved_soft_clobbers,
+            safepoint_slots: self.safepoint_slots,
+            safepoint_derived_slots: self.safepoint_derived_slots,
+            debug_locations: self.debug_locations,
+            fingerprint: self.opts.fingerprint(),
+            block_edits: self.block_edits,
+            used_regs: self.used_regs,
+            edit_clobbered_regs: self.edit_clobbered_regs,
+            explanations,
+            stats: self.stats,
+        };
+        (output, arena)
     }
 
     fn annotate(&mut self, progpoint: ProgPoint, s: String) {
@@ -3656,6 +7274,11 @@ impl<'a, F: Function> Env<'a, F> {
 
     fn dump_results(&self) {
         log::debug!("=== REGALLOC RESULTS ===");
+        let mut names = String::new();
+        self.write_vreg_names(&mut names).unwrap();
+        for line in names.lines() {
+            log::debug!("{}", line);
+        }
         for block in 0..self.func.blocks() {
             let block = Block::new(block);
             log::debug!(
@@ -3681,16 +7304,13 @@ impl<'a, F: Function> Env<'a, F> {
                 {
                     log::debug!("  inst{}-pre: {}", inst.index(), annotation);
                 }
-                let ops = self
-                    .func
-                    .inst_operands(inst)
+                let ops = crate::liveness::inst_operands(self.func, inst)
                     .iter()
                     .map(|op| format!("{}", op))
                     .collect::<Vec<_>>();
                 let clobbers = self
-                    .func
-                    .inst_clobbers(inst)
-                    .iter()
+                    .env
+                    .inst_clobbers(self.func, inst)
                     .map(|preg| format!("{}", preg))
                     .collect::<Vec<_>>();
                 let allocs = (0..ops.len())
@@ -3736,27 +7356,3154 @@ impl<'a, F: Function> Env<'a, F> {
 }
 
 pub fn run<F: Function>(func: &F, mach_env: &MachineEnv) -> Result<Output, RegAllocError> {
-    let cfginfo = CFGInfo::new(func);
-    validate_ssa(func, &cfginfo)?;
+    run_with_options(func, mach_env, &RegallocOptions::default())
+}
 
-    let mut env = Env::new(func, mach_env, cfginfo);
-    env.init()?;
+/// Like `run()`, but with the given options.
+pub fn run_with_options<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<Output, RegAllocError> {
+    run_with_arena(func, mach_env, options, &mut Arena::default())
+}
 
-    env.run()?;
+/// Like `run_with_options()`, but take the allocator's working storage from
+/// `arena`, and return it there once the run completes.
+pub fn run_with_arena<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+    options: &RegallocOptions,
+    arena: &mut Arena,
+) -> Result<Output, RegAllocError> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("regalloc2").entered();
+
+    // Reserve or release the frame pointer for this function.
+    let fp_env;
+    let mach_env = if mach_env.frame_pointer.is_some() {
+        fp_env = mach_env.with_frame_pointer(options.allocate_frame_pointer);
+        &fp_env
+    } else {
+        mach_env
+    };
+
+    let liveness = Liveness::compute_in_arena(func, mach_env, options, arena)?;
+    let allocated = if liveness.can_allocate_linear() {
+        liveness.allocate_linear()
+    } else {
+        liveness.merge().allocate()
+    };
+    if let Some(count) = allocated.iteration_limit_exceeded() {
+        *arena = allocated.into_arena();
+        return Err(RegAllocError::IterationLimit(count));
+    }
+    let over_budget = allocated.vregs_over_spillslot_budget();
+    let spilled_no_spill = allocated.spilled_no_spill_vreg();
+    #[cfg(feature = "checker")]
+    let live_ranges = if options.validate_output {
+        allocated.live_ranges()
+    } else {
+        vec![]
+    };
+    let (output, returned) = allocated.reify_and_return_arena();
+    *arena = returned;
+    if let Some((vreg, inst)) = spilled_no_spill {
+        return Err(RegAllocError::NoSpill(vreg, inst));
+    }
+    check_edit_placement(func, &output)?;
 
-    if log::log_enabled!(log::Level::Debug) {
-        env.dump_results();
+    if let Some(max) = options.max_spillslots {
+        if output.num_spillslots > max {
+            return Err(RegAllocError::TooManySpillslots(
+                output.num_spillslots,
+                over_budget,
+            ));
+        }
     }
 
-    Ok(Output {
-        edits: env
-            .edits
-            .into_iter()
-            .map(|(pos, _, edit)| (ProgPoint::from_index(pos), edit))
-            .collect(),
-        allocs: env.allocs,
-        inst_alloc_offsets: env.inst_alloc_offsets,
-        num_spillslots: env.num_spillslots as usize,
-        stats: env.stats,
-    })
+    #[cfg(feature = "checker")]
+    if options.validate_output {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("check").entered();
+        let mut checker = crate::checker::Checker::new(func);
+        checker.add_machine_env(mach_env);
+        checker.prepare(&output);
+        for &(vreg, range, alloc) in &live_ranges {
+            checker.add_live_range(vreg, range.from, range.to, alloc);
+        }
+        checker.run().map_err(RegAllocError::CheckerFailed)?;
+    }
+
+    Ok(output)
+}
+
+/// Fail if a vreg's class has no allocatable registers, or if a
+/// mention of a vreg has a different class from its def.
+pub(crate) fn check_reg_classes<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+) -> Result<(), RegAllocError> {
+    // Every vreg mentioned by the function must have at least one
+    // register in its class to be allocated into; otherwise the
+    // probing loops have nothing to choose from.
+    let has_regs = |class: RegClass| {
+        env.regs_by_class
+            .get(class as u8 as usize)
+            .map(|regs| !regs.is_empty())
+            .unwrap_or(false)
+    };
+    // Every mention of a vreg must also have the class it is
+    // defined with: bundles are merged and allocated per class,
+    // taking the class from the def.
+    let mut def_class: Vec<Option<RegClass>> = vec![None; func.num_vregs()];
+    for block in 0..func.blocks() {
+        let block = Block::new(block);
+        for &vreg in func.block_params(block) {
+            def_class[vreg.vreg()] = Some(vreg.class());
+        }
+        for inst in func.block_insns(block).iter() {
+            for op in crate::liveness::inst_operands(func, inst) {
+                if op.kind() == OperandKind::Def {
+                    def_class[op.vreg().vreg()] = Some(op.class());
+                }
+            }
+        }
+    }
+    for block in 0..func.blocks() {
+        let block = Block::new(block);
+        for &vreg in func.block_params(block) {
+            if !has_regs(vreg.class()) {
+                return Err(RegAllocError::NoRegistersInClass(
+                    vreg.class(),
+                    vreg,
+                    Inst::invalid(),
+                ));
+            }
+        }
+        for inst in func.block_insns(block).iter() {
+            for op in crate::liveness::inst_operands(func, inst) {
+                if !has_regs(op.class()) {
+                    return Err(RegAllocError::NoRegistersInClass(
+                        op.class(),
+                        op.vreg(),
+                        inst,
+                    ));
+                }
+                match def_class[op.vreg().vreg()] {
+                    Some(class) if class != op.class() => {
+                        return Err(RegAllocError::ClassMismatch(op.vreg(), class, inst));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the insts after which no edit may be placed: those the
+/// function forbids edits after, and those from a write of the flags
+/// up to its last read. A restriction after the last inst of a block
+/// has no next inst to apply to.
+pub(crate) fn restricted_points<F: Function>(func: &F) -> Result<BitVec, RegAllocError> {
+    let mut restricted = BitVec::new();
+    for block in 0..func.blocks() {
+        let insns = func.block_insns(Block::new(block));
+        let mut flags_def = None;
+        for inst in insns.iter() {
+            if func.reads_flags(inst) {
+                let def = flags_def.ok_or(RegAllocError::FlagsNotDefined(inst))?;
+                for i in def..inst.index() {
+                    restricted.set(i, true);
+                }
+            }
+            if func.writes_flags(inst) {
+                flags_def = Some(inst.index());
+            }
+            if inst != insns.last() && func.no_edits_after(inst) {
+                restricted.set(inst.index(), true);
+            }
+        }
+    }
+    Ok(restricted)
+}
+
+/// Find the insts over which `RegallocOptions::save_callee_saves`
+/// has moved the callee-saved registers back for a return: each
+/// return, and, if edits are forbidden before it, the run of insts
+/// that joins it. Fail if an operand of one of them is fixed to a
+/// callee-saved register, which could not hold its value there.
+pub(crate) fn callee_restored_insts<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    restricted: &BitVec,
+) -> Result<BitVec, RegAllocError> {
+    let mut restored = BitVec::new();
+    for ret in 0..func.insts() {
+        let mut inst = Inst::new(ret);
+        if !func.is_ret(inst) {
+            continue;
+        }
+        loop {
+            restored.set(inst.index(), true);
+            for op in crate::liveness::inst_operands(func, inst) {
+                match op.policy() {
+                    OperandPolicy::FixedReg(preg) if env.callee_saved_regs.contains(&preg) => {
+                        return Err(RegAllocError::CalleeSavedFixedReg(preg, inst));
+                    }
+                    _ => {}
+                }
+            }
+            if inst.index() == 0 || !restricted.get(inst.index() - 1) {
+                break;
+            }
+            inst = inst.prev();
+        }
+    }
+    Ok(restored)
+}
+
+/// Fail if any edit lies between two insts that edits are forbidden
+/// between. Split points are kept out of such gaps, but a move
+/// required by an operand constraint of one of the insts themselves
+/// cannot be placed elsewhere.
+fn check_edit_placement<F: Function>(func: &F, output: &Output) -> Result<(), RegAllocError> {
+    let restricted = restricted_points(func)?;
+    for &(pos, _) in &output.edits {
+        let inst = match pos.pos {
+            InstPosition::After => pos.inst,
+            InstPosition::Before if pos.inst.index() > 0 => pos.inst.prev(),
+            InstPosition::Before => continue,
+        };
+        if restricted.get(inst.index()) {
+            return Err(RegAllocError::EditAtRestrictedPoint(inst));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "checker")]
+    use crate::checker::Checker;
+    use crate::fuzzing::func::{machine_env, Func, FuncBuilder, InstData, InstOpcode, Options};
+    use crate::test::test_funcs;
+    use crate::{run_with_options, AllocatorAlgorithm, CallConv};
+
+    #[test]
+    #[cfg(feature = "verify-invariants")]
+    #[should_panic(expected = "test: use of v0 at")]
+    fn test_verify_invariants() {
+        // The ranges computed for a def and a use pass; moving the use
+        // past the end of its range is caught.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let options = RegallocOptions::default();
+        let cfginfo = CFGInfo::new(&func);
+        let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+        env.create_pregs_and_vregs();
+        env.compute_liveness().expect("liveness failed");
+        env.verify_invariants("test");
+
+        let range = env.vregs[0].first_range;
+        let use_idx = env.ranges[range.index()].uses[0];
+        env.uses[use_idx.index()].pos = env.ranges[range.index()].range.to;
+        env.verify_invariants("test");
+    }
+
+    #[test]
+    fn test_sorted_use_lists() {
+        // Uses inserted out of order come out sorted by position, and
+        // distributing them into a subrange moves exactly the uses
+        // within it.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder.inst(block, &[Operand::reg_def(v0)], &[]);
+        for _ in 0..4 {
+            builder.inst(block, &[Operand::reg_use(v0)], &[]);
+        }
+        builder.ret(block);
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let options = RegallocOptions::default();
+        let cfginfo = CFGInfo::new(&func);
+        let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+        env.create_pregs_and_vregs();
+        env.compute_liveness().expect("liveness failed");
+
+        let from = env.vregs[0].first_range;
+        let weight = env.ranges[from.index()].uses_spill_weight;
+        let mut uses: Vec<UseIndex> = env.ranges[from.index()].uses.drain(..).collect();
+        assert_eq!(uses.len(), 4);
+        uses.reverse();
+        for u in uses {
+            env.update_liverange_stats_on_remove_use(from, u);
+            env.insert_use_into_liverange_and_update_stats(from, u);
+        }
+        assert_eq!(env.ranges[from.index()].uses_spill_weight, weight);
+        let insts = |env: &Env<_>, lr: LiveRangeIndex| -> Vec<usize> {
+            env.ranges[lr.index()]
+                .uses
+                .iter()
+                .map(|u| env.uses[u.index()].pos.inst.index())
+                .collect()
+        };
+        assert_eq!(insts(&env, from), vec![1, 2, 3, 4]);
+
+        let into = env.create_liverange(CodeRange {
+            from: ProgPoint::before(Inst::new(2)),
+            to: ProgPoint::before(Inst::new(4)),
+        });
+        env.ranges[into.index()].vreg = env.ranges[from.index()].vreg;
+        env.distribute_liverange_uses(from, into);
+        assert_eq!(insts(&env, from), vec![1, 4]);
+        assert_eq!(insts(&env, into), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_prio_queue() {
+        // Highest priority first; equal priorities in insertion order,
+        // including bundles pushed after some have been popped.
+        let b = LiveBundleIndex::new;
+        let mut queue = PrioQueue::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+        queue.insert(b(0), 2);
+        queue.insert(b(1), 5);
+        queue.insert(b(2), 2);
+        queue.insert(b(3), 0);
+        queue.insert(b(4), 5);
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.pop(), Some(b(1)));
+        assert_eq!(queue.pop(), Some(b(4)));
+        assert_eq!(queue.pop(), Some(b(0)));
+        queue.insert(b(5), 3);
+        queue.insert(b(6), 2);
+        assert_eq!(queue.pop(), Some(b(5)));
+        assert_eq!(queue.pop(), Some(b(2)));
+        assert_eq!(queue.pop(), Some(b(6)));
+        assert_eq!(queue.pop(), Some(b(3)));
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+
+        // Long bundles share buckets by their leading bits.
+        assert_eq!(PrioQueue::bucket(63), 63);
+        assert_eq!(PrioQueue::bucket(64), 64);
+        assert_eq!(PrioQueue::bucket(71), 64);
+        assert_eq!(PrioQueue::bucket(72), 65);
+        assert_eq!(PrioQueue::bucket(128), 72);
+        let buckets: Vec<usize> = (0..1 << 16).map(PrioQueue::bucket).collect();
+        assert!(buckets.windows(2).all(|w| w[0] <= w[1]));
+        assert!(PrioQueue::bucket(usize::MAX) < 600);
+        queue.insert(b(0), 1 << 20);
+        queue.insert(b(1), 100);
+        queue.insert(b(2), 1 << 30);
+        queue.insert(b(3), (1 << 20) + 1);
+        assert_eq!(queue.pop(), Some(b(2)));
+        assert_eq!(queue.pop(), Some(b(0)));
+        assert_eq!(queue.pop(), Some(b(3)));
+        assert_eq!(queue.pop(), Some(b(1)));
+        assert!(queue.buckets.len() <= PrioQueue::bucket(1 << 30) + 1);
+    }
+
+    #[test]
+    fn test_prio_queue_long_bundle() {
+        // One value live across 100000 insts: its bundle's priority is
+        // their count, but the queue needs only a few hundred buckets.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder.inst(block, &[Operand::reg_def(v0)], &[]);
+        for _ in 0..100_000 {
+            builder.inst(block, &[], &[]);
+        }
+        builder.inst(block, &[Operand::reg_use(v0)], &[]).ret(block);
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let options = RegallocOptions::default();
+        let cfginfo = CFGInfo::new(&func);
+        let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+        env.create_pregs_and_vregs();
+        env.compute_liveness().expect("liveness failed");
+        env.merge_vreg_bundles();
+        env.queue_bundles();
+        assert!(env.bundles[0].prio > 100_000);
+        assert_eq!(env.allocation_queue.len(), 1);
+        assert!(env.allocation_queue.buckets.len() < 200);
+        env.process_bundles();
+        assert!(env.bundles[0].allocation.as_reg().is_some());
+    }
+
+    #[test]
+    fn test_fixed_conflict_memory() {
+        // A register found reserved by a clobber is not probed again
+        // for the same bundle, until a split shrinks the bundle.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let p0 = PReg::new(0, RegClass::Int);
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[], &[p0])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let options = RegallocOptions::default();
+        let cfginfo = CFGInfo::new(&func);
+        let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+        env.create_pregs_and_vregs();
+        env.compute_liveness().expect("liveness failed");
+        env.merge_vreg_bundles();
+
+        let bundle = env.ranges[env.vregs[0].first_range.index()].bundle;
+        let reg = PRegIndex::new(p0.index());
+        assert_eq!(
+            env.probe_bundle_reg(bundle, reg),
+            AllocRegResult::ConflictWithFixed
+        );
+        assert_eq!(&env.bundles[bundle.index()].fixed_conflicts[..], &[p0]);
+        assert_eq!(env.stats.fixed_conflict_probes_skipped, 0);
+        assert_eq!(
+            env.probe_bundle_reg(bundle, reg),
+            AllocRegResult::ConflictWithFixed
+        );
+        assert_eq!(env.stats.fixed_conflict_probes_skipped, 1);
+
+        // Split off everything from the clobber on: the leading part
+        // no longer conflicts, and a probe finds that out.
+        env.split_bundle_at(bundle, &[ProgPoint::before(Inst::new(1))]);
+        assert!(env.bundles[bundle.index()].fixed_conflicts.is_empty());
+        assert_eq!(
+            env.probe_bundle_reg(bundle, reg),
+            AllocRegResult::Allocated(Allocation::reg(p0))
+        );
+        assert_eq!(env.stats.fixed_conflict_probes_skipped, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_eviction_pressure() {
+        // Two registers, and values whose register uses keep evicting
+        // each other: an eviction comes back around to a bundle on
+        // its own chain, and the cascade is broken by force-spilling.
+        let mut env = machine_env();
+        env.regs_by_class[RegClass::Int as u8 as usize].truncate(2);
+        env.regs.truncate(2);
+
+        let any_use = |vreg| {
+            Operand::new(
+                vreg,
+                OperandPolicy::Any,
+                OperandKind::Use,
+                OperandPos::Before,
+            )
+        };
+        let any_def = |vreg| {
+            Operand::new(
+                vreg,
+                OperandPolicy::Any,
+                OperandKind::Def,
+                OperandPos::After,
+            )
+        };
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v: Vec<VReg> = (0..10).map(|_| builder.vreg()).collect();
+        builder
+            .inst(block, &[Operand::reg_def(v[8])], &[])
+            .inst(block, &[Operand::reg_def(v[9])], &[])
+            .inst(block, &[Operand::reg_def_at_start(v[0])], &[])
+            .inst(block, &[Operand::reg_def(v[1])], &[])
+            .inst(block, &[Operand::reg_def_at_start(v[2])], &[])
+            .inst(
+                block,
+                &[any_def(v[3]), any_use(v[1]), Operand::reg_use(v[0])],
+                &[],
+            )
+            .inst(
+                block,
+                &[Operand::reg_def_at_start(v[4]), Operand::reg_use(v[0])],
+                &[],
+            )
+            .inst(
+                block,
+                &[any_def(v[5]), Operand::reg_use(v[9]), any_use(v[2])],
+                &[],
+            )
+            .inst(block, &[any_def(v[6]), any_use(v[5])], &[])
+            .inst(
+                block,
+                &[Operand::reg_def_at_start(v[7]), any_use(v[9])],
+                &[],
+            )
+            .inst(block, &[any_use(v[8]), any_use(v[9])], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+
+        let out = run(&func, &env).unwrap();
+        assert!(out.stats.eviction_cascades > 0);
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_requeue_limit() {
+        // Many values live across calls that clobber every register:
+        // each is split around every call, so far more bundles are
+        // processed than there are instructions, but hardly any go
+        // back into the queue. (This used to trip a limit on bundles
+        // processed, taken for an infinite loop.)
+        let env = machine_env();
+        let opts = Options {
+            live_vregs: 58,
+            loop_depth: 1,
+            fixed_reg_percent: 3,
+            reuse_percent: 24,
+            clobber_call_percent: 99,
+            ..Options::default()
+        };
+        let func = test_funcs(&opts, 106).pop().unwrap();
+        let options = RegallocOptions {
+            report_blockparams: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        assert!(out.stats.process_bundle_count > 50 * func.insts());
+        assert!(out.stats.requeues < func.insts());
+        assert_eq!(out.stats.iteration_limit_hits, 0);
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+        for seed in 0..4 {
+            if let Err(e) = crate::fuzzing::interp::run(&func, &out, seed, 10 * func.insts()) {
+                panic!("interpreter found a mismatch: {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_churning_no_spill_bundle() {
+        // A no-spill bundle that has churned past `MAX_REQUEUES`
+        // cannot be force-spilled itself, so it force-spills what it
+        // evicts instead of requeueing it whole.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let v1 = builder.vreg();
+        let any_use = |vreg| {
+            Operand::new(
+                vreg,
+                OperandPolicy::Any,
+                OperandKind::Use,
+                OperandPos::Before,
+            )
+        };
+        let any_def = Operand::new(v0, OperandPolicy::Any, OperandKind::Def, OperandPos::After);
+        builder
+            .no_spill(v0)
+            .inst(block, &[any_def], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[any_use(v0)], &[])
+            .inst(block, &[Operand::reg_use(v1)], &[])
+            .inst(block, &[any_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let mut mach_env = machine_env();
+        mach_env.regs_by_class[RegClass::Int as u8 as usize].truncate(1);
+        mach_env.regs.truncate(1);
+        let options = RegallocOptions::default();
+        let cfginfo = CFGInfo::new(&func);
+        let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+        env.create_pregs_and_vregs();
+        env.compute_liveness().expect("liveness failed");
+        env.merge_vreg_bundles();
+        env.queue_bundles();
+
+        let b0 = env.ranges[env.vregs[0].first_range.index()].bundle;
+        let b1 = env.ranges[env.vregs[1].first_range.index()].bundle;
+        env.process_bundle(b1);
+        assert!(env.bundles[b1.index()].allocation.as_reg().is_some());
+        let spillset = env.bundles[b0.index()].spillset;
+        env.spillsets[spillset.index()].requeues = MAX_REQUEUES + 1;
+        env.process_bundle(b0);
+        assert!(env.bundles[b0.index()].allocation.as_reg().is_some());
+        assert_eq!(env.stats.requeue_limit_spills, 1);
+        assert_eq!(env.stats.evict_bundle_count, 0);
+    }
+
+    #[test]
+    fn test_iteration_limit() {
+        // Five values live at once, with two registers. Once the count
+        // of bundles processed passes the global limit, every bundle
+        // left is force-spilled between its uses, and allocation still
+        // completes; at twice the limit, it gives up.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v: Vec<VReg> = (0..5).map(|_| builder.vreg()).collect();
+        for &vreg in &v {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        for &vreg in &v {
+            builder.inst(block, &[Operand::reg_use(vreg)], &[]);
+        }
+        builder.ret(block);
+        let func = builder.build().unwrap();
+        let mut mach_env = machine_env();
+        mach_env.regs_by_class[RegClass::Int as u8 as usize].truncate(2);
+        mach_env.regs.truncate(2);
+        let options = RegallocOptions::default();
+        let setup = || {
+            let cfginfo = CFGInfo::new(&func);
+            let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+            env.create_pregs_and_vregs();
+            env.compute_liveness().expect("liveness failed");
+            env.merge_vreg_bundles();
+            env.queue_bundles();
+            let limit = MAX_PROCESS_BUNDLE_FACTOR * (func.insts() + env.bundles.len());
+            (env, limit)
+        };
+
+        let (mut env, limit) = setup();
+        env.stats.process_bundle_count = limit;
+        env.process_bundles();
+        assert_eq!(env.stats.iteration_limit_hits, 1);
+        assert!(env.requeue_limit_hit);
+        assert!(!env.iteration_limit_exceeded);
+        assert!(env.stats.requeue_limit_spills > 0);
+        assert_eq!(env.stats.evict_bundle_count, 0);
+
+        let (mut env, limit) = setup();
+        env.stats.process_bundle_count = 2 * limit;
+        env.stats.iteration_limit_hits = 1;
+        env.process_bundles();
+        assert_eq!(env.stats.iteration_limit_hits, 2);
+        assert!(env.iteration_limit_exceeded);
+        assert_eq!(env.stats.process_bundle_count, 2 * limit + 1);
+        assert!(env.allocation_queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_no_spill() {
+        // Eight values that need no register, live across a call that
+        // clobbers all but the seven callee-saved registers: one of
+        // them must go to memory, but not one that must stay in a
+        // register.
+        let env = machine_env();
+        let clobbers: Vec<PReg> = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let build = |no_spill: &[usize]| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let values: Vec<VReg> = (0..8).map(|_| builder.vreg()).collect();
+            for &i in no_spill {
+                builder.no_spill(values[i]);
+            }
+            for &value in &values {
+                let def = Operand::new(
+                    value,
+                    OperandPolicy::Any,
+                    OperandKind::Def,
+                    OperandPos::After,
+                );
+                builder.inst(block, &[def], &[]);
+            }
+            let uses: Vec<Operand> = values
+                .iter()
+                .map(|&value| {
+                    Operand::new(
+                        value,
+                        OperandPolicy::Any,
+                        OperandKind::Use,
+                        OperandPos::Before,
+                    )
+                })
+                .collect();
+            builder
+                .inst(block, &[], &clobbers)
+                .inst(block, &uses, &[])
+                .ret(block);
+            builder.build().unwrap()
+        };
+        let spilled = |no_spill: &[usize]| -> Result<Vec<usize>, RegAllocError> {
+            let func = build(no_spill);
+            let out = run_with_options(&func, &env, &RegallocOptions::default())?;
+            let uses = out.inst_allocs(Inst::new(func.insts() - 2));
+            Ok((0..8).filter(|&i| uses[i].as_stack().is_some()).collect())
+        };
+
+        let default = spilled(&[]).unwrap()[0];
+        let other = spilled(&[default]).unwrap();
+        assert_eq!(other.len(), 1);
+        assert_ne!(other[0], default);
+        let all_but_one: Vec<usize> = (0..8).filter(|&i| i != default).collect();
+        assert_eq!(spilled(&all_but_one).unwrap(), vec![default]);
+        let all: Vec<usize> = (0..8).collect();
+        match spilled(&all) {
+            Err(err @ RegAllocError::NoSpill(..)) => assert!(err
+                .to_string()
+                .contains("must stay in a register, but no register is left for it")),
+            other => panic!("expected a no-spill error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_impossible_no_spill() {
+        // Three no-spill values live at once, with two registers:
+        // every preset reports the first of them that had to spill.
+        let mut env = machine_env();
+        env.regs_by_class[RegClass::Int as u8 as usize].truncate(2);
+        env.regs.truncate(2);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v: Vec<VReg> = (0..3).map(|_| builder.vreg()).collect();
+        for &vreg in &v {
+            builder
+                .no_spill(vreg)
+                .inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        for i in 0..20 {
+            let t = builder.vreg();
+            builder.inst(block, &[Operand::reg_def(t)], &[]).inst(
+                block,
+                &[Operand::reg_use(t), Operand::reg_use(v[i % 3])],
+                &[],
+            );
+        }
+        let uses: Vec<Operand> = v
+            .iter()
+            .map(|&vreg| {
+                Operand::new(
+                    vreg,
+                    OperandPolicy::Any,
+                    OperandKind::Use,
+                    OperandPos::Before,
+                )
+            })
+            .collect();
+        builder.inst(block, &uses, &[]).ret(block);
+        let func = builder.build().unwrap();
+        for options in [
+            RegallocOptions::default(),
+            RegallocOptions::high_effort(),
+            RegallocOptions::optimize_for_size(),
+            RegallocOptions::optimize_for_compile_speed(),
+        ] {
+            match run_with_options(&func, &env, &options) {
+                Err(RegAllocError::NoSpill(vreg, _)) => assert!(v.contains(&vreg)),
+                other => panic!("expected NoSpill, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_spill_merged_with_reftype() {
+        // A no-spill blockparam merges with its input, a reference
+        // that must be on the stack across a call; every other operand
+        // of either is `Any`. The merged bundle is split apart rather
+        // than spilled whole, so the blockparam still gets a register.
+        // Reduced from a fuzzed function on
+        // which some presets reported `NoSpill` and others did not.
+        let mut builder = FuncBuilder::new();
+        let b0 = builder.block();
+        let b1 = builder.block();
+        let v0 = builder.vreg();
+        let v1 = builder.vreg();
+        let any_use = |vreg| {
+            Operand::new(
+                vreg,
+                OperandPolicy::Any,
+                OperandKind::Use,
+                OperandPos::Before,
+            )
+        };
+        let any_def = Operand::new(v0, OperandPolicy::Any, OperandKind::Def, OperandPos::After);
+        builder.reftype(v0).no_spill(v1).inst(b0, &[any_def], &[]);
+        builder.add_inst(b0, InstData::new(InstOpcode::Call, vec![], vec![]));
+        builder.add_edge(b0, b1);
+        let branch = InstData::new(InstOpcode::Branch, vec![any_use(v0)], vec![]);
+        builder.add_inst(b0, branch);
+        builder
+            .params(b1, &[v1])
+            .inst(b1, &[any_use(v1)], &[])
+            .inst(b1, &[any_use(v1)], &[])
+            .ret(b1);
+        let func = builder.build().unwrap();
+        let env = machine_env();
+        for options in [
+            RegallocOptions::default(),
+            RegallocOptions::high_effort(),
+            RegallocOptions::optimize_for_size(),
+            RegallocOptions::optimize_for_compile_speed(),
+        ] {
+            let allocated = Liveness::compute(&func, &env, &options)
+                .expect("liveness failed")
+                .merge()
+                .allocate();
+            assert_eq!(allocated.spilled_no_spill_vreg(), None);
+            for (_, alloc) in allocated.vreg_allocations(v1) {
+                assert!(alloc.as_reg().is_some(), "v1 in {:?}", alloc);
+            }
+            let safepoint = allocated
+                .vreg_allocations(v0)
+                .into_iter()
+                .find(|(range, _)| range.contains_point(ProgPoint::before(Inst::new(1))));
+            assert!(safepoint.unwrap().1.as_stack().is_some());
+        }
+    }
+
+    #[test]
+    fn test_regions() {
+        // Three blocks of three or four insts each, with regions of
+        // three: v0, live through all of them, is split at both region
+        // boundaries, and no bundle spans one, before or after
+        // allocation.
+        let mut builder = FuncBuilder::new();
+        let blocks: Vec<Block> = (0..3).map(|_| builder.block()).collect();
+        let v0 = builder.vreg();
+        builder.inst(blocks[0], &[Operand::reg_def(v0)], &[]);
+        for (i, &block) in blocks.iter().enumerate() {
+            let v1 = builder.vreg();
+            builder.inst(block, &[Operand::reg_def(v1)], &[]).inst(
+                block,
+                &[Operand::reg_use(v1), Operand::reg_use(v0)],
+                &[],
+            );
+            match blocks.get(i + 1) {
+                Some(&next) => builder.branch(block, &[next], &[]),
+                None => builder.ret(block),
+            };
+        }
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let options = RegallocOptions {
+            region_size: Some(3),
+            ..RegallocOptions::default()
+        };
+        let cfginfo = CFGInfo::new(&func);
+        let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+        env.create_pregs_and_vregs();
+        env.compute_liveness().expect("liveness failed");
+        env.merge_vreg_bundles();
+        env.queue_bundles();
+        assert_eq!(env.stats.region_count, 3);
+        assert_eq!(env.stats.region_splits, 2);
+
+        let boundaries: Vec<ProgPoint> = blocks[1..]
+            .iter()
+            .map(|&block| ProgPoint::before(func.block_insns(block).first()))
+            .collect();
+        let check = |env: &Env<Func>| {
+            for bundle in 0..env.bundles.len() {
+                let mut regions = vec![];
+                let mut iter = env.bundles[bundle].first_range;
+                while iter.is_valid() {
+                    let range = env.ranges[iter.index()].range;
+                    regions.push(boundaries.partition_point(|&b| b <= range.from));
+                    regions.push(boundaries.partition_point(|&b| b < range.to));
+                    iter = env.ranges[iter.index()].next_in_bundle;
+                }
+                assert!(
+                    regions.windows(2).all(|w| w[0] == w[1]),
+                    "bundle{} spans regions {:?}",
+                    bundle,
+                    regions
+                );
+            }
+        };
+        check(&env);
+        env.process_bundles();
+        check(&env);
+        let v0_bundles: Vec<LiveBundleIndex> = {
+            let mut bundles = vec![];
+            let mut iter = env.vregs[v0.vreg()].first_range;
+            while iter.is_valid() {
+                bundles.push(env.ranges[iter.index()].bundle);
+                iter = env.ranges[iter.index()].next_in_reg;
+            }
+            bundles.dedup();
+            bundles
+        };
+        assert_eq!(v0_bundles.len(), 3);
+    }
+
+    #[test]
+    fn test_regions_in_order() {
+        // With regions, one region's bundles are all processed before
+        // the next region's, whatever their priorities.
+        let mut builder = FuncBuilder::new();
+        let blocks: Vec<Block> = (0..3).map(|_| builder.block()).collect();
+        let v0 = builder.vreg();
+        builder.inst(blocks[0], &[Operand::reg_def(v0)], &[]);
+        for (i, &block) in blocks.iter().enumerate() {
+            // Longer-lived vregs, of higher priority, come later.
+            let vregs: Vec<VReg> = (0..=i).map(|_| builder.vreg()).collect();
+            for &vreg in &vregs {
+                builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+            }
+            builder.inst(block, &[Operand::reg_use(v0)], &[]);
+            for &vreg in &vregs {
+                builder.inst(block, &[Operand::reg_use(vreg)], &[]);
+            }
+            match blocks.get(i + 1) {
+                Some(&next) => builder.branch(block, &[next], &[]),
+                None => builder.ret(block),
+            };
+        }
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let options = RegallocOptions {
+            region_size: Some(4),
+            ..RegallocOptions::default()
+        };
+        let region_of = |point: ProgPoint| {
+            blocks
+                .iter()
+                .rposition(|&block| func.block_insns(block).first() <= point.inst)
+                .unwrap()
+        };
+        let regions: Vec<usize> = Liveness::compute(&func, &mach_env, &options)
+            .unwrap()
+            .merge()
+            .allocate_stepwise()
+            .map(|decision| region_of(decision.ranges[0].from))
+            .collect();
+        assert!(regions.windows(2).all(|w| w[0] <= w[1]), "{:?}", regions);
+        assert_eq!(regions.last(), Some(&2));
+    }
+
+    #[test]
+    fn test_arena_kept_on_error() {
+        // A run that fails once liveness is under way still gives the
+        // arena back its storage.
+        let mach_env = machine_env();
+        let options = RegallocOptions::default();
+        let mut arena = Arena::default();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        run_with_arena(&func, &mach_env, &options, &mut arena).unwrap();
+        let capacity = arena.ranges.capacity();
+        assert!(capacity > 0);
+
+        // A pinned vreg read from the stack fails once its ranges are
+        // built.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let stack_use = Operand::new(
+            v0,
+            OperandPolicy::Stack,
+            OperandKind::Use,
+            OperandPos::Before,
+        );
+        builder
+            .pin(v0, PReg::new(31, RegClass::Int))
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[stack_use], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        match run_with_arena(&func, &mach_env, &options, &mut arena) {
+            Err(RegAllocError::Pinned(vreg, _)) => assert_eq!(vreg, v0),
+            other => panic!("expected Pinned, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(arena.ranges.capacity(), capacity);
+        assert!(arena.ranges.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_derived_keepalive_late_def() {
+        // The base of a reference derived from it dies at a call,
+        // across which only the derived reference is live, and the
+        // call defines a value late into a spillslot. That value must
+        // not take the slot the base is kept in for the stackmap.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let v1 = builder.vreg();
+        let v2 = builder.vreg();
+        builder.reftype(v0).reftype(v1).derived(v1, v0);
+        builder.inst(block, &[Operand::reg_def(v0)], &[]).inst(
+            block,
+            &[Operand::reg_def(v1), Operand::reg_use(v0)],
+            &[],
+        );
+        let late_def = Operand::new(
+            v2,
+            OperandPolicy::Stack,
+            OperandKind::Def,
+            OperandPos::AfterLate,
+        );
+        let clobbers = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        builder.add_inst(
+            block,
+            InstData::new(InstOpcode::Call, vec![late_def], clobbers),
+        );
+        builder
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let call = Inst::new(2);
+        let mach_env = machine_env();
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..RegallocOptions::default()
+            };
+            let out = run_with_options(&func, &mach_env, &options).unwrap();
+            let (_, _, base) = out.safepoint_derived_slots[0];
+            assert_ne!(out.inst_allocs(call)[0], Allocation::stack(base));
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+        }
+    }
+
+    #[test]
+    fn test_pre_spill() {
+        // Three registers, and four values live across insts 3 and 4,
+        // where v2 and v3 need two of them. v0 and v1 are live across
+        // without being used there; v0, with fewer uses, is cheaper,
+        // so it alone is spilled across the run up front.
+        let mut env = machine_env();
+        env.regs_by_class[RegClass::Int as u8 as usize].truncate(3);
+        env.regs.truncate(3);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v: Vec<VReg> = (0..4).map(|_| builder.vreg()).collect();
+        for &vreg in &v {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        builder.inst(
+            block,
+            &[Operand::reg_use(v[2]), Operand::reg_use(v[3])],
+            &[],
+        );
+        builder.inst(block, &[Operand::reg_use(v[0])], &[]);
+        for _ in 0..3 {
+            builder.inst(block, &[Operand::reg_use(v[1])], &[]);
+        }
+        builder.ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions {
+            pre_spill: true,
+            ..RegallocOptions::default()
+        };
+
+        let allocated = Liveness::compute(&func, &env, &options)
+            .expect("liveness failed")
+            .merge()
+            .allocate();
+        assert_eq!(allocated.stats().pre_spills, 1);
+        let across = CodeRange {
+            from: ProgPoint::before(Inst::new(3)),
+            to: ProgPoint::before(Inst::new(5)),
+        };
+        let in_run = |vreg: VReg| -> Vec<Allocation> {
+            allocated
+                .vreg_allocations(vreg)
+                .into_iter()
+                .filter(|(range, _)| range.overlaps(&across))
+                .map(|(_, alloc)| alloc)
+                .collect()
+        };
+        assert!(matches!(in_run(v[0])[..], [alloc] if alloc.as_stack().is_some()));
+        assert!(in_run(v[1]).iter().all(|alloc| alloc.as_reg().is_some()));
+        assert_eq!(allocated.stats().evict_bundle_count, 0);
+
+        // Without it, a register is found for v3 by evicting.
+        let options = RegallocOptions::default();
+        let allocated = Liveness::compute(&func, &env, &options)
+            .expect("liveness failed")
+            .merge()
+            .allocate();
+        assert_eq!(allocated.stats().pre_spills, 0);
+        assert_eq!(allocated.stats().evict_bundle_count, 1);
+    }
+
+    #[test]
+    fn test_precolor_fixed() {
+        // v0's def and v1's use are both fixed to p0. Their pieces
+        // around those operands claim p0 before the main loop, and v1
+        // is hinted to it throughout, so v0 moves out of the way
+        // rather than being evicted when v1 needs the register.
+        let p0 = PReg::new(0, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, p0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_fixed_use(v1, p0)], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let env = machine_env();
+        let options = RegallocOptions::default();
+        let allocated = Liveness::compute(&func, &env, &options)
+            .expect("liveness failed")
+            .merge()
+            .allocate();
+        assert_eq!(allocated.stats().precolored_bundles, 2);
+        assert_eq!(allocated.stats().evict_bundle_count, 0);
+
+        let out = allocated.reify();
+        let p0 = Allocation::reg(p0);
+        assert_eq!(out.inst_allocs(Inst::new(1)), &[p0]);
+        assert_eq!(out.inst_allocs(Inst::new(2)), &[p0]);
+        let moved_to = out.inst_allocs(Inst::new(3))[0];
+        assert_ne!(moved_to, p0);
+        assert_eq!(
+            out.edits,
+            vec![(
+                ProgPoint::before(Inst::new(1)),
+                Edit::Move {
+                    from: p0,
+                    to: moved_to
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_spillslot_per_vreg() {
+        // One register, clobbered while v0 and then v1 are live: v0
+        // is spilled twice, and v1 once, after v0 is dead.
+        let mut env = machine_env();
+        env.regs_by_class[RegClass::Int as u8 as usize].truncate(1);
+        env.regs.truncate(1);
+        let p0 = PReg::new(0, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[], &[p0])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .inst(block, &[], &[p0])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[], &[p0])
+            .inst(block, &[Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        for per_vreg in [false, true] {
+            let options = RegallocOptions {
+                spillslot_per_vreg: per_vreg,
+                ..RegallocOptions::default()
+            };
+            let allocated = Liveness::compute(&func, &env, &options)
+                .expect("liveness failed")
+                .merge()
+                .allocate();
+            let slots = |vreg: VReg| -> Vec<SpillSlot> {
+                let mut slots: Vec<SpillSlot> = allocated
+                    .vreg_allocations(vreg)
+                    .iter()
+                    .filter_map(|(_, alloc)| alloc.as_stack())
+                    .collect();
+                slots.dedup();
+                slots
+            };
+            // v0 is in one slot wherever it is spilled. v1 shares it
+            // by default, and has its own in this mode.
+            let (s0, s1) = (slots(v0), slots(v1));
+            assert_eq!(s0.len(), 1);
+            assert_eq!(s1.len(), 1);
+            assert_eq!(s0 == s1, !per_vreg);
+            assert_eq!(allocated.num_spillslots(), if per_vreg { 2 } else { 1 });
+        }
+    }
+
+    /// Two references, v0 and v2, and a plain value, v1, live across a
+    /// call at inst3 that clobbers p0..p23; the call defines v3 and
+    /// takes `arg` as well.
+    fn stackmap_func(arg: Operand) -> Func {
+        let int = |i| PReg::new(i, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v: Vec<VReg> = (0..4).map(|_| builder.vreg()).collect();
+        builder.reftype(v[0]).reftype(v[2]);
+        for &vreg in &v[..3] {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        let call = vec![Operand::reg_def(v[3]), arg];
+        let clobbers = (0..24).map(int).collect();
+        builder.add_inst(block, InstData::new(InstOpcode::Call, call, clobbers));
+        let uses: Vec<Operand> = v.iter().map(|&vreg| Operand::reg_use(vreg)).collect();
+        builder.inst(block, &uses, &[]).ret(block);
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_stackmaps() {
+        // The references are spilled across the call, and the stackmap
+        // at the call lists exactly their slots. v1 stays in a register
+        // the call does not clobber, and v2 is also read by the call
+        // from p0.
+        let env = machine_env();
+        let p0 = PReg::new(0, RegClass::Int);
+        let func = stackmap_func(Operand::reg_fixed_use(VReg::new(2, RegClass::Int), p0));
+        let options = RegallocOptions::default();
+        let allocated = Liveness::compute(&func, &env, &options)
+            .expect("liveness failed")
+            .merge()
+            .allocate();
+        let call = Inst::new(3);
+        let across = |vreg: usize| -> Allocation {
+            allocated
+                .vreg_allocations(VReg::new(vreg, RegClass::Int))
+                .into_iter()
+                .find(|(range, _)| range.contains_point(ProgPoint::after(call)))
+                .expect("not live across the call")
+                .1
+        };
+        let slot = |vreg: usize| across(vreg).as_stack().expect("not spilled");
+        let (s0, s2) = (slot(0), slot(2));
+        assert!(across(1).as_reg().is_some_and(|preg| preg.hw_enc() >= 24));
+        let out = allocated.reify();
+        let mut expected = vec![(call, s0), (call, s2)];
+        expected.sort_by_key(|&(inst, slot)| (inst, slot.index()));
+        assert_eq!(out.safepoint_slots, expected);
+        assert!(out.safepoint_derived_slots.is_empty());
+        assert_eq!(out.inst_allocs(call)[1], Allocation::reg(p0));
+
+        // A reference live across the call cannot also be read there
+        // from a register.
+        let func = stackmap_func(Operand::reg_use(VReg::new(0, RegClass::Int)));
+        match run_with_options(&func, &env, &options) {
+            Err(RegAllocError::RefUseAtSafepoint(vreg, inst)) => {
+                assert_eq!((vreg.vreg(), inst), (0, call));
+            }
+            other => panic!("expected a reference-use error, got {:?}", other),
+        }
+    }
+
+    /// v1 is a reference derived from v0 at inst1, live across the
+    /// call at inst2 after v0's last use.
+    fn derived_ref_func(base_is_ref: bool) -> Func {
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder.reftype(v1).derived(v1, v0);
+        if base_is_ref {
+            builder.reftype(v0);
+        }
+        builder.inst(block, &[Operand::reg_def(v0)], &[]).inst(
+            block,
+            &[Operand::reg_def(v1), Operand::reg_use(v0)],
+            &[],
+        );
+        let clobbers = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let call = vec![Operand::reg_def(v2)];
+        builder.add_inst(block, InstData::new(InstOpcode::Call, call, clobbers));
+        builder
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_derived_refs() {
+        // v0 is kept alive across the call for v1's sake, and both are
+        // spilled there: the stackmap lists v0's slot, and pairs v1's
+        // with it.
+        let env = machine_env();
+        let func = derived_ref_func(true);
+        let options = RegallocOptions::default();
+        let allocated = Liveness::compute(&func, &env, &options)
+            .expect("liveness failed")
+            .merge()
+            .allocate();
+        let call = Inst::new(2);
+        let slot = |vreg: usize| -> SpillSlot {
+            allocated
+                .vreg_allocations(VReg::new(vreg, RegClass::Int))
+                .into_iter()
+                .find(|(range, _)| range.contains_point(ProgPoint::after(call)))
+                .and_then(|(_, alloc)| alloc.as_stack())
+                .expect("not spilled across the call")
+        };
+        let (base, derived) = (slot(0), slot(1));
+        assert_ne!(base, derived);
+        let out = allocated.reify();
+        assert_eq!(out.safepoint_slots, vec![(call, base)]);
+        assert_eq!(out.safepoint_derived_slots, vec![(call, derived, base)]);
+
+        // The base must be a reference too.
+        let func = derived_ref_func(false);
+        match run_with_options(&func, &env, &options) {
+            Err(RegAllocError::DerivedRef(vreg)) => assert_eq!(vreg.vreg(), 1),
+            other => panic!("expected a derived-reference error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_callee_saves() {
+        // Only p24, the one callee-saved register the function uses, is
+        // saved at entry and restored before the return.
+        let env = machine_env();
+        let p24 = PReg::new(24, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, p24)], &[])
+            .inst(block, &[Operand::reg_def(v1), Operand::reg_use(v0)], &[])
+            .inst(block, &[Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions {
+            save_callee_saves: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        let slot = SpillSlot::new(0, RegClass::Int);
+        assert_eq!(out.callee_saves, vec![(p24, slot)]);
+        assert_eq!(
+            out.to_string(),
+            "spillslots: 1\n\
+             spillslot offsets: stack0@0 (area size 8)\n\
+             \x20 before inst0: move p24i -> stack0\n\
+             inst0: p24i\n\
+             inst1: p1i, p24i\n\
+             inst2: p1i\n\
+             \x20 before inst3: move stack0 -> p24i\n\
+             inst3:\n"
+        );
+    }
+
+    #[test]
+    fn test_callee_saves_restricted() {
+        // The registers are moved back before the run of insts that
+        // edits are forbidden within, and kept free through it.
+        let env = machine_env();
+        let options = RegallocOptions {
+            save_callee_saves: true,
+            ..RegallocOptions::default()
+        };
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let vregs: Vec<VReg> = (0..env.regs.len()).map(|_| builder.vreg()).collect();
+        for &vreg in &vregs {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        let uses: Vec<Operand> = vregs.iter().map(|&vreg| Operand::reg_use(vreg)).collect();
+        builder
+            .inst(block, &uses, &[])
+            .inst(block, &[Operand::reg_use(vregs[0])], &[])
+            .no_edits_after(block)
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        let mut saved: Vec<PReg> = out.callee_saves.iter().map(|&(preg, _)| preg).collect();
+        saved.sort_unstable_by_key(|preg| preg.index());
+        assert_eq!(saved, env.callee_saved_regs[..saved.len()]);
+        assert!(!saved.is_empty());
+        let ret = Inst::new(func.insts() - 1);
+        for &(preg, slot) in &out.callee_saves {
+            assert!(out.edits.iter().any(|(pos, edit)| {
+                *pos == ProgPoint::before(ret.prev())
+                    && matches!(edit, Edit::Move { from, to }
+                        if *from == Allocation::stack(slot) && *to == Allocation::reg(preg))
+            }));
+        }
+        let alloc = out.inst_allocs(ret.prev())[0];
+        assert!(!env.callee_saved_regs.contains(&alloc.as_reg().unwrap()));
+
+        // An operand fixed to a callee-saved register cannot be read
+        // after its restore.
+        let preg = env.callee_saved_regs[0];
+        for restricted in [false, true] {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            let ret_use = [Operand::reg_fixed_use(v0, preg)];
+            builder.inst(block, &[Operand::reg_def(v0)], &[]);
+            if restricted {
+                builder
+                    .inst(block, &ret_use, &[])
+                    .no_edits_after(block)
+                    .ret(block);
+            } else {
+                builder.add_inst(
+                    block,
+                    InstData::new(InstOpcode::Ret, ret_use.to_vec(), vec![]),
+                );
+            }
+            let func = builder.build().unwrap();
+            assert!(matches!(
+                run_with_options(&func, &env, &options),
+                Err(RegAllocError::CalleeSavedFixedReg(p, inst)) if p == preg && inst.index() == 1
+            ));
+            run_with_options(&func, &env, &RegallocOptions::default())
+                .expect("regalloc did not succeed");
+        }
+    }
+
+    /// v0 is defined before an instruction that clobbers every
+    /// register, and used after it.
+    fn remat_func(remat: bool) -> Func {
+        let clobbers: Vec<PReg> = (0..31).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let any_use = Operand::new(v0, OperandPolicy::Any, OperandKind::Use, OperandPos::Before);
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[], &clobbers)
+            .inst(block, &[any_use], &[])
+            .ret(block);
+        if remat {
+            builder.remat(v0, RematKind::Address);
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_remat() {
+        // A rematerializable v0 is recomputed into a register before
+        // its use, rather than spilled and reloaded.
+        let env = machine_env();
+        let options = RegallocOptions::default();
+        let v0 = VReg::new(0, RegClass::Int);
+        let func = remat_func(true);
+        let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        let use_inst = Inst::new(2);
+        let to = out.inst_allocs(use_inst)[0];
+        assert!(to.as_reg().is_some());
+        assert_eq!(
+            out.edits,
+            vec![(
+                ProgPoint::before(use_inst),
+                Edit::Remat {
+                    vreg: v0,
+                    kind: RematKind::Address,
+                    to
+                }
+            )]
+        );
+        assert_eq!(out.num_spillslots, 0);
+        assert_eq!(out.stats.remats_count, 1);
+
+        // Otherwise it is spilled after its def.
+        let func = remat_func(false);
+        let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        assert_eq!(out.num_spillslots, 1);
+        assert!(out
+            .edits
+            .iter()
+            .all(|(_, edit)| matches!(edit, Edit::Move { .. })));
+        assert_eq!(out.stats.remats_count, 0);
+    }
+
+    #[test]
+    fn test_large_reg_class() {
+        // 200 integer registers and 200 values live at once: every
+        // register is handed out, the high-numbered ones included,
+        // and nothing is spilled.
+        let regs: Vec<PReg> = (0..200).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let mach_env = MachineEnv {
+            regs: regs.clone(),
+            regs_by_class: vec![regs, vec![]],
+            scratch_by_class: vec![
+                PReg::new(PReg::MAX, RegClass::Int),
+                PReg::new(0, RegClass::Float),
+            ],
+            ..MachineEnv::default()
+        };
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v: Vec<VReg> = (0..200).map(|_| builder.vreg()).collect();
+        for &vreg in &v {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        for &vreg in v.iter().rev() {
+            builder.inst(block, &[Operand::reg_use(vreg)], &[]);
+        }
+        builder.ret(block);
+        let func = builder.build().unwrap();
+        let out = run_with_options(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        assert_eq!(out.num_spillslots, 0);
+        let mut used: Vec<usize> = (0..200)
+            .map(|inst| {
+                out.inst_allocs(Inst::new(inst))[0]
+                    .as_reg()
+                    .unwrap()
+                    .hw_enc()
+            })
+            .collect();
+        used.sort_unstable();
+        used.dedup();
+        assert_eq!(used, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_spillslot_offsets() {
+        // Three values on the stack at once: an integer, a float and
+        // another integer. Each slot is laid out in slot order at the
+        // alignment of its class, eight bytes per integer unit and
+        // sixteen per float unit; a float takes two units, starting
+        // at an even slot index.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let v1 = VReg::new(builder.vreg().vreg(), RegClass::Float);
+        let v2 = builder.vreg();
+        let stack = |vreg, kind| Operand::new(vreg, OperandPolicy::Stack, kind, OperandPos::Before);
+        for &vreg in &[v0, v1, v2] {
+            builder.inst(block, &[stack(vreg, OperandKind::Def)], &[]);
+        }
+        for &vreg in &[v0, v1, v2] {
+            builder.inst(block, &[stack(vreg, OperandKind::Use)], &[]);
+        }
+        builder.ret(block);
+        let func = builder.build().unwrap();
+        let mut mach_env = machine_env();
+        let f1 = PReg::new(1, RegClass::Float);
+        mach_env.regs_by_class[RegClass::Float as u8 as usize].push(f1);
+        mach_env.regs.push(f1);
+        let out = run_with_options(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        let slot = |inst| out.inst_allocs(Inst::new(inst))[0].as_stack().unwrap();
+        assert_eq!(slot(0).index(), 0);
+        assert_eq!(slot(1).index(), 2);
+        assert_eq!(slot(2).index(), 4);
+        assert_eq!(out.spillslot_offset(slot(0)), Some(0));
+        assert_eq!(out.spillslot_offset(slot(1)), Some(16));
+        assert_eq!(out.spillslot_offset(slot(2)), Some(48));
+        assert_eq!(out.spillslot_area_size, 56);
+        // The padding before the float slot names no slot.
+        assert_eq!(out.spillslot_offset(SpillSlot::new(1, RegClass::Int)), None);
+    }
+
+    #[test]
+    fn test_reserved_spillslots() {
+        // With three slots reserved by the embedder, the first slot
+        // either allocator hands out is the fourth, and the layout
+        // gives the reserved ones no offset.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .reserved_spillslots(3)
+            .inst(block, &[Operand::stack_def(v0)], &[])
+            .inst(block, &[Operand::stack_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..RegallocOptions::default()
+            };
+            let out = run_with_options(&func, &mach_env, &options).unwrap();
+            let slot = SpillSlot::new(3, RegClass::Int);
+            assert_eq!(out.num_spillslots, 4);
+            assert_eq!(out.inst_allocs(Inst::new(0)), &[Allocation::stack(slot)]);
+            assert_eq!(out.inst_allocs(Inst::new(1)), &[Allocation::stack(slot)]);
+            assert_eq!(out.spillslot_offset(slot), Some(0));
+            for reserved in 0..3 {
+                let reserved = SpillSlot::new(reserved, RegClass::Int);
+                assert_eq!(out.spillslot_offset(reserved), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        // A constant is never spilled: after each inst that clobbers
+        // every register, it is defined again into a register just
+        // before its next use.
+        let clobbers: Vec<PReg> = (0..31).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .inst(block, &[Operand::constant_def(v0)], &[])
+            .inst(block, &[], &clobbers)
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .inst(block, &[], &clobbers)
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_with_options(&func, &machine_env(), &RegallocOptions::default())
+            .expect("regalloc did not succeed");
+        assert_eq!(
+            out.to_string(),
+            "spillslots: 0\n\
+             inst0: p0i\n\
+             inst1:\n\
+             \x20 before inst2: constant v0 -> p3i\n\
+             inst2: p3i\n\
+             inst3:\n\
+             \x20 before inst4: constant v0 -> p3i\n\
+             inst4: p3i\n\
+             inst5:\n"
+        );
+    }
+
+    #[test]
+    fn test_no_scratch_reg() {
+        // v0 and v1 swap registers before inst2, a move cycle, while
+        // v2 is live across it. Without a designated scratch register,
+        // a free one breaks the cycle; if the only register the moves
+        // do not touch holds v2, it is saved and restored around them.
+        let p = |i| PReg::new(i, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v2)], &[])
+            .inst(
+                block,
+                &[
+                    Operand::reg_fixed_def(v0, p(0)),
+                    Operand::reg_fixed_def(v1, p(1)),
+                ],
+                &[],
+            )
+            .inst(
+                block,
+                &[
+                    Operand::reg_fixed_use(v0, p(1)),
+                    Operand::reg_fixed_use(v1, p(0)),
+                ],
+                &[],
+            )
+            .inst(block, &[Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let mut mach_env = MachineEnv {
+            scratch_by_class: vec![],
+            ..machine_env()
+        };
+        let options = RegallocOptions::default();
+
+        let out = run_with_options(&func, &mach_env, &options).unwrap();
+        assert_eq!(
+            out.to_string(),
+            "spillslots: 0\n\
+             inst0: p2i\n\
+             inst1: p0i, p1i\n\
+             \x20 before inst2: move p0i -> p3i\n\
+             \x20 before inst2: move p1i -> p0i\n\
+             \x20 before inst2: move p3i -> p1i\n\
+             inst2: p1i, p0i\n\
+             inst3: p2i\n\
+             inst4:\n"
+        );
+        assert_eq!(out.stats.dynamic_scratch_count, 1);
+        assert_eq!(out.stats.scratch_saves_count, 0);
+
+        mach_env.regs_by_class[RegClass::Int as u8 as usize].truncate(3);
+        mach_env.regs.truncate(3);
+        let out = run_with_options(&func, &mach_env, &options).unwrap();
+        assert_eq!(
+            out.to_string(),
+            "spillslots: 1\n\
+             spillslot offsets: stack0@0 (area size 8)\n\
+             inst0: p2i\n\
+             inst1: p0i, p1i\n\
+             \x20 before inst2: move p2i -> stack0\n\
+             \x20 before inst2: move p0i -> p2i\n\
+             \x20 before inst2: move p1i -> p0i\n\
+             \x20 before inst2: move p2i -> p1i\n\
+             \x20 before inst2: move stack0 -> p2i\n\
+             inst2: p1i, p0i\n\
+             inst3: p2i\n\
+             inst4:\n"
+        );
+        assert_eq!(out.stats.dynamic_scratch_count, 1);
+        assert_eq!(out.stats.scratch_saves_count, 1);
+    }
+
+    #[test]
+    fn test_elided_moves() {
+        // v1 is a copy of v0, which dies there, so they share a
+        // register and the move is reported as elided. v2 is a copy of
+        // v1, but both are live after it, so that move must stay.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        let copy = |to, from| {
+            InstData::new(
+                InstOpcode::Move,
+                vec![Operand::reg_def(to), Operand::reg_use(from)],
+                vec![],
+            )
+        };
+        builder.inst(block, &[Operand::reg_def(v0)], &[]);
+        builder.add_inst(block, copy(v1, v0));
+        builder.add_inst(block, copy(v2, v1));
+        builder
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_with_options(&func, &machine_env(), &RegallocOptions::default())
+            .expect("regalloc did not succeed");
+        let (first, second) = (Inst::new(1), Inst::new(2));
+        assert!(out.is_elided_move(first));
+        assert_eq!(out.inst_allocs(first)[0], out.inst_allocs(first)[1]);
+        assert!(!out.is_elided_move(second));
+        assert_ne!(out.inst_allocs(second)[0], out.inst_allocs(second)[1]);
+        assert_eq!(out.stats.elided_moves_count, 1);
+    }
+
+    #[test]
+    fn test_compare_splits() {
+        // v0 is live across three insts that clobber p5. By default a
+        // split goes around each clobber, the first kind in priority
+        // order; comparing splits instead takes the one move after the
+        // def, as the cheapest.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder.inst(block, &[Operand::reg_def(v0)], &[]);
+        for _ in 0..3 {
+            builder.inst(block, &[], &[PReg::new(5, RegClass::Int)]);
+        }
+        builder.inst(block, &[Operand::reg_use(v0)], &[]).ret(block);
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let split = |options: &RegallocOptions| {
+            let cfginfo = CFGInfo::new(&func);
+            let mut env = Env::new(&func, &mach_env, options, cfginfo, Arena::default());
+            env.create_pregs_and_vregs();
+            env.compute_liveness().expect("liveness failed");
+            env.merge_vreg_bundles();
+            env.queue_bundles();
+            let bundle = env.ranges[env.vregs[v0.vreg()].first_range.index()].bundle;
+            env.find_split_points(bundle, LiveBundleIndex::invalid())
+        };
+        let before = |inst| ProgPoint::before(Inst::new(inst));
+
+        let (reason, points) = split(&RegallocOptions::default());
+        assert_eq!(reason, SplitReason::Clobbers);
+        assert_eq!(&points[..], &[before(1), before(2), before(3)]);
+
+        let (reason, points) = split(&RegallocOptions::high_effort());
+        assert_eq!(reason, SplitReason::AfterConflict);
+        assert_eq!(&points[..], &[before(1)]);
+    }
+
+    #[test]
+    fn test_register_pressure() {
+        // Each block's pressure is the most values of each class live
+        // at once in it, counting the classes separately: in the
+        // entry, v0 and v1 with f0 and f1 (f0 dies where v1 is
+        // defined); in the exit, v0 and v1 with f1, then v2 with f1.
+        let mut mach_env = machine_env();
+        let floats: Vec<PReg> = (1..8).map(|i| PReg::new(i, RegClass::Float)).collect();
+        mach_env.regs.extend(floats.iter().cloned());
+        mach_env.regs_by_class[RegClass::Float as usize] = floats;
+        let mut builder = FuncBuilder::new();
+        let (entry, exit) = (builder.block(), builder.block());
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        let (f0, f1) = (builder.vreg(), builder.vreg());
+        let (f0, f1) = (
+            VReg::new(f0.vreg(), RegClass::Float),
+            VReg::new(f1.vreg(), RegClass::Float),
+        );
+        builder
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(f0)], &[])
+            .inst(entry, &[Operand::reg_def(f1)], &[])
+            .inst(entry, &[Operand::reg_def(v1), Operand::reg_use(f0)], &[])
+            .branch(entry, &[exit], &[])
+            .inst(
+                exit,
+                &[
+                    Operand::reg_def(v2),
+                    Operand::reg_use(v0),
+                    Operand::reg_use(v1),
+                ],
+                &[],
+            )
+            .inst(exit, &[Operand::reg_use(v2), Operand::reg_use(f1)], &[])
+            .ret(exit);
+        let func = builder.build().unwrap();
+        let expected = vec![
+            BlockPressure { int: 2, float: 2 },
+            BlockPressure { int: 2, float: 1 },
+        ];
+        let options = RegallocOptions::default();
+        let liveness = Liveness::compute(&func, &mach_env, &options).expect("liveness failed");
+        assert_eq!(liveness.register_pressure(), expected);
+        assert_eq!(
+            crate::register_pressure(&func, &mach_env).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_safepoint_locations() {
+        // v0 and v1 are live across the call and reported where they
+        // are during it, out of the registers it clobbers; v2, used by
+        // the call, is not live past it.
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_def(v2)], &[]);
+        let clobbers: Vec<PReg> = (0..8).map(|i| PReg::new(i, RegClass::Int)).collect();
+        builder.add_inst(
+            block,
+            InstData::new(
+                InstOpcode::Call,
+                vec![Operand::reg_use(v2)],
+                clobbers.clone(),
+            ),
+        );
+        builder
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions {
+            report_safepoint_locations: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+        let call = Inst::new(3);
+        let uses = out.inst_allocs(Inst::new(4));
+        assert_eq!(
+            out.safepoint_locations,
+            vec![(call, v0, uses[0]), (call, v1, uses[1])]
+        );
+        for &(_, _, alloc) in &out.safepoint_locations {
+            assert!(!matches!(alloc.as_reg(), Some(preg) if clobbers.contains(&preg)));
+        }
+
+        let out = run_with_options(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        assert!(out.safepoint_locations.is_empty());
+    }
+
+    #[test]
+    fn test_phases() {
+        // v0 dies where v1 is defined, so the two can share a bundle;
+        // v1 and v2 are live together, so they cannot.
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_def(v2)], &[])
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions::default();
+
+        // Driving the phases unmodified is the same as `run()`.
+        let liveness = Liveness::compute(&func, &mach_env, &options).expect("liveness failed");
+        assert!(liveness.can_allocate_linear());
+        let phased = liveness.allocate_linear().reify();
+        let out = run_with_options(&func, &mach_env, &options).unwrap();
+        assert_eq!(phased.to_string(), out.to_string());
+
+        // Replace the hot-code info and merge vregs between phases.
+        let mut liveness = Liveness::compute(&func, &mach_env, &options).expect("liveness failed");
+        let hot = CodeRange::new(
+            ProgPoint::before(Inst::new(1)),
+            ProgPoint::before(Inst::new(3)),
+        );
+        liveness.set_hot_code(&[hot]);
+        assert_eq!(liveness.hot_code(), vec![hot]);
+        let mut merge = liveness.merge();
+        assert_ne!(merge.vreg_bundle(v0), merge.vreg_bundle(v1));
+        assert!(merge.try_merge(v0, v1));
+        assert_eq!(merge.vreg_bundle(v0), merge.vreg_bundle(v1));
+        assert!(!merge.try_merge(v1, v2));
+        let allocated = merge.allocate();
+        let alloc = |vreg| {
+            let allocs = allocated.vreg_allocations(vreg);
+            assert_eq!(allocs.len(), 1);
+            allocs[0].1
+        };
+        assert_eq!(alloc(v0), alloc(v1));
+        assert_ne!(alloc(v1), alloc(v2));
+    }
+
+    #[test]
+    fn test_preferred_regs() {
+        // The caller-saved registers are preferred, and the
+        // callee-saved ones not.
+        let mut mach_env = machine_env();
+        mach_env.preferred_regs_by_class =
+            vec![(0..24).map(|i| PReg::new(i, RegClass::Int)).collect()];
+        mach_env.non_preferred_regs_by_class =
+            vec![(24..31).map(|i| PReg::new(i, RegClass::Int)).collect()];
+        mach_env.call_convs.push(CallConv {
+            name: "test".to_string(),
+            ..CallConv::default()
+        });
+        let options = RegallocOptions::default();
+
+        // v0 lives across a call and v1 does not, with one fixed use
+        // (so the function is not allocated linearly) if `fixed`.
+        let build = |fixed: bool| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            let v1 = builder.vreg();
+            builder.inst(block, &[Operand::reg_def(v0)], &[]);
+            builder.call(block, &[], 0);
+            builder.inst(block, &[Operand::reg_def(v1)], &[]);
+            let use_ = if fixed {
+                Operand::reg_fixed_use(v1, PReg::new(3, RegClass::Int))
+            } else {
+                Operand::reg_use(v1)
+            };
+            builder
+                .inst(block, &[Operand::reg_use(v0), use_], &[])
+                .ret(block);
+            builder.build().unwrap()
+        };
+        for fixed in [false, true] {
+            let func = build(fixed);
+            let out = run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+            let def_reg = |inst: usize| out.inst_allocs(Inst::new(inst))[0].as_reg().unwrap();
+            assert!(def_reg(0).hw_enc() >= 24);
+            assert!(def_reg(2).hw_enc() < 24);
+        }
+
+        // Preferences never make an allocation fail: nine values
+        // across a call, more than the seven callee-saved registers,
+        // all still get registers.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let vregs: Vec<VReg> = (0..9).map(|_| builder.vreg()).collect();
+        for &vreg in &vregs {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        builder.call(block, &[], 0);
+        let uses: Vec<Operand> = vregs.iter().map(|&v| Operand::reg_use(v)).collect();
+        builder.inst(block, &uses, &[]).ret(block);
+        let func = builder.build().unwrap();
+        let out = run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+        assert_eq!(out.num_spillslots, 0);
+        assert!(out.edits.is_empty());
+        let defs: Vec<_> = (0..9).map(|i| out.inst_allocs(Inst::new(i))[0]).collect();
+        assert_eq!(
+            defs.iter()
+                .filter(|a| a.as_reg().unwrap().hw_enc() >= 24)
+                .count(),
+            7
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_spill_weights() {
+        // Two registers, and two long-lived values competing for the
+        // one left over by the temps in between: the heavier value
+        // keeps it throughout, and only the other is split.
+        let mut env = machine_env();
+        env.regs_by_class[RegClass::Int as u8 as usize].truncate(2);
+        env.regs.truncate(2);
+        let options = RegallocOptions {
+            explain: true,
+            ..RegallocOptions::default()
+        };
+        let build = |heavy: usize, weight: SpillWeight| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let values = [builder.vreg(), builder.vreg()];
+            builder.spill_weight(values[heavy], weight);
+            for &value in &values {
+                builder.inst(block, &[Operand::reg_def(value)], &[]);
+            }
+            for _ in 0..4 {
+                let temp = builder.vreg();
+                builder.inst(block, &[Operand::reg_def(temp)], &[]).inst(
+                    block,
+                    &[Operand::reg_use(temp)],
+                    &[],
+                );
+            }
+            builder
+                .inst(
+                    block,
+                    &[Operand::reg_use(values[0]), Operand::reg_use(values[1])],
+                    &[],
+                )
+                .ret(block);
+            (builder.build().unwrap(), values)
+        };
+        for weight in [SpillWeight::Override(100_000), SpillWeight::Scale(100.0)] {
+            for heavy in 0..2 {
+                let (func, values) = build(heavy, weight);
+                let out =
+                    run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+                let mut checker = Checker::new(&func);
+                checker.prepare(&out);
+                checker.run().expect("checker failed");
+                let explained: Vec<VReg> = out.explanations.iter().map(|&(v, _)| v).collect();
+                assert!(
+                    !explained.contains(&values[heavy]),
+                    "{:?}",
+                    out.explanations
+                );
+                assert!(
+                    explained.contains(&values[1 - heavy]),
+                    "{:?}",
+                    out.explanations
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_spill_preferences() {
+        // Eight values that need no register, live across a call that
+        // clobbers all but the seven callee-saved registers: one of
+        // them must go to memory, and preferences decide which.
+        let env = machine_env();
+        let clobbers: Vec<PReg> = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let build = |prefs: &[(usize, SpillPreference)]| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let values: Vec<VReg> = (0..8).map(|_| builder.vreg()).collect();
+            for &(i, pref) in prefs {
+                builder.spill_preference(values[i], pref);
+            }
+            for &value in &values {
+                let def = Operand::new(
+                    value,
+                    OperandPolicy::Any,
+                    OperandKind::Def,
+                    OperandPos::After,
+                );
+                builder.inst(block, &[def], &[]);
+            }
+            let uses: Vec<Operand> = values
+                .iter()
+                .map(|&value| {
+                    Operand::new(
+                        value,
+                        OperandPolicy::Any,
+                        OperandKind::Use,
+                        OperandPos::Before,
+                    )
+                })
+                .collect();
+            builder
+                .inst(block, &[], &clobbers)
+                .inst(block, &uses, &[])
+                .ret(block);
+            builder.build().unwrap()
+        };
+        let spilled = |prefs: &[(usize, SpillPreference)]| {
+            let func = build(prefs);
+            let out = run(&func, &env).expect("regalloc did not succeed");
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+            let uses = out.inst_allocs(Inst::new(func.insts() - 2));
+            let spilled: Vec<usize> = (0..8).filter(|&i| uses[i].as_stack().is_some()).collect();
+            assert_eq!(spilled.len(), 1, "{:?}", uses);
+            spilled[0]
+        };
+
+        let default = spilled(&[]);
+        let other = (default + 1) % 8;
+        assert_ne!(
+            spilled(&[(default, SpillPreference::CalleeSavedReg)]),
+            default
+        );
+        assert_eq!(spilled(&[(other, SpillPreference::Memory)]), other);
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_pinned_vregs() {
+        // v0 is pinned to an allocatable register, and live across
+        // `n` other values and a call that clobbers every caller-saved
+        // register: the others must make do without its register.
+        let env = machine_env();
+        let pinned = PReg::new(26, RegClass::Int);
+        let clobbers: Vec<PReg> = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let build = |n: usize, use_policy: OperandPolicy, clobber: Option<PReg>| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            builder.pin(v0, pinned);
+            let values: Vec<VReg> = (0..n).map(|_| builder.vreg()).collect();
+            builder.inst(block, &[Operand::reg_def(v0)], &[]);
+            for &value in &values {
+                builder.inst(block, &[Operand::reg_def(value)], &[]);
+            }
+            builder.inst(block, &[], &clobbers);
+            let mut uses: Vec<Operand> = values.iter().map(|&v| Operand::reg_use(v)).collect();
+            uses.push(Operand::new(
+                v0,
+                use_policy,
+                OperandKind::Use,
+                OperandPos::Before,
+            ));
+            builder.inst(block, &uses, &[]);
+            builder.inst(block, &[], clobber.as_slice());
+            builder.inst(block, &[Operand::reg_use(v0)], &[]).ret(block);
+            builder.build().unwrap()
+        };
+        for n in [2, 12] {
+            for policy in [OperandPolicy::Any, OperandPolicy::FixedReg(pinned)] {
+                let func = build(n, policy, None);
+                let out = run(&func, &env).expect("regalloc did not succeed");
+                crate::checker::check(&func, &env, &out).expect("checker failed");
+                for inst in (0..func.insts()).map(Inst::new) {
+                    for (op, alloc) in func.inst_operands(inst).iter().zip(out.inst_allocs(inst)) {
+                        assert_eq!(*alloc == Allocation::reg(pinned), op.vreg().vreg() == 0);
+                    }
+                }
+                // Never moved, nor another value moved into it.
+                assert!(out.edits.iter().all(|(_, edit)| match edit {
+                    Edit::Move { from, to } =>
+                        *from != Allocation::reg(pinned) && *to != Allocation::reg(pinned),
+                    _ => true,
+                }));
+            }
+        }
+
+        // Constraints the register cannot meet.
+        let pinned_at = |func: &Func| match run(func, &env) {
+            Err(RegAllocError::Pinned(vreg, inst)) => (vreg.vreg(), inst),
+            other => panic!("expected a pinned-vreg error, got {:?}", other),
+        };
+        let n = 2;
+        let use_inst = Inst::new(n + 2);
+        let stack = build(n, OperandPolicy::Stack, None);
+        assert_eq!(pinned_at(&stack), (0, use_inst));
+        let other = build(
+            n,
+            OperandPolicy::FixedReg(PReg::new(3, RegClass::Int)),
+            None,
+        );
+        assert_eq!(pinned_at(&other), (0, use_inst));
+        let clobbered = build(n, OperandPolicy::Any, Some(pinned));
+        assert_eq!(pinned_at(&clobbered), (0, use_inst.next()));
+        // Another vreg fixed to the register while v0 is live.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let v1 = builder.vreg();
+        builder.pin(v0, pinned);
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(
+                block,
+                &[Operand::reg_fixed_use(v1, pinned), Operand::reg_use(v0)],
+                &[],
+            )
+            .ret(block);
+        assert_eq!(pinned_at(&builder.build().unwrap()), (0, Inst::new(2)));
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder.pin(v0, PReg::new(31, RegClass::Int));
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let err = run(&builder.build().unwrap(), &env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "v0 cannot be pinned: its register is a scratch register, or it holds a reference"
+        );
+
+        // A reused input of the pinned vreg is copied out to its
+        // def's register, leaving the pinned one intact.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder.pin(v0, pinned);
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(
+                block,
+                &[Operand::reg_reuse_def(v1, 1), Operand::reg_use(v0)],
+                &[],
+            )
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run(&func, &env).expect("regalloc did not succeed");
+        let allocs = out.inst_allocs(Inst::new(1));
+        assert_eq!(allocs[0], allocs[1]);
+        assert_ne!(allocs[1], Allocation::reg(pinned));
+        assert_eq!(out.inst_allocs(Inst::new(2))[0], Allocation::reg(pinned));
+        assert_eq!(
+            out.edits,
+            vec![(
+                ProgPoint::before(Inst::new(1)),
+                Edit::Move {
+                    from: Allocation::reg(pinned),
+                    to: allocs[1]
+                }
+            )]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_cold_blocks() {
+        // A value live across a diamond, one side of which is a cold
+        // slow path that clobbers every register: the value is saved
+        // and restored on that side only.
+        let env = machine_env();
+        let clobbers = env.regs_by_class[RegClass::Int as u8 as usize].clone();
+        let build = |cold: bool| {
+            let mut builder = FuncBuilder::new();
+            let (entry, slow, fast, exit) = (
+                builder.block(),
+                builder.block(),
+                builder.block(),
+                builder.block(),
+            );
+            let v0 = builder.vreg();
+            builder
+                .inst(entry, &[Operand::reg_def(v0)], &[])
+                .branch(entry, &[slow, fast], &[])
+                .inst(slow, &[], &clobbers)
+                .branch(slow, &[exit], &[])
+                .branch(fast, &[exit], &[])
+                .inst(exit, &[Operand::reg_use(v0)], &[])
+                .ret(exit);
+            if cold {
+                builder.cold(slow);
+            }
+            builder.build().unwrap()
+        };
+        let moves_outside_slow_path = |func: &Func, out: &Output| {
+            out.edits
+                .iter()
+                .filter(|(pos, edit)| {
+                    matches!(edit, Edit::Move { .. })
+                        && func
+                            .block_insns(Block::new(1))
+                            .iter()
+                            .all(|i| i != pos.inst)
+                })
+                .count()
+        };
+
+        let func = build(false);
+        let out = run(&func, &env).expect("regalloc did not succeed");
+        assert!(moves_outside_slow_path(&func, &out) > 0);
+
+        let func = build(true);
+        assert!(func.is_cold_block(Block::new(1)));
+        let out = run(&func, &env).expect("regalloc did not succeed");
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+        assert_eq!(moves_outside_slow_path(&func, &out), 0, "{:?}", out.edits);
+    }
+
+    #[test]
+    fn test_soft_clobbers() {
+        // A value live across an instruction that soft-clobbers the
+        // given registers: it avoids them while any other is free, and
+        // otherwise relies on one being preserved. A dead fixed def,
+        // if present, keeps the function off the linear path.
+        let env = machine_env();
+        let regs = env.regs_by_class[RegClass::Int as u8 as usize].clone();
+        let build = |soft_clobbers: &[PReg], fixed: bool| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            let v1 = builder.vreg();
+            if fixed {
+                let def = Operand::new(
+                    v1,
+                    OperandPolicy::FixedReg(regs[0]),
+                    OperandKind::Def,
+                    OperandPos::After,
+                );
+                builder.inst(block, &[def], &[]);
+            }
+            builder
+                .inst(block, &[Operand::reg_def(v0)], &[])
+                .inst(block, &[], &[])
+                .soft_clobbers(block, soft_clobbers)
+                .inst(block, &[Operand::reg_use(v0)], &[])
+                .ret(block);
+            builder.build().unwrap()
+        };
+
+        let (free, soft) = regs.split_last().unwrap();
+        for fixed in [false, true] {
+            let func = build(soft, fixed);
+            let out = run(&func, &env).expect("regalloc did not succeed");
+            let use_inst = Inst::new(func.insts() - 2);
+            assert_eq!(out.inst_allocs(use_inst), &[Allocation::reg(*free)]);
+            assert!(out.preserved_soft_clobbers.is_empty());
+
+            let func = build(&regs, fixed);
+            let out = run(&func, &env).expect("regalloc did not succeed");
+            let alloc = out.inst_allocs(use_inst)[0];
+            assert_eq!(
+                out.preserved_soft_clobbers,
+                vec![(use_inst.prev(), alloc.as_reg().unwrap())]
+            );
+        }
+
+        // A late fixed use of a soft-clobbered register reads it after
+        // the clobber, so the register is preserved even though the
+        // value lives elsewhere and only reaches it by a fixup move.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let late_use = Operand::new(
+            v0,
+            OperandPolicy::FixedReg(regs[1]),
+            OperandKind::Use,
+            OperandPos::BeforeLate,
+        );
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, regs[0])], &[])
+            .inst(block, &[Operand::reg_fixed_use(v0, regs[0]), late_use], &[])
+            .soft_clobbers(block, &[regs[1]])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run(&func, &env).expect("regalloc did not succeed");
+        assert_eq!(out.preserved_soft_clobbers, vec![(Inst::new(1), regs[1])]);
+        for seed in 0..4 {
+            if let Err(e) = crate::fuzzing::interp::run(&func, &out, seed, 10 * func.insts()) {
+                panic!("interpreter found a mismatch: {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_edge_moves() {
+        // v0 and v1 are both live into the join, spilled across the
+        // call, so its param can share a location with neither: each
+        // edge's moves are reported, costing one per register move and
+        // two per move to or from the stack.
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let (entry, left, right, join) = (
+            builder.block(),
+            builder.block(),
+            builder.block(),
+            builder.block(),
+        );
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder.params(join, &[v2]);
+        builder.inst(entry, &[Operand::reg_def(v0)], &[]);
+        builder.inst(entry, &[Operand::reg_def(v1)], &[]);
+        let clobbers = mach_env.regs.clone();
+        builder.add_inst(entry, InstData::new(InstOpcode::Call, vec![], clobbers));
+        builder
+            .branch(entry, &[left, right], &[])
+            .branch(left, &[join], &[v0])
+            .branch(right, &[join], &[v1])
+            .inst(
+                join,
+                &[
+                    Operand::reg_use(v0),
+                    Operand::reg_use(v1),
+                    Operand::reg_use(v2),
+                ],
+                &[],
+            )
+            .ret(join);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions {
+            report_edge_moves: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+        let edge = |from, to, moves, cost| EdgeMoves {
+            from,
+            to,
+            moves,
+            cost,
+        };
+        assert_eq!(
+            out.edge_moves,
+            vec![
+                // Reloads at the start of each side, which has the
+                // entry as its only predecessor.
+                edge(entry, left, 1, 2),
+                edge(entry, right, 2, 4),
+                // Into the param's register, and v1's reload.
+                edge(left, join, 2, 3),
+                edge(right, join, 1, 1),
+            ],
+            "{}",
+            out
+        );
+
+        let out = run_with_options(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        assert!(out.edge_moves.is_empty());
+    }
+
+    #[test]
+    fn test_spill_metrics() {
+        // v0 is live around a loop whose body makes a call that
+        // clobbers every register: it is stored and reloaded in the
+        // body, where each move weighs eight times as much as one
+        // outside the loop, and counts as a move in hot code. In a
+        // cold body, they weigh nothing.
+        let mach_env = machine_env();
+        let build = |cold: bool| {
+            let mut builder = FuncBuilder::new();
+            let (entry, header, body, exit) = (
+                builder.block(),
+                builder.block(),
+                builder.block(),
+                builder.block(),
+            );
+            let v0 = builder.vreg();
+            builder
+                .inst(entry, &[Operand::reg_def(v0)], &[])
+                .branch(entry, &[header], &[])
+                .branch(header, &[body, exit], &[]);
+            let clobbers = mach_env.regs.clone();
+            builder.add_inst(body, InstData::new(InstOpcode::Call, vec![], clobbers));
+            builder
+                .inst(body, &[Operand::reg_use(v0)], &[])
+                .branch(body, &[header], &[])
+                .inst(exit, &[Operand::reg_use(v0)], &[])
+                .ret(exit);
+            if cold {
+                builder.cold(body);
+            }
+            builder.build().unwrap()
+        };
+        let out = run(&build(false), &mach_env).expect("regalloc failed");
+        assert_eq!((out.stats.spill_stores, out.stats.spill_reloads), (1, 1));
+        assert_eq!(out.stats.spill_stores_weighted, 8);
+        assert_eq!(out.stats.spill_reloads_weighted, 8);
+        assert_eq!(out.stats.hot_code_moves, 2);
+
+        let out = run(&build(true), &mach_env).expect("regalloc failed");
+        assert_eq!((out.stats.spill_stores, out.stats.spill_reloads), (1, 1));
+        assert_eq!(out.stats.spill_stores_weighted, 0);
+        assert_eq!(out.stats.spill_reloads_weighted, 0);
+    }
+
+    #[test]
+    fn test_allocation_steps() {
+        // Two registers, and three values: v1 and v2 are used
+        // together, and v0, the longest-lived, after them. v2 evicts
+        // v0, which is then split around them.
+        let mut mach_env = machine_env();
+        mach_env.regs_by_class[RegClass::Int as u8 as usize].truncate(2);
+        mach_env.regs.truncate(2);
+        let (p0, p1) = (mach_env.regs[0], mach_env.regs[1]);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_def(v2)], &[])
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions::default();
+        let mut steps = Liveness::compute(&func, &mach_env, &options)
+            .expect("liveness failed")
+            .merge()
+            .allocate_stepwise();
+        let v0_range = CodeRange::new(
+            ProgPoint::after(Inst::new(0)),
+            ProgPoint::before(Inst::new(4)).next(),
+        );
+
+        let decision = steps.next().unwrap();
+        assert_eq!(
+            (decision.bundle, &decision.ranges[..]),
+            (0, &[v0_range][..])
+        );
+        assert_eq!(decision.probes, vec![(p0, ProbeResult::Allocated)]);
+        assert_eq!(decision.outcome, DecisionOutcome::Allocated(p0));
+        let decision = steps.next().unwrap();
+        assert_eq!(decision.outcome, DecisionOutcome::Allocated(p1));
+        assert_eq!(steps.queue_len(), 1);
+        let decision = steps.next().unwrap();
+        assert_eq!(decision.bundle, 2);
+        assert_eq!(
+            decision.probes,
+            vec![
+                (p0, ProbeResult::Conflict(vec![0])),
+                (p1, ProbeResult::Conflict(vec![1])),
+                (p0, ProbeResult::Allocated),
+            ]
+        );
+        assert_eq!(decision.evicted, vec![0]);
+        assert_eq!(decision.outcome, DecisionOutcome::Allocated(p0));
+        let decision = steps.next().unwrap();
+        assert_eq!(
+            (decision.bundle, decision.outcome),
+            (0, DecisionOutcome::Split)
+        );
+
+        // Finishing from here gives the same result as an
+        // uninterrupted run.
+        let out = steps.finish().reify();
+        let expected = run(&func, &mach_env).expect("regalloc failed");
+        assert_eq!(out.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn test_equivalent_vregs() {
+        // v1 is defined where v0 dies, with no move between them, and
+        // is linked with it: they are merged. v2 is linked with v1
+        // too, but is live at the same time, so the link is ignored.
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder.equivalent(v1, v0).equivalent(v2, v1);
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1), Operand::reg_use(v0)], &[])
+            .inst(block, &[Operand::reg_def(v2)], &[])
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions::default();
+        let merge = Liveness::compute(&func, &mach_env, &options)
+            .expect("liveness failed")
+            .merge();
+        assert_eq!(merge.vreg_bundle(v0), merge.vreg_bundle(v1));
+        assert_ne!(merge.vreg_bundle(v1), merge.vreg_bundle(v2));
+        let out = merge.allocate().reify();
+        assert_eq!(
+            out.inst_allocs(Inst::new(0)),
+            &out.inst_allocs(Inst::new(1))[..1]
+        );
+        assert!(out.edits.is_empty());
+    }
+
+    #[test]
+    fn test_max_spillslots() {
+        // Three values live across a call that clobbers every
+        // register need a slot each, after any reserved ones. Over
+        // budget, the vregs in the slots beyond it are reported.
+        let mach_env = machine_env();
+        let build = |reserved: usize| {
+            let mut builder = FuncBuilder::new();
+            builder.reserved_spillslots(reserved);
+            let block = builder.block();
+            let vregs: Vec<VReg> = (0..3).map(|_| builder.vreg()).collect();
+            for &vreg in &vregs {
+                builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+            }
+            let clobbers = mach_env.regs.clone();
+            builder.add_inst(block, InstData::new(InstOpcode::Call, vec![], clobbers));
+            let uses: Vec<Operand> = vregs.iter().map(|&v| Operand::reg_use(v)).collect();
+            builder.inst(block, &uses, &[]).ret(block);
+            (builder.build().unwrap(), vregs)
+        };
+        let budget = |max: usize| RegallocOptions {
+            max_spillslots: Some(max),
+            ..RegallocOptions::default()
+        };
+
+        let (func, vregs) = build(0);
+        let out = run_with_options(&func, &mach_env, &budget(3)).expect("regalloc failed");
+        assert_eq!(out.num_spillslots, 3);
+        match run_with_options(&func, &mach_env, &budget(2)) {
+            Err(RegAllocError::TooManySpillslots(3, over)) => {
+                assert_eq!(over.len(), 1);
+                assert!(vregs.contains(&over[0]));
+            }
+            other => panic!("expected TooManySpillslots, got {:?}", other.map(|_| ())),
+        }
+
+        let (func, vregs) = build(1);
+        match run_with_options(&func, &mach_env, &budget(1)) {
+            Err(RegAllocError::TooManySpillslots(4, mut over)) => {
+                over.sort_by_key(|v| v.vreg());
+                assert_eq!(over, vregs);
+            }
+            other => panic!("expected TooManySpillslots, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_shrink_frame() {
+        // v1 is spilled across the first call only, v0 and v2..v10
+        // across both, and v11 across the second only. The usual
+        // assignment gives up on v11 after trying ten slots, all taken,
+        // before reaching v1's, and allocates a twelfth, which merging
+        // folds back into v1's.
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let vregs: Vec<VReg> = (0..12).map(|_| builder.vreg()).collect();
+        for &vreg in &vregs[..11] {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        let clobbers = mach_env.regs.clone();
+        builder.add_inst(
+            block,
+            InstData::new(InstOpcode::Call, vec![], clobbers.clone()),
+        );
+        builder
+            .inst(block, &[Operand::reg_use(vregs[1])], &[])
+            .inst(block, &[Operand::reg_def(vregs[11])], &[]);
+        builder.add_inst(block, InstData::new(InstOpcode::Call, vec![], clobbers));
+        let uses: Vec<Operand> = vregs
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 1)
+            .map(|(_, &v)| Operand::reg_use(v))
+            .collect();
+        builder.inst(block, &uses, &[]).ret(block);
+        let func = builder.build().unwrap();
+
+        let out = run(&func, &mach_env).expect("regalloc failed");
+        assert_eq!(out.num_spillslots, 12);
+        assert_eq!(out.stats.spillslots_merged, 0);
+        let options = RegallocOptions {
+            shrink_frame: true,
+            ..RegallocOptions::default()
+        };
+        let shrunk = run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+        assert_eq!(shrunk.num_spillslots, 11);
+        assert_eq!(shrunk.stats.spillslots_merged, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_chaos_mode() {
+        // With every register free, v1 gets the first one it probes; a
+        // chaos seed starts the probe elsewhere, the same place for the
+        // same seed. (The fixed def of v0 keeps the function off the
+        // linear path, which probes nothing.)
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, mach_env.regs[0])], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let base = run(&func, &mach_env).expect("regalloc failed");
+
+        let mut regs = vec![];
+        for seed in 0..8 {
+            let options = RegallocOptions {
+                chaos_seed: Some(seed),
+                ..RegallocOptions::default()
+            };
+            let out = run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+            assert_eq!(out.allocs[2], out.allocs[3]);
+            let again = run_with_options(&func, &mach_env, &options).unwrap();
+            assert_eq!(out.allocs, again.allocs);
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+            regs.push(out.allocs[2]);
+        }
+        assert!(regs.iter().any(|&alloc| alloc != base.allocs[2]));
+    }
+
+    #[test]
+    fn test_block_edits() {
+        // v0 is spilled around the call in the entry block and
+        // reloaded for its use in the exit; v1, a constant, is
+        // materialized there instead.
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let (entry, exit) = (builder.block(), builder.block());
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        let clobbers = mach_env.regs.clone();
+        builder
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::constant_def(v1)], &[])
+            .inst(entry, &[], &clobbers)
+            .branch(entry, &[exit], &[])
+            .inst(exit, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(exit);
+        let func = builder.build().unwrap();
+        let out = run(&func, &mach_env).expect("regalloc failed");
+        let spills = BlockEdits {
+            spills: 1,
+            ..BlockEdits::default()
+        };
+        let reloads = BlockEdits {
+            reloads: 1,
+            constants: 1,
+            ..BlockEdits::default()
+        };
+        assert_eq!(out.block_edits, vec![spills, reloads]);
+        assert_eq!(out.edits.len(), 3);
+    }
+
+    #[test]
+    fn test_used_regs() {
+        // v0 is defined in p5 and used in p7, so a move writes p7; v1
+        // takes some other register. The instruction's own clobber of
+        // p20 does not count.
+        let mach_env = machine_env();
+        let p = |i: usize| mach_env.regs[i];
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, p(5))], &[])
+            .inst(block, &[Operand::reg_fixed_use(v0, p(7))], &[p(20)])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run(&func, &mach_env).expect("regalloc failed");
+        let mut used = vec![p(5), p(7), out.allocs[2].as_reg().unwrap()];
+        used.sort_by_key(|preg| preg.index());
+        assert_eq!(out.used_regs, used);
+        assert_eq!(out.edit_clobbered_regs, vec![p(7)]);
+    }
+
+    #[test]
+    fn test_explain() {
+        // As in `test_allocation_steps()`: v0 is evicted by v2, then
+        // split around its uses, with v1 in the register it wants, and
+        // spilled in between. v1 and v2 keep their registers, so have
+        // no explanation.
+        let mut mach_env = machine_env();
+        mach_env.regs_by_class[RegClass::Int as u8 as usize].truncate(2);
+        mach_env.regs.truncate(2);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_def(v2)], &[])
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let base = run(&func, &mach_env).expect("regalloc failed");
+        assert!(base.explanations.is_empty());
+        let options = RegallocOptions {
+            explain: true,
+            ..RegallocOptions::default()
+        };
+        let out = run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+        assert_eq!(out.allocs, base.allocs);
+        let v0_range = CodeRange::new(
+            ProgPoint::after(Inst::new(0)),
+            ProgPoint::before(Inst::new(4)).next(),
+        );
+        let (split_from, split_to) = (
+            ProgPoint::before(Inst::new(1)),
+            ProgPoint::before(Inst::new(4)),
+        );
+        let events = vec![
+            AllocationEvent::Evicted {
+                range: v0_range,
+                preg: mach_env.regs[0],
+                by: v2,
+            },
+            AllocationEvent::Split {
+                range: v0_range,
+                at: vec![split_from, split_to],
+                reason: SplitReason::AllUses,
+                conflict: Some(v1),
+            },
+            AllocationEvent::Spilled {
+                range: CodeRange::new(split_from, split_to),
+            },
+        ];
+        assert_eq!(out.explanations, vec![(v0, events)]);
+    }
+
+    #[test]
+    fn test_split_points() {
+        // v0 is used twice by inst 1 and once by inst 2, so splitting
+        // around every use proposes the points before inst 1 and 2
+        // several times over, and one past the end of the bundle.
+        // Canonicalized, each is left once, in order, and only where
+        // both sides keep part of the bundle.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v0)], &[])
+            .inst(block, &[Operand::reg_use(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let mach_env = machine_env();
+        let options = RegallocOptions::default();
+        let cfginfo = CFGInfo::new(&func);
+        let mut env = Env::new(&func, &mach_env, &options, cfginfo, Arena::default());
+        env.create_pregs_and_vregs();
+        env.compute_liveness().expect("liveness failed");
+        env.merge_vreg_bundles();
+
+        let bundle = env.ranges[env.vregs[0].first_range.index()].bundle;
+        let before = |i: usize| ProgPoint::before(Inst::new(i));
+        let mut points = env.find_all_use_split_points(bundle);
+        assert_eq!(
+            &points[..],
+            &[
+                before(1),
+                before(1),
+                before(1),
+                before(2),
+                before(2),
+                before(2),
+                before(3)
+            ]
+        );
+        env.canonicalize_split_points(bundle, &mut points);
+        assert_eq!(&points[..], &[before(1), before(2)]);
+    }
+
+    #[test]
+    fn test_arena_reuse() {
+        // A run leaves its tables in the arena, cleared; the next,
+        // smaller function allocates within them, just as it would
+        // from scratch. A run that fails gives them back as well.
+        let mach_env = machine_env();
+        let build = |n: usize| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let vregs: Vec<VReg> = (0..n).map(|_| builder.vreg()).collect();
+            for &vreg in &vregs {
+                builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+            }
+            builder.inst(block, &[], &mach_env.regs);
+            for &vreg in &vregs {
+                builder.inst(block, &[Operand::reg_use(vreg)], &[]);
+            }
+            builder.ret(block);
+            builder.build().unwrap()
+        };
+        let options = RegallocOptions::default();
+        let mut arena = Arena::default();
+        run_with_arena(&build(8), &mach_env, &options, &mut arena).expect("regalloc failed");
+        assert!(arena.ranges.is_empty());
+        let capacity = arena.ranges.capacity();
+        assert!(capacity >= 8);
+
+        let func = build(4);
+        let fresh = run(&func, &mach_env).expect("regalloc failed");
+        let reused =
+            run_with_arena(&func, &mach_env, &options, &mut arena).expect("regalloc failed");
+        assert_eq!(fresh.to_string(), reused.to_string());
+        assert_eq!(fresh.num_spillslots, reused.num_spillslots);
+        assert_eq!(arena.ranges.capacity(), capacity);
+
+        let over_budget = RegallocOptions {
+            max_spillslots: Some(1),
+            ..RegallocOptions::default()
+        };
+        match run_with_arena(&func, &mach_env, &over_budget, &mut arena) {
+            Err(RegAllocError::TooManySpillslots(..)) => {}
+            other => panic!("expected TooManySpillslots, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(arena.ranges.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        // Each phase writes the function, and then the tables as it
+        // leaves them.
+        let mach_env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let (entry, exit) = (builder.block(), builder.block());
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(entry, &[Operand::reg_fixed_def(v0, mach_env.regs[3])], &[])
+            .branch(entry, &[exit], &[v0])
+            .params(exit, &[v1])
+            .inst(exit, &[Operand::reg_use(v1)], &[mach_env.regs[5]])
+            .ret(exit);
+        let func = builder.build().unwrap();
+        let dir = std::env::temp_dir().join(format!("regalloc2-snapshots-{}", std::process::id()));
+        let options = RegallocOptions {
+            snapshot_dir: Some(dir.clone()),
+            ..RegallocOptions::default()
+        };
+        run_with_options(&func, &mach_env, &options).expect("regalloc failed");
+        let snapshot = |phase: &str| {
+            std::fs::read_to_string(dir.join(format!("{}.txt", phase)))
+                .expect("snapshot not written")
+        };
+        let (liveness, merge, allocate) = (
+            snapshot("liveness"),
+            snapshot("merge"),
+            snapshot("allocate"),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let function = concat!(
+            "Function:\n",
+            "block0(): succs: block1 preds:\n",
+            "  inst0: Def@After: v0 fixed(p3i)\n",
+            "  inst1: Use@Before: v0 reg\n",
+            "block1(v1): succs: preds: block0\n",
+            "  inst2: Use@Before: v1 reg clobbers: p5i\n",
+            "  inst3:\n",
+            "Bundles:\n",
+        );
+        for text in [&liveness, &merge, &allocate] {
+            assert!(text.starts_with(function), "{}", text);
+        }
+        // Liveness forms no bundles; merging puts v0 and the blockparam
+        // it flows into in one; allocation gives it v0's register.
+        assert!(liveness.starts_with(&format!("{}VRegs:\n", function)));
+        assert!(
+            merge.contains("bundle1: first_range=LiveRangeIndex(2) last_range=LiveRangeIndex(1) ")
+        );
+        let p3 = format!("alloc={:?}", Allocation::reg(mach_env.regs[3]));
+        assert!(!merge.contains(&p3));
+        assert!(allocate.contains(&p3));
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_multi_fixed_regs() {
+        // v0 is needed in p3 and p4 at once by inst 2, which clobbers
+        // p9; in p3 and p4 again by inst 3, which also reuses v1's
+        // register for v2; and in p5 by inst 4. Each operand gets its
+        // register, and the copies between them check out.
+        let mach_env = machine_env();
+        let p = |i: usize| mach_env.regs[i];
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(
+                block,
+                &[
+                    Operand::reg_fixed_use(v0, p(3)),
+                    Operand::reg_fixed_use(v0, p(4)),
+                ],
+                &[p(9)],
+            )
+            .inst(
+                block,
+                &[
+                    Operand::reg_reuse_def(v2, 1),
+                    Operand::reg_use(v1),
+                    Operand::reg_fixed_use(v0, p(3)),
+                    Operand::reg_fixed_use(v0, p(4)),
+                ],
+                &[],
+            )
+            .inst(
+                block,
+                &[Operand::reg_fixed_use(v0, p(5)), Operand::reg_use(v2)],
+                &[],
+            )
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run(&func, &mach_env).expect("regalloc failed");
+        let reg = |i: usize| Allocation::reg(p(i));
+        assert_eq!(out.inst_allocs(Inst::new(2)), &[reg(3), reg(4)]);
+        let allocs = out.inst_allocs(Inst::new(3));
+        assert_eq!(allocs[0], allocs[1]);
+        assert_eq!(&allocs[2..], &[reg(3), reg(4)]);
+        assert_eq!(out.inst_allocs(Inst::new(4))[0], reg(5));
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn test_tracing() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Records the name of each span and the message of each
+        /// event.
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+        struct Message<'a>(&'a mut Vec<String>);
+        impl<'a> Visit for Message<'a> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0.push(format!("{:?}", value));
+                }
+            }
+        }
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes) -> Id {
+                let mut names = self.0.lock().unwrap();
+                names.push(span.metadata().name().to_string());
+                Id::from_u64(names.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event) {
+                event.record(&mut Message(&mut self.0.lock().unwrap()));
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        // Two values wanting the same fixed register, so that the
+        // bundles go through the allocation loop.
+        let env = machine_env();
+        let p0 = PReg::new(0, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, p0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(
+                block,
+                &[Operand::reg_fixed_use(v1, p0), Operand::reg_use(v0)],
+                &[],
+            )
+            .ret(block);
+        let func = builder.build().unwrap();
+        let names = Arc::new(Mutex::new(vec![]));
+        tracing::subscriber::with_default(Recorder(names.clone()), || {
+            run(&func, &env).expect("regalloc did not succeed")
+        });
+        let names = names.lock().unwrap();
+        for name in [
+            "regalloc2",
+            "liveness",
+            "merge",
+            "allocate",
+            "reify",
+            "bundle",
+        ] {
+            assert!(names.iter().any(|n| n == name), "no {} span", name);
+        }
+        for message in ["probe: conflict", "probe: free", "allocated"] {
+            assert!(names.iter().any(|n| n == message), "no {} event", message);
+        }
+    }
+
+    #[test]
+    fn test_operand_record_size() {
+        // See the comment on `Operand::bits`.
+        assert_eq!(std::mem::size_of::<Operand>(), 8);
+        assert_eq!(std::mem::size_of::<Use>(), 24);
+        assert_eq!(std::mem::size_of::<Def>(), 24);
+    }
+
+    /// A random non-empty `CodeRange` within the first few insts.
+    fn random_range(next: &mut impl FnMut(usize) -> usize) -> CodeRange {
+        let from = next(16) as ProgPointIndex;
+        let to = from + 1 + next(6) as ProgPointIndex;
+        CodeRange::new(ProgPoint::from_index(from), ProgPoint::from_index(to))
+    }
+
+    #[test]
+    fn test_code_range_order() {
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        let mut next = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+
+        // Pairwise: overlapping ranges compare equal, and otherwise
+        // ranges order by position, antisymmetrically; keys agree.
+        for _ in 0..10000 {
+            let a = random_range(&mut next);
+            let b = random_range(&mut next);
+            let expected = if a.to() <= b.from() {
+                Ordering::Less
+            } else if a.from() >= b.to() {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            };
+            assert_eq!(a.cmp(&b), expected, "{:?} vs {:?}", a, b);
+            assert_eq!(a.overlaps(&b), expected == Ordering::Equal);
+            assert_eq!(b.cmp(&a), expected.reverse());
+            let (ka, kb) = (LiveRangeKey::from_range(&a), LiveRangeKey::from_range(&b));
+            assert_eq!(ka.cmp(&kb), expected);
+            assert_eq!(ka == kb, expected == Ordering::Equal);
+        }
+
+        // The order is not transitive over overlapping ranges, which
+        // is why only disjoint ranges may share a map.
+        let range =
+            |from, to| CodeRange::new(ProgPoint::from_index(from), ProgPoint::from_index(to));
+        assert_eq!(range(0, 2).cmp(&range(1, 3)), Ordering::Equal);
+        assert_eq!(range(1, 3).cmp(&range(2, 4)), Ordering::Equal);
+        assert_eq!(range(0, 2).cmp(&range(2, 4)), Ordering::Less);
+
+        // Over disjoint ranges, a set orders by position, and a
+        // lookup finds exactly the ranges that overlap the query.
+        for _ in 0..1000 {
+            let mut ranges: Vec<CodeRange> = vec![];
+            for _ in 0..next(8) {
+                let candidate = random_range(&mut next);
+                if ranges.iter().all(|r| !r.overlaps(&candidate)) {
+                    ranges.push(candidate);
+                }
+            }
+            let mut set = LiveRangeSet::new();
+            for (i, &r) in ranges.iter().enumerate() {
+                set.insert(r, LiveRangeIndex::new(i));
+            }
+            let mut sorted = ranges.clone();
+            sorted.sort_by_key(|r| r.from());
+            let in_order: Vec<CodeRange> =
+                set.btree.values().map(|lr| ranges[lr.index()]).collect();
+            assert_eq!(in_order, sorted);
+            for _ in 0..10 {
+                let query = random_range(&mut next);
+                match set.btree.get(&LiveRangeKey::from_range(&query)) {
+                    Some(lr) => assert!(ranges[lr.index()].overlaps(&query)),
+                    None => assert!(ranges.iter().all(|r| !r.overlaps(&query))),
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overlaps one already in the set")]
+    fn test_live_range_set_overlap() {
+        let mut set = LiveRangeSet::new();
+        let range =
+            |from, to| CodeRange::new(ProgPoint::from_index(from), ProgPoint::from_index(to));
+        set.insert(range(0, 4), LiveRangeIndex::new(0));
+        set.insert(range(3, 6), LiveRangeIndex::new(1));
+    }
 }
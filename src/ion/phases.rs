@@ -0,0 +1,436 @@
+//! The allocator as a sequence of explicit phases.
+//!
+//! `run()` is equivalent to
+//!
+//! ```ignore
+//! Liveness::compute(func, env, options)?.merge().allocate().reify()
+//! ```
+//!
+//! except that `run()` uses `Liveness::allocate_linear()` instead of
+//! the merge and allocate phases for small enough functions.
+//!
+//! Driving the phases by hand allows inspecting the state after
+//! each one, and adjusting it (e.g. supplying custom hot-code
+//! information, or merging additional vregs) before the next.
+
+use super::{Arena, CodeRange, Env, LiveBundleIndex, LiveRangeKey, Stats};
+use crate::cfg::CFGInfo;
+use crate::{
+    Allocation, BlockPressure, Function, Inst, MachineEnv, Output, PReg, ProgPoint, RegAllocError,
+    RegallocOptions, VReg,
+};
+
+/// The first phase: liveness analysis. Every vreg has its live ranges
+/// computed, and hot code (inner loops) has been identified.
+pub struct Liveness<'a, F: Function> {
+    env: Env<'a, F>,
+}
+
+/// The second phase: vregs have been grouped into bundles, each of
+/// which will receive a single allocation if possible.
+pub struct Merge<'a, F: Function> {
+    env: Env<'a, F>,
+}
+
+/// The third phase: every bundle has been assigned a register or a
+/// spillslot. No moves have been inserted yet.
+pub struct Allocate<'a, F: Function> {
+    env: Env<'a, F>,
+}
+
+impl<'a, F: Function> Liveness<'a, F> {
+    /// Validate the function and compute liveness. Unlike `run()`,
+    /// this uses `mach_env` exactly as given: if the target has a
+    /// frame pointer, pass `MachineEnv::with_frame_pointer()` to
+    /// honor `RegallocOptions::allocate_frame_pointer`.
+    pub fn compute(
+        func: &'a F,
+        mach_env: &'a MachineEnv,
+        options: &'a RegallocOptions,
+    ) -> Result<Self, RegAllocError> {
+        Self::compute_in_arena(func, mach_env, options, &mut Arena::default())
+    }
+
+    /// Like `compute()`, but take the allocator's working storage
+    /// from `arena`; `Allocate::reify_and_return_arena()` gives it
+    /// back for the next run. If this fails, the storage is back in
+    /// `arena` already.
+    pub fn compute_in_arena(
+        func: &'a F,
+        mach_env: &'a MachineEnv,
+        options: &'a RegallocOptions,
+        arena: &mut Arena,
+    ) -> Result<Self, RegAllocError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "liveness",
+            insts = func.insts(),
+            blocks = func.blocks(),
+            vregs = func.num_vregs()
+        )
+        .entered();
+        if func.insts() > ProgPoint::MAX_INSTS {
+            return Err(RegAllocError::TooManyInsts(func.insts()));
+        }
+        let cfginfo = CFGInfo::new(func);
+        if options.validate_ssa {
+            crate::ssa::validate_ssa(func, &cfginfo)?;
+        } else {
+            super::validate_ssa(func, &cfginfo)?;
+        }
+
+        super::check_reg_classes(func, mach_env)?;
+        let mut env = Env::new(func, mach_env, options, cfginfo, std::mem::take(arena));
+        if let Err(e) = Self::init(&mut env) {
+            *arena = env.into_arena();
+            return Err(e);
+        }
+        Ok(Self { env })
+    }
+
+    fn init(env: &mut Env<'a, F>) -> Result<(), RegAllocError> {
+        env.no_edits_after = super::restricted_points(env.func)?;
+        if env.opts.save_callee_saves {
+            env.callee_restored =
+                super::callee_restored_insts(env.func, env.env, &env.no_edits_after)?;
+        }
+        env.create_pregs_and_vregs();
+        env.compute_liveness()?;
+        env.reserve_pinned_vregs()?;
+        env.compute_hot_code();
+        env.compute_cold_code();
+        env.verify_invariants("liveness");
+        env.write_snapshot("liveness");
+        Ok(())
+    }
+
+    /// The ranges over which `vreg` is live, in program order.
+    pub fn vreg_ranges(&self, vreg: VReg) -> Vec<CodeRange> {
+        let mut ranges = vec![];
+        let mut iter = self.env.vregs[vreg.vreg()].first_range;
+        while iter.is_valid() {
+            ranges.push(self.env.ranges[iter.index()].range);
+            iter = self.env.ranges[iter.index()].next_in_reg;
+        }
+        ranges
+    }
+
+    /// The register pressure in each block, indexed by block: the
+    /// greatest number of vregs of each class live at once anywhere in
+    /// it.
+    pub fn register_pressure(&self) -> Vec<BlockPressure> {
+        self.env.register_pressure()
+    }
+
+    /// The ranges of code considered hot, in program order.
+    pub fn hot_code(&self) -> Vec<CodeRange> {
+        self.env
+            .hot_code
+            .btree
+            .values()
+            .map(|&lr| self.env.ranges[lr.index()].range)
+            .collect()
+    }
+
+    /// The ranges of code in cold blocks (see
+    /// `Function::is_cold_block`), in program order.
+    pub fn cold_code(&self) -> Vec<CodeRange> {
+        self.env
+            .cold_code
+            .btree
+            .values()
+            .map(|&lr| self.env.ranges[lr.index()].range)
+            .collect()
+    }
+
+    /// Replace the hot-code information with the given ranges, which
+    /// must not overlap.
+    pub fn set_hot_code(&mut self, ranges: &[CodeRange]) {
+        self.env.hot_code.btree.clear();
+        for range in ranges {
+            let lr = self.env.create_liverange(*range);
+            let prev = self
+                .env
+                .hot_code
+                .btree
+                .insert(LiveRangeKey::from_range(range), lr);
+            assert!(prev.is_none(), "overlapping hot-code ranges");
+        }
+    }
+
+    /// Whether the function is small enough for `allocate_linear()`:
+    /// every operand policy is `Any` or `Reg`, and no class has more
+    /// live vregs than registers that no clobber reserves.
+    pub fn can_allocate_linear(&self) -> bool {
+        self.env.linear_allocation_regs().is_some()
+    }
+
+    /// Allocate a small function in a single pass, skipping the merge
+    /// phase, the allocation queue and spillslot assignment: every
+    /// vreg gets a register for its whole lifetime, shared with the
+    /// vreg it is copied from where their ranges allow. Panics unless
+    /// `can_allocate_linear()`.
+    pub fn allocate_linear(mut self) -> Allocate<'a, F> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("allocate_linear").entered();
+        let free = self
+            .env
+            .linear_allocation_regs()
+            .expect("function too large for linear allocation");
+        self.env.allocate_linear(free);
+        self.env.verify_invariants("allocate");
+        self.env.write_snapshot("allocate");
+        Allocate { env: self.env }
+    }
+
+    /// Group vregs into bundles, merging across moves, reused inputs
+    /// and blockparams where possible.
+    pub fn merge(mut self) -> Merge<'a, F> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("merge").entered();
+        self.env.merge_vreg_bundles();
+        self.env.verify_invariants("merge");
+        self.env.write_snapshot("merge");
+        Merge { env: self.env }
+    }
+}
+
+impl<'a, F: Function> Merge<'a, F> {
+    /// The index of the bundle containing `vreg`, or `None` if the
+    /// vreg is never live. Vregs with the same index were merged.
+    pub fn vreg_bundle(&self, vreg: VReg) -> Option<usize> {
+        let first_range = self.env.vregs[vreg.vreg()].first_range;
+        if first_range.is_valid() {
+            Some(self.env.ranges[first_range.index()].bundle.index())
+        } else {
+            None
+        }
+    }
+
+    /// Attempt to merge the bundles of two vregs, so that they share
+    /// an allocation. This fails (returning `false`) if either vreg is
+    /// never live, if the vregs differ in class, or if their live
+    /// ranges overlap.
+    pub fn try_merge(&mut self, a: VReg, b: VReg) -> bool {
+        match (self.vreg_bundle(a), self.vreg_bundle(b)) {
+            (Some(from), Some(to)) => self
+                .env
+                .merge_bundles(LiveBundleIndex::new(from), LiveBundleIndex::new(to)),
+            _ => false,
+        }
+    }
+
+    /// Assign a register or spillslot to every bundle, splitting
+    /// bundles as necessary.
+    pub fn allocate(self) -> Allocate<'a, F> {
+        self.allocate_stepwise().finish()
+    }
+
+    /// Like `allocate()`, but process one bundle at a time: the
+    /// returned iterator yields a `Decision` describing each bundle's
+    /// processing in turn. Call `AllocationSteps::finish()` to
+    /// complete the phase.
+    pub fn allocate_stepwise(mut self) -> AllocationSteps<'a, F> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("queue_bundles").entered();
+        self.env.queue_bundles();
+        if log::log_enabled!(log::Level::Debug) {
+            self.env.dump_state();
+        }
+        AllocationSteps { env: self.env }
+    }
+}
+
+/// The allocation phase in progress, one bundle at a time; see
+/// `Merge::allocate_stepwise()`.
+pub struct AllocationSteps<'a, F: Function> {
+    env: Env<'a, F>,
+}
+
+/// A record of the processing of one bundle popped from the
+/// allocation queue.
+#[derive(Clone, Debug)]
+pub struct Decision {
+    /// The bundle processed.
+    pub bundle: usize,
+    /// The bundle's ranges when it was popped.
+    pub ranges: Vec<CodeRange>,
+    /// Each register probed, in order, and the result.
+    pub probes: Vec<(PReg, ProbeResult)>,
+    /// The bundles evicted from their registers to make room, in
+    /// order. They are requeued.
+    pub evicted: Vec<usize>,
+    /// What finally became of the bundle.
+    pub outcome: DecisionOutcome,
+}
+
+/// The result of trying to allocate a bundle to one register.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProbeResult {
+    Allocated,
+    /// The register is held by these bundles, which could be evicted.
+    Conflict(Vec<usize>),
+    /// The register is reserved by a fixed-register constraint.
+    ConflictWithFixed,
+}
+
+/// The final outcome of processing a bundle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecisionOutcome {
+    /// The bundle was allocated to this register.
+    Allocated(PReg),
+    /// The bundle does not require a register; it will get one after
+    /// the main loop if any is free, and be spilled otherwise.
+    Deferred,
+    /// The bundle must live in a spillslot (e.g. it holds a reference
+    /// across a safepoint), and was spilled.
+    Spilled,
+    /// The bundle was split, and the pieces requeued.
+    Split,
+    /// The bundle was sent to its spillslot everywhere but around its
+    /// uses, to break an eviction cascade or a requeue churn, and the
+    /// pieces around its uses requeued.
+    ForceSpilled,
+}
+
+impl<'a, F: Function> AllocationSteps<'a, F> {
+    /// The number of bundles waiting to be processed: in the
+    /// allocation queue, or in regions not yet reached.
+    pub fn queue_len(&self) -> usize {
+        self.env.queued_bundle_count()
+    }
+
+    /// Process the remaining bundles and complete the phase.
+    pub fn finish(mut self) -> Allocate<'a, F> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("allocate").entered();
+        self.env.process_bundles();
+        self.env.try_allocating_regs_for_spilled_bundles();
+        self.env.allocate_spillslots();
+        self.env.verify_invariants("allocate");
+        self.env.write_snapshot("allocate");
+        Allocate { env: self.env }
+    }
+}
+
+impl<'a, F: Function> Iterator for AllocationSteps<'a, F> {
+    type Item = Decision;
+
+    fn next(&mut self) -> Option<Decision> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("allocate").entered();
+        self.env.decision = Some(Decision {
+            bundle: 0,
+            ranges: vec![],
+            probes: vec![],
+            evicted: vec![],
+            outcome: DecisionOutcome::Split,
+        });
+        let processed = self.env.process_next_bundle();
+        let decision = self.env.decision.take();
+        if processed {
+            decision
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, F: Function> Allocate<'a, F> {
+    /// The allocation of `vreg` over each of its live ranges, in
+    /// program order. A range with allocation `none` belongs to a
+    /// constant that is rematerialized where needed.
+    pub fn vreg_allocations(&self, vreg: VReg) -> Vec<(CodeRange, Allocation)> {
+        let mut allocs = vec![];
+        let mut iter = self.env.vregs[vreg.vreg()].first_range;
+        while iter.is_valid() {
+            allocs.push((
+                self.env.ranges[iter.index()].range,
+                self.env.get_alloc_for_range(iter),
+            ));
+            iter = self.env.ranges[iter.index()].next_in_reg;
+        }
+        allocs
+    }
+
+    /// Every live range of every vreg with its allocation, in vreg
+    /// order.
+    pub fn live_ranges(&self) -> Vec<(VReg, CodeRange, Allocation)> {
+        let mut ranges = vec![];
+        for vreg in &self.env.vregs {
+            let mut iter = vreg.first_range;
+            while iter.is_valid() {
+                ranges.push((
+                    vreg.reg,
+                    self.env.ranges[iter.index()].range,
+                    self.env.get_alloc_for_range(iter),
+                ));
+                iter = self.env.ranges[iter.index()].next_in_reg;
+            }
+        }
+        ranges
+    }
+
+    /// The number of spillslots used so far.
+    pub fn num_spillslots(&self) -> usize {
+        self.env.num_spillslots as usize
+    }
+
+    /// The vregs with a spillslot beyond `RegallocOptions::max_spillslots`
+    /// (none if no budget is set). Slots needed later, e.g. to save
+    /// callee-saved registers, are not yet accounted for.
+    pub fn vregs_over_spillslot_budget(&self) -> Vec<VReg> {
+        match self.env.opts.max_spillslots {
+            Some(max) => self.env.vregs_in_spillslots_beyond(max),
+            None => vec![],
+        }
+    }
+
+    /// A vreg that must not spill (see `Function::no_spill`) but was
+    /// allocated a spillslot anyway, for lack of a register, with the
+    /// inst where it first is in the spillslot; `run()` reports this
+    /// as `RegAllocError::NoSpill`.
+    pub fn spilled_no_spill_vreg(&self) -> Option<(VReg, Inst)> {
+        self.env.spilled_no_spill_vreg()
+    }
+
+    /// The number of bundles processed, if allocation gave up for not
+    /// converging; the allocation is then incomplete, and `run()`
+    /// reports this as `RegAllocError::IterationLimit`.
+    pub fn iteration_limit_exceeded(&self) -> Option<usize> {
+        if self.env.iteration_limit_exceeded {
+            Some(self.env.stats.process_bundle_count)
+        } else {
+            None
+        }
+    }
+
+    /// Statistics gathered so far.
+    pub fn stats(&self) -> &Stats {
+        &self.env.stats
+    }
+
+    /// Rewrite the program with the allocations: fill in operand
+    /// allocations and insert the moves that connect them.
+    pub fn reify(self) -> Output {
+        self.reify_and_return_arena().0
+    }
+
+    /// Discard the allocation, and return the allocator's working
+    /// storage, emptied, for reuse by the next run.
+    pub fn into_arena(self) -> Arena {
+        self.env.into_arena()
+    }
+
+    /// Like `reify()`, but also return the allocator's working
+    /// storage, emptied, for reuse by the next run.
+    pub fn reify_and_return_arena(mut self) -> (Output, Arena) {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("reify").entered();
+        self.env.reify();
+        if log::log_enabled!(log::Level::Debug) {
+            self.env.dump_results();
+        }
+        self.env.into_output()
+    }
+}
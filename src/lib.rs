@@ -13,9 +13,12 @@
 #![allow(dead_code)]
 
 pub mod bitvec;
+pub mod cached;
 pub mod cfg;
 pub mod domtree;
+pub mod fastalloc;
 pub mod ion;
+pub mod liveness;
 pub mod moves;
 pub mod postorder;
 pub mod ssa;
@@ -24,41 +27,85 @@ pub mod ssa;
 pub mod index;
 pub use index::{Block, Inst, InstRange, InstRangeIter};
 
+#[cfg(feature = "checker")]
 pub mod checker;
+pub mod compact;
+pub mod diff;
 pub mod fuzzing;
 
 /// Register classes.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RegClass {
     Int = 0,
     Float = 1,
 }
 
-/// A physical register. Contains a physical register number and a class.
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct PReg(u8, RegClass);
+/// A physical register. Contains a physical register number, a class,
+/// and the register's hardware encoding.
+///
+/// The register number is the allocator's dense numbering of the
+/// registers in a class and determines `index()`. The hardware
+/// encoding is opaque to the allocator and is carried through to
+/// `Allocation`s for the embedder's use; it defaults to the register
+/// number (see `PReg::new()`), but targets with non-contiguous or
+/// banked hardware numbering can set it separately with
+/// `PReg::new_with_hw_enc()`. Equality, ordering and hashing consider
+/// only the class and register number, so a given register must
+/// always be created with the same hardware encoding.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PReg {
+    num: u8,
+    class: RegClass,
+    hw_enc: u16,
+}
 
 impl PReg {
-    pub const MAX_BITS: usize = 5;
+    pub const MAX_BITS: usize = 8;
     pub const MAX: usize = (1 << Self::MAX_BITS) - 1;
+    /// The size of the index space covered by `PReg::index()`, across
+    /// all register classes.
+    pub const NUM_INDEX: usize = 1 << (Self::MAX_BITS + 1);
+    pub const MAX_HW_ENC: usize = (1 << 16) - 1;
+
+    /// Create a new PReg whose hardware encoding is equal to its
+    /// register number. The `num` range is 8 bits.
+    #[inline(always)]
+    pub fn new(num: usize, class: RegClass) -> Self {
+        PReg::new_with_hw_enc(num, num, class)
+    }
+
+    /// Create a new PReg with a hardware encoding distinct from its
+    /// register number. The `num` range is 8 bits and the `hw_enc`
+    /// range is 16 bits.
+    #[inline(always)]
+    pub fn new_with_hw_enc(num: usize, hw_enc: usize, class: RegClass) -> Self {
+        assert!(num <= Self::MAX);
+        assert!(hw_enc <= Self::MAX_HW_ENC);
+        PReg {
+            num: num as u8,
+            class,
+            hw_enc: hw_enc as u16,
+        }
+    }
 
-    /// Create a new PReg. The `hw_enc` range is 6 bits.
+    /// The allocator's register number, dense within the register class.
     #[inline(always)]
-    pub fn new(hw_enc: usize, class: RegClass) -> Self {
-        assert!(hw_enc <= Self::MAX);
-        PReg(hw_enc as u8, class)
+    pub fn num(self) -> usize {
+        self.num as usize
     }
 
     /// The physical register number, as encoded by the ISA for the particular register class.
     #[inline(always)]
     pub fn hw_enc(self) -> usize {
-        self.0 as usize
+        self.hw_enc as usize
     }
 
     /// The register class.
     #[inline(always)]
     pub fn class(self) -> RegClass {
-        self.1
+        self.class
     }
 
     /// Get an index into the (not necessarily contiguous) index space of
@@ -66,12 +113,14 @@ impl PReg {
     /// all PRegs and index it efficiently.
     #[inline(always)]
     pub fn index(self) -> usize {
-        ((self.1 as u8 as usize) << 6) | (self.0 as usize)
+        ((self.class as u8 as usize) << Self::MAX_BITS) | (self.num as usize)
     }
 
+    /// Get the PReg for an index. The hardware encoding of the result
+    /// is the default one (equal to the register number).
     #[inline(always)]
     pub fn from_index(index: usize) -> Self {
-        let class = (index >> 6) & 1;
+        let class = (index >> Self::MAX_BITS) & 1;
         let class = match class {
             0 => RegClass::Int,
             1 => RegClass::Float,
@@ -87,11 +136,34 @@ impl PReg {
     }
 }
 
+impl std::cmp::PartialEq for PReg {
+    fn eq(&self, other: &Self) -> bool {
+        self.index() == other.index()
+    }
+}
+impl std::cmp::Eq for PReg {}
+impl std::cmp::PartialOrd for PReg {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl std::cmp::Ord for PReg {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.index().cmp(&other.index())
+    }
+}
+impl std::hash::Hash for PReg {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index().hash(state);
+    }
+}
+
 impl std::fmt::Debug for PReg {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "PReg(hw = {}, class = {:?}, index = {})",
+            "PReg(num = {}, hw = {}, class = {:?}, index = {})",
+            self.num(),
             self.hw_enc(),
             self.class(),
             self.index()
@@ -111,6 +183,7 @@ impl std::fmt::Display for PReg {
 
 /// A virtual register. Contains a virtual register number and a class.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VReg(u32);
 
 impl VReg {
@@ -160,7 +233,17 @@ impl std::fmt::Display for VReg {
     }
 }
 
+/// `vreg` for diagnostics, followed by its `Function::debug_vreg_name`
+/// if it has one: e.g. `v3 (x)`.
+pub(crate) fn vreg_label<F: Function>(func: &F, vreg: VReg) -> String {
+    match func.debug_vreg_name(vreg) {
+        Some(name) => format!("{} ({})", vreg, name),
+        None => vreg.to_string(),
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SpillSlot(u32);
 
 impl SpillSlot {
@@ -200,39 +283,71 @@ impl std::fmt::Display for SpillSlot {
 /// An Operand may be a use or def (this corresponds to `LUse` and
 /// `LAllocation` in Ion).
 #[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Operand {
-    /// Bit-pack into 31 bits. This allows a `Reg` to encode an
-    /// `Operand` or an `Allocation` in 32 bits.
+    /// Bit-pack into 64 bits. The low half holds the vreg and the
+    /// operand's shape; the high half holds the policy's payload (a
+    /// fixed PReg, a reused-input index or a set of excluded
+    /// registers), which must be wide enough for register classes
+    /// with many registers.
     ///
-    /// op-or-alloc:1 pos:2 kind:1 policy:2 class:1 preg:5 vreg:20
-    bits: u32,
+    /// payload:32 unused:1 fixed:1 cond:1 pos:3 kind:2 policy:3 class:1 vreg:20
+    ///
+    /// where a fixed- or preferred-reg payload is preg-hw-enc:16
+    /// preg-num:8. `Stack` and `FixedStack` share a policy value, and
+    /// are told apart by the fixed bit; a fixed-stack payload is the
+    /// slot index.
+    ///
+    /// 32 bits are not enough: the fields other than the payload take
+    /// 31, 20 of them for the vreg, and the payload needs up to 32
+    /// more (four excluded register numbers of 8 bits each) or 24 (a
+    /// register's number and 16-bit hardware encoding). The cost is 4
+    /// bytes per operand in the embedder's instruction data. The
+    /// allocator's own record of each operand (`Use` and `Def` in
+    /// `ion`) is 24 bytes either way, as the 4 bytes are otherwise
+    /// padding before its 8-byte operand slot.
+    bits: u64,
 }
 
 impl Operand {
     #[inline(always)]
     pub fn new(vreg: VReg, policy: OperandPolicy, kind: OperandKind, pos: OperandPos) -> Self {
-        let (preg_field, policy_field): (u32, u32) = match policy {
+        let (payload_field, policy_field): (u64, u64) = match policy {
             OperandPolicy::Any => (0, 0),
             OperandPolicy::Reg => (0, 1),
             OperandPolicy::FixedReg(preg) => {
                 assert_eq!(preg.class(), vreg.class());
-                (preg.hw_enc() as u32, 2)
+                (preg.num() as u64 | ((preg.hw_enc() as u64) << 8), 2)
             }
             OperandPolicy::Reuse(which) => {
-                assert!(which <= PReg::MAX);
-                (which as u32, 3)
+                assert!(which <= u32::MAX as usize);
+                (which as u64, 3)
+            }
+            OperandPolicy::Constant => (0, 4),
+            OperandPolicy::RegExcept(excluded) => (excluded.bits as u64, 5),
+            OperandPolicy::PrefReg(preg) => {
+                assert_eq!(preg.class(), vreg.class());
+                (preg.num() as u64 | ((preg.hw_enc() as u64) << 8), 6)
+            }
+            OperandPolicy::Stack => (0, 7),
+            OperandPolicy::FixedStack(slot) => {
+                assert_eq!(slot.class(), vreg.class());
+                (slot.index() as u64, 7)
             }
         };
-        let class_field = vreg.class() as u8 as u32;
-        let pos_field = pos as u8 as u32;
-        let kind_field = kind as u8 as u32;
+        assert!(kind != OperandKind::Mod || pos == OperandPos::Before);
+        let fixed_field = matches!(policy, OperandPolicy::FixedStack(_)) as u64;
+        let class_field = vreg.class() as u8 as u64;
+        let pos_field = pos as u8 as u64;
+        let kind_field = kind as u8 as u64;
         Operand {
-            bits: vreg.vreg() as u32
-                | (preg_field << 20)
-                | (class_field << 25)
-                | (policy_field << 26)
-                | (kind_field << 28)
-                | (pos_field << 29),
+            bits: vreg.vreg() as u64
+                | (class_field << 20)
+                | (policy_field << 21)
+                | (kind_field << 24)
+                | (pos_field << 26)
+                | (fixed_field << 30)
+                | (payload_field << 32),
         }
     }
 
@@ -247,7 +362,12 @@ impl Operand {
     }
     #[inline(always)]
     pub fn reg_use_at_end(vreg: VReg) -> Self {
-        Operand::new(vreg, OperandPolicy::Reg, OperandKind::Use, OperandPos::Both)
+        Operand::new(
+            vreg,
+            OperandPolicy::Reg,
+            OperandKind::Use,
+            OperandPos::After,
+        )
     }
     #[inline(always)]
     pub fn reg_def(vreg: VReg) -> Self {
@@ -260,11 +380,21 @@ impl Operand {
     }
     #[inline(always)]
     pub fn reg_def_at_start(vreg: VReg) -> Self {
-        Operand::new(vreg, OperandPolicy::Reg, OperandKind::Def, OperandPos::Both)
+        Operand::new(
+            vreg,
+            OperandPolicy::Reg,
+            OperandKind::Def,
+            OperandPos::Before,
+        )
     }
     #[inline(always)]
     pub fn reg_temp(vreg: VReg) -> Self {
-        Operand::new(vreg, OperandPolicy::Reg, OperandKind::Def, OperandPos::Both)
+        Operand::new(
+            vreg,
+            OperandPolicy::Reg,
+            OperandKind::Def,
+            OperandPos::Before,
+        )
     }
     #[inline(always)]
     pub fn reg_reuse_def(vreg: VReg, idx: usize) -> Self {
@@ -272,7 +402,36 @@ impl Operand {
             vreg,
             OperandPolicy::Reuse(idx),
             OperandKind::Def,
-            OperandPos::Both,
+            OperandPos::After,
+        )
+    }
+    /// A def that the instruction may leave unwritten (e.g. a
+    /// predicated load, or a cmov), in which case it keeps the prior
+    /// value given by the input at index `prior`. Liveness treats it
+    /// as a use of that value as well as a def: the prior value is
+    /// placed in the def's location before the instruction, as for
+    /// `reg_reuse_def`, and no other input may share that location.
+    #[inline(always)]
+    pub fn reg_cond_def(vreg: VReg, prior: usize) -> Self {
+        let op = Operand::new(
+            vreg,
+            OperandPolicy::Reuse(prior),
+            OperandKind::Def,
+            OperandPos::After,
+        );
+        Operand {
+            bits: op.bits | (1 << 29),
+        }
+    }
+    /// A read-modify-write of `vreg` in a register, e.g. the
+    /// destination of a two-address `add`; see `OperandKind::Mod`.
+    #[inline(always)]
+    pub fn reg_mod(vreg: VReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::Reg,
+            OperandKind::Mod,
+            OperandPos::Before,
         )
     }
     #[inline(always)]
@@ -293,6 +452,80 @@ impl Operand {
             OperandPos::After,
         )
     }
+    #[inline(always)]
+    pub fn reg_pref_use(vreg: VReg, preg: PReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::PrefReg(preg),
+            OperandKind::Use,
+            OperandPos::Before,
+        )
+    }
+    #[inline(always)]
+    pub fn reg_pref_def(vreg: VReg, preg: PReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::PrefReg(preg),
+            OperandKind::Def,
+            OperandPos::After,
+        )
+    }
+    #[inline(always)]
+    pub fn constant_def(vreg: VReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::Constant,
+            OperandKind::Def,
+            OperandPos::After,
+        )
+    }
+    #[inline(always)]
+    pub fn stack_use(vreg: VReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::Stack,
+            OperandKind::Use,
+            OperandPos::Before,
+        )
+    }
+    #[inline(always)]
+    pub fn stack_def(vreg: VReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::Stack,
+            OperandKind::Def,
+            OperandPos::After,
+        )
+    }
+    #[inline(always)]
+    pub fn stack_fixed_use(vreg: VReg, slot: SpillSlot) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::FixedStack(slot),
+            OperandKind::Use,
+            OperandPos::Before,
+        )
+    }
+    #[inline(always)]
+    pub fn stack_fixed_def(vreg: VReg, slot: SpillSlot) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::FixedStack(slot),
+            OperandKind::Def,
+            OperandPos::After,
+        )
+    }
+    /// A branch arg that the branch itself does not read; see
+    /// `OperandPos::Transfer`.
+    #[inline(always)]
+    pub fn transfer_use(vreg: VReg) -> Self {
+        Operand::new(
+            vreg,
+            OperandPolicy::Any,
+            OperandKind::Use,
+            OperandPos::Transfer,
+        )
+    }
 
     #[inline(always)]
     pub fn vreg(self) -> VReg {
@@ -302,7 +535,7 @@ impl Operand {
 
     #[inline(always)]
     pub fn class(self) -> RegClass {
-        let class_field = (self.bits >> 25) & 1;
+        let class_field = (self.bits >> 20) & 1;
         match class_field {
             0 => RegClass::Int,
             1 => RegClass::Float,
@@ -312,45 +545,71 @@ impl Operand {
 
     #[inline(always)]
     pub fn kind(self) -> OperandKind {
-        let kind_field = (self.bits >> 28) & 1;
+        let kind_field = (self.bits >> 24) & 3;
         match kind_field {
             0 => OperandKind::Def,
             1 => OperandKind::Use,
+            2 => OperandKind::Mod,
             _ => unreachable!(),
         }
     }
 
     #[inline(always)]
     pub fn pos(self) -> OperandPos {
-        let pos_field = (self.bits >> 29) & 3;
+        let pos_field = (self.bits >> 26) & 7;
         match pos_field {
             0 => OperandPos::Before,
-            1 => OperandPos::After,
-            2 => OperandPos::Both,
+            1 => OperandPos::BeforeLate,
+            2 => OperandPos::After,
+            3 => OperandPos::AfterLate,
+            4 => OperandPos::Transfer,
             _ => unreachable!(),
         }
     }
 
+    /// Is this a conditional def (see `reg_cond_def`)?
+    #[inline(always)]
+    pub fn is_conditional_def(self) -> bool {
+        (self.bits >> 29) & 1 != 0
+    }
+
     #[inline(always)]
     pub fn policy(self) -> OperandPolicy {
-        let policy_field = (self.bits >> 26) & 3;
-        let preg_field = ((self.bits >> 20) as usize) & PReg::MAX;
+        let policy_field = (self.bits >> 21) & 7;
+        let payload_field = (self.bits >> 32) as usize;
         match policy_field {
             0 => OperandPolicy::Any,
             1 => OperandPolicy::Reg,
-            2 => OperandPolicy::FixedReg(PReg::new(preg_field, self.class())),
-            3 => OperandPolicy::Reuse(preg_field),
+            2 => OperandPolicy::FixedReg(PReg::new_with_hw_enc(
+                payload_field & PReg::MAX,
+                (payload_field >> 8) & PReg::MAX_HW_ENC,
+                self.class(),
+            )),
+            3 => OperandPolicy::Reuse(payload_field),
+            4 => OperandPolicy::Constant,
+            5 => OperandPolicy::RegExcept(ExcludedRegs {
+                bits: payload_field as u32,
+            }),
+            6 => OperandPolicy::PrefReg(PReg::new_with_hw_enc(
+                payload_field & PReg::MAX,
+                (payload_field >> 8) & PReg::MAX_HW_ENC,
+                self.class(),
+            )),
+            7 if (self.bits >> 30) & 1 != 0 => {
+                OperandPolicy::FixedStack(SpillSlot::new(payload_field, self.class()))
+            }
+            7 => OperandPolicy::Stack,
             _ => unreachable!(),
         }
     }
 
     #[inline(always)]
-    pub fn bits(self) -> u32 {
+    pub fn bits(self) -> u64 {
         self.bits
     }
 
     #[inline(always)]
-    pub fn from_bits(bits: u32) -> Self {
+    pub fn from_bits(bits: u64) -> Self {
         Operand { bits }
     }
 }
@@ -359,12 +618,17 @@ impl std::fmt::Debug for Operand {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Operand(vreg = {:?}, class = {:?}, kind = {:?}, pos = {:?}, policy = {:?})",
+            "Operand(vreg = {:?}, class = {:?}, kind = {:?}, pos = {:?}, policy = {:?}{})",
             self.vreg().vreg(),
             self.class(),
             self.kind(),
             self.pos(),
-            self.policy()
+            self.policy(),
+            if self.is_conditional_def() {
+                ", conditional"
+            } else {
+                ""
+            }
         )
     }
 }
@@ -373,7 +637,12 @@ impl std::fmt::Display for Operand {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "{:?}@{:?}: {} {}",
+            "{}{:?}@{:?}: {} {}",
+            if self.is_conditional_def() {
+                "Cond"
+            } else {
+                ""
+            },
             self.kind(),
             self.pos(),
             self.vreg(),
@@ -390,8 +659,41 @@ pub enum OperandPolicy {
     Reg,
     /// Operand must be in a fixed register.
     FixedReg(PReg),
-    /// On defs only: reuse a use's register. Which use is given by `preg` field.
+    /// On defs only: reuse a use's register. Which use is given by the payload field.
     Reuse(usize),
+    /// On defs only: the value is a constant (e.g. an immediate or a
+    /// symbol address) that the embedder can synthesize into any
+    /// location at any point. Rather than keeping it live or spilling
+    /// it, the allocator may drop the value and insert an
+    /// `Edit::DefineConstant` wherever it is needed again. If the
+    /// def's own allocation is `Allocation::none()`, the defining
+    /// instruction need not be emitted at all. Uses of the value with
+    /// an `Any` policy are always given a register.
+    Constant,
+    /// Operand must be in a register, but not in any of the given
+    /// registers (of the operand's class).
+    RegExcept(ExcludedRegs),
+    /// Operand must be in a register, preferably the given one (e.g.
+    /// the register a return value or a two-address input will end
+    /// up in). Unlike `FixedReg`, the preference does not reserve the
+    /// register: the allocator probes it first, but takes any other
+    /// register rather than insert a move to satisfy it.
+    PrefReg(PReg),
+    /// Operand must be in a spillslot, e.g. for an instruction that
+    /// reads or writes it as a memory operand. The allocator moves the
+    /// value through the vreg's spillslot around the instruction as it
+    /// would for a spill. A constant read this way is kept in a
+    /// spillslot like any other value, rather than rematerialized; a
+    /// vreg that `Function::no_spill` keeps in a register cannot be.
+    Stack,
+    /// Operand must be in the given spillslot, which must be one of
+    /// those the embedder reserves (see
+    /// `Function::num_reserved_spillslots`): e.g. an outgoing stack
+    /// argument of a call, or an incoming stack parameter defined on
+    /// entry. The vreg itself may live anywhere; the allocator copies
+    /// it into the slot just before the instruction (for a use), or
+    /// out of the slot just after it (for a def).
+    FixedStack(SpillSlot),
 }
 
 impl std::fmt::Display for OperandPolicy {
@@ -401,7 +703,65 @@ impl std::fmt::Display for OperandPolicy {
             Self::Reg => write!(f, "reg"),
             Self::FixedReg(preg) => write!(f, "fixed({})", preg),
             Self::Reuse(idx) => write!(f, "reuse({})", idx),
+            Self::Constant => write!(f, "constant"),
+            Self::PrefReg(preg) => write!(f, "pref({})", preg),
+            Self::Stack => write!(f, "stack"),
+            Self::FixedStack(slot) => write!(f, "fixed({})", slot),
+            Self::RegExcept(excluded) => {
+                write!(f, "reg_except(")?;
+                for (i, num) in excluded.nums().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "p{}", num)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A small set of registers, by number within a class, that an
+/// `OperandPolicy::RegExcept` operand must not be allocated to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExcludedRegs {
+    /// Up to four register numbers, one per byte; unused bytes repeat
+    /// the first number.
+    bits: u32,
+}
+
+impl ExcludedRegs {
+    /// The maximum number of registers that can be excluded.
+    pub const MAX_LEN: usize = 4;
+
+    /// Exclude the given registers, of which there must be between
+    /// one and `MAX_LEN`.
+    pub fn new(regs: &[PReg]) -> Self {
+        assert!(!regs.is_empty() && regs.len() <= Self::MAX_LEN);
+        let mut bits = 0;
+        for i in 0..Self::MAX_LEN {
+            let preg = regs.get(i).unwrap_or(&regs[0]);
+            assert_eq!(preg.class(), regs[0].class());
+            bits |= (preg.num() as u32) << (8 * i);
         }
+        ExcludedRegs { bits }
+    }
+
+    /// Is `preg` excluded? Only its number is compared: the class is
+    /// that of the operand.
+    #[inline(always)]
+    pub fn contains(self, preg: PReg) -> bool {
+        self.nums().any(|num| num == preg.num())
+    }
+
+    /// The numbers of the excluded registers, without duplicates.
+    pub fn nums(self) -> impl Iterator<Item = usize> {
+        let bits = self.bits;
+        (0..Self::MAX_LEN)
+            .map(move |i| ((bits >> (8 * i)) & 0xff) as usize)
+            .enumerate()
+            .filter(move |&(i, num)| (0..i).all(|j| ((bits >> (8 * j)) & 0xff) as usize != num))
+            .map(|(_, num)| num)
     }
 }
 
@@ -409,22 +769,73 @@ impl std::fmt::Display for OperandPolicy {
 pub enum OperandKind {
     Def = 0,
     Use = 1,
+    /// A read-modify-write: the operand reads its vreg's value at
+    /// `Before` and writes the vreg's new value back to the same
+    /// location at `After`, so the vreg stays live across the
+    /// instruction. A `Mod` operand must be at `OperandPos::Before`,
+    /// must be the only mention of its vreg in its instruction, and
+    /// may not have a `Reuse`, `Constant` or `FixedStack` policy nor
+    /// be reused. It redefines its vreg, so it is only allowed in
+    /// non-SSA functions (see `Function::is_ssa`).
+    Mod = 2,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Where an operand is read (a use) or written (a def), relative to
+/// its instruction. Each of the `Before` and `After` points has an
+/// early and a late half, in program order `Before`, `BeforeLate`,
+/// `After`, `AfterLate`. A use is live until its point and a def from
+/// its point, so a use and a def of one instruction may share a
+/// register only if the def's point is later: e.g., a def at
+/// `BeforeLate` is written early, before the instruction's uses at
+/// `After` are read, but after those at `Before`; and a use at
+/// `AfterLate` is read late, after the defs at `After` are written.
+///
+/// `Transfer` is for the uses of a branch only: such a use is read
+/// not by the branch but by the moves on its out-edges, at the
+/// control transfer, so it places no constraint on where its value
+/// is kept at the branch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OperandPos {
     Before = 0,
-    After = 1,
-    Both = 2,
+    BeforeLate = 1,
+    After = 2,
+    AfterLate = 3,
+    Transfer = 4,
+}
+
+impl OperandPos {
+    /// All positions within an instruction, in program order.
+    pub const ALL: [OperandPos; 4] = [
+        OperandPos::Before,
+        OperandPos::BeforeLate,
+        OperandPos::After,
+        OperandPos::AfterLate,
+    ];
+
+    /// The program point of this position at `inst`.
+    #[inline(always)]
+    pub fn at(self, inst: Inst) -> ProgPoint {
+        match self {
+            OperandPos::Before => ProgPoint::before(inst),
+            OperandPos::BeforeLate => ProgPoint::before(inst).late(),
+            OperandPos::After => ProgPoint::after(inst),
+            OperandPos::AfterLate => ProgPoint::after(inst).late(),
+            // The control transfer happens once the branch is done.
+            OperandPos::Transfer => ProgPoint::after(inst).late(),
+        }
+    }
 }
 
 /// An Allocation represents the end result of regalloc for an
 /// Operand.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Allocation {
     /// Bit-pack in 31 bits:
     ///
     /// op-or-alloc:1 kind:2  index:29
+    ///
+    /// where a register's index is preg-hw-enc:16 preg-index:9.
     bits: u32,
 }
 
@@ -462,9 +873,15 @@ impl Allocation {
         Allocation::new(AllocationKind::None, 0)
     }
 
+    /// Create a register allocation. Both the register's index and
+    /// its hardware encoding are kept, and are available again via
+    /// `as_reg()`.
     #[inline(always)]
     pub fn reg(preg: PReg) -> Allocation {
-        Allocation::new(AllocationKind::Reg, preg.index())
+        Allocation::new(
+            AllocationKind::Reg,
+            preg.index() | (preg.hw_enc() << (PReg::MAX_BITS + 1)),
+        )
     }
 
     #[inline(always)]
@@ -490,7 +907,9 @@ impl Allocation {
     #[inline(always)]
     pub fn as_reg(self) -> Option<PReg> {
         if self.kind() == AllocationKind::Reg {
-            Some(PReg::from_index(self.index()))
+            let preg = PReg::from_index(self.index() & (PReg::NUM_INDEX - 1));
+            let hw_enc = self.index() >> (PReg::MAX_BITS + 1);
+            Some(PReg::new_with_hw_enc(preg.num(), hw_enc, preg.class()))
         } else {
             None
         }
@@ -535,6 +954,55 @@ impl Allocation {
     }
 }
 
+/// An adjustment to the spill weight that the allocator computes for a
+/// vreg from its defs and uses; see `Function::spill_weight`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SpillWeight {
+    /// Use the computed weight.
+    Default,
+    /// Multiply the computed weight by the given (non-negative)
+    /// factor.
+    Scale(f32),
+    /// Use the given weight instead, per instruction the vreg is
+    /// live across. For comparison, each register use of a vreg
+    /// weighs 2000, and each use that may be on the stack 1000,
+    /// spread over its whole live range.
+    Override(u32),
+}
+
+/// Where a vreg should go when it cannot keep a register throughout;
+/// see `Function::spill_preference`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SpillPreference {
+    /// Retry for any free register once allocation is otherwise
+    /// done, and spill if there is none.
+    Default,
+    /// Retry ahead of the vregs that prefer `Default`, trying the
+    /// callee-saved registers first, and only then fall back as for
+    /// `Default`. Suits hot values that must survive calls.
+    CalleeSavedReg,
+    /// Spill straight to a spillslot, without retrying for a
+    /// register. Suits cold values, leaving the registers to others.
+    Memory,
+}
+
+/// How the embedder recomputes a rematerializable vreg; see
+/// `Function::is_rematerializable`. The allocator treats every kind
+/// alike, and passes it back in each `Edit::Remat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RematKind {
+    /// A constant held in a register, e.g. a large immediate.
+    Constant,
+    /// An address computed from a pinned base, e.g. a frame slot or a
+    /// global's address.
+    Address,
+    /// Another cheap computation, identified by the embedder.
+    Other(u32),
+}
+
 /// A trait defined by the regalloc client to provide access to its
 /// machine-instruction / CFG representation.
 pub trait Function {
@@ -563,6 +1031,14 @@ pub trait Function {
     /// Get the block parameters for a given block.
     fn block_params(&self, block: Block) -> &[VReg];
 
+    /// Whether a block is known to run rarely, e.g. a slow path or a
+    /// trap handler. Splits, and hence spills and reloads, are placed
+    /// at the boundaries of cold blocks in preference to elsewhere,
+    /// and moves in them are considered free.
+    fn is_cold_block(&self, _block: Block) -> bool {
+        false
+    }
+
     /// Determine whether an instruction is a call instruction. This is used
     /// only for splitting heuristics.
     fn is_call(&self, insn: Inst) -> bool;
@@ -571,10 +1047,44 @@ pub trait Function {
     fn is_ret(&self, insn: Inst) -> bool;
 
     /// Determine whether an instruction is the end-of-block
-    /// branch. If so, its operands *must* be the block parameters for
-    /// each of its block's `block_succs` successor blocks, in order.
+    /// branch. If so, its `Use` operands *must* be the block
+    /// parameters for each of its block's `block_succs` successor
+    /// blocks, in order. It may also have `Def` operands (e.g. the
+    /// link register of a branch-and-link), which are written before
+    /// any out-edge moves and so are available in every successor.
+    /// A use that the branch does not itself read should be at
+    /// `OperandPos::Transfer` (see `Operand::transfer_use`); any
+    /// other use is held as its policy requires until the end of the
+    /// block, whatever its position.
     fn is_branch(&self, insn: Inst) -> bool;
 
+    /// Does this branch have a delay slot? Moves on the out-edge of a
+    /// block with a single successor normally go after its branch,
+    /// where they would run in the delay slot or not at all; for such
+    /// a branch they go just before it instead, after any other moves
+    /// there. An operand whose location those moves overwrite is
+    /// given the location of the corresponding successor blockparam,
+    /// which holds the argument once they have run, so the operands
+    /// of such a branch should use `OperandPolicy::Any`. Such a
+    /// branch may not have `Def` operands, as these would be written
+    /// only after the moves that should carry them.
+    fn branch_has_delay_slot(&self, _insn: Inst) -> bool {
+        false
+    }
+
+    /// Should the early `FixedReg` uses of this instruction (e.g. the
+    /// args of a call) be resolved as one group? If so, each such
+    /// vreg is allocated as if its use were `OperandPolicy::Any`, and
+    /// the whole group is then moved into its fixed registers by one
+    /// parallel move just before the instruction, which shares a
+    /// single scratch register. The vregs do not compete for their
+    /// registers in the lead-up to the instruction, and a vreg may be
+    /// fixed to several registers, or to a register the instruction
+    /// clobbers, at no extra cost. Uses of a branch are never grouped.
+    fn groups_fixed_uses(&self, _insn: Inst) -> bool {
+        false
+    }
+
     /// Determine whether an instruction is a safepoint and requires a stackmap.
     fn is_safepoint(&self, insn: Inst) -> bool;
 
@@ -582,22 +1092,220 @@ pub trait Function {
     /// vregs for (src, dst).
     fn is_move(&self, insn: Inst) -> Option<(VReg, VReg)>;
 
+    /// Does the embedder know that this vreg should share storage
+    /// with another one (e.g., they are related by a copy that it
+    /// plans to delete, which is not visible to `is_move`)? Chains of
+    /// such links form equivalence classes, which seed bundle merging
+    /// before the allocator's own move and blockparam merging. A link
+    /// is only a hint: it is ignored if the two vregs' live ranges
+    /// overlap or their classes differ.
+    fn equivalent_vreg(&self, _vreg: VReg) -> Option<VReg> {
+        None
+    }
+
+    /// Must no edits be inserted between this instruction and the
+    /// next one in its block? E.g., a compare and the conditional
+    /// branch that consumes its flags, or the parts of a
+    /// pseudo-instruction sequence. The allocator places moves at the
+    /// nearest legal point instead; if an operand constraint of
+    /// either instruction itself requires a move in between,
+    /// allocation fails with `RegAllocError::EditAtRestrictedPoint`.
+    /// Ignored for the last instruction of a block.
+    fn no_edits_after(&self, _insn: Inst) -> bool {
+        false
+    }
+
+    /// Does this instruction write the condition flags (or clobber
+    /// them, as a call may)? The flags are not allocated as a
+    /// register: they stay where the instructions put them, and no
+    /// edit is ever inserted between an instruction that writes them
+    /// and the last one that reads the value written, as if by
+    /// `no_edits_after`, since an edit might clobber them. Every read
+    /// of the flags must follow a write in the same block.
+    fn writes_flags(&self, _insn: Inst) -> bool {
+        false
+    }
+
+    /// Does this instruction read the condition flags? See
+    /// `writes_flags`.
+    fn reads_flags(&self, _insn: Inst) -> bool {
+        false
+    }
+
     // --------------------------
     // Instruction register slots
     // --------------------------
 
-    /// Get the Operands for an instruction.
-    fn inst_operands(&self, insn: Inst) -> &[Operand];
+    /// Get the Operands for an instruction. The allocator reads
+    /// operands only through `visit_operands()`, whose default calls
+    /// this; an embedder must implement one or the other. The default
+    /// panics.
+    fn inst_operands(&self, _insn: Inst) -> &[Operand] {
+        panic!("Function must implement inst_operands() or visit_operands()")
+    }
+
+    /// Call `f` with each operand of an instruction and its index, in
+    /// order. This is how the allocator, its validation and the
+    /// checker read operands, so an embedder that computes operands
+    /// on demand can implement this instead of `inst_operands()`,
+    /// rather than keep every instruction's operands materialized for
+    /// the whole allocation. The default visits `inst_operands()`.
+    fn visit_operands(&self, insn: Inst, f: &mut dyn FnMut(usize, Operand)) {
+        for (i, &op) in self.inst_operands(insn).iter().enumerate() {
+            f(i, op);
+        }
+    }
 
     /// Get the clobbers for an instruction.
     fn inst_clobbers(&self, insn: Inst) -> &[PReg];
 
+    /// The calling convention an instruction (typically a call)
+    /// follows, as an index into `MachineEnv::call_convs`, if any.
+    /// The convention's clobbers are added to those returned by
+    /// `inst_clobbers()`, so need not be listed there.
+    fn inst_call_conv(&self, _insn: Inst) -> Option<usize> {
+        None
+    }
+
+    /// Registers an instruction destroys only if the embedder emits
+    /// its slow path, e.g. a helper call with a fast path that
+    /// preserves them. The allocator avoids keeping values in these
+    /// registers across the instruction when another register is
+    /// free, but may do so; `Output::preserved_soft_clobbers` lists
+    /// where it did, and there the embedder must emit the path that
+    /// preserves the register.
+    fn inst_soft_clobbers(&self, _insn: Inst) -> &[PReg] {
+        &[]
+    }
+
     /// Get the precise number of `VReg` in use in this function, to allow
     /// preallocating data structures. This number *must* be a correct
     /// lower-bound, otherwise invalid index failures may happen; it is of
     /// course better if it is exact.
     fn num_vregs(&self) -> usize;
 
+    /// Is the function in SSA form, with each vreg defined exactly
+    /// once, by a `Def` operand or a blockparam? If not, a vreg may be
+    /// defined by any number of `Def` and `Mod` operands (see
+    /// `OperandKind::Mod`), each of which replaces its value, and each
+    /// use reads the value last written on the path to it. Every path
+    /// from the entry block to a use must still pass a def of its
+    /// vreg, and a blockparam's vreg, a constant (one with a
+    /// `Constant` def) and a rematerializable vreg must still be
+    /// defined once. SSA validation (`RegallocOptions::validate_ssa`)
+    /// checks this instead of dominance.
+    fn is_ssa(&self) -> bool {
+        true
+    }
+
+    /// A name for this vreg to show alongside it in diagnostics:
+    /// checker errors, explain-mode logging, debug annotations and
+    /// state dumps. E.g., the source variable it holds.
+    fn debug_vreg_name(&self, _vreg: VReg) -> Option<&str> {
+        None
+    }
+
+    /// The source variables whose locations to report in
+    /// `Output::debug_locations`, as `(vreg, from, to, label)`: the
+    /// variable `label`, an ID of the embedder's choosing (e.g. a
+    /// DWARF variable), is held in `vreg` from the start of `from` up
+    /// to the start of `to`. A variable may be held in different
+    /// vregs over different ranges, and a vreg may hold several
+    /// variables. Labels do not affect allocation.
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        &[]
+    }
+
+    /// Adjust the spill weight of this vreg, which decides which
+    /// values keep their registers when there are too few to go
+    /// round: e.g., raise it for a loop-carried accumulator, or lower
+    /// it for a value that only debug info reads. The pieces of a
+    /// vreg around its individual register uses are unaffected: these
+    /// must get registers whatever the weight.
+    fn spill_weight(&self, _vreg: VReg) -> SpillWeight {
+        SpillWeight::Default
+    }
+
+    /// Where the pieces of this vreg that need no register should
+    /// go once it is split, e.g. around a call or between uses: see
+    /// `SpillPreference`. A bundle of several vregs prefers a
+    /// callee-saved register if any of them does, and memory only if
+    /// all of them do.
+    fn spill_preference(&self, _vreg: VReg) -> SpillPreference {
+        SpillPreference::Default
+    }
+
+    /// Whether this vreg must stay in a register wherever it is live,
+    /// e.g. between a load-linked and a store-conditional, where a
+    /// reload from the stack would clear the reservation. The
+    /// allocator evicts and splits other vregs to make room, moving
+    /// this one only between registers, and fails with
+    /// `RegAllocError::NoSpill` if there is no room.
+    fn no_spill(&self, _vreg: VReg) -> bool {
+        false
+    }
+
+    /// The register this vreg is held in wherever it is live, if it
+    /// is pinned to one: e.g. a stack pointer, or a VM context
+    /// register, which other code expects to find there. Every range
+    /// of the vreg is reserved in the register, as for a clobber, and
+    /// no other vreg is allocated to it there; the vreg is never
+    /// split, moved or spilled. Its operands need not be fixed to the
+    /// register, but must accept it: each must have an `Any`, `Reg`,
+    /// `PrefReg` or `RegExcept` policy that does not exclude it, or a
+    /// `FixedReg` policy for the register itself. The register must
+    /// not be a scratch register, nor be clobbered or fixed to another
+    /// operand while the vreg is live, and the vreg must not hold a
+    /// reference (see `is_reftype`); otherwise allocation fails with
+    /// `RegAllocError::Pinned`. The register is typically not
+    /// allocatable at all; if it is, other vregs may use it where the
+    /// pinned vreg is not live.
+    fn pinned_vreg(&self, _vreg: VReg) -> Option<PReg> {
+        None
+    }
+
+    /// Whether this vreg holds a reference that a garbage collector
+    /// must find, and may update, at each safepoint (see
+    /// `is_safepoint`) that the vreg is live across. The allocator
+    /// keeps the vreg in a spillslot during each such safepoint, and
+    /// lists the slot in `Output::safepoint_slots`. The safepoint
+    /// itself may use the vreg only with an `Any` or `Stack` policy,
+    /// or with a `FixedReg` policy at `Before`, which is handled as if
+    /// `groups_fixed_uses` were set; any other register use of it
+    /// fails with `RegAllocError::RefUseAtSafepoint`. A constant has
+    /// no location of its own and is never listed.
+    fn is_reftype(&self, _vreg: VReg) -> bool {
+        false
+    }
+
+    /// For a reference-typed vreg that points into the middle of an
+    /// object (e.g. base plus offset), the reference-typed vreg
+    /// holding the object's base, which a moving collector must see
+    /// with it. At each safepoint the derived vreg is live across,
+    /// the allocator keeps the base in its spillslot too, even if the
+    /// base is not otherwise live there, and reports the derived slot
+    /// paired with the base's slot in `Output::safepoint_derived_slots`
+    /// rather than in `Output::safepoint_slots`. The base may not
+    /// itself be derived or be a constant; otherwise allocation fails
+    /// with `RegAllocError::DerivedRef`.
+    fn derived_ref_base(&self, _vreg: VReg) -> Option<VReg> {
+        None
+    }
+
+    /// Whether this vreg's value can be recomputed cheaply at any
+    /// point where it is live, without reading any other vreg (e.g. a
+    /// constant, or an address off the frame pointer), and how.
+    /// Wherever the allocator would spill such a vreg, it keeps no
+    /// copy at all, and inserts an `Edit::Remat` where a register
+    /// needs the value again, instead of a reload. The defining
+    /// instruction is still emitted. Uses of the vreg with an `Any`
+    /// policy are given a register. A vreg defined by a blockparam or
+    /// a conditional def, read with a `Stack` policy, or holding a
+    /// reference (see `is_reftype`) is spilled as usual.
+    fn is_rematerializable(&self, _vreg: VReg) -> Option<RematKind> {
+        None
+    }
+
     // --------------
     // Spills/reloads
     // --------------
@@ -622,28 +1330,70 @@ pub trait Function {
     fn multi_spillslot_named_by_last_slot(&self) -> bool {
         false
     }
+
+    /// How many spillslots at the start of the spillslot numbering
+    /// space are reserved by the embedder (e.g., for stack arguments
+    /// or statically allocated temporaries)? The allocator never
+    /// assigns slots with an index below this number, so that
+    /// `Allocation::stack` values from both sources can share one
+    /// numbering space; `Output::num_spillslots` includes the
+    /// reserved slots.
+    fn num_reserved_spillslots(&self) -> usize {
+        0
+    }
 }
 
 /// A position before or after an instruction.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum InstPosition {
     Before = 0,
     After = 1,
 }
 
-/// A program point: a single point before or after a given instruction.
+/// The packed form of a `ProgPoint`, as returned by
+/// `ProgPoint::to_index()`. This is a `u32` by default, which limits
+/// a function to `ProgPoint::MAX_INSTS` instructions; the
+/// `large-functions` feature widens it to a `u64`, raising the limit
+/// to nearly the full range of `Inst`, at some cost in memory.
+#[cfg(not(feature = "large-functions"))]
+pub type ProgPointIndex = u32;
+/// The packed form of a `ProgPoint`, as returned by
+/// `ProgPoint::to_index()`. This is a `u64` with the
+/// `large-functions` feature, allowing up to `ProgPoint::MAX_INSTS`
+/// instructions in a function.
+#[cfg(feature = "large-functions")]
+pub type ProgPointIndex = u64;
+
+/// A program point: a single point before or after a given
+/// instruction. Each point has an early and a late half, which order
+/// the operands of the instruction at that point (see `OperandPos`).
+/// Edits are only ever placed at the early half.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProgPoint {
     pub inst: Inst,
     pub pos: InstPosition,
+    late: bool,
 }
 
 impl ProgPoint {
+    /// The most instructions a function may have: the point before
+    /// the instruction past the last must still pack into a
+    /// `ProgPointIndex`.
+    #[cfg(not(feature = "large-functions"))]
+    pub const MAX_INSTS: usize = (1 << 30) - 1;
+    /// The most instructions a function may have: the instruction
+    /// past the last must still have a valid `Inst` index.
+    #[cfg(feature = "large-functions")]
+    pub const MAX_INSTS: usize = u32::MAX as usize - 1;
+
     pub fn before(inst: Inst) -> Self {
         Self {
             inst,
             pos: InstPosition::Before,
+            late: false,
         }
     }
 
@@ -651,94 +1401,489 @@ impl ProgPoint {
         Self {
             inst,
             pos: InstPosition::After,
+            late: false,
+        }
+    }
+
+    /// The late half of this point.
+    pub fn late(self) -> Self {
+        Self { late: true, ..self }
+    }
+
+    /// The early half of this point.
+    pub fn early(self) -> Self {
+        Self {
+            late: false,
+            ..self
         }
     }
 
+    pub fn is_late(self) -> bool {
+        self.late
+    }
+
     pub fn next(self) -> ProgPoint {
-        match self.pos {
-            InstPosition::Before => ProgPoint {
-                inst: self.inst,
-                pos: InstPosition::After,
-            },
-            InstPosition::After => ProgPoint {
-                inst: self.inst.next(),
-                pos: InstPosition::Before,
-            },
+        match (self.pos, self.late) {
+            (_, false) => self.late(),
+            (InstPosition::Before, true) => ProgPoint::after(self.inst),
+            (InstPosition::After, true) => ProgPoint::before(self.inst.next()),
         }
     }
 
     pub fn prev(self) -> ProgPoint {
-        match self.pos {
-            InstPosition::Before => ProgPoint {
-                inst: self.inst.prev(),
-                pos: InstPosition::After,
-            },
-            InstPosition::After => ProgPoint {
-                inst: self.inst,
-                pos: InstPosition::Before,
-            },
+        match (self.pos, self.late) {
+            (_, true) => self.early(),
+            (InstPosition::Before, false) => ProgPoint::after(self.inst.prev()).late(),
+            (InstPosition::After, false) => ProgPoint::before(self.inst).late(),
         }
     }
 
-    pub fn to_index(self) -> u32 {
-        debug_assert!(self.inst.index() <= ((1 << 31) - 1));
-        ((self.inst.index() as u32) << 1) | (self.pos as u8 as u32)
+    pub fn to_index(self) -> ProgPointIndex {
+        debug_assert!(self.inst.index() <= Self::MAX_INSTS);
+        ((self.inst.index() as ProgPointIndex) << 2)
+            | ((self.pos as u8 as ProgPointIndex) << 1)
+            | (self.late as ProgPointIndex)
     }
 
-    pub fn from_index(index: u32) -> Self {
-        let inst = Inst::new((index >> 1) as usize);
-        let pos = match index & 1 {
-            0 => InstPosition::Before,
-            1 => InstPosition::After,
+    pub fn from_index(index: ProgPointIndex) -> Self {
+        let inst = Inst::new((index >> 2) as usize);
+        let point = match (index >> 1) & 1 {
+            0 => ProgPoint::before(inst),
+            1 => ProgPoint::after(inst),
             _ => unreachable!(),
         };
-        Self { inst, pos }
+        if index & 1 != 0 {
+            point.late()
+        } else {
+            point
+        }
     }
 }
 
 /// An instruction to insert into the program to perform some data movement.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Edit {
     /// Move one allocation to another. Each allocation may be a
     /// register or a stack slot (spillslot).
     Move { from: Allocation, to: Allocation },
     /// Define blockparams' locations. Note that this is not typically
     /// turned into machine code, but can be useful metadata (e.g. for
-    /// the checker).
+    /// the checker). Only generated if
+    /// `RegallocOptions::report_blockparams` or `validate_output` is
+    /// set.
     BlockParams {
         vregs: Vec<VReg>,
         allocs: Vec<Allocation>,
     },
+    /// Materialize the value of the given vreg, which must be defined
+    /// with `OperandPolicy::Constant`, into an allocation. This may be
+    /// a register or a spillslot.
+    DefineConstant { vreg: VReg, to: Allocation },
+    /// Recompute the value of the given vreg, which
+    /// `Function::is_rematerializable` describes with `kind`, into an
+    /// allocation. This is always a register.
+    Remat {
+        vreg: VReg,
+        kind: RematKind,
+        to: Allocation,
+    },
+}
+
+impl std::fmt::Display for Edit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Edit::Move { from, to } => write!(f, "move {} -> {}", from, to),
+            Edit::BlockParams { vregs, allocs } => {
+                write!(f, "blockparams")?;
+                for (i, (vreg, alloc)) in vregs.iter().zip(allocs.iter()).enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}={}", sep, vreg, alloc)?;
+                }
+                Ok(())
+            }
+            Edit::DefineConstant { vreg, to } => write!(f, "constant {} -> {}", vreg, to),
+            Edit::Remat { vreg, kind, to } => write!(f, "remat {} ({:?}) -> {}", vreg, kind, to),
+        }
+    }
 }
 
 /// A machine envrionment tells the register allocator which registers
 /// are available to allocate and what register may be used as a
 /// scratch register for each class, and some other miscellaneous info
 /// as well.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct MachineEnv {
-    regs: Vec<PReg>,
-    regs_by_class: Vec<Vec<PReg>>,
-    scratch_by_class: Vec<PReg>,
+    /// All allocatable registers.
+    pub regs: Vec<PReg>,
+    /// Allocatable registers, in order of preference, indexed by
+    /// register class.
+    pub regs_by_class: Vec<Vec<PReg>>,
+    /// The scratch register for each register class, used when
+    /// resolving cyclic moves. A class may be left without an entry
+    /// here; the allocator then uses any register that is free at the
+    /// point where a scratch is needed.
+    pub scratch_by_class: Vec<PReg>,
+    /// The byte size and alignment of one spillslot unit, indexed by
+    /// register class. If provided, the allocator lays out the
+    /// spillslots it uses in bytes and reports the result in
+    /// `Output::spillslot_offsets`; if empty, no layout is computed.
+    pub spillslot_layout_by_class: Vec<SpillSlotLayout>,
+    /// Registers that the function must preserve for its caller. If
+    /// `RegallocOptions::save_callee_saves` is set, the allocator saves
+    /// each of these that it uses to a dedicated spillslot at function
+    /// entry and restores it before every return.
+    pub callee_saved_regs: Vec<PReg>,
+    /// The frame-pointer register, if the target has one. It is
+    /// reserved (never allocated, even if listed in `regs`) unless
+    /// `RegallocOptions::allocate_frame_pointer` is set for the
+    /// function, in which case it is allocatable, after all other
+    /// registers of its class if it is not listed in `regs_by_class`.
+    pub frame_pointer: Option<PReg>,
+    /// Calling conventions that instructions may refer to with
+    /// `Function::inst_call_conv`, rather than listing every register
+    /// they clobber.
+    pub call_convs: Vec<CallConv>,
+    /// A small cost for allocating each listed register, e.g. for
+    /// one that needs a longer instruction encoding (such as a REX
+    /// prefix for r8-r15 on x86-64) or one that is best left alone.
+    /// Registers not listed cost zero. When more than one register is
+    /// free for a bundle, the allocator takes the cheapest; costs
+    /// never cause a spill or an eviction.
+    pub reg_costs: Vec<(PReg, u32)>,
+    /// Registers, indexed by register class, to try first for a value
+    /// that does not live across a call, and last for one that does:
+    /// typically the caller-saved registers, which the function may
+    /// use without saving them. Each should also be listed in
+    /// `regs_by_class`; others are ignored. Among registers of equal
+    /// cost (see `reg_costs`), the allocator takes these before any
+    /// unlisted ones.
+    pub preferred_regs_by_class: Vec<Vec<PReg>>,
+    /// Registers, indexed by register class, to try first for a value
+    /// that lives across a call, and last for one that does not:
+    /// typically the callee-saved registers, which keep a value
+    /// through a call without a save and restore around it. As for
+    /// `preferred_regs_by_class`, each should also be listed in
+    /// `regs_by_class`.
+    pub non_preferred_regs_by_class: Vec<Vec<PReg>>,
 }
 
-/// The output of the register allocator.
-#[derive(Clone, Debug)]
-pub struct Output {
-    /// How many spillslots are needed in the frame?
-    pub num_spillslots: usize,
-    /// Edits (insertions or removals). Guaranteed to be sorted by
-    /// program point.
-    pub edits: Vec<(ProgPoint, Edit)>,
-    /// Allocations for each operand. Mapping from instruction to
-    /// allocations provided by `inst_alloc_offsets` below.
-    pub allocs: Vec<Allocation>,
-    /// Allocation offset in `allocs` for each instruction.
-    pub inst_alloc_offsets: Vec<u32>,
+impl MachineEnv {
+    /// All registers `inst` clobbers: those `Function::inst_clobbers`
+    /// lists, then those of its calling convention, if any. A register
+    /// may appear twice.
+    pub fn inst_clobbers<'a, F: Function>(
+        &'a self,
+        func: &'a F,
+        inst: Inst,
+    ) -> impl Iterator<Item = PReg> + 'a {
+        let conv_clobbers = match func.inst_call_conv(inst) {
+            Some(conv) => &self.call_convs[conv].clobbers[..],
+            None => &[],
+        };
+        func.inst_clobbers(inst)
+            .iter()
+            .chain(conv_clobbers.iter())
+            .cloned()
+    }
 
-    /// Internal stats from the allocator.
-    pub stats: ion::Stats,
-}
+    /// The cost of allocating `preg`; see `reg_costs`.
+    pub fn reg_cost(&self, preg: PReg) -> u32 {
+        self.reg_costs
+            .iter()
+            .find(|&&(reg, _)| reg == preg)
+            .map(|&(_, cost)| cost)
+            .unwrap_or(0)
+    }
+
+    /// This environment with the frame pointer, if any, either
+    /// removed from or added to the allocatable registers.
+    pub fn with_frame_pointer(&self, allocatable: bool) -> MachineEnv {
+        let mut env = self.clone();
+        if let Some(fp) = self.frame_pointer {
+            let class = fp.class() as u8 as usize;
+            if !allocatable {
+                env.regs.retain(|&r| r != fp);
+                env.regs_by_class[class].retain(|&r| r != fp);
+            } else if !env.regs_by_class[class].contains(&fp) {
+                env.regs.push(fp);
+                env.regs_by_class[class].push(fp);
+            }
+        }
+        env
+    }
+}
+
+/// The registers used by a calling convention; see
+/// `MachineEnv::call_convs`.
+#[derive(Clone, Debug, Default)]
+pub struct CallConv {
+    /// A name for the convention, for debugging output.
+    pub name: String,
+    /// The registers clobbered by every instruction following the
+    /// convention. As with `Function::inst_clobbers`, a clobber
+    /// occupies its register for the whole instruction, so none of
+    /// the instruction's operands may be fixed to one, other than
+    /// uses it groups (see `Function::groups_fixed_uses`).
+    pub clobbers: Vec<PReg>,
+    /// The registers arguments are passed in, in order, indexed by
+    /// register class. The allocator does not use these itself; they
+    /// let the embedder build `OperandPolicy::FixedReg` operands for a
+    /// call without keeping its own tables.
+    pub arg_regs_by_class: Vec<Vec<PReg>>,
+    /// The registers return values are passed in, in order, indexed
+    /// by register class.
+    pub ret_regs_by_class: Vec<Vec<PReg>>,
+}
+
+impl CallConv {
+    /// The register the `index`th argument of `class` is passed in,
+    /// or `None` if it is passed on the stack.
+    pub fn arg_reg(&self, class: RegClass, index: usize) -> Option<PReg> {
+        self.arg_regs_by_class
+            .get(class as u8 as usize)
+            .and_then(|regs| regs.get(index))
+            .cloned()
+    }
+
+    /// The register the `index`th return value of `class` is passed
+    /// in, or `None` if it is passed on the stack.
+    pub fn ret_reg(&self, class: RegClass, index: usize) -> Option<PReg> {
+        self.ret_regs_by_class
+            .get(class as u8 as usize)
+            .and_then(|regs| regs.get(index))
+            .cloned()
+    }
+}
+
+/// The moves the allocator placed on one CFG edge; see
+/// `Output::edge_moves`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EdgeMoves {
+    pub from: Block,
+    pub to: Block,
+    /// The number of moves (and constant materializations) on the
+    /// edge.
+    pub moves: usize,
+    /// A rough estimate of the moves' execution cost: one per
+    /// register-to-register move or constant, two per move to or from
+    /// the stack.
+    pub cost: usize,
+}
+
+/// The edits the allocator inserted into one block, by kind; see
+/// `Output::block_edits`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockEdits {
+    /// Moves from one register to another.
+    pub moves: usize,
+    /// Moves from a register to a spillslot.
+    pub spills: usize,
+    /// Moves from a spillslot to a register.
+    pub reloads: usize,
+    /// Moves from one spillslot to another.
+    pub stack_moves: usize,
+    /// Constant materializations.
+    pub constants: usize,
+    /// Rematerializations, in place of reloads.
+    pub remats: usize,
+}
+
+impl BlockEdits {
+    /// The total number of edits, of all kinds.
+    pub fn total(&self) -> usize {
+        self.moves + self.spills + self.reloads + self.stack_moves + self.constants + self.remats
+    }
+}
+
+/// The register pressure in one block: the greatest number of vregs
+/// of each class live at once anywhere in it, per the allocator's
+/// liveness analysis; see `register_pressure()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockPressure {
+    /// Integer vregs.
+    pub int: usize,
+    /// Float vregs.
+    pub float: usize,
+}
+
+impl BlockPressure {
+    /// The pressure on the registers of `class`.
+    pub fn get(&self, class: RegClass) -> usize {
+        match class {
+            RegClass::Int => self.int,
+            RegClass::Float => self.float,
+        }
+    }
+}
+
+/// One event in the allocation of a vreg, recorded if
+/// `RegallocOptions::explain` is set; see `Output::explanations`.
+/// Each gives the span of the vreg's live ranges that it concerns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AllocationEvent {
+    /// The vreg was evicted from `preg` to make room for `by` (or
+    /// rather, for a bundle of vregs including it), whose spill
+    /// weight was higher.
+    Evicted {
+        range: ion::CodeRange,
+        preg: PReg,
+        by: VReg,
+    },
+    /// The vreg's bundle got no register, and was split at the
+    /// given points. `conflict` is (a vreg in) the bundle holding the
+    /// register that it wanted, or `None` if it was held by a
+    /// fixed-register constraint.
+    Split {
+        range: ion::CodeRange,
+        at: Vec<ProgPoint>,
+        reason: SplitReason,
+        conflict: Option<VReg>,
+    },
+    /// The vreg was spilled: no register was required, and none was
+    /// free; or it had to be in its spillslot, across a safepoint.
+    Spilled { range: ion::CodeRange },
+}
+
+/// Where a bundle was split; see `AllocationEvent::Split`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SplitReason {
+    /// At the boundaries between hot code (inner loops) and the rest.
+    HotCode,
+    /// At the boundaries between cold blocks (see
+    /// `Function::is_cold_block`) and the rest.
+    ColdBlocks,
+    /// Before each instruction with clobbers (e.g. a call).
+    Clobbers,
+    /// Just before the first use after the conflicting bundle.
+    AfterConflict,
+    /// Just after the last use before the conflicting bundle.
+    BeforeConflict,
+    /// Around every use, leaving minimal bundles.
+    AllUses,
+}
+
+/// The in-memory layout of one spillslot unit for a register class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpillSlotLayout {
+    /// Size in bytes of one spillslot unit. A spillslot of size `n`
+    /// (as returned by `Function::spillslot_size()`) occupies `n`
+    /// times this many bytes.
+    pub unit_bytes: u32,
+    /// Required alignment in bytes of a spillslot; must be a power
+    /// of two.
+    pub align: u32,
+}
+
+/// The output of the register allocator. With the `enable-serde`
+/// feature, this can be serialized (in any `serde` format), e.g. to
+/// cache it keyed by a hash of the input function.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Output {
+    /// How many spillslots are needed in the frame?
+    pub num_spillslots: usize,
+    /// Edits (insertions or removals). Guaranteed to be sorted by
+    /// program point.
+    pub edits: Vec<(ProgPoint, Edit)>,
+    /// Allocations for each operand. Mapping from instruction to
+    /// allocations provided by `inst_alloc_offsets` below.
+    pub allocs: Vec<Allocation>,
+    /// Allocation offset in `allocs` for each instruction.
+    pub inst_alloc_offsets: Vec<u32>,
+    /// Byte offset of each spillslot within the spill area, indexed
+    /// by `SpillSlot::index()`. Only computed if the `MachineEnv`
+    /// provides a `spillslot_layout_by_class`, and empty
+    /// otherwise. Entries for indices that do not name a spillslot
+    /// (e.g. the non-named units of a multi-unit slot, or slots
+    /// reserved by the embedder) are `u32::MAX`; prefer
+    /// `Output::spillslot_offset()`.
+    pub spillslot_offsets: Vec<u32>,
+    /// Total size in bytes of the spill area described by
+    /// `spillslot_offsets`, or zero if no layout was computed.
+    pub spillslot_area_size: u32,
+    /// The callee-saved registers that were used and hence saved, with
+    /// the spillslot each was saved to, if
+    /// `RegallocOptions::save_callee_saves` was set; empty otherwise.
+    pub callee_saves: Vec<(PReg, SpillSlot)>,
+    /// The move instructions (per `Function::is_move()`) whose source
+    /// and destination received the same allocation, indexed by
+    /// instruction. These are no-ops and may be skipped during
+    /// emission; prefer `Output::is_elided_move()`.
+    pub elided_moves: bitvec::BitVec,
+    /// For each CFG edge that carries moves, how many and at what
+    /// estimated cost, sorted by edge. Edges not listed carry no
+    /// moves, and so are the best candidates to lay out as
+    /// fallthroughs. Only computed if
+    /// `RegallocOptions::report_edge_moves` is set; empty otherwise.
+    pub edge_moves: Vec<EdgeMoves>,
+    /// The edits inserted into each block, by kind, indexed by block.
+    /// Edits on a CFG edge count towards the block they were placed
+    /// in; `Edit::BlockParams` is metadata and is not counted.
+    pub block_edits: Vec<BlockEdits>,
+    /// Every register the allocation uses, sorted by index: those
+    /// assigned to an operand, and those read or written by an edit
+    /// (e.g. the scratch register). Registers the instructions
+    /// themselves clobber (`MachineEnv::inst_clobbers`), and the saves
+    /// and restores of `RegallocOptions::save_callee_saves`, are not
+    /// counted.
+    pub used_regs: Vec<PReg>,
+    /// The registers written by some edit, sorted by index: a subset
+    /// of `used_regs`.
+    pub edit_clobbered_regs: Vec<PReg>,
+    /// For each vreg that was evicted, split or spilled, the events
+    /// that led to its final allocation, in order; sorted by vreg.
+    /// Only recorded if `RegallocOptions::explain` is set; empty
+    /// otherwise.
+    pub explanations: Vec<(VReg, Vec<AllocationEvent>)>,
+    /// For each safepoint (per `Function::is_safepoint`), the location
+    /// during it of every vreg live across it, i.e. live both before
+    /// and after it; sorted by inst, then vreg. The embedder picks out
+    /// the vregs holding references, e.g. to build register maps for
+    /// a runtime that can walk registers. Constants, which have no
+    /// location, are left out. Only computed if
+    /// `RegallocOptions::report_safepoint_locations` is set; empty
+    /// otherwise.
+    pub safepoint_locations: Vec<(Inst, VReg, Allocation)>,
+    /// The soft clobbers (per `Function::inst_soft_clobbers`) that the
+    /// allocation relies on being preserved, i.e. where it keeps a
+    /// value in the register during the instruction; sorted by inst,
+    /// then register index. Every other soft clobber may be
+    /// destroyed.
+    pub preserved_soft_clobbers: Vec<(Inst, PReg)>,
+    /// For each safepoint (per `Function::is_safepoint`), the
+    /// spillslots holding the reference-typed vregs (per
+    /// `Function::is_reftype`) live across it, i.e. its stackmap;
+    /// sorted by inst, then slot. A slot holds the same reference
+    /// before and after the safepoint, so a moving collector updates
+    /// it in place.
+    pub safepoint_slots: Vec<(Inst, SpillSlot)>,
+    /// For each safepoint, the spillslots holding the derived
+    /// references (per `Function::derived_ref_base`) live across it,
+    /// each with the slot holding its base, as `(inst, derived,
+    /// base)`; sorted by inst, then derived slot. The base's slot is
+    /// also listed in `safepoint_slots`, so a moving collector can
+    /// update the base and then the derived slot by the same offset.
+    pub safepoint_derived_slots: Vec<(Inst, SpillSlot, SpillSlot)>,
+    /// For each label of `Function::debug_value_labels`, where its
+    /// vreg is over the label's range, as `(label, from, to, alloc)`:
+    /// in `alloc` from `from` up to but not including `to`. Sorted by
+    /// label, then `from`; adjacent entries in the same allocation are
+    /// joined. Where the vreg is not live, or has no location (a
+    /// constant or a spilled rematerializable value), there is no
+    /// entry.
+    pub debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
+    /// The configuration that produced this output; see
+    /// `RegallocOptions::fingerprint()`.
+    pub fingerprint: Fingerprint,
+
+    /// Internal stats from the allocator.
+    pub stats: ion::Stats,
+}
 
 impl Output {
     pub fn inst_allocs(&self, inst: Inst) -> &[Allocation] {
@@ -750,6 +1895,81 @@ impl Output {
         };
         &self.allocs[start..end]
     }
+
+    /// Returns the byte offset within the spill area of the start of
+    /// the given spillslot, regardless of whether the slot is named by
+    /// its first or last unit. Returns `None` if no spillslot layout
+    /// was requested or the slot was not allocated.
+    pub fn spillslot_offset(&self, slot: SpillSlot) -> Option<u32> {
+        match self.spillslot_offsets.get(slot.index()) {
+            Some(&offset) if offset != u32::MAX => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Returns true if `inst` is a move instruction that the
+    /// allocation made redundant, so that it need not be emitted.
+    pub fn is_elided_move(&self, inst: Inst) -> bool {
+        self.elided_moves.get(inst.index())
+    }
+}
+
+/// A canonical textual rendering of the allocation results, suitable
+/// for golden/snapshot tests: one line per instruction with its
+/// operand allocations, and one indented line per edit, placed before
+/// or after the instruction at whose program point it occurs. The
+/// format depends only on the results themselves (not on `stats` or
+/// on allocator-internal state), and is kept stable across versions.
+///
+/// ```text
+/// spillslots: 1
+///   before inst1: move p0i -> stack0
+/// inst1: p0i, p1i
+///   after inst1: blockparams v3=p0i, v4=stack0
+/// ```
+///
+/// If a spillslot layout was computed, a second header line lists the
+/// offset of each allocated slot and the total area size.
+impl std::fmt::Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "spillslots: {}", self.num_spillslots)?;
+        if !self.spillslot_offsets.is_empty() {
+            write!(f, "spillslot offsets:")?;
+            for (slot, &offset) in self.spillslot_offsets.iter().enumerate() {
+                if offset != u32::MAX {
+                    write!(f, " stack{}@{}", slot, offset)?;
+                }
+            }
+            writeln!(f, " (area size {})", self.spillslot_area_size)?;
+        }
+
+        let mut edits = self.edits.iter().peekable();
+        for inst in 0..self.inst_alloc_offsets.len() {
+            let inst = Inst::new(inst);
+            for pos in &[InstPosition::Before, InstPosition::After] {
+                let point = match pos {
+                    InstPosition::Before => ProgPoint::before(inst),
+                    InstPosition::After => ProgPoint::after(inst),
+                };
+                while let Some((_, edit)) = edits.next_if(|(p, _)| *p == point) {
+                    let pos = match pos {
+                        InstPosition::Before => "before",
+                        InstPosition::After => "after",
+                    };
+                    writeln!(f, "  {} inst{}: {}", pos, inst.index(), edit)?;
+                }
+                if *pos == InstPosition::Before {
+                    write!(f, "inst{}:", inst.index())?;
+                    for (i, alloc) in self.inst_allocs(inst).iter().enumerate() {
+                        let sep = if i == 0 { " " } else { ", " };
+                        write!(f, "{}{}", sep, alloc)?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// An error that prevents allocation.
@@ -757,24 +1977,2878 @@ impl Output {
 pub enum RegAllocError {
     /// Invalid SSA for given vreg at given inst: multiple defs or
     /// illegal use. `inst` may be `Inst::invalid()` if this concerns
-    /// a block param.
+    /// a block param. In a non-SSA function (see `Function::is_ssa`),
+    /// a vreg that must be defined once defined again, or a def or
+    /// `OperandKind::Mod` operand that its instruction cannot have.
     SSA(VReg, Inst),
     /// Invalid basic block: does not end in branch/ret, or contains a
-    /// branch/ret in the middle.
+    /// branch/ret in the middle. (`FuncBuilder::build` also reports a
+    /// block with a critical out-edge this way.)
     BB(Block),
-    /// Invalid branch: operand count does not match sum of block
-    /// params of successor blocks.
+    /// Invalid branch: use-operand count does not match sum of block
+    /// params of successor blocks, or a branch with a delay slot has
+    /// a def.
     Branch(Inst),
+    /// The `MachineEnv` provides no allocatable registers for the
+    /// given class, but the function mentions a vreg of that
+    /// class. The vreg and the instruction of its first mention are
+    /// given; `inst` may be `Inst::invalid()` if the vreg is a block
+    /// param.
+    NoRegistersInClass(RegClass, VReg, Inst),
+    /// The given vreg is mentioned at the given instruction with its
+    /// own class, but is defined with the given, different class.
+    ClassMismatch(VReg, RegClass, Inst),
+    /// The allocation produced was found to be incorrect by the
+    /// checker. Only returned if `RegallocOptions::validate_output`
+    /// is set.
+    #[cfg(feature = "checker")]
+    CheckerFailed(checker::CheckerErrors),
+    /// An edit had to be placed between the given instruction and
+    /// the next one, which `Function::no_edits_after` forbids, or
+    /// while the flags are live (see `Function::writes_flags`).
+    EditAtRestrictedPoint(Inst),
+    /// The given instruction reads the condition flags, but no
+    /// earlier instruction in its block writes them.
+    FlagsNotDefined(Inst),
+    /// The given vreg is used at the given instruction, which can be
+    /// reached from the entry block without passing a def of the
+    /// vreg.
+    UseBeforeDef(VReg, Inst),
+    /// The function needs the given number of spillslots, more than
+    /// `RegallocOptions::max_spillslots` allows even when packed as
+    /// tightly as the allocator can. The vregs whose spillslots lie
+    /// beyond the budget are given; there are none if the overflow is
+    /// due only to slots the allocator needs for itself (e.g. to save
+    /// callee-saved registers).
+    TooManySpillslots(usize, Vec<VReg>),
+    /// The function has the given number of instructions, more than
+    /// `ProgPoint::MAX_INSTS`. Enable the `large-functions` feature
+    /// to raise the limit.
+    TooManyInsts(usize),
+    /// The given instruction has an operand at
+    /// `OperandPos::Transfer` that is not an `OperandPolicy::Any` use
+    /// of a branch.
+    Transfer(Inst),
+    /// The given vreg, which `Function::no_spill` says must stay in a
+    /// register, could not have one at the given instruction: too
+    /// many such vregs, or registers clobbered or fixed to other
+    /// operands, there.
+    NoSpill(VReg, Inst),
+    /// The given reference-typed vreg (see `Function::is_reftype`) is
+    /// live across the given safepoint, which also uses it in a
+    /// register; it cannot be in its spillslot there at the same
+    /// time.
+    RefUseAtSafepoint(VReg, Inst),
+    /// The given vreg is used at the given instruction with
+    /// `OperandPolicy::Stack`, and also in a register: by another use,
+    /// or as the input a `Reuse` def takes the register of.
+    StackUseConflict(VReg, Inst),
+    /// The given instruction has an `OperandPolicy::FixedStack`
+    /// operand whose slot is not one of the function's reserved
+    /// spillslots, or that another operand needs for a different
+    /// value: a use of another vreg, or a second def, in the same
+    /// slot, or a `Reuse` def taking the operand as its input.
+    FixedStack(SpillSlot, Inst),
+    /// The given vreg, which `Function::pinned_vreg` pins to a
+    /// register, cannot be held there at the given instruction: an
+    /// operand of the vreg there does not accept the register, or
+    /// the register is clobbered or fixed to another operand there
+    /// while the vreg is live. `inst` is `Inst::invalid()` if the
+    /// register is a scratch register, or the vreg holds a reference.
+    Pinned(VReg, Inst),
+    /// `RegallocOptions::save_callee_saves` is set, but the given
+    /// instruction has an operand fixed to the given callee-saved
+    /// register, which is moved back from its save slot before the
+    /// instruction: it is a return, or edits are forbidden between it
+    /// and the return that follows.
+    CalleeSavedFixedReg(PReg, Inst),
+    /// The given vreg is derived from a base (see
+    /// `Function::derived_ref_base`), but it or its base is not
+    /// reference-typed, or the base is itself derived or is a
+    /// constant.
+    DerivedRef(VReg),
+    /// The backtracking allocator processed the given number of
+    /// bundles without converging, even after it began to spill every
+    /// bundle between its uses; this is a bug in the allocator.
+    IterationLimit(usize),
+    /// The allocator panicked, with the given message. Only reported
+    /// by `run_with_fallback()`, which catches the panic and retries
+    /// with the fallback options (and reports a panic in the retry
+    /// too); this is a bug in the allocator.
+    Panicked(String),
 }
 
 impl std::fmt::Display for RegAllocError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::SSA(vreg, inst) if inst.is_invalid() => write!(
+                f,
+                "invalid SSA: block param {} is defined more than once",
+                vreg
+            ),
+            Self::SSA(vreg, inst) => write!(
+                f,
+                "invalid SSA at inst{}: {} is defined more than once, or used where its \
+                 definition does not dominate",
+                inst.index(),
+                vreg
+            ),
+            Self::BB(block) => write!(
+                f,
+                "invalid block{}: it must end, and only end, in a branch or return, the \
+                 entry block may not have block params, and critical edges must be split",
+                block.index()
+            ),
+            Self::Branch(inst) => write!(
+                f,
+                "invalid branch inst{}: its use count must equal the total number of \
+                 block params of its successors, and it may not have a def if it has a \
+                 delay slot",
+                inst.index()
+            ),
+            Self::NoRegistersInClass(class, vreg, inst) => {
+                write!(
+                    f,
+                    "no allocatable registers in class {:?}, but {} has that class",
+                    class, vreg
+                )?;
+                if inst.is_valid() {
+                    write!(f, " (first mentioned at inst{})", inst.index())?;
+                } else {
+                    write!(f, " (a block param)")?;
+                }
+                Ok(())
+            }
+            Self::ClassMismatch(vreg, class, inst) => write!(
+                f,
+                "{} has class {:?} at inst{}, but is defined with class {:?}",
+                vreg,
+                vreg.class(),
+                inst.index(),
+                class
+            ),
+            #[cfg(feature = "checker")]
+            Self::CheckerFailed(errors) => {
+                write!(f, "checker found an incorrect allocation: {}", errors)
+            }
+            Self::EditAtRestrictedPoint(inst) => write!(
+                f,
+                "a move is needed after inst{}, but `Function::no_edits_after` or live \
+                 condition flags forbid edits there",
+                inst.index()
+            ),
+            Self::FlagsNotDefined(inst) => write!(
+                f,
+                "inst{} reads the condition flags, but no earlier inst in its block \
+                 writes them",
+                inst.index()
+            ),
+            Self::UseBeforeDef(vreg, inst) => write!(
+                f,
+                "{} is used at inst{}, which is reachable from the entry block without \
+                 passing a def of it",
+                vreg,
+                inst.index()
+            ),
+            Self::TooManySpillslots(needed, vregs) => {
+                write!(
+                    f,
+                    "the function needs {} spillslots, more than `max_spillslots` allows",
+                    needed
+                )?;
+                if !vregs.is_empty() {
+                    write!(f, "; vregs spilled beyond the budget:")?;
+                    for vreg in vregs {
+                        write!(f, " {}", vreg)?;
+                    }
+                }
+                Ok(())
+            }
+            Self::TooManyInsts(insts) => write!(
+                f,
+                "the function has {} instructions, more than the limit of {}",
+                insts,
+                ProgPoint::MAX_INSTS
+            ),
+            Self::Transfer(inst) => write!(
+                f,
+                "invalid operand at inst{}: only the uses of a branch may be read at the \
+                 control transfer, and only with policy Any",
+                inst.index()
+            ),
+            Self::NoSpill(vreg, inst) => write!(
+                f,
+                "{} must stay in a register, but no register is left for it at inst{}",
+                vreg,
+                inst.index()
+            ),
+            Self::RefUseAtSafepoint(vreg, inst) => write!(
+                f,
+                "reference {} is live across safepoint inst{}, which must not also use it \
+                 in a register",
+                vreg,
+                inst.index()
+            ),
+            Self::StackUseConflict(vreg, inst) => write!(
+                f,
+                "{} is used at inst{} from its spillslot, but also in a register",
+                vreg,
+                inst.index()
+            ),
+            Self::FixedStack(slot, inst) => write!(
+                f,
+                "inst{} needs {} as a fixed stack slot, but it is not reserved, or is also \
+                 needed there for another value",
+                inst.index(),
+                slot
+            ),
+            Self::Pinned(vreg, inst) if inst.is_invalid() => write!(
+                f,
+                "{} cannot be pinned: its register is a scratch register, or it holds a \
+                 reference",
+                vreg
+            ),
+            Self::Pinned(vreg, inst) => write!(
+                f,
+                "{} is pinned to a register, which inst{} does not let it stay in",
+                vreg,
+                inst.index()
+            ),
+            Self::CalleeSavedFixedReg(preg, inst) => write!(
+                f,
+                "inst{} has an operand fixed to callee-saved {}, which \
+                 `save_callee_saves` restores before it",
+                inst.index(),
+                preg
+            ),
+            Self::DerivedRef(vreg) => write!(
+                f,
+                "{} is declared derived, but it or its base is not a reference, or its \
+                 base is derived or a constant",
+                vreg
+            ),
+            Self::IterationLimit(count) => write!(
+                f,
+                "allocation did not converge after processing {} bundles",
+                count
+            ),
+            Self::Panicked(message) => write!(f, "the allocator panicked: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RegAllocError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "checker")]
+            Self::CheckerFailed(errors) => Some(errors),
+            _ => None,
+        }
     }
 }
 
-impl std::error::Error for RegAllocError {}
+/// Options for the register allocator. Every option defaults to the
+/// allocator's standard behavior, so embedders should construct this
+/// with `..RegallocOptions::default()` to remain compatible as new
+/// options are added.
+#[derive(Clone, Debug, Default)]
+pub struct RegallocOptions {
+    /// Run the SSA validator over the input function before
+    /// allocating, returning `RegAllocError::SSA` (or another error)
+    /// if it is malformed. This is cheap relative to allocation but
+    /// not free, so it is off by default.
+    pub validate_ssa: bool,
+    /// If set, functions with more instructions than this are
+    /// partitioned into regions of consecutive blocks of about this
+    /// many instructions, which are allocated independently and
+    /// stitched together with moves at their boundaries. This bounds
+    /// allocation time on enormous (e.g. machine-generated)
+    /// functions, at some cost in allocation quality around region
+    /// boundaries.
+    pub region_size: Option<usize>,
+    /// Save every callee-saved register (per
+    /// `MachineEnv::callee_saved_regs`) that the allocation uses: emit
+    /// a move to a dedicated spillslot at the start of the entry block
+    /// and a move back before every return instruction, or, if edits
+    /// are forbidden before a return (see `Function::no_edits_after`
+    /// and `Function::writes_flags`), before the first instruction of
+    /// the run that joins it. No value is kept in a callee-saved
+    /// register from there through the return; an operand fixed to
+    /// one there fails with `RegAllocError::CalleeSavedFixedReg`. The
+    /// saved registers are reported in `Output::callee_saves`.
+    pub save_callee_saves: bool,
+    /// Allow the allocator to use `MachineEnv::frame_pointer` as an
+    /// ordinary register, e.g. for a leaf function that does not set
+    /// up a frame pointer. Otherwise it is reserved.
+    pub allocate_frame_pointer: bool,
+    /// Run the checker over the allocator's output before returning
+    /// it, returning `RegAllocError::CheckerFailed` if it finds any
+    /// error. The checker is also given the final live ranges, to
+    /// verify that no two vregs share a register or spillslot at
+    /// once. This is expensive, and meant for testing and CI rather
+    /// than production use. Requires the `checker` feature.
+    #[cfg(feature = "checker")]
+    pub validate_output: bool,
+    /// Report the moves placed on each CFG edge in
+    /// `Output::edge_moves`, e.g. to guide block layout.
+    pub report_edge_moves: bool,
+    /// Generate `Edit::BlockParams` edits, which record where each
+    /// block's params live on entry. They are not machine code, but
+    /// the checker needs them; leave this unset to save building
+    /// them when the output will not be checked. Implied by
+    /// `validate_output`.
+    pub report_blockparams: bool,
+    /// The maximum number of spillslots (as counted by
+    /// `Output::num_spillslots`, so including reserved slots) that
+    /// the function may use, e.g. for a target with a small stack.
+    /// If the allocator's usual spillslot assignment exceeds it, the
+    /// assignment is redone, packing values into slots harder; if
+    /// that still exceeds it, allocation fails with
+    /// `RegAllocError::TooManySpillslots`.
+    pub max_spillslots: Option<usize>,
+    /// Once all spilled ranges are known, merge spillslots whose
+    /// occupants never overlap. The usual assignment tries only a few
+    /// existing slots for each spilled value, so this can noticeably
+    /// shrink the frame of a large function, at some cost in
+    /// allocation time.
+    pub shrink_frame: bool,
+    /// Break the allocator's ties at random, from this seed: the
+    /// order in which registers are probed, the kind of split taken
+    /// when several apply, and the choice between equally cheap sets
+    /// of bundles to evict. Every choice is still a valid one, so
+    /// together with the checker this exercises decision paths that
+    /// the usual deterministic heuristics never reach. Meant for
+    /// fuzzing; allocation quality suffers.
+    pub chaos_seed: Option<u64>,
+    /// Record why each vreg was evicted, split or spilled, in
+    /// `Output::explanations`.
+    pub explain: bool,
+    /// If set, write a text snapshot of the function and of the
+    /// allocator's internal state after liveness analysis, bundle
+    /// merging and allocation to `liveness.txt`, `merge.txt` and
+    /// `allocate.txt` in this directory, creating it if necessary.
+    /// Later runs overwrite the files, so when allocating many
+    /// functions, give each its own directory.
+    pub snapshot_dir: Option<std::path::PathBuf>,
+    /// How many times to evict the bundles holding a register before
+    /// giving up and splitting a bundle that needs one; two if unset.
+    /// More rounds find more assignments that avoid a split, at some
+    /// cost in allocation time.
+    pub eviction_attempts: Option<usize>,
+    /// When a bundle must be split, work out every applicable kind of
+    /// split (see `SplitReason`) and take the one with the lowest
+    /// modeled cost, rather than the first applicable one in a fixed
+    /// order. The model charges a move at each split point, weighted
+    /// by an estimated execution frequency of 8^(loop depth). Ignored
+    /// if `chaos_seed` is set.
+    pub compare_splits: bool,
+    /// When a bundle fits in more than one register without evicting
+    /// anything, score each and take the best, rather than the first
+    /// one probed. A register scores better for matching the bundle's
+    /// hint, for not being a callee-saved register that no other
+    /// bundle uses yet (which would cost a save and restore), for a
+    /// lower `MachineEnv::reg_costs` entry, and for staying free
+    /// longer after the bundle ends, in that order of significance.
+    /// Ignored if `chaos_seed` is set.
+    pub score_free_regs: bool,
+    /// Before the main allocation loop, find the stretches of code
+    /// where more vregs of a class are live than there are registers
+    /// of that class, and spill the cheapest values that are live
+    /// across each one without being used in it. This saves the
+    /// repeated eviction and splitting that would otherwise arrive at
+    /// much the same result in functions under very high register
+    /// pressure.
+    pub pre_spill: bool,
+    /// Give every spilled vreg a spillslot of its own, which holds it
+    /// wherever it is spilled, rather than sharing slots among vregs
+    /// whose spilled ranges never overlap. This takes more stack space
+    /// but assigns slots in one quick pass, and gives each vreg a
+    /// single stack location, which can make allocated code easier to
+    /// debug. `shrink_frame` has no effect, and a `max_spillslots`
+    /// budget is checked without repacking.
+    pub spillslot_per_vreg: bool,
+    /// Report where each vreg live across a safepoint is during it,
+    /// in `Output::safepoint_locations`.
+    pub report_safepoint_locations: bool,
+    /// The allocation algorithm to run; see `AllocatorAlgorithm`.
+    pub algorithm: AllocatorAlgorithm,
+}
+
+/// Which allocator `run_with_options()` runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AllocatorAlgorithm {
+    /// The backtracking allocator (`ion`), which splits, evicts and
+    /// merges live ranges to keep values in registers.
+    #[default]
+    Backtracking,
+    /// The single-pass allocator (`fastalloc`), which keeps every
+    /// value in its spillslot between instructions, for baseline or
+    /// debug compiles that need allocation to be quick more than
+    /// they need quick code. Options that tune the backtracking
+    /// allocator's heuristics have no effect. Functions it cannot
+    /// allocate are handed to the backtracking allocator, as recorded
+    /// in `Fingerprint::algorithm`.
+    Fast,
+}
+
+impl RegallocOptions {
+    /// Options for ahead-of-time compilers, which can afford several
+    /// times the usual allocation time for better code: more rounds
+    /// of eviction before splitting, the cheapest of all applicable
+    /// splits, scored choices among free registers, and merging of
+    /// spillslots.
+    pub fn high_effort() -> Self {
+        RegallocOptions {
+            eviction_attempts: Some(8),
+            compare_splits: true,
+            score_free_regs: true,
+            shrink_frame: true,
+            ..RegallocOptions::default()
+        }
+    }
+
+    /// Options for the smallest code, at whatever cost in allocation
+    /// time: the fewest moves, then a frame shrunk by merging
+    /// spillslots. These are `high_effort()` with still more rounds of
+    /// eviction before splitting. Splits are still costed by estimated
+    /// execution frequency, so moves out of loops are preferred over
+    /// fewer moves overall.
+    pub fn optimize_for_size() -> Self {
+        RegallocOptions {
+            region_size: None,
+            eviction_attempts: Some(16),
+            compare_splits: true,
+            score_free_regs: true,
+            shrink_frame: true,
+            pre_spill: false,
+            spillslot_per_vreg: false,
+            ..RegallocOptions::default()
+        }
+    }
+
+    /// Options for JIT compilers and other embedders to whom
+    /// allocation time matters more than code quality: a single round
+    /// of eviction before splitting, values spilled up front where
+    /// pressure is high, a spillslot of its own for every spilled
+    /// vreg, and enormous functions allocated in regions of about
+    /// 10000 instructions.
+    pub fn optimize_for_compile_speed() -> Self {
+        RegallocOptions {
+            region_size: Some(10_000),
+            eviction_attempts: Some(1),
+            compare_splits: false,
+            score_free_regs: false,
+            shrink_frame: false,
+            pre_spill: true,
+            spillslot_per_vreg: true,
+            ..RegallocOptions::default()
+        }
+    }
+
+    /// The options named `name`, e.g. from a command-line flag:
+    /// `"default"`, `"high-effort"`, `"optimize-for-size"` or
+    /// `"optimize-for-compile-speed"`.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(RegallocOptions::default()),
+            "high-effort" => Some(RegallocOptions::high_effort()),
+            "optimize-for-size" => Some(RegallocOptions::optimize_for_size()),
+            "optimize-for-compile-speed" => Some(RegallocOptions::optimize_for_compile_speed()),
+            _ => None,
+        }
+    }
+
+    /// These options with the fast allocator selected, and every
+    /// optional heuristic of the backtracking allocator (which the
+    /// fast one hands the functions it cannot allocate to) turned off:
+    /// the configuration `run_with_fallback()` retries with. Spilled
+    /// vregs share spillslots, packed as tightly as need be to meet
+    /// `max_spillslots`, which is kept, as are options that only
+    /// report more results, or that change what the embedder must
+    /// emit (`save_callee_saves`, `allocate_frame_pointer`).
+    pub fn fallback(&self) -> Self {
+        RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            region_size: None,
+            chaos_seed: None,
+            snapshot_dir: None,
+            eviction_attempts: None,
+            compare_splits: false,
+            score_free_regs: false,
+            pre_spill: false,
+            spillslot_per_vreg: false,
+            ..self.clone()
+        }
+    }
+
+    /// Identify the allocator configuration these options select, as
+    /// recorded in `Output::fingerprint`: e.g. to find, when
+    /// investigating a miscompile, which version and options produced
+    /// the code, and to check that a replay uses the same ones.
+    pub fn fingerprint(&self) -> Fingerprint {
+        // The debug rendering covers every option, including any
+        // added later; the hash only needs to be stable for a given
+        // version, which the fingerprint records alongside it.
+        let options = RegallocOptions {
+            snapshot_dir: None,
+            ..self.clone()
+        };
+        let options_hash = format!("{:?}", options)
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        Fingerprint {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            options_hash,
+            chaos_seed: self.chaos_seed,
+            algorithm: self.algorithm,
+        }
+    }
+
+    /// Whether the output is to be checked before it is returned.
+    pub(crate) fn validates_output(&self) -> bool {
+        #[cfg(feature = "checker")]
+        return self.validate_output;
+        #[cfg(not(feature = "checker"))]
+        return false;
+    }
+}
 
+/// The allocator configuration that produced an `Output`; see
+/// `RegallocOptions::fingerprint()`. Two runs over the same function
+/// and `MachineEnv` with equal fingerprints produce the same output.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fingerprint {
+    /// The version of this crate.
+    pub version: String,
+    /// A hash (FNV-1a) of the options, other than `snapshot_dir`,
+    /// which does not affect the results. It is stable across runs
+    /// and hosts, but not across versions.
+    pub options_hash: u64,
+    /// `RegallocOptions::chaos_seed`, the seed of the only
+    /// nondeterminism in the allocator's heuristics.
+    pub chaos_seed: Option<u64>,
+    /// The allocator that produced the output. This is `Backtracking`
+    /// when `AllocatorAlgorithm::Fast` was requested but the function
+    /// was one the fast allocator hands over.
+    pub algorithm: AllocatorAlgorithm,
+}
+
+/// Run the allocator with default options.
 pub fn run<F: Function>(func: &F, env: &MachineEnv) -> Result<Output, RegAllocError> {
-    ion::run(func, env)
+    run_with_options(func, env, &RegallocOptions::default())
+}
+
+/// Run the allocator with the given options.
+pub fn run_with_options<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<Output, RegAllocError> {
+    match options.algorithm {
+        AllocatorAlgorithm::Backtracking => ion::run_with_options(func, env, options),
+        AllocatorAlgorithm::Fast => fastalloc::run(func, env, options),
+    }
+}
+
+/// Run the allocator with the given options and, if it fails for a
+/// reason that lies with the allocation rather than with the function
+/// (a checker failure, an edit at a restricted point, too many
+/// spillslots, or a panic), retry once with `options.fallback()`.
+/// Returns the output, together with the error that forced the retry,
+/// if any. A malformed function, or a failure of the retry, is still
+/// an error.
+pub fn run_with_fallback<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<(Output, Option<RegAllocError>), RegAllocError> {
+    match run_catching_panics(func, env, options) {
+        Ok(output) => Ok((output, None)),
+        Err(err) if retries_after(&err) => {
+            log::info!(
+                "allocation failed ({}); retrying with fallback options",
+                err
+            );
+            let output = run_catching_panics(func, env, &options.fallback())?;
+            Ok((output, Some(err)))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `run_with_fallback()` retries after `err`.
+fn retries_after(err: &RegAllocError) -> bool {
+    match err {
+        #[cfg(feature = "checker")]
+        RegAllocError::CheckerFailed(_) => true,
+        RegAllocError::EditAtRestrictedPoint(_)
+        | RegAllocError::TooManySpillslots(..)
+        | RegAllocError::IterationLimit(_)
+        | RegAllocError::Panicked(_) => true,
+        _ => false,
+    }
+}
+
+/// Run the allocator, reporting a panic as `RegAllocError::Panicked`.
+fn run_catching_panics<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<Output, RegAllocError> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        run_with_options(func, env, options)
+    }))
+    .unwrap_or_else(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "(no message)".to_string(),
+            },
+        };
+        Err(RegAllocError::Panicked(message))
+    })
+}
+
+/// Compute the register pressure in each block of `func`, indexed by
+/// block, without allocating: e.g. to guide lowering choices before
+/// allocation. This validates the function as `run()` does.
+pub fn register_pressure<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+) -> Result<Vec<BlockPressure>, RegAllocError> {
+    let options = RegallocOptions::default();
+    Ok(ion::Liveness::compute(func, env, &options)?.register_pressure())
+}
+
+/// Run the allocator with the given options, taking its working
+/// storage from `arena` and returning it there afterward. Reusing one
+/// arena across many functions avoids reallocating that storage for
+/// each. The fast allocator keeps no such storage, and leaves the
+/// arena alone.
+pub fn run_with_arena<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    arena: &mut ion::Arena,
+) -> Result<Output, RegAllocError> {
+    match options.algorithm {
+        AllocatorAlgorithm::Backtracking => ion::run_with_arena(func, env, options, arena),
+        AllocatorAlgorithm::Fast => fastalloc::run(func, env, options),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "checker")]
+    use crate::checker::Checker;
+    use crate::fuzzing::func::{machine_env, Func, FuncBuilder, InstData, InstOpcode, Options};
+    use crate::liveness::LivenessInfo;
+    use arbitrary::Unstructured;
+
+    /// Options under which the output can be checked: the checker
+    /// needs `Edit::BlockParams`.
+    fn checked() -> RegallocOptions {
+        RegallocOptions {
+            report_blockparams: true,
+            ..RegallocOptions::default()
+        }
+    }
+
+    /// Run the allocator with `checked()` options.
+    fn run_checked<F: Function>(func: &F, env: &MachineEnv) -> Result<Output, RegAllocError> {
+        run_with_options(func, env, &checked())
+    }
+
+    /// Generate up to `count` random functions from a fixed seed.
+    pub(crate) fn test_funcs(opts: &Options, count: usize) -> Vec<Func> {
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        let mut funcs = vec![];
+        for _ in 0..count {
+            let bytes: Vec<u8> = (0..10000)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect();
+            if let Ok(func) = Func::arbitrary_with_options(&mut Unstructured::new(&bytes), opts) {
+                funcs.push(func);
+            }
+        }
+        funcs
+    }
+
+    #[test]
+    fn test_preg_index_roundtrip() {
+        for class in [RegClass::Int, RegClass::Float].iter().cloned() {
+            for hw in 0..=PReg::MAX {
+                let preg = PReg::new(hw, class);
+                assert!(preg.index() < PReg::NUM_INDEX);
+                assert_eq!(PReg::from_index(preg.index()), preg);
+                assert_eq!(Allocation::reg(preg).as_reg(), Some(preg));
+                let op = Operand::reg_fixed_use(VReg::new(VReg::MAX, class), preg);
+                assert_eq!(op.policy(), OperandPolicy::FixedReg(preg));
+                assert_eq!(op.vreg(), VReg::new(VReg::MAX, class));
+                assert_eq!(op.kind(), OperandKind::Use);
+            }
+        }
+    }
+
+    #[test]
+    fn test_preg_hw_enc() {
+        let preg = PReg::new_with_hw_enc(3, 0x1234, RegClass::Float);
+        assert_eq!(preg.num(), 3);
+        assert_eq!(preg.hw_enc(), 0x1234);
+        assert_eq!(preg, PReg::new(3, RegClass::Float));
+
+        let alloc = Allocation::reg(preg).as_reg().unwrap();
+        assert_eq!(alloc.index(), preg.index());
+        assert_eq!(alloc.hw_enc(), 0x1234);
+
+        let op = Operand::reg_fixed_def(VReg::new(0, RegClass::Float), preg);
+        match op.policy() {
+            OperandPolicy::FixedReg(fixed) => {
+                assert_eq!(fixed.num(), 3);
+                assert_eq!(fixed.hw_enc(), 0x1234);
+            }
+            _ => panic!("expected fixed-reg policy"),
+        }
+    }
+
+    #[test]
+    fn test_output_text() {
+        let p0 = Allocation::reg(PReg::new(0, RegClass::Int));
+        let s0 = Allocation::stack(SpillSlot::new(0, RegClass::Int));
+        let out = Output {
+            num_spillslots: 1,
+            edits: vec![
+                (
+                    ProgPoint::before(Inst::new(1)),
+                    Edit::Move { from: p0, to: s0 },
+                ),
+                (
+                    ProgPoint::after(Inst::new(1)),
+                    Edit::BlockParams {
+                        vregs: vec![VReg::new(3, RegClass::Int), VReg::new(4, RegClass::Int)],
+                        allocs: vec![p0, s0],
+                    },
+                ),
+            ],
+            allocs: vec![p0, p0, s0],
+            inst_alloc_offsets: vec![0, 1, 3],
+            spillslot_offsets: vec![],
+            block_edits: vec![],
+            used_regs: vec![],
+            edit_clobbered_regs: vec![],
+            explanations: vec![],
+            safepoint_locations: vec![],
+            preserved_soft_clobbers: vec![],
+            safepoint_slots: vec![],
+            safepoint_derived_slots: vec![],
+            debug_locations: vec![],
+            fingerprint: Fingerprint::default(),
+            spillslot_area_size: 0,
+            callee_saves: vec![],
+            elided_moves: bitvec::BitVec::new(),
+            edge_moves: vec![],
+            stats: ion::Stats::default(),
+        };
+        assert_eq!(
+            out.to_string(),
+            "spillslots: 1\n\
+             inst0: p0i\n\
+             \x20 before inst1: move p0i -> stack0\n\
+             inst1: p0i, stack0\n\
+             \x20 after inst1: blockparams v3=p0i, v4=stack0\n\
+             inst2:\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_allocate_linear() {
+        let env = machine_env();
+        let options = checked();
+        // A diamond and a loop, with plain register operands or with
+        // fixed ones, which need the full allocator. (`run()` takes
+        // the single pass for every such small CFG in
+        // `test_small_cfgs`.)
+        for succs in &[
+            vec![vec![1, 2], vec![3], vec![3], vec![]],
+            vec![vec![1], vec![1, 2], vec![]],
+        ] {
+            let succs = split_critical_edges(succs.clone());
+            for &params in &[false, true] {
+                let func = small_cfg_func(&succs, params, 0);
+                let liveness =
+                    ion::Liveness::compute(&func, &env, &options).expect("liveness failed");
+                assert!(liveness.can_allocate_linear());
+                let out = liveness.allocate_linear().reify();
+                assert_eq!(out.num_spillslots, 0);
+                let mut checker = Checker::new(&func);
+                checker.prepare(&out);
+                checker.run().expect("checker failed");
+
+                let func = small_cfg_func(&succs, params, 1);
+                let liveness =
+                    ion::Liveness::compute(&func, &env, &options).expect("liveness failed");
+                assert!(!liveness.can_allocate_linear());
+            }
+        }
+
+        // A copy shares the register of its source, which dies there.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        let v1 = builder.vreg();
+        builder.inst(block, &[Operand::reg_def(v0)], &[]);
+        builder.add_inst(
+            block,
+            InstData::new(
+                InstOpcode::Move,
+                vec![Operand::reg_def(v1), Operand::reg_use(v0)],
+                vec![],
+            ),
+        );
+        builder.inst(block, &[Operand::reg_use(v1)], &[]).ret(block);
+        let func = builder.build().expect("invalid function");
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        assert_eq!(out.inst_allocs(Inst::new(0)), out.inst_allocs(Inst::new(2)));
+        assert!(out.is_elided_move(Inst::new(1)));
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_high_effort_split_past_end() {
+        // With every generator feature on, this function has a bundle
+        // whose split points, costed for `compare_splits`, include the
+        // point past its last instruction.
+        let env = machine_env();
+        let opts = Options {
+            reused_inputs: true,
+            fixed_regs: true,
+            clobbers: true,
+            control_flow: true,
+            reducible: true,
+            block_params: true,
+            always_local_uses: true,
+            reserved_spillslots: true,
+            constants: true,
+            moves: true,
+            excluded_regs: true,
+            pref_regs: true,
+            edit_restrictions: true,
+            delay_slots: true,
+            conditional_defs: true,
+            equivalent_vregs: true,
+            flags: true,
+            multi_fixed_regs: true,
+            late_positions: true,
+            branch_defs: true,
+            transfer_args: true,
+            spill_weights: true,
+            cold_blocks: true,
+            soft_clobbers: true,
+            spill_preferences: true,
+            no_spill: true,
+            reftypes: true,
+            stack_operands: true,
+            fixed_stack_operands: true,
+            remat: true,
+            pinned: true,
+            non_ssa: true,
+            debug_labels: true,
+            ..Options::default()
+        };
+        let mut seed: u64 = 205;
+        let bytes: Vec<u8> = (0..100000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        let func = Func::arbitrary_with_options(&mut Unstructured::new(&bytes), &opts).unwrap();
+        let options = RegallocOptions {
+            report_blockparams: true,
+            ..RegallocOptions::high_effort()
+        };
+        // Too many vregs kept in registers by `no_spill` may fail
+        // allocation, but must not panic.
+        match run_with_options(&func, &env, &options) {
+            Ok(out) => {
+                let mut checker = Checker::new(&func);
+                checker.prepare(&out);
+                checker.run().expect("checker failed");
+            }
+            Err(e) => assert!(matches!(e, RegAllocError::NoSpill(..)), "{}", e),
+        }
+    }
+
+    #[test]
+    fn test_presets() {
+        // Each preset is found by its name, and they differ where
+        // they say they do: two values spilled one after the other
+        // share a slot, except when compiling for speed, which gives
+        // each its own.
+        for (name, options) in [
+            ("default", RegallocOptions::default()),
+            ("high-effort", RegallocOptions::high_effort()),
+            ("optimize-for-size", RegallocOptions::optimize_for_size()),
+            (
+                "optimize-for-compile-speed",
+                RegallocOptions::optimize_for_compile_speed(),
+            ),
+        ] {
+            let preset = RegallocOptions::preset(name).expect("no such preset");
+            assert_eq!(format!("{:?}", preset), format!("{:?}", options));
+        }
+        assert!(RegallocOptions::preset("fast").is_none());
+
+        let clobbers: Vec<PReg> = (0..31).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        for _ in 0..2 {
+            let vreg = builder.vreg();
+            builder
+                .inst(block, &[Operand::reg_def(vreg)], &[])
+                .inst(block, &[], &clobbers)
+                .inst(block, &[Operand::reg_use(vreg)], &[]);
+        }
+        builder.ret(block);
+        let func = builder.build().unwrap();
+        let env = machine_env();
+        for (name, slots) in [
+            ("default", 1),
+            ("high-effort", 1),
+            ("optimize-for-size", 1),
+            ("optimize-for-compile-speed", 2),
+        ] {
+            let options = RegallocOptions::preset(name).unwrap();
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            assert_eq!(out.num_spillslots, slots, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_debug_locations() {
+        // A value live across a call that clobbers every register,
+        // labelled from the call to the end of the block, and again
+        // after its last use.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder.inst(block, &[Operand::reg_def(v0)], &[]);
+        let clobbers = env.regs.clone();
+        builder.add_inst(block, InstData::new(InstOpcode::Call, vec![], clobbers));
+        builder.inst(block, &[Operand::reg_use(v0)], &[]);
+        builder.ret(block);
+        builder.label(v0, Inst::new(1), Inst::new(4), 7);
+        builder.label(v0, Inst::new(3), Inst::new(4), 8);
+        let func = builder.build().unwrap();
+        let fast = RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            ..checked()
+        };
+        for options in [checked(), fast] {
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            let locations = &out.debug_locations;
+            assert!(!locations.is_empty());
+            assert!(locations.iter().all(|&(label, ..)| label == 7));
+            assert_eq!(locations[0].1, ProgPoint::before(Inst::new(1)));
+            for pair in locations.windows(2) {
+                assert_eq!(pair[0].2, pair[1].1);
+                assert_ne!(pair[0].3, pair[1].3);
+            }
+            let at = |point: ProgPoint| {
+                locations
+                    .iter()
+                    .find(|&&(_, from, to, _)| from <= point && point < to)
+                    .map(|&(.., alloc)| alloc)
+            };
+            // In a slot across the call, and where the use reads it
+            // just after.
+            assert!(at(ProgPoint::after(Inst::new(1)))
+                .unwrap()
+                .as_stack()
+                .is_some());
+            let at_use = at(ProgPoint::before(Inst::new(2))).unwrap();
+            if options.algorithm == AllocatorAlgorithm::Fast {
+                assert_eq!(locations.len(), 1);
+            } else {
+                assert_eq!(at_use, out.inst_allocs(Inst::new(2))[0]);
+            }
+            assert_eq!(at(ProgPoint::before(Inst::new(3))), None);
+        }
+    }
+
+    #[cfg(feature = "enable-serde")]
+    #[test]
+    fn test_serde() {
+        // An output with spills across a call, moves on the edges
+        // into a blockparam, and explanations round-trips.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let (entry, left, right, join) = (
+            builder.block(),
+            builder.block(),
+            builder.block(),
+            builder.block(),
+        );
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder.params(join, &[v2]);
+        builder.inst(entry, &[Operand::reg_def(v0)], &[]);
+        builder.inst(entry, &[Operand::reg_def(v1)], &[]);
+        let clobbers = env.regs.clone();
+        builder.add_inst(entry, InstData::new(InstOpcode::Call, vec![], clobbers));
+        builder
+            .branch(entry, &[left, right], &[])
+            .branch(left, &[join], &[v0])
+            .branch(right, &[join], &[v1])
+            .inst(
+                join,
+                &[
+                    Operand::reg_use(v0),
+                    Operand::reg_use(v1),
+                    Operand::reg_use(v2),
+                ],
+                &[],
+            )
+            .ret(join);
+        let func = builder.build().unwrap();
+        let options = RegallocOptions {
+            explain: true,
+            report_edge_moves: true,
+            ..checked()
+        };
+        let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        assert!(out.num_spillslots > 0);
+        assert!(!out.edge_moves.is_empty());
+        assert!(!out.explanations.is_empty());
+        let json = serde_json::to_string(&out).unwrap();
+        let back: Output = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_string(), out.to_string());
+        assert_eq!(format!("{:?}", back), format!("{:?}", out));
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_call_conv() {
+        let mut env = machine_env();
+        let int = |i| PReg::new(i, RegClass::Int);
+        env.call_convs.push(CallConv {
+            name: "test".to_string(),
+            clobbers: (0..24).map(int).collect(),
+            arg_regs_by_class: vec![vec![int(24), int(25)], vec![]],
+            ret_regs_by_class: vec![vec![int(24)], vec![]],
+        });
+        let conv = &env.call_convs[0];
+        assert_eq!(conv.arg_reg(RegClass::Int, 1), Some(int(25)));
+        assert_eq!(conv.arg_reg(RegClass::Int, 2), None);
+        assert_eq!(conv.ret_reg(RegClass::Float, 0), None);
+
+        // The same function, with the call's clobbers taken from the
+        // convention or listed out.
+        let build = |explicit: bool| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let vregs: Vec<VReg> = (0..6).map(|_| builder.vreg()).collect();
+            for &vreg in &vregs[..5] {
+                builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+            }
+            let call = [
+                Operand::reg_fixed_def(vregs[5], conv.ret_reg(RegClass::Int, 0).unwrap()),
+                Operand::reg_fixed_use(vregs[0], conv.arg_reg(RegClass::Int, 0).unwrap()),
+            ];
+            if explicit {
+                let data = InstData::new(InstOpcode::Call, call.to_vec(), conv.clobbers.clone());
+                builder.add_inst(block, data);
+            } else {
+                builder.call(block, &call, 0);
+            }
+            let uses: Vec<Operand> = vregs[1..].iter().map(|&v| Operand::reg_use(v)).collect();
+            builder.inst(block, &uses, &[]).ret(block);
+            builder.build().unwrap()
+        };
+        let options = RegallocOptions {
+            report_safepoint_locations: true,
+            ..checked()
+        };
+        let func = build(false);
+        let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+        let explicit = run_with_options(&build(true), &env, &options).unwrap();
+        assert_eq!(out.allocs, explicit.allocs);
+        assert_eq!(out.edits, explicit.edits);
+
+        // Nothing live across the call is left in a clobbered register.
+        assert_eq!(out.safepoint_locations.len(), 4);
+        for &(_, _, alloc) in &out.safepoint_locations {
+            assert!(!matches!(alloc.as_reg(), Some(preg) if conv.clobbers.contains(&preg)));
+        }
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_reg_costs() {
+        // The registers the allocator would otherwise prefer, the
+        // first eight, are costly.
+        let mut env = machine_env();
+        env.reg_costs = (0..8).map(|i| (PReg::new(i, RegClass::Int), 1)).collect();
+        assert_eq!(env.reg_cost(PReg::new(3, RegClass::Int)), 1);
+        assert_eq!(env.reg_cost(PReg::new(30, RegClass::Int)), 0);
+
+        // `live` vregs live at once, with one fixed use (so the function
+        // is not allocated linearly) if `fixed`.
+        let build = |live: usize, fixed: bool| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let vregs: Vec<VReg> = (0..live).map(|_| builder.vreg()).collect();
+            for &vreg in &vregs {
+                builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+            }
+            let mut uses: Vec<Operand> = vregs.iter().map(|&v| Operand::reg_use(v)).collect();
+            if fixed {
+                uses[0] = Operand::reg_fixed_use(vregs[0], PReg::new(8, RegClass::Int));
+            }
+            builder.inst(block, &uses, &[]).ret(block);
+            builder.build().unwrap()
+        };
+        let regs_used = |func: &Func| {
+            let out = run_checked(func, &env).expect("regalloc did not succeed");
+            let mut checker = Checker::new(func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+            assert_eq!(out.num_spillslots, 0);
+            out.allocs
+                .iter()
+                .map(|alloc| alloc.as_reg().unwrap().hw_enc())
+                .collect::<Vec<_>>()
+        };
+        for fixed in [false, true] {
+            // With enough cheap registers, no costly one is used...
+            assert!(regs_used(&build(8, fixed)).iter().all(|&enc| enc >= 8));
+            // ...and without, costly ones are used rather than spilling.
+            assert!(regs_used(&build(28, fixed)).iter().any(|&enc| enc < 8));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_score_free_regs() {
+        // Callee-saved registers come first in the order of preference.
+        let mut env = machine_env();
+        let callee_saved = env.callee_saved_regs.clone();
+        env.regs_by_class[RegClass::Int as u8 as usize]
+            .sort_by_key(|preg| !callee_saved.contains(preg));
+
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let vregs: Vec<VReg> = (0..4).map(|_| builder.vreg()).collect();
+        for &vreg in &vregs {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        let uses: Vec<Operand> = vregs.iter().map(|&v| Operand::reg_use(v)).collect();
+        builder.inst(block, &uses, &[]);
+        // A fixed use, so the function is not allocated linearly.
+        let fixed = Operand::reg_fixed_use(vregs[0], PReg::new(0, RegClass::Int));
+        builder.inst(block, &[fixed], &[]).ret(block);
+        let func = builder.build().unwrap();
+
+        let callee_saves = |score_free_regs: bool| {
+            let options = RegallocOptions {
+                save_callee_saves: true,
+                score_free_regs,
+                ..checked()
+            };
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+            out.callee_saves.len()
+        };
+        // First fit takes callee-saved registers, each needing a save
+        // and restore; scoring finds free registers that need neither.
+        assert!(callee_saves(false) > 0);
+        assert_eq!(callee_saves(true), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_non_ssa() {
+        // A loop that counts v0 down in place, and redefines v1 on each
+        // trip, across a call that clobbers every caller-saved
+        // register.
+        let env = machine_env();
+        let clobbers: Vec<PReg> = (0..24).map(|i| PReg::new(i, RegClass::Int)).collect();
+        let build = |ssa_only: bool| {
+            let mut builder = FuncBuilder::new();
+            let (entry, header, body, exit) = (
+                builder.block(),
+                builder.block(),
+                builder.block(),
+                builder.block(),
+            );
+            let (v0, v1) = (builder.vreg(), builder.vreg());
+            if !ssa_only {
+                builder.non_ssa();
+            }
+            builder
+                .inst(entry, &[Operand::reg_def(v0)], &[])
+                .inst(entry, &[Operand::reg_def(v1)], &[])
+                .branch(entry, &[header], &[])
+                .inst(header, &[Operand::reg_mod(v0)], &[])
+                .branch(header, &[body, exit], &[])
+                .inst(body, &[Operand::reg_use(v1)], &clobbers)
+                .inst(body, &[Operand::reg_def(v1), Operand::reg_use(v0)], &[])
+                .branch(body, &[header], &[])
+                .inst(exit, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+                .ret(exit);
+            builder.build()
+        };
+        let func = build(false).unwrap();
+        let fast = RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            ..checked()
+        };
+        for options in [checked(), fast] {
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            checker::check(&func, &env, &out).expect("checker failed");
+            // The mod is read from and written back to one place.
+            assert!(out.inst_allocs(Inst::new(3))[0].as_reg().is_some());
+            for seed in 0..4 {
+                if let Err(e) = fuzzing::interp::run(&func, &out, seed, 100) {
+                    panic!("interpreter found a mismatch: {}", e);
+                }
+            }
+        }
+        let cfginfo = cfg::CFGInfo::new(&func);
+        let liveness = LivenessInfo::new(&func, &cfginfo).unwrap();
+        let v1 = VReg::new(1, RegClass::Int);
+        assert!(liveness.is_live_in(Block::new(1), v1));
+        assert!(!liveness.is_live_at(v1, ProgPoint::after(Inst::new(5))));
+
+        // A dead redefinition on one side of a fork, whichever side
+        // the allocator visits first, with v0 live on the other.
+        for swap in [false, true] {
+            let mut builder = FuncBuilder::new();
+            let (entry, left, right) = (builder.block(), builder.block(), builder.block());
+            let v0 = builder.vreg();
+            let succs = if swap { [right, left] } else { [left, right] };
+            builder
+                .non_ssa()
+                .inst(entry, &[Operand::reg_def(v0)], &[])
+                .branch(entry, &succs, &[])
+                .inst(left, &[Operand::reg_use(v0)], &[])
+                .ret(left)
+                .inst(right, &[Operand::reg_def(v0)], &[])
+                .ret(right);
+            let func = builder.build().unwrap();
+            let out = run_checked(&func, &env).expect("regalloc did not succeed");
+            checker::check(&func, &env, &out).expect("checker failed");
+        }
+
+        // Without `is_ssa() == false`, a mod or a second def is
+        // rejected.
+        assert!(matches!(build(true), Err(RegAllocError::SSA(_, _))));
+        // Nor may a mod share its inst with another mention of its
+        // vreg, or be read before a def.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .non_ssa()
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_mod(v0), Operand::reg_use(v0)], &[])
+            .ret(block);
+        assert!(matches!(builder.build(), Err(RegAllocError::SSA(_, _))));
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .non_ssa()
+            .inst(block, &[Operand::reg_mod(v0)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        assert!(matches!(
+            run_checked(&func, &env),
+            Err(RegAllocError::UseBeforeDef(_, _))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_grouped_fixed_uses() {
+        let mut env = machine_env();
+        let int = |i| PReg::new(i, RegClass::Int);
+        env.call_convs.push(CallConv {
+            name: "test".to_string(),
+            clobbers: (0..24).map(int).collect(),
+            ..CallConv::default()
+        });
+
+        // The call's args swap registers with their defs, one is
+        // passed in two registers, and all are clobbered by the call
+        // but used after it.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_fixed_def(v0, int(1))], &[])
+            .inst(block, &[Operand::reg_fixed_def(v1, int(0))], &[])
+            .call(
+                block,
+                &[
+                    Operand::reg_fixed_def(v2, int(24)),
+                    Operand::reg_fixed_use(v0, int(0)),
+                    Operand::reg_fixed_use(v1, int(1)),
+                    Operand::reg_fixed_use(v0, int(2)),
+                ],
+                0,
+            )
+            .inst(
+                block,
+                &[
+                    Operand::reg_use(v0),
+                    Operand::reg_use(v1),
+                    Operand::reg_use(v2),
+                ],
+                &[],
+            )
+            .ret(block);
+        let func = builder.build().unwrap();
+        let call = Inst::new(2);
+        assert!(func.groups_fixed_uses(call));
+
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        assert_eq!(
+            &out.inst_allocs(call)[1..],
+            &[int(0), int(1), int(2)].map(Allocation::reg)
+        );
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+        for seed in 0..4 {
+            if let Err(e) = fuzzing::interp::run(&func, &out, seed, 100) {
+                panic!("interpreter found a mismatch: {}", e);
+            }
+        }
+    }
+
+    /// A function whose operands can only be visited, as if they were
+    /// computed on demand.
+    pub(crate) struct VisitOnly<'a>(pub(crate) &'a Func);
+
+    impl<'a> Function for VisitOnly<'a> {
+        fn insts(&self) -> usize {
+            self.0.insts()
+        }
+        fn blocks(&self) -> usize {
+            self.0.blocks()
+        }
+        fn entry_block(&self) -> Block {
+            self.0.entry_block()
+        }
+        fn block_insns(&self, block: Block) -> InstRange {
+            self.0.block_insns(block)
+        }
+        fn block_succs(&self, block: Block) -> &[Block] {
+            self.0.block_succs(block)
+        }
+        fn block_preds(&self, block: Block) -> &[Block] {
+            self.0.block_preds(block)
+        }
+        fn block_params(&self, block: Block) -> &[VReg] {
+            self.0.block_params(block)
+        }
+        fn is_call(&self, insn: Inst) -> bool {
+            self.0.is_call(insn)
+        }
+        fn is_ret(&self, insn: Inst) -> bool {
+            self.0.is_ret(insn)
+        }
+        fn is_branch(&self, insn: Inst) -> bool {
+            self.0.is_branch(insn)
+        }
+        fn groups_fixed_uses(&self, insn: Inst) -> bool {
+            self.0.groups_fixed_uses(insn)
+        }
+        fn is_safepoint(&self, insn: Inst) -> bool {
+            self.0.is_safepoint(insn)
+        }
+        fn is_move(&self, insn: Inst) -> Option<(VReg, VReg)> {
+            self.0.is_move(insn)
+        }
+        fn visit_operands(&self, insn: Inst, f: &mut dyn FnMut(usize, Operand)) {
+            self.0.visit_operands(insn, f)
+        }
+        fn inst_clobbers(&self, insn: Inst) -> &[PReg] {
+            self.0.inst_clobbers(insn)
+        }
+        fn num_vregs(&self) -> usize {
+            self.0.num_vregs()
+        }
+        fn spillslot_size(&self, regclass: RegClass, vreg: VReg) -> usize {
+            self.0.spillslot_size(regclass, vreg)
+        }
+    }
+
+    #[test]
+    fn test_visit_operands() {
+        // A function with a constant, a fixed def, a copy, a reused
+        // input and clobbers allocates the same through the visitor.
+        let env = machine_env();
+        let int = |i| PReg::new(i, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2, v3, v4) = (
+            builder.vreg(),
+            builder.vreg(),
+            builder.vreg(),
+            builder.vreg(),
+            builder.vreg(),
+        );
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_fixed_def(v1, int(2))], &[])
+            .inst(block, &[Operand::constant_def(v4)], &[]);
+        builder.add_inst(
+            block,
+            InstData::new(
+                InstOpcode::Move,
+                vec![Operand::reg_def(v2), Operand::reg_use(v0)],
+                vec![],
+            ),
+        );
+        builder
+            .inst(
+                block,
+                &[
+                    Operand::reg_reuse_def(v3, 1),
+                    Operand::reg_use(v1),
+                    Operand::reg_use(v2),
+                    Operand::reg_use(v4),
+                ],
+                &[int(0), int(1)],
+            )
+            .inst(
+                block,
+                &[Operand::reg_fixed_use(v3, int(0)), Operand::reg_use(v0)],
+                &[],
+            )
+            .ret(block);
+        let func = builder.build().unwrap();
+        let fast = RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            ..checked()
+        };
+        for options in [checked(), fast] {
+            let out = run_with_options(&VisitOnly(&func), &env, &options)
+                .expect("regalloc did not succeed");
+            let expected = run_with_options(&func, &env, &options).unwrap();
+            assert!(!expected.edits.is_empty());
+            assert_eq!(out.allocs, expected.allocs);
+            assert_eq!(out.edits, expected.edits);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_validate_output() {
+        // A spill across a call and a blockparam pass the checker
+        // under both allocators, and checking changes no allocation.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let (entry, exit) = (builder.block(), builder.block());
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder.params(exit, &[v2]);
+        builder.inst(entry, &[Operand::reg_def(v0)], &[]);
+        builder.inst(entry, &[Operand::reg_def(v1)], &[]);
+        let clobbers = env.regs.clone();
+        builder.add_inst(entry, InstData::new(InstOpcode::Call, vec![], clobbers));
+        builder
+            .branch(entry, &[exit], &[v1])
+            .inst(exit, &[Operand::reg_use(v0), Operand::reg_use(v2)], &[])
+            .ret(exit);
+        let func = builder.build().unwrap();
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..RegallocOptions::default()
+            };
+            let validating = RegallocOptions {
+                validate_output: true,
+                ..options.clone()
+            };
+            assert!(validating.validates_output());
+            let out = run_with_options(&func, &env, &options).unwrap();
+            let checked = run_with_options(&func, &env, &validating).expect("checker failed");
+            assert!(checked.num_spillslots > 0);
+            assert_eq!(checked.allocs, out.allocs);
+        }
+    }
+
+    #[test]
+    fn test_report_blockparams() {
+        // Reporting blockparams adds an edit for each block with
+        // params, and changes nothing else.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let (entry, left, right, join) = (
+            builder.block(),
+            builder.block(),
+            builder.block(),
+            builder.block(),
+        );
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .params(join, &[v2])
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v1)], &[])
+            .branch(entry, &[left, right], &[])
+            .branch(left, &[join], &[v0])
+            .branch(right, &[join], &[v1])
+            .inst(join, &[Operand::reg_use(v2)], &[])
+            .ret(join);
+        let func = builder.build().unwrap();
+        let is_blockparams = |edit: &Edit| matches!(edit, Edit::BlockParams { .. });
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..RegallocOptions::default()
+            };
+            let out = run_with_options(&func, &env, &options).unwrap();
+            assert!(!out.edits.iter().any(|(_, edit)| is_blockparams(edit)));
+            let reporting = RegallocOptions {
+                report_blockparams: true,
+                ..options
+            };
+            let reported = run_with_options(&func, &env, &reporting).unwrap();
+            let (params, others): (Vec<_>, Vec<_>) = reported
+                .edits
+                .into_iter()
+                .partition(|(_, edit)| is_blockparams(edit));
+            assert_eq!(params.len(), 1, "{:?}", params);
+            assert_eq!(
+                params[0].0,
+                ProgPoint::before(func.block_insns(join).first())
+            );
+            assert_eq!(others, out.edits);
+            assert_eq!(reported.allocs, out.allocs);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_excluded_regs() {
+        let excluded =
+            ExcludedRegs::new(&[PReg::new(3, RegClass::Int), PReg::new(200, RegClass::Int)]);
+        assert_eq!(excluded.nums().collect::<Vec<_>>(), vec![3, 200]);
+        assert!(excluded.contains(PReg::new(200, RegClass::Int)));
+        assert!(!excluded.contains(PReg::new(0, RegClass::Int)));
+        let op = Operand::new(
+            VReg::new(1, RegClass::Int),
+            OperandPolicy::RegExcept(excluded),
+            OperandKind::Use,
+            OperandPos::Before,
+        );
+        assert_eq!(op.policy(), OperandPolicy::RegExcept(excluded));
+        assert_eq!(op.to_string(), "Use@Before: v1 reg_except(p3, p200)");
+
+        // The def and the use each avoid the registers they exclude,
+        // which they would otherwise take, under both allocators; an
+        // allocation into one is caught by the checker.
+        let env = machine_env();
+        let int = |i| PReg::new(i, RegClass::Int);
+        let build = |excluded: &[PReg]| {
+            let policy = if excluded.is_empty() {
+                OperandPolicy::Reg
+            } else {
+                OperandPolicy::RegExcept(ExcludedRegs::new(excluded))
+            };
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            builder
+                .inst(
+                    block,
+                    &[Operand::new(
+                        v0,
+                        policy,
+                        OperandKind::Def,
+                        OperandPos::After,
+                    )],
+                    &[],
+                )
+                .inst(
+                    block,
+                    &[Operand::new(
+                        v0,
+                        policy,
+                        OperandKind::Use,
+                        OperandPos::Before,
+                    )],
+                    &[],
+                )
+                .ret(block);
+            builder.build().unwrap()
+        };
+        let fast = RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            ..checked()
+        };
+        for options in [checked(), fast] {
+            let out = run_with_options(&build(&[]), &env, &options).unwrap();
+            let taken = out.inst_allocs(Inst::new(0))[0].as_reg().unwrap();
+            let excluded = [taken, int((taken.hw_enc() + 1) % 24)];
+            let func = build(&excluded);
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            for inst in 0..2 {
+                let alloc = out.inst_allocs(Inst::new(inst))[0].as_reg().unwrap();
+                assert!(!excluded.contains(&alloc), "{}", out);
+            }
+            checker::check(&func, &env, &out).expect("checker failed");
+
+            let out = run_with_options(&build(&[]), &env, &options).unwrap();
+            let errors = checker::check(&func, &env, &out).unwrap_err();
+            assert!(matches!(
+                errors.errors()[0],
+                checker::CheckerError::AllocationIsExcluded { .. }
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_pref_regs() {
+        let p5 = PReg::new(5, RegClass::Int);
+        let op = Operand::reg_pref_use(VReg::new(1, RegClass::Int), p5);
+        assert_eq!(op.policy(), OperandPolicy::PrefReg(p5));
+        assert_eq!(op.to_string(), "Use@Before: v1 pref(p5i)");
+
+        // A preferred register is taken when free, with no move to
+        // get there, and is only a preference when it is not.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        let pref_use = Operand::reg_pref_use(v0, p5);
+        builder
+            .inst(block, &[Operand::reg_pref_def(v0, p5)], &[])
+            .inst(block, &[Operand::reg_fixed_def(v1, p5)], &[])
+            .inst(block, &[Operand::reg_def(v2), pref_use], &[])
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+        assert_ne!(out.allocs[0], Allocation::reg(p5));
+
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        let pref_use = Operand::reg_pref_use(v0, p5);
+        builder
+            .inst(block, &[Operand::reg_pref_def(v0, p5)], &[])
+            .inst(block, &[Operand::reg_def(v1), pref_use], &[])
+            .inst(block, &[Operand::reg_fixed_use(v1, p5)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        assert_eq!(out.allocs[0], Allocation::reg(p5));
+        assert_eq!(out.allocs[2], Allocation::reg(p5));
+        assert!(out.edits.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_stack_operands() {
+        let op = Operand::stack_use(VReg::new(1, RegClass::Int));
+        assert_eq!(op.policy(), OperandPolicy::Stack);
+        assert_eq!(op.to_string(), "Use@Before: v1 stack");
+
+        // A value used both from its slot and from a register, one
+        // defined straight into its slot, and a constant read from a
+        // slot.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2, v3) = (
+            builder.vreg(),
+            builder.vreg(),
+            builder.vreg(),
+            builder.vreg(),
+        );
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::stack_def(v1)], &[])
+            .inst(block, &[Operand::constant_def(v2)], &[])
+            .inst(block, &[Operand::reg_def(v3), Operand::stack_use(v0)], &[])
+            .inst(block, &[Operand::stack_use(v2)], &[])
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .inst(block, &[Operand::reg_use(v3)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        assert!(out.inst_allocs(Inst::new(1))[0].as_stack().is_some());
+        assert!(out.inst_allocs(Inst::new(3))[1].as_stack().is_some());
+        assert!(out.inst_allocs(Inst::new(4))[0].as_stack().is_some());
+        assert!(out
+            .inst_allocs(Inst::new(5))
+            .iter()
+            .all(|a| a.as_reg().is_some()));
+        fuzzing::interp::run(&func, &out, 0, 100).expect("interpreter found a mismatch");
+
+        // A vreg cannot be read both from its slot and from a register
+        // at one inst.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(
+                block,
+                &[
+                    Operand::reg_def(v1),
+                    Operand::stack_use(v0),
+                    Operand::reg_use(v0),
+                ],
+                &[],
+            )
+            .inst(block, &[Operand::reg_use(v1)], &[])
+            .ret(block);
+        assert!(matches!(
+            builder.build(),
+            Err(RegAllocError::StackUseConflict(vreg, inst)) if (vreg, inst) == (v0, Inst::new(1))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_fixed_stack_operands() {
+        let s0 = SpillSlot::new(0, RegClass::Int);
+        let s1 = SpillSlot::new(1, RegClass::Int);
+        let op = Operand::stack_fixed_use(VReg::new(1, RegClass::Int), s1);
+        assert_eq!(op.policy(), OperandPolicy::FixedStack(s1));
+        assert_eq!(op.to_string(), "Use@Before: v1 fixed(stack1)");
+        assert_eq!(
+            Operand::stack_use(VReg::new(1, RegClass::Int)).policy(),
+            OperandPolicy::Stack
+        );
+
+        // A value passed in two stack args, and one defined in a stack
+        // slot, e.g. a call's result returned on the stack.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        builder.reserved_spillslots(2);
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        let args = [
+            Operand::reg_def(v1),
+            Operand::stack_fixed_use(v0, s0),
+            Operand::stack_fixed_use(v0, s1),
+        ];
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &args, &[])
+            .inst(block, &[Operand::stack_fixed_def(v2, s0)], &[])
+            .inst(
+                block,
+                &[
+                    Operand::reg_use(v0),
+                    Operand::reg_use(v1),
+                    Operand::reg_use(v2),
+                ],
+                &[],
+            )
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        let stack = |slot| Allocation::stack(slot);
+        assert_eq!(&out.inst_allocs(Inst::new(1))[1..], &[stack(s0), stack(s1)]);
+        assert_eq!(out.inst_allocs(Inst::new(2)), &[stack(s0)]);
+        assert!(out.num_spillslots >= 2);
+        fuzzing::interp::run(&func, &out, 0, 100).expect("interpreter found a mismatch");
+
+        // The slot must be reserved, and not needed for two values.
+        let conflict = |ops: &[Operand]| {
+            let mut builder = FuncBuilder::new();
+            builder.reserved_spillslots(2);
+            let block = builder.block();
+            let (v0, v1) = (builder.vreg(), builder.vreg());
+            builder
+                .inst(block, &[Operand::reg_def(v0)], &[])
+                .inst(block, &[Operand::reg_def(v1)], &[])
+                .inst(block, ops, &[])
+                .ret(block);
+            match builder.build() {
+                Err(RegAllocError::FixedStack(slot, inst)) => (slot, inst),
+                other => panic!("expected a fixed-stack error, got {:?}", other),
+            }
+        };
+        let s2 = SpillSlot::new(2, RegClass::Int);
+        let (v0, v1) = (VReg::new(0, RegClass::Int), VReg::new(1, RegClass::Int));
+        assert_eq!(
+            conflict(&[Operand::stack_fixed_use(v0, s2)]),
+            (s2, Inst::new(2))
+        );
+        assert_eq!(
+            conflict(&[
+                Operand::stack_fixed_use(v0, s1),
+                Operand::stack_fixed_use(v1, s1)
+            ]),
+            (s1, Inst::new(2))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_conditional_defs() {
+        let op = Operand::reg_cond_def(VReg::new(2, RegClass::Int), 1);
+        assert!(op.is_conditional_def());
+        assert_eq!(op.kind(), OperandKind::Def);
+        assert_eq!(op.policy(), OperandPolicy::Reuse(1));
+        assert_eq!(op.to_string(), "CondDef@After: v2 reuse(1)");
+        assert!(!Operand::reg_def(VReg::new(2, RegClass::Int)).is_conditional_def());
+
+        // v2 is conditionally defined over v1, which is live past it:
+        // it shares v1's register there, so v1 is copied out first.
+        // Neither may be recomputed after the call that clobbers
+        // every register, even if v2 is marked rematerializable,
+        // since its value may be v1's.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder.remat(v2, RematKind::Other(0));
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(
+                block,
+                &[
+                    Operand::reg_cond_def(v2, 1),
+                    Operand::reg_use(v1),
+                    Operand::reg_use(v0),
+                ],
+                &[],
+            );
+        let clobbers = env.regs.clone();
+        builder.add_inst(block, InstData::new(InstOpcode::Call, vec![], clobbers));
+        builder
+            .inst(block, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        let allocs = out.inst_allocs(Inst::new(2));
+        assert_eq!(allocs[0], allocs[1]);
+        assert!(out
+            .edits
+            .iter()
+            .all(|(_, edit)| !matches!(edit, Edit::Remat { .. })));
+        checker::check(&func, &env, &out).expect("checker failed");
+        for seed in 0..8 {
+            if let Err(e) = fuzzing::interp::run(&func, &out, seed, 100) {
+                panic!("interpreter found a mismatch: {}", e);
+            }
+        }
+    }
+
+    /// A function that panics the first time its vreg count is asked
+    /// for.
+    struct PanicsOnce<'a>(&'a Func, std::cell::Cell<bool>);
+
+    impl<'a> Function for PanicsOnce<'a> {
+        fn insts(&self) -> usize {
+            self.0.insts()
+        }
+        fn blocks(&self) -> usize {
+            self.0.blocks()
+        }
+        fn entry_block(&self) -> Block {
+            self.0.entry_block()
+        }
+        fn block_insns(&self, block: Block) -> InstRange {
+            self.0.block_insns(block)
+        }
+        fn block_succs(&self, block: Block) -> &[Block] {
+            self.0.block_succs(block)
+        }
+        fn block_preds(&self, block: Block) -> &[Block] {
+            self.0.block_preds(block)
+        }
+        fn block_params(&self, block: Block) -> &[VReg] {
+            self.0.block_params(block)
+        }
+        fn is_call(&self, insn: Inst) -> bool {
+            self.0.is_call(insn)
+        }
+        fn is_ret(&self, insn: Inst) -> bool {
+            self.0.is_ret(insn)
+        }
+        fn is_branch(&self, insn: Inst) -> bool {
+            self.0.is_branch(insn)
+        }
+        fn is_safepoint(&self, insn: Inst) -> bool {
+            self.0.is_safepoint(insn)
+        }
+        fn is_move(&self, insn: Inst) -> Option<(VReg, VReg)> {
+            self.0.is_move(insn)
+        }
+        fn inst_operands(&self, insn: Inst) -> &[Operand] {
+            self.0.inst_operands(insn)
+        }
+        fn inst_clobbers(&self, insn: Inst) -> &[PReg] {
+            self.0.inst_clobbers(insn)
+        }
+        fn num_vregs(&self) -> usize {
+            if self.1.replace(false) {
+                panic!("the first run panics");
+            }
+            self.0.num_vregs()
+        }
+        fn spillslot_size(&self, regclass: RegClass, vreg: VReg) -> usize {
+            self.0.spillslot_size(regclass, vreg)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_fallback() {
+        let env = machine_env();
+        // v0 and v2 are spilled across different calls, so they can
+        // share a slot once the fallback packs them, but take one each
+        // with a slot per vreg: a budget of two is missed by the first
+        // run and met by the retry.
+        let mut builder = FuncBuilder::new();
+        let entry = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v1)], &[])
+            .inst(entry, &[], &env.regs)
+            .inst(entry, &[Operand::reg_use(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v2)], &[])
+            .inst(entry, &[], &env.regs)
+            .inst(entry, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(entry);
+        let func = builder.finalize();
+        let options = RegallocOptions {
+            spillslot_per_vreg: true,
+            max_spillslots: Some(2),
+            ..checked()
+        };
+        let (out, err) = run_with_fallback(&func, &env, &options).unwrap();
+        match err {
+            Some(RegAllocError::TooManySpillslots(3, _)) => {}
+            other => panic!("expected TooManySpillslots, got {:?}", other),
+        }
+        assert_eq!(out.num_spillslots, 2);
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+
+        // The retry keeps the budget, so one that cannot be met at
+        // all is still an error.
+        let options = RegallocOptions {
+            max_spillslots: Some(1),
+            ..options
+        };
+        match run_with_fallback(&func, &env, &options) {
+            Err(RegAllocError::TooManySpillslots(..)) => {}
+            other => panic!("expected TooManySpillslots, got {:?}", other.err()),
+        }
+
+        // A panic is caught and retried as well.
+        let panics = PanicsOnce(&func, std::cell::Cell::new(true));
+        let (out, err) = run_with_fallback(&panics, &env, &checked()).unwrap();
+        match err {
+            Some(RegAllocError::Panicked(message)) => assert_eq!(message, "the first run panics"),
+            other => panic!("expected a caught panic, got {:?}", other),
+        }
+        assert_eq!(out.fingerprint.algorithm, AllocatorAlgorithm::Fast);
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+
+        // Errors in the function itself are not retried.
+        let mut builder = FuncBuilder::new();
+        let entry = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(entry, &[Operand::reg_def(v1), Operand::reg_use(v0)], &[])
+            .ret(entry);
+        match run_with_fallback(&builder.finalize(), &env, &checked()) {
+            Err(RegAllocError::UseBeforeDef(..)) => {}
+            other => panic!("expected use-before-def, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let env = machine_env();
+        // v0 and v2 are spilled across different calls: they share a
+        // slot when packed, but not with a slot per vreg.
+        let mut builder = FuncBuilder::new();
+        let entry = builder.block();
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v1)], &[])
+            .inst(entry, &[], &env.regs)
+            .inst(entry, &[Operand::reg_use(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v2)], &[])
+            .inst(entry, &[], &env.regs)
+            .inst(entry, &[Operand::reg_use(v1), Operand::reg_use(v2)], &[])
+            .ret(entry);
+        let func = builder.finalize();
+        let options = checked();
+        let out = run_with_options(&func, &env, &options).unwrap();
+        assert_eq!(out.fingerprint, options.fingerprint());
+        assert_eq!(out.fingerprint.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(out.fingerprint.chaos_seed, None);
+
+        // Any option that may change the output changes the
+        // fingerprint; where snapshots are written does not.
+        let seeded = RegallocOptions {
+            chaos_seed: Some(7),
+            ..checked()
+        };
+        assert_eq!(seeded.fingerprint().chaos_seed, Some(7));
+        assert_ne!(seeded.fingerprint(), options.fingerprint());
+        let shrunk = RegallocOptions {
+            shrink_frame: true,
+            ..checked()
+        };
+        assert_ne!(
+            shrunk.fingerprint().options_hash,
+            options.fingerprint().options_hash
+        );
+        let snapshots = RegallocOptions {
+            snapshot_dir: Some("snapshots".into()),
+            ..checked()
+        };
+        assert_eq!(snapshots.fingerprint(), options.fingerprint());
+
+        // A retry records the fallback configuration it ran with,
+        // under the allocator that ran it: here the fast one hands the
+        // function over to the backtracking one to meet the budget.
+        let options = RegallocOptions {
+            spillslot_per_vreg: true,
+            max_spillslots: Some(2),
+            ..checked()
+        };
+        let (out, err) = run_with_fallback(&func, &env, &options).unwrap();
+        assert!(matches!(err, Some(RegAllocError::TooManySpillslots(..))));
+        let expected = RegallocOptions {
+            algorithm: AllocatorAlgorithm::Backtracking,
+            ..options.fallback()
+        };
+        assert_eq!(out.fingerprint, expected.fingerprint());
+    }
+
+    #[test]
+    fn test_use_before_def() {
+        let env = machine_env();
+
+        // A use of a vreg that is never defined.
+        let mut builder = FuncBuilder::new();
+        let entry = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .inst(entry, &[Operand::reg_def(v1), Operand::reg_use(v0)], &[])
+            .ret(entry);
+        match run(&builder.finalize(), &env) {
+            Err(RegAllocError::UseBeforeDef(vreg, inst)) => {
+                assert_eq!((vreg, inst), (v0, Inst::new(0)))
+            }
+            other => panic!("expected use-before-def, got {:?}", other.err()),
+        }
+
+        // A use at a join of a vreg defined on only one of its paths.
+        let mut builder = FuncBuilder::new();
+        let blocks: Vec<Block> = (0..4).map(|_| builder.block()).collect();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .branch(blocks[0], &[blocks[1], blocks[2]], &[])
+            .inst(blocks[1], &[Operand::reg_def(v0)], &[])
+            .branch(blocks[1], &[blocks[3]], &[])
+            .branch(blocks[2], &[blocks[3]], &[])
+            .inst(
+                blocks[3],
+                &[Operand::reg_def(v1), Operand::reg_use(v0)],
+                &[],
+            )
+            .ret(blocks[3]);
+        match run(&builder.finalize(), &env) {
+            Err(RegAllocError::UseBeforeDef(vreg, inst)) => {
+                assert_eq!((vreg, inst), (v0, Inst::new(4)))
+            }
+            other => panic!("expected use-before-def, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_no_registers_in_class() {
+        // `machine_env()` has no float registers, preferred or not.
+        let env = machine_env();
+        assert!(env.regs_by_class[RegClass::Float as usize].is_empty());
+        assert!(env.preferred_regs_by_class.is_empty());
+        assert!(env.non_preferred_regs_by_class.is_empty());
+        let fast = RegallocOptions {
+            algorithm: AllocatorAlgorithm::Fast,
+            ..checked()
+        };
+
+        // A float operand is reported with its instruction.
+        let mut builder = FuncBuilder::new();
+        let entry = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        let f1 = VReg::new(v1.vreg(), RegClass::Float);
+        builder
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(f1), Operand::reg_use(v0)], &[])
+            .ret(entry);
+        let func = builder.build().unwrap();
+        for options in [checked(), fast.clone()] {
+            match run_with_options(&func, &env, &options) {
+                Err(RegAllocError::NoRegistersInClass(class, vreg, inst)) => {
+                    assert_eq!((class, vreg, inst), (RegClass::Float, f1, Inst::new(1)))
+                }
+                other => panic!("expected no registers in class, got {:?}", other.err()),
+            }
+        }
+
+        // A float block param has no instruction.
+        let mut builder = FuncBuilder::new();
+        let entry = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        let f0 = VReg::new(v0.vreg(), RegClass::Float);
+        builder
+            .params(entry, &[f0])
+            .inst(entry, &[Operand::reg_def(v1)], &[])
+            .ret(entry);
+        match run_checked(&builder.finalize(), &env) {
+            Err(RegAllocError::NoRegistersInClass(class, vreg, inst)) => {
+                assert_eq!((class, vreg, inst), (RegClass::Float, f0, Inst::invalid()))
+            }
+            other => panic!("expected no registers in class, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_class_mismatch() {
+        let mut env = machine_env();
+        let floats: Vec<PReg> = (1..8).map(|i| PReg::new(i, RegClass::Float)).collect();
+        env.regs.extend(floats.iter().cloned());
+        env.regs_by_class[RegClass::Float as usize] = floats;
+
+        let mut builder = FuncBuilder::new();
+        let entry = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        let f0 = VReg::new(v0.vreg(), RegClass::Float);
+        builder
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v1), Operand::reg_use(f0)], &[])
+            .ret(entry);
+        match run(&builder.finalize(), &env) {
+            Err(RegAllocError::ClassMismatch(vreg, class, inst)) => {
+                assert_eq!((vreg, class, inst), (f0, RegClass::Int, Inst::new(1)))
+            }
+            other => panic!("expected class mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_error_display() {
+        let v3 = VReg::new(3, RegClass::Int);
+        assert_eq!(
+            RegAllocError::SSA(v3, Inst::new(5)).to_string(),
+            "invalid SSA at inst5: v3 is defined more than once, or used where its \
+             definition does not dominate"
+        );
+        assert_eq!(
+            RegAllocError::NoRegistersInClass(RegClass::Float, v3, Inst::invalid()).to_string(),
+            "no allocatable registers in class Float, but v3 has that class (a block param)"
+        );
+        assert_eq!(
+            RegAllocError::TooManySpillslots(4, vec![v3]).to_string(),
+            "the function needs 4 spillslots, more than `max_spillslots` allows; vregs \
+             spilled beyond the budget: v3"
+        );
+        assert_eq!(
+            RegAllocError::ClassMismatch(v3, RegClass::Float, Inst::new(2)).to_string(),
+            "v3 has class Int at inst2, but is defined with class Float"
+        );
+        assert_eq!(
+            RegAllocError::UseBeforeDef(v3, Inst::new(2)).to_string(),
+            "v3 is used at inst2, which is reachable from the entry block without passing \
+             a def of it"
+        );
+        assert_eq!(
+            RegAllocError::RefUseAtSafepoint(v3, Inst::new(4)).to_string(),
+            "reference v3 is live across safepoint inst4, which must not also use it in a \
+             register"
+        );
+        assert_eq!(
+            RegAllocError::StackUseConflict(v3, Inst::new(4)).to_string(),
+            "v3 is used at inst4 from its spillslot, but also in a register"
+        );
+        assert_eq!(
+            RegAllocError::FixedStack(SpillSlot::new(2, RegClass::Int), Inst::new(4)).to_string(),
+            "inst4 needs stack2 as a fixed stack slot, but it is not reserved, or is also \
+             needed there for another value"
+        );
+        assert_eq!(
+            RegAllocError::Pinned(v3, Inst::new(4)).to_string(),
+            "v3 is pinned to a register, which inst4 does not let it stay in"
+        );
+
+        // Corrupt a fixed-reg use's allocation and check that the
+        // checker's report surfaces through the error.
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_fixed_use(v0, env.regs[3])], &[])
+            .ret(block);
+        let func = builder.finalize();
+        let mut out = run_checked(&func, &env).expect("regalloc did not succeed");
+        let idx = out.inst_alloc_offsets[1] as usize;
+        out.allocs[idx] = Allocation::stack(SpillSlot::new(0, RegClass::Int));
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        let err = RegAllocError::CheckerFailed(checker.run().unwrap_err());
+        let text = err.to_string();
+        assert_eq!(
+            text,
+            "checker found an incorrect allocation: 2 error(s)\n  \
+             inst1: operand Use@Before: v0 fixed(p3i) reads stack0, which holds no known value\n  \
+             stack0 extends beyond the 0 spillslots of the frame"
+        );
+        let source = std::error::Error::source(&err).expect("no source");
+        assert!(text.ends_with(&source.to_string()));
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_debug_vreg_names() {
+        let env = machine_env();
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        builder
+            .name(v0, "x")
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_def(v1)], &[])
+            .inst(block, &[Operand::reg_use(v0), Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.build().unwrap();
+        assert_eq!(func.debug_vreg_name(v0), Some("x"));
+        assert_eq!(func.debug_vreg_name(v1), None);
+        assert_eq!(vreg_label(&func, v0), "v0 (x)");
+        assert_eq!(vreg_label(&func, v1), "v1");
+
+        // Swap the uses' allocations: the checker's report names the
+        // named vreg.
+        let mut out = run_checked(&func, &env).expect("regalloc did not succeed");
+        let idx = out.inst_alloc_offsets[2] as usize;
+        out.allocs.swap(idx, idx + 1);
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        let text = checker.run().unwrap_err().to_string();
+        assert!(text.ends_with("\n  where v0 is x"), "{}", text);
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_edit_restrictions() {
+        // v0 is defined in p5 and used in p7. Its moves include one
+        // just before the use, unless no edits are allowed there, in
+        // which case a single move goes before the inst in between.
+        let env = machine_env();
+        let (p5, p7) = (Allocation::reg(env.regs[5]), Allocation::reg(env.regs[7]));
+        let build = |restricted: bool| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            builder
+                .inst(block, &[Operand::reg_fixed_def(v0, env.regs[5])], &[])
+                .inst(block, &[], &[]);
+            if restricted {
+                builder.no_edits_after(block);
+            }
+            builder
+                .inst(block, &[Operand::reg_fixed_use(v0, env.regs[7])], &[])
+                .ret(block);
+            builder.finalize()
+        };
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..checked()
+            };
+            let out = run_with_options(&build(false), &env, &options).unwrap();
+            let before_use = ProgPoint::before(Inst::new(2));
+            assert!(out.edits.iter().any(|&(pos, _)| pos == before_use));
+
+            let func = build(true);
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            let edit = Edit::Move { from: p5, to: p7 };
+            assert_eq!(out.edits, vec![(ProgPoint::before(Inst::new(1)), edit)]);
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_flags() {
+        // v0 is defined in p5 and used in p7 by an inst that reads the
+        // flags. Its moves include one just before the use, unless the
+        // inst in between writes the flags, in which case a single move
+        // goes before that.
+        let env = machine_env();
+        let (p5, p7) = (Allocation::reg(env.regs[5]), Allocation::reg(env.regs[7]));
+        let build = |flags: bool| {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let v0 = builder.vreg();
+            builder
+                .inst(block, &[Operand::reg_fixed_def(v0, env.regs[5])], &[])
+                .inst(block, &[], &[]);
+            if flags {
+                builder.writes_flags(block);
+            }
+            builder.inst(block, &[Operand::reg_fixed_use(v0, env.regs[7])], &[]);
+            if flags {
+                builder.reads_flags(block);
+            }
+            builder.ret(block);
+            builder.finalize()
+        };
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..checked()
+            };
+            let out = run_with_options(&build(false), &env, &options).unwrap();
+            let before_use = ProgPoint::before(Inst::new(2));
+            assert!(out.edits.iter().any(|&(pos, _)| pos == before_use));
+
+            let func = build(true);
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            let edit = Edit::Move { from: p5, to: p7 };
+            assert_eq!(out.edits, vec![(ProgPoint::before(Inst::new(1)), edit)]);
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_delay_slots() {
+        let env = machine_env();
+        let build = |delay_slot: bool| {
+            let mut builder = FuncBuilder::new();
+            let (entry, left, right, join) = (
+                builder.block(),
+                builder.block(),
+                builder.block(),
+                builder.block(),
+            );
+            let (v0, v1) = (builder.vreg(), builder.vreg());
+            builder
+                .inst(entry, &[Operand::reg_fixed_def(v0, env.regs[5])], &[])
+                .branch(entry, &[left, right], &[]);
+            for block in [left, right] {
+                builder.branch(block, &[join], &[v0]);
+                if delay_slot {
+                    builder.delay_slot(block);
+                }
+            }
+            builder
+                .params(join, &[v1])
+                .inst(join, &[Operand::reg_fixed_use(v1, env.regs[7])], &[])
+                .inst(join, &[Operand::reg_use(v0)], &[])
+                .ret(join);
+            builder.finalize()
+        };
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..checked()
+            };
+            // The out-edge moves of each side of the diamond go after
+            // its branch, or before it if it has a delay slot.
+            let branches = [Inst::new(2), Inst::new(3)];
+            let out = run_with_options(&build(false), &env, &options).unwrap();
+            for &branch in &branches {
+                assert!(out
+                    .edits
+                    .iter()
+                    .any(|&(pos, _)| pos == ProgPoint::after(branch)));
+            }
+
+            let func = build(true);
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            let edit = Edit::Move {
+                from: Allocation::reg(env.regs[5]),
+                to: Allocation::reg(env.regs[7]),
+            };
+            let moves: Vec<_> = out
+                .edits
+                .iter()
+                .filter(|(_, edit)| matches!(edit, Edit::Move { .. }))
+                .cloned()
+                .collect();
+            let expected: Vec<_> = branches
+                .iter()
+                .map(|&branch| (ProgPoint::before(branch), edit.clone()))
+                .collect();
+            assert_eq!(moves, expected);
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_frame_pointer() {
+        // 31 values live at once fill every allocatable register, the
+        // frame pointer included, once it is allocatable; while it is
+        // reserved, one of them goes to the stack instead. (The fast
+        // allocator spills them all anyway.)
+        let fp = PReg::new(30, RegClass::Int);
+        let env = MachineEnv {
+            frame_pointer: Some(fp),
+            ..machine_env()
+        };
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let vregs: Vec<VReg> = (0..31).map(|_| builder.vreg()).collect();
+        for &vreg in &vregs {
+            builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        }
+        for &vreg in &vregs {
+            builder.inst(block, &[Operand::reg_use(vreg)], &[]);
+        }
+        builder.ret(block);
+        let func = builder.finalize();
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            for allocate_frame_pointer in [false, true] {
+                let options = RegallocOptions {
+                    algorithm,
+                    allocate_frame_pointer,
+                    ..checked()
+                };
+                let out =
+                    run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+                let uses_fp = out.allocs.iter().any(|a| a.as_reg() == Some(fp));
+                assert!(allocate_frame_pointer || !uses_fp);
+                if algorithm == AllocatorAlgorithm::Backtracking {
+                    assert_eq!(uses_fp, allocate_frame_pointer);
+                    assert_eq!(out.num_spillslots, usize::from(!allocate_frame_pointer));
+                }
+                let mut checker = Checker::new(&func);
+                checker.prepare(&out);
+                checker.run().expect("checker failed");
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_late_positions() {
+        // With a single register, v1 can only be defined in the one v0
+        // is read from if it is written late in the half of the
+        // instruction that reads v0.
+        let mut env = machine_env();
+        env.regs_by_class[RegClass::Int as u8 as usize].truncate(1);
+        env.regs.truncate(1);
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let (v0, v1) = (builder.vreg(), builder.vreg());
+        let late_def = Operand::new(
+            v1,
+            OperandPolicy::Reg,
+            OperandKind::Def,
+            OperandPos::BeforeLate,
+        );
+        builder
+            .inst(block, &[Operand::reg_def(v0)], &[])
+            .inst(block, &[Operand::reg_use(v0), late_def], &[])
+            .inst(block, &[Operand::reg_use(v1)], &[])
+            .ret(block);
+        let func = builder.finalize();
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..checked()
+            };
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            let p0 = Allocation::reg(env.regs[0]);
+            assert_eq!(out.inst_allocs(Inst::new(1)), &[p0, p0]);
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+            for seed in 0..4 {
+                if let Err(e) = fuzzing::interp::run(&func, &out, seed, 10 * func.insts()) {
+                    panic!("interpreter found a mismatch: {}", e);
+                }
+            }
+        }
+
+        // A use read late in the half after a def is written overlaps
+        // it: with v1 in the single register, v0 is read from the
+        // stack, where an early read could have taken the register.
+        for pos in [OperandPos::Before, OperandPos::AfterLate] {
+            let mut builder = FuncBuilder::new();
+            let block = builder.block();
+            let (v0, v1) = (builder.vreg(), builder.vreg());
+            let any_use = Operand::new(v0, OperandPolicy::Any, OperandKind::Use, pos);
+            builder
+                .inst(block, &[Operand::reg_def(v0)], &[])
+                .inst(block, &[Operand::reg_def(v1), any_use], &[])
+                .inst(block, &[Operand::reg_use(v1)], &[])
+                .ret(block);
+            let func = builder.finalize();
+            for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+                let options = RegallocOptions {
+                    algorithm,
+                    ..checked()
+                };
+                let out =
+                    run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+                let p0 = Allocation::reg(env.regs[0]);
+                assert_eq!(out.inst_allocs(Inst::new(1))[0], p0);
+                // The fast allocator reads `Any` uses from the stack
+                // regardless.
+                let stack = out.inst_allocs(Inst::new(1))[1].as_stack().is_some();
+                if algorithm == AllocatorAlgorithm::Backtracking {
+                    assert_eq!(stack, pos == OperandPos::AfterLate);
+                } else {
+                    assert!(stack);
+                }
+                let mut checker = Checker::new(&func);
+                checker.prepare(&out);
+                checker.run().expect("checker failed");
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_branch_defs() {
+        // The branch passes v0 to the exit's blockparam v1 and defines
+        // a link register v2 in p3, which the exit reads in p4: v2 is
+        // live out of the branch, and moved to p4 after it.
+        let env = machine_env();
+        let p = |i: usize| env.regs[i];
+        let mut builder = FuncBuilder::new();
+        let (entry, exit) = (builder.block(), builder.block());
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder.inst(entry, &[Operand::reg_def(v0)], &[]);
+        builder.add_edge(entry, exit);
+        let operands = vec![Operand::reg_use(v0), Operand::reg_fixed_def(v2, p(3))];
+        builder.add_inst(entry, InstData::new(InstOpcode::Branch, operands, vec![]));
+        builder
+            .params(exit, &[v1])
+            .inst(
+                exit,
+                &[Operand::reg_fixed_use(v2, p(4)), Operand::reg_use(v1)],
+                &[],
+            )
+            .ret(exit);
+        let func = builder.finalize();
+        for algorithm in [AllocatorAlgorithm::Backtracking, AllocatorAlgorithm::Fast] {
+            let options = RegallocOptions {
+                algorithm,
+                ..checked()
+            };
+            let out = run_with_options(&func, &env, &options).expect("regalloc did not succeed");
+            let reg = |i: usize| Allocation::reg(p(i));
+            assert_eq!(out.inst_allocs(Inst::new(1))[1], reg(3));
+            assert_eq!(out.inst_allocs(Inst::new(2))[0], reg(4));
+            let mut checker = Checker::new(&func);
+            checker.prepare(&out);
+            checker.run().expect("checker failed");
+            for seed in 0..4 {
+                if let Err(e) = fuzzing::interp::run(&func, &out, seed, 10 * func.insts()) {
+                    panic!("interpreter found a mismatch: {}", e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_transfer_args() {
+        let env = machine_env();
+        // An arg spilled across a call is passed from its spillslot,
+        // rather than reloaded for the branch.
+        let int = |i| PReg::new(i, RegClass::Int);
+        let mut builder = FuncBuilder::new();
+        let (entry, exit) = (builder.block(), builder.block());
+        let (arg, param) = (builder.vreg(), builder.vreg());
+        let clobbers: Vec<PReg> = (0..31).map(int).collect();
+        builder.inst(entry, &[Operand::reg_def(arg)], &[]);
+        builder.inst(entry, &[], &clobbers);
+        builder.add_edge(entry, exit);
+        let branch = InstData::new(InstOpcode::Branch, vec![Operand::transfer_use(arg)], vec![]);
+        builder.add_inst(entry, branch);
+        builder.params(exit, &[param]);
+        builder
+            .inst(exit, &[Operand::reg_use(param)], &[])
+            .ret(exit);
+        let func = builder.build().unwrap();
+
+        let out = run_checked(&func, &env).expect("regalloc did not succeed");
+        assert!(out.inst_allocs(Inst::new(2))[0].as_stack().is_some());
+        let mut checker = Checker::new(&func);
+        checker.prepare(&out);
+        checker.run().expect("checker failed");
+        for seed in 0..4 {
+            if let Err(e) = fuzzing::interp::run(&func, &out, seed, 10 * func.insts()) {
+                panic!("interpreter found a mismatch: {}", e);
+            }
+        }
+
+        // Only the uses of a branch are read at the transfer.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let vreg = builder.vreg();
+        builder.inst(block, &[Operand::reg_def(vreg)], &[]);
+        builder
+            .inst(block, &[Operand::transfer_use(vreg)], &[])
+            .ret(block);
+        assert!(matches!(
+            builder.build(),
+            Err(RegAllocError::Transfer(inst)) if inst == Inst::new(1)
+        ));
+    }
+
+    /// Whether every block is reachable from the entry block.
+    fn all_reachable(succs: &[Vec<usize>]) -> bool {
+        let mut seen = vec![false; succs.len()];
+        let mut stack = vec![0];
+        seen[0] = true;
+        while let Some(block) = stack.pop() {
+            for &succ in &succs[block] {
+                if !seen[succ] {
+                    seen[succ] = true;
+                    stack.push(succ);
+                }
+            }
+        }
+        seen.iter().all(|&seen| seen)
+    }
+
+    /// Split critical edges, as the allocator requires, by inserting
+    /// an edge block on each.
+    fn split_critical_edges(mut succs: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+        let mut preds = vec![0; succs.len()];
+        for block_succs in &succs {
+            for &succ in block_succs {
+                preds[succ] += 1;
+            }
+        }
+        for block in 0..succs.len() {
+            if succs[block].len() < 2 {
+                continue;
+            }
+            for i in 0..succs[block].len() {
+                let succ = succs[block][i];
+                if preds[succ] > 1 {
+                    succs[block][i] = succs.len();
+                    succs.push(vec![succ]);
+                }
+            }
+        }
+        succs
+    }
+
+    /// A function over the given CFG. Two values defined in the entry
+    /// block are used in every block; every block defines one more
+    /// with an instruction of the given constraint pattern, and passes
+    /// it to its successors' blockparams, if `params`.
+    fn small_cfg_func(succs: &[Vec<usize>], params: bool, pattern: usize) -> Func {
+        let mut builder = FuncBuilder::new();
+        let blocks: Vec<Block> = succs.iter().map(|_| builder.block()).collect();
+        let a = builder.vreg();
+        let b = builder.vreg();
+        builder.inst(blocks[0], &[Operand::reg_def(a)], &[]).inst(
+            blocks[0],
+            &[Operand::reg_def(b)],
+            &[],
+        );
+        let block_params: Vec<Option<VReg>> = blocks
+            .iter()
+            .map(|&block| {
+                if params && block != blocks[0] {
+                    let param = builder.vreg();
+                    builder.params(block, &[param]);
+                    Some(param)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let p0 = PReg::new(0, RegClass::Int);
+        let p1 = PReg::new(1, RegClass::Int);
+        for (&block, block_succs) in blocks.iter().zip(succs) {
+            let input = block_params[block.index()].unwrap_or(a);
+            let def = builder.vreg();
+            match pattern {
+                0 => builder.inst(
+                    block,
+                    &[
+                        Operand::reg_def(def),
+                        Operand::reg_use(input),
+                        Operand::reg_use(b),
+                    ],
+                    &[],
+                ),
+                1 => builder.inst(
+                    block,
+                    &[
+                        Operand::reg_fixed_def(def, p0),
+                        Operand::reg_fixed_use(input, p1),
+                        Operand::reg_use(b),
+                    ],
+                    &[],
+                ),
+                2 => builder.inst(
+                    block,
+                    &[
+                        Operand::reg_reuse_def(def, 1),
+                        Operand::reg_use(input),
+                        Operand::reg_use(b),
+                    ],
+                    &[],
+                ),
+                _ => {
+                    builder.add_inst(
+                        block,
+                        InstData::new(
+                            InstOpcode::Call,
+                            vec![
+                                Operand::reg_fixed_def(def, p0),
+                                Operand::reg_fixed_use(input, p1),
+                                Operand::reg_use(b),
+                            ],
+                            (2..24).map(|i| PReg::new(i, RegClass::Int)).collect(),
+                        ),
+                    );
+                    &mut builder
+                }
+            };
+            if block_succs.is_empty() {
+                builder.ret(block);
+            } else {
+                let succs: Vec<Block> = block_succs.iter().map(|&succ| blocks[succ]).collect();
+                let args: Vec<VReg> = block_succs
+                    .iter()
+                    .filter(|&&succ| block_params[succ].is_some())
+                    .map(|_| def)
+                    .collect();
+                builder.branch(block, &succs, &args);
+            }
+        }
+        builder.build().expect("invalid function")
+    }
+
+    #[test]
+    #[cfg(feature = "checker")]
+    fn test_small_cfgs() {
+        // Every CFG of up to four blocks where each block has at most
+        // two successors, none of them the entry block, and every
+        // block is reachable: straight lines, diamonds, self-loops,
+        // nested and irreducible loops, and critical edges (split).
+        let env = machine_env();
+        let mut shapes = 0;
+        for num_blocks in 1..=4 {
+            let mut choices: Vec<Vec<usize>> = vec![vec![]];
+            for a in 1..num_blocks {
+                choices.push(vec![a]);
+                for b in (a + 1)..num_blocks {
+                    choices.push(vec![a, b]);
+                }
+            }
+            for shape in 0..choices.len().pow(num_blocks as u32) {
+                let mut rest = shape;
+                let mut succs = vec![];
+                for _ in 0..num_blocks {
+                    succs.push(choices[rest % choices.len()].clone());
+                    rest /= choices.len();
+                }
+                if !all_reachable(&succs) {
+                    continue;
+                }
+                let succs = split_critical_edges(succs);
+                shapes += 1;
+                for &params in &[false, true] {
+                    for pattern in 0..4 {
+                        let func = small_cfg_func(&succs, params, pattern);
+                        let out = run_checked(&func, &env).unwrap_or_else(|e| {
+                            panic!("regalloc failed on {:?}: {}\n{:?}", succs, e, func)
+                        });
+                        let mut checker = Checker::new(&func);
+                        checker.prepare(&out);
+                        if let Err(e) = checker.run() {
+                            panic!("checker failed on {:?}: {}\n{:?}\n{}", succs, e, func, out);
+                        }
+                        for seed in 0..2 {
+                            if let Err(e) = fuzzing::interp::run(&func, &out, seed, 100) {
+                                panic!("interpreter failed on {:?}: {}\n{:?}", succs, e, func);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert_eq!(shapes, 1127);
+    }
+
+    #[test]
+    fn test_prog_point_index_roundtrip() {
+        let last = Inst::new(ProgPoint::MAX_INSTS);
+        for &inst in &[Inst::new(0), Inst::new(1), last.prev(), last] {
+            for &point in &[ProgPoint::before(inst), ProgPoint::after(inst)] {
+                for &point in &[point, point.late()] {
+                    assert_eq!(ProgPoint::from_index(point.to_index()), point);
+                }
+            }
+        }
+        // Packing preserves the order of points.
+        let points = [
+            ProgPoint::after(last.prev()).late(),
+            ProgPoint::before(last),
+            ProgPoint::before(last).late(),
+            ProgPoint::after(last),
+        ];
+        for pair in points.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!(pair[0].to_index() < pair[1].to_index());
+        }
+    }
 }
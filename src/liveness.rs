@@ -0,0 +1,316 @@
+//! Liveness analysis as a standalone pass over a `Function`, apart
+//! from the allocator: e.g. to estimate register pressure before
+//! allocation, or for an embedder's own passes after it.
+//!
+//! The allocator computes the same sets and ranges together with its
+//! own live-range data, which it could not share with an embedder.
+
+use crate::bitvec::BitVec;
+use crate::cfg::CFGInfo;
+use crate::ion::CodeRange;
+use crate::{Block, Function, Inst, Operand, OperandKind, ProgPoint, RegAllocError, VReg};
+use smallvec::{smallvec, SmallVec};
+use std::collections::VecDeque;
+
+#[derive(Clone, Debug)]
+pub struct LivenessInfo {
+    /// For each block, the vregs live on entry to it, other than its
+    /// blockparams.
+    pub livein: Vec<BitVec>,
+    /// For each block, the vregs live on exit from it, including the
+    /// args its branch passes to its successors' blockparams.
+    pub liveout: Vec<BitVec>,
+    /// For each vreg, the disjoint ranges over which it is live, in
+    /// program order. A vreg that is defined but never used is live
+    /// just at its def.
+    pub ranges: Vec<Vec<CodeRange>>,
+}
+
+impl LivenessInfo {
+    /// Compute liveness for `f`, which must pass `ssa::validate_ssa`.
+    /// Fails if a use is reachable from the entry block without
+    /// passing a def of its vreg.
+    pub fn new<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<LivenessInfo, RegAllocError> {
+        let mut livein = vec![BitVec::new(); f.blocks()];
+        let mut liveout = vec![BitVec::new(); f.blocks()];
+
+        // Iterate backward over the blocks until no set changes. If
+        // the blocks are in RPO, each sees its successors' final sets
+        // but for backedges, which take a further pass.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..f.blocks()).rev() {
+                let block = Block::new(block);
+                let mut live = BitVec::with_capacity(f.num_vregs());
+                for &succ in f.block_succs(block) {
+                    live.or(&livein[succ.index()]);
+                }
+                for inst in f.block_insns(block).rev().iter() {
+                    let operands = inst_operands(f, inst);
+                    for op in &operands {
+                        if op.kind() == OperandKind::Def {
+                            live.set(op.vreg().vreg(), false);
+                        }
+                    }
+                    for op in &operands {
+                        if op.kind() != OperandKind::Def {
+                            live.set(op.vreg().vreg(), true);
+                        }
+                    }
+                }
+                for param in f.block_params(block) {
+                    live.set(param.vreg(), false);
+                }
+                if !live.iter().eq(livein[block.index()].iter()) {
+                    livein[block.index()] = live;
+                    changed = true;
+                }
+            }
+        }
+        for block in 0..f.blocks() {
+            let block = Block::new(block);
+            let out = &mut liveout[block.index()];
+            for &succ in f.block_succs(block) {
+                out.or(&livein[succ.index()]);
+            }
+            let last = f.block_insns(block).last();
+            if f.is_branch(last) {
+                for op in inst_operands(f, last) {
+                    if op.kind() == OperandKind::Use {
+                        out.set(op.vreg().vreg(), true);
+                    }
+                }
+            }
+        }
+
+        if let Some(vreg) = livein[f.entry_block().index()].iter().next() {
+            if let Some((vreg, inst)) = find_use_before_def(f, vreg) {
+                return Err(RegAllocError::UseBeforeDef(vreg, inst));
+            }
+        }
+
+        // Build ranges one block at a time, walking backward from the
+        // block's exit with the end of each live vreg's range so far.
+        let mut ranges: Vec<Vec<CodeRange>> = vec![vec![]; f.num_vregs()];
+        let mut ends: Vec<Option<ProgPoint>> = vec![None; f.num_vregs()];
+        for block in 0..f.blocks() {
+            let block = Block::new(block);
+            let entry = cfginfo.block_entry[block.index()];
+            let exit = cfginfo.block_exit[block.index()].next();
+            for vreg in liveout[block.index()].iter() {
+                ends[vreg] = Some(exit);
+            }
+            for inst in f.block_insns(block).rev().iter() {
+                // A vreg redefined here in a non-SSA function is live
+                // into the instruction too, so take its range below
+                // before extending it to any use here.
+                let mut operands = inst_operands(f, inst);
+                operands.sort_by_key(|op| op.kind() != OperandKind::Def);
+                for op in operands {
+                    let vreg = op.vreg().vreg();
+                    match op.kind() {
+                        OperandKind::Def => {
+                            let pos = op.pos().at(inst);
+                            let end = ends[vreg].take().unwrap_or(pos.next());
+                            ranges[vreg].push(CodeRange::new(pos, end));
+                        }
+                        // A branch's uses are live out; see above.
+                        OperandKind::Use if f.is_branch(inst) => {}
+                        OperandKind::Use => {
+                            let end = op.pos().at(inst).next();
+                            if ends[vreg] < Some(end) {
+                                ends[vreg] = Some(end);
+                            }
+                        }
+                        // Written back at `After`, where the new value
+                        // is live even if never read.
+                        OperandKind::Mod => {
+                            let end = ProgPoint::after(inst).next();
+                            if ends[vreg] < Some(end) {
+                                ends[vreg] = Some(end);
+                            }
+                        }
+                    }
+                }
+            }
+            for param in f.block_params(block) {
+                let end = ends[param.vreg()].take().unwrap_or(entry.next());
+                ranges[param.vreg()].push(CodeRange::new(entry, end));
+            }
+            for vreg in livein[block.index()].iter() {
+                let end = ends[vreg].take().unwrap();
+                ranges[vreg].push(CodeRange::new(entry, end));
+            }
+            debug_assert!(ends.iter().all(|end| end.is_none()));
+        }
+
+        // Sort each vreg's ranges, joining those that abut.
+        for vreg_ranges in &mut ranges {
+            vreg_ranges.sort_by_key(|r| r.from());
+            let mut joined: Vec<CodeRange> = Vec::with_capacity(vreg_ranges.len());
+            for &range in vreg_ranges.iter() {
+                match joined.last_mut() {
+                    Some(last) if last.to() == range.from() => {
+                        *last = CodeRange::new(last.from(), range.to());
+                    }
+                    _ => joined.push(range),
+                }
+            }
+            *vreg_ranges = joined;
+        }
+
+        Ok(LivenessInfo {
+            livein,
+            liveout,
+            ranges,
+        })
+    }
+
+    /// Whether `vreg` is live on entry to `block`.
+    pub fn is_live_in(&self, block: Block, vreg: VReg) -> bool {
+        self.livein[block.index()].get(vreg.vreg())
+    }
+
+    /// Whether `vreg` is live on exit from `block`.
+    pub fn is_live_out(&self, block: Block, vreg: VReg) -> bool {
+        self.liveout[block.index()].get(vreg.vreg())
+    }
+
+    /// The ranges over which `vreg` is live, in program order.
+    pub fn vreg_ranges(&self, vreg: VReg) -> &[CodeRange] {
+        &self.ranges[vreg.vreg()]
+    }
+
+    /// Whether `vreg` is live at `point`.
+    pub fn is_live_at(&self, vreg: VReg, point: ProgPoint) -> bool {
+        let ranges = self.vreg_ranges(vreg);
+        let i = ranges.partition_point(|r| r.to() <= point);
+        ranges.get(i).is_some_and(|r| r.contains_point(point))
+    }
+}
+
+pub(crate) fn inst_operands<F: Function>(f: &F, inst: Inst) -> SmallVec<[Operand; 8]> {
+    let mut operands = smallvec![];
+    f.visit_operands(inst, &mut |_, op| operands.push(op));
+    operands
+}
+
+/// Find a use of the vreg with index `vreg` that is reachable from
+/// the entry block without passing a def of it, if there is one.
+pub(crate) fn find_use_before_def<F: Function>(func: &F, vreg: usize) -> Option<(VReg, Inst)> {
+    let mut visited = vec![false; func.blocks()];
+    let mut worklist = VecDeque::new();
+    visited[func.entry_block().index()] = true;
+    worklist.push_back(func.entry_block());
+    'blocks: while let Some(block) = worklist.pop_front() {
+        if func.block_params(block).iter().any(|p| p.vreg() == vreg) {
+            continue;
+        }
+        for inst in func.block_insns(block).iter() {
+            let operands = inst_operands(func, inst);
+            if let Some(op) = operands
+                .iter()
+                .find(|op| op.kind() != OperandKind::Def && op.vreg().vreg() == vreg)
+            {
+                return Some((op.vreg(), inst));
+            }
+            if operands.iter().any(|op| op.vreg().vreg() == vreg) {
+                continue 'blocks;
+            }
+        }
+        for &succ in func.block_succs(block) {
+            if !visited[succ.index()] {
+                visited[succ.index()] = true;
+                worklist.push_back(succ);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzzing::func::{machine_env, FuncBuilder};
+    use crate::ion::Liveness;
+    use crate::RegallocOptions;
+
+    #[test]
+    fn test_liveness_info() {
+        // A loop: v0 is live around it, v1 past it, and v2, defined in
+        // the header, into the body only.
+        let mut builder = FuncBuilder::new();
+        let (entry, header, body, exit) = (
+            builder.block(),
+            builder.block(),
+            builder.block(),
+            builder.block(),
+        );
+        let (v0, v1, v2) = (builder.vreg(), builder.vreg(), builder.vreg());
+        builder
+            .inst(entry, &[Operand::reg_def(v0)], &[])
+            .inst(entry, &[Operand::reg_def(v1)], &[])
+            .branch(entry, &[header], &[])
+            .inst(header, &[Operand::reg_def(v2)], &[])
+            .branch(header, &[body, exit], &[])
+            .inst(body, &[Operand::reg_use(v0), Operand::reg_use(v2)], &[])
+            .branch(body, &[header], &[])
+            .inst(exit, &[Operand::reg_use(v1)], &[])
+            .ret(exit);
+        let func = builder.build().unwrap();
+        let cfginfo = CFGInfo::new(&func);
+        let liveness = LivenessInfo::new(&func, &cfginfo).expect("liveness failed");
+        let set = |bits: &BitVec| bits.iter().collect::<Vec<_>>();
+        let livein: Vec<_> = liveness.livein.iter().map(set).collect();
+        let liveout: Vec<_> = liveness.liveout.iter().map(set).collect();
+        assert_eq!(livein, vec![vec![], vec![0, 1], vec![0, 1, 2], vec![1]]);
+        assert_eq!(liveout, vec![vec![0, 1], vec![0, 1, 2], vec![0, 1], vec![]]);
+        let point = |inst| ProgPoint::before(Inst::new(inst));
+        let after = |inst| ProgPoint::after(Inst::new(inst));
+        assert_eq!(
+            liveness.vreg_ranges(v0),
+            &[CodeRange::new(after(0), point(7))]
+        );
+        assert_eq!(
+            liveness.vreg_ranges(v1),
+            &[CodeRange::new(after(1), point(7).next())]
+        );
+        assert_eq!(
+            liveness.vreg_ranges(v2),
+            &[CodeRange::new(after(3), point(5).next())]
+        );
+        assert!(liveness.is_live_at(v0, point(3)));
+        assert!(!liveness.is_live_at(v0, point(7)));
+        assert!(!liveness.is_live_at(v2, point(6)));
+
+        // The exact ranges lie within the allocator's own.
+        let (env, options) = (machine_env(), RegallocOptions::default());
+        let ion = Liveness::compute(&func, &env, &options).expect("liveness failed");
+        for vreg in [v0, v1, v2] {
+            let ion_ranges = ion.vreg_ranges(vreg);
+            for range in liveness.vreg_ranges(vreg) {
+                for point in range.from().to_index()..range.to().to_index() {
+                    let point = ProgPoint::from_index(point);
+                    assert!(
+                        ion_ranges.iter().any(|r| r.contains_point(point)),
+                        "{} at {:?}",
+                        vreg,
+                        point
+                    );
+                }
+            }
+        }
+
+        // A use that no def reaches is an error.
+        let mut builder = FuncBuilder::new();
+        let block = builder.block();
+        let v0 = builder.vreg();
+        builder.inst(block, &[Operand::reg_use(v0)], &[]).ret(block);
+        let func = builder.finalize();
+        assert!(matches!(
+            LivenessInfo::new(&func, &CFGInfo::new(&func)),
+            Err(RegAllocError::UseBeforeDef(vreg, inst)) if vreg == v0 && inst == Inst::new(0)
+        ));
+    }
+}
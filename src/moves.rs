@@ -3,25 +3,47 @@ use smallvec::{smallvec, SmallVec};
 
 pub type MoveVec = SmallVec<[(Allocation, Allocation); 16]>;
 
+/// The storage units an allocation occupies, e.g. `[0, 1]` for a
+/// register D0 made of S0 (unit 0) and S1 (unit 1). Two allocations
+/// overlap if they share a unit.
+pub type AliasUnits = SmallVec<[u32; 4]>;
+
 /// A `ParallelMoves` represents a list of alloc-to-alloc moves that
 /// must happen in parallel -- i.e., all reads of sources semantically
 /// happen before all writes of destinations, and destinations are
 /// allowed to overwrite sources. It can compute a list of sequential
 /// moves that will produce the equivalent data movement, possibly
 /// using a scratch register if one is necessary.
-pub struct ParallelMoves {
+///
+/// Two allocations are assumed to be either identical or entirely
+/// independent, unless `with_alias_units()` says which units each
+/// occupies: then moves are ordered against every move that reads or
+/// writes any of the same units, so that moving D0 waits for a read
+/// of S1. The scratch must then be as wide as any allocation in a
+/// cycle, and overlap none of the moves; a set of moves that would
+/// need a second scratch is a panic.
+pub struct ParallelMoves<'a> {
     parallel_moves: MoveVec,
     scratch: Allocation,
+    alias_units: Option<&'a dyn Fn(Allocation) -> AliasUnits>,
 }
 
-impl ParallelMoves {
+impl<'a> ParallelMoves<'a> {
     pub fn new(scratch: Allocation) -> Self {
         Self {
             parallel_moves: smallvec![],
             scratch,
+            alias_units: None,
         }
     }
 
+    /// Resolve the moves over the units that `alias_units` gives for
+    /// each allocation, rather than over whole allocations.
+    pub fn with_alias_units(mut self, alias_units: &'a dyn Fn(Allocation) -> AliasUnits) -> Self {
+        self.alias_units = Some(alias_units);
+        self
+    }
+
     pub fn add(&mut self, from: Allocation, to: Allocation) {
         self.parallel_moves.push((from, to));
     }
@@ -46,6 +68,10 @@ impl ParallelMoves {
             return self.parallel_moves;
         }
 
+        if let Some(alias_units) = self.alias_units {
+            return self.resolve_by_units(alias_units);
+        }
+
         // Sort moves by source so that we can efficiently test for
         // presence.
         self.parallel_moves.sort();
@@ -167,6 +193,10 @@ impl ParallelMoves {
                     //     C := B
                     //     B := A
                     //     A := scratch
+                    assert!(
+                        self.scratch != Allocation::none(),
+                        "no scratch register available to break a move cycle"
+                    );
                     let mut last_dst = None;
                     let mut scratch_src = None;
                     while let Some(move_idx) = stack.pop() {
@@ -196,4 +226,339 @@ impl ParallelMoves {
         ret.reverse();
         ret
     }
+
+    /// Resolve moves whose allocations may partially overlap. A
+    /// destination may then overlap the sources of several moves, so
+    /// the dependencies no longer form simple cycles: emit any move
+    /// whose destination no pending move still reads, and when none
+    /// is left, copy one move's source into the scratch first. Once a
+    /// source is copied, the moves still to read it read the copy,
+    /// which no move overwrites, instead.
+    fn resolve_by_units(self, alias_units: &dyn Fn(Allocation) -> AliasUnits) -> MoveVec {
+        let mut moves: MoveVec = smallvec![];
+        let mut src_units: SmallVec<[AliasUnits; 16]> = smallvec![];
+        let mut dst_units: SmallVec<[AliasUnits; 16]> = smallvec![];
+        for &(src, dst) in &self.parallel_moves {
+            if src != dst {
+                moves.push((src, dst));
+                src_units.push(alias_units(src));
+                dst_units.push(alias_units(dst));
+            }
+        }
+        if cfg!(debug_assertions) {
+            // As above, each unit has only one writer.
+            for i in 0..dst_units.len() {
+                for j in 0..i {
+                    assert!(!overlap(&dst_units[i], &dst_units[j]));
+                }
+            }
+        }
+
+        let mut ret: MoveVec = smallvec![];
+        let mut pending: SmallVec<[usize; 16]> = (0..moves.len()).collect();
+        while !pending.is_empty() {
+            if let Some(i) = step(&mut moves, &mut src_units, &dst_units, &mut pending) {
+                ret.push(moves[i]);
+                continue;
+            }
+
+            // Every pending move waits on another. Copy a source such
+            // that the moves then go ahead at least until the last
+            // read of the copy frees the scratch again; try the widest
+            // sources first, as others are most likely to wait on
+            // them.
+            assert!(
+                self.scratch != Allocation::none(),
+                "no scratch register available to break a move cycle"
+            );
+            let frees_scratch = |i: usize| {
+                let mut moves = moves.clone();
+                let mut src_units = src_units.clone();
+                let mut pending = pending.clone();
+                let from = moves[i].0;
+                copied(&mut moves, &mut src_units, &pending, from, self.scratch);
+                while pending.iter().any(|&j| moves[j].0 == self.scratch) {
+                    if step(&mut moves, &mut src_units, &dst_units, &mut pending).is_none() {
+                        return false;
+                    }
+                }
+                true
+            };
+            let mut candidates = pending.clone();
+            candidates.sort_by_key(|&i| std::cmp::Reverse(src_units[i].len()));
+            let i = candidates
+                .into_iter()
+                .find(|&i| frees_scratch(i))
+                .expect("breaking these move cycles needs more than one scratch register");
+            let from = moves[i].0;
+            ret.push((from, self.scratch));
+            copied(&mut moves, &mut src_units, &pending, from, self.scratch);
+        }
+        ret
+    }
+}
+
+fn overlap(a: &AliasUnits, b: &AliasUnits) -> bool {
+    a.iter().any(|unit| b.contains(unit))
+}
+
+/// Remove and return the first of the `pending` moves that
+/// overwrites no unit another still reads, if any; see
+/// `ParallelMoves::resolve_by_units()`.
+fn step(
+    moves: &mut MoveVec,
+    src_units: &mut [AliasUnits],
+    dst_units: &[AliasUnits],
+    pending: &mut SmallVec<[usize; 16]>,
+) -> Option<usize> {
+    let k = (0..pending.len()).find(|&k| {
+        let i = pending[k];
+        pending
+            .iter()
+            .all(|&j| j == i || !overlap(&dst_units[i], &src_units[j]))
+    })?;
+    let i = pending.remove(k);
+    let (from, to) = moves[i];
+    copied(moves, src_units, pending, from, to);
+    Some(i)
+}
+
+/// Point the `pending` moves that read `from` at `to`, which now holds
+/// a copy of it that no move overwrites.
+fn copied(
+    moves: &mut MoveVec,
+    src_units: &mut [AliasUnits],
+    pending: &[usize],
+    from: Allocation,
+    to: Allocation,
+) {
+    for &j in pending {
+        if moves[j].0 == from {
+            moves[j].0 = to;
+            src_units[j] = smallvec![];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{PReg, RegClass, SpillSlot};
+    use std::collections::HashMap;
+
+    // Registers 0-7 are S0-S7, one unit each; registers 8-11 are
+    // D0-D3, each made of two S registers, as on ARM VFP.
+    fn s(i: usize) -> Allocation {
+        Allocation::reg(PReg::new(i, RegClass::Float))
+    }
+
+    fn d(i: usize) -> Allocation {
+        Allocation::reg(PReg::new(8 + i, RegClass::Float))
+    }
+
+    fn units(alloc: Allocation) -> AliasUnits {
+        match alloc.as_reg() {
+            Some(preg) if preg.hw_enc() < 8 => smallvec![preg.hw_enc() as u32],
+            Some(preg) => {
+                let d = preg.hw_enc() as u32 - 8;
+                smallvec![2 * d, 2 * d + 1]
+            }
+            None => smallvec![100 + alloc.as_stack().unwrap().index() as u32],
+        }
+    }
+
+    /// Resolve `moves` with D3 as the scratch, and check that doing
+    /// the result in sequence leaves each unit as the parallel moves
+    /// would. Returns the sequence.
+    fn check(moves: &[(Allocation, Allocation)]) -> MoveVec {
+        let mut parallel_moves = ParallelMoves::new(d(3)).with_alias_units(&units);
+        for &(src, dst) in moves {
+            parallel_moves.add(src, dst);
+        }
+        let resolved = parallel_moves.resolve();
+
+        // Each unit starts out holding its own number.
+        let mut expected: HashMap<u32, u32> = HashMap::new();
+        for &(src, dst) in moves {
+            for (&from, &to) in units(src).iter().zip(units(dst).iter()) {
+                expected.insert(to, from);
+            }
+        }
+        let mut state: HashMap<u32, u32> = HashMap::new();
+        for &(src, dst) in &resolved {
+            let values: Vec<u32> = units(src)
+                .iter()
+                .map(|unit| state.get(unit).copied().unwrap_or(*unit))
+                .collect();
+            for (&to, value) in units(dst).iter().zip(values) {
+                state.insert(to, value);
+            }
+        }
+        let scratch = units(d(3));
+        for (unit, value) in state {
+            if !scratch.contains(&unit) {
+                let want = expected.get(&unit).copied().unwrap_or(unit);
+                assert_eq!(value, want, "{:?} resolved to {:?}", moves, resolved);
+            }
+        }
+        resolved
+    }
+
+    #[test]
+    fn test_partial_overlap_order() {
+        // D0 := D1 reads S2, so writing S2 must wait for it.
+        let resolved = check(&[(s(5), s(2)), (d(1), d(0))]);
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[0], (d(1), d(0)));
+
+        // A whole-register resolver would see no conflict here.
+        let resolved = check(&[(s(0), s(6)), (d(2), d(0))]);
+        assert_eq!(resolved[0], (s(0), s(6)));
+    }
+
+    #[test]
+    fn test_partial_overlap_cycle() {
+        // S1 := S2 overwrites half of D0, which D1 := D0 reads, and
+        // D1 := D0 overwrites S2: a cycle through a partial overlap.
+        let resolved = check(&[(s(2), s(1)), (d(0), d(1))]);
+        assert_eq!(resolved.len(), 3);
+        assert!(resolved.iter().any(|&(_, dst)| dst == d(3)));
+
+        // Swapping D0 and D1, with D0's halves moved one at a time.
+        let resolved = check(&[(s(0), s(2)), (s(1), s(3)), (d(1), d(0))]);
+        assert_eq!(resolved.len(), 4);
+
+        // Without a partial overlap, it matches the whole-register
+        // resolver.
+        let resolved = check(&[(d(0), d(1)), (d(1), d(0))]);
+        let mut whole = ParallelMoves::new(d(3));
+        whole.add(d(0), d(1));
+        whole.add(d(1), d(0));
+        assert_eq!(resolved.len(), whole.resolve().len());
+    }
+
+    #[test]
+    fn test_partial_overlap_random() {
+        // Random moves among S0-S5, D0-D2 and a few spillslots, each
+        // unit written at most once, and each source of the same
+        // width as its destination.
+        let slots: Vec<Allocation> = (0..3)
+            .map(|i| Allocation::stack(SpillSlot::new(i, RegClass::Float)))
+            .collect();
+        let narrow: Vec<Allocation> = (0..6).map(s).chain(slots).collect();
+        let wide: Vec<Allocation> = (0..3).map(d).collect();
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        let mut next = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+        let mut scratched = 0;
+        for _ in 0..10000 {
+            let mut moves: Vec<(Allocation, Allocation)> = vec![];
+            let mut written: Vec<u32> = vec![];
+            for _ in 0..6 {
+                let (src, dst) = if next(2) == 0 {
+                    (wide[next(wide.len())], wide[next(wide.len())])
+                } else {
+                    (narrow[next(narrow.len())], narrow[next(narrow.len())])
+                };
+                if units(dst).iter().any(|unit| written.contains(unit)) {
+                    continue;
+                }
+                written.extend(units(dst));
+                moves.push((src, dst));
+            }
+            let resolved = check(&moves);
+            scratched += resolved.iter().any(|&(_, dst)| dst == d(3)) as usize;
+        }
+        assert!(scratched > 0);
+    }
+
+    #[test]
+    fn test_parallel_moves() {
+        // Random sets of parallel moves among a few registers and
+        // spillslots, with each location written at most once but
+        // read any number of times: cycles, fan-out, and self-moves.
+        let locs: Vec<Allocation> = (0..6)
+            .map(|i| Allocation::reg(PReg::new(i, RegClass::Int)))
+            .chain((0..3).map(|i| Allocation::stack(SpillSlot::new(i, RegClass::Int))))
+            .collect();
+        let scratch = Allocation::reg(PReg::new(31, RegClass::Int));
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        let mut next = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+        let mut cycles = 0;
+        for _ in 0..10000 {
+            let mut moves = vec![];
+            for &dst in &locs {
+                if next(3) != 0 {
+                    moves.push((locs[next(locs.len())], dst));
+                }
+            }
+            for i in (1..moves.len()).rev() {
+                moves.swap(i, next(i + 1));
+            }
+
+            // Is there a cycle among the moves, i.e. does following
+            // each dest back to the source written into it ever
+            // revisit a location?
+            let src_of: HashMap<Allocation, Allocation> =
+                moves.iter().map(|&(src, dst)| (dst, src)).collect();
+            let has_cycle = moves.iter().any(|&(_, dst)| {
+                let mut loc = dst;
+                for _ in 0..=moves.len() {
+                    match src_of.get(&loc) {
+                        Some(&src) if src == dst => return true,
+                        Some(&src) => loc = src,
+                        None => return false,
+                    }
+                }
+                false
+            });
+            cycles += has_cycle as usize;
+
+            let expected: HashMap<Allocation, Allocation> = locs
+                .iter()
+                .map(|&loc| (loc, src_of.get(&loc).copied().unwrap_or(loc)))
+                .collect();
+            let scratches: &[Allocation] = if has_cycle {
+                &[scratch]
+            } else {
+                &[scratch, Allocation::none()]
+            };
+            for &scratch in scratches {
+                let mut parallel_moves = ParallelMoves::new(scratch);
+                for &(src, dst) in &moves {
+                    parallel_moves.add(src, dst);
+                }
+                // Each location starts out holding its own name.
+                let mut state: HashMap<Allocation, Allocation> =
+                    locs.iter().map(|&loc| (loc, loc)).collect();
+                for (src, dst) in parallel_moves.resolve() {
+                    for loc in [src, dst].iter() {
+                        assert!(
+                            src_of.contains_key(loc)
+                                || moves.iter().any(|&(src, _)| src == *loc)
+                                || (*loc == scratch && has_cycle),
+                            "{:?} touches {} beyond the moves and scratch",
+                            moves,
+                            loc
+                        );
+                    }
+                    let value = state[&src];
+                    state.insert(dst, value);
+                }
+                for &loc in &locs {
+                    assert_eq!(state[&loc], expected[&loc], "{:?} at {}", moves, loc);
+                }
+            }
+        }
+        assert!(cycles > 1000);
+    }
 }
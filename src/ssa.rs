@@ -1,8 +1,11 @@
 //! SSA-related utilities.
 
 use crate::cfg::CFGInfo;
+use crate::liveness::inst_operands;
 
-use crate::{Block, Function, Inst, OperandKind, RegAllocError};
+use crate::{
+    Block, Function, Inst, Operand, OperandKind, OperandPolicy, OperandPos, RegAllocError,
+};
 
 pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllocError> {
     // Walk the blocks in arbitrary order. Check, for every use, that
@@ -10,7 +13,15 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
     // defined (by inst or blockparam) in some other block that
     // dominates this one. Also check that for every block param and
     // inst def, that this is the only def.
+    //
+    // A non-SSA function may define a vreg more than once, so there
+    // is no one def to dominate its uses; liveness instead checks
+    // that a def reaches every use. Here, check only that the vregs
+    // that must be defined once are, and the shape of each
+    // instruction's defs and `Mod` operands.
+    let ssa = f.is_ssa();
     let mut defined = vec![false; f.num_vregs()];
+    let mut single_def = vec![false; f.num_vregs()];
     for block in 0..f.blocks() {
         let block = Block::new(block);
         for blockparam in f.block_params(block) {
@@ -18,29 +29,104 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
                 return Err(RegAllocError::SSA(*blockparam, Inst::invalid()));
             }
             defined[blockparam.vreg()] = true;
+            single_def[blockparam.vreg()] = true;
         }
         for iix in f.block_insns(block).iter() {
-            let operands = f.inst_operands(iix);
-            for operand in operands {
-                match operand.kind() {
-                    OperandKind::Use => {
-                        let def_block = if cfginfo.vreg_def_inst[operand.vreg().vreg()].is_valid() {
-                            cfginfo.insn_block[cfginfo.vreg_def_inst[operand.vreg().vreg()].index()]
-                        } else {
-                            cfginfo.vreg_def_blockparam[operand.vreg().vreg()].0
-                        };
-                        if def_block.is_invalid() {
-                            return Err(RegAllocError::SSA(operand.vreg(), iix));
-                        }
-                        if !cfginfo.dominates(def_block, block) {
-                            return Err(RegAllocError::SSA(operand.vreg(), iix));
+            let operands = inst_operands(f, iix);
+            for (idx, operand) in operands.iter().enumerate() {
+                if operand.pos() == OperandPos::Transfer
+                    && (!f.is_branch(iix)
+                        || operand.kind() != OperandKind::Use
+                        || operand.policy() != OperandPolicy::Any)
+                {
+                    return Err(RegAllocError::Transfer(iix));
+                }
+                // A use from a spillslot cannot share its instruction
+                // with a use of the same vreg in a register.
+                if operand.kind() == OperandKind::Use
+                    && operand.policy() == OperandPolicy::Stack
+                    && operands.iter().any(|other| {
+                        (other.kind() == OperandKind::Use
+                            && other.vreg() == operand.vreg()
+                            && !matches!(other.policy(), OperandPolicy::Any | OperandPolicy::Stack))
+                            || other.policy() == OperandPolicy::Reuse(idx)
+                    })
+                {
+                    return Err(RegAllocError::StackUseConflict(operand.vreg(), iix));
+                }
+                if let OperandPolicy::FixedStack(slot) = operand.policy() {
+                    let conflict = |(j, other): (usize, &Operand)| {
+                        if other.policy() == OperandPolicy::Reuse(idx) {
+                            return true;
                         }
+                        j != idx
+                            && other.policy() == operand.policy()
+                            && other.kind() == operand.kind()
+                            && (other.kind() == OperandKind::Def || other.vreg() != operand.vreg())
+                    };
+                    if slot.index() >= f.num_reserved_spillslots()
+                        || operands.iter().enumerate().any(conflict)
+                    {
+                        return Err(RegAllocError::FixedStack(slot, iix));
+                    }
+                }
+                if operand.kind() != OperandKind::Use {
+                    if operand.kind() == OperandKind::Mod
+                        && (ssa
+                            || matches!(
+                                operand.policy(),
+                                OperandPolicy::Reuse(_)
+                                    | OperandPolicy::Constant
+                                    | OperandPolicy::FixedStack(_)
+                            ))
+                    {
+                        return Err(RegAllocError::SSA(operand.vreg(), iix));
+                    }
+                    // A vreg both read and written here must be
+                    // written after it is read, so that the two
+                    // values need not be held at once; a `Mod` reads
+                    // and writes its vreg in one place.
+                    let conflict = |(j, other): (usize, &Operand)| {
+                        j != idx
+                            && other.vreg() == operand.vreg()
+                            && (operand.kind() == OperandKind::Mod
+                                || other.kind() != OperandKind::Use
+                                || other.pos() >= operand.pos())
+                    };
+                    if operands.iter().enumerate().any(conflict)
+                        || operands.iter().any(|other| {
+                            operand.kind() == OperandKind::Mod
+                                && other.policy() == OperandPolicy::Reuse(idx)
+                        })
+                    {
+                        return Err(RegAllocError::SSA(operand.vreg(), iix));
                     }
-                    OperandKind::Def => {
-                        if defined[operand.vreg().vreg()] {
+                    let vreg = operand.vreg().vreg();
+                    if operand.policy() == OperandPolicy::Constant
+                        || f.is_rematerializable(operand.vreg()).is_some()
+                    {
+                        if defined[vreg] {
                             return Err(RegAllocError::SSA(operand.vreg(), iix));
                         }
-                        defined[operand.vreg().vreg()] = true;
+                        single_def[vreg] = true;
+                    }
+                    if defined[vreg] && (ssa || single_def[vreg]) {
+                        return Err(RegAllocError::SSA(operand.vreg(), iix));
+                    }
+                    defined[vreg] = true;
+                    continue;
+                }
+                if ssa {
+                    let def_block = if cfginfo.vreg_def_inst[operand.vreg().vreg()].is_valid() {
+                        cfginfo.insn_block[cfginfo.vreg_def_inst[operand.vreg().vreg()].index()]
+                    } else {
+                        cfginfo.vreg_def_blockparam[operand.vreg().vreg()].0
+                    };
+                    if def_block.is_invalid() {
+                        return Err(RegAllocError::SSA(operand.vreg(), iix));
+                    }
+                    if !cfginfo.dominates(def_block, block) {
+                        return Err(RegAllocError::SSA(operand.vreg(), iix));
                     }
                 }
             }
@@ -48,9 +134,10 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
     }
 
     // Check that the length of branch args matches the sum of the
-    // number of blockparams in their succs, and that the end of every
-    // block ends in this branch or in a ret, and that there are no
-    // other branches or rets in the middle of the block.
+    // number of blockparams in their succs, that a branch with a
+    // delay slot defines nothing, and that the end of every block
+    // ends in this branch or in a ret, and that there are no other
+    // branches or rets in the middle of the block.
     for block in 0..f.blocks() {
         let block = Block::new(block);
         let insns = f.block_insns(block);
@@ -64,8 +151,16 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
                         .block_succs(block)
                         .iter()
                         .map(|&succ| f.block_params(succ).len())
-                        .sum();
-                    if f.inst_operands(insn).len() != expected {
+                        .sum::<usize>();
+                    let operands = inst_operands(f, insn);
+                    let uses = operands
+                        .iter()
+                        .filter(|op| op.kind() == OperandKind::Use)
+                        .count();
+                    if uses != expected {
+                        return Err(RegAllocError::Branch(insn));
+                    }
+                    if uses != operands.len() && f.branch_has_delay_slot(insn) {
                         return Err(RegAllocError::Branch(insn));
                     }
                 }